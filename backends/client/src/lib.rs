@@ -75,7 +75,7 @@ impl ChunksToString for Vec<InputChunk> {
         let mut output = String::new();
         self.iter().for_each(|c| match &c.chunk {
             Some(Chunk::Text(text)) => output.push_str(text),
-            Some(Chunk::Image(Image { data, mimetype })) => {
+            Some(Chunk::Image(Image { data, mimetype, .. })) => {
                 let encoded = STANDARD.encode(data);
                 output.push_str(&format!("![](data:{};base64,{})", mimetype, encoded))
             }
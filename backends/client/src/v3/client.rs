@@ -129,6 +129,8 @@ impl Client {
                         // Safe unwrap, because we control the data.
                         data: STANDARD.decode(WARMUP_IMAGE_BASE64).unwrap(),
                         mimetype: "image/jpeg;base64".to_string(),
+                        width: 20,
+                        height: 20,
                     })
                     .into(),
                 );
@@ -168,6 +170,8 @@ impl Client {
                 slots: vec![],
                 cache_len: 0,
                 chunk_len: None,
+                adapters: vec![],
+                guidance: None,
                 // Set sampling parameters to also take these ops into account in the max memory
                 parameters: Some(NextTokenChooserParameters {
                     temperature: 0.9,
@@ -181,11 +185,21 @@ impl Client {
                     watermark: true,
                     grammar: String::new(),
                     grammar_type: GrammarType::None as i32,
+                    speculate: None,
+                    sampling_step: 0,
+                    healing_token_id: None,
+                    logit_bias: Default::default(),
+                    presence_penalty: 0.1,
+                    min_p: 0.05,
+                    watermark_key: 0,
+                    no_repeat_ngram_size: 3,
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens,
                     stop_sequences: vec![],
                     ignore_eos_token: true,
+                    stop_token_ids: vec![],
+                    min_new_tokens: 0,
                 }),
                 prefill_logprobs: true,
                 top_n_tokens: 20,
@@ -212,6 +226,7 @@ impl Client {
             max_input_tokens,
             max_prefill_tokens,
             max_total_tokens,
+            max_batch_total_tokens: None,
         })
         .inject_context();
         let response = self.stub.warmup(request).await?.into_inner();
@@ -254,7 +269,11 @@ impl Client {
         &mut self,
         batches: Vec<CachedBatch>,
     ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)> {
-        let request = tonic::Request::new(DecodeRequest { batches }).inject_context();
+        let request = tonic::Request::new(DecodeRequest {
+            batches,
+            draft_tokens: Vec::new(),
+        })
+        .inject_context();
         let response = self.stub.decode(request).await?.into_inner();
         Ok((
             response.generations,
@@ -267,6 +286,32 @@ impl Client {
             ),
         ))
     }
+
+    /// Embed tokenized inputs
+    ///
+    /// Returns one Embedding per input
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub async fn embed(&mut self, inputs: Vec<EmbedInput>) -> Result<Vec<Embedding>> {
+        let request = tonic::Request::new(EmbedRequest { inputs }).inject_context();
+        let response = self.stub.embed(request).await?.into_inner();
+        Ok(response.embeddings)
+    }
+
+    /// Copy KV cache blocks from the device to the host memory
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_out(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let request = tonic::Request::new(SwapRequest { swaps }).inject_context();
+        self.stub.swap_out(request).await?;
+        Ok(())
+    }
+
+    /// Copy KV cache blocks from the host memory back to the device
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_in(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let request = tonic::Request::new(SwapRequest { swaps }).inject_context();
+        self.stub.swap_in(request).await?;
+        Ok(())
+    }
 }
 
 pub struct PrefillTimings {
@@ -6,8 +6,8 @@ mod sharded_client;
 
 pub use client::Client;
 pub use pb::generate::v3::{
-    input_chunk::Chunk, Batch, CachedBatch, FinishReason, GeneratedText, Generation, GrammarType,
-    HealthResponse, Image, InfoResponse, Input, InputChunk, NextTokenChooserParameters, Request,
-    StoppingCriteriaParameters, Tokens,
+    input_chunk::Chunk, AdapterWeight, Batch, BlockSwap, CachedBatch, EmbedInput, Embedding,
+    FinishReason, GeneratedText, Generation, GrammarType, HealthResponse, Image, InfoResponse,
+    Input, InputChunk, NextTokenChooserParameters, Request, StoppingCriteriaParameters, Tokens,
 };
 pub use sharded_client::ShardedClient;
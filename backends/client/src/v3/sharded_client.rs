@@ -9,8 +9,8 @@ use tonic::transport::Uri;
 use tracing::instrument;
 use v3::client::{DecodeTimings, PrefillTimings};
 use v3::{
-    Batch, CachedBatch, Client, Generation, GrammarType, HealthResponse,
-    NextTokenChooserParameters, Request, StoppingCriteriaParameters,
+    Batch, BlockSwap, CachedBatch, Client, EmbedInput, Embedding, Generation, GrammarType,
+    HealthResponse, NextTokenChooserParameters, Request, StoppingCriteriaParameters,
 };
 
 #[derive(Debug, Clone)]
@@ -200,6 +200,43 @@ impl ShardedClient {
         }
         Ok((generations, next_batch, timings))
     }
+
+    /// Embed tokenized inputs
+    ///
+    /// Returns one Embedding per input
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub async fn embed(&mut self, inputs: Vec<EmbedInput>) -> Result<Vec<Embedding>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.embed(inputs.clone())))
+            .collect();
+        let results: Result<Vec<Vec<Embedding>>> = join_all(futures).await.into_iter().collect();
+        // All shards return the same embeddings
+        results?.pop().ok_or(ClientError::EmptyResults)
+    }
+
+    /// Copy KV cache blocks from the device to the host memory
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_out(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.swap_out(swaps.clone())))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
+    /// Copy KV cache blocks from the host memory back to the device
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_in(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.swap_in(swaps.clone())))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
 }
 
 impl From<InfoResponse> for ShardInfo {
@@ -244,11 +281,21 @@ impl Health for ShardedClient {
                 watermark: false,
                 grammar: String::new(),
                 grammar_type: GrammarType::None as i32,
+                speculate: None,
+                sampling_step: 0,
+                healing_token_id: None,
+                logit_bias: Default::default(),
+                presence_penalty: 0.0,
+                min_p: 0.0,
+                watermark_key: 0,
+                no_repeat_ngram_size: 0,
             }),
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: 1,
                 stop_sequences: vec![],
                 ignore_eos_token: false,
+                stop_token_ids: vec![],
+                min_new_tokens: 0,
             }),
             top_n_tokens: 0,
             // Block 0 is reserved for health checks
@@ -256,6 +303,8 @@ impl Health for ShardedClient {
             slots: (0..16).collect(),
             cache_len: 0,
             chunk_len: None,
+            adapters: vec![],
+            guidance: None,
             adapter_id: None,
         };
         let batch = Batch {
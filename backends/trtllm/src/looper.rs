@@ -14,7 +14,9 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 use tracing::{debug, error, warn};
 
 use text_generation_router::infer::InferError::{GenerationError, ValidationError};
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::{
+    Backend, BatchResidency, GeneratedText, InferError, InferStreamResponse,
+};
 use text_generation_router::validation::ValidationError::{
     EmptyInput, Grammar, TopNTokensDisabled, UnsupportedModality,
 };
@@ -208,6 +210,7 @@ fn post_processor_looper<const MAX_NUM_TOKENS: usize>(
                                     generated_tokens: tokens.len() as u32,
                                     finish_reason: FinishReason::EndOfSequenceToken,
                                     seed: None,
+                                    batches: BatchResidency::default(),
                                 };
 
                                 InferStreamResponse::End {
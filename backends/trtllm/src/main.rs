@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Parser;
 use hf_hub::api::tokio::{Api, ApiBuilder};
@@ -20,7 +21,7 @@ struct Args {
     max_concurrent_requests: usize,
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
-    #[clap(default_value = "4", long, env)]
+    #[clap(default_value = "16", long, env)]
     max_stop_sequences: usize,
     #[clap(default_value = "5", long, env)]
     max_top_n_tokens: u32,
@@ -36,11 +37,15 @@ struct Args {
     hostname: String,
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
     #[clap(long, env, required = true)]
     tokenizer_name: String,
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
     #[clap(long, env)]
+    chat_template_path: Option<String>,
+    #[clap(long, env)]
     revision: Option<String>,
     #[clap(long, env)]
     model_id: String,
@@ -58,12 +63,84 @@ struct Args {
     max_client_batch_size: usize,
     #[clap(long, env)]
     auth_token: Option<String>,
+    #[clap(long, env)]
+    api_keys_path: Option<String>,
+    #[clap(long, env)]
+    usage_path: Option<String>,
     #[clap(long, env, help = "Path to the TensorRT-LLM Orchestrator worker")]
     executor_worker: PathBuf,
     #[clap(default_value = "on", long, env)]
     usage_stats: usage_stats::UsageStatsLevel,
     #[clap(default_value = "2000000", long, env)]
     payload_limit: usize,
+    #[clap(long, env)]
+    max_queue_size: Option<usize>,
+    #[clap(long, env)]
+    max_queue_wait: Option<u64>,
+    #[clap(long, env)]
+    health_check_interval: Option<u64>,
+    #[clap(long, env)]
+    stream_heartbeat_interval: Option<u64>,
+    #[clap(long, env)]
+    queue_journal_path: Option<String>,
+    #[clap(long, env)]
+    rate_limit_requests: Option<f64>,
+    #[clap(long, env)]
+    rate_limit_tokens: Option<u32>,
+    #[clap(long, env)]
+    rate_limit_concurrent_requests: Option<u32>,
+    #[clap(long, env)]
+    rate_limit_config_path: Option<String>,
+    #[clap(long, env)]
+    tenant_header: Option<String>,
+    #[clap(long, env)]
+    response_cache_size: Option<usize>,
+    #[clap(default_value = "3600", long, env)]
+    response_cache_ttl: u64,
+    #[clap(long, env)]
+    response_cache_redis_url: Option<String>,
+    #[clap(long, env)]
+    audit_log: Option<String>,
+    #[clap(default_value = "1.0", long, env)]
+    audit_sample_rate: f64,
+    #[clap(default_value = "256", long, env)]
+    audit_prompt_length: usize,
+    #[clap(long, env)]
+    audit_hash_prompts: bool,
+    #[clap(long, env)]
+    audit_hash_key: Option<String>,
+    #[clap(long, env)]
+    watermark_keys_path: Option<String>,
+    #[clap(default_value = "0.5", long, env)]
+    watermark_gamma: f64,
+    #[clap(long, env)]
+    content_filter_path: Option<String>,
+    #[clap(long, env)]
+    transforms_path: Option<String>,
+    #[clap(long, env)]
+    default_max_new_tokens: Option<u32>,
+    #[clap(long, env)]
+    max_new_tokens_ceiling: Option<u32>,
+    #[clap(long, env)]
+    stream_max_new_tokens_ceiling: Option<u32>,
+    #[clap(long, env)]
+    max_new_tokens_config_path: Option<String>,
+    #[clap(long, env)]
+    idempotency_cache_size: Option<usize>,
+    #[clap(default_value = "3600", long, env)]
+    idempotency_ttl: u64,
+    #[clap(long, env)]
+    stream_resume_events: Option<usize>,
+    #[clap(default_value = "30", long, env)]
+    stream_resume_timeout: u64,
+    #[clap(long, env)]
+    prompt_templates_path: Option<String>,
+    #[clap(long, env)]
+    max_images_per_request: Option<usize>,
+    #[clap(long, env)]
+    max_image_pixels: Option<usize>,
+    #[clap(long, env)]
+    allow_vocab_mismatch: bool,
 }
 
 async fn get_tokenizer(
@@ -206,8 +283,10 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         max_batch_total_tokens,
         hostname,
         port,
+        grpc_port,
         tokenizer_name,
         tokenizer_config_path,
+        chat_template_path,
         revision,
         model_id,
         validation_workers,
@@ -217,9 +296,45 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         cors_allow_origin,
         max_client_batch_size,
         auth_token,
+        api_keys_path,
+        usage_path,
         executor_worker,
         usage_stats,
         payload_limit,
+        max_queue_size,
+        max_queue_wait,
+        health_check_interval,
+        stream_heartbeat_interval,
+        queue_journal_path,
+        rate_limit_requests,
+        rate_limit_tokens,
+        rate_limit_concurrent_requests,
+        rate_limit_config_path,
+        tenant_header,
+        response_cache_size,
+        response_cache_ttl,
+        response_cache_redis_url,
+        audit_log,
+        audit_sample_rate,
+        audit_prompt_length,
+        audit_hash_prompts,
+        audit_hash_key,
+        watermark_keys_path,
+        watermark_gamma,
+        content_filter_path,
+        transforms_path,
+        default_max_new_tokens,
+        max_new_tokens_ceiling,
+        stream_max_new_tokens_ceiling,
+        max_new_tokens_config_path,
+        idempotency_cache_size,
+        idempotency_ttl,
+        stream_resume_events,
+        stream_resume_timeout,
+        prompt_templates_path,
+        max_images_per_request,
+        max_image_pixels,
+        allow_vocab_mismatch,
     } = args;
 
     // Launch Tokio runtime
@@ -241,6 +356,17 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         ));
     }
 
+    if health_check_interval == Some(0) {
+        return Err(TensorRtLlmBackendError::ArgumentValidation(
+            "`health_check_interval` must be > 0".to_string(),
+        ));
+    }
+    if stream_heartbeat_interval == Some(0) {
+        return Err(TensorRtLlmBackendError::ArgumentValidation(
+            "`stream_heartbeat_interval` must be > 0".to_string(),
+        ));
+    }
+
     if let Some(ref max_batch_total_tokens) = max_batch_total_tokens {
         if max_batch_prefill_tokens > *max_batch_total_tokens {
             return Err(TensorRtLlmBackendError::ArgumentValidation(format!("`max_batch_prefill_tokens` must be <= `max_batch_total_tokens`. Given: {max_batch_prefill_tokens} and {max_batch_total_tokens}")));
@@ -287,12 +413,16 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         max_total_tokens,
         validation_workers,
         auth_token,
+        api_keys_path,
+        usage_path,
         tokenizer_name,
         tokenizer_config_path,
+        chat_template_path,
         revision,
         false,
         hostname,
         port,
+        grpc_port,
         cors_allow_origin,
         false,
         None,
@@ -301,6 +431,40 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         max_client_batch_size,
         usage_stats,
         payload_limit,
+        max_queue_size,
+        max_queue_wait.map(Duration::from_secs),
+        health_check_interval.map(Duration::from_secs),
+        stream_heartbeat_interval.map(Duration::from_secs),
+        queue_journal_path,
+        rate_limit_requests,
+        rate_limit_tokens,
+        rate_limit_concurrent_requests,
+        rate_limit_config_path,
+        tenant_header,
+        response_cache_size,
+        Duration::from_secs(response_cache_ttl),
+        response_cache_redis_url,
+        audit_log,
+        audit_sample_rate,
+        audit_prompt_length,
+        audit_hash_prompts,
+        audit_hash_key,
+        watermark_keys_path,
+        watermark_gamma,
+        content_filter_path,
+        transforms_path,
+        default_max_new_tokens,
+        max_new_tokens_ceiling,
+        stream_max_new_tokens_ceiling,
+        max_new_tokens_config_path,
+        idempotency_cache_size,
+        Duration::from_secs(idempotency_ttl),
+        stream_resume_events,
+        Duration::from_secs(stream_resume_timeout),
+        prompt_templates_path,
+        max_images_per_request,
+        max_image_pixels,
+        allow_vocab_mismatch,
     )
     .await?;
     Ok(())
@@ -4,7 +4,9 @@ use crate::queue::{Entry, Queue};
 use async_trait::async_trait;
 use nohash_hasher::IntMap;
 use std::sync::Arc;
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::{
+    Backend, BatchResidency, GeneratedText, InferError, InferStreamResponse,
+};
 use text_generation_router::validation::ValidGenerateRequest;
 use text_generation_router::{FinishReason, PrefillToken, Token};
 use tokio::sync::mpsc::error::SendError;
@@ -85,6 +87,7 @@ impl Backend for BackendV2 {
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            batches: BatchResidency::default(),
         });
 
         // Notify the background task that we have a new entry in the queue that needs
@@ -355,13 +358,15 @@ async fn filter_batch(
 /// and filter entries
 #[instrument(skip_all)]
 fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u64, Entry>) {
+    let batch_size = entries.len();
     generations.into_iter().for_each(|generation| {
         let id = generation.request_id;
         // Get entry
         // We can `expect` here as the request id should always be in the entries
         let entry = entries
-            .get(&id)
+            .get_mut(&id)
             .expect("ID not found in entries. This is a bug.");
+        entry.batches.record(batch_size);
 
         // Create and enter a span to link this function back to the entry
         let _span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered();
@@ -452,7 +457,10 @@ fn send_responses(
                 entry.response_tx.send(Ok(InferStreamResponse::End {
                     token,
                     top_tokens,
-                    generated_text: GeneratedText::from(generated_text.clone()),
+                    generated_text: GeneratedText {
+                        batches: entry.batches,
+                        ..GeneratedText::from(generated_text.clone())
+                    },
                     queued: entry.queue_time,
                     start: entry.batch_time.unwrap(),
                 }))?;
@@ -501,6 +509,7 @@ impl From<crate::client::GeneratedText> for GeneratedText {
             generated_tokens: value.generated_tokens,
             finish_reason,
             seed: value.seed,
+            batches: BatchResidency::default(),
         }
     }
 }
@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
-use text_generation_router::{server, usage_stats};
+use std::time::Duration;
+use text_generation_router::{bench, server, usage_stats};
 use text_generation_router_v2::{connect_backend, V2Error};
 use thiserror::Error;
 
@@ -14,7 +15,7 @@ struct Args {
     max_concurrent_requests: usize,
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
-    #[clap(default_value = "4", long, env)]
+    #[clap(default_value = "16", long, env)]
     max_stop_sequences: usize,
     #[clap(default_value = "5", long, env)]
     max_top_n_tokens: u32,
@@ -36,6 +37,8 @@ struct Args {
     hostname: String,
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
     #[clap(default_value = "/tmp/text-generation-server-0", long, env)]
     master_shard_uds_path: String,
     #[clap(default_value = "bigscience/bloom", long, env)]
@@ -43,6 +46,8 @@ struct Args {
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
     #[clap(long, env)]
+    chat_template_path: Option<String>,
+    #[clap(long, env)]
     revision: Option<String>,
     #[clap(long, env, value_enum)]
     trust_remote_code: bool,
@@ -51,6 +56,10 @@ struct Args {
     #[clap(long, env)]
     api_key: Option<String>,
     #[clap(long, env)]
+    api_keys_path: Option<String>,
+    #[clap(long, env)]
+    usage_path: Option<String>,
+    #[clap(long, env)]
     json_output: bool,
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
@@ -72,11 +81,81 @@ struct Args {
     usage_stats: usage_stats::UsageStatsLevel,
     #[clap(default_value = "2000000", long, env)]
     payload_limit: usize,
+    #[clap(long, env)]
+    max_queue_size: Option<usize>,
+    #[clap(long, env)]
+    max_queue_wait: Option<u64>,
+    #[clap(long, env)]
+    health_check_interval: Option<u64>,
+    #[clap(long, env)]
+    stream_heartbeat_interval: Option<u64>,
+    #[clap(long, env)]
+    queue_journal_path: Option<String>,
+    #[clap(long, env)]
+    rate_limit_requests: Option<f64>,
+    #[clap(long, env)]
+    rate_limit_tokens: Option<u32>,
+    #[clap(long, env)]
+    rate_limit_concurrent_requests: Option<u32>,
+    #[clap(long, env)]
+    rate_limit_config_path: Option<String>,
+    #[clap(long, env)]
+    tenant_header: Option<String>,
+    #[clap(long, env)]
+    response_cache_size: Option<usize>,
+    #[clap(default_value = "3600", long, env)]
+    response_cache_ttl: u64,
+    #[clap(long, env)]
+    response_cache_redis_url: Option<String>,
+    #[clap(long, env)]
+    audit_log: Option<String>,
+    #[clap(default_value = "1.0", long, env)]
+    audit_sample_rate: f64,
+    #[clap(default_value = "256", long, env)]
+    audit_prompt_length: usize,
+    #[clap(long, env)]
+    audit_hash_prompts: bool,
+    #[clap(long, env)]
+    audit_hash_key: Option<String>,
+    #[clap(long, env)]
+    watermark_keys_path: Option<String>,
+    #[clap(default_value = "0.5", long, env)]
+    watermark_gamma: f64,
+    #[clap(long, env)]
+    content_filter_path: Option<String>,
+    #[clap(long, env)]
+    transforms_path: Option<String>,
+    #[clap(long, env)]
+    default_max_new_tokens: Option<u32>,
+    #[clap(long, env)]
+    max_new_tokens_ceiling: Option<u32>,
+    #[clap(long, env)]
+    stream_max_new_tokens_ceiling: Option<u32>,
+    #[clap(long, env)]
+    max_new_tokens_config_path: Option<String>,
+    #[clap(long, env)]
+    idempotency_cache_size: Option<usize>,
+    #[clap(default_value = "3600", long, env)]
+    idempotency_ttl: u64,
+    #[clap(long, env)]
+    stream_resume_events: Option<usize>,
+    #[clap(default_value = "30", long, env)]
+    stream_resume_timeout: u64,
+    #[clap(long, env)]
+    prompt_templates_path: Option<String>,
+    #[clap(long, env)]
+    max_images_per_request: Option<usize>,
+    #[clap(long, env)]
+    max_image_pixels: Option<usize>,
+    #[clap(long, env)]
+    allow_vocab_mismatch: bool,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     PrintSchema,
+    /// Replay a JSONL trace of requests against a running router or the mock backend
+    Bench(bench::BenchArgs),
 }
 
 #[tokio::main]
@@ -99,13 +178,17 @@ async fn main() -> Result<(), RouterError> {
         max_batch_size,
         hostname,
         port,
+        grpc_port,
         master_shard_uds_path,
         tokenizer_name,
         tokenizer_config_path,
+        chat_template_path,
         revision,
         trust_remote_code,
         validation_workers,
         api_key,
+        api_keys_path,
+        usage_path,
         json_output,
         otlp_endpoint,
         otlp_service_name,
@@ -117,6 +200,40 @@ async fn main() -> Result<(), RouterError> {
         max_client_batch_size,
         usage_stats,
         payload_limit,
+        max_queue_size,
+        max_queue_wait,
+        health_check_interval,
+        stream_heartbeat_interval,
+        queue_journal_path,
+        rate_limit_requests,
+        rate_limit_tokens,
+        rate_limit_concurrent_requests,
+        rate_limit_config_path,
+        tenant_header,
+        response_cache_size,
+        response_cache_ttl,
+        response_cache_redis_url,
+        audit_log,
+        audit_sample_rate,
+        audit_prompt_length,
+        audit_hash_prompts,
+        audit_hash_key,
+        watermark_keys_path,
+        watermark_gamma,
+        content_filter_path,
+        transforms_path,
+        default_max_new_tokens,
+        max_new_tokens_ceiling,
+        stream_max_new_tokens_ceiling,
+        max_new_tokens_config_path,
+        idempotency_cache_size,
+        idempotency_ttl,
+        stream_resume_events,
+        stream_resume_timeout,
+        prompt_templates_path,
+        max_images_per_request,
+        max_image_pixels,
+        allow_vocab_mismatch,
     } = args;
 
     if let Some(Commands::PrintSchema) = command {
@@ -126,6 +243,9 @@ async fn main() -> Result<(), RouterError> {
         println!("{}", api_doc);
         std::process::exit(0);
     };
+    if let Some(Commands::Bench(bench_args)) = command {
+        return Ok(bench::run(bench_args).await?);
+    }
     text_generation_router::logging::init_logging(otlp_endpoint, otlp_service_name, json_output);
 
     // Validate args
@@ -144,6 +264,17 @@ async fn main() -> Result<(), RouterError> {
         ));
     }
 
+    if health_check_interval == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`health_check_interval` must be > 0".to_string(),
+        ));
+    }
+    if stream_heartbeat_interval == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`stream_heartbeat_interval` must be > 0".to_string(),
+        ));
+    }
+
     if let Some(ref max_batch_total_tokens) = max_batch_total_tokens {
         if max_batch_prefill_tokens > *max_batch_total_tokens {
             return Err(RouterError::ArgumentValidation(format!("`max_batch_prefill_tokens` must be <= `max_batch_total_tokens`. Given: {max_batch_prefill_tokens} and {max_batch_total_tokens}")));
@@ -184,12 +315,16 @@ async fn main() -> Result<(), RouterError> {
         max_total_tokens,
         validation_workers,
         api_key,
+        api_keys_path,
+        usage_path,
         tokenizer_name,
         tokenizer_config_path,
+        chat_template_path,
         revision,
         trust_remote_code,
         hostname,
         port,
+        grpc_port,
         cors_allow_origin,
         ngrok,
         ngrok_authtoken,
@@ -198,6 +333,40 @@ async fn main() -> Result<(), RouterError> {
         max_client_batch_size,
         usage_stats,
         payload_limit,
+        max_queue_size,
+        max_queue_wait.map(Duration::from_secs),
+        health_check_interval.map(Duration::from_secs),
+        stream_heartbeat_interval.map(Duration::from_secs),
+        queue_journal_path,
+        rate_limit_requests,
+        rate_limit_tokens,
+        rate_limit_concurrent_requests,
+        rate_limit_config_path,
+        tenant_header,
+        response_cache_size,
+        Duration::from_secs(response_cache_ttl),
+        response_cache_redis_url,
+        audit_log,
+        audit_sample_rate,
+        audit_prompt_length,
+        audit_hash_prompts,
+        audit_hash_key,
+        watermark_keys_path,
+        watermark_gamma,
+        content_filter_path,
+        transforms_path,
+        default_max_new_tokens,
+        max_new_tokens_ceiling,
+        stream_max_new_tokens_ceiling,
+        max_new_tokens_config_path,
+        idempotency_cache_size,
+        Duration::from_secs(idempotency_ttl),
+        stream_resume_events,
+        Duration::from_secs(stream_resume_timeout),
+        prompt_templates_path,
+        max_images_per_request,
+        max_image_pixels,
+        allow_vocab_mismatch,
     )
     .await?;
     Ok(())
@@ -211,6 +380,8 @@ enum RouterError {
     Backend(#[from] V2Error),
     #[error("WebServer error: {0}")]
     WebServer(#[from] server::WebServerError),
+    #[error("Benchmark failed: {0}")]
+    Bench(#[from] bench::BenchError),
     #[error("Tokio runtime failed to start: {0}")]
     Tokio(#[from] std::io::Error),
 }
@@ -4,8 +4,8 @@ use crate::client::{
 use nohash_hasher::{BuildNoHashHasher, IntMap};
 use std::cmp::min;
 use std::collections::VecDeque;
-use text_generation_router::infer::InferError;
 use text_generation_router::infer::InferStreamResponse;
+use text_generation_router::infer::{BatchResidency, InferError};
 use text_generation_router::validation::{
     ChunksToString, ValidGenerateRequest, ValidGrammar, ValidParameters, ValidStoppingParameters,
 };
@@ -28,6 +28,8 @@ pub(crate) struct Entry {
     pub queue_time: Instant,
     /// Instant when this entry was added to a batch
     pub batch_time: Option<Instant>,
+    /// Batches that generated the tokens of this entry
+    pub batches: BatchResidency,
 }
 
 /// Request Queue
@@ -300,6 +302,7 @@ impl State {
             });
             // Set batch_time
             entry.batch_time = Some(Instant::now());
+            let _ = entry.response_tx.send(Ok(InferStreamResponse::Started));
             // Insert in batch_entries IntMap
             batch_entries.insert(id, entry);
 
@@ -372,6 +375,7 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             Some(grammar) => match grammar {
                 ValidGrammar::Json(grammar_string) => (grammar_string, GrammarType::Json),
                 ValidGrammar::Regex(grammar_string) => (grammar_string, GrammarType::Regex),
+                ValidGrammar::JsonObject => (String::new(), GrammarType::JsonObject),
             },
         };
 
@@ -404,7 +408,9 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
     use std::sync::Arc;
+    use text_generation_router::Priority;
     use tracing::info_span;
 
     fn default_entry() -> (
@@ -426,27 +432,47 @@ mod tests {
                     top_k: 0,
                     top_p: 0.0,
                     typical_p: 0.0,
+                    min_p: 0.0,
+                    no_repeat_ngram_size: 0,
                     do_sample: false,
                     seed: 0,
                     repetition_penalty: 0.0,
                     frequency_penalty: 0.0,
+                    presence_penalty: 0.0,
                     watermark: false,
+                    watermark_key: 0,
                     grammar: None,
+                    speculate: None,
+                    healing_token_id: None,
+                    logit_bias: HashMap::new(),
                 },
                 stopping_parameters: ValidStoppingParameters {
                     ignore_eos_token: false,
                     max_new_tokens: 1,
                     max_total_new_tokens: 1024,
                     stop_sequences: vec![],
+                    stop_token_ids: vec![],
+                    min_new_tokens: 0,
                 },
                 top_n_tokens: 0,
                 adapter_id: None,
+                adapters: Vec::new(),
+                priority: Priority::Normal,
+                session_id: None,
+                generated_tokens: 0,
+                healed_prefix: None,
+                guidance: None,
+                tenant: None,
+                request_id: None,
+                deadline: None,
+                prompt_group: None,
             },
             response_tx,
             span: info_span!("entry"),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            batches: BatchResidency::default(),
         };
         (entry, receiver_tx)
     }
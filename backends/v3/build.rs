@@ -9,7 +9,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tonic_build::configure()
         .build_client(true)
-        .build_server(false)
+        // The tests serve fake shards
+        .build_server(true)
         .out_dir("src/client/pb")
         .include_file("mod.rs")
         .compile_with_config(config, &["../../proto/v3/generate.proto"], &["../../proto"])
@@ -1,20 +1,50 @@
 /// Batching and inference logic
+use crate::block_allocator::Compaction;
+use crate::budget::{is_out_of_memory, Budget};
 use crate::client::{
-    Batch, CachedBatch, ClientError, Generation, Health, InfoResponse, ShardedClient,
+    Batch, CachedBatch, ClientError, Generation, Health, InfoResponse, LoraAdapter, ShardedClient,
 };
-use crate::queue::{Entry, Queue};
+use crate::disaggregation::{
+    can_hand_off, copy_prompt_kv, needs_copy, PendingCopy, PrefillShards, PrefillStage,
+};
+use crate::draft::{Draft, DraftShards, Drafter};
+use crate::embed::Embedder;
+use crate::lookup::PromptLookup;
+use crate::preemption::{self, Checkpoint};
+use crate::queue::{
+    sequences, Entry, Fairness, InFlight, Queue, QueueConfig, NEGATIVE_REQUEST_BIT,
+};
+use crate::slo::{DecodeLatencyController, TtftController};
+use crate::supervisor::is_shard_down;
+use crate::swap::SwapSpace;
+use crate::timings::{IterationLog, Phases};
 use async_trait::async_trait;
 use nohash_hasher::IntMap;
-use std::sync::Arc;
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
-use text_generation_router::validation::ValidGenerateRequest;
-use text_generation_router::{FinishReason, PrefillToken, Token};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use text_generation_router::infer::{
+    Backend, BatchResidency, GeneratedText, InferError, InferStreamResponse,
+};
+use text_generation_router::validation::{ValidGenerateRequest, ValidationError};
+use text_generation_router::{
+    BatchRecord, CacheStats, FinishReason, IterationTimings, LoadAdapterRequest, LoraAdapterInfo,
+    PrefillToken, QueuedRequest, SessionStats, ShardInfo, Token,
+};
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tracing::{info_span, instrument, Instrument, Span};
 
+/// Interval at which queued requests are retried while the batch budget recovers
+const BUDGET_RECOVERY_POLL: Duration = Duration::from_secs(1);
+/// Longest wait for the batching task to stop, as a stuck shard would hang the shutdown
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct BackendV3 {
     /// Request queue
     queue: Queue,
@@ -22,6 +52,50 @@ pub struct BackendV3 {
     batching_task_notifier: Arc<Notify>,
     /// Client clone, used for health checks to skip the queue
     client: ShardedClient,
+    /// Number of speculative tokens of the model
+    speculate: u32,
+    /// Number of requests queued or running on this backend
+    load: Arc<AtomicUsize>,
+    /// Cleared by the batching task when an inference call fails, set back by health checks
+    healthy: Arc<AtomicBool>,
+    /// Set when the shards went down, the replica then connects a new backend
+    crashed: Arc<AtomicBool>,
+    /// Finished when the batching loop stopped or panicked
+    batching_task: JoinHandle<()>,
+    /// Cancelled to stop the batching task, which then starts no new batch and stops the
+    /// running one after its current forward
+    cancellation: CancellationToken,
+    /// Cancelled by the batching task once it stopped
+    stopped: CancellationToken,
+    /// Whether the shards prefill a sequence on top of the blocks of a prompt computed in the
+    /// same batch
+    shares_prompt_kv: bool,
+    /// Batching of the embedding requests, if the model supports them
+    embedder: Option<Embedder>,
+    /// Bits of the `waiting_served_ratio` used by the batching task, unless the model supports
+    /// prefill chunking
+    waiting_served_ratio: Option<Arc<AtomicU32>>,
+    /// Longest n-gram looked up in the context to draft the speculative tokens, if the router
+    /// drafts them
+    prompt_lookup_max_ngram: Option<usize>,
+    /// Whether a draft model proposes the speculative tokens
+    draft_model: bool,
+    /// Shards computing the prompts before this backend decodes them, if the replica is
+    /// disaggregated
+    prefill_stage: Option<PrefillStage>,
+    /// Queued requests from which the running requests are preempted, if they can be
+    preemption_queue_size: Option<usize>,
+    /// LoRA adapters loaded in the shards, that the requests can use or mix
+    lora_adapters: RwLock<BTreeMap<String, LoraAdapterInfo>>,
+    /// The ones loaded by `load_adapter`, that the shards do not load when they start
+    runtime_adapters: RwLock<BTreeMap<String, LoadAdapterRequest>>,
+    /// Model and kernels of the shards, for `/info`
+    shard_info: ShardInfo,
+    /// Most tokens that the prompt and the negative prompt of a guided request can prefill,
+    /// if the shards support classifier-free guidance
+    guidance_prefill_tokens: Option<u32>,
+    /// Phase timings of the last decode iterations of the batching task
+    iteration_log: IterationLog,
 }
 
 impl BackendV3 {
@@ -29,33 +103,116 @@ impl BackendV3 {
     pub(crate) fn new(
         client: ShardedClient,
         waiting_served_ratio: f32,
+        target_ttft: Option<Duration>,
+        target_decode_latency: Option<Duration>,
         max_batch_prefill_tokens: u32,
         max_batch_total_tokens: u32,
         max_waiting_tokens: usize,
         max_batch_size: Option<usize>,
+        session_ttl: Duration,
+        compaction: Compaction,
+        fairness: Fairness,
+        prompt_lookup_max_ngram: Option<usize>,
+        preemption_queue_size: Option<usize>,
         shard_info: InfoResponse,
+        prefill_shards: Option<PrefillShards>,
+        draft_shards: Option<DraftShards>,
     ) -> Self {
         if shard_info.support_chunking {
-            tracing::warn!("Model supports prefill chunking. `waiting_served_ratio` and `max_waiting_tokens` will be ignored.");
+            tracing::warn!("Model supports prefill chunking. `waiting_served_ratio`, `target_ttft_ms` and `max_waiting_tokens` will be ignored.");
+        } else {
+            metrics::gauge!("tgi_batch_waiting_served_ratio").set(waiting_served_ratio as f64);
         }
+        let prompt_lookup_max_ngram = prompt_lookup_max_ngram.filter(|_| {
+            if draft_shards.is_some() {
+                tracing::warn!("The draft model proposes the speculative tokens. `prompt_lookup_max_ngram` will be ignored.");
+                return false;
+            }
+            let supported = shard_info.speculate > 0 && shard_info.accepts_draft_tokens;
+            if !supported {
+                tracing::warn!("Model does not speculate or has its own speculator. `prompt_lookup_max_ngram` will be ignored.");
+            }
+            supported
+        });
 
         let block_size = shard_info.block_size;
+        let lora_adapters = shard_info
+            .lora_adapters
+            .iter()
+            .map(|adapter| (adapter.id.clone(), adapter_info(adapter.clone())))
+            .collect();
+        let info = ShardInfo {
+            model_dtype: shard_info.dtype.clone(),
+            model_device_type: shard_info.device_type.clone(),
+            context_length: shard_info.context_length,
+            vocab_size: shard_info.vocab_size,
+            quantization: shard_info.quantize.clone(),
+            attention_impl: shard_info.attention_impl.clone(),
+            kernel_versions: shard_info.kernel_versions.clone().into_iter().collect(),
+        };
+        // The blocks copied back are a prefix of the prompt, which the shards only skip with
+        // prefix caching
+        let swap_space =
+            (shard_info.swap_blocks > 0 && shard_info.use_prefix_caching).then(|| SwapSpace {
+                client: client.clone(),
+                blocks: shard_info.swap_blocks,
+            });
 
-        let queue = Queue::new(
-            shard_info.requires_padding,
+        let queue = Queue::new(QueueConfig {
+            requires_padding: shard_info.requires_padding,
             block_size,
-            shard_info.use_prefix_caching,
-            shard_info.window_size,
-            shard_info.speculate,
+            kv_bytes_per_token: shard_info.kv_bytes_per_token,
+            prefix_caching: shard_info.use_prefix_caching,
+            window_size: shard_info.window_size,
+            session_ttl,
+            swap_space,
+            compaction,
+            speculate: shard_info.speculate,
             max_batch_total_tokens,
-            shard_info.support_chunking,
-        );
+            support_chunking: shard_info.support_chunking,
+            fairness: fairness.clone(),
+        });
         let batching_task_notifier = Arc::new(Notify::new());
+        let healthy = Arc::new(AtomicBool::new(true));
+        let crashed = Arc::new(AtomicBool::new(false));
+        let cancellation = CancellationToken::new();
+        let stopped = CancellationToken::new();
+
+        let prefill_stage = prefill_shards.map(|prefill_shards| {
+            PrefillStage::new(
+                prefill_shards,
+                max_batch_prefill_tokens,
+                max_batch_size,
+                session_ttl,
+                compaction,
+                fairness,
+                queue.clone(),
+                batching_task_notifier.clone(),
+                healthy.clone(),
+                crashed.clone(),
+                cancellation.child_token(),
+            )
+        });
+        let draft_model = draft_shards.is_some();
+        let drafter =
+            draft_shards.map(|draft_shards| Drafter::new(draft_shards, shard_info.speculate));
+        let shared_waiting_served_ratio = Arc::new(AtomicU32::new(waiting_served_ratio.to_bits()));
+        let ttft_controller =
+            target_ttft
+                .filter(|_| !shard_info.support_chunking)
+                .map(|target_ttft| {
+                    TtftController::new(target_ttft, shared_waiting_served_ratio.clone())
+                });
+        let decode_latency_controller = target_decode_latency
+            .map(|target| DecodeLatencyController::new(target, max_batch_size));
+        let iteration_log = IterationLog::default();
 
         // Spawn batching background task that contains all the inference logic
-        tokio::spawn(batching_task(
+        let batching_task = tokio::spawn(batching_task(
             client.clone(),
-            waiting_served_ratio,
+            shared_waiting_served_ratio.clone(),
+            ttft_controller,
+            decode_latency_controller,
             max_batch_prefill_tokens,
             max_batch_total_tokens,
             max_waiting_tokens,
@@ -63,14 +220,232 @@ impl BackendV3 {
             shard_info.support_chunking,
             queue.clone(),
             batching_task_notifier.clone(),
+            healthy.clone(),
+            crashed.clone(),
+            prefill_stage.as_ref().map(|stage| stage.client.clone()),
+            drafter,
+            preemption_queue_size,
+            iteration_log.clone(),
+            cancellation.clone(),
+            stopped.clone(),
         ));
 
+        // The negative sequences are generated with the tokens of their guided request, the
+        // speculative tokens and the KV copied from the prefill shards only cover the latter
+        let guidance_prefill_tokens = (shard_info.supports_guidance
+            && shard_info.speculate == 0
+            && !draft_model
+            && prefill_stage.is_none())
+        .then_some(max_batch_prefill_tokens);
+
+        let embedder = shard_info
+            .support_embeddings
+            .then(|| Embedder::new(client.clone(), max_batch_prefill_tokens));
+
         Self {
             queue,
             batching_task_notifier,
             client,
+            speculate: shard_info.speculate,
+            load: Arc::new(AtomicUsize::new(0)),
+            healthy,
+            crashed,
+            batching_task,
+            cancellation,
+            stopped,
+            // Only the attentions that read the KV of the prefix from the blocks, which are also
+            // the ones supporting prefix caching, see the KV stored by another sequence. The
+            // windowed blocks are reused by their own sequence.
+            shares_prompt_kv: !shard_info.requires_padding
+                && shard_info.window_size.is_none()
+                && matches!(
+                    shard_info.attention_impl.as_str(),
+                    "flashinfer" | "flashdecoding"
+                ),
+            embedder,
+            waiting_served_ratio: (!shard_info.support_chunking)
+                .then_some(shared_waiting_served_ratio),
+            prompt_lookup_max_ngram,
+            draft_model,
+            prefill_stage,
+            preemption_queue_size,
+            lora_adapters: RwLock::new(lora_adapters),
+            runtime_adapters: RwLock::new(BTreeMap::new()),
+            shard_info: info,
+            guidance_prefill_tokens,
+            iteration_log,
         }
     }
+
+    /// Number of requests queued or running on this backend
+    pub(crate) fn load(&self) -> usize {
+        self.load.load(Ordering::Relaxed)
+    }
+
+    /// Adapters loaded at runtime, to load again in the shards replacing these ones
+    pub(crate) fn runtime_adapters(&self) -> Vec<LoadAdapterRequest> {
+        self.runtime_adapters
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Load the `adapters` that were loaded at runtime in the previous shards of the replica,
+    /// unless these shards loaded them when they started
+    pub(crate) async fn restore_adapters(
+        &self,
+        adapters: Vec<LoadAdapterRequest>,
+    ) -> Result<(), InferError> {
+        for adapter in adapters {
+            if self.lora_adapters.read().unwrap().contains_key(&adapter.id) {
+                continue;
+            }
+            self.load_adapter(adapter).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the last inference call or health check of this backend succeeded
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Whether the shards went down or the batching task panicked
+    pub(crate) fn is_crashed(&self) -> bool {
+        self.crashed.load(Ordering::Relaxed)
+            || self.batching_task.is_finished()
+            || self
+                .prefill_stage
+                .as_ref()
+                .is_some_and(|stage| stage.is_finished())
+    }
+
+    /// Stop batching and fail the queued requests with an error their clients can retry
+    pub(crate) fn shut_down(&self) {
+        self.crashed.store(true, Ordering::Relaxed);
+        self.healthy.store(false, Ordering::Relaxed);
+        self.cancellation.cancel();
+        self.queue.close();
+        if let Some(stage) = &self.prefill_stage {
+            stage.shut_down();
+        }
+    }
+
+    /// Stop batching once the server stopped, and wait until the batching task freed the KV
+    /// cache of the shards, for up to `STOP_TIMEOUT`
+    pub(crate) async fn stop(&self) {
+        self.cancellation.cancel();
+        if tokio::time::timeout(STOP_TIMEOUT, self.stopped.cancelled())
+            .await
+            .is_err()
+        {
+            // Dropping its entries fails the running requests and frees their blocks, the shards
+            // may still hold their KV cache
+            tracing::error!(
+                "The batching task did not stop within {}s, aborting it",
+                STOP_TIMEOUT.as_secs()
+            );
+            self.batching_task.abort();
+        }
+        self.queue.close();
+        if let Some(stage) = &self.prefill_stage {
+            stage.shut_down();
+        }
+    }
+
+    /// Queue a `request` whose responses go to `response_tx`, as if it was queued at
+    /// `queue_time`
+    fn enqueue(
+        &self,
+        request: ValidGenerateRequest,
+        response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+        span: Span,
+        queue_time: Instant,
+    ) -> Result<(), InferError> {
+        if self.is_crashed() {
+            return Err(reconnecting());
+        }
+        // Requests can only lower the speculation of the model
+        if let Some(speculate) = request.parameters.speculate {
+            if speculate > self.speculate {
+                metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                return Err(ValidationError::Speculate(self.speculate, speculate).into());
+            }
+        }
+
+        if let (Some(guidance), Some(prefill_tokens)) =
+            (&request.guidance, self.guidance_prefill_tokens)
+        {
+            let tokens = request.input_length + guidance.input_length;
+            if tokens > prefill_tokens {
+                metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                return Err(ValidationError::NegativePromptPrefill(prefill_tokens, tokens).into());
+            }
+        }
+
+        let lookup = self.prompt_lookup_max_ngram.and_then(|max_ngram| {
+            let max_draft = request.parameters.speculate.unwrap_or(self.speculate);
+            let input_ids = request.input_ids.as_ref()?;
+            (max_draft > 0).then(|| PromptLookup::new(input_ids, max_ngram, max_draft as usize))
+        });
+        let draft = self
+            .draft_model
+            .then(|| Draft::new(request.parameters.speculate.unwrap_or(self.speculate) as usize));
+
+        let entry = Entry {
+            request,
+            response_tx,
+            span,
+            temp_span: None,
+            queue_time,
+            batch_time: None,
+            block_allocation: None,
+            negative_block_allocation: None,
+            overtaken: 0,
+            in_flight: InFlight::new(self.load.clone()),
+            lookup,
+            draft,
+            tag: 0.0,
+            handoff: None,
+            batches: BatchResidency::default(),
+            checkpoint: self.preemption_queue_size.map(|_| Checkpoint::default()),
+        };
+
+        match &self.prefill_stage {
+            // The prefill shards hand the entry to the queue once they computed its prompt
+            Some(prefill_stage) if can_hand_off(&entry) => prefill_stage.append(entry),
+            _ => {
+                // Append the request to the queue
+                self.queue.append(entry);
+                // Notify the background task that we have a new entry in the queue that needs
+                // to be batched
+                self.batching_task_notifier.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue an `entry` handed over by the closed queue of another replica, keeping its place
+    /// in time. Its client gets the error if this backend cannot serve it either.
+    pub(crate) fn adopt(&self, entry: Entry) {
+        let Entry {
+            request,
+            response_tx,
+            span,
+            queue_time,
+            ..
+        } = entry;
+        if let Err(err) = self.enqueue(request, response_tx.clone(), span, queue_time) {
+            let _ = response_tx.send(Err(err));
+        }
+    }
+
+    /// Hand the queued requests that did not start over to `handover` when the shards go down
+    pub(crate) fn set_handover(&self, handover: mpsc::UnboundedSender<Entry>) {
+        self.queue.set_handover(handover);
+    }
 }
 
 #[async_trait]
@@ -82,39 +457,241 @@ impl Backend for BackendV3 {
     ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = mpsc::unbounded_channel();
+        self.enqueue(request, response_tx, Span::current(), Instant::now())?;
+        // Return stream
+        Ok(UnboundedReceiverStream::new(response_rx))
+    }
 
-        // Append the request to the queue
-        self.queue.append(Entry {
-            request,
-            response_tx,
-            span: Span::current(),
-            temp_span: None,
-            queue_time: Instant::now(),
-            batch_time: None,
-            block_allocation: None,
-        });
+    fn shares_prompt_kv(&self) -> bool {
+        self.shares_prompt_kv
+    }
+
+    fn supports_token_healing(&self) -> bool {
+        true
+    }
 
-        // Notify the background task that we have a new entry in the queue that needs
-        // to be batched
-        self.batching_task_notifier.notify_one();
+    fn supports_logit_bias(&self) -> bool {
+        true
+    }
 
-        // Return stream
-        Ok(UnboundedReceiverStream::new(response_rx))
+    fn supports_presence_penalty(&self) -> bool {
+        true
+    }
+
+    fn supports_min_p(&self) -> bool {
+        true
+    }
+
+    fn supports_no_repeat_ngram_size(&self) -> bool {
+        true
+    }
+
+    fn supports_guidance(&self) -> bool {
+        self.guidance_prefill_tokens.is_some()
+    }
+
+    fn lora_adapters(&self) -> Option<HashMap<String, u32>> {
+        let lora_adapters = self.lora_adapters.read().unwrap();
+        Some(
+            lora_adapters
+                .values()
+                .map(|adapter| (adapter.id.clone(), adapter.rank))
+                .collect(),
+        )
+    }
+
+    fn supports_watermark_key(&self) -> bool {
+        true
+    }
+
+    fn supports_stop_token_ids(&self) -> bool {
+        true
+    }
+
+    fn supports_min_new_tokens(&self) -> bool {
+        true
+    }
+
+    fn supports_deadlines(&self) -> bool {
+        true
+    }
+
+    fn shard_info(&self) -> Option<ShardInfo> {
+        Some(self.shard_info.clone())
+    }
+
+    fn loaded_adapters(&self) -> Vec<LoraAdapterInfo> {
+        self.lora_adapters
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn load_adapter(
+        &self,
+        request: LoadAdapterRequest,
+    ) -> Result<LoraAdapterInfo, InferError> {
+        let LoadAdapterRequest { id, path, revision } = request.clone();
+        if self.lora_adapters.read().unwrap().contains_key(&id) {
+            return Err(InferError::AdapterAlreadyLoaded(id));
+        }
+        if self.is_crashed() {
+            return Err(reconnecting());
+        }
+        let adapter = self
+            .client
+            .clone()
+            .load_adapter(id.clone(), path.clone(), revision.clone())
+            .await
+            .map_err(adapter_error)?;
+        // The prefill shards compute the prompts with the adapter as well
+        if let Some(stage) = &self.prefill_stage {
+            if let Err(err) = stage
+                .client
+                .clone()
+                .load_adapter(id.clone(), path, revision)
+                .await
+            {
+                let _ = self.client.clone().unload_adapter(id).await;
+                return Err(adapter_error(err));
+            }
+        }
+        let adapter = adapter_info(adapter);
+        self.lora_adapters
+            .write()
+            .unwrap()
+            .insert(adapter.id.clone(), adapter.clone());
+        self.runtime_adapters.write().unwrap().insert(id, request);
+        Ok(adapter)
+    }
+
+    #[instrument(skip(self))]
+    async fn unload_adapter(&self, id: &str) -> Result<(), InferError> {
+        if !self.lora_adapters.read().unwrap().contains_key(id) {
+            return Err(InferError::AdapterNotLoaded(id.to_string()));
+        }
+        if self.is_crashed() {
+            return Err(reconnecting());
+        }
+        self.client
+            .clone()
+            .unload_adapter(id.to_string())
+            .await
+            .map_err(adapter_error)?;
+        if let Some(stage) = &self.prefill_stage {
+            stage
+                .client
+                .clone()
+                .unload_adapter(id.to_string())
+                .await
+                .map_err(adapter_error)?;
+        }
+        self.lora_adapters.write().unwrap().remove(id);
+        self.runtime_adapters.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn supports_embeddings(&self) -> bool {
+        self.embedder.is_some()
+    }
+
+    async fn embed(
+        &self,
+        _model: Option<&str>,
+        input_ids: Vec<Vec<u32>>,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        let Some(embedder) = &self.embedder else {
+            return Err(InferError::EmbeddingsUnsupported);
+        };
+        if self.is_crashed() {
+            return Err(reconnecting());
+        }
+        let _in_flight = InFlight::new(self.load.clone());
+        embedder
+            .embed(input_ids)
+            .await
+            .map_err(|err| InferError::GenerationError(err.to_string()))
     }
 
     async fn health(&self, current_health: bool) -> bool {
-        if current_health {
-            // Generation is healthy, we only check that the shards can allocate on device
-            self.client.device_health().await
-        } else {
-            self.client.model_health().await
+        if self.is_crashed() {
+            return false;
         }
-        .is_ok()
+        let mut result = shards_health(&self.client, current_health && self.is_healthy()).await;
+        if let (Ok(()), Some(stage)) = (&result, &self.prefill_stage) {
+            result = shards_health(&stage.client, current_health && self.is_healthy()).await;
+        }
+        // Shards that die while idle are found by the health checks
+        if result.as_ref().is_err_and(is_shard_down) {
+            self.crashed.store(true, Ordering::Relaxed);
+        }
+        let healthy = result.is_ok();
+        self.healthy.store(healthy, Ordering::Relaxed);
+        healthy
     }
 
     fn start_health(&self) -> bool {
         true
     }
+
+    async fn batch_history(&self) -> Vec<BatchRecord> {
+        let mut history = self.queue.batch_history().await;
+        if let Some(stage) = &self.prefill_stage {
+            history.extend(stage.queue().batch_history().await);
+            history.sort_by_key(|batch| batch.timestamp);
+        }
+        history
+    }
+
+    fn iteration_timings(&self) -> Vec<IterationTimings> {
+        self.iteration_log.history()
+    }
+
+    async fn session_stats(&self) -> Vec<SessionStats> {
+        self.queue.session_stats().await
+    }
+
+    async fn cache_stats(&self) -> Vec<CacheStats> {
+        self.queue.cache_stats().await.into_iter().collect()
+    }
+
+    async fn queued_requests(&self) -> Vec<QueuedRequest> {
+        let mut requests = self.queue.entries().await;
+        if let Some(stage) = &self.prefill_stage {
+            requests.extend(stage.queue().entries().await);
+        }
+        requests
+    }
+
+    fn waiting_served_ratio(&self) -> Option<f32> {
+        self.waiting_served_ratio
+            .as_ref()
+            .map(|ratio| f32::from_bits(ratio.load(Ordering::Relaxed)))
+    }
+
+    fn set_waiting_served_ratio(&self, waiting_served_ratio: f32) {
+        if let Some(ratio) = &self.waiting_served_ratio {
+            ratio.store(waiting_served_ratio.to_bits(), Ordering::Relaxed);
+            metrics::gauge!("tgi_batch_waiting_served_ratio").set(waiting_served_ratio as f64);
+        }
+    }
+}
+
+/// Check the health of `client`. A healthy generation only needs the shards to allocate on
+/// device.
+async fn shards_health(
+    client: &ShardedClient,
+    generation_healthy: bool,
+) -> Result<(), ClientError> {
+    if generation_healthy {
+        client.device_health().await
+    } else {
+        client.model_health().await
+    }
 }
 
 /// Batching logic
@@ -124,7 +701,9 @@ impl Backend for BackendV3 {
 #[allow(clippy::too_many_arguments)]
 pub(crate) async fn batching_task(
     mut client: ShardedClient,
-    waiting_served_ratio: f32,
+    waiting_served_ratio: Arc<AtomicU32>,
+    mut ttft_controller: Option<TtftController>,
+    mut decode_latency_controller: Option<DecodeLatencyController>,
     max_batch_prefill_tokens: u32,
     max_batch_total_tokens: u32,
     max_waiting_tokens: usize,
@@ -132,32 +711,89 @@ pub(crate) async fn batching_task(
     support_chunking: bool,
     queue: Queue,
     notifier: Arc<Notify>,
+    healthy: Arc<AtomicBool>,
+    crashed: Arc<AtomicBool>,
+    mut prefill_client: Option<ShardedClient>,
+    mut drafter: Option<Drafter>,
+    preemption_queue_size: Option<usize>,
+    iteration_log: IterationLog,
+    cancellation: CancellationToken,
+    stopped: CancellationToken,
 ) {
-    // Infinite loop
+    // Also signals the tasks waiting for the batching task when it panics
+    let _stopped = stopped.drop_guard();
+    let mut budget = Budget::new(
+        max_batch_prefill_tokens,
+        max_batch_total_tokens,
+        max_batch_size,
+    );
+    // Requests stopped by the cancellation
+    let mut aborted = 0;
+    // Batch waiting for the KV of its prompts, on a disaggregated replica
+    let mut pending_copy = None;
+
+    // Loop until the backend is stopped
     loop {
         // Wait for a notification from the Infer struct
-        notifier.notified().await;
+        let notified = async {
+            if budget.is_reduced() {
+                // Requests over the reduced budget are still queued, retry them as it grows back
+                let _ = tokio::time::timeout(BUDGET_RECOVERY_POLL, notifier.notified()).await;
+            } else {
+                notifier.notified().await;
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = cancellation.cancelled() => break,
+            _ = notified => {}
+        }
 
         // Get the next batch from the queue
         // This batch might be smaller than the maximum batch size if there are not enough requests
         // waiting in the queue
-        while let Some((mut entries, batch, span)) = queue
-            .next_batch(
+        while let Some((mut entries, batch, span)) = next_batch(
+            &queue,
+            &mut prefill_client,
+            &mut client,
+            &healthy,
+            &crashed,
+            &mut pending_copy,
+            false,
+            None,
+            batch_size_cap(&budget, decode_latency_controller.as_ref()),
+            budget.prefill_tokens(),
+            budget.total_tokens(),
+        )
+        .await
+        {
+            let mut cached_batch = prefill(
+                &mut client,
+                batch,
                 None,
-                max_batch_size,
-                max_batch_prefill_tokens,
-                max_batch_total_tokens,
+                &mut entries,
+                &healthy,
+                &crashed,
+                &mut budget,
+                ttft_controller.as_mut(),
+                drafter.as_mut(),
             )
-            .await
-        {
-            let mut cached_batch = prefill(&mut client, batch, None, &mut entries)
-                .instrument(span)
-                .await;
+            .instrument(span)
+            .await;
             let mut waiting_tokens = 1;
+            // Whether a request was preempted since waiting requests were last admitted
+            let mut preempted = false;
 
             // We loop until we do not receive any cached batch from the inference server (== until
             // all requests have met their stopping criteria)
             while let Some(batch) = cached_batch {
+                let iteration_start = Instant::now();
+                let mut phases = Phases::default();
+                if cancellation.is_cancelled() {
+                    aborted += entries.len();
+                    abort(&mut client, batch, &mut entries, drafter.as_mut()).await;
+                    break;
+                }
                 // Get current batch info
                 let batch_size = batch.size;
                 let batch_max_tokens = batch.max_tokens;
@@ -166,18 +802,24 @@ pub(crate) async fn batching_task(
                 metrics::gauge!("tgi_batch_current_size").set(batch_size as f64);
                 metrics::gauge!("tgi_batch_current_max_tokens").set(batch_max_tokens as f64);
 
-                let token_budget = max_batch_total_tokens.saturating_sub(batch_max_tokens);
+                let token_budget = budget.total_tokens().saturating_sub(batch_max_tokens);
+                let size_cap = batch_size_cap(&budget, decode_latency_controller.as_ref());
 
                 let (min_size, max_size, prefill_token_budget) = if support_chunking {
                     // Since the next batch will be concatenated with the current batch,
                     // the current batch tokens must be subtracted to the prefill budget
                     let prefill_token_budget =
-                        max_batch_prefill_tokens.saturating_sub(current_tokens);
+                        budget.prefill_tokens().saturating_sub(current_tokens);
                     // We can ignore min_size and max_size
                     // Models than rely on max_size cannot support chunking
                     // Regarding min_size, chunking allow us to consistently run at the compute
                     // bound, making min_size useless.
-                    (None, None, prefill_token_budget)
+                    // The decode latency target still caps the batch size.
+                    let max_size = decode_latency_controller
+                        .as_ref()
+                        .and_then(DecodeLatencyController::cap)
+                        .map(|max_size| max_size.saturating_sub(batch_size as usize));
+                    (None, max_size, prefill_token_budget)
                 } else {
                     let min_size = if waiting_tokens >= max_waiting_tokens {
                         // If we didn't onboard any new requests since >= max_waiting_tokens, we try
@@ -187,19 +829,33 @@ pub(crate) async fn batching_task(
                         // Minimum batch size
                         // TODO: temporarily disable to avoid incorrect deallocation +
                         //       reallocation when using prefix caching.
+                        let waiting_served_ratio =
+                            f32::from_bits(waiting_served_ratio.load(Ordering::Relaxed));
                         Some((batch_size as f32 * waiting_served_ratio).floor() as usize)
                     };
 
                     let max_size =
-                        max_batch_size.map(|max_size| max_size.saturating_sub(batch_size as usize));
+                        size_cap.map(|max_size| max_size.saturating_sub(batch_size as usize));
 
-                    (min_size, max_size, max_batch_prefill_tokens)
+                    (min_size, max_size, budget.prefill_tokens())
                 };
 
                 // Try to get a new batch
-                if let Some((mut new_entries, new_batch, span)) = queue
-                    .next_batch(min_size, max_size, prefill_token_budget, token_budget)
-                    .await
+                let admission_start = Instant::now();
+                if let Some((mut new_entries, new_batch, span)) = next_batch(
+                    &queue,
+                    &mut prefill_client,
+                    &mut client,
+                    &healthy,
+                    &crashed,
+                    &mut pending_copy,
+                    true,
+                    min_size,
+                    max_size,
+                    prefill_token_budget,
+                    token_budget,
+                )
+                .await
                 {
                     // Tracking metrics
                     if min_size.is_some() {
@@ -221,10 +877,19 @@ pub(crate) async fn batching_task(
                         // concatenated during the prefill op server side
                         entries.extend(new_entries);
                         // Generate one token for both the cached batch and the new batch
-                        let new_cached_batch =
-                            prefill(&mut client, new_batch, cached_batch, &mut entries)
-                                .instrument(span)
-                                .await;
+                        let new_cached_batch = prefill(
+                            &mut client,
+                            new_batch,
+                            cached_batch,
+                            &mut entries,
+                            &healthy,
+                            &crashed,
+                            &mut budget,
+                            None,
+                            drafter.as_mut(),
+                        )
+                        .instrument(span)
+                        .await;
                         if new_cached_batch.is_none() {
                             // New cached batch is empty, no work left
                             break;
@@ -245,10 +910,19 @@ pub(crate) async fn batching_task(
                         });
 
                         // Generate one token for this new batch to have the attention past in cache
-                        let new_cached_batch =
-                            prefill(&mut client, new_batch, None, &mut new_entries)
-                                .instrument(span)
-                                .await;
+                        let new_cached_batch = prefill(
+                            &mut client,
+                            new_batch,
+                            None,
+                            &mut new_entries,
+                            &healthy,
+                            &crashed,
+                            &mut budget,
+                            ttft_controller.as_mut(),
+                            drafter.as_mut(),
+                        )
+                        .instrument(span)
+                        .await;
                         if new_cached_batch.is_some() {
                             // Extend entries
                             entries.extend(new_entries);
@@ -258,19 +932,46 @@ pub(crate) async fn batching_task(
 
                     // Reset waiting counter
                     waiting_tokens = 1;
+                    preempted = false;
                     // Extend current batch with the new batch
                     if let Some(new_cached_batch) = new_cached_batch {
                         batches.push(new_cached_batch);
                     }
+                } else if let Some(queue_size) =
+                    preemption_queue_size.filter(|_| !preempted && pending_copy.is_none())
+                {
+                    // The waiting requests do not fit: make room for them by preempting the
+                    // longest running request at its next token
+                    if queue.len().await >= queue_size {
+                        if let Some(id) = preemption::victim(&entries) {
+                            tracing::debug!("Preempting request {id}");
+                            let entry = entries.get_mut(&id).expect("ID not found in entries");
+                            if let Some(checkpoint) = entry.checkpoint.as_mut() {
+                                checkpoint.preempted = true;
+                            }
+                            preempted = true;
+                        }
+                    }
                 }
+                phases.prefill = admission_start.elapsed();
 
                 // Create span for this batch to add context to inference calls
                 let next_batch_size = entries.len();
-                let next_batch_span =
-                    info_span!(parent: None, "batch", batch_size = next_batch_size);
-                entries.iter_mut().for_each(|(_, entry)| {
+                let next_batch_id = batches[0].id;
+                let next_batch_span = info_span!(
+                    parent: None,
+                    "batch",
+                    id = next_batch_id,
+                    batch_size = next_batch_size
+                );
+                entries.iter_mut().for_each(|(id, entry)| {
                     // Create a new span to link the batch back to this entry
-                    let entry_batch_span = info_span!(parent: &entry.span, "infer");
+                    let entry_batch_span = info_span!(
+                        parent: &entry.span,
+                        "infer",
+                        id,
+                        batch_id = next_batch_id
+                    );
                     // Add relationships
                     next_batch_span.follows_from(&entry_batch_span);
                     entry_batch_span.follows_from(&next_batch_span);
@@ -278,36 +979,206 @@ pub(crate) async fn batching_task(
                     entry.temp_span = Some(entry_batch_span);
                 });
 
-                cached_batch = decode(&mut client, batches, &mut entries)
-                    .instrument(next_batch_span)
-                    .await;
+                cached_batch = decode(
+                    &mut client,
+                    batches,
+                    &mut entries,
+                    &healthy,
+                    &crashed,
+                    &mut budget,
+                    decode_latency_controller.as_mut(),
+                    drafter.as_mut(),
+                    &mut phases,
+                )
+                .instrument(next_batch_span)
+                .await;
+                iteration_log.record(
+                    &phases,
+                    next_batch_id,
+                    next_batch_size,
+                    iteration_start.elapsed(),
+                );
                 waiting_tokens += 1;
             }
             metrics::gauge!("tgi_batch_current_size").set(0.0);
             metrics::gauge!("tgi_batch_current_max_tokens").set(0.0);
+            // The draft shards hold no batch anymore, they can mirror the next one
+            if let Some(drafter) = drafter.as_mut() {
+                drafter.resume();
+            }
+
+            if crashed.load(Ordering::Relaxed) {
+                // The replica connects a new backend, its clients can retry the queued requests
+                queue.close();
+                return;
+            }
+            if cancellation.is_cancelled() {
+                break;
+            }
         }
     }
+
+    if let Some(copy) = pending_copy.take() {
+        let (mut entries, ..) = copy.finish().await;
+        aborted += entries.len();
+        fail_aborted(&mut entries);
+    }
+
+    // Nothing is left on the shards, the next router does not start from a stale KV cache
+    if let Err(err) = client.clear_cache(None).await {
+        tracing::warn!("Unable to clear the cache of the shards: {err}");
+    }
+    if let Some(prefill_client) = prefill_client.as_mut() {
+        let _ = prefill_client.clear_cache(None).await;
+    }
+    if let Some(drafter) = drafter.as_mut() {
+        drafter.stop().await;
+    }
+    tracing::info!("Batching task stopped, {aborted} running requests aborted");
 }
 
-#[instrument(skip_all)]
+/// Stop the running `batch` once the backend is stopped. Dropping the `entries` frees their
+/// blocks.
+async fn abort(
+    client: &mut ShardedClient,
+    batch: CachedBatch,
+    entries: &mut IntMap<u64, Entry>,
+    drafter: Option<&mut Drafter>,
+) {
+    let _ = client.clear_cache(Some(batch.id)).await;
+    if let Some(drafter) = drafter {
+        drafter.clear(&[batch.id]).await;
+    }
+    fail_aborted(entries);
+}
+
+/// Fail the `entries` of the backend that is stopped
+fn fail_aborted(entries: &mut IntMap<u64, Entry>) {
+    for (_, entry) in entries.drain() {
+        metrics::counter!("tgi_request_failure", "err" => "backend_unavailable").increment(1);
+        let _ = entry.response_tx.send(Err(InferError::BackendUnavailable(
+            "the router is shutting down".to_string(),
+        )));
+    }
+}
+
+/// Next batch of the queue. On a disaggregated replica, the KV of the prompts computed by the
+/// prefill shards is copied first, and the entries of a batch whose copy failed are failed.
+///
+/// While a batch is `running`, the copy is left in `pending_copy` so that the running batch
+/// keeps decoding, and its batch is returned by a later call once it is done. No other batch
+/// is taken from the queue until then.
+#[allow(clippy::too_many_arguments)]
+async fn next_batch(
+    queue: &Queue,
+    prefill_client: &mut Option<ShardedClient>,
+    client: &mut ShardedClient,
+    healthy: &AtomicBool,
+    crashed: &AtomicBool,
+    pending_copy: &mut Option<PendingCopy>,
+    running: bool,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    prefill_token_budget: u32,
+    token_budget: u32,
+) -> Option<(IntMap<u64, Entry>, Batch, Span)> {
+    loop {
+        let copied = match pending_copy.take() {
+            Some(copy) if running && !copy.is_finished() => {
+                *pending_copy = Some(copy);
+                return None;
+            }
+            Some(copy) => Some(copy.finish().await),
+            None => None,
+        };
+        let (mut entries, batch, span, result) = match copied {
+            Some(copied) => copied,
+            None => {
+                let (mut entries, batch, span) = queue
+                    .next_batch(min_size, max_size, prefill_token_budget, token_budget)
+                    .await?;
+                let Some(prefill_client) = prefill_client.as_mut() else {
+                    return Some((entries, batch, span));
+                };
+                if running && needs_copy(&entries) {
+                    *pending_copy = Some(PendingCopy::spawn(
+                        prefill_client.clone(),
+                        client.clone(),
+                        entries,
+                        batch,
+                        span,
+                    ));
+                    return None;
+                }
+                let result = copy_prompt_kv(prefill_client, client, &mut entries)
+                    .instrument(span.clone())
+                    .await;
+                (entries, batch, span, result)
+            }
+        };
+        match result {
+            Ok(()) => return Some((entries, batch, span)),
+            Err(err) => {
+                healthy.store(false, Ordering::Relaxed);
+                let shard_down = is_shard_down(&err);
+                send_errors(err, &mut entries);
+                if shard_down {
+                    crashed.store(true, Ordering::Relaxed);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Prefill a `batch` of new `entries`. The `ttft_controller` records their time to first token.
+/// The `drafter` prefills them on the draft shards at the same time.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(id = batch.id, size = batch.size, generated_tokens))]
 async fn prefill(
     client: &mut ShardedClient,
     batch: Batch,
     cached_batch: Option<CachedBatch>,
     entries: &mut IntMap<u64, Entry>,
+    healthy: &AtomicBool,
+    crashed: &AtomicBool,
+    budget: &mut Budget,
+    ttft_controller: Option<&mut TtftController>,
+    mut drafter: Option<&mut Drafter>,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_id = batch.id;
+    // A chunked prompt is concatenated to the cached batch, under the ID of the latter
+    let next_batch_id = cached_batch.as_ref().map_or(batch_id, |cached| cached.id);
     metrics::counter!("tgi_batch_inference_count", "method" => "prefill").increment(1);
 
-    match client.prefill(batch, cached_batch).await {
+    let result = match drafter.as_deref_mut() {
+        Some(drafter) => {
+            let (result, ()) = tokio::join!(
+                client.prefill(batch.clone(), cached_batch.clone()),
+                drafter.prefill(batch, cached_batch)
+            );
+            result
+        }
+        None => client.prefill(batch, cached_batch).await,
+    };
+    match result {
         Ok((generations, next_batch, timings)) => {
+            Span::current().record("generated_tokens", generated_tokens(&generations));
+            if let Some(ttft_controller) = ttft_controller {
+                ttft_controller.record(entries.values().map(|entry| entry.queue_time.elapsed()));
+            }
             let start_filtering_time = Instant::now();
             // Send generated tokens and filter stopped entries
             filter_send_generations(generations, entries);
+            // Filter entries that did not generate but were dropped by the client
+            filter_dropped_entries(entries);
 
             // Filter next batch and remove requests that were stopped
             let next_batch = filter_batch(client, next_batch, entries).await;
+            if let Some(drafter) = drafter {
+                drafter.filter(next_batch_id, next_batch.as_ref()).await;
+            }
 
             if let Some(concat_duration) = timings.concat {
                 metrics::histogram!("tgi_batch_concat_duration", "method" => "decode")
@@ -327,6 +1198,17 @@ async fn prefill(
         // If we have an error, we discard the whole batch
         Err(err) => {
             let _ = client.clear_cache(Some(batch_id)).await;
+            if let Some(drafter) = drafter {
+                drafter.clear(&[next_batch_id]).await;
+            }
+            if is_out_of_memory(&err) {
+                budget.shrink(sequences(entries));
+            } else {
+                healthy.store(false, Ordering::Relaxed);
+                if is_shard_down(&err) {
+                    crashed.store(true, Ordering::Relaxed);
+                }
+            }
             send_errors(err, entries);
             metrics::counter!("tgi_batch_inference_failure", "method" => "prefill").increment(1);
             None
@@ -334,24 +1216,58 @@ async fn prefill(
     }
 }
 
-#[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(size = entries.len(), generated_tokens))]
 async fn decode(
     client: &mut ShardedClient,
     batches: Vec<CachedBatch>,
     entries: &mut IntMap<u64, Entry>,
+    healthy: &AtomicBool,
+    crashed: &AtomicBool,
+    budget: &mut Budget,
+    decode_latency_controller: Option<&mut DecodeLatencyController>,
+    mut drafter: Option<&mut Drafter>,
+    phases: &mut Phases,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_ids: Vec<u64> = batches.iter().map(|b| b.id).collect();
+    let batch_size = sequences(entries);
+
+    let draft_tokens = match drafter.as_deref_mut() {
+        Some(drafter) => drafter.draft(&batches, entries).await,
+        None => entries
+            .iter()
+            .filter_map(|(&id, entry)| entry.lookup.as_ref()?.draft_tokens(id))
+            .collect(),
+    };
     metrics::counter!("tgi_batch_inference_count", "method" => "decode").increment(1);
 
-    match client.decode(batches).await {
+    let decode_start = Instant::now();
+    let result = client
+        .decode(batches, draft_tokens)
+        .instrument(info_span!("decode_rpc"))
+        .await;
+    phases.decode = decode_start.elapsed();
+    match result {
         Ok((generations, next_batch, timings)) => {
+            Span::current().record("generated_tokens", generated_tokens(&generations));
+            phases.concatenate = timings.concat.unwrap_or_default();
+            phases.forward = timings.forward;
             let start_filtering_time = Instant::now();
             // Send generated tokens and filter stopped entries
-            filter_send_generations(generations, entries);
+            phases.dispatch = filter_send_generations(generations, entries);
+            phases.generations = start_filtering_time.elapsed();
+            let filter_start = Instant::now();
+            // Filter entries that did not generate but were dropped by the client
+            filter_dropped_entries(entries);
 
             // Filter next batch and remove requests that were stopped
             let next_batch = filter_batch(client, next_batch, entries).await;
+            if let Some(drafter) = drafter {
+                // The batches were concatenated under the ID of the first one
+                drafter.filter(batch_ids[0], next_batch.as_ref()).await;
+            }
+            phases.filter = filter_start.elapsed();
 
             if let Some(concat_duration) = timings.concat {
                 metrics::histogram!("tgi_batch_concat_duration", "method" => "decode")
@@ -366,13 +1282,27 @@ async fn decode(
             metrics::histogram!("tgi_batch_inference_duration", "method" => "decode")
                 .record(start_time.elapsed().as_secs_f64());
             metrics::counter!("tgi_batch_inference_success", "method" => "decode").increment(1);
+            if let Some(decode_latency_controller) = decode_latency_controller {
+                decode_latency_controller.record(start_time.elapsed(), batch_size);
+            }
             next_batch
         }
         // If we have an error, we discard the whole batch
         Err(err) => {
-            for id in batch_ids {
+            for &id in &batch_ids {
                 let _ = client.clear_cache(Some(id)).await;
             }
+            if let Some(drafter) = drafter {
+                drafter.clear(&batch_ids).await;
+            }
+            if is_out_of_memory(&err) {
+                budget.shrink(sequences(entries));
+            } else {
+                healthy.store(false, Ordering::Relaxed);
+                if is_shard_down(&err) {
+                    crashed.store(true, Ordering::Relaxed);
+                }
+            }
             send_errors(err, entries);
             metrics::counter!("tgi_batch_inference_failure", "method" => "decode").increment(1);
             None
@@ -380,6 +1310,48 @@ async fn decode(
     }
 }
 
+/// Largest batch to form, under the budget and the cap of the decode latency target
+fn batch_size_cap(
+    budget: &Budget,
+    decode_latency_controller: Option<&DecodeLatencyController>,
+) -> Option<usize> {
+    let cap = decode_latency_controller.and_then(DecodeLatencyController::cap);
+    match (budget.batch_size(), cap) {
+        (Some(batch_size), Some(cap)) => Some(batch_size.min(cap)),
+        (batch_size, cap) => batch_size.or(cap),
+    }
+}
+
+/// Error of the requests refused while the replica connects a new backend
+fn reconnecting() -> InferError {
+    InferError::BackendUnavailable("reconnecting to the shards".to_string())
+}
+
+fn adapter_error(err: ClientError) -> InferError {
+    InferError::AdapterError(err.to_string())
+}
+
+fn adapter_info(adapter: LoraAdapter) -> LoraAdapterInfo {
+    LoraAdapterInfo {
+        id: adapter.id,
+        rank: adapter.rank,
+        memory_bytes: adapter.memory_bytes,
+    }
+}
+
+/// Number of tokens generated by a forward, accepted speculative tokens included
+fn generated_tokens(generations: &[Generation]) -> usize {
+    generations
+        .iter()
+        .map(|generation| {
+            generation
+                .tokens
+                .as_ref()
+                .map_or(0, |tokens| tokens.ids.len())
+        })
+        .sum()
+}
+
 /// Filter a `batch` and remove all requests not present in `entries`
 #[instrument(skip_all)]
 async fn filter_batch(
@@ -390,14 +1362,17 @@ async fn filter_batch(
     let mut batch = next_batch?;
 
     // No need to filter
-    if batch.size as usize == entries.len() {
+    let sequences: usize = entries.values().map(Entry::sequences).sum();
+    if batch.size as usize == sequences {
         return Some(batch);
     }
 
     let id = batch.id;
 
-    // Retain only requests that are still in entries
-    batch.request_ids.retain(|id| entries.contains_key(id));
+    // Retain only requests that are still in entries, with their negative sequences
+    batch
+        .request_ids
+        .retain(|id| entries.contains_key(&(id & !NEGATIVE_REQUEST_BIT)));
 
     if batch.request_ids.is_empty() {
         // All requests have been filtered out
@@ -416,34 +1391,84 @@ async fn filter_batch(
 /// Send one or multiple `InferStreamResponse` to Infer for all `entries`
 /// and filter entries
 #[instrument(skip_all)]
-fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u64, Entry>) {
+fn filter_send_generations(
+    generations: Vec<Generation>,
+    entries: &mut IntMap<u64, Entry>,
+) -> Duration {
+    let batch_size = entries.len();
+    // Time spent sending the responses
+    let mut dispatch = Duration::ZERO;
     generations.into_iter().for_each(|generation| {
         let id = generation.request_id;
         // Get entry
         // We can `expect` here as the request id should always be in the entries
         let entry = entries
-            .get(&id)
+            .get_mut(&id)
             .expect("ID not found in entries. This is a bug.");
+        entry.batches.record(batch_size);
+
+        // The prompt was prefilled, identical prompts can reuse its KV from now on
+        if let Some(block_allocation) = entry.block_allocation.as_mut() {
+            block_allocation.cache_prefill();
+        }
+        if let Some(block_allocation) = entry.negative_block_allocation.as_mut() {
+            block_allocation.cache_prefill();
+        }
+
+        // Sessions keep the KV of the generated tokens for their next turn
+        if let (Some(generated_tokens), Some(tokens)) = (
+            entry
+                .block_allocation
+                .as_mut()
+                .and_then(|block_allocation| block_allocation.generated_tokens.as_mut()),
+            generation.tokens.as_ref(),
+        ) {
+            generated_tokens.extend(&tokens.ids);
+        }
+        if let (Some(lookup), Some(tokens)) = (entry.lookup.as_mut(), generation.tokens.as_ref()) {
+            lookup.extend(&tokens.ids);
+        }
+        if let (Some(draft), Some(tokens)) = (entry.draft.as_mut(), generation.tokens.as_ref()) {
+            draft.extend(&tokens.ids);
+        }
 
         // Create and enter a span to link this function back to the entry
         let _span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered();
         // Send generation responses back to the infer task
         // If the receive an error from the Flume channel, it means that the client dropped the
         // request and we need to stop generating hence why we unwrap_or(true)
+        let dispatch_start = Instant::now();
         let stopped = send_responses(generation, entry).inspect_err(|_err| {
             tracing::error!("Entry response channel error.");
             metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
         }).unwrap_or(true);
+        dispatch += dispatch_start.elapsed();
         if stopped {
             entries.remove(&id).expect("ID not found in entries. This is a bug.");
         }
     });
+    dispatch
+}
+
+/// Remove entries whose client went away, so that they are cancelled on the shards by the
+/// next `filter_batch` call. This catches requests that do not produce generations, e.g.
+/// while their prefill is chunked.
+#[instrument(skip_all)]
+fn filter_dropped_entries(entries: &mut IntMap<u64, Entry>) {
+    entries.retain(|_, entry| {
+        let dropped = entry.response_tx.is_closed();
+        if dropped {
+            tracing::debug!("Dropping entry");
+            metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
+        }
+        !dropped
+    });
 }
 
 /// Send responses through the `entry` response channel
 fn send_responses(
     generation: Generation,
-    entry: &Entry,
+    entry: &mut Entry,
 ) -> Result<bool, Box<SendError<Result<InferStreamResponse, InferError>>>> {
     // Return directly if the channel is disconnected
     if entry.response_tx.is_closed() {
@@ -483,6 +1508,9 @@ fn send_responses(
         .enumerate()
         .peekable();
     while let Some((i, (((id, logprob), text), special))) = iterator.next() {
+        if let Some(checkpoint) = entry.checkpoint.as_mut() {
+            checkpoint.push(id, &text, special);
+        }
         let token = Token {
             id,
             text,
@@ -514,7 +1542,38 @@ fn send_responses(
                 entry.response_tx.send(Ok(InferStreamResponse::End {
                     token,
                     top_tokens,
-                    generated_text: GeneratedText::from(generated_text.clone()),
+                    generated_text: GeneratedText {
+                        batches: entry.batches,
+                        ..GeneratedText::from(generated_text.clone())
+                    },
+                    queued: entry.queue_time,
+                    start: entry.batch_time.unwrap(),
+                }))?;
+            }
+            (None, None) if entry.checkpoint.as_ref().is_some_and(|c| c.preempted) => {
+                // The round ends here, the infer task continues the request in a new one
+                stopped = true;
+                metrics::counter!("tgi_request_preempted").increment(1);
+                let checkpoint = entry.checkpoint.take().expect("checkpoint is None");
+                // Keep the KV of the generated tokens in the prefix cache and in the host memory
+                // for the next round
+                if let Some(block_allocation) = entry.block_allocation.as_mut() {
+                    block_allocation
+                        .generated_tokens
+                        .get_or_insert_with(|| checkpoint.ids.clone());
+                    block_allocation.preempted = true;
+                }
+                let parameters = &entry.request.parameters;
+                entry.response_tx.send(Ok(InferStreamResponse::End {
+                    token,
+                    top_tokens,
+                    generated_text: GeneratedText {
+                        text: checkpoint.text,
+                        generated_tokens: checkpoint.ids.len() as u32,
+                        finish_reason: FinishReason::Length,
+                        seed: parameters.do_sample.then_some(parameters.seed),
+                        batches: entry.batches,
+                    },
                     queued: entry.queue_time,
                     start: entry.batch_time.unwrap(),
                 }))?;
@@ -533,12 +1592,18 @@ fn send_responses(
 
 /// Send errors to Infer for all `entries`
 #[instrument(skip_all)]
-fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
+pub(crate) fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
+    let shard_down = is_shard_down(&error);
     entries.drain().for_each(|(_, entry)| {
         // Create and enter a span to link this function back to the entry
         let _send_error_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_error").entered();
-        let err = InferError::GenerationError(error.to_string());
-        metrics::counter!("tgi_request_failure", "err" => "generation").increment(1);
+        let err = if shard_down {
+            metrics::counter!("tgi_request_failure", "err" => "backend_unavailable").increment(1);
+            InferError::BackendUnavailable(error.to_string())
+        } else {
+            metrics::counter!("tgi_request_failure", "err" => "generation").increment(1);
+            InferError::GenerationError(error.to_string())
+        };
         tracing::error!("{err}");
 
         // unwrap_or is valid here as we don't care if the receiver is gone.
@@ -563,6 +1628,7 @@ impl From<crate::client::GeneratedText> for GeneratedText {
             generated_tokens: value.generated_tokens,
             finish_reason,
             seed: value.seed,
+            batches: BatchResidency::default(),
         }
     }
 }
@@ -1,7 +1,11 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use text_generation_router::{CacheStats, SessionStats};
 use tokio::sync::{mpsc, oneshot};
 
 use crate::radix::RadixAllocator;
+use crate::swap::{SwapSpace, Swaps};
 
 #[derive(Debug, Clone)]
 pub struct BlockAllocation {
@@ -13,17 +17,85 @@ pub struct BlockAllocation {
     /// be recomputed.
     pub prefix_len: u32,
 
+    /// Tokens generated so far. Only tracked for allocations that belong to a
+    /// session, so that their KV can be kept for the next turn.
+    pub generated_tokens: Option<Vec<u32>>,
+
+    /// Whether the KV of the prompt was offered to the prefix cache
+    pub(crate) prefill_cached: bool,
+
+    /// Set when the request is preempted: its blocks are copied to the host memory when they
+    /// are freed, so that its next round copies them back instead of prefilling them again.
+    pub(crate) preempted: bool,
+
+    /// Set when the blocks of the prompt are shared with the allocations of the other
+    /// sequences generated for it
+    pub(crate) share: Option<Share>,
+
     pub(crate) block_allocator: Option<BlockAllocator>,
 }
 
+/// Role of an allocation among the allocations sharing the blocks of a prompt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Share {
+    /// Owns the blocks of the prompt, which are only freed with the last fork
+    Prompt(u64),
+    /// Reads the first `blocks` blocks of the prompt, holding its first `slots` slots, and
+    /// writes to blocks of its own
+    Fork {
+        id: u64,
+        blocks: usize,
+        slots: usize,
+    },
+}
+
+impl BlockAllocation {
+    /// The KV of the prompt is computed: let the allocations that follow with the same prompt
+    /// reuse its blocks instead of prefilling it again.
+    pub(crate) fn cache_prefill(&mut self) {
+        if self.prefill_cached {
+            return;
+        }
+        self.prefill_cached = true;
+        if let Some(block_allocator) = self.block_allocator.as_ref() {
+            block_allocator.cache_prefill(self.blocks.clone(), self.allocation_id);
+        }
+    }
+}
+
 impl Drop for BlockAllocation {
     fn drop(&mut self) {
         if let Some(block_allocator) = self.block_allocator.as_mut() {
-            block_allocator.free(self.blocks.clone(), self.allocation_id)
+            // A fork only frees its own blocks
+            let (shared_blocks, shared_slots) = match self.share {
+                Some(Share::Fork { blocks, slots, .. }) => (blocks, slots),
+                _ => (0, 0),
+            };
+            block_allocator.free(
+                FreedAllocation {
+                    blocks: self.blocks[shared_blocks..].to_vec(),
+                    slots: self.slots.len() - shared_slots,
+                    allocation_id: self.allocation_id,
+                    generated_tokens: self.generated_tokens.take(),
+                    preempted: self.preempted,
+                },
+                self.share,
+            )
         }
     }
 }
 
+/// Maintenance of the blocks while the allocator is idle
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Compaction {
+    /// Time without any allocation or free after which the allocator is compacted. Never
+    /// compacted without it.
+    pub(crate) idle_interval: Option<Duration>,
+    /// Time after which an unused prefix cache entry is released when compacting. The entries
+    /// are otherwise only evicted to make room for new allocations.
+    pub(crate) prefix_ttl: Option<Duration>,
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockAllocator {
     /// Channel to communicate with the background task
@@ -34,8 +106,12 @@ impl BlockAllocator {
     pub(crate) fn new(
         max_batch_total_tokens: u32,
         block_size: u32,
+        kv_bytes_per_token: u64,
         prefix_caching: bool,
         window_size: Option<u32>,
+        session_ttl: Duration,
+        swap_space: Option<SwapSpace>,
+        compaction: Compaction,
     ) -> Self {
         // Create channel
         let (sender, receiver) = mpsc::unbounded_channel();
@@ -43,9 +119,12 @@ impl BlockAllocator {
         // Launch background queue task
         tokio::spawn(block_allocator_task(
             max_batch_total_tokens / block_size,
-            block_size,
+            KvLayout::new(block_size, kv_bytes_per_token),
             prefix_caching,
             window_size,
+            session_ttl,
+            swap_space,
+            compaction,
             receiver,
         ));
 
@@ -58,12 +137,14 @@ impl BlockAllocator {
         &self,
         tokens: u32,
         prefill_tokens: Option<Arc<Vec<u32>>>,
+        session_id: Option<String>,
     ) -> Option<BlockAllocation> {
         let (response_sender, response_receiver) = oneshot::channel();
         self.block_allocator
             .send(BlockAllocatorCommand::Allocate {
                 tokens,
                 prefill_tokens,
+                session_id,
                 response_sender,
             })
             .unwrap();
@@ -74,58 +155,524 @@ impl BlockAllocator {
         })
     }
 
-    pub(crate) fn free(&self, blocks: Vec<u32>, allocation_id: u64) {
+    /// Allocate `tokens` for another sequence of the prompt of `allocation`, which reads the
+    /// blocks of the prompt that are full before its last token instead of computing them
+    /// again. The sequences write to their own blocks from there, so that the shared blocks
+    /// are never copied.
+    pub(crate) async fn fork(
+        &self,
+        allocation: &mut BlockAllocation,
+        tokens: u32,
+        prompt_length: u32,
+    ) -> Option<BlockAllocation> {
+        let share = match allocation.share {
+            Some(Share::Prompt(id)) => Some(id),
+            _ => None,
+        };
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.block_allocator
+            .send(BlockAllocatorCommand::Fork {
+                share,
+                blocks: allocation.blocks.clone(),
+                slots: allocation.slots.clone(),
+                prompt_length,
+                tokens,
+                response_sender,
+            })
+            .unwrap();
+
+        let mut fork = response_receiver.await.unwrap()?;
+        if let Some(Share::Fork { id, .. }) = fork.share {
+            allocation.share = Some(Share::Prompt(id));
+        }
+        fork.block_allocator = Some(self.clone());
+        Some(fork)
+    }
+
+    pub(crate) fn free(&self, allocation: FreedAllocation, share: Option<Share>) {
         self.block_allocator
-            .send(BlockAllocatorCommand::Free {
+            .send(BlockAllocatorCommand::Free { allocation, share })
+            .unwrap();
+    }
+
+    pub(crate) fn cache_prefill(&self, blocks: Vec<u32>, allocation_id: u64) {
+        self.block_allocator
+            .send(BlockAllocatorCommand::CachePrefill {
                 allocation_id,
                 blocks,
             })
             .unwrap();
     }
+
+    pub(crate) async fn session_stats(&self) -> Vec<SessionStats> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.block_allocator
+            .send(BlockAllocatorCommand::SessionStats { response_sender })
+            .unwrap();
+
+        response_receiver.await.unwrap()
+    }
+
+    pub(crate) async fn cache_stats(&self) -> CacheStats {
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.block_allocator
+            .send(BlockAllocatorCommand::CacheStats { response_sender })
+            .unwrap();
+
+        response_receiver.await.unwrap()
+    }
+}
+
+/// Size of the blocks of the KV cache of a model
+#[derive(Debug, Clone, Copy)]
+struct KvLayout {
+    /// Tokens held by a block
+    block_size: u32,
+    /// Bytes of GPU memory of a block over all the shards, unknown if the shards do not report
+    /// the footprint of a token
+    block_bytes: Option<u64>,
+}
+
+impl KvLayout {
+    fn new(block_size: u32, kv_bytes_per_token: u64) -> Self {
+        Self {
+            block_size,
+            block_bytes: (kv_bytes_per_token > 0).then(|| block_size as u64 * kv_bytes_per_token),
+        }
+    }
+
+    /// Move the gauges of the free and allocated blocks, and of their bytes if known
+    fn move_gauges(&self, free_blocks: f64, allocated_blocks: f64) {
+        metrics::gauge!("tgi_kv_free_blocks").increment(free_blocks);
+        metrics::gauge!("tgi_kv_allocated_blocks").increment(allocated_blocks);
+        if let Some(block_bytes) = self.block_bytes {
+            let block_bytes = block_bytes as f64;
+            metrics::gauge!("tgi_kv_free_bytes").increment(free_blocks * block_bytes);
+            metrics::gauge!("tgi_kv_allocated_bytes").increment(allocated_blocks * block_bytes);
+        }
+    }
+}
+
+/// Usage of the blocks by the allocations, tracked for every allocator
+#[derive(Debug, Default)]
+struct AllocationUsage {
+    /// Blocks of the allocations that were not freed yet, counted once per allocation
+    blocks: u64,
+    /// Slots of the allocations that were not freed yet
+    slots: u64,
+    /// Prompt tokens of all the allocations
+    prompt_tokens: u64,
+    /// Prompt tokens of all the allocations that were found in the prefix cache
+    prefix_tokens: u64,
+}
+
+impl AllocationUsage {
+    fn allocate(&mut self, allocation: &BlockAllocation, prompt_tokens: Option<usize>) {
+        self.blocks += allocation.blocks.len() as u64;
+        self.slots += allocation.slots.len() as u64;
+        if let Some(prompt_tokens) = prompt_tokens {
+            self.prompt_tokens += prompt_tokens as u64;
+            self.prefix_tokens += allocation.prefix_len as u64;
+        }
+    }
+
+    fn free(&mut self, blocks: usize, slots: usize) {
+        self.blocks -= blocks as u64;
+        self.slots -= slots as u64;
+    }
+
+    fn stats(
+        &self,
+        allocator: &dyn Allocator,
+        blocks: u32,
+        layout: KvLayout,
+        prefix_caching: bool,
+    ) -> CacheStats {
+        let block_size = layout.block_size;
+        // Block 0 is reserved for health checks
+        let total_blocks = blocks.saturating_sub(1);
+        let free_blocks = allocator.free_blocks() as u32;
+        let cached_blocks = allocator.cached_blocks() as u32;
+        let allocated_slots = self.blocks * block_size as u64;
+        let fragmentation = if allocated_slots == 0 {
+            0.0
+        } else {
+            // Windowed allocations reuse their blocks for more slots than they hold
+            allocated_slots.saturating_sub(self.slots) as f32 / allocated_slots as f32
+        };
+        CacheStats {
+            // Set by the replicas
+            model: String::new(),
+            replica: 0,
+            block_size,
+            block_bytes: layout.block_bytes,
+            total_blocks,
+            free_blocks,
+            pinned_blocks: total_blocks.saturating_sub(free_blocks + cached_blocks),
+            cached_blocks,
+            fragmentation,
+            prefix_cache_hit_rate: (prefix_caching && self.prompt_tokens > 0)
+                .then(|| self.prefix_tokens as f32 / self.prompt_tokens as f32),
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn block_allocator_task(
     blocks: u32,
-    block_size: u32,
+    layout: KvLayout,
     prefix_caching: bool,
     window_size: Option<u32>,
+    session_ttl: Duration,
+    mut swap_space: Option<SwapSpace>,
+    compaction: Compaction,
     mut receiver: mpsc::UnboundedReceiver<BlockAllocatorCommand>,
 ) {
+    let block_size = layout.block_size;
     let mut allocator: Box<dyn Allocator + Send> = if prefix_caching {
-        Box::new(RadixAllocator::new(block_size, blocks, window_size))
+        Box::new(RadixAllocator::new(
+            block_size,
+            blocks,
+            window_size,
+            session_ttl,
+            swap_space
+                .as_ref()
+                .map_or(0, |swap_space| swap_space.blocks),
+        ))
     } else {
         Box::new(SimpleAllocator::new(blocks, block_size, window_size))
     };
-    while let Some(cmd) = receiver.recv().await {
+    let mut usage = AllocationUsage::default();
+    // Prompts whose blocks are shared with forks, by id
+    let mut shared_prompts: HashMap<u64, SharedPrompt> = HashMap::new();
+    let mut next_shared_prompt = 0;
+    // Block 0 is reserved for health checks
+    let total_blocks = blocks.saturating_sub(1) as usize;
+    // Free blocks reported by the gauges. The gauges are shared by the allocators of all the
+    // replicas, so they are moved by the changes of this allocator instead of being set.
+    let mut free_blocks = 0;
+    layout.move_gauges(0.0, total_blocks as f64);
+    update_block_gauges(allocator.as_ref(), layout, &mut free_blocks);
+    // Whether the blocks changed since the last compaction
+    let mut compaction_pending = false;
+    loop {
+        let cmd = match compaction.idle_interval {
+            Some(idle_interval) if compaction_pending => {
+                match tokio::time::timeout(idle_interval, receiver.recv()).await {
+                    Ok(cmd) => cmd,
+                    Err(_) => {
+                        compact(allocator.as_mut(), compaction.prefix_ttl);
+                        update_block_gauges(allocator.as_ref(), layout, &mut free_blocks);
+                        // The cached blocks left expire later
+                        compaction_pending =
+                            compaction.prefix_ttl.is_some() && allocator.cached_blocks() > 0;
+                        continue;
+                    }
+                }
+            }
+            _ => receiver.recv().await,
+        };
+        let Some(cmd) = cmd else {
+            // The replica is gone, its blocks no longer count
+            layout.move_gauges(
+                -(free_blocks as f64),
+                -(total_blocks.saturating_sub(free_blocks) as f64),
+            );
+            break;
+        };
+        if !matches!(
+            cmd,
+            BlockAllocatorCommand::SessionStats { .. } | BlockAllocatorCommand::CacheStats { .. }
+        ) {
+            compaction_pending = true;
+        }
         match cmd {
-            BlockAllocatorCommand::Free {
+            BlockAllocatorCommand::Free { allocation, share } => {
+                let allocations = match share {
+                    None => vec![allocation],
+                    Some(Share::Prompt(id)) => {
+                        let shared_prompt = shared_prompts.entry(id).or_default();
+                        if shared_prompt.forks > 0 {
+                            shared_prompt.freed = Some(allocation);
+                            Vec::new()
+                        } else {
+                            shared_prompts.remove(&id);
+                            vec![allocation]
+                        }
+                    }
+                    Some(Share::Fork { id, .. }) => {
+                        let mut allocations = vec![allocation];
+                        if let Some(shared_prompt) = shared_prompts.get_mut(&id) {
+                            shared_prompt.forks -= 1;
+                            if shared_prompt.forks == 0 && shared_prompt.freed.is_some() {
+                                allocations.extend(shared_prompts.remove(&id).unwrap().freed);
+                            }
+                        }
+                        allocations
+                    }
+                };
+                for allocation in allocations {
+                    free(allocator.as_mut(), &mut usage, allocation);
+                }
+            }
+            BlockAllocatorCommand::Fork {
+                share,
+                blocks: prompt_blocks,
+                slots: prompt_slots,
+                prompt_length,
+                tokens,
+                response_sender,
+            } => {
+                // The windowed allocations reuse their blocks, that cannot be shared
+                let fork = window_size.is_none().then(|| {
+                    let shared_blocks = (prompt_length.saturating_sub(1) / block_size) as usize;
+                    let shared_blocks = shared_blocks.min(prompt_blocks.len());
+                    let shared_slots =
+                        (shared_blocks * block_size as usize).min(prompt_slots.len());
+                    let mut fork =
+                        allocator.allocate(tokens.saturating_sub(shared_slots as u32), None)?;
+                    usage.allocate(&fork, None);
+                    let id = share.unwrap_or_else(|| {
+                        next_shared_prompt += 1;
+                        next_shared_prompt
+                    });
+                    shared_prompts.entry(id).or_default().forks += 1;
+
+                    fork.blocks = [&prompt_blocks[..shared_blocks], &fork.blocks].concat();
+                    fork.slots = [&prompt_slots[..shared_slots], &fork.slots].concat();
+                    fork.prefix_len = shared_slots as u32;
+                    // Nothing of the prompt to offer to the prefix cache
+                    fork.prefill_cached = true;
+                    fork.share = Some(Share::Fork {
+                        id,
+                        blocks: shared_blocks,
+                        slots: shared_slots,
+                    });
+                    Some(fork)
+                });
+                let fork = fork.flatten();
+                if fork.is_none() {
+                    metrics::counter!("tgi_kv_allocation_failure").increment(1);
+                }
+                response_sender.send(fork).unwrap();
+            }
+            BlockAllocatorCommand::CachePrefill {
                 blocks,
                 allocation_id,
-            } => allocator.free(blocks, allocation_id),
+            } => allocator.cache_prefill(&blocks, allocation_id),
             BlockAllocatorCommand::Allocate {
                 tokens,
                 prefill_tokens,
+                session_id,
                 response_sender,
             } => {
-                response_sender
-                    .send(allocator.allocate(tokens, prefill_tokens))
-                    .unwrap();
+                let prompt_tokens = prefill_tokens.as_ref().map(|tokens| tokens.len());
+                let mut allocation = match session_id {
+                    None => allocator.allocate(tokens, prefill_tokens),
+                    Some(session_id) => allocator
+                        .allocate_session(tokens, prefill_tokens, session_id)
+                        .map(|mut allocation| {
+                            allocation.generated_tokens = Some(Vec::new());
+                            allocation
+                        }),
+                };
+                if let Some(swap_space) = swap_space.as_mut() {
+                    let swaps = allocator.take_swaps();
+                    if !swaps.is_empty() {
+                        run_swaps(
+                            swap_space,
+                            allocator.as_mut(),
+                            swaps,
+                            block_size,
+                            &mut allocation,
+                        )
+                        .await;
+                    }
+                }
+                match allocation.as_ref() {
+                    Some(allocation) => {
+                        usage.allocate(allocation, prompt_tokens);
+                        record_allocation(allocation, prompt_tokens, prefix_caching);
+                    }
+                    None => metrics::counter!("tgi_kv_allocation_failure").increment(1),
+                }
+                let evicted_blocks = allocator.take_evicted_blocks();
+                if evicted_blocks > 0 {
+                    metrics::counter!("tgi_prefix_cache_evicted_blocks")
+                        .increment(evicted_blocks as u64);
+                }
+                response_sender.send(allocation).unwrap();
+            }
+            BlockAllocatorCommand::SessionStats { response_sender } => {
+                response_sender.send(allocator.session_stats()).unwrap();
+            }
+            BlockAllocatorCommand::CacheStats { response_sender } => {
+                let stats = usage.stats(allocator.as_ref(), blocks, layout, prefix_caching);
+                response_sender.send(stats).unwrap();
+            }
+        }
+        update_block_gauges(allocator.as_ref(), layout, &mut free_blocks);
+    }
+}
+
+/// Free the blocks of an allocation, keeping the KV of a session for its next turn
+fn free(
+    allocator: &mut (dyn Allocator + Send),
+    usage: &mut AllocationUsage,
+    allocation: FreedAllocation,
+) {
+    let FreedAllocation {
+        blocks,
+        slots,
+        allocation_id,
+        generated_tokens,
+        preempted,
+    } = allocation;
+    usage.free(blocks.len(), slots);
+    match generated_tokens {
+        None => allocator.free(blocks, allocation_id),
+        Some(generated_tokens) => {
+            if preempted {
+                allocator.swap_out_preempted(&blocks, allocation_id, &generated_tokens);
+            }
+            allocator.free_session(blocks, allocation_id, generated_tokens)
+        }
+    }
+}
+
+/// Move the block gauges by the blocks that were allocated or freed since `free_blocks`
+fn update_block_gauges(allocator: &dyn Allocator, layout: KvLayout, free_blocks: &mut usize) {
+    let current = allocator.free_blocks();
+    if current == *free_blocks {
+        return;
+    }
+    let delta = current as f64 - *free_blocks as f64;
+    layout.move_gauges(delta, -delta);
+    *free_blocks = current;
+}
+
+/// Footprint of a new allocation, and whether its prompt was found in the prefix cache
+fn record_allocation(
+    allocation: &BlockAllocation,
+    prompt_tokens: Option<usize>,
+    prefix_caching: bool,
+) {
+    metrics::histogram!("tgi_request_blocks").record(allocation.blocks.len() as f64);
+    if prefix_caching && prompt_tokens.is_some() {
+        if allocation.prefix_len > 0 {
+            metrics::counter!("tgi_prefix_cache_hits").increment(1);
+            metrics::counter!("tgi_prefix_cache_hit_tokens")
+                .increment(allocation.prefix_len as u64);
+        } else {
+            metrics::counter!("tgi_prefix_cache_misses").increment(1);
+        }
+    }
+}
+
+fn compact(allocator: &mut (dyn Allocator + Send), prefix_ttl: Option<Duration>) {
+    let start = Instant::now();
+    let released_blocks = allocator.compact(prefix_ttl);
+    tracing::debug!(
+        "Compacted KV cache blocks in {:?}, released {released_blocks} cached block(s)",
+        start.elapsed()
+    );
+    metrics::counter!("tgi_kv_compaction_runs").increment(1);
+    metrics::counter!("tgi_kv_compaction_released_blocks").increment(released_blocks as u64);
+}
+
+/// Copy the blocks of an allocation on the shards before it is used, after the blocks of the
+/// preempted allocations freed since the last one. The allocator waits for the copies, so that
+/// no later allocation reuses the blocks while they are being read.
+async fn run_swaps(
+    swap_space: &mut SwapSpace,
+    allocator: &mut (dyn Allocator + Send),
+    swaps: Swaps,
+    block_size: u32,
+    allocation: &mut Option<BlockAllocation>,
+) {
+    let swap_out = swaps.swap_out.len();
+    let swap_in = swaps.swap_in.len();
+    let mut result = Ok(());
+    if swap_out > 0 {
+        result = swap_space.client.swap_out(swaps.swap_out.clone()).await;
+    }
+    if result.is_ok() && swap_in > 0 {
+        result = swap_space.client.swap_in(swaps.swap_in.clone()).await;
+    }
+
+    match result {
+        Ok(()) => {
+            metrics::counter!("tgi_kv_swap_out_blocks").increment(swap_out as u64);
+            metrics::counter!("tgi_kv_swap_in_blocks").increment(swap_in as u64);
+        }
+        Err(err) => {
+            tracing::error!("Failed to swap KV cache blocks: {err}");
+            metrics::counter!("tgi_kv_swap_failure").increment(1);
+            // The content of the host blocks is unknown, and the restored prefix has to be
+            // prefilled again
+            let host_blocks: Vec<u32> = swaps
+                .swap_out
+                .iter()
+                .chain(&swaps.swap_in)
+                .map(|swap| swap.host_block)
+                .collect();
+            allocator.forget_swapped(&host_blocks);
+            if let Some(allocation) = allocation.as_mut() {
+                allocation.prefix_len -= swap_in as u32 * block_size;
             }
         }
     }
 }
 
+/// Blocks released by an allocation
+#[derive(Debug)]
+pub(crate) struct FreedAllocation {
+    blocks: Vec<u32>,
+    slots: usize,
+    allocation_id: u64,
+    generated_tokens: Option<Vec<u32>>,
+    preempted: bool,
+}
+
+/// Allocations sharing the blocks of a prompt
+#[derive(Debug, Default)]
+struct SharedPrompt {
+    /// Forks not freed yet
+    forks: usize,
+    /// Blocks of the prompt, once its allocation was freed before its forks
+    freed: Option<FreedAllocation>,
+}
+
 #[derive(Debug)]
 enum BlockAllocatorCommand {
     Free {
+        allocation: FreedAllocation,
+        share: Option<Share>,
+    },
+    Fork {
+        share: Option<u64>,
+        blocks: Vec<u32>,
+        slots: Vec<u32>,
+        prompt_length: u32,
+        tokens: u32,
+        response_sender: oneshot::Sender<Option<BlockAllocation>>,
+    },
+    CachePrefill {
         blocks: Vec<u32>,
         allocation_id: u64,
     },
     Allocate {
         tokens: u32,
         prefill_tokens: Option<Arc<Vec<u32>>>,
+        session_id: Option<String>,
         response_sender: oneshot::Sender<Option<BlockAllocation>>,
     },
+    SessionStats {
+        response_sender: oneshot::Sender<Vec<SessionStats>>,
+    },
+    CacheStats {
+        response_sender: oneshot::Sender<CacheStats>,
+    },
 }
 
 pub trait Allocator {
@@ -136,6 +683,81 @@ pub trait Allocator {
     ) -> Option<BlockAllocation>;
 
     fn free(&mut self, blocks: Vec<u32>, allocation_id: u64);
+
+    /// Number of blocks holding nothing.
+    fn free_blocks(&self) -> usize;
+
+    /// Number of blocks only held by the prefix cache, which are reclaimed on demand.
+    ///
+    /// Allocators without a prefix cache hold no block once it is freed.
+    fn cached_blocks(&self) -> usize {
+        0
+    }
+
+    /// Allocate blocks for a request that belongs to a session.
+    ///
+    /// Allocators without a prefix cache have nothing to keep between the turns
+    /// of a session and fall back to a regular allocation.
+    fn allocate_session(
+        &mut self,
+        tokens: u32,
+        prefill_tokens: Option<Arc<Vec<u32>>>,
+        _session_id: String,
+    ) -> Option<BlockAllocation> {
+        self.allocate(tokens, prefill_tokens)
+    }
+
+    /// Free the blocks of a session allocation. `generated_tokens` are the tokens
+    /// generated by the request, which follow the prefill tokens in `blocks`.
+    fn free_session(&mut self, blocks: Vec<u32>, allocation_id: u64, _generated_tokens: Vec<u32>) {
+        self.free(blocks, allocation_id)
+    }
+
+    /// Make the computed prompt of an allocation available to the allocations that follow.
+    ///
+    /// Allocators without a prefix cache never share blocks.
+    fn cache_prefill(&mut self, _blocks: &[u32], _allocation_id: u64) {}
+
+    fn session_stats(&self) -> Vec<SessionStats> {
+        Vec::new()
+    }
+
+    /// Take the block copies that the shards must run before the last allocation is used.
+    ///
+    /// Allocators without a host memory tier never copy blocks.
+    fn take_swaps(&mut self) -> Swaps {
+        Swaps::default()
+    }
+
+    /// Drop host blocks whose copy failed.
+    fn forget_swapped(&mut self, _host_blocks: &[u32]) {}
+
+    /// Copy the blocks of a preempted allocation to the host memory before they are freed.
+    /// The copies run with the next allocation, before its blocks are used.
+    ///
+    /// Allocators without a host memory tier let the next round prefill the KV again.
+    fn swap_out_preempted(
+        &mut self,
+        _blocks: &[u32],
+        _allocation_id: u64,
+        _generated_tokens: &[u32],
+    ) {
+    }
+
+    /// Take the number of prefix cache blocks evicted to make room for the allocations since
+    /// the last call.
+    ///
+    /// Allocators without a prefix cache never evict blocks.
+    fn take_evicted_blocks(&mut self) -> usize {
+        0
+    }
+
+    /// Sort the free blocks as they are at startup, so that the blocks in use stay packed
+    /// instead of being scattered by the order of the frees, and release the prefix cache
+    /// entries unused for `prefix_ttl`.
+    ///
+    /// Returns the number of released blocks.
+    fn compact(&mut self, prefix_ttl: Option<Duration>) -> usize;
 }
 pub struct SimpleAllocator {
     free_blocks: Vec<u32>,
@@ -198,6 +820,10 @@ impl Allocator for SimpleAllocator {
                 blocks,
                 slots,
                 prefix_len: 0,
+                generated_tokens: None,
+                prefill_cached: false,
+                preempted: false,
+                share: None,
                 block_allocator: None,
             })
         }
@@ -206,4 +832,13 @@ impl Allocator for SimpleAllocator {
     fn free(&mut self, blocks: Vec<u32>, _allocation_id: u64) {
         self.free_blocks.extend(blocks)
     }
+
+    fn free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    fn compact(&mut self, _prefix_ttl: Option<Duration>) -> usize {
+        self.free_blocks.sort_unstable();
+        0
+    }
 }
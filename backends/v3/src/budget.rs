@@ -0,0 +1,163 @@
+use crate::client::ClientError;
+use std::time::{Duration, Instant};
+
+/// Lowest fraction of the limits the budget can shrink to
+const MIN_SCALE: f64 = 1.0 / 16.0;
+/// Time for a shrunk budget to grow back from zero to the full limits
+const RECOVERY_TIME: Duration = Duration::from_secs(120);
+
+/// Limits of the batches formed by the batching task.
+///
+/// The limits are halved every time the shards run out of memory on a batch and grow back
+/// linearly with time. The batch size is capped relative to the batch that failed.
+#[derive(Debug)]
+pub(crate) struct Budget {
+    max_batch_prefill_tokens: u32,
+    max_batch_total_tokens: u32,
+    max_batch_size: Option<usize>,
+    /// Fraction of the limits right after the last out of memory error
+    scale: f64,
+    /// Size of the largest batch allowed when the last out of memory error happened
+    oom_batch_size: usize,
+    /// Instant of the last out of memory error
+    oom_time: Option<Instant>,
+}
+
+impl Budget {
+    pub(crate) fn new(
+        max_batch_prefill_tokens: u32,
+        max_batch_total_tokens: u32,
+        max_batch_size: Option<usize>,
+    ) -> Self {
+        Self {
+            max_batch_prefill_tokens,
+            max_batch_total_tokens,
+            max_batch_size,
+            scale: 1.0,
+            oom_batch_size: 0,
+            oom_time: None,
+        }
+    }
+
+    /// Current fraction of the limits
+    fn scale(&self) -> f64 {
+        match self.oom_time {
+            None => 1.0,
+            Some(oom_time) => {
+                let recovered = oom_time.elapsed().as_secs_f64() / RECOVERY_TIME.as_secs_f64();
+                (self.scale + recovered).min(1.0)
+            }
+        }
+    }
+
+    /// Whether the limits are still below the configured ones
+    pub(crate) fn is_reduced(&self) -> bool {
+        self.scale() < 1.0
+    }
+
+    pub(crate) fn prefill_tokens(&self) -> u32 {
+        ((self.max_batch_prefill_tokens as f64 * self.scale()) as u32).max(1)
+    }
+
+    pub(crate) fn total_tokens(&self) -> u32 {
+        ((self.max_batch_total_tokens as f64 * self.scale()) as u32).max(1)
+    }
+
+    pub(crate) fn batch_size(&self) -> Option<usize> {
+        let scale = self.scale();
+        if scale >= 1.0 {
+            return self.max_batch_size;
+        }
+        let batch_size = ((self.oom_batch_size as f64 * scale) as usize).max(1);
+        Some(
+            self.max_batch_size
+                .map_or(batch_size, |max_batch_size| max_batch_size.min(batch_size)),
+        )
+    }
+
+    /// The shards ran out of memory on a batch of `batch_size` requests
+    pub(crate) fn shrink(&mut self, batch_size: usize) {
+        let oom_batch_size = self
+            .batch_size()
+            .map_or(batch_size, |max_batch_size| max_batch_size.min(batch_size));
+        self.scale = (self.scale() / 2.0).max(MIN_SCALE);
+        self.oom_batch_size = oom_batch_size.max(1);
+        self.oom_time = Some(Instant::now());
+
+        metrics::counter!("tgi_batch_out_of_memory").increment(1);
+        tracing::warn!(
+            "Shards ran out of memory on a batch of {batch_size} requests. \
+            Reducing the batch limits to {:.0}%",
+            self.scale * 100.0
+        );
+    }
+}
+
+/// Whether the shards failed because they could not allocate memory
+pub(crate) fn is_out_of_memory(err: &ClientError) -> bool {
+    match err {
+        ClientError::Generation(message) => message.to_lowercase().contains("out of memory"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_shrink() {
+        let mut budget = Budget::new(4096, 16384, None);
+        assert!(!budget.is_reduced());
+        assert_eq!(budget.batch_size(), None);
+
+        budget.shrink(32);
+        assert!(budget.is_reduced());
+        assert_eq!(budget.prefill_tokens(), 2048);
+        assert_eq!(budget.total_tokens(), 8192);
+        assert_eq!(budget.batch_size(), Some(16));
+
+        budget.shrink(16);
+        assert_eq!(budget.prefill_tokens(), 1024);
+        assert_eq!(budget.batch_size(), Some(4));
+
+        for _ in 0..8 {
+            budget.shrink(1);
+        }
+        assert_eq!(budget.prefill_tokens(), 256);
+        assert_eq!(budget.batch_size(), Some(1));
+    }
+
+    #[test]
+    fn test_budget_max_batch_size() {
+        let mut budget = Budget::new(4096, 16384, Some(8));
+        budget.shrink(64);
+        assert_eq!(budget.batch_size(), Some(4));
+    }
+
+    #[test]
+    fn test_budget_recovery() {
+        let mut budget = Budget::new(4096, 16384, Some(8));
+        budget.shrink(8);
+
+        budget.oom_time = Instant::now().checked_sub(RECOVERY_TIME / 4);
+        assert!(budget.is_reduced());
+        assert_eq!(budget.prefill_tokens(), 3072);
+
+        budget.oom_time = Instant::now().checked_sub(RECOVERY_TIME / 2);
+        assert!(!budget.is_reduced());
+        assert_eq!(budget.prefill_tokens(), 4096);
+        assert_eq!(budget.batch_size(), Some(8));
+    }
+
+    #[test]
+    fn test_is_out_of_memory() {
+        assert!(is_out_of_memory(&ClientError::Generation(
+            "CUDA out of memory. Tried to allocate 2.00 GiB".to_string()
+        )));
+        assert!(!is_out_of_memory(&ClientError::Generation(
+            "Unexpected <class 'KeyError'>".to_string()
+        )));
+        assert!(!is_out_of_memory(&ClientError::EmptyResults));
+    }
+}
@@ -0,0 +1,200 @@
+/// Shard answering the calls of the router without a model, for the tests of the connection
+/// and of the warmup of the shards
+use crate::client::pb::generate::v3::text_generation_service_server::{
+    TextGenerationService, TextGenerationServiceServer,
+};
+use crate::client::pb::generate::v3::*;
+use futures::Stream;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Model of a fake shard
+#[derive(Clone, Debug)]
+pub(crate) struct FakeModel {
+    pub(crate) vocab_size: u32,
+    /// Limits of the requests given by the warmup when the router does not set them
+    pub(crate) max_input_tokens: u32,
+    pub(crate) max_total_tokens: u32,
+}
+
+struct FakeShardService {
+    uds_path: String,
+    model: FakeModel,
+    warmups: Arc<AtomicUsize>,
+}
+
+/// Single shard listening on a socket until it is dropped
+pub(crate) struct FakeShard {
+    pub(crate) uds_path: String,
+    warmups: Arc<AtomicUsize>,
+    shutdown: CancellationToken,
+}
+
+impl FakeShard {
+    pub(crate) fn start(name: &str, model: FakeModel) -> Self {
+        let uds_path: PathBuf =
+            std::env::temp_dir().join(format!("tgi-fake-shard-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&uds_path);
+        let listener = UnixListener::bind(&uds_path).unwrap();
+        let uds_path = uds_path.to_string_lossy().into_owned();
+        let warmups = Arc::new(AtomicUsize::new(0));
+        let service = FakeShardService {
+            uds_path: uds_path.clone(),
+            model,
+            warmups: warmups.clone(),
+        };
+        let shutdown = CancellationToken::new();
+        let incoming = async_stream::stream! {
+            loop {
+                yield listener.accept().await.map(|(stream, _)| stream);
+            }
+        };
+        let server = tonic::transport::Server::builder()
+            .add_service(TextGenerationServiceServer::new(service))
+            .serve_with_incoming_shutdown(incoming, {
+                let shutdown = shutdown.clone();
+                async move { shutdown.cancelled().await }
+            });
+        tokio::spawn(server);
+        Self {
+            uds_path,
+            warmups,
+            shutdown,
+        }
+    }
+
+    /// Number of warmups run by the router
+    pub(crate) fn warmups(&self) -> usize {
+        self.warmups.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for FakeShard {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        let _ = std::fs::remove_file(&self.uds_path);
+    }
+}
+
+type KvStream = Pin<Box<dyn Stream<Item = Result<KvChunk, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl TextGenerationService for FakeShardService {
+    async fn info(&self, _: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
+        Ok(Response::new(InfoResponse {
+            dtype: "torch.float16".to_string(),
+            device_type: "cuda".to_string(),
+            attention_impl: "paged".to_string(),
+            block_size: 16,
+            vocab_size: self.model.vocab_size,
+            ..Default::default()
+        }))
+    }
+
+    async fn service_discovery(
+        &self,
+        _: Request<ServiceDiscoveryRequest>,
+    ) -> Result<Response<ServiceDiscoveryResponse>, Status> {
+        Ok(Response::new(ServiceDiscoveryResponse {
+            urls: vec![format!("unix://{}", self.uds_path)],
+        }))
+    }
+
+    async fn clear_cache(
+        &self,
+        _: Request<ClearCacheRequest>,
+    ) -> Result<Response<ClearCacheResponse>, Status> {
+        Ok(Response::new(ClearCacheResponse {}))
+    }
+
+    async fn filter_batch(
+        &self,
+        _: Request<FilterBatchRequest>,
+    ) -> Result<Response<FilterBatchResponse>, Status> {
+        Err(Status::unimplemented("filter_batch"))
+    }
+
+    async fn warmup(
+        &self,
+        request: Request<WarmupRequest>,
+    ) -> Result<Response<WarmupResponse>, Status> {
+        self.warmups.fetch_add(1, Ordering::SeqCst);
+        let request = request.into_inner();
+        let max_total_tokens = request
+            .max_total_tokens
+            .unwrap_or(self.model.max_total_tokens);
+        Ok(Response::new(WarmupResponse {
+            max_supported_total_tokens: Some(max_total_tokens.max(request.max_prefill_tokens)),
+            max_input_tokens: request
+                .max_input_tokens
+                .unwrap_or(self.model.max_input_tokens),
+            max_total_tokens,
+        }))
+    }
+
+    async fn prefill(
+        &self,
+        _: Request<PrefillRequest>,
+    ) -> Result<Response<PrefillResponse>, Status> {
+        Err(Status::unimplemented("prefill"))
+    }
+
+    async fn decode(&self, _: Request<DecodeRequest>) -> Result<Response<DecodeResponse>, Status> {
+        Err(Status::unimplemented("decode"))
+    }
+
+    async fn draft(&self, _: Request<DraftRequest>) -> Result<Response<DraftResponse>, Status> {
+        Err(Status::unimplemented("draft"))
+    }
+
+    async fn health(&self, _: Request<HealthRequest>) -> Result<Response<HealthResponse>, Status> {
+        Ok(Response::new(HealthResponse {}))
+    }
+
+    async fn embed(&self, _: Request<EmbedRequest>) -> Result<Response<EmbedResponse>, Status> {
+        Err(Status::unimplemented("embed"))
+    }
+
+    async fn swap_out(&self, _: Request<SwapRequest>) -> Result<Response<SwapResponse>, Status> {
+        Err(Status::unimplemented("swap_out"))
+    }
+
+    async fn swap_in(&self, _: Request<SwapRequest>) -> Result<Response<SwapResponse>, Status> {
+        Err(Status::unimplemented("swap_in"))
+    }
+
+    type ExportKvStream = KvStream;
+
+    async fn export_kv(
+        &self,
+        _: Request<ExportKvRequest>,
+    ) -> Result<Response<Self::ExportKvStream>, Status> {
+        Err(Status::unimplemented("export_kv"))
+    }
+
+    async fn import_kv(
+        &self,
+        _: Request<Streaming<ImportKvRequest>>,
+    ) -> Result<Response<ImportKvResponse>, Status> {
+        Err(Status::unimplemented("import_kv"))
+    }
+
+    async fn load_adapter(
+        &self,
+        _: Request<LoadAdapterRequest>,
+    ) -> Result<Response<LoadAdapterResponse>, Status> {
+        Err(Status::unimplemented("load_adapter"))
+    }
+
+    async fn unload_adapter(
+        &self,
+        _: Request<UnloadAdapterRequest>,
+    ) -> Result<Response<UnloadAdapterResponse>, Status> {
+        Err(Status::unimplemented("unload_adapter"))
+    }
+}
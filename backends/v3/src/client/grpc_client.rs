@@ -3,14 +3,21 @@ use crate::client::{pb, Chunk};
 use crate::client::{ClientError, Result, WARMUP_IMAGE_BASE64};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use futures::future::try_join;
 use grpc_metadata::InjectTelemetryContext;
 use pb::generate::v3::text_generation_service_client::TextGenerationServiceClient;
 use pb::generate::v3::*;
 use std::cmp::min;
 use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
 use tonic::transport::{Channel, Uri};
 use tracing::instrument;
 
+/// Chunks of an export waiting for the import on the other shard
+const KV_CHUNK_BUFFER: usize = 8;
+
 /// Text Generation Inference gRPC client
 #[derive(Debug, Clone)]
 pub struct Client {
@@ -104,7 +111,9 @@ impl Client {
 
     /// Warmup on a max size batch
     ///
-    /// Returns the maximum amount of tokens supported by the hardware
+    /// Returns the maximum amount of tokens supported by the hardware.
+    /// If `max_batch_total_tokens` is set, the shard skips memory probing and allocates the KV
+    /// cache for this number of tokens instead.
     #[instrument(skip_all)]
     pub async fn warmup(
         &mut self,
@@ -112,6 +121,7 @@ impl Client {
         max_prefill_tokens: u32,
         max_total_tokens: Option<u32>,
         max_batch_size: Option<usize>,
+        max_batch_total_tokens: Option<u32>,
     ) -> Result<(Option<u32>, u32, u32)> {
         let mut n_tokens = 0;
         let mut requests = Vec::new();
@@ -130,6 +140,8 @@ impl Client {
                         // Safe unwrap, because we control the data.
                         data: STANDARD.decode(WARMUP_IMAGE_BASE64).unwrap(),
                         mimetype: "image/jpeg;base64".to_string(),
+                        width: 20,
+                        height: 20,
                     })
                     .into(),
                 );
@@ -168,6 +180,8 @@ impl Client {
                 slots: vec![],
                 cache_len: 0,
                 chunk_len: None,
+                adapters: vec![],
+                guidance: None,
                 // Set sampling parameters to also take these ops into account in the max memory
                 parameters: Some(NextTokenChooserParameters {
                     temperature: 0.9,
@@ -181,11 +195,21 @@ impl Client {
                     watermark: true,
                     grammar: String::new(),
                     grammar_type: GrammarType::None as i32,
+                    speculate: None,
+                    sampling_step: 0,
+                    healing_token_id: None,
+                    logit_bias: Default::default(),
+                    presence_penalty: 0.1,
+                    min_p: 0.05,
+                    watermark_key: 0,
+                    no_repeat_ngram_size: 3,
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens,
                     stop_sequences: vec![],
                     ignore_eos_token: true,
+                    stop_token_ids: vec![],
+                    min_new_tokens: 0,
                 }),
                 prefill_logprobs: true,
                 top_n_tokens: 20,
@@ -212,6 +236,7 @@ impl Client {
             max_input_tokens,
             max_prefill_tokens,
             max_total_tokens,
+            max_batch_total_tokens,
         })
         .inject_context();
         let response = self.stub.warmup(request).await?.into_inner();
@@ -253,13 +278,19 @@ impl Client {
     /// Generate one token for each request in the given cached batches
     ///
     /// Returns Generation for each request in batches
-    /// and the next cached batch
+    /// and the next cached batch. `draft_tokens` replace the speculative tokens proposed by the
+    /// shards for some of the requests.
     #[instrument(skip_all, fields(size = batches.iter().map(|batch|{batch.size}).sum::<u32>()))]
     pub async fn decode(
         &mut self,
         batches: Vec<CachedBatch>,
+        draft_tokens: Vec<DraftTokens>,
     ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)> {
-        let request = tonic::Request::new(DecodeRequest { batches }).inject_context();
+        let request = tonic::Request::new(DecodeRequest {
+            batches,
+            draft_tokens,
+        })
+        .inject_context();
         let response = self.stub.decode(request).await?.into_inner();
         Ok((
             response.generations,
@@ -272,6 +303,117 @@ impl Client {
             ),
         ))
     }
+
+    /// Propose `tokens` tokens for each request in the given cached batches, on the shards of a
+    /// draft model. `generated` are the tokens generated for the requests since their last
+    /// draft.
+    ///
+    /// Returns the draft of each request
+    #[instrument(skip_all, fields(size = batches.iter().map(|batch|{batch.size}).sum::<u32>()))]
+    pub async fn draft(
+        &mut self,
+        batches: Vec<CachedBatch>,
+        generated: Vec<DraftTokens>,
+        tokens: u32,
+    ) -> Result<Vec<DraftTokens>> {
+        let request = tonic::Request::new(DraftRequest {
+            batches,
+            generated,
+            tokens,
+        })
+        .inject_context();
+        let response = self.stub.draft(request).await?.into_inner();
+        Ok(response.drafts)
+    }
+
+    /// Embed tokenized inputs
+    ///
+    /// Returns one Embedding per input
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub async fn embed(&mut self, inputs: Vec<EmbedInput>) -> Result<Vec<Embedding>> {
+        let request = tonic::Request::new(EmbedRequest { inputs }).inject_context();
+        let response = self.stub.embed(request).await?.into_inner();
+        Ok(response.embeddings)
+    }
+
+    /// Copy KV cache blocks from the device to the host memory
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_out(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let request = tonic::Request::new(SwapRequest { swaps }).inject_context();
+        self.stub.swap_out(request).await?;
+        Ok(())
+    }
+
+    /// Copy KV cache blocks from the host memory back to the device
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_in(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let request = tonic::Request::new(SwapRequest { swaps }).inject_context();
+        self.stub.swap_in(request).await?;
+        Ok(())
+    }
+
+    /// Load a LoRA adapter in the model of the shard
+    #[instrument(skip(self))]
+    pub async fn load_adapter(
+        &mut self,
+        id: String,
+        path: Option<String>,
+        revision: Option<String>,
+    ) -> Result<LoraAdapter> {
+        let request =
+            tonic::Request::new(LoadAdapterRequest { id, path, revision }).inject_context();
+        let response = self.stub.load_adapter(request).await?.into_inner();
+        response.adapter.ok_or(ClientError::EmptyResults)
+    }
+
+    /// Unload a LoRA adapter of the model of the shard
+    #[instrument(skip(self))]
+    pub async fn unload_adapter(&mut self, id: String) -> Result<()> {
+        let request = tonic::Request::new(UnloadAdapterRequest { id }).inject_context();
+        self.stub.unload_adapter(request).await?;
+        Ok(())
+    }
+
+    /// Copy the KV cache blocks `blocks` of this shard to the blocks `target_blocks` of the
+    /// same shard of another shard-set. The blocks are streamed through the router, chunk by
+    /// chunk.
+    #[instrument(skip_all, fields(size = blocks.len()))]
+    pub async fn copy_kv(
+        &mut self,
+        target: &mut Client,
+        blocks: Vec<u32>,
+        target_blocks: Vec<u32>,
+    ) -> Result<()> {
+        let request = tonic::Request::new(ExportKvRequest { blocks }).inject_context();
+        let mut chunks = self.stub.export_kv(request).await?.into_inner();
+        let (sender, receiver) = mpsc::channel(KV_CHUNK_BUFFER);
+        let requests = tokio_stream::once(ImportKvRequest {
+            blocks: target_blocks,
+            chunk: None,
+        })
+        .chain(ReceiverStream::new(receiver));
+        let request = tonic::Request::new(requests).inject_context();
+        let import = async {
+            target.stub.import_kv(request).await?;
+            Ok::<_, ClientError>(())
+        };
+        let export = async move {
+            while let Some(chunk) = chunks.message().await? {
+                let request = ImportKvRequest {
+                    blocks: Vec::new(),
+                    chunk: Some(chunk),
+                };
+                // The import stopped, its error is returned
+                if sender.send(request).await.is_err() {
+                    break;
+                }
+            }
+            Ok::<_, ClientError>(())
+        };
+        // The first error of the export is returned, and cancels the import of the blocks
+        try_join(export, import).await?;
+        Ok(())
+    }
 }
 
 pub struct PrefillTimings {
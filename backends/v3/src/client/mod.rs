@@ -3,18 +3,21 @@
 use async_trait::async_trait;
 use thiserror::Error;
 use tonic::transport;
-use tonic::Status;
+use tonic::{Code, Status};
 
 #[allow(clippy::derive_partial_eq_without_eq)]
 mod pb;
 
+#[cfg(test)]
+pub(crate) mod fake_shard;
 mod grpc_client;
 mod sharded_client;
 
 pub use grpc_client::Client;
 pub use pb::generate::v3::{
-    input_chunk::Chunk, Batch, CachedBatch, FinishReason, GeneratedText, Generation, GrammarType,
-    HealthResponse, Image, InfoResponse, Input, InputChunk, NextTokenChooserParameters, Request,
+    input_chunk::Chunk, AdapterWeight, Batch, BlockSwap, CachedBatch, DraftTokens, EmbedInput,
+    Embedding, FinishReason, GeneratedText, Generation, GrammarType, HealthResponse, Image,
+    InfoResponse, Input, InputChunk, LoraAdapter, NextTokenChooserParameters, Request,
     StoppingCriteriaParameters,
 };
 pub use sharded_client::ShardedClient;
@@ -41,7 +44,11 @@ pub enum ClientError {
 
 impl From<Status> for ClientError {
     fn from(err: Status) -> Self {
-        let err = Self::Generation(err.message().to_string());
+        let err = match err.code() {
+            // The channel could not reach the shard
+            Code::Unavailable => Self::Connection(err.message().to_string()),
+            _ => Self::Generation(err.message().to_string()),
+        };
         tracing::error!("{err}");
         err
     }
@@ -4,10 +4,10 @@ use crate::client::{ClientError, Result};
 
 use crate::client::grpc_client::{DecodeTimings, PrefillTimings};
 use crate::client::{
-    Batch, CachedBatch, Client, Generation, GrammarType, HealthResponse,
-    NextTokenChooserParameters, Request, StoppingCriteriaParameters,
+    Batch, BlockSwap, CachedBatch, Client, DraftTokens, EmbedInput, Embedding, Generation,
+    GrammarType, HealthResponse, NextTokenChooserParameters, Request, StoppingCriteriaParameters,
 };
-use crate::client::{Chunk, InfoResponse, Input};
+use crate::client::{Chunk, InfoResponse, Input, LoraAdapter};
 use async_trait::async_trait;
 use futures::future::join_all;
 use tonic::transport::Uri;
@@ -55,7 +55,15 @@ impl ShardedClient {
             .iter_mut()
             .map(|client| client.info())
             .collect();
-        join_all(futures).await.pop().unwrap()
+        let mut infos = join_all(futures)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        // Every shard holds the KV of its own heads
+        let kv_bytes_per_token = infos.iter().map(|info| info.kv_bytes_per_token).sum();
+        let mut info = infos.pop().unwrap();
+        info.kv_bytes_per_token = kv_bytes_per_token;
+        Ok(info)
     }
 
     /// GRPC health check
@@ -106,6 +114,7 @@ impl ShardedClient {
         max_prefill_tokens: u32,
         max_total_tokens: Option<u32>,
         max_batch_size: Option<usize>,
+        max_batch_total_tokens: Option<u32>,
     ) -> Result<(Option<u32>, u32, u32)> {
         let futures: Vec<_> = self
             .clients
@@ -116,6 +125,7 @@ impl ShardedClient {
                     max_prefill_tokens,
                     max_total_tokens,
                     max_batch_size,
+                    max_batch_total_tokens,
                 ))
             })
             .collect();
@@ -171,16 +181,18 @@ impl ShardedClient {
     /// Generate one token for each request in the given cached batches
     ///
     /// Returns Generation for each request in batches
-    /// and the next cached batch
+    /// and the next cached batch. `draft_tokens` replace the speculative tokens proposed by the
+    /// shards for some of the requests.
     #[instrument(skip_all, fields(size = batches.iter().map(| batch | {batch.size}).sum::< u32 > ()))]
     pub async fn decode(
         &mut self,
         batches: Vec<CachedBatch>,
+        draft_tokens: Vec<DraftTokens>,
     ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)> {
         let futures: Vec<_> = self
             .clients
             .iter_mut()
-            .map(|client| Box::pin(client.decode(batches.clone())))
+            .map(|client| Box::pin(client.decode(batches.clone(), draft_tokens.clone())))
             .collect();
         #[allow(clippy::type_complexity)]
         let results: Result<Vec<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)>> =
@@ -200,6 +212,142 @@ impl ShardedClient {
         }
         Ok((generations, next_batch, timings))
     }
+
+    /// Propose `tokens` tokens for each request in the given cached batches, on the shards of a
+    /// draft model. `generated` are the tokens generated for the requests since their last
+    /// draft.
+    ///
+    /// Returns the draft of each request
+    #[instrument(skip_all, fields(size = batches.iter().map(| batch | {batch.size}).sum::< u32 > ()))]
+    pub async fn draft(
+        &mut self,
+        batches: Vec<CachedBatch>,
+        generated: Vec<DraftTokens>,
+        tokens: u32,
+    ) -> Result<Vec<DraftTokens>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.draft(batches.clone(), generated.clone(), tokens)))
+            .collect();
+        let results: Result<Vec<Vec<DraftTokens>>> = join_all(futures).await.into_iter().collect();
+        // All shards propose the same tokens
+        results?.pop().ok_or(ClientError::EmptyResults)
+    }
+
+    /// Embed tokenized inputs
+    ///
+    /// Returns one Embedding per input
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub async fn embed(&mut self, inputs: Vec<EmbedInput>) -> Result<Vec<Embedding>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.embed(inputs.clone())))
+            .collect();
+        let results: Result<Vec<Vec<Embedding>>> = join_all(futures).await.into_iter().collect();
+        // All shards return the same embeddings
+        results?.pop().ok_or(ClientError::EmptyResults)
+    }
+
+    /// Copy KV cache blocks from the device to the host memory
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_out(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.swap_out(swaps.clone())))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
+    /// Copy KV cache blocks from the host memory back to the device
+    #[instrument(skip_all, fields(size = swaps.len()))]
+    pub async fn swap_in(&mut self, swaps: Vec<BlockSwap>) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.swap_in(swaps.clone())))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
+    /// Load a LoRA adapter on every shard. The shards that loaded it unload it again if another
+    /// shard failed to.
+    #[instrument(skip(self))]
+    pub async fn load_adapter(
+        &mut self,
+        id: String,
+        path: Option<String>,
+        revision: Option<String>,
+    ) -> Result<LoraAdapter> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.load_adapter(id.clone(), path.clone(), revision.clone())))
+            .collect();
+        let results = join_all(futures).await;
+        if let Some(Err(err)) = results.iter().find(|result| result.is_err()) {
+            let err = err.clone();
+            let futures: Vec<_> = self
+                .clients
+                .iter_mut()
+                .zip(&results)
+                .filter(|(_, result)| result.is_ok())
+                .map(|(client, _)| Box::pin(client.unload_adapter(id.clone())))
+                .collect();
+            join_all(futures).await;
+            return Err(err);
+        }
+        // Every shard reports the memory of its own shard of the adapter
+        results
+            .into_iter()
+            .next()
+            .ok_or(ClientError::EmptyResults)?
+    }
+
+    /// Unload a LoRA adapter of every shard
+    #[instrument(skip(self))]
+    pub async fn unload_adapter(&mut self, id: String) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.unload_adapter(id.clone())))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
+    /// Copy KV cache blocks to the blocks `target_blocks` of another shard-set of the same
+    /// model, every shard to the shard of the same rank
+    #[instrument(skip_all, fields(size = blocks.len()))]
+    pub async fn copy_kv(
+        &mut self,
+        target: &mut ShardedClient,
+        blocks: Vec<u32>,
+        target_blocks: Vec<u32>,
+    ) -> Result<()> {
+        if self.clients.len() != target.clients.len() {
+            return Err(ClientError::Generation(format!(
+                "Cannot copy the KV cache of {} shards to {} shards",
+                self.clients.len(),
+                target.clients.len()
+            )));
+        }
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .zip(target.clients.iter_mut())
+            .map(|(client, target)| {
+                Box::pin(client.copy_kv(target, blocks.clone(), target_blocks.clone()))
+            })
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
+    /// Number of shards of the shard-set
+    pub fn shards(&self) -> usize {
+        self.clients.len()
+    }
 }
 
 #[async_trait]
@@ -232,11 +380,21 @@ impl Health for ShardedClient {
                 watermark: false,
                 grammar: String::new(),
                 grammar_type: GrammarType::None as i32,
+                speculate: None,
+                sampling_step: 0,
+                healing_token_id: None,
+                logit_bias: Default::default(),
+                presence_penalty: 0.0,
+                min_p: 0.0,
+                watermark_key: 0,
+                no_repeat_ngram_size: 0,
             }),
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: 1,
                 stop_sequences: vec![],
                 ignore_eos_token: false,
+                stop_token_ids: vec![],
+                min_new_tokens: 0,
             }),
             top_n_tokens: 0,
             // Block 0 is reserved for health checks
@@ -245,6 +403,8 @@ impl Health for ShardedClient {
             cache_len: 0,
             adapter_id: None,
             chunk_len: None,
+            adapters: vec![],
+            guidance: None,
         };
         let batch = Batch {
             id: u64::MAX,
@@ -0,0 +1,412 @@
+/// Prefill of the prompts on a shard-set of their own, before they are decoded by another one
+use crate::backend::send_errors;
+use crate::block_allocator::{BlockAllocation, Compaction};
+use crate::budget::{is_out_of_memory, Budget};
+use crate::client::{Batch, ClientError, InfoResponse, ShardedClient};
+use crate::queue::{Entry, Fairness, Queue, QueueConfig};
+use crate::supervisor::is_shard_down;
+use nohash_hasher::IntMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{instrument, Instrument, Span};
+
+/// Interval at which queued prompts are retried while the prefill budget recovers
+const BUDGET_RECOVERY_POLL: Duration = Duration::from_secs(1);
+
+/// Shard-set computing the prompts of a replica, connected and warmed up like the shards that
+/// decode them
+#[derive(Debug)]
+pub(crate) struct PrefillShards {
+    pub(crate) client: ShardedClient,
+    pub(crate) shard_info: InfoResponse,
+    pub(crate) max_batch_total_tokens: u32,
+}
+
+/// Stage of an entry of a disaggregated replica
+#[derive(Debug)]
+pub(crate) enum Handoff {
+    /// Queued on the prefill shards, which compute the prompt but keep none of the tokens
+    Prefill {
+        /// Restored once the entry is handed to the decode shards
+        max_new_tokens: u32,
+    },
+    /// Queued on the decode shards, which copy the KV of the prompt before their prefill
+    Decode(PromptKv),
+}
+
+/// KV of a prompt computed by the prefill shards
+#[derive(Debug)]
+pub(crate) struct PromptKv {
+    /// Number of tokens of the prompt, from its start, in the KV. The last token of the prompt
+    /// is computed again by the decode shards to sample the first token.
+    pub(crate) tokens: u32,
+    /// Blocks of the prefill shards holding the KV, freed once it is copied
+    allocation: BlockAllocation,
+    /// Blocks of the prefill shards to copy to blocks of the decode shards, planned when the
+    /// entry is batched
+    copies: Vec<(u32, u32)>,
+}
+
+impl PromptKv {
+    /// Copy the blocks of the KV that are not in the prefix cache of the decode `allocation`
+    pub(crate) fn plan(&mut self, allocation: &BlockAllocation, block_size: u32) {
+        let first = (allocation.prefix_len / block_size) as usize;
+        let end = self.tokens.div_ceil(block_size) as usize;
+        self.copies = (first..end)
+            .map(|i| (self.allocation.blocks[i], allocation.blocks[i]))
+            .collect();
+    }
+}
+
+/// Whether the prompt of an entry can be computed by other shards. The decode shards prefill
+/// the prompts that need their own logprobs, that have images, that belong to a session, or
+/// whose blocks are shared by several sequences.
+pub(crate) fn can_hand_off(entry: &Entry) -> bool {
+    let request = &entry.request;
+    request.input_ids.is_some()
+        && !request.decoder_input_details
+        && request.session_id.is_none()
+        && request.prompt_group.is_none()
+        && request.input_length > 1
+}
+
+/// Queue and batching task of the prefill shards of a replica
+pub(crate) struct PrefillStage {
+    queue: Queue,
+    notifier: Arc<Notify>,
+    pub(crate) client: ShardedClient,
+    task: JoinHandle<()>,
+}
+
+impl PrefillStage {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        shards: PrefillShards,
+        max_batch_prefill_tokens: u32,
+        max_batch_size: Option<usize>,
+        session_ttl: Duration,
+        compaction: Compaction,
+        fairness: Fairness,
+        decode_queue: Queue,
+        decode_notifier: Arc<Notify>,
+        healthy: Arc<AtomicBool>,
+        crashed: Arc<AtomicBool>,
+        cancellation: CancellationToken,
+    ) -> Self {
+        let PrefillShards {
+            client,
+            shard_info,
+            max_batch_total_tokens,
+        } = shards;
+        // Prompts are never chunked, they leave the queue once their prefill is done
+        let queue = Queue::new(QueueConfig {
+            requires_padding: shard_info.requires_padding,
+            block_size: shard_info.block_size,
+            kv_bytes_per_token: shard_info.kv_bytes_per_token,
+            prefix_caching: shard_info.use_prefix_caching,
+            window_size: shard_info.window_size,
+            session_ttl,
+            swap_space: None,
+            compaction,
+            speculate: shard_info.speculate,
+            max_batch_total_tokens,
+            support_chunking: false,
+            fairness,
+        });
+        let notifier = Arc::new(Notify::new());
+        let budget = Budget::new(
+            max_batch_prefill_tokens,
+            max_batch_total_tokens,
+            max_batch_size,
+        );
+        let task = tokio::spawn(prefill_task(
+            client.clone(),
+            budget,
+            queue.clone(),
+            notifier.clone(),
+            decode_queue,
+            decode_notifier,
+            healthy,
+            crashed,
+            cancellation,
+        ));
+        Self {
+            queue,
+            notifier,
+            client,
+            task,
+        }
+    }
+
+    /// Queue an entry on the prefill shards. They only generate one token, which is dropped.
+    pub(crate) fn append(&self, mut entry: Entry) {
+        let stopping_parameters = &mut entry.request.stopping_parameters;
+        entry.handoff = Some(Handoff::Prefill {
+            max_new_tokens: stopping_parameters.max_new_tokens,
+        });
+        stopping_parameters.max_new_tokens = 1;
+        self.queue.append(entry);
+        self.notifier.notify_one();
+    }
+
+    pub(crate) fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Fail the queued prompts, the task stops with the batching task of the replica
+    pub(crate) fn shut_down(&self) {
+        self.queue.close();
+    }
+}
+
+/// Prefill the batches of the prefill queue and hand their entries to the decode queue
+#[allow(clippy::too_many_arguments)]
+async fn prefill_task(
+    mut client: ShardedClient,
+    mut budget: Budget,
+    queue: Queue,
+    notifier: Arc<Notify>,
+    decode_queue: Queue,
+    decode_notifier: Arc<Notify>,
+    healthy: Arc<AtomicBool>,
+    crashed: Arc<AtomicBool>,
+    cancellation: CancellationToken,
+) {
+    loop {
+        let notified = async {
+            if budget.is_reduced() {
+                let _ = tokio::time::timeout(BUDGET_RECOVERY_POLL, notifier.notified()).await;
+            } else {
+                notifier.notified().await;
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = cancellation.cancelled() => return,
+            _ = notified => {}
+        }
+
+        while let Some((mut entries, batch, span)) = queue
+            .next_batch(
+                None,
+                budget.batch_size(),
+                budget.prefill_tokens(),
+                budget.total_tokens(),
+            )
+            .await
+        {
+            let batch_id = batch.id;
+            let start_time = Instant::now();
+            metrics::counter!("tgi_batch_inference_count", "method" => "remote_prefill")
+                .increment(1);
+            match client.prefill(batch, None).instrument(span).await {
+                Ok((_, next_batch, _)) => {
+                    metrics::histogram!("tgi_batch_inference_duration", "method" => "remote_prefill")
+                        .record(start_time.elapsed().as_secs_f64());
+                    metrics::counter!("tgi_batch_inference_success", "method" => "remote_prefill")
+                        .increment(1);
+                    // The requests stop after their first token, nothing should be left
+                    if let Some(next_batch) = next_batch {
+                        let _ = client.clear_cache(Some(next_batch.id)).await;
+                    }
+                    // In the order they were queued
+                    let mut entries: Vec<(u64, Entry)> = entries.drain().collect();
+                    entries.sort_by_key(|(id, _)| *id);
+                    for (_, entry) in entries {
+                        decode_queue.append(hand_off(entry));
+                    }
+                    decode_notifier.notify_one();
+                }
+                Err(err) => {
+                    let _ = client.clear_cache(Some(batch_id)).await;
+                    if is_out_of_memory(&err) {
+                        budget.shrink(entries.len());
+                    } else {
+                        healthy.store(false, Ordering::Relaxed);
+                        if is_shard_down(&err) {
+                            crashed.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    send_errors(err, &mut entries);
+                    metrics::counter!("tgi_batch_inference_failure", "method" => "remote_prefill")
+                        .increment(1);
+                }
+            }
+
+            if crashed.load(Ordering::Relaxed) {
+                queue.close();
+                return;
+            }
+            if cancellation.is_cancelled() {
+                return;
+            }
+        }
+    }
+}
+
+/// Entry of the decode queue for an entry whose prompt was prefilled
+fn hand_off(mut entry: Entry) -> Entry {
+    if let Some(Handoff::Prefill { max_new_tokens }) = entry.handoff.take() {
+        entry.request.stopping_parameters.max_new_tokens = max_new_tokens;
+    }
+    entry.handoff = entry.block_allocation.take().map(|mut allocation| {
+        // Later prompts with the same prefix reuse the KV on the prefill shards
+        allocation.cache_prefill();
+        Handoff::Decode(PromptKv {
+            tokens: entry.request.input_length - 1,
+            allocation,
+            copies: Vec::new(),
+        })
+    });
+    entry.temp_span = None;
+    entry.batch_time = None;
+    entry
+}
+
+/// Whether the entries of a batch of the decode shards have KV to copy before their prefill
+pub(crate) fn needs_copy(entries: &IntMap<u64, Entry>) -> bool {
+    entries.values().any(|entry| {
+        matches!(&entry.handoff, Some(Handoff::Decode(prompt_kv)) if !prompt_kv.copies.is_empty())
+    })
+}
+
+/// Batch of the decode shards whose KV is copied from the prefill shards while the running
+/// batch keeps decoding
+pub(crate) struct PendingCopy {
+    task: JoinHandle<(IntMap<u64, Entry>, Batch, Span, Result<(), ClientError>)>,
+}
+
+impl PendingCopy {
+    pub(crate) fn spawn(
+        mut prefill_client: ShardedClient,
+        mut client: ShardedClient,
+        mut entries: IntMap<u64, Entry>,
+        batch: Batch,
+        span: Span,
+    ) -> Self {
+        let task = tokio::spawn(async move {
+            let result = copy_prompt_kv(&mut prefill_client, &mut client, &mut entries)
+                .instrument(span.clone())
+                .await;
+            (entries, batch, span, result)
+        });
+        Self { task }
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Entries and batch of the copy once it is done, with its result
+    pub(crate) async fn finish(self) -> (IntMap<u64, Entry>, Batch, Span, Result<(), ClientError>) {
+        self.task.await.expect("The KV copy task panicked")
+    }
+}
+
+/// Copy the KV of the prompts of a batch of the decode shards from the prefill shards
+#[instrument(skip_all, fields(size = entries.len()))]
+pub(crate) async fn copy_prompt_kv(
+    prefill_client: &mut ShardedClient,
+    client: &mut ShardedClient,
+    entries: &mut IntMap<u64, Entry>,
+) -> Result<(), ClientError> {
+    // Keeps the blocks of the prefill shards until the copy is done
+    let prompt_kvs: Vec<PromptKv> = entries
+        .values_mut()
+        .filter_map(|entry| match entry.handoff.take() {
+            Some(Handoff::Decode(prompt_kv)) => Some(prompt_kv),
+            handoff => {
+                entry.handoff = handoff;
+                None
+            }
+        })
+        .collect();
+    let (blocks, target_blocks): (Vec<u32>, Vec<u32>) = prompt_kvs
+        .iter()
+        .flat_map(|prompt_kv| prompt_kv.copies.iter().copied())
+        .unzip();
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let start_time = Instant::now();
+    prefill_client
+        .copy_kv(client, blocks, target_blocks)
+        .await?;
+    metrics::histogram!("tgi_batch_kv_copy_duration").record(start_time.elapsed().as_secs_f64());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::default_entry;
+
+    fn allocation(blocks: Vec<u32>, prefix_len: u32) -> BlockAllocation {
+        BlockAllocation {
+            allocation_id: 0,
+            slots: Vec::new(),
+            blocks,
+            prefix_len,
+            generated_tokens: None,
+            prefill_cached: false,
+            preempted: false,
+            share: None,
+            block_allocator: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_copies() {
+        let mut prompt_kv = PromptKv {
+            tokens: 9,
+            allocation: allocation(vec![10, 11, 12, 13], 0),
+            copies: Vec::new(),
+        };
+        prompt_kv.plan(&allocation(vec![0, 1, 2, 3], 0), 4);
+        assert_eq!(prompt_kv.copies, vec![(10, 0), (11, 1), (12, 2)]);
+
+        // The blocks in the prefix cache of the decode shards are not copied
+        prompt_kv.plan(&allocation(vec![0, 1, 2, 3], 8), 4);
+        assert_eq!(prompt_kv.copies, vec![(12, 2)]);
+        prompt_kv.plan(&allocation(vec![0, 1, 2, 3], 12), 4);
+        assert!(prompt_kv.copies.is_empty());
+    }
+
+    #[test]
+    fn test_needs_copy() {
+        let (entry, _receiver) = default_entry();
+        let mut entries = IntMap::from_iter([(0, entry)]);
+        assert!(!needs_copy(&entries));
+
+        let mut prompt_kv = PromptKv {
+            tokens: 5,
+            allocation: allocation(vec![10, 11], 0),
+            copies: Vec::new(),
+        };
+        // The prompt is already in the prefix cache of the decode shards
+        prompt_kv.plan(&allocation(vec![0, 1], 8), 4);
+        entries.get_mut(&0).unwrap().handoff = Some(Handoff::Decode(prompt_kv));
+        assert!(!needs_copy(&entries));
+
+        let (mut entry, _receiver) = default_entry();
+        let mut prompt_kv = PromptKv {
+            tokens: 5,
+            allocation: allocation(vec![12, 13], 0),
+            copies: Vec::new(),
+        };
+        prompt_kv.plan(&allocation(vec![2, 3], 0), 4);
+        entry.handoff = Some(Handoff::Decode(prompt_kv));
+        entries.insert(1, entry);
+        assert!(needs_copy(&entries));
+    }
+}
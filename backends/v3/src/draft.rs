@@ -0,0 +1,371 @@
+/// Speculation with a draft model running on a shard-set of its own
+use crate::client::{Batch, CachedBatch, DraftTokens, GrammarType, ShardedClient};
+use crate::queue::Entry;
+use nohash_hasher::IntMap;
+use tokio::time::Instant;
+use tracing::instrument;
+
+/// Shards of a small model proposing the speculative tokens of a replica, connected and warmed
+/// up with the limits of the shards verifying them
+#[derive(Debug)]
+pub(crate) struct DraftShards {
+    pub(crate) client: ShardedClient,
+}
+
+/// Speculative tokens of an entry, proposed by the draft model
+#[derive(Debug)]
+pub(crate) struct Draft {
+    /// Tokens generated since the last draft, the draft model has not seen them yet
+    generated: Vec<u32>,
+    /// Tokens of the last draft, verified by the next forward
+    proposed: Vec<u32>,
+    /// Maximum number of proposed tokens
+    max_draft: usize,
+}
+
+impl Draft {
+    pub(crate) fn new(max_draft: usize) -> Self {
+        Self {
+            generated: Vec::new(),
+            proposed: Vec::new(),
+            max_draft,
+        }
+    }
+
+    /// Add the tokens generated by the last forward, and count the proposed ones they accepted
+    pub(crate) fn extend(&mut self, ids: &[u32]) {
+        if !self.proposed.is_empty() {
+            // The last token is sampled by the verifying model, the ones before it are the
+            // accepted prefix of the draft
+            let accepted = self
+                .proposed
+                .iter()
+                .zip(&ids[..ids.len().saturating_sub(1)])
+                .take_while(|(proposed, id)| proposed == id)
+                .count();
+            metrics::counter!("tgi_draft_proposed_tokens").increment(self.proposed.len() as u64);
+            metrics::counter!("tgi_draft_accepted_tokens").increment(accepted as u64);
+            metrics::histogram!("tgi_draft_acceptance_rate")
+                .record(accepted as f64 / self.proposed.len() as f64);
+            self.proposed.clear();
+        }
+        self.generated.extend_from_slice(ids);
+    }
+
+    /// Tokens generated for request `request_id` since its last draft
+    fn take_generated(&mut self, request_id: u64) -> DraftTokens {
+        DraftTokens {
+            request_id,
+            ids: std::mem::take(&mut self.generated),
+        }
+    }
+
+    /// Keep the proposed tokens until they are verified, and return the ones to send to the
+    /// verifying shards
+    fn propose(&mut self, mut draft: DraftTokens) -> Option<DraftTokens> {
+        draft.ids.truncate(self.max_draft);
+        self.proposed.clone_from(&draft.ids);
+        (!draft.ids.is_empty()).then_some(draft)
+    }
+}
+
+/// Requests of the batches held by the draft shards
+#[derive(Debug, Default)]
+struct Mirror {
+    batches: IntMap<u64, Vec<u64>>,
+}
+
+impl Mirror {
+    /// Record the prefill of `batch`, which the shards concatenate after `cached_batch` when
+    /// the prompts are chunked. Returns the ID of the batch holding both.
+    fn prefill(&mut self, batch: &Batch, cached_batch: Option<&CachedBatch>) -> u64 {
+        let (id, mut request_ids) = match cached_batch {
+            Some(cached_batch) => (
+                cached_batch.id,
+                self.batches.remove(&cached_batch.id).unwrap_or_default(),
+            ),
+            None => (batch.id, Vec::new()),
+        };
+        request_ids.extend(batch.requests.iter().map(|request| request.id));
+        self.batches.insert(id, request_ids);
+        id
+    }
+
+    /// Record the filter of the batch `id` down to `request_ids`, or its removal. Returns false
+    /// if the draft shards do not hold the batch or one of the requests.
+    fn filter(&mut self, id: u64, request_ids: Option<&[u64]>) -> bool {
+        let Some(held) = self.batches.remove(&id) else {
+            return false;
+        };
+        match request_ids {
+            Some(request_ids) if request_ids.iter().all(|id| held.contains(id)) => {
+                self.batches.insert(id, request_ids.to_vec());
+                true
+            }
+            Some(_) => false,
+            None => true,
+        }
+    }
+
+    /// Record the concatenation of the `batches` into the first one by a decode
+    fn concatenate(&mut self, batches: &[CachedBatch]) {
+        let Some((first, rest)) = batches.split_first() else {
+            return;
+        };
+        let mut request_ids = self.batches.remove(&first.id).unwrap_or_default();
+        for batch in rest {
+            request_ids.extend(self.batches.remove(&batch.id).unwrap_or_default());
+        }
+        self.batches.insert(first.id, request_ids);
+    }
+}
+
+/// Mirror of the batches of a replica on its draft shards.
+///
+/// The draft shards hold the same batches as the verifying shards, with the same IDs and the
+/// same KV cache blocks, so every prefill and filter of the replica is repeated on them. A
+/// chunked prompt is concatenated to the cached batch on both. Their
+/// KV cache only lags behind for the blocks that were swapped or copied, which lowers the
+/// acceptance rate but never changes the generated tokens.
+#[derive(Debug)]
+pub(crate) struct Drafter {
+    client: ShardedClient,
+    /// Number of tokens proposed for every request
+    tokens: u32,
+    /// Cleared when a call to the draft shards fails. The running batch is then verified
+    /// without drafts, and the draft shards are mirrored again from the next one.
+    in_sync: bool,
+    mirror: Mirror,
+}
+
+impl Drafter {
+    pub(crate) fn new(shards: DraftShards, tokens: u32) -> Self {
+        Self {
+            client: shards.client,
+            tokens,
+            in_sync: true,
+            mirror: Mirror::default(),
+        }
+    }
+
+    /// Prefill the prompts of a new `batch`, after `cached_batch` like the verifying shards.
+    /// The tokens sampled by the draft model are dropped, the requests must only stop when the
+    /// verifying model stops them.
+    #[instrument(skip_all, fields(id = batch.id, size = batch.size))]
+    pub(crate) async fn prefill(&mut self, mut batch: Batch, cached_batch: Option<CachedBatch>) {
+        if !self.in_sync {
+            return;
+        }
+        self.mirror.prefill(&batch, cached_batch.as_ref());
+        for request in batch.requests.iter_mut() {
+            request.prefill_logprobs = false;
+            request.top_n_tokens = 0;
+            // The adapters are loaded on the verifying shards only
+            request.adapter_id = None;
+            request.adapters.clear();
+            if let Some(parameters) = request.parameters.as_mut() {
+                parameters.grammar = String::new();
+                parameters.grammar_type = GrammarType::None.into();
+            }
+            if let Some(stopping_parameters) = request.stopping_parameters.as_mut() {
+                stopping_parameters.stop_sequences.clear();
+                stopping_parameters.stop_token_ids.clear();
+                stopping_parameters.ignore_eos_token = true;
+            }
+        }
+        let result = self.client.prefill(batch, cached_batch).await;
+        if let Err(err) = result {
+            self.fail(err).await;
+        }
+    }
+
+    /// Propose the next tokens of the `entries` of the cached `batches`
+    #[instrument(skip_all, fields(size = entries.len()))]
+    pub(crate) async fn draft(
+        &mut self,
+        batches: &[CachedBatch],
+        entries: &mut IntMap<u64, Entry>,
+    ) -> Vec<DraftTokens> {
+        if !self.in_sync {
+            return Vec::new();
+        }
+        self.mirror.concatenate(batches);
+        let generated = entries
+            .iter_mut()
+            .filter_map(|(&id, entry)| Some(entry.draft.as_mut()?.take_generated(id)))
+            .collect();
+
+        let start_time = Instant::now();
+        metrics::counter!("tgi_batch_inference_count", "method" => "draft").increment(1);
+        match self
+            .client
+            .draft(batches.to_vec(), generated, self.tokens)
+            .await
+        {
+            Ok(drafts) => {
+                metrics::histogram!("tgi_batch_inference_duration", "method" => "draft")
+                    .record(start_time.elapsed().as_secs_f64());
+                metrics::counter!("tgi_batch_inference_success", "method" => "draft").increment(1);
+                drafts
+                    .into_iter()
+                    .filter_map(|draft| {
+                        entries
+                            .get_mut(&draft.request_id)?
+                            .draft
+                            .as_mut()?
+                            .propose(draft)
+                    })
+                    .collect()
+            }
+            Err(err) => {
+                metrics::counter!("tgi_batch_inference_failure", "method" => "draft").increment(1);
+                self.fail(err).await;
+                Vec::new()
+            }
+        }
+    }
+
+    /// Filter the batch `id` like the verifying shards did, its requests are the ones of
+    /// `next_batch`
+    #[instrument(skip_all)]
+    pub(crate) async fn filter(&mut self, id: u64, next_batch: Option<&CachedBatch>) {
+        if !self.in_sync {
+            return;
+        }
+        let request_ids = next_batch.map(|batch| batch.request_ids.as_slice());
+        if !self.mirror.filter(id, request_ids) {
+            self.fail(format!("batch {id} is not the one of the verifying shards"))
+                .await;
+            return;
+        }
+        let result = match next_batch {
+            Some(batch) => self
+                .client
+                .filter_batch(id, batch.request_ids.clone())
+                .await
+                .map(|_| ()),
+            None => self.client.clear_cache(Some(id)).await,
+        };
+        if let Err(err) = result {
+            self.fail(err).await;
+        }
+    }
+
+    /// Drop the batches `ids`, that failed on the verifying shards
+    pub(crate) async fn clear(&mut self, ids: &[u64]) {
+        for &id in ids {
+            self.filter(id, None).await;
+        }
+    }
+
+    /// Mirror the batches again, once the running batch of the replica is done
+    pub(crate) fn resume(&mut self) {
+        self.in_sync = true;
+    }
+
+    /// Drop all the batches of the draft shards, once the replica is stopped
+    pub(crate) async fn stop(&mut self) {
+        let _ = self.client.clear_cache(None).await;
+    }
+
+    async fn fail(&mut self, err: impl std::fmt::Display) {
+        tracing::warn!("Draft shards failed, the running batch continues without drafts: {err}");
+        self.in_sync = false;
+        self.mirror = Mirror::default();
+        let _ = self.client.clear_cache(None).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Request;
+
+    fn batch(id: u64, request_ids: &[u64]) -> Batch {
+        Batch {
+            id,
+            requests: request_ids
+                .iter()
+                .map(|&id| Request {
+                    id,
+                    ..Default::default()
+                })
+                .collect(),
+            size: request_ids.len() as u32,
+            ..Default::default()
+        }
+    }
+
+    fn cached_batch(id: u64, request_ids: &[u64]) -> CachedBatch {
+        CachedBatch {
+            id,
+            request_ids: request_ids.to_vec(),
+            size: request_ids.len() as u32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mirror_chunked_prefill_then_filter() {
+        let mut mirror = Mirror::default();
+        assert_eq!(mirror.prefill(&batch(0, &[1, 2]), None), 0);
+
+        // The chunk of batch 1 is concatenated to the cached batch 0, under the ID of the latter
+        let cached = cached_batch(0, &[1, 2]);
+        assert_eq!(mirror.prefill(&batch(1, &[3]), Some(&cached)), 0);
+        assert!(!mirror.batches.contains_key(&1));
+
+        // The verifying shards filter batch 0 down to the requests left
+        assert!(mirror.filter(0, Some(&[2, 3])));
+        assert_eq!(mirror.batches[&0], vec![2, 3]);
+        // The filter of a batch the draft shards never saw is refused
+        assert!(!mirror.filter(1, Some(&[3])));
+        // As is a filter keeping a request they do not hold
+        assert!(!mirror.filter(0, Some(&[4])));
+    }
+
+    #[test]
+    fn test_mirror_concatenate() {
+        let mut mirror = Mirror::default();
+        mirror.prefill(&batch(0, &[1]), None);
+        mirror.prefill(&batch(1, &[2]), None);
+        mirror.concatenate(&[cached_batch(0, &[1]), cached_batch(1, &[2])]);
+        assert_eq!(mirror.batches[&0], vec![1, 2]);
+        assert!(mirror.filter(0, None));
+        assert!(mirror.batches.is_empty());
+    }
+
+    #[test]
+    fn test_propose_truncates_to_max_draft() {
+        let mut draft = Draft::new(2);
+        let proposed = draft.propose(DraftTokens {
+            request_id: 0,
+            ids: vec![4, 5, 6],
+        });
+        assert_eq!(proposed.unwrap().ids, vec![4, 5]);
+        assert_eq!(draft.proposed, vec![4, 5]);
+        assert!(Draft::new(0)
+            .propose(DraftTokens {
+                request_id: 0,
+                ids: vec![4],
+            })
+            .is_none());
+    }
+
+    #[test]
+    fn test_extend_collects_generated_tokens() {
+        let mut draft = Draft::new(3);
+        draft.extend(&[1]);
+        draft.propose(DraftTokens {
+            request_id: 7,
+            ids: vec![2, 3, 4],
+        });
+        // The first proposed token is accepted, the verifying model samples `9` instead of `3`
+        draft.extend(&[2, 9]);
+        assert!(draft.proposed.is_empty());
+        let generated = draft.take_generated(7);
+        assert_eq!(generated.request_id, 7);
+        assert_eq!(generated.ids, vec![1, 2, 9]);
+        assert!(draft.generated.is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+/// Batching of the embedding requests, separate from the generation queue
+use crate::client::{ClientError, EmbedInput, Embedding, ShardedClient};
+use tokio::sync::{mpsc, oneshot};
+use tracing::instrument;
+
+type EmbedResult = Result<Vec<Vec<f32>>, ClientError>;
+
+#[derive(Debug)]
+struct EmbedEntry {
+    /// Tokenized inputs of the request
+    input_ids: Vec<Vec<u32>>,
+    /// Embeddings of the inputs, in order
+    response_tx: oneshot::Sender<EmbedResult>,
+}
+
+impl EmbedEntry {
+    fn tokens(&self) -> usize {
+        self.input_ids.iter().map(Vec::len).sum()
+    }
+}
+
+/// Embedding requests are batched together by a background task and sent to the shards
+/// directly, without going through the generation queue.
+#[derive(Debug, Clone)]
+pub(crate) struct Embedder {
+    sender: mpsc::UnboundedSender<EmbedEntry>,
+}
+
+impl Embedder {
+    /// `max_batch_tokens` caps the number of tokens of a batch. A request larger than the cap
+    /// is sent on its own.
+    pub(crate) fn new(client: ShardedClient, max_batch_tokens: u32) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(embed_task(client, max_batch_tokens as usize, receiver));
+        Self { sender }
+    }
+
+    pub(crate) async fn embed(&self, input_ids: Vec<Vec<u32>>) -> EmbedResult {
+        // The shards pool the hidden states of at least one token
+        if input_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(EmbedEntry {
+                input_ids,
+                response_tx,
+            })
+            .expect("Embedding task dropped the receiver. This is a bug.");
+        response_rx
+            .await
+            .expect("Embedding task dropped the sender. This is a bug.")
+    }
+}
+
+async fn embed_task(
+    mut client: ShardedClient,
+    max_batch_tokens: usize,
+    mut receiver: mpsc::UnboundedReceiver<EmbedEntry>,
+) {
+    // Request that did not fit in the previous batch
+    let mut next = None;
+
+    loop {
+        let entry = match next.take() {
+            Some(entry) => entry,
+            None => match receiver.recv().await {
+                Some(entry) => entry,
+                None => break,
+            },
+        };
+
+        let (entries, rest) = collect_batch(entry, &mut receiver, max_batch_tokens);
+        next = rest;
+        embed_batch(&mut client, entries).await;
+    }
+}
+
+/// Batch of `entry` and of the requests already waiting behind it, up to `max_batch_tokens`.
+/// Returns the batch and the first request that did not fit in it.
+fn collect_batch(
+    entry: EmbedEntry,
+    receiver: &mut mpsc::UnboundedReceiver<EmbedEntry>,
+    max_batch_tokens: usize,
+) -> (Vec<EmbedEntry>, Option<EmbedEntry>) {
+    let mut tokens = entry.tokens();
+    let mut entries = vec![entry];
+    while let Ok(entry) = receiver.try_recv() {
+        if tokens + entry.tokens() > max_batch_tokens {
+            return (entries, Some(entry));
+        }
+        tokens += entry.tokens();
+        entries.push(entry);
+    }
+    (entries, None)
+}
+
+#[instrument(skip_all, fields(size = entries.len()))]
+async fn embed_batch(client: &mut ShardedClient, entries: Vec<EmbedEntry>) {
+    let inputs: Vec<EmbedInput> = entries
+        .iter()
+        .flat_map(|entry| entry.input_ids.iter().cloned())
+        .enumerate()
+        .map(|(id, input_ids)| EmbedInput {
+            id: id as u64,
+            input_ids,
+        })
+        .collect();
+    let size = inputs.len();
+
+    let embeddings = match client.embed(inputs).await {
+        Ok(embeddings) if embeddings.len() == size => embeddings,
+        Ok(_) => {
+            send_error(entries, ClientError::EmptyResults);
+            return;
+        }
+        Err(err) => {
+            send_error(entries, err);
+            return;
+        }
+    };
+    send_embeddings(entries, embeddings);
+}
+
+/// Send the `embeddings` of the inputs of a batch, numbered in order, to their `entries`
+fn send_embeddings(entries: Vec<EmbedEntry>, mut embeddings: Vec<Embedding>) {
+    embeddings.sort_by_key(|embedding| embedding.id);
+
+    let mut embeddings = embeddings.into_iter().map(|embedding| embedding.values);
+    for entry in entries {
+        let entry_embeddings = embeddings.by_ref().take(entry.input_ids.len()).collect();
+        // The client may have gone away
+        let _ = entry.response_tx.send(Ok(entry_embeddings));
+    }
+}
+
+fn send_error(entries: Vec<EmbedEntry>, err: ClientError) {
+    for entry in entries {
+        let _ = entry.response_tx.send(Err(err.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(lengths: &[usize]) -> (EmbedEntry, oneshot::Receiver<EmbedResult>) {
+        let (response_tx, response_rx) = oneshot::channel();
+        let entry = EmbedEntry {
+            input_ids: lengths.iter().map(|&length| vec![0; length]).collect(),
+            response_tx,
+        };
+        (entry, response_rx)
+    }
+
+    #[test]
+    fn test_collect_batch() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let (first, _) = entry(&[3, 2]);
+        let mut responses = Vec::new();
+        for lengths in [&[4][..], &[2], &[1]] {
+            let (entry, response_rx) = entry(lengths);
+            sender.send(entry).unwrap();
+            responses.push(response_rx);
+        }
+
+        // 5 + 4 tokens fit in the cap, the next request waits for the next batch
+        let (entries, next) = collect_batch(first, &mut receiver, 10);
+        assert_eq!(entries.len(), 2);
+        let next = next.unwrap();
+        assert_eq!(next.tokens(), 2);
+
+        // A request larger than the cap is sent on its own
+        let (large, _) = entry(&[20]);
+        let (entries, next) = collect_batch(large, &mut receiver, 10);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(next.unwrap().tokens(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_embeddings() {
+        let (first, first_rx) = entry(&[1, 1]);
+        let (second, second_rx) = entry(&[1]);
+        // The shards may answer out of order
+        let embeddings = [2, 0, 1]
+            .into_iter()
+            .map(|id| Embedding {
+                id,
+                values: vec![id as f32],
+            })
+            .collect();
+        send_embeddings(vec![first, second], embeddings);
+
+        assert_eq!(first_rx.await.unwrap().unwrap(), vec![vec![0.0], vec![1.0]]);
+        assert_eq!(second_rx.await.unwrap().unwrap(), vec![vec![2.0]]);
+    }
+}
@@ -0,0 +1,169 @@
+/// Client of the control socket of the launcher, which starts the shard-sets of the other
+/// revisions of the model and stops the ones the router no longer uses
+use serde::{Deserialize, Serialize};
+use text_generation_router::infer::InferError;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum ControlRequest {
+    Spawn {
+        revision: Option<String>,
+        cuda_visible_devices: Option<String>,
+    },
+    Stop {
+        shard_set: usize,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum ControlResponse {
+    Spawned {
+        shard_set: usize,
+        master_shard_uds_path: String,
+    },
+    Stopped {},
+    Error {
+        message: String,
+    },
+}
+
+/// Shard-set started by the launcher
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LaunchedShardSet {
+    /// Number of the shard-set for the launcher, 0 for the one it started first
+    pub(crate) shard_set: usize,
+    pub(crate) master_shard_uds_path: String,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Launcher {
+    uds_path: String,
+}
+
+impl Launcher {
+    pub(crate) fn new(uds_path: String) -> Self {
+        Self { uds_path }
+    }
+
+    /// Start the shards of `revision` of the model on `cuda_visible_devices`, returning once
+    /// they are ready to be warmed up
+    pub(crate) async fn spawn(
+        &self,
+        revision: Option<String>,
+        cuda_visible_devices: Option<String>,
+    ) -> Result<LaunchedShardSet, InferError> {
+        let request = ControlRequest::Spawn {
+            revision,
+            cuda_visible_devices,
+        };
+        match self.call(&request).await? {
+            ControlResponse::Spawned {
+                shard_set,
+                master_shard_uds_path,
+            } => Ok(LaunchedShardSet {
+                shard_set,
+                master_shard_uds_path,
+            }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Stop the shards of a shard-set, freeing their GPU memory
+    pub(crate) async fn stop(&self, shard_set: usize) -> Result<(), InferError> {
+        match self.call(&ControlRequest::Stop { shard_set }).await? {
+            ControlResponse::Stopped {} => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn call(&self, request: &ControlRequest) -> Result<ControlResponse, InferError> {
+        let error = |err: std::io::Error| {
+            InferError::SwapError(format!("unable to reach the launcher: {err}"))
+        };
+        let mut stream = UnixStream::connect(&self.uds_path).await.map_err(error)?;
+        let mut line = serde_json::to_string(request).unwrap();
+        line.push('\n');
+        stream.write_all(line.as_bytes()).await.map_err(error)?;
+
+        // Starting the shards takes as long as loading the model
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .await
+            .map_err(error)?;
+        serde_json::from_str(&line)
+            .map_err(|err| InferError::SwapError(format!("invalid answer of the launcher: {err}")))
+    }
+}
+
+fn unexpected(response: ControlResponse) -> InferError {
+    match response {
+        ControlResponse::Error { message } => InferError::SwapError(message),
+        response => {
+            InferError::SwapError(format!("unexpected answer of the launcher: {response:?}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_launcher() {
+        let uds_path = std::env::temp_dir().join(format!("tgi-launcher-{}", std::process::id()));
+        let _ = std::fs::remove_file(&uds_path);
+        let listener = UnixListener::bind(&uds_path).unwrap();
+        let answers = [
+            r#"{"status": "spawned", "shard_set": 1, "master_shard_uds_path": "/tmp/shard-swap1-0"}"#,
+            r#"{"status": "error", "message": "shard-set 3 is not running"}"#,
+        ];
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for answer in answers {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                requests.push(serde_json::from_str::<serde_json::Value>(&line).unwrap());
+                stream
+                    .get_mut()
+                    .write_all(format!("{answer}\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+            requests
+        });
+
+        let launcher = Launcher::new(uds_path.to_string_lossy().into_owned());
+        let shard_set = launcher
+            .spawn(Some("v2".to_string()), Some("2,3".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            shard_set,
+            LaunchedShardSet {
+                shard_set: 1,
+                master_shard_uds_path: "/tmp/shard-swap1-0".to_string(),
+            }
+        );
+        assert!(matches!(
+            launcher.stop(3).await,
+            Err(InferError::SwapError(message)) if message == "shard-set 3 is not running"
+        ));
+
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                serde_json::json!({"command": "spawn", "revision": "v2", "cuda_visible_devices": "2,3"}),
+                serde_json::json!({"command": "stop", "shard_set": 3}),
+            ]
+        );
+        let _ = std::fs::remove_file(&uds_path);
+    }
+}
@@ -1,14 +1,43 @@
 mod backend;
 pub mod block_allocator;
+mod budget;
 mod client;
+mod disaggregation;
+mod draft;
+mod embed;
+mod launcher;
+mod lookup;
+mod models;
+mod preemption;
 mod queue;
 pub mod radix;
+mod replicas;
+mod slo;
+mod supervisor;
+mod swap;
+mod tenancy;
+mod timings;
+mod warmup;
 
-use crate::client::{ClientError, ShardedClient};
+use crate::block_allocator::Compaction;
+use crate::budget::is_out_of_memory;
+use crate::client::{ClientError, InfoResponse, ShardedClient};
+use crate::disaggregation::PrefillShards;
+use crate::draft::DraftShards;
+use crate::launcher::Launcher;
+use crate::queue::Fairness;
+pub use crate::queue::{SchedulingPolicy, ShortPromptBudget};
 pub(crate) use backend::BackendV3;
+pub use models::{ModelConfig, Models};
+pub use replicas::Replicas;
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use utoipa::ToSchema;
+use warmup::WarmupLimits;
+pub use warmup::WarmupShape;
 
 #[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct BackendInfo {
@@ -37,24 +66,339 @@ pub struct BackendInfo {
     pub attention_impl: String,
     #[schema(example = "1")]
     pub block_size: u32,
+    #[schema(nullable = true, example = "8192")]
+    pub context_length: Option<u32>,
+    #[schema(example = "128256")]
+    pub vocab_size: u32,
+    #[schema(nullable = true, example = "awq")]
+    pub quantization: Option<String>,
+    pub kernel_versions: BTreeMap<String, String>,
 
     #[schema(example = "30000")]
     pub max_input_tokens: usize,
     #[schema(example = "32000")]
     pub max_total_tokens: usize,
+
+    /// Shapes run during warmup, the ones that could not are left out
+    pub warmed_up_shapes: Vec<WarmupShape>,
 }
 
-#[allow(clippy::too_many_arguments)]
-pub async fn connect_backend(
+/// Parameters to connect to the shards of a replica, kept to reconnect when they restart
+#[derive(Clone, Debug)]
+pub(crate) struct ReplicaConfig {
     max_input_tokens: Option<usize>,
     max_total_tokens: Option<usize>,
+    /// Limits given at startup, from which the limits of the shards of another revision of the
+    /// model are derived again
+    requested_max_input_tokens: Option<usize>,
+    requested_max_total_tokens: Option<usize>,
     master_shard_uds_path: String,
+    /// Shard-set of the launcher serving the replica, stopped through it once replaced. None if
+    /// the shards were started separately.
+    shard_set: Option<usize>,
+    prefill_shard_uds_path: Option<String>,
+    draft_shard_uds_path: Option<String>,
+    waiting_served_ratio: f32,
+    target_ttft: Option<Duration>,
+    target_decode_latency: Option<Duration>,
+    max_batch_prefill_tokens: u32,
+    max_batch_total_tokens: Option<u32>,
+    max_waiting_tokens: usize,
+    max_batch_size: Option<usize>,
+    session_ttl: Duration,
+    kv_compaction_interval: Option<Duration>,
+    prefix_cache_ttl: Option<Duration>,
+    max_request_token_share: Option<f32>,
+    fairness_queue_depth: Option<usize>,
+    tenant_weights: Option<Arc<HashMap<String, f64>>>,
+    scheduling_policy: SchedulingPolicy,
+    short_prompts: Option<ShortPromptBudget>,
+    prompt_lookup_max_ngram: Option<usize>,
+    preemption_queue_size: Option<usize>,
+    warmup_retries: u32,
+    warmup_shapes: Arc<Vec<WarmupShape>>,
+}
+
+/// Delay before the first warmup retry, doubled on every retry
+const WARMUP_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_WARMUP_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connect to the replicas of every model and warm them up. The first model serves the
+/// requests that do not name one of the others. When the router was started by the launcher
+/// listening on `launcher_uds_path`, the single replica of the first model runs on the first
+/// shard-set of the launcher.
+#[allow(clippy::too_many_arguments)]
+pub async fn connect_backends(
+    max_input_tokens: Option<usize>,
+    max_total_tokens: Option<usize>,
+    models: Vec<ModelConfig>,
+    launcher_uds_path: Option<String>,
+    waiting_served_ratio: f32,
+    target_ttft: Option<Duration>,
+    target_decode_latency: Option<Duration>,
+    max_batch_prefill_tokens: u32,
+    max_batch_total_tokens: Option<u32>,
+    max_waiting_tokens: usize,
+    max_batch_size: Option<usize>,
+    session_ttl: Duration,
+    kv_compaction_interval: Option<Duration>,
+    prefix_cache_ttl: Option<Duration>,
+    max_request_token_share: Option<f32>,
+    fairness_queue_depth: Option<usize>,
+    tenant_weights: Option<HashMap<String, f64>>,
+    scheduling_policy: SchedulingPolicy,
+    short_prompts: Option<ShortPromptBudget>,
+    prompt_lookup_max_ngram: Option<usize>,
+    preemption_queue_size: Option<usize>,
+    warmup_retries: u32,
+    warmup_shapes: Vec<WarmupShape>,
+) -> Result<(Models, BackendInfo), V3Error> {
+    let tenant_weights = tenant_weights.map(Arc::new);
+    let warmup_shapes = Arc::new(warmup_shapes);
+    let mut served_models = Vec::with_capacity(models.len());
+    let mut backend_info: Option<BackendInfo> = None;
+    let launcher = launcher_uds_path.map(Launcher::new);
+
+    for model in models {
+        tracing::info!("Connecting to model {}", model.name);
+        let launched = launcher.is_some() && served_models.is_empty();
+        let (replicas, model_info) = connect_model(
+            max_input_tokens,
+            max_total_tokens,
+            model.master_shard_uds_paths,
+            launched,
+            model.prefill_shard_uds_paths,
+            model.draft_shard_uds_paths,
+            waiting_served_ratio,
+            target_ttft,
+            target_decode_latency,
+            max_batch_prefill_tokens,
+            max_batch_total_tokens,
+            max_waiting_tokens,
+            max_batch_size,
+            session_ttl,
+            kv_compaction_interval,
+            prefix_cache_ttl,
+            max_request_token_share,
+            fairness_queue_depth,
+            tenant_weights.clone(),
+            scheduling_policy,
+            short_prompts,
+            prompt_lookup_max_ngram,
+            preemption_queue_size,
+            warmup_retries,
+            warmup_shapes.clone(),
+        )
+        .await?;
+
+        backend_info = Some(match backend_info {
+            None => model_info,
+            // The router validates the requests of the other models with their own limits
+            Some(backend_info) => BackendInfo {
+                max_batch_total_tokens: backend_info
+                    .max_batch_total_tokens
+                    .min(model_info.max_batch_total_tokens),
+                support_chunking: backend_info.support_chunking && model_info.support_chunking,
+                ..backend_info
+            },
+        });
+        served_models.push((model.name, replicas));
+    }
+    let backend_info = backend_info.ok_or(V3Error::NoModel)?;
+
+    Ok((Models::new(served_models, launcher), backend_info))
+}
+
+/// Connect to the replicas of a model listening on `master_shard_uds_paths` and warm them up.
+/// When `prefill_shard_uds_paths` is not empty, each replica prefills its prompts on its own
+/// prefill shards. When `draft_shard_uds_paths` is not empty, a draft model on its own shards
+/// proposes the speculative tokens of each replica. When `launched`, the first replica runs on
+/// the first shard-set of the launcher.
+#[allow(clippy::too_many_arguments)]
+async fn connect_model(
+    mut max_input_tokens: Option<usize>,
+    mut max_total_tokens: Option<usize>,
+    master_shard_uds_paths: Vec<String>,
+    launched: bool,
+    prefill_shard_uds_paths: Vec<String>,
+    draft_shard_uds_paths: Vec<String>,
     waiting_served_ratio: f32,
+    target_ttft: Option<Duration>,
+    target_decode_latency: Option<Duration>,
     max_batch_prefill_tokens: u32,
     max_batch_total_tokens: Option<u32>,
     max_waiting_tokens: usize,
     max_batch_size: Option<usize>,
+    session_ttl: Duration,
+    kv_compaction_interval: Option<Duration>,
+    prefix_cache_ttl: Option<Duration>,
+    max_request_token_share: Option<f32>,
+    fairness_queue_depth: Option<usize>,
+    tenant_weights: Option<Arc<HashMap<String, f64>>>,
+    scheduling_policy: SchedulingPolicy,
+    short_prompts: Option<ShortPromptBudget>,
+    prompt_lookup_max_ngram: Option<usize>,
+    preemption_queue_size: Option<usize>,
+    warmup_retries: u32,
+    warmup_shapes: Arc<Vec<WarmupShape>>,
+) -> Result<(Replicas, BackendInfo), V3Error> {
+    let mut replicas = Vec::with_capacity(master_shard_uds_paths.len());
+    let mut backend_info: Option<BackendInfo> = None;
+    let mut total_batch_total_tokens = 0;
+    let (requested_max_input_tokens, requested_max_total_tokens) =
+        (max_input_tokens, max_total_tokens);
+
+    for (i, master_shard_uds_path) in master_shard_uds_paths.into_iter().enumerate() {
+        tracing::info!("Connecting to replica {i} on {master_shard_uds_path}");
+        let mut config = ReplicaConfig {
+            max_input_tokens,
+            max_total_tokens,
+            requested_max_input_tokens,
+            requested_max_total_tokens,
+            master_shard_uds_path,
+            shard_set: (launched && i == 0).then_some(0),
+            prefill_shard_uds_path: prefill_shard_uds_paths.get(i).cloned(),
+            draft_shard_uds_path: draft_shard_uds_paths.get(i).cloned(),
+            waiting_served_ratio,
+            target_ttft,
+            target_decode_latency,
+            max_batch_prefill_tokens,
+            max_batch_total_tokens,
+            max_waiting_tokens,
+            max_batch_size,
+            session_ttl,
+            kv_compaction_interval,
+            prefix_cache_ttl,
+            max_request_token_share,
+            fairness_queue_depth,
+            tenant_weights: tenant_weights.clone(),
+            scheduling_policy,
+            short_prompts,
+            prompt_lookup_max_ngram,
+            preemption_queue_size,
+            warmup_retries,
+            warmup_shapes: warmup_shapes.clone(),
+        };
+        let (replica, replica_info) = connect_replica(&config).await?;
+        // The other replicas, and this one when it reconnects, must accept the same requests
+        // as the first one
+        max_input_tokens = Some(replica_info.max_input_tokens);
+        max_total_tokens = Some(replica_info.max_total_tokens);
+        config.max_input_tokens = max_input_tokens;
+        config.max_total_tokens = max_total_tokens;
+        total_batch_total_tokens += replica_info.max_batch_total_tokens;
+
+        backend_info = Some(match backend_info {
+            None => replica_info,
+            // Batches are formed per replica, report the smallest one
+            Some(mut backend_info) => {
+                backend_info
+                    .warmed_up_shapes
+                    .retain(|shape| replica_info.warmed_up_shapes.contains(shape));
+                BackendInfo {
+                    max_batch_total_tokens: backend_info
+                        .max_batch_total_tokens
+                        .min(replica_info.max_batch_total_tokens),
+                    ..backend_info
+                }
+            }
+        });
+        replicas.push((replica, config));
+    }
+    let backend_info = backend_info.ok_or(V3Error::NoReplica)?;
+    // The models add up their capacities
+    metrics::gauge!("tgi_batch_max_total_tokens").increment(total_batch_total_tokens);
+
+    tracing::info!("Using backend V3 with {} replica(s)", replicas.len());
+
+    Ok((Replicas::new(replicas), backend_info))
+}
+
+/// Connect to the shards of a replica and warm them up
+pub(crate) async fn connect_replica(
+    config: &ReplicaConfig,
 ) -> Result<(BackendV3, BackendInfo), V3Error> {
+    let ReplicaConfig {
+        max_input_tokens,
+        max_total_tokens,
+        requested_max_input_tokens: _,
+        requested_max_total_tokens: _,
+        ref master_shard_uds_path,
+        shard_set: _,
+        ref prefill_shard_uds_path,
+        ref draft_shard_uds_path,
+        waiting_served_ratio,
+        target_ttft,
+        target_decode_latency,
+        max_batch_prefill_tokens,
+        max_batch_total_tokens,
+        max_waiting_tokens,
+        max_batch_size,
+        session_ttl,
+        kv_compaction_interval,
+        prefix_cache_ttl,
+        max_request_token_share,
+        fairness_queue_depth,
+        ref tenant_weights,
+        scheduling_policy,
+        short_prompts,
+        prompt_lookup_max_ngram,
+        preemption_queue_size,
+        warmup_retries,
+        ref warmup_shapes,
+    } = *config;
+
+    let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path.clone())
+        .await
+        .map_err(V3Error::Connection)?;
+
+    // server is running on v3
+    // Clear the cache; useful if the webserver rebooted
+    sharded_client
+        .clear_cache(None)
+        .await
+        .map_err(V3Error::Cache)?;
+    // Get info from the shard
+    let shard_info = sharded_client.info().await.map_err(V3Error::Info)?;
+    if !shard_info.requires_padding && shard_info.block_size == 0 {
+        return Err(V3Error::BlockSize);
+    }
+    tracing::info!(
+        "KV cache blocks of {} token(s), {}",
+        shard_info.block_size,
+        match shard_info.kv_bytes_per_token {
+            0 => "of unknown size".to_string(),
+            bytes => format!("{bytes} bytes per token"),
+        }
+    );
+
+    // Without limits, the requests can take the context window of the model
+    let max_prefill_input_tokens =
+        (!shard_info.support_chunking).then_some(max_batch_prefill_tokens as usize);
+    let context_length = shard_info
+        .context_length
+        .filter(|_| max_input_tokens.is_none() || max_total_tokens.is_none());
+    let (warmup_input_tokens, warmup_total_tokens) = match context_length {
+        Some(context_length) => {
+            let (input_tokens, total_tokens) = warmup::context_limits(
+                context_length as usize,
+                max_input_tokens,
+                max_total_tokens,
+                max_prefill_input_tokens,
+            )
+            .ok_or(V3Error::ContextLength(
+                max_input_tokens.unwrap_or_default(),
+                context_length,
+            ))?;
+            tracing::info!(
+                "Deriving max input tokens {input_tokens} and max total tokens {total_tokens} \
+                from the context length of {context_length}"
+            );
+            (Some(input_tokens), Some(total_tokens))
+        }
+        None => (max_input_tokens, max_total_tokens),
+    };
+
     // Helper function
     let check_max_batch_total_tokens = |(
         max_supported_batch_total_tokens,
@@ -62,11 +406,16 @@ pub async fn connect_backend(
         shard_max_total_tokens,
     ): (Option<u32>, u32, u32)|
      -> Result<(u32, usize, usize), V3Error> {
-        if let Some(max_input_tokens) = max_input_tokens {
-            assert_eq!(max_input_tokens as u32, shard_max_input_tokens);
-        }
-        if let Some(max_total_tokens) = max_total_tokens {
-            assert_eq!(max_total_tokens as u32, shard_max_total_tokens);
+        let expected = |limit: Option<usize>, shard_limit: u32| {
+            limit.map_or(true, |limit| limit as u32 == shard_limit)
+        };
+        if !expected(warmup_input_tokens, shard_max_input_tokens)
+            || !expected(warmup_total_tokens, shard_max_total_tokens)
+        {
+            return Err(V3Error::LimitsMismatch(
+                shard_max_input_tokens as usize,
+                shard_max_total_tokens as usize,
+            ));
         }
         match max_supported_batch_total_tokens {
             // Older models do not support automatic max-batch-total-tokens
@@ -96,7 +445,31 @@ pub async fn connect_backend(
                     );
                 }
                 if shard_max_total_tokens > max_supported_batch_total_tokens {
-                    return Err(V3Error::NotEnoughMemory(shard_max_total_tokens as usize));
+                    // The limits derived from the context window shrink to fit in the KV
+                    // cache, the ones of the user must fit as they are
+                    let (input_tokens, total_tokens) = match context_length {
+                        Some(_) => warmup::context_limits(
+                            max_supported_batch_total_tokens as usize,
+                            max_input_tokens,
+                            max_total_tokens,
+                            max_prefill_input_tokens,
+                        )
+                        .ok_or(V3Error::NotEnoughMemory(
+                            max_input_tokens.unwrap_or_default() + 1,
+                        ))?,
+                        None => (
+                            shard_max_input_tokens as usize,
+                            shard_max_total_tokens as usize,
+                        ),
+                    };
+                    if total_tokens > max_supported_batch_total_tokens as usize {
+                        return Err(V3Error::NotEnoughMemory(total_tokens));
+                    }
+                    tracing::warn!(
+                        "Reducing max input tokens to {input_tokens} and max total tokens to \
+                        {total_tokens} to fit in the KV cache"
+                    );
+                    return Ok((max_supported_batch_total_tokens, input_tokens, total_tokens));
                 }
 
                 Ok((
@@ -108,34 +481,82 @@ pub async fn connect_backend(
         }
     };
 
-    let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
-        .await
-        .map_err(V3Error::Connection)?;
-
-    // server is running on v3
-    // Clear the cache; useful if the webserver rebooted
-    sharded_client
-        .clear_cache(None)
-        .await
-        .map_err(V3Error::Cache)?;
-    // Get info from the shard
-    let shard_info = sharded_client.info().await.map_err(V3Error::Info)?;
-
     // Warmup model
     tracing::info!("Warming up model");
-    let answer = sharded_client
-        .warmup(
-            max_input_tokens.map(|p| p as u32),
-            max_batch_prefill_tokens,
-            max_total_tokens.map(|p| p as u32),
-            max_batch_size,
-        )
-        .await
-        .map_err(V3Error::Warmup)?;
+    let answer = warmup(
+        &mut sharded_client,
+        warmup_input_tokens.map(|p| p as u32),
+        max_batch_prefill_tokens,
+        warmup_total_tokens.map(|p| p as u32),
+        max_batch_size,
+        max_batch_total_tokens,
+        warmup_retries,
+    )
+    .await?;
     let (max_batch_total_tokens, max_input_tokens, max_total_tokens) =
         check_max_batch_total_tokens(answer)?;
     tracing::info!("Setting max batch total tokens to {max_batch_total_tokens}");
-    metrics::gauge!("tgi_batch_max_total_tokens").set(max_batch_total_tokens);
+
+    let warmed_up_shapes = warmup::warmup_shapes(
+        &mut sharded_client,
+        &shard_info,
+        warmup_shapes,
+        &WarmupLimits {
+            max_input_tokens,
+            max_total_tokens,
+            max_batch_prefill_tokens,
+            max_batch_total_tokens,
+            max_batch_size,
+        },
+    )
+    .await
+    .map_err(V3Error::Cache)?;
+    if !warmup_shapes.is_empty() {
+        tracing::info!("Warmed up shapes: {warmed_up_shapes:?}");
+    }
+
+    let prefill_shards = match prefill_shard_uds_path {
+        Some(prefill_shard_uds_path) => Some(
+            connect_prefill_shards(
+                prefill_shard_uds_path,
+                &sharded_client,
+                &shard_info,
+                max_input_tokens,
+                max_total_tokens,
+                max_batch_prefill_tokens,
+                max_batch_total_tokens,
+                max_batch_size,
+                warmup_retries,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    let draft_shards = match draft_shard_uds_path {
+        Some(draft_shard_uds_path) => {
+            // The prompts copied from the prefill shards are not in the KV cache of the draft
+            // shards
+            if prefill_shards.is_some() {
+                return Err(V3Error::DraftUnsupported(
+                    "prefill/decode disaggregation".to_string(),
+                ));
+            }
+            Some(
+                connect_draft_shards(
+                    draft_shard_uds_path,
+                    &shard_info,
+                    max_input_tokens,
+                    max_total_tokens,
+                    max_batch_prefill_tokens,
+                    max_batch_total_tokens,
+                    max_batch_size,
+                    warmup_retries,
+                )
+                .await?,
+            )
+        }
+        None => None,
+    };
 
     let backend_info = BackendInfo {
         waiting_served_ratio,
@@ -151,23 +572,277 @@ pub async fn connect_backend(
         prefix_caching: shard_info.use_prefix_caching,
         attention_impl: shard_info.attention_impl.clone(),
         block_size: shard_info.block_size,
+        context_length: shard_info.context_length,
+        vocab_size: shard_info.vocab_size,
+        quantization: shard_info.quantize.clone(),
+        kernel_versions: shard_info.kernel_versions.clone().into_iter().collect(),
+        warmed_up_shapes,
+    };
+
+    let fairness = Fairness {
+        max_request_tokens: max_request_token_share
+            .map(|share| (share * max_batch_total_tokens as f32) as u32),
+        queue_depth: fairness_queue_depth,
+        tenant_weights: tenant_weights.clone(),
+        scheduling_policy,
+        short_prompts,
     };
 
     let backend = BackendV3::new(
         sharded_client,
         waiting_served_ratio,
+        target_ttft,
+        target_decode_latency,
         max_batch_prefill_tokens,
         max_batch_total_tokens,
         max_waiting_tokens,
         max_batch_size,
+        session_ttl,
+        Compaction {
+            idle_interval: kv_compaction_interval,
+            prefix_ttl: prefix_cache_ttl,
+        },
+        fairness,
+        prompt_lookup_max_ngram,
+        preemption_queue_size,
         shard_info,
+        prefill_shards,
+        draft_shards,
     );
 
-    tracing::info!("Using backend V3");
-
     Ok((backend, backend_info))
 }
 
+/// Warm up the shards, retrying with a backoff when they run out of memory while probing the
+/// size of the KV cache. Once the retries are exhausted, the KV cache is allocated for the user
+/// provided `max_batch_total_tokens` if there is one. The other errors fail right away.
+async fn warmup(
+    sharded_client: &mut ShardedClient,
+    max_input_tokens: Option<u32>,
+    max_batch_prefill_tokens: u32,
+    max_total_tokens: Option<u32>,
+    max_batch_size: Option<usize>,
+    max_batch_total_tokens: Option<u32>,
+    warmup_retries: u32,
+) -> Result<(Option<u32>, u32, u32), V3Error> {
+    let mut attempt = 0;
+    let mut degraded = false;
+    loop {
+        let result = sharded_client
+            .warmup(
+                max_input_tokens,
+                max_batch_prefill_tokens,
+                max_total_tokens,
+                max_batch_size,
+                max_batch_total_tokens.filter(|_| degraded),
+            )
+            .await;
+        match result {
+            Ok(answer) => return Ok(answer),
+            // A crashed shard or an invalid configuration fails the same way again
+            Err(err) if !is_memory_probing_error(&err) => return Err(V3Error::Warmup(err)),
+            Err(err) if attempt < warmup_retries => {
+                let backoff =
+                    (WARMUP_BACKOFF * 2u32.saturating_pow(attempt)).min(MAX_WARMUP_BACKOFF);
+                attempt += 1;
+                tracing::warn!(
+                    "Warmup failed: {err}. Retrying in {backoff:?} ({attempt}/{warmup_retries})"
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(err) => match max_batch_total_tokens {
+                // Memory probing on the shards keeps failing: trust the user provided value
+                Some(max_batch_total_tokens) if !degraded => {
+                    tracing::error!("Warmup failed: {err}");
+                    tracing::error!(
+                        "DEGRADED MODE: skipping memory probing and allocating the KV cache for \
+                        `--max-batch-total-tokens={max_batch_total_tokens}`. The shards may run \
+                        out of memory under load."
+                    );
+                    degraded = true;
+                }
+                _ => return Err(V3Error::Warmup(err)),
+            },
+        }
+        // Free what the failed warmup may have left on the shards
+        sharded_client
+            .clear_cache(None)
+            .await
+            .map_err(V3Error::Cache)?;
+    }
+}
+
+/// Whether the shards ran out of memory while probing the size of the KV cache, which other
+/// processes may release or skipping the probing avoids
+fn is_memory_probing_error(err: &ClientError) -> bool {
+    is_out_of_memory(err)
+        || matches!(err, ClientError::Generation(message) if message.contains("Not enough memory"))
+}
+
+/// Connect to the shards prefilling the prompts of a replica and warm them up with the limits
+/// of the shards that decode them
+#[allow(clippy::too_many_arguments)]
+async fn connect_prefill_shards(
+    prefill_shard_uds_path: &str,
+    decode_client: &ShardedClient,
+    decode_info: &InfoResponse,
+    max_input_tokens: usize,
+    max_total_tokens: usize,
+    max_batch_prefill_tokens: u32,
+    max_batch_total_tokens: u32,
+    max_batch_size: Option<usize>,
+    warmup_retries: u32,
+) -> Result<PrefillShards, V3Error> {
+    tracing::info!("Connecting to the prefill shards on {prefill_shard_uds_path}");
+    // The decode shards skip the part of the prompt found in their prefix cache, which is
+    // where the copied KV goes
+    if !decode_info.use_prefix_caching {
+        return Err(V3Error::PrefillWithoutPrefixCaching);
+    }
+    // The prefill shards do not chunk the prompts
+    if max_input_tokens > max_batch_prefill_tokens as usize {
+        return Err(V3Error::PrefillBudget(
+            max_input_tokens,
+            max_batch_prefill_tokens,
+        ));
+    }
+
+    let mut client = ShardedClient::connect_uds(prefill_shard_uds_path.to_string())
+        .await
+        .map_err(V3Error::Connection)?;
+    client.clear_cache(None).await.map_err(V3Error::Cache)?;
+    let shard_info = client.info().await.map_err(V3Error::Info)?;
+    // The KV is copied block by block, from every shard to the shard of the same rank
+    if shard_info.block_size != decode_info.block_size {
+        return Err(V3Error::PrefillMismatch(format!(
+            "`block_size={}` instead of `block_size={}`",
+            shard_info.block_size, decode_info.block_size
+        )));
+    }
+    if shard_info.kv_bytes_per_token != decode_info.kv_bytes_per_token {
+        return Err(V3Error::PrefillMismatch(format!(
+            "{} KV cache bytes per token instead of {}",
+            shard_info.kv_bytes_per_token, decode_info.kv_bytes_per_token
+        )));
+    }
+    if client.shards() != decode_client.shards() {
+        return Err(V3Error::PrefillMismatch(format!(
+            "{} shard(s) instead of {}",
+            client.shards(),
+            decode_client.shards()
+        )));
+    }
+
+    tracing::info!("Warming up the prefill shards");
+    let (prefill_batch_total_tokens, shard_max_input_tokens, shard_max_total_tokens) = warmup(
+        &mut client,
+        Some(max_input_tokens as u32),
+        max_batch_prefill_tokens,
+        Some(max_total_tokens as u32),
+        max_batch_size,
+        Some(max_batch_total_tokens),
+        warmup_retries,
+    )
+    .await?;
+    if shard_max_input_tokens as usize != max_input_tokens
+        || shard_max_total_tokens as usize != max_total_tokens
+    {
+        return Err(V3Error::LimitsMismatch(
+            shard_max_input_tokens as usize,
+            shard_max_total_tokens as usize,
+        ));
+    }
+    let max_batch_total_tokens = prefill_batch_total_tokens.unwrap_or(max_batch_total_tokens);
+    tracing::info!(
+        "Setting max batch total tokens of the prefill shards to {max_batch_total_tokens}"
+    );
+
+    Ok(PrefillShards {
+        client,
+        shard_info,
+        max_batch_total_tokens,
+    })
+}
+
+/// Connect to the shards of the draft model of a replica and warm them up with the limits of
+/// the shards verifying its tokens
+#[allow(clippy::too_many_arguments)]
+async fn connect_draft_shards(
+    draft_shard_uds_path: &str,
+    verify_info: &InfoResponse,
+    max_input_tokens: usize,
+    max_total_tokens: usize,
+    max_batch_prefill_tokens: u32,
+    max_batch_total_tokens: u32,
+    max_batch_size: Option<usize>,
+    warmup_retries: u32,
+) -> Result<DraftShards, V3Error> {
+    tracing::info!("Connecting to the draft shards on {draft_shard_uds_path}");
+    // The drafts replace the speculative tokens of the shards, which must not come from
+    // speculator heads
+    if verify_info.speculate == 0 || !verify_info.accepts_draft_tokens {
+        return Err(V3Error::DraftUnsupported(
+            "a model without `--speculate` or with speculator heads".to_string(),
+        ));
+    }
+
+    let mut client = ShardedClient::connect_uds(draft_shard_uds_path.to_string())
+        .await
+        .map_err(V3Error::Connection)?;
+    client.clear_cache(None).await.map_err(V3Error::Cache)?;
+    let shard_info = client.info().await.map_err(V3Error::Info)?;
+    // The draft shards store the KV of the requests in the blocks allocated by the router
+    if shard_info.block_size != verify_info.block_size {
+        return Err(V3Error::DraftMismatch(format!(
+            "`block_size={}` instead of `block_size={}`",
+            shard_info.block_size, verify_info.block_size
+        )));
+    }
+    if shard_info.speculate > 0 {
+        return Err(V3Error::DraftMismatch(format!(
+            "`speculate={}` instead of `speculate=0`",
+            shard_info.speculate
+        )));
+    }
+    // The chunks of the prompts are prefilled after the cached batch on both shard-sets
+    if shard_info.support_chunking != verify_info.support_chunking {
+        return Err(V3Error::DraftMismatch(format!(
+            "`support_chunking={}` instead of `support_chunking={}`",
+            shard_info.support_chunking, verify_info.support_chunking
+        )));
+    }
+
+    tracing::info!("Warming up the draft shards");
+    let (draft_batch_total_tokens, shard_max_input_tokens, shard_max_total_tokens) = warmup(
+        &mut client,
+        Some(max_input_tokens as u32),
+        max_batch_prefill_tokens,
+        Some(max_total_tokens as u32),
+        max_batch_size,
+        Some(max_batch_total_tokens),
+        warmup_retries,
+    )
+    .await?;
+    if shard_max_input_tokens as usize != max_input_tokens
+        || shard_max_total_tokens as usize != max_total_tokens
+    {
+        return Err(V3Error::LimitsMismatch(
+            shard_max_input_tokens as usize,
+            shard_max_total_tokens as usize,
+        ));
+    }
+    match draft_batch_total_tokens {
+        Some(draft_batch_total_tokens) if draft_batch_total_tokens >= max_batch_total_tokens => {
+            Ok(DraftShards { client })
+        }
+        Some(draft_batch_total_tokens) => Err(V3Error::DraftMismatch(format!(
+            "room for {draft_batch_total_tokens} tokens in the KV cache instead of at least \
+            {max_batch_total_tokens}"
+        ))),
+        None => Err(V3Error::DraftMismatch("no paged KV cache".to_string())),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum V3Error {
     #[error("Unable to clear the Python model shards cache: {0}")]
@@ -180,4 +855,33 @@ pub enum V3Error {
     Warmup(ClientError),
     #[error("Not enough memory to handle `max_total_tokens={0}`")]
     NotEnoughMemory(usize),
+    #[error(
+        "`max_input_tokens={0}` leaves no token to the generation in the context length of \
+        {1} tokens of the model, set `max_total_tokens` to go beyond it"
+    )]
+    ContextLength(usize, u32),
+    #[error(
+        "The shards use `max_input_tokens={0}` and `max_total_tokens={1}`, unlike the other \
+        shards of the model"
+    )]
+    LimitsMismatch(usize, usize),
+    #[error("The shards page their KV cache with blocks of 0 tokens")]
+    BlockSize,
+    #[error("No master shard uds path was given")]
+    NoReplica,
+    #[error("No model was given")]
+    NoModel,
+    #[error("Prefill/decode disaggregation requires prefix caching on the decode shards")]
+    PrefillWithoutPrefixCaching,
+    #[error(
+        "The prefill shards do not chunk the prompts: `max_input_tokens={0}` must not exceed \
+        `max_batch_prefill_tokens={1}`"
+    )]
+    PrefillBudget(usize, u32),
+    #[error("The prefill shards do not match the decode shards: {0}")]
+    PrefillMismatch(String),
+    #[error("A draft model cannot be used with {0}")]
+    DraftUnsupported(String),
+    #[error("The draft shards do not match the shards verifying their tokens: {0}")]
+    DraftMismatch(String),
 }
@@ -0,0 +1,98 @@
+/// Prompt lookup speculation
+use crate::client::DraftTokens;
+
+/// Proposes the speculative tokens of a request by looking up the last generated n-gram in
+/// its context.
+///
+/// Generations that copy parts of their prompt (summaries, code edits, retrieval answers)
+/// repeat long spans of it, so the tokens that followed the previous occurrence of the last
+/// n-gram are likely to come next. The main model verifies them like any other speculation.
+#[derive(Debug)]
+pub(crate) struct PromptLookup {
+    /// Prompt followed by the generated tokens
+    tokens: Vec<u32>,
+    /// Longest n-gram looked up. Shorter ones are tried when it is not found.
+    max_ngram: usize,
+    /// Maximum number of proposed tokens
+    max_draft: usize,
+}
+
+impl PromptLookup {
+    pub(crate) fn new(prompt: &[u32], max_ngram: usize, max_draft: usize) -> Self {
+        Self {
+            tokens: prompt.to_vec(),
+            max_ngram,
+            max_draft,
+        }
+    }
+
+    /// Add the tokens generated by the last forward
+    pub(crate) fn extend(&mut self, ids: &[u32]) {
+        self.tokens.extend_from_slice(ids);
+    }
+
+    /// Tokens that followed the most recent previous occurrence of the longest n-gram ending
+    /// the context. Empty if no n-gram is found.
+    pub(crate) fn propose(&self) -> &[u32] {
+        let len = self.tokens.len();
+        for n in (1..=self.max_ngram.min(len.saturating_sub(1))).rev() {
+            let ngram = &self.tokens[len - n..];
+            // Windows of the context without its last token, so the n-gram does not match itself
+            if let Some(start) = self.tokens[..len - 1]
+                .windows(n)
+                .rposition(|window| window == ngram)
+            {
+                let draft_start = start + n;
+                let draft_end = (draft_start + self.max_draft).min(len);
+                return &self.tokens[draft_start..draft_end];
+            }
+        }
+        &[]
+    }
+
+    /// Draft tokens to send to the shards for request `request_id`
+    pub(crate) fn draft_tokens(&self, request_id: u64) -> Option<DraftTokens> {
+        let ids = self.propose();
+        (!ids.is_empty()).then(|| DraftTokens {
+            request_id,
+            ids: ids.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_propose_continuation_of_prompt_ngram() {
+        let mut lookup = PromptLookup::new(&[1, 2, 3, 4, 5, 6, 7], 2, 3);
+        lookup.extend(&[9, 2, 3]);
+        assert_eq!(lookup.propose(), &[4, 5, 6]);
+    }
+
+    #[test]
+    fn test_propose_prefers_longest_ngram() {
+        // `3` alone last appears before `8`, but `2, 3` appears before `4`
+        let lookup = PromptLookup::new(&[2, 3, 4, 3, 8, 2, 3], 2, 1);
+        assert_eq!(lookup.propose(), &[4]);
+
+        let lookup = PromptLookup::new(&[2, 3, 4, 3, 8, 2, 3], 1, 1);
+        assert_eq!(lookup.propose(), &[8]);
+    }
+
+    #[test]
+    fn test_propose_stops_at_end_of_context() {
+        let lookup = PromptLookup::new(&[1, 2, 1], 1, 4);
+        assert_eq!(lookup.propose(), &[2, 1]);
+    }
+
+    #[test]
+    fn test_propose_without_match() {
+        let mut lookup = PromptLookup::new(&[1, 2, 3], 3, 4);
+        assert!(lookup.propose().is_empty());
+        lookup.extend(&[4]);
+        assert!(lookup.draft_tokens(0).is_none());
+        assert!(PromptLookup::new(&[], 3, 4).propose().is_empty());
+    }
+}
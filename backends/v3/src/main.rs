@@ -1,8 +1,16 @@
 use clap::{Parser, Subcommand};
-use text_generation_router::{server, usage_stats};
-use text_generation_router_v3::{connect_backend, V3Error};
+use std::collections::HashMap;
+use std::time::Duration;
+use text_generation_router::infer::mock::MockBackend;
+use text_generation_router::{bench, server, usage_stats};
+use text_generation_router_v3::{
+    connect_backends, ModelConfig, SchedulingPolicy, ShortPromptBudget, V3Error, WarmupShape,
+};
 use thiserror::Error;
 
+/// Limit of the requests served by the mock backend without `max_total_tokens`
+const MOCK_MAX_TOTAL_TOKENS: usize = 4096;
+
 /// App Configuration
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -14,7 +22,7 @@ struct Args {
     max_concurrent_requests: usize,
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
-    #[clap(default_value = "4", long, env)]
+    #[clap(default_value = "16", long, env)]
     max_stop_sequences: usize,
     #[clap(default_value = "5", long, env)]
     max_top_n_tokens: u32,
@@ -24,6 +32,10 @@ struct Args {
     max_total_tokens: Option<usize>,
     #[clap(default_value = "1.2", long, env)]
     waiting_served_ratio: f32,
+    #[clap(long, env)]
+    target_ttft_ms: Option<u64>,
+    #[clap(long, env)]
+    target_decode_latency_ms: Option<u64>,
     #[clap(default_value = "4096", long, env)]
     max_batch_prefill_tokens: u32,
     #[clap(long, env)]
@@ -36,13 +48,30 @@ struct Args {
     hostname: String,
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
-    #[clap(default_value = "/tmp/text-generation-server-0", long, env)]
-    master_shard_uds_path: String,
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
+    #[clap(
+        default_value = "/tmp/text-generation-server-0",
+        long,
+        env,
+        value_delimiter = ','
+    )]
+    master_shard_uds_path: Vec<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    prefill_shard_uds_path: Vec<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    draft_shard_uds_path: Vec<String>,
+    #[clap(long, env, value_delimiter = ';')]
+    served_model: Vec<String>,
+    #[clap(long, env)]
+    launcher_uds_path: Option<String>,
     #[clap(default_value = "bigscience/bloom", long, env)]
     tokenizer_name: String,
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
     #[clap(long, env)]
+    chat_template_path: Option<String>,
+    #[clap(long, env)]
     revision: Option<String>,
     #[clap(long, env, value_enum)]
     trust_remote_code: bool,
@@ -51,6 +80,10 @@ struct Args {
     #[clap(long, env)]
     api_key: Option<String>,
     #[clap(long, env)]
+    api_keys_path: Option<String>,
+    #[clap(long, env)]
+    usage_path: Option<String>,
+    #[clap(long, env)]
     json_output: bool,
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
@@ -72,11 +105,113 @@ struct Args {
     usage_stats: usage_stats::UsageStatsLevel,
     #[clap(default_value = "2000000", long, env)]
     payload_limit: usize,
+    #[clap(long, env)]
+    max_queue_size: Option<usize>,
+    #[clap(long, env)]
+    max_queue_wait: Option<u64>,
+    #[clap(long, env)]
+    health_check_interval: Option<u64>,
+    #[clap(long, env)]
+    stream_heartbeat_interval: Option<u64>,
+    #[clap(long, env)]
+    queue_journal_path: Option<String>,
+    #[clap(long, env)]
+    rate_limit_requests: Option<f64>,
+    #[clap(long, env)]
+    rate_limit_tokens: Option<u32>,
+    #[clap(long, env)]
+    rate_limit_concurrent_requests: Option<u32>,
+    #[clap(long, env)]
+    rate_limit_config_path: Option<String>,
+    #[clap(long, env)]
+    tenant_header: Option<String>,
+    #[clap(long, env)]
+    response_cache_size: Option<usize>,
+    #[clap(default_value = "3600", long, env)]
+    response_cache_ttl: u64,
+    #[clap(long, env)]
+    response_cache_redis_url: Option<String>,
+    #[clap(long, env)]
+    audit_log: Option<String>,
+    #[clap(default_value = "1.0", long, env)]
+    audit_sample_rate: f64,
+    #[clap(default_value = "256", long, env)]
+    audit_prompt_length: usize,
+    #[clap(long, env)]
+    audit_hash_prompts: bool,
+    #[clap(long, env)]
+    audit_hash_key: Option<String>,
+    #[clap(long, env)]
+    watermark_keys_path: Option<String>,
+    #[clap(default_value = "0.5", long, env)]
+    watermark_gamma: f64,
+    #[clap(long, env)]
+    content_filter_path: Option<String>,
+    #[clap(long, env)]
+    transforms_path: Option<String>,
+    #[clap(long, env)]
+    default_max_new_tokens: Option<u32>,
+    #[clap(long, env)]
+    max_new_tokens_ceiling: Option<u32>,
+    #[clap(long, env)]
+    stream_max_new_tokens_ceiling: Option<u32>,
+    #[clap(long, env)]
+    max_new_tokens_config_path: Option<String>,
+    #[clap(long, env)]
+    idempotency_cache_size: Option<usize>,
+    #[clap(default_value = "3600", long, env)]
+    idempotency_ttl: u64,
+    #[clap(long, env)]
+    stream_resume_events: Option<usize>,
+    #[clap(default_value = "30", long, env)]
+    stream_resume_timeout: u64,
+    #[clap(long, env)]
+    prompt_templates_path: Option<String>,
+    #[clap(long, env)]
+    max_images_per_request: Option<usize>,
+    #[clap(long, env)]
+    max_image_pixels: Option<usize>,
+    #[clap(long, env)]
+    allow_vocab_mismatch: bool,
+    #[clap(default_value = "300", long, env)]
+    session_ttl: u64,
+    #[clap(long, env)]
+    kv_compaction_interval: Option<u64>,
+    #[clap(long, env)]
+    prefix_cache_ttl: Option<u64>,
+    #[clap(long, env)]
+    max_request_token_share: Option<f32>,
+    #[clap(long, env)]
+    fairness_queue_depth: Option<usize>,
+    #[clap(long, env)]
+    tenant_fair_share: bool,
+    #[clap(long, env, value_delimiter = ';')]
+    tenant_weight: Vec<String>,
+    #[clap(default_value = "priority", long, env)]
+    scheduling_policy: SchedulingPolicy,
+    #[clap(long, env, requires = "short_prompt_prefill_share")]
+    short_prompt_tokens: Option<u32>,
+    #[clap(long, env, requires = "short_prompt_tokens")]
+    short_prompt_prefill_share: Option<f32>,
+    #[clap(long, env)]
+    prompt_lookup_max_ngram: Option<usize>,
+    #[clap(long, env)]
+    preemption_queue_size: Option<usize>,
+    #[clap(default_value = "3", long, env)]
+    warmup_retries: u32,
+    #[clap(long, env, value_delimiter = ',')]
+    warmup_shape: Vec<String>,
+    #[clap(long, env)]
+    mock_backend: bool,
+    #[clap(default_value = "0", long, env)]
+    mock_tokens_per_second: f64,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     PrintSchema,
+    /// Replay a JSONL trace of requests against a running router or the mock backend
+    Bench(bench::BenchArgs),
 }
 
 #[tokio::main]
@@ -93,19 +228,29 @@ async fn main() -> Result<(), RouterError> {
         max_input_tokens,
         max_total_tokens,
         waiting_served_ratio,
+        target_ttft_ms,
+        target_decode_latency_ms,
         max_batch_prefill_tokens,
         max_batch_total_tokens,
         max_waiting_tokens,
         max_batch_size,
         hostname,
         port,
+        grpc_port,
         master_shard_uds_path,
+        prefill_shard_uds_path,
+        draft_shard_uds_path,
+        served_model,
+        launcher_uds_path,
         tokenizer_name,
         tokenizer_config_path,
+        chat_template_path,
         revision,
         trust_remote_code,
         validation_workers,
         api_key,
+        api_keys_path,
+        usage_path,
         json_output,
         otlp_endpoint,
         otlp_service_name,
@@ -117,6 +262,56 @@ async fn main() -> Result<(), RouterError> {
         max_client_batch_size,
         usage_stats,
         payload_limit,
+        session_ttl,
+        kv_compaction_interval,
+        prefix_cache_ttl,
+        max_request_token_share,
+        fairness_queue_depth,
+        tenant_fair_share,
+        tenant_weight,
+        scheduling_policy,
+        short_prompt_tokens,
+        short_prompt_prefill_share,
+        prompt_lookup_max_ngram,
+        preemption_queue_size,
+        max_queue_size,
+        max_queue_wait,
+        health_check_interval,
+        stream_heartbeat_interval,
+        queue_journal_path,
+        rate_limit_requests,
+        rate_limit_tokens,
+        rate_limit_concurrent_requests,
+        rate_limit_config_path,
+        tenant_header,
+        response_cache_size,
+        response_cache_ttl,
+        response_cache_redis_url,
+        audit_log,
+        audit_sample_rate,
+        audit_prompt_length,
+        audit_hash_prompts,
+        audit_hash_key,
+        watermark_keys_path,
+        watermark_gamma,
+        content_filter_path,
+        transforms_path,
+        default_max_new_tokens,
+        max_new_tokens_ceiling,
+        stream_max_new_tokens_ceiling,
+        max_new_tokens_config_path,
+        idempotency_cache_size,
+        idempotency_ttl,
+        stream_resume_events,
+        stream_resume_timeout,
+        prompt_templates_path,
+        max_images_per_request,
+        max_image_pixels,
+        allow_vocab_mismatch,
+        warmup_retries,
+        warmup_shape,
+        mock_backend,
+        mock_tokens_per_second,
     } = args;
 
     if let Some(Commands::PrintSchema) = command {
@@ -126,6 +321,9 @@ async fn main() -> Result<(), RouterError> {
         println!("{}", api_doc);
         std::process::exit(0);
     };
+    if let Some(Commands::Bench(bench_args)) = command {
+        return Ok(bench::run(bench_args).await?);
+    }
     text_generation_router::logging::init_logging(otlp_endpoint, otlp_service_name, json_output);
 
     // Validate args
@@ -134,6 +332,16 @@ async fn main() -> Result<(), RouterError> {
             "`validation_workers` must be > 0".to_string(),
         ));
     }
+    if health_check_interval == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`health_check_interval` must be > 0".to_string(),
+        ));
+    }
+    if stream_heartbeat_interval == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`stream_heartbeat_interval` must be > 0".to_string(),
+        ));
+    }
     if let Some(max_batch_size) = max_batch_size {
         if max_batch_size == 0 {
             return Err(RouterError::ArgumentValidation(
@@ -141,16 +349,248 @@ async fn main() -> Result<(), RouterError> {
             ));
         }
     }
+    if let Some(max_request_token_share) = max_request_token_share {
+        if max_request_token_share <= 0.0 || max_request_token_share > 1.0 {
+            return Err(RouterError::ArgumentValidation(
+                "`max_request_token_share` must be > 0.0 and <= 1.0".to_string(),
+            ));
+        }
+    }
+    if prompt_lookup_max_ngram == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`prompt_lookup_max_ngram` must be > 0".to_string(),
+        ));
+    }
+    if preemption_queue_size == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`preemption_queue_size` must be > 0".to_string(),
+        ));
+    }
+    if short_prompt_tokens == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`short_prompt_tokens` must be > 0".to_string(),
+        ));
+    }
+    if let Some(short_prompt_prefill_share) = short_prompt_prefill_share {
+        if short_prompt_prefill_share <= 0.0 || short_prompt_prefill_share >= 1.0 {
+            return Err(RouterError::ArgumentValidation(
+                "`short_prompt_prefill_share` must be > 0.0 and < 1.0".to_string(),
+            ));
+        }
+    }
+    let short_prompts = short_prompt_tokens.zip(short_prompt_prefill_share).map(
+        |(max_input_tokens, reserved_share)| ShortPromptBudget {
+            max_input_tokens,
+            reserved_share,
+        },
+    );
+    if target_ttft_ms == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`target_ttft_ms` must be > 0".to_string(),
+        ));
+    }
+    if target_decode_latency_ms == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`target_decode_latency_ms` must be > 0".to_string(),
+        ));
+    }
+    if kv_compaction_interval == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`kv_compaction_interval` must be > 0".to_string(),
+        ));
+    }
+    if prefix_cache_ttl.is_some() && kv_compaction_interval.is_none() {
+        tracing::warn!("`prefix_cache_ttl` is only applied when compacting, it will be ignored without `kv_compaction_interval`.");
+    }
+
+    if !prefill_shard_uds_path.is_empty()
+        && prefill_shard_uds_path.len() != master_shard_uds_path.len()
+    {
+        return Err(RouterError::ArgumentValidation(format!(
+            "`prefill_shard_uds_path` must name one master shard per `master_shard_uds_path`. \
+            Given: {} instead of {}",
+            prefill_shard_uds_path.len(),
+            master_shard_uds_path.len()
+        )));
+    }
+    if !draft_shard_uds_path.is_empty() && draft_shard_uds_path.len() != master_shard_uds_path.len()
+    {
+        return Err(RouterError::ArgumentValidation(format!(
+            "`draft_shard_uds_path` must name one master shard per `master_shard_uds_path`. \
+            Given: {} instead of {}",
+            draft_shard_uds_path.len(),
+            master_shard_uds_path.len()
+        )));
+    }
+
+    // The model of the router is the default one
+    let mut models = vec![ModelConfig {
+        name: tokenizer_name.clone(),
+        master_shard_uds_paths: master_shard_uds_path,
+        prefill_shard_uds_paths: prefill_shard_uds_path,
+        draft_shard_uds_paths: draft_shard_uds_path,
+    }];
+    for served_model in served_model {
+        let Some((name, master_shard_uds_path)) = served_model.split_once('=') else {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`served_model` must be `<NAME>=<MASTER_SHARD_UDS_PATH>`. Given: {served_model}"
+            )));
+        };
+        match models.iter_mut().find(|model| model.name == name) {
+            Some(model) => model
+                .master_shard_uds_paths
+                .push(master_shard_uds_path.to_string()),
+            None => models.push(ModelConfig {
+                name: name.to_string(),
+                master_shard_uds_paths: vec![master_shard_uds_path.to_string()],
+                prefill_shard_uds_paths: Vec::new(),
+                draft_shard_uds_paths: Vec::new(),
+            }),
+        }
+    }
+
+    // Tenants that are not listed have a weight of 1
+    let mut tenant_weights = HashMap::new();
+    for tenant_weight in tenant_weight {
+        let weight = tenant_weight
+            .split_once('=')
+            .and_then(|(name, weight)| Some((name, weight.parse::<f64>().ok()?)));
+        match weight {
+            Some((name, weight)) if weight > 0.0 && weight.is_finite() => {
+                tenant_weights.insert(name.to_string(), weight);
+            }
+            _ => {
+                return Err(RouterError::ArgumentValidation(format!(
+                    "`tenant_weight` must be `<TENANT>=<WEIGHT>` with a weight > 0. Given: {tenant_weight}"
+                )));
+            }
+        }
+    }
+    let tenant_weights =
+        (tenant_fair_share || !tenant_weights.is_empty()).then_some(tenant_weights);
+
+    let warmup_shapes = warmup_shape
+        .iter()
+        .map(|shape| {
+            shape.parse::<WarmupShape>().map_err(|err| {
+                RouterError::ArgumentValidation(format!("`warmup_shape` {err}. Given: {shape}"))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Serve with the backend, within the limits of its requests
+    macro_rules! run_server {
+        ($backend:expr, $max_input_tokens:expr, $max_total_tokens:expr) => {
+            server::run(
+                $backend,
+                max_concurrent_requests,
+                max_best_of,
+                max_stop_sequences,
+                max_top_n_tokens,
+                $max_input_tokens,
+                $max_total_tokens,
+                validation_workers,
+                api_key,
+                api_keys_path,
+                usage_path,
+                tokenizer_name,
+                tokenizer_config_path,
+                chat_template_path,
+                revision,
+                trust_remote_code,
+                hostname,
+                port,
+                grpc_port,
+                cors_allow_origin,
+                ngrok,
+                ngrok_authtoken,
+                ngrok_edge,
+                disable_grammar_support,
+                max_client_batch_size,
+                usage_stats,
+                payload_limit,
+                max_queue_size,
+                max_queue_wait.map(Duration::from_secs),
+                health_check_interval.map(Duration::from_secs),
+                stream_heartbeat_interval.map(Duration::from_secs),
+                queue_journal_path,
+                rate_limit_requests,
+                rate_limit_tokens,
+                rate_limit_concurrent_requests,
+                rate_limit_config_path,
+                tenant_header,
+                response_cache_size,
+                Duration::from_secs(response_cache_ttl),
+                response_cache_redis_url,
+                audit_log,
+                audit_sample_rate,
+                audit_prompt_length,
+                audit_hash_prompts,
+                audit_hash_key,
+                watermark_keys_path,
+                watermark_gamma,
+                content_filter_path,
+                transforms_path,
+                default_max_new_tokens,
+                max_new_tokens_ceiling,
+                stream_max_new_tokens_ceiling,
+                max_new_tokens_config_path,
+                idempotency_cache_size,
+                Duration::from_secs(idempotency_ttl),
+                stream_resume_events,
+                Duration::from_secs(stream_resume_timeout),
+                prompt_templates_path,
+                max_images_per_request,
+                max_image_pixels,
+                allow_vocab_mismatch,
+            )
+            .await?;
+        };
+    }
+
+    if mock_backend {
+        // The tokens are generated without shards, the requests are still validated with the
+        // tokenizer of the model
+        let max_total_tokens = max_total_tokens.unwrap_or(MOCK_MAX_TOTAL_TOKENS);
+        let max_input_tokens = max_input_tokens.unwrap_or(max_total_tokens.saturating_sub(1));
+        if max_input_tokens >= max_total_tokens {
+            return Err(RouterError::ArgumentValidation(
+                "`max_input_tokens` must be < `max_total_tokens`".to_string(),
+            ));
+        }
+        tracing::info!("Using the mock backend");
+        run_server!(
+            MockBackend::new(mock_tokens_per_second),
+            max_input_tokens,
+            max_total_tokens
+        );
+        return Ok(());
+    }
 
-    let (backend, backend_info) = connect_backend(
+    let (backend, backend_info) = connect_backends(
         max_input_tokens,
         max_total_tokens,
-        master_shard_uds_path,
+        models,
+        launcher_uds_path,
         waiting_served_ratio,
+        target_ttft_ms.map(Duration::from_millis),
+        target_decode_latency_ms.map(Duration::from_millis),
         max_batch_prefill_tokens,
         max_batch_total_tokens,
         max_waiting_tokens,
         max_batch_size,
+        Duration::from_secs(session_ttl),
+        kv_compaction_interval.map(Duration::from_secs),
+        prefix_cache_ttl.map(Duration::from_secs),
+        max_request_token_share,
+        fairness_queue_depth,
+        tenant_weights,
+        scheduling_policy,
+        short_prompts,
+        prompt_lookup_max_ngram,
+        preemption_queue_size,
+        warmup_retries,
+        warmup_shapes,
     )
     .await?;
 
@@ -182,6 +622,12 @@ async fn main() -> Result<(), RouterError> {
     if max_input_tokens as u32 > max_batch_prefill_tokens && !support_chunking {
         return Err(RouterError::ArgumentValidation(format!("`max_batch_prefill_tokens` must be >= `max_input_tokens`. Given: {max_batch_prefill_tokens} and {max_input_tokens}")));
     }
+    if let Some(short_prompts) = short_prompts {
+        let long_prefill_tokens = short_prompts.long_prefill_token_budget(max_batch_prefill_tokens);
+        if max_input_tokens as u32 > long_prefill_tokens && !support_chunking {
+            return Err(RouterError::ArgumentValidation(format!("The long prompts must fit in the prefill budget left by `short_prompt_prefill_share`, `max_input_tokens` must be <= {long_prefill_tokens}. Given: {max_input_tokens}")));
+        }
+    }
     if max_batch_prefill_tokens > max_batch_total_tokens {
         return Err(RouterError::ArgumentValidation(format!("`max_batch_prefill_tokens` must be <= `max_batch_total_tokens`. Given: {max_batch_prefill_tokens} and {max_batch_total_tokens}")));
     }
@@ -190,32 +636,7 @@ async fn main() -> Result<(), RouterError> {
     }
 
     // Run server
-    server::run(
-        backend,
-        max_concurrent_requests,
-        max_best_of,
-        max_stop_sequences,
-        max_top_n_tokens,
-        max_input_tokens,
-        max_total_tokens,
-        validation_workers,
-        api_key,
-        tokenizer_name,
-        tokenizer_config_path,
-        revision,
-        trust_remote_code,
-        hostname,
-        port,
-        cors_allow_origin,
-        ngrok,
-        ngrok_authtoken,
-        ngrok_edge,
-        disable_grammar_support,
-        max_client_batch_size,
-        usage_stats,
-        payload_limit,
-    )
-    .await?;
+    run_server!(backend, max_input_tokens, max_total_tokens);
     Ok(())
 }
 
@@ -227,6 +648,8 @@ enum RouterError {
     Backend(#[from] V3Error),
     #[error("WebServer error: {0}")]
     WebServer(#[from] server::WebServerError),
+    #[error("Benchmark failed: {0}")]
+    Bench(#[from] bench::BenchError),
     #[error("Tokio runtime failed to start: {0}")]
     Tokio(#[from] std::io::Error),
 }
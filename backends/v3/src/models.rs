@@ -0,0 +1,313 @@
+/// Several models served by the same router
+use crate::launcher::Launcher;
+use crate::replicas::Replicas;
+use async_trait::async_trait;
+use futures::future::join_all;
+use std::collections::HashMap;
+use text_generation_router::infer::swap::{ModelSwap, ShardCheck};
+use text_generation_router::infer::{Backend, InferError, InferStreamResponse};
+use text_generation_router::validation::ValidGenerateRequest;
+use text_generation_router::{
+    BatchRecord, CacheStats, IterationTimings, LoadAdapterRequest, LoraAdapterInfo, QueuedRequest,
+    SessionStats, ShardInfo, SwapModelRequest,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::instrument;
+
+/// Shard-sets of a model
+#[derive(Clone, Debug)]
+pub struct ModelConfig {
+    /// Name selecting the model in the `model` field of the requests
+    pub name: String,
+    /// Master shard of every replica of the model
+    pub master_shard_uds_paths: Vec<String>,
+    /// Master shard prefilling the prompts of every replica, if the replicas are disaggregated
+    pub prefill_shard_uds_paths: Vec<String>,
+    /// Master shard of the draft model of every replica, if a draft model proposes the
+    /// speculative tokens
+    pub draft_shard_uds_paths: Vec<String>,
+}
+
+/// Models served side by side, each with its own replicas, queues and block allocators.
+///
+/// A request goes to the model named by its `adapter_id`, which the OpenAI routes set from
+/// their `model` field, and an embedding request to the model named by its `model`. Requests
+/// naming another adapter, or none, go to the first model, which loads the adapter as before.
+/// The router validates the requests of every model with its own tokenizer and limits.
+pub struct Models {
+    /// The first one is the default model
+    models: Vec<(String, Replicas)>,
+    /// Launcher of the shards of the default model, which starts the shards it is swapped for
+    launcher: Option<Launcher>,
+}
+
+impl Models {
+    pub(crate) fn new(models: Vec<(String, Replicas)>, launcher: Option<Launcher>) -> Self {
+        assert!(!models.is_empty(), "At least one model is required");
+        Self { models, launcher }
+    }
+
+    fn default_model(&self) -> &Replicas {
+        &self.models[0].1
+    }
+
+    fn model(&self, name: &str) -> Option<&Replicas> {
+        self.models
+            .iter()
+            .find(|(model, _)| model == name)
+            .map(|(_, model)| model)
+    }
+
+    /// Pick the model of a new request
+    fn route(&self, request: &mut ValidGenerateRequest) -> &Replicas {
+        let model = request
+            .adapter_id
+            .as_deref()
+            .and_then(|adapter_id| self.model(adapter_id));
+        match model {
+            Some(model) => {
+                // The name of a model is not the name of one of its adapters
+                request.adapter_id = None;
+                model
+            }
+            None => self.default_model(),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for Models {
+    #[instrument(skip_all)]
+    fn schedule(
+        &self,
+        mut request: ValidGenerateRequest,
+    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        self.route(&mut request).schedule(request)
+    }
+
+    fn shares_prompt_kv(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.shares_prompt_kv())
+    }
+
+    fn supports_embeddings(&self) -> bool {
+        self.models
+            .iter()
+            .any(|(_, model)| model.supports_embeddings())
+    }
+
+    #[instrument(skip_all)]
+    async fn embed(
+        &self,
+        model: Option<&str>,
+        input_ids: Vec<Vec<u32>>,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        model
+            .and_then(|model| self.model(model))
+            .unwrap_or_else(|| self.default_model())
+            .embed(None, input_ids)
+            .await
+    }
+
+    async fn health(&self, current_health: bool) -> bool {
+        let health = join_all(
+            self.models
+                .iter()
+                .map(|(_, model)| model.health(current_health)),
+        )
+        .await;
+        // Requests to a model that is down would fail
+        health.into_iter().all(|healthy| healthy)
+    }
+
+    fn start_health(&self) -> bool {
+        true
+    }
+
+    async fn batch_history(&self) -> Vec<BatchRecord> {
+        let mut history: Vec<BatchRecord> =
+            join_all(self.models.iter().map(|(_, model)| model.batch_history()))
+                .await
+                .into_iter()
+                .flatten()
+                .collect();
+        history.sort_by_key(|batch| batch.timestamp);
+        history
+    }
+
+    fn iteration_timings(&self) -> Vec<IterationTimings> {
+        let mut timings: Vec<IterationTimings> = self
+            .models
+            .iter()
+            .flat_map(|(_, model)| model.iteration_timings())
+            .collect();
+        timings.sort_by_key(|iteration| iteration.timestamp);
+        timings
+    }
+
+    async fn session_stats(&self) -> Vec<SessionStats> {
+        join_all(self.models.iter().map(|(_, model)| model.session_stats()))
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    async fn queued_requests(&self) -> Vec<QueuedRequest> {
+        join_all(self.models.iter().map(|(_, model)| model.queued_requests()))
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    async fn cache_stats(&self) -> Vec<CacheStats> {
+        let stats = join_all(self.models.iter().map(|(_, model)| model.cache_stats())).await;
+        self.models
+            .iter()
+            .zip(stats)
+            .flat_map(|((name, _), stats)| {
+                stats.into_iter().map(|stats| CacheStats {
+                    model: name.clone(),
+                    ..stats
+                })
+            })
+            .collect()
+    }
+
+    fn waiting_served_ratio(&self) -> Option<f32> {
+        // All the models share the same settings
+        self.default_model().waiting_served_ratio()
+    }
+
+    fn set_waiting_served_ratio(&self, waiting_served_ratio: f32) {
+        for (_, model) in self.models.iter() {
+            model.set_waiting_served_ratio(waiting_served_ratio);
+        }
+    }
+
+    fn served_models(&self) -> Vec<String> {
+        self.models.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn model_token_limits(&self, model: &str) -> Option<(usize, usize)> {
+        self.model(model)?.token_limits()
+    }
+
+    fn model_shard_info(&self, model: &str) -> Option<ShardInfo> {
+        self.model(model)?.shard_info()
+    }
+
+    fn supports_token_healing(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_token_healing())
+    }
+
+    fn supports_logit_bias(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_logit_bias())
+    }
+
+    fn supports_presence_penalty(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_presence_penalty())
+    }
+
+    fn supports_min_p(&self) -> bool {
+        self.models.iter().all(|(_, model)| model.supports_min_p())
+    }
+
+    fn supports_no_repeat_ngram_size(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_no_repeat_ngram_size())
+    }
+
+    fn supports_guidance(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_guidance())
+    }
+
+    fn lora_adapters(&self) -> Option<HashMap<String, u32>> {
+        // The requests mixing adapters go to the default model
+        self.default_model().lora_adapters()
+    }
+
+    fn supports_watermark_key(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_watermark_key())
+    }
+
+    fn supports_stop_token_ids(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_stop_token_ids())
+    }
+
+    fn supports_min_new_tokens(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_min_new_tokens())
+    }
+
+    fn supports_deadlines(&self) -> bool {
+        self.models
+            .iter()
+            .all(|(_, model)| model.supports_deadlines())
+    }
+
+    fn shard_info(&self) -> Option<ShardInfo> {
+        self.default_model().shard_info()
+    }
+
+    fn loaded_adapters(&self) -> Vec<LoraAdapterInfo> {
+        self.default_model().loaded_adapters()
+    }
+
+    async fn load_adapter(
+        &self,
+        request: LoadAdapterRequest,
+    ) -> Result<LoraAdapterInfo, InferError> {
+        // The requests with this `adapter_id` would go to the model of the same name
+        if self.models.iter().any(|(name, _)| *name == request.id) {
+            return Err(InferError::AdapterError(format!(
+                "`{}` is the name of a served model",
+                request.id
+            )));
+        }
+        self.default_model().load_adapter(request).await
+    }
+
+    async fn unload_adapter(&self, id: &str) -> Result<(), InferError> {
+        self.default_model().unload_adapter(id).await
+    }
+
+    async fn swap_model(
+        &self,
+        request: SwapModelRequest,
+        check: ShardCheck,
+    ) -> Result<ModelSwap, InferError> {
+        let index = match &request.model {
+            Some(model) => self
+                .models
+                .iter()
+                .position(|(name, _)| name == model)
+                .ok_or_else(|| InferError::SwapError(format!("`{model}` is not a served model")))?,
+            None => 0,
+        };
+        self.models[index]
+            .1
+            .swap(request, self.launcher.as_ref(), check)
+            .await
+    }
+
+    async fn shutdown(&self) {
+        join_all(self.models.iter().map(|(_, model)| model.shutdown())).await;
+    }
+}
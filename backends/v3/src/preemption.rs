@@ -0,0 +1,119 @@
+/// Soft preemption of the running requests when the queue builds up
+use crate::queue::Entry;
+use nohash_hasher::IntMap;
+
+/// Tokens generated by an entry in its current round, to resume it once preempted.
+///
+/// A preempted entry ends its round with a `length` finish reason at its next token, and the
+/// infer task continues it with a new round whose prompt ends with the generated text. The KV
+/// of the generated tokens is kept in the prefix cache and copied to the host memory of the
+/// shards if they have one, so the next round only prefills what was lost in between.
+#[derive(Debug, Default)]
+pub(crate) struct Checkpoint {
+    pub ids: Vec<u32>,
+    /// Text of the tokens, without the special ones
+    pub text: String,
+    /// Set when the entry is to be preempted
+    pub preempted: bool,
+}
+
+impl Checkpoint {
+    pub(crate) fn push(&mut self, id: u32, text: &str, special: bool) {
+        self.ids.push(id);
+        if !special {
+            self.text.push_str(text);
+        }
+    }
+}
+
+/// Entry to preempt among the running `entries`: the one running for the longest time among
+/// the ones that can resume with the same prompt.
+pub(crate) fn victim(entries: &IntMap<u64, Entry>) -> Option<u64> {
+    entries
+        .iter()
+        .filter(|(_, entry)| can_resume(entry))
+        .min_by_key(|(_, entry)| entry.batch_time)
+        .map(|(&id, _)| id)
+}
+
+fn can_resume(entry: &Entry) -> bool {
+    let Some(checkpoint) = entry.checkpoint.as_ref() else {
+        return false;
+    };
+    let request = &entry.request;
+    let generated = checkpoint.ids.len() as u32;
+    !checkpoint.preempted
+        && generated > 0
+        // Nothing to gain from the requests about to finish
+        && generated + 1 < request.stopping_parameters.max_new_tokens
+        // The state of the grammar and the details of the prompt do not carry over rounds
+        && request.parameters.grammar.is_none()
+        && !request.decoder_input_details
+        // The prompt of the next round must not be truncated
+        && request.input_length + generated <= request.truncate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::default_entry;
+    use std::time::Duration;
+    use text_generation_router::validation::ValidGrammar;
+    use tokio::time::Instant;
+
+    /// Entry running since `batch_time`, which generated `generated` of its 16 tokens
+    fn running(batch_time: Instant, generated: u32) -> Entry {
+        let (mut entry, _) = default_entry();
+        entry.request.input_length = 4;
+        entry.request.truncate = 1024;
+        entry.request.stopping_parameters.max_new_tokens = 16;
+        entry.batch_time = Some(batch_time);
+        let mut checkpoint = Checkpoint::default();
+        for id in 0..generated {
+            checkpoint.push(id, " a", false);
+        }
+        entry.checkpoint = Some(checkpoint);
+        entry
+    }
+
+    #[test]
+    fn test_victim() {
+        let start = Instant::now();
+        let mut entries = IntMap::default();
+        assert_eq!(victim(&entries), None);
+
+        entries.insert(0, running(start + Duration::from_secs(2), 4));
+        entries.insert(1, running(start + Duration::from_secs(1), 4));
+        // The entry running for the longest time
+        assert_eq!(victim(&entries), Some(1));
+
+        // The entries that cannot resume are passed over, however long they ran
+        let cannot_resume: [fn(&mut Entry); 7] = [
+            |entry| entry.checkpoint = None,
+            |entry| entry.checkpoint.as_mut().unwrap().preempted = true,
+            |entry| entry.checkpoint.as_mut().unwrap().ids.clear(),
+            |entry| entry.request.stopping_parameters.max_new_tokens = 5,
+            |entry| entry.request.parameters.grammar = Some(ValidGrammar::JsonObject),
+            |entry| entry.request.decoder_input_details = true,
+            |entry| entry.request.truncate = 7,
+        ];
+        for update in cannot_resume {
+            let mut entry = running(start, 4);
+            update(&mut entry);
+            entries.insert(2, entry);
+            assert_eq!(victim(&entries), Some(1));
+        }
+        entries.insert(2, running(start, 4));
+        assert_eq!(victim(&entries), Some(2));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.push(10, " Hello", false);
+        checkpoint.push(11, " world", false);
+        checkpoint.push(2, "</s>", true);
+        assert_eq!(checkpoint.ids, vec![10, 11, 2]);
+        assert_eq!(checkpoint.text, " Hello world");
+    }
+}
@@ -1,21 +1,166 @@
-use crate::block_allocator::{BlockAllocation, BlockAllocator};
+use crate::block_allocator::{BlockAllocation, BlockAllocator, Compaction};
 use crate::client;
 use crate::client::{
     Batch, GrammarType, NextTokenChooserParameters, Request, StoppingCriteriaParameters,
 };
+use crate::disaggregation::Handoff;
+use crate::draft::Draft;
+use crate::lookup::PromptLookup;
+use crate::preemption::Checkpoint;
+use crate::swap::SwapSpace;
+use crate::tenancy::FairShare;
 use nohash_hasher::{BuildNoHashHasher, IntMap};
-use std::cmp::max;
-use std::collections::VecDeque;
-use text_generation_router::infer::InferError;
+use std::cmp::{max, Ordering as CmpOrdering};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use text_generation_router::infer::InferStreamResponse;
+use text_generation_router::infer::{BatchResidency, InferError};
 use text_generation_router::validation::{
-    Chunk, ChunksToString, ValidGenerateRequest, ValidGrammar, ValidParameters,
+    Chunk, ChunksToString, ValidGenerateRequest, ValidGrammar, ValidGuidance, ValidParameters,
     ValidStoppingParameters,
 };
+use text_generation_router::{BatchRecord, CacheStats, QueuedRequest, SessionStats};
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
 use tracing::{info_span, instrument, Instrument, Span};
 
+/// Number of batches kept in the batch history
+const BATCH_HISTORY_SIZE: usize = 256;
+
+/// Maximum number of times an entry can be overtaken by higher priority entries.
+/// Past this point the entry keeps its place, so lower classes cannot be starved.
+const MAX_OVERTAKEN: u32 = 32;
+
+/// Minimum number of tokens a request may generate in a round, so that requests with a long
+/// prompt are not continued after every token.
+const MIN_ROUND_NEW_TOKENS: u32 = 16;
+
+/// Set in the ids of the requests generating the negative prompts of the guided requests, on
+/// top of the id of their guided request
+pub(crate) const NEGATIVE_REQUEST_BIT: u64 = 1 << 63;
+
+/// Order in which the queued requests are added to the batches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchedulingPolicy {
+    /// By priority, then by tenant share and arrival order
+    #[default]
+    Priority,
+    /// As `priority`, except that the requests that waited for more than half of the time
+    /// before their `deadline_ms` go first, earliest deadline first
+    Edf,
+}
+
+/// Prefill capacity of every batch kept for the short prompts, so that a flood of long
+/// prompts cannot delay their first token
+#[derive(Clone, Copy, Debug)]
+pub struct ShortPromptBudget {
+    /// Prompts with fewer tokens are short
+    pub max_input_tokens: u32,
+    /// Share of the prefill token budget of a batch that the long prompts cannot use
+    pub reserved_share: f32,
+}
+
+impl ShortPromptBudget {
+    /// Prefill tokens of a batch of `prefill_token_budget` that the long prompts can use
+    pub fn long_prefill_token_budget(&self, prefill_token_budget: u32) -> u32 {
+        (prefill_token_budget as f32 * (1.0 - self.reserved_share)) as u32
+    }
+}
+
+/// Order of the queue and limits on the share of the batch a single request or tenant may hold
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Fairness {
+    /// Maximum number of tokens, prompt included, a request may reserve in a scheduling round.
+    /// A request that needs more is continued in a new round that is queued again.
+    pub(crate) max_request_tokens: Option<u32>,
+    /// Number of queued requests from which the continued requests are queued behind the new
+    /// requests of the same priority
+    pub(crate) queue_depth: Option<usize>,
+    /// Weights of the tenants sharing the queue. Without them, requests of the same priority
+    /// are dequeued in arrival order whatever their tenant.
+    pub(crate) tenant_weights: Option<Arc<HashMap<String, f64>>>,
+    pub(crate) scheduling_policy: SchedulingPolicy,
+    /// Prefill budget of the short prompts. Without it, all the prompts share the budget.
+    pub(crate) short_prompts: Option<ShortPromptBudget>,
+}
+
+impl Fairness {
+    /// Whether the request can use the prefill budget reserved for the short prompts
+    fn is_short(&self, request: &ValidGenerateRequest) -> bool {
+        self.short_prompts.map_or(true, |short_prompts| {
+            request.input_length < short_prompts.max_input_tokens
+        })
+    }
+
+    /// Prefill tokens of a batch of `prefill_token_budget` that the long prompts can use
+    fn long_prefill_token_budget(&self, prefill_token_budget: u32) -> u32 {
+        match self.short_prompts {
+            Some(short_prompts) => short_prompts.long_prefill_token_budget(prefill_token_budget),
+            None => prefill_token_budget,
+        }
+    }
+
+    /// Number of tokens the request may generate in this round, or `None` without a limit
+    fn round_max_new_tokens(&self, request: &ValidGenerateRequest) -> Option<u32> {
+        // A continued round restarts the grammar, the output would not follow it anymore
+        if request.parameters.grammar.is_some() {
+            return None;
+        }
+        self.max_request_tokens.map(|max_request_tokens| {
+            max_request_tokens
+                .saturating_sub(request.input_length)
+                .max(MIN_ROUND_NEW_TOKENS)
+        })
+    }
+}
+
+/// Parameters of a queue and of its block allocator
+#[derive(Debug, Clone)]
+pub(crate) struct QueueConfig {
+    /// Whether the shards pad the batches to their longest prompt, they then have no blocks
+    pub(crate) requires_padding: bool,
+    /// Paged Attention block size
+    pub(crate) block_size: u32,
+    /// Bytes of the KV cache of a token over all the shards, 0 if unknown
+    pub(crate) kv_bytes_per_token: u64,
+    pub(crate) prefix_caching: bool,
+    /// Sliding window of the model, if it has one
+    pub(crate) window_size: Option<u32>,
+    /// Time the prefix of a session is kept after its last request
+    pub(crate) session_ttl: Duration,
+    /// Host memory the preempted blocks are swapped to, if the shards have some
+    pub(crate) swap_space: Option<SwapSpace>,
+    pub(crate) compaction: Compaction,
+    /// Speculation amount
+    pub(crate) speculate: u32,
+    /// Tokens of the KV cache of the shards
+    pub(crate) max_batch_total_tokens: u32,
+    /// Whether the model allow the prefill chunking
+    pub(crate) support_chunking: bool,
+    pub(crate) fairness: Fairness,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            requires_padding: false,
+            block_size: 1,
+            kv_bytes_per_token: 0,
+            prefix_caching: false,
+            window_size: None,
+            session_ttl: Duration::from_secs(300),
+            swap_space: None,
+            compaction: Compaction::default(),
+            speculate: 0,
+            max_batch_total_tokens: 0,
+            support_chunking: false,
+            fairness: Fairness::default(),
+        }
+    }
+}
+
 /// Queue entry
 #[derive(Debug)]
 pub(crate) struct Entry {
@@ -33,6 +178,58 @@ pub(crate) struct Entry {
     pub batch_time: Option<Instant>,
     /// Block Allocation
     pub block_allocation: Option<BlockAllocation>,
+    /// Block allocation of the negative sequence, if the request uses classifier-free guidance
+    pub negative_block_allocation: Option<BlockAllocation>,
+    /// Number of higher priority entries that were queued ahead of this entry
+    pub overtaken: u32,
+    /// Counts this entry in the load of the backend
+    pub in_flight: InFlight,
+    /// Proposes the speculative tokens of this entry, if the router drafts them
+    pub lookup: Option<PromptLookup>,
+    /// Speculative tokens of this entry, if a draft model proposes them
+    pub draft: Option<Draft>,
+    /// Start tag of the entry among the entries of the other tenants
+    pub tag: f64,
+    /// Stage of the entry if its prompt is prefilled by other shards than the ones decoding it
+    pub handoff: Option<Handoff>,
+    /// Batches that generated the tokens of this entry
+    pub batches: BatchResidency,
+    /// Tokens generated so far, tracked if the running entries can be preempted
+    pub checkpoint: Option<Checkpoint>,
+}
+
+impl Entry {
+    /// Number of requests of the entry in the batches of the shards, its negative sequence
+    /// included
+    pub(crate) fn sequences(&self) -> usize {
+        if self.negative_block_allocation.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+/// Number of requests of `entries` in the batches of the shards
+pub(crate) fn sequences(entries: &IntMap<u64, Entry>) -> usize {
+    entries.values().map(Entry::sequences).sum()
+}
+
+/// Guard counting a request in the load of a backend until it is dropped
+#[derive(Debug)]
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub(crate) fn new(load: Arc<AtomicUsize>) -> Self {
+        load.fetch_add(1, Ordering::Relaxed);
+        Self(load)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 /// Request Queue
@@ -43,29 +240,12 @@ pub(crate) struct Queue {
 }
 
 impl Queue {
-    pub(crate) fn new(
-        requires_padding: bool,
-        block_size: u32,
-        prefix_caching: bool,
-        window_size: Option<u32>,
-        speculate: u32,
-        max_batch_total_tokens: u32,
-        support_chunking: bool,
-    ) -> Self {
+    pub(crate) fn new(config: QueueConfig) -> Self {
         // Create channel
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
 
         // Launch background queue task
-        tokio::spawn(queue_task(
-            requires_padding,
-            block_size,
-            prefix_caching,
-            window_size,
-            speculate,
-            max_batch_total_tokens,
-            support_chunking,
-            queue_receiver,
-        ));
+        tokio::spawn(queue_task(config, queue_receiver));
 
         Self { queue_sender }
     }
@@ -111,32 +291,122 @@ impl Queue {
         // Unwrap is safe here
         response_receiver.await.unwrap()
     }
+
+    /// Get the prefix cache usage of the sessions
+    #[instrument(skip(self))]
+    pub(crate) async fn session_stats(&self) -> Vec<SessionStats> {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::SessionStats { response_sender })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Get the KV cache usage of the block allocator, if the queue has one
+    #[instrument(skip(self))]
+    pub(crate) async fn cache_stats(&self) -> Option<CacheStats> {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::CacheStats { response_sender })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Number of entries waiting in the queue
+    #[instrument(skip(self))]
+    pub(crate) async fn len(&self) -> usize {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Len { response_sender })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Get the entries waiting in the queue, in their order
+    #[instrument(skip(self))]
+    pub(crate) async fn entries(&self) -> Vec<QueuedRequest> {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Entries { response_sender })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Get the most recent batches formed by the queue
+    #[instrument(skip(self))]
+    pub(crate) async fn batch_history(&self) -> Vec<BatchRecord> {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::BatchHistory { response_sender })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Hand the entries that have not started generating over to `handover` once the queue is
+    /// closed, instead of failing them
+    pub(crate) fn set_handover(&self, handover: mpsc::UnboundedSender<Entry>) {
+        let _ = self.queue_sender.send(QueueCommand::Handover(handover));
+    }
+
+    /// Fail the queued entries and the ones appended later, once the shards are gone. The ones
+    /// that have not started generating are handed over instead, if the queue has a handover.
+    #[instrument(skip(self))]
+    pub(crate) fn close(&self) {
+        // The queue task is gone if the backend was already closed and dropped
+        let _ = self.queue_sender.send(QueueCommand::Close);
+    }
 }
 
 // Background task responsible of the queue state
-#[allow(clippy::too_many_arguments)]
-async fn queue_task(
-    requires_padding: bool,
-    block_size: u32,
-    prefix_caching: bool,
-    window_size: Option<u32>,
-    speculate: u32,
-    max_batch_total_tokens: u32,
-    support_chunking: bool,
-    mut receiver: mpsc::UnboundedReceiver<QueueCommand>,
-) {
-    let mut state = State::new(
-        requires_padding,
-        block_size,
-        prefix_caching,
-        window_size,
-        speculate,
-        max_batch_total_tokens,
-        support_chunking,
-    );
-
-    while let Some(cmd) = receiver.recv().await {
+async fn queue_task(config: QueueConfig, mut receiver: mpsc::UnboundedReceiver<QueueCommand>) {
+    let mut state = State::new(config);
+
+    loop {
+        // The entries expire at their deadline even when no batch is scheduled, as when the
+        // KV cache is full
+        let next_deadline = state.next_deadline();
+        let cmd = tokio::select! {
+            cmd = receiver.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now)),
+                if next_deadline.is_some() =>
+            {
+                state.expire_deadlines();
+                metrics::gauge!("tgi_queue_size").set(state.entries.len() as f64);
+                continue;
+            }
+        };
         match cmd {
+            QueueCommand::Append(entry, span) if state.closed => {
+                span.in_scope(|| hand_over(*entry, state.handover.as_ref()));
+            }
             QueueCommand::Append(entry, span) => {
                 span.in_scope(|| state.append(*entry));
                 metrics::gauge!("tgi_queue_size").increment(1.0);
@@ -156,10 +426,80 @@ async fn queue_task(
                 response_sender.send(next_batch).unwrap();
                 metrics::gauge!("tgi_queue_size").set(state.entries.len() as f64);
             }
+            QueueCommand::BatchHistory { response_sender } => {
+                let history = state.batch_history.iter().cloned().collect();
+                response_sender.send(history).unwrap();
+            }
+            QueueCommand::SessionStats { response_sender } => {
+                let stats = match &state.block_allocator {
+                    Some(block_allocator) => block_allocator.session_stats().await,
+                    None => Vec::new(),
+                };
+                response_sender.send(stats).unwrap();
+            }
+            QueueCommand::CacheStats { response_sender } => {
+                let stats = match &state.block_allocator {
+                    Some(block_allocator) => Some(block_allocator.cache_stats().await),
+                    None => None,
+                };
+                response_sender.send(stats).unwrap();
+            }
+            QueueCommand::Len { response_sender } => {
+                response_sender.send(state.entries.len()).unwrap();
+            }
+            QueueCommand::Entries { response_sender } => {
+                response_sender.send(state.queued_requests()).unwrap();
+            }
+            QueueCommand::Handover(handover) => {
+                state.handover = Some(handover);
+            }
+            QueueCommand::Close => {
+                state.closed = true;
+                for (_, entry) in state.entries.drain(..) {
+                    hand_over(entry, state.handover.as_ref());
+                }
+                metrics::gauge!("tgi_queue_size").set(0.0);
+            }
         }
     }
 }
 
+/// Fail an entry still queued at its deadline
+fn expire(entry: Entry) {
+    metrics::counter!("tgi_request_failure", "err" => "deadline_exceeded").increment(1);
+    let _ = entry.response_tx.send(Err(InferError::DeadlineExceeded));
+}
+
+/// Whether an entry waited for more than half of the time it had before its deadline
+fn deadline_at_risk(entry: &Entry, deadline: Instant, now: Instant) -> bool {
+    now.duration_since(entry.queue_time) * 2 >= deadline.duration_since(entry.queue_time)
+}
+
+/// Send an entry of a closed queue to `handover` if none of its tokens were streamed yet, as a
+/// preempted entry resumes from its checkpoint on these shards. The others are rejected.
+fn hand_over(entry: Entry, handover: Option<&mpsc::UnboundedSender<Entry>>) {
+    let started = entry
+        .checkpoint
+        .as_ref()
+        .is_some_and(|checkpoint| !checkpoint.ids.is_empty());
+    match handover {
+        Some(handover) if !started => {
+            if let Err(mpsc::error::SendError(entry)) = handover.send(entry) {
+                reject(entry);
+            }
+        }
+        _ => reject(entry),
+    }
+}
+
+/// Fail an entry of a closed queue with an error its client can retry
+fn reject(entry: Entry) {
+    metrics::counter!("tgi_request_failure", "err" => "backend_unavailable").increment(1);
+    let _ = entry.response_tx.send(Err(InferError::BackendUnavailable(
+        "lost the connection to the shards".to_string(),
+    )));
+}
+
 /// Queue State
 #[derive(Debug)]
 struct State {
@@ -185,27 +525,54 @@ struct State {
 
     /// Paged Attention Block Allocation
     block_allocator: Option<BlockAllocator>,
+
+    /// Limits on the share of the batch of a single request
+    fairness: Fairness,
+
+    /// Shares the queue between the tenants, if they have weights
+    fair_share: Option<FairShare>,
+
+    /// Most recent batches, oldest first
+    batch_history: VecDeque<BatchRecord>,
+
+    /// Set when the shards went down, new entries are then rejected
+    closed: bool,
+
+    /// Receives the entries of the closed queue that can start over on another replica
+    handover: Option<mpsc::UnboundedSender<Entry>>,
 }
 
 impl State {
-    fn new(
-        requires_padding: bool,
-        block_size: u32,
-        prefix_caching: bool,
-        window_size: Option<u32>,
-        speculate: u32,
-        max_batch_total_tokens: u32,
-        support_chunking: bool,
-    ) -> Self {
+    fn new(config: QueueConfig) -> Self {
+        let QueueConfig {
+            requires_padding,
+            block_size,
+            kv_bytes_per_token,
+            prefix_caching,
+            window_size,
+            session_ttl,
+            swap_space,
+            compaction,
+            speculate,
+            max_batch_total_tokens,
+            support_chunking,
+            fairness,
+        } = config;
         let block_allocator = (!requires_padding).then(|| {
             BlockAllocator::new(
                 max_batch_total_tokens,
                 block_size,
+                kv_bytes_per_token,
                 prefix_caching,
                 window_size,
+                session_ttl,
+                swap_space,
+                compaction,
             )
         });
 
+        let fair_share = fairness.tenant_weights.clone().map(FairShare::new);
+
         Self {
             entries: VecDeque::with_capacity(128),
             next_id: 0,
@@ -214,20 +581,142 @@ impl State {
             speculate,
             support_chunking,
             block_allocator,
+            fairness,
+            fair_share,
+            batch_history: VecDeque::with_capacity(BATCH_HISTORY_SIZE),
+            closed: false,
+            handover: None,
         }
     }
 
     /// Append an entry to the queue
     fn append(&mut self, mut entry: Entry) {
         // Create a span that will live as long as the entry is in the queue waiting to be batched
-        let queue_span = info_span!(parent: &entry.span, "queued");
+        let queue_span = info_span!(
+            parent: &entry.span,
+            "queued",
+            id = self.next_id,
+            input_tokens = entry.request.input_length
+        );
         entry.temp_span = Some(queue_span);
+        if let Some(fair_share) = self.fair_share.as_mut() {
+            entry.tag = fair_share.tag(entry.request.tenant.as_deref());
+        }
+
+        // Entries are kept ordered by priority. The new entry goes ahead of any lower priority
+        // entry, unless that entry has already been overtaken too many times. When the queue is
+        // deep, continued requests rank below the new requests of their priority. Within a
+        // rank, entries are ordered by their tenant start tag, which is 0 without tenants.
+        let deep = self
+            .fairness
+            .queue_depth
+            .is_some_and(|queue_depth| self.entries.len() >= queue_depth);
+        let rank = |entry: &Entry| {
+            let continued = deep && entry.request.generated_tokens > 0;
+            (entry.request.priority, !continued)
+        };
+        let entry_rank = rank(&entry);
+        let entry_tag = entry.tag;
+        let mut index = self.entries.len();
+        while index > 0 {
+            let (_, queued) = &self.entries[index - 1];
+            let stays_ahead = match rank(queued).cmp(&entry_rank) {
+                CmpOrdering::Greater => true,
+                CmpOrdering::Equal => queued.tag <= entry_tag,
+                // Only overtaking by a higher rank is limited, tenants cannot starve each other
+                CmpOrdering::Less => queued.overtaken >= MAX_OVERTAKEN,
+            };
+            if stays_ahead {
+                break;
+            }
+            index -= 1;
+        }
+        for (_, queued) in self.entries.range_mut(index..) {
+            if rank(queued) < entry_rank {
+                queued.overtaken += 1;
+            }
+        }
 
         // Push entry in the queue
-        self.entries.push_back((self.next_id, entry));
+        self.entries.insert(index, (self.next_id, entry));
         self.next_id += 1;
     }
 
+    /// Entries waiting in the queue, in their order
+    fn queued_requests(&self) -> Vec<QueuedRequest> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(position, (_, entry))| QueuedRequest {
+                request_id: entry.request.request_id.clone(),
+                position,
+                age_ms: now.duration_since(entry.queue_time).as_millis() as u64,
+                input_tokens: entry.request.input_length,
+                max_new_tokens: entry.request.stopping_parameters.max_new_tokens,
+                generated_tokens: entry.request.generated_tokens,
+                priority: entry.request.priority,
+                tenant: entry.request.tenant.clone(),
+                adapter_id: entry.request.adapter_id.clone(),
+                overtaken: entry.overtaken,
+                deadline_in_ms: entry
+                    .request
+                    .deadline
+                    .map(|deadline| deadline.saturating_duration_since(now).as_millis() as u64),
+            })
+            .collect()
+    }
+
+    /// Earliest deadline of the queued entries
+    fn next_deadline(&self) -> Option<Instant> {
+        self.entries
+            .iter()
+            .filter_map(|(_, entry)| entry.request.deadline)
+            .min()
+    }
+
+    /// Fail the entries still queued at their deadline
+    fn expire_deadlines(&mut self) {
+        let now = Instant::now();
+        let entries = std::mem::take(&mut self.entries);
+        for (id, entry) in entries {
+            match entry.request.deadline {
+                Some(deadline) if deadline <= now => expire(entry),
+                _ => self.entries.push_back((id, entry)),
+            }
+        }
+    }
+
+    /// Fail the entries still queued at their deadline. With the `edf` policy, move the entries
+    /// whose deadline is at risk to the front of the queue, earliest deadline first.
+    fn schedule_deadlines(&mut self) {
+        if self
+            .entries
+            .iter()
+            .all(|(_, entry)| entry.request.deadline.is_none())
+        {
+            return;
+        }
+        let now = Instant::now();
+        let edf = self.fairness.scheduling_policy == SchedulingPolicy::Edf;
+        let mut at_risk = Vec::new();
+        let mut entries = VecDeque::with_capacity(self.entries.len());
+        for (id, entry) in self.entries.drain(..) {
+            match entry.request.deadline {
+                Some(deadline) if deadline <= now => expire(entry),
+                Some(deadline) if edf && deadline_at_risk(&entry, deadline, now) => {
+                    at_risk.push((id, entry))
+                }
+                _ => entries.push_back((id, entry)),
+            }
+        }
+        at_risk.sort_by_key(|(_, entry)| entry.request.deadline);
+        for entry in at_risk.into_iter().rev() {
+            entries.push_front(entry);
+        }
+        self.entries = entries;
+    }
+
     // Get the next batch
     async fn next_batch(
         &mut self,
@@ -236,6 +725,7 @@ impl State {
         prefill_token_budget: u32,
         token_budget: u32,
     ) -> Option<NextBatch> {
+        self.schedule_deadlines();
         if self.entries.is_empty() {
             tracing::debug!("No queue");
             return None;
@@ -261,17 +751,56 @@ impl State {
             ((prefill_token_budget + self.block_size - 1) / self.block_size) * self.block_size;
 
         // Create span for this batch to add context to inference calls
-        let next_batch_span = info_span!(parent: None, "batch", batch_size = tracing::field::Empty);
+        let next_batch_span = info_span!(
+            parent: None,
+            "batch",
+            id = self.next_batch_id,
+            batch_size = tracing::field::Empty,
+            prefill_tokens = tracing::field::Empty,
+            decode_tokens = tracing::field::Empty
+        );
         next_batch_span.follows_from(Span::current());
 
         let mut batch = Vec::with_capacity(self.entries.len());
+        // Requests of the batch in the shards, the negative sequences included, that
+        // `max_size` bounds
+        let mut sequences = 0;
         let mut max_input_length = 0;
         let mut prefill_tokens: u32 = 0;
         let mut decode_tokens: u32 = 0;
         let mut max_blocks = 0;
+        // Tokens of the KV cache blocks taken by the batch, the blocks of its cached prefixes
+        // aside. The blocks are bounded by `token_budget`, which shrinks when the shards run
+        // out of memory, on top of the free blocks of the allocator.
+        let mut kv_tokens: u32 = 0;
+        let block_size = self.block_size;
+        let new_kv_tokens = |allocation: &BlockAllocation| {
+            (allocation.blocks.len() as u32).saturating_sub(allocation.prefix_len / block_size)
+                * block_size
+        };
+
+        // Once the long prompts used up their share of the prefill budget, the next ones are
+        // passed over for the short prompts behind them, and queued back in their order. A long
+        // prompt uses the whole budget when no short prompt is waiting, or when it cannot be
+        // chunked and opens the batch, so that it is scheduled once it reaches the front.
+        let long_prefill_token_budget = self
+            .fairness
+            .long_prefill_token_budget(prefill_token_budget);
+        let mut short_waiting = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| self.fairness.is_short(&entry.request))
+            .count();
+        let mut long_budget_spent = false;
+        let mut passed_over = Vec::new();
 
         // Pop entries starting from the front of the queue
-        'entry_loop: while let Some((id, entry)) = self.entries.pop_front() {
+        'entry_loop: while let Some((id, mut entry)) = self.entries.pop_front() {
+            let short = self.fairness.is_short(&entry.request);
+            if short {
+                short_waiting -= 1;
+            }
+
             // Filter entries where the response receiver was dropped (== entries where the request
             // was dropped by the client)
             if entry.response_tx.is_closed() {
@@ -280,23 +809,66 @@ impl State {
                 continue;
             }
 
+            if let Some(max_new_tokens) = self.fairness.round_max_new_tokens(&entry.request) {
+                let stopping_parameters = &mut entry.request.stopping_parameters;
+                if stopping_parameters.max_new_tokens > max_new_tokens {
+                    tracing::debug!("Capping round to {max_new_tokens} new tokens");
+                    stopping_parameters.max_new_tokens = max_new_tokens;
+                }
+            }
+
+            if !short && long_budget_spent {
+                passed_over.push((id, entry));
+                continue;
+            }
+            let chunkable = self.support_chunking
+                && self.block_allocator.is_some()
+                && entry.request.guidance.is_none();
+            let prefill_token_budget =
+                if short || short_waiting == 0 || (batch.is_empty() && !chunkable) {
+                    prefill_token_budget
+                } else {
+                    long_prefill_token_budget
+                };
+
+            // A guided request runs next to its negative sequence
+            let entry_sequences = if entry.request.guidance.is_some() {
+                2
+            } else {
+                1
+            };
+            if max_size.is_some_and(|max_size| sequences + entry_sequences > max_size) {
+                tracing::debug!("Over the batch size: {sequences} + {entry_sequences} requests");
+                self.entries.push_front((id, entry));
+                break;
+            }
+
             let block_allocation = match &self.block_allocator {
                 None => {
                     // We pad to max input length in the Python shards
                     // We need to take these padding tokens into the equation
-                    max_input_length = max_input_length.max(entry.request.input_length);
-                    prefill_tokens = (batch.len() + 1) as u32 * max_input_length;
+                    let entry_max_input_length = max_input_length.max(entry.request.input_length);
+                    let entry_prefill_tokens = (batch.len() + 1) as u32 * entry_max_input_length;
 
-                    decode_tokens += entry.request.stopping_parameters.max_new_tokens;
-                    let total_tokens = prefill_tokens + decode_tokens + self.speculate;
+                    let entry_decode_tokens =
+                        decode_tokens + entry.request.stopping_parameters.max_new_tokens;
+                    let total_tokens = entry_prefill_tokens + entry_decode_tokens + self.speculate;
 
-                    if prefill_tokens > prefill_token_budget || total_tokens > token_budget {
+                    if entry_prefill_tokens > prefill_token_budget || total_tokens > token_budget {
                         // Entry is over budget
+                        tracing::debug!("Over budget: prefill_tokens={entry_prefill_tokens} > {prefill_token_budget} || {entry_prefill_tokens} + {entry_decode_tokens} + {} > {token_budget}", self.speculate);
+                        if !short {
+                            long_budget_spent = true;
+                            passed_over.push((id, entry));
+                            continue;
+                        }
                         // Add it back to the front
-                        tracing::debug!("Over budget: prefill_tokens={prefill_tokens} > {prefill_token_budget} || {prefill_tokens} + {decode_tokens} + {} > {token_budget}", self.speculate);
                         self.entries.push_front((id, entry));
                         break 'entry_loop;
                     }
+                    max_input_length = entry_max_input_length;
+                    prefill_tokens = entry_prefill_tokens;
+                    decode_tokens = entry_decode_tokens;
                     None
                 }
                 Some(block_allocator) => {
@@ -314,17 +886,30 @@ impl State {
                         - 1;
                     tracing::debug!("Allocating {tokens} with {input_ids:?}");
 
-                    let block_allocation = match block_allocator.allocate(tokens, input_ids).await {
+                    let (block_allocation, entry_kv_tokens) = match block_allocator
+                        .allocate(tokens, input_ids, entry.request.session_id.clone())
+                        .await
+                        .filter(|allocation| kv_tokens + new_kv_tokens(allocation) <= token_budget)
+                    {
                         None => {
-                            // Entry is over budget
+                            // Entry is over budget, its blocks are freed if it got any
+                            tracing::debug!(
+                                "Over budget: not enough free blocks or {kv_tokens} KV tokens \
+                                 + the entry > {token_budget}"
+                            );
+                            if !short {
+                                long_budget_spent = true;
+                                passed_over.push((id, entry));
+                                continue;
+                            }
                             // Add it back to the front
-                            tracing::debug!("Over budget: not enough free blocks");
                             self.entries.push_front((id, entry));
                             break 'entry_loop;
                         }
                         Some(mut block_allocation) => {
                             tracing::debug!("Allocation: {block_allocation:?}");
                             max_blocks = max(max_blocks, block_allocation.blocks.len() as u32);
+                            let entry_kv_tokens = new_kv_tokens(&block_allocation);
 
                             if block_allocation.prefix_len == entry.request.input_length {
                                 // The whole request was found in the radix trie
@@ -333,51 +918,188 @@ impl State {
                                 block_allocation.prefix_len -= 1;
                             }
 
-                            block_allocation
+                            if let Some(Handoff::Decode(prompt_kv)) = entry.handoff.as_mut() {
+                                // The prefill shards computed the prompt but its last token
+                                prompt_kv.plan(&block_allocation, self.block_size);
+                                block_allocation.prefix_len =
+                                    block_allocation.prefix_len.max(prompt_kv.tokens);
+                            }
+
+                            (block_allocation, entry_kv_tokens)
+                        }
+                    };
+
+                    // The negative sequence of a guided request runs next to it
+                    let negative_block_allocation = match &entry.request.guidance {
+                        Some(guidance) => allocate_negative(
+                            block_allocator,
+                            guidance,
+                            entry.request.stopping_parameters.max_new_tokens,
+                        )
+                        .await
+                        .filter(|allocation| {
+                            kv_tokens + entry_kv_tokens + new_kv_tokens(allocation) <= token_budget
+                        })
+                        .map(Some),
+                        None => Some(None),
+                    };
+                    let Some(negative_block_allocation) = negative_block_allocation else {
+                        // Entry is over budget
+                        tracing::debug!(
+                            "Over budget: not enough free blocks for the negative prompt"
+                        );
+                        if !short {
+                            long_budget_spent = true;
+                            passed_over.push((id, entry));
+                            continue;
                         }
+                        // Add it back to the front
+                        self.entries.push_front((id, entry));
+                        break 'entry_loop;
                     };
+                    let negative_postfix_len =
+                        match (&entry.request.guidance, &negative_block_allocation) {
+                            (Some(guidance), Some(negative_block_allocation)) => {
+                                guidance.input_length - negative_block_allocation.prefix_len
+                            }
+                            _ => 0,
+                        };
 
-                    let postfix_len = entry.request.input_length - block_allocation.prefix_len;
+                    let postfix_len = entry.request.input_length - block_allocation.prefix_len
+                        + negative_postfix_len;
 
                     if prefill_tokens + postfix_len > prefill_token_budget {
                         // Entry is over budget
-                        if self.support_chunking {
+                        // Both sequences of a guided request must prefill at once, so that
+                        // their logits are combined from the first token
+                        if self.support_chunking && entry.request.guidance.is_none() {
                             // We support chunking, just set postfix_len to exactly match prefill_token_budget
                             let chunk_len = prefill_token_budget.saturating_sub(prefill_tokens);
+                            tracing::debug!(
+                                "Matched budget: prefill_tokens={} == {prefill_token_budget}",
+                                prefill_tokens + postfix_len
+                            );
                             if chunk_len > 0 {
                                 // Push this entry inside the batch
+                                prefill_tokens += chunk_len;
+                                kv_tokens += entry_kv_tokens;
+                                sequences += entry_sequences;
                                 batch.push((id, entry, Some(block_allocation), Some(chunk_len)));
-                            } else {
+                                if short || Some(sequences) == max_size {
+                                    break 'entry_loop;
+                                }
+                            } else if short {
                                 // We cannot prefill even one token for this entry
                                 // Add it back to the queue
                                 self.entries.push_front((id, entry));
+                                break 'entry_loop;
+                            } else {
+                                passed_over.push((id, entry));
                             }
-                            tracing::debug!(
-                                "Matched budget: prefill_tokens={} == {prefill_token_budget}",
-                                prefill_tokens + postfix_len
-                            );
-                            break 'entry_loop;
+                            long_budget_spent = true;
+                            continue;
                         } else {
                             // We don't support chunking, this entry needs to go back to the buffer
-                            // Add it back to the front
                             tracing::debug!(
                                 "Over budget: prefill_tokens={} > {prefill_token_budget}",
                                 prefill_tokens + postfix_len
                             );
+                            if !short {
+                                long_budget_spent = true;
+                                passed_over.push((id, entry));
+                                continue;
+                            }
+                            // Add it back to the front
                             self.entries.push_front((id, entry));
                             break 'entry_loop;
                         }
                     }
 
                     prefill_tokens += postfix_len;
+                    max_blocks = max(
+                        max_blocks,
+                        negative_block_allocation
+                            .as_ref()
+                            .map_or(0, |allocation| allocation.blocks.len() as u32),
+                    );
+                    kv_tokens += entry_kv_tokens
+                        + negative_block_allocation.as_ref().map_or(0, new_kv_tokens);
+                    entry.negative_block_allocation = negative_block_allocation;
 
                     Some(block_allocation)
                 }
             };
+            sequences += entry_sequences;
+            let prompt_group = entry
+                .request
+                .prompt_group
+                .clone()
+                .filter(|prompt_group| prompt_group.is_leader() && can_fork(&entry));
             batch.push((id, entry, block_allocation, None));
-            if Some(batch.len()) == max_size {
+            if Some(sequences) == max_size {
                 break;
             }
+
+            // The other sequences of the prompt join the batch with the blocks of its prompt
+            let (Some(block_allocator), Some(prompt_group), Some((_, _, Some(allocation), _))) =
+                (&self.block_allocator, prompt_group, batch.last_mut())
+            else {
+                continue;
+            };
+            let mut forks = Vec::new();
+            let mut index = 0;
+            while index < self.entries.len() {
+                let (_, entry) = &mut self.entries[index];
+                let forked = entry
+                    .request
+                    .prompt_group
+                    .as_ref()
+                    .is_some_and(|group| group.same_prompt(&prompt_group));
+                if !forked || !can_fork(entry) || entry.response_tx.is_closed() {
+                    index += 1;
+                    continue;
+                }
+                if max_size.is_some_and(|max_size| sequences + 1 > max_size) {
+                    break;
+                }
+                if let Some(max_new_tokens) = self.fairness.round_max_new_tokens(&entry.request) {
+                    let stopping_parameters = &mut entry.request.stopping_parameters;
+                    stopping_parameters.max_new_tokens =
+                        stopping_parameters.max_new_tokens.min(max_new_tokens);
+                }
+                let tokens = entry.request.input_length
+                    + entry.request.stopping_parameters.max_new_tokens
+                    + self.speculate
+                    - 1;
+                // The sequence waits for a later batch if it is over budget, its blocks are
+                // freed if it got any
+                let Some(fork) = block_allocator
+                    .fork(allocation, tokens, entry.request.input_length)
+                    .await
+                    .filter(|fork| {
+                        kv_tokens + new_kv_tokens(fork) <= token_budget
+                            && prefill_tokens + entry.request.input_length - fork.prefix_len
+                                <= prefill_token_budget
+                    })
+                else {
+                    tracing::debug!("Over budget: the sequence is not forked");
+                    break;
+                };
+                tracing::debug!("Fork: {fork:?}");
+                prefill_tokens += entry.request.input_length - fork.prefix_len;
+                kv_tokens += new_kv_tokens(&fork);
+                max_blocks = max(max_blocks, fork.blocks.len() as u32);
+                sequences += 1;
+                let (id, entry) = self.entries.remove(index).unwrap();
+                forks.push((id, entry, Some(fork), None));
+            }
+            batch.extend(forks);
+            if Some(sequences) == max_size {
+                break;
+            }
+        }
+        for entry in passed_over.into_iter().rev() {
+            self.entries.push_front(entry);
         }
 
         // Empty batch
@@ -392,7 +1114,8 @@ impl State {
             // Batch is too small
             if batch.len() < min_size {
                 // Add back entries to the queue in the correct order
-                for (id, entry, _, _) in batch.into_iter().rev() {
+                for (id, mut entry, _, _) in batch.into_iter().rev() {
+                    entry.negative_block_allocation = None;
                     self.entries.push_front((id, entry));
                 }
                 return None;
@@ -403,9 +1126,23 @@ impl State {
         let mut batch_entries =
             IntMap::with_capacity_and_hasher(self.entries.len(), BuildNoHashHasher::default());
 
+        // Batch statistics
+        let mut batch_prefill_tokens = 0;
+        let mut batch_decode_tokens = 0;
+        let mut total_queue_time = Duration::ZERO;
+        let mut max_queue_time = Duration::ZERO;
+
         for (id, mut entry, block_allocation, chunk_len) in batch {
+            if let Some(fair_share) = self.fair_share.as_mut() {
+                fair_share.start(entry.tag);
+            }
             // Create a new span to link the batch back to this entry
-            let entry_batch_span = info_span!(parent: &entry.span, "infer");
+            let entry_batch_span = info_span!(
+                parent: &entry.span,
+                "infer",
+                id,
+                batch_id = self.next_batch_id
+            );
             // Add relationships
             next_batch_span.follows_from(&entry_batch_span);
             entry_batch_span.follows_from(&next_batch_span);
@@ -423,32 +1160,24 @@ impl State {
 
             entry.block_allocation = block_allocation;
 
+            batch_prefill_tokens += chunk_len.unwrap_or(entry.request.input_length - prefix_len);
+            batch_decode_tokens += entry.request.stopping_parameters.max_new_tokens;
+            let queue_time = entry.queue_time.elapsed();
+            total_queue_time += queue_time;
+            max_queue_time = max_queue_time.max(queue_time);
+
             batch_requests.push(Request {
                 id,
                 prefill_logprobs: entry.request.decoder_input_details,
-                input_chunks: Some(client::Input {
-                    chunks: entry
-                        .request
-                        .inputs
-                        .clone()
-                        .into_iter()
-                        .map(|c| client::InputChunk {
-                            chunk: Some(match c {
-                                Chunk::Text(text) => client::Chunk::Text(text),
-                                Chunk::Image(image) => client::Chunk::Image(client::Image {
-                                    data: image.data,
-                                    mimetype: image.mimetype,
-                                }),
-                            }),
-                        })
-                        .collect(),
-                }),
+                input_chunks: Some(input_chunks(entry.request.inputs.clone())),
                 inputs: entry.request.inputs.chunks_to_string(),
                 truncate: entry.request.truncate,
                 add_special_tokens: entry.request.add_special_tokens,
-                parameters: Some(NextTokenChooserParameters::from(
-                    entry.request.parameters.clone(),
-                )),
+                parameters: Some(NextTokenChooserParameters {
+                    // Continued requests resume the sampling where the previous round stopped
+                    sampling_step: entry.request.generated_tokens,
+                    ..NextTokenChooserParameters::from(entry.request.parameters.clone())
+                }),
                 stopping_parameters: Some(StoppingCriteriaParameters::from(
                     entry.request.stopping_parameters.clone(),
                 )),
@@ -458,9 +1187,40 @@ impl State {
                 cache_len: prefix_len,
                 adapter_id: entry.request.adapter_id.clone(),
                 chunk_len,
+                adapters: entry
+                    .request
+                    .adapters
+                    .iter()
+                    .map(|adapter| client::AdapterWeight {
+                        id: adapter.id.clone(),
+                        weight: adapter.weight,
+                    })
+                    .collect(),
+                guidance: entry
+                    .request
+                    .guidance
+                    .as_ref()
+                    .map(|guidance| client::Guidance {
+                        scale: guidance.scale,
+                        negative_id: id | NEGATIVE_REQUEST_BIT,
+                    }),
             });
+            if let (Some(guidance), Some(negative_block_allocation)) =
+                (&entry.request.guidance, &entry.negative_block_allocation)
+            {
+                batch_prefill_tokens +=
+                    guidance.input_length - negative_block_allocation.prefix_len;
+                batch_decode_tokens += entry.request.stopping_parameters.max_new_tokens;
+                batch_requests.push(negative_request(
+                    id,
+                    &entry.request,
+                    guidance,
+                    negative_block_allocation,
+                ));
+            }
             // Set batch_time
             entry.batch_time = Some(Instant::now());
+            let _ = entry.response_tx.send(Ok(InferStreamResponse::Started));
             // Insert in batch_entries IntMap
             batch_entries.insert(id, entry);
         }
@@ -468,6 +1228,8 @@ impl State {
         // Final batch size
         let size = batch_requests.len() as u32;
         next_batch_span.record("batch_size", size);
+        next_batch_span.record("prefill_tokens", batch_prefill_tokens);
+        next_batch_span.record("decode_tokens", batch_decode_tokens);
 
         let batch = Batch {
             id: self.next_batch_id,
@@ -480,6 +1242,32 @@ impl State {
         self.next_batch_id += 1;
 
         metrics::histogram!("tgi_batch_next_size").record(batch.size as f64);
+        metrics::histogram!("tgi_batch_next_tokens", "phase" => "prefill")
+            .record(batch_prefill_tokens as f64);
+        metrics::histogram!("tgi_batch_next_tokens", "phase" => "decode")
+            .record(batch_decode_tokens as f64);
+        metrics::histogram!("tgi_batch_next_budget_usage", "phase" => "prefill")
+            .record(batch_prefill_tokens as f64 / prefill_token_budget as f64);
+        metrics::histogram!("tgi_batch_next_budget_usage", "phase" => "total")
+            .record(batch.max_tokens as f64 / token_budget as f64);
+
+        if self.batch_history.len() == BATCH_HISTORY_SIZE {
+            self.batch_history.pop_front();
+        }
+        self.batch_history.push_back(BatchRecord {
+            id: batch.id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            size,
+            prefill_tokens: batch_prefill_tokens,
+            prefill_token_budget,
+            decode_tokens: batch_decode_tokens,
+            token_budget,
+            mean_queue_time_ms: (total_queue_time / size).as_millis() as u64,
+            max_queue_time_ms: max_queue_time.as_millis() as u64,
+        });
 
         Some((batch_entries, batch, next_batch_span))
     }
@@ -498,6 +1286,118 @@ enum QueueCommand {
         response_sender: oneshot::Sender<Option<NextBatch>>,
         span: Span,
     },
+    BatchHistory {
+        response_sender: oneshot::Sender<Vec<BatchRecord>>,
+    },
+    SessionStats {
+        response_sender: oneshot::Sender<Vec<SessionStats>>,
+    },
+    CacheStats {
+        response_sender: oneshot::Sender<Option<CacheStats>>,
+    },
+    Len {
+        response_sender: oneshot::Sender<usize>,
+    },
+    Entries {
+        response_sender: oneshot::Sender<Vec<QueuedRequest>>,
+    },
+    Handover(mpsc::UnboundedSender<Entry>),
+    Close,
+}
+
+/// Whether an entry can share the blocks of its prompt with the other sequences generated for
+/// it. The sequences must start from the same prompt without its logprobs, and a negative
+/// sequence would need blocks of its own.
+fn can_fork(entry: &Entry) -> bool {
+    let request = &entry.request;
+    request.generated_tokens == 0
+        && !request.decoder_input_details
+        && request.guidance.is_none()
+        && entry.handoff.is_none()
+}
+
+/// Allocate the blocks of the negative sequence of a guided request, that generates as many
+/// tokens as the request
+async fn allocate_negative(
+    block_allocator: &BlockAllocator,
+    guidance: &ValidGuidance,
+    max_new_tokens: u32,
+) -> Option<BlockAllocation> {
+    let tokens = guidance.input_length + max_new_tokens - 1;
+    let mut block_allocation = block_allocator
+        .allocate(tokens, Some(guidance.input_ids.clone()), None)
+        .await?;
+    // As for the request, at least one token of the negative prompt must be computed
+    if block_allocation.prefix_len == guidance.input_length {
+        block_allocation.prefix_len -= 1;
+    }
+    Some(block_allocation)
+}
+
+fn input_chunks(chunks: Vec<Chunk>) -> client::Input {
+    client::Input {
+        chunks: chunks
+            .into_iter()
+            .map(|c| client::InputChunk {
+                chunk: Some(match c {
+                    Chunk::Text(text) => client::Chunk::Text(text),
+                    Chunk::Image(image) => client::Chunk::Image(client::Image {
+                        data: image.data,
+                        mimetype: image.mimetype,
+                        width: image.width,
+                        height: image.height,
+                    }),
+                }),
+            })
+            .collect(),
+    }
+}
+
+/// Request generating the negative prompt of the guided request `id`. Its tokens are the ones
+/// chosen for the guided request, so it is greedy and only stops with it.
+fn negative_request(
+    id: u64,
+    request: &ValidGenerateRequest,
+    guidance: &ValidGuidance,
+    block_allocation: &BlockAllocation,
+) -> Request {
+    Request {
+        id: id | NEGATIVE_REQUEST_BIT,
+        prefill_logprobs: false,
+        input_chunks: Some(input_chunks(guidance.inputs.clone())),
+        inputs: guidance.inputs.chunks_to_string(),
+        truncate: guidance.input_length,
+        add_special_tokens: request.add_special_tokens,
+        parameters: Some(NextTokenChooserParameters {
+            temperature: 1.0,
+            top_p: 1.0,
+            typical_p: 1.0,
+            repetition_penalty: 1.0,
+            ..Default::default()
+        }),
+        stopping_parameters: Some(StoppingCriteriaParameters {
+            max_new_tokens: request.stopping_parameters.max_new_tokens,
+            stop_sequences: Vec::new(),
+            ignore_eos_token: true,
+            stop_token_ids: Vec::new(),
+            min_new_tokens: 0,
+        }),
+        top_n_tokens: 0,
+        blocks: block_allocation.blocks.clone(),
+        slots: block_allocation.slots.clone(),
+        cache_len: block_allocation.prefix_len,
+        adapter_id: request.adapter_id.clone(),
+        chunk_len: None,
+        adapters: request
+            .adapters
+            .iter()
+            .map(|adapter| client::AdapterWeight {
+                id: adapter.id.clone(),
+                weight: adapter.weight,
+            })
+            .collect(),
+        guidance: None,
+    }
 }
 
 impl From<ValidParameters> for NextTokenChooserParameters {
@@ -508,6 +1408,7 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             Some(grammar) => match grammar {
                 ValidGrammar::Json(grammar_string) => (grammar_string, GrammarType::Json),
                 ValidGrammar::Regex(grammar_string) => (grammar_string, GrammarType::Regex),
+                ValidGrammar::JsonObject => (String::new(), GrammarType::JsonObject),
             },
         };
 
@@ -520,9 +1421,17 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             seed: value.seed,
             repetition_penalty: value.repetition_penalty,
             frequency_penalty: value.frequency_penalty,
+            presence_penalty: value.presence_penalty,
+            min_p: value.min_p,
             watermark: value.watermark,
+            watermark_key: value.watermark_key,
+            no_repeat_ngram_size: value.no_repeat_ngram_size,
             grammar,
             grammar_type: grammar_type.into(),
+            speculate: value.speculate,
+            sampling_step: 0,
+            healing_token_id: value.healing_token_id,
+            logit_bias: value.logit_bias,
         }
     }
 }
@@ -533,18 +1442,20 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
             max_new_tokens: value.max_new_tokens,
             stop_sequences: value.stop_sequences,
             ignore_eos_token: value.ignore_eos_token,
+            stop_token_ids: value.stop_token_ids,
+            min_new_tokens: value.min_new_tokens,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
-    use std::sync::Arc;
-
+pub(crate) mod tests {
     use super::*;
+    use text_generation_router::validation::PromptGroup;
+    use text_generation_router::Priority;
     use tracing::info_span;
 
-    fn default_entry() -> (
+    pub(crate) fn default_entry() -> (
         Entry,
         mpsc::UnboundedReceiver<Result<InferStreamResponse, InferError>>,
     ) {
@@ -563,21 +1474,40 @@ mod tests {
                     top_k: 0,
                     top_p: 0.0,
                     typical_p: 0.0,
+                    min_p: 0.0,
+                    no_repeat_ngram_size: 0,
                     do_sample: false,
                     seed: 0,
                     repetition_penalty: 0.0,
                     frequency_penalty: 0.0,
+                    presence_penalty: 0.0,
                     watermark: false,
+                    watermark_key: 0,
                     grammar: None,
+                    speculate: None,
+                    healing_token_id: None,
+                    logit_bias: HashMap::new(),
                 },
                 stopping_parameters: ValidStoppingParameters {
                     ignore_eos_token: false,
                     max_new_tokens: 1,
                     max_total_new_tokens: 1024,
                     stop_sequences: vec![],
+                    stop_token_ids: vec![],
+                    min_new_tokens: 0,
                 },
                 top_n_tokens: 0,
                 adapter_id: None,
+                adapters: Vec::new(),
+                priority: Priority::Normal,
+                session_id: None,
+                generated_tokens: 0,
+                healed_prefix: None,
+                guidance: None,
+                tenant: None,
+                request_id: None,
+                deadline: None,
+                prompt_group: None,
             },
             response_tx,
             span: info_span!("entry"),
@@ -585,13 +1515,25 @@ mod tests {
             queue_time: Instant::now(),
             batch_time: None,
             block_allocation: None,
+            negative_block_allocation: None,
+            overtaken: 0,
+            in_flight: InFlight::new(Arc::default()),
+            lookup: None,
+            draft: None,
+            tag: 0.0,
+            handoff: None,
+            batches: BatchResidency::default(),
+            checkpoint: None,
         };
         (entry, receiver_tx)
     }
 
     #[tokio::test]
     async fn test_append() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry, _guard) = default_entry();
 
         assert_eq!(state.next_id, 0);
@@ -605,9 +1547,233 @@ mod tests {
         assert_eq!(id, 0);
     }
 
+    #[tokio::test]
+    async fn test_append_priority() {
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let (entry1, _guard1) = default_entry();
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.priority = Priority::Low;
+        let (mut entry3, _guard3) = default_entry();
+        entry3.request.priority = Priority::High;
+        let (entry4, _guard4) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+        state.append(entry4);
+
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![2, 0, 3, 1]);
+        assert_eq!(state.entries[3].1.overtaken, 2);
+    }
+
+    #[tokio::test]
+    async fn test_queued_requests() {
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let (mut entry1, _guard1) = default_entry();
+        entry1.request.request_id = Some("first".to_string());
+        entry1.request.tenant = Some("team-a".to_string());
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.priority = Priority::High;
+        entry2.request.deadline = Some(Instant::now() + Duration::from_secs(60));
+        state.append(entry1);
+        state.append(entry2);
+
+        let requests = state.queued_requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].position, 0);
+        assert_eq!(requests[0].priority, Priority::High);
+        assert!(requests[0].deadline_in_ms.is_some_and(|ms| ms <= 60_000));
+        assert_eq!(requests[1].position, 1);
+        assert_eq!(requests[1].request_id.as_deref(), Some("first"));
+        assert_eq!(requests[1].tenant.as_deref(), Some("team-a"));
+        assert_eq!(requests[1].overtaken, 1);
+        assert_eq!(requests[1].input_tokens, 1);
+        assert_eq!(requests[1].max_new_tokens, 1);
+    }
+
+    #[tokio::test]
+    async fn test_append_priority_starvation() {
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let (mut entry, _guard) = default_entry();
+        entry.request.priority = Priority::Low;
+        state.append(entry);
+
+        let mut guards = Vec::new();
+        for _ in 0..MAX_OVERTAKEN + 1 {
+            let (entry, guard) = default_entry();
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The low priority entry was overtaken `MAX_OVERTAKEN` times and then kept its place
+        let position = state.entries.iter().position(|(id, _)| *id == 0).unwrap();
+        assert_eq!(position, MAX_OVERTAKEN as usize);
+        assert_eq!(state.entries[position].1.overtaken, MAX_OVERTAKEN);
+    }
+
+    #[tokio::test]
+    async fn test_append_deep_queue_continued_requests() {
+        let fairness = Fairness {
+            max_request_tokens: None,
+            queue_depth: Some(2),
+            tenant_weights: None,
+            scheduling_policy: SchedulingPolicy::Priority,
+            short_prompts: None,
+        };
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            fairness,
+            ..QueueConfig::default()
+        });
+        let (entry0, _guard0) = default_entry();
+        let (entry1, _guard1) = default_entry();
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.generated_tokens = 1024;
+        let (entry3, _guard3) = default_entry();
+        state.append(entry0);
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![0, 1, 3, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_append_tenants_fair_share() {
+        let fairness = Fairness {
+            tenant_weights: Some(Arc::new(HashMap::from([("large".to_string(), 2.0)]))),
+            ..Fairness::default()
+        };
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            fairness,
+            ..QueueConfig::default()
+        });
+        let mut guards = Vec::new();
+        for tenant in [
+            "noisy", "noisy", "noisy", "large", "large", "large", "large", "quiet",
+        ] {
+            let (mut entry, guard) = default_entry();
+            entry.request.tenant = Some(tenant.to_string());
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        let tenants: Vec<&str> = state
+            .entries
+            .iter()
+            .map(|(_, entry)| entry.request.tenant.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            tenants,
+            vec!["noisy", "large", "quiet", "large", "noisy", "large", "large", "noisy"]
+        );
+        // Overtaking within a priority does not count against the starvation limit
+        assert!(state.entries.iter().all(|(_, entry)| entry.overtaken == 0));
+    }
+
+    #[tokio::test]
+    async fn test_schedule_deadlines() {
+        let fairness = Fairness {
+            scheduling_policy: SchedulingPolicy::Edf,
+            ..Fairness::default()
+        };
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            fairness,
+            ..QueueConfig::default()
+        });
+        let now = Instant::now();
+        // (waited, time left before the deadline)
+        let deadlines = [
+            None,
+            Some((Duration::ZERO, Duration::from_secs(10))),
+            Some((Duration::from_secs(3), Duration::from_secs(1))),
+            Some((Duration::from_secs(2), Duration::from_millis(500))),
+        ];
+        let mut guards = Vec::new();
+        for deadline in deadlines {
+            let (mut entry, guard) = default_entry();
+            if let Some((waited, left)) = deadline {
+                entry.queue_time = now - waited;
+                entry.request.deadline = Some(now + left);
+            }
+            state.append(entry);
+            guards.push(guard);
+        }
+        let (mut expired, mut expired_receiver) = default_entry();
+        expired.request.deadline = Some(now);
+        state.append(expired);
+
+        state.schedule_deadlines();
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        // The entries at risk first, earliest deadline first
+        assert_eq!(ids, vec![3, 2, 0, 1]);
+        assert!(matches!(
+            expired_receiver.try_recv(),
+            Ok(Err(InferError::DeadlineExceeded))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_queue_expires_deadlines() {
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let (mut entry, mut receiver) = default_entry();
+        entry.request.deadline = Some(Instant::now() + Duration::from_millis(50));
+        queue.append(entry);
+        let (entry, _guard) = default_entry();
+        queue.append(entry);
+
+        // The entry expires without any batch being scheduled
+        let response = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Err(InferError::DeadlineExceeded))));
+        assert_eq!(queue.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_max_request_tokens() {
+        let fairness = Fairness {
+            max_request_tokens: Some(20),
+            queue_depth: None,
+            tenant_weights: None,
+            scheduling_policy: SchedulingPolicy::Priority,
+            short_prompts: None,
+        };
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 32,
+            fairness,
+            ..QueueConfig::default()
+        });
+        let (mut entry, _guard) = default_entry();
+        entry.request.stopping_parameters.max_new_tokens = 100;
+        state.append(entry);
+
+        let (_, batch, _) = state.next_batch(None, None, 32, 32).await.unwrap();
+        let stopping_parameters = batch.requests[0].stopping_parameters.as_ref().unwrap();
+        assert_eq!(stopping_parameters.max_new_tokens, 19);
+    }
+
     #[tokio::test]
     async fn test_next_batch_empty() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
 
         assert!(state.next_batch(None, None, 1, 1).await.is_none());
         assert!(state.next_batch(Some(1), None, 1, 1).await.is_none());
@@ -615,7 +1781,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_next_batch_min_size() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -647,7 +1816,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_next_batch_max_size() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -665,9 +1837,70 @@ mod tests {
         assert_eq!(state.next_batch_id, 1);
     }
 
+    #[tokio::test]
+    async fn test_next_batch_prompt_group() {
+        let mut state = State::new(QueueConfig {
+            block_size: 2,
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let block_allocator = state.block_allocator.clone().unwrap();
+        let free_blocks = block_allocator.cache_stats().await.free_blocks;
+
+        let groups = PromptGroup::new(3);
+        let mut guards = Vec::new();
+        for group in [Some(&groups[0]), None, Some(&groups[1]), Some(&groups[2])] {
+            let (mut entry, guard) = default_entry();
+            entry.request.input_length = 5;
+            entry.request.prompt_group = group.cloned();
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The followers join the batch of the leader with the blocks of its prompt that are
+        // full before its last token, and prefill the rest of it
+        let (mut entries, batch, _) = state.next_batch(None, Some(3), 16, 16).await.unwrap();
+        assert_eq!(entries.len(), 3);
+        let ids: Vec<_> = batch.requests.iter().map(|request| request.id).collect();
+        assert_eq!(ids, [0, 2, 3]);
+        let leader = &batch.requests[0];
+        assert_eq!(leader.blocks.len(), 3);
+        assert_eq!(leader.cache_len, 0);
+        for follower in &batch.requests[1..] {
+            assert_eq!(follower.blocks[..2], leader.blocks[..2]);
+            assert_eq!(follower.slots[..4], leader.slots[..4]);
+            assert_eq!(follower.blocks.len(), 3);
+            assert_ne!(follower.blocks[2], leader.blocks[2]);
+            assert_eq!(follower.cache_len, 4);
+        }
+        assert_ne!(batch.requests[1].blocks[2], batch.requests[2].blocks[2]);
+        assert_eq!(state.entries.len(), 1);
+        assert_eq!(
+            block_allocator.cache_stats().await.free_blocks,
+            free_blocks - 5
+        );
+
+        // The blocks of the prompt are freed with the last sequence using them
+        entries.remove(&0);
+        assert_eq!(
+            block_allocator.cache_stats().await.free_blocks,
+            free_blocks - 5
+        );
+        entries.remove(&2);
+        assert_eq!(
+            block_allocator.cache_stats().await.free_blocks,
+            free_blocks - 4
+        );
+        drop(entries);
+        assert_eq!(block_allocator.cache_stats().await.free_blocks, free_blocks);
+    }
+
     #[tokio::test]
     async fn test_next_batch_token_budget() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -698,16 +1931,226 @@ mod tests {
         assert_eq!(state.next_batch_id, 2);
     }
 
+    #[tokio::test]
+    async fn test_next_batch_kv_budget() {
+        let mut state = State::new(QueueConfig {
+            block_size: 4,
+            max_batch_total_tokens: 64,
+            ..QueueConfig::default()
+        });
+        let mut guards = Vec::new();
+        for _ in 0..2 {
+            let (mut entry, guard) = default_entry();
+            entry.request.input_length = 2;
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // Each request takes a whole block of 4 tokens, though it only needs 2
+        let (entries, batch, _) = state.next_batch(None, None, 64, 6).await.unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), [&0]);
+        assert_eq!(batch.max_blocks, 1);
+        assert_eq!(state.entries.len(), 1);
+
+        let (entries, _, _) = state.next_batch(None, None, 64, 8).await.unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_short_prompts() {
+        let fairness = Fairness {
+            short_prompts: Some(ShortPromptBudget {
+                max_input_tokens: 4,
+                reserved_share: 0.5,
+            }),
+            ..Fairness::default()
+        };
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 64,
+            fairness,
+            ..QueueConfig::default()
+        });
+        let mut guards = Vec::new();
+        for input_length in [6, 6, 2] {
+            let (mut entry, guard) = default_entry();
+            entry.request.input_length = input_length;
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The first long prompt opens the batch, the next one cannot use the half of the budget
+        // kept for the short ones
+        let (entries, _, _) = state.next_batch(None, None, 10, 100).await.unwrap();
+        let mut ids: Vec<&u64> = entries.keys().collect();
+        ids.sort();
+        assert_eq!(ids, [&0, &2]);
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [1]);
+
+        let (entries, _, _) = state.next_batch(None, None, 10, 100).await.unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), [&1]);
+        assert!(state.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_long_prompt_not_starved() {
+        let fairness = Fairness {
+            short_prompts: Some(ShortPromptBudget {
+                max_input_tokens: 4,
+                reserved_share: 0.5,
+            }),
+            ..Fairness::default()
+        };
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 64,
+            fairness,
+            ..QueueConfig::default()
+        });
+        let mut guards = Vec::new();
+        for input_length in [2, 6, 2] {
+            let (mut entry, guard) = default_entry();
+            entry.request.input_length = input_length;
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The long prompt is over the budget of the long prompts, and cannot be chunked
+        let (entries, _, _) = state.next_batch(None, None, 10, 100).await.unwrap();
+        let mut ids: Vec<&u64> = entries.keys().collect();
+        ids.sort();
+        assert_eq!(ids, [&0, &2]);
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [1]);
+
+        // Short prompts keep coming, but the long prompt is now at the front and opens the batch
+        let (mut entry, guard) = default_entry();
+        entry.request.input_length = 2;
+        state.append(entry);
+        guards.push(guard);
+        let (entries, _, _) = state.next_batch(None, None, 10, 100).await.unwrap();
+        let mut ids: Vec<&u64> = entries.keys().collect();
+        ids.sort();
+        assert_eq!(ids, [&1, &3]);
+        assert!(state.entries.is_empty());
+
+        // Without short prompts waiting, the long prompts share the whole budget
+        for _ in 0..2 {
+            let (mut entry, guard) = default_entry();
+            entry.request.input_length = 6;
+            state.append(entry);
+            guards.push(guard);
+        }
+        let (entries, _, _) = state.next_batch(None, None, 20, 100).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(state.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_guidance() {
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 64,
+            ..QueueConfig::default()
+        });
+        let mut guards = Vec::new();
+        for _ in 0..2 {
+            let (mut entry, guard) = default_entry();
+            entry.request.input_length = 3;
+            entry.request.guidance = Some(ValidGuidance {
+                scale: 1.5,
+                inputs: vec![Chunk::Text("negative".to_string())],
+                input_ids: Arc::new(vec![1, 2]),
+                input_length: 2,
+            });
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // Both sequences of a guided request are prefilled in the same batch
+        let (entries, batch, _) = state.next_batch(None, None, 8, 64).await.unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), [&0]);
+        assert_eq!(entries[&0].sequences(), 2);
+        let ids: Vec<u64> = batch.requests.iter().map(|request| request.id).collect();
+        assert_eq!(ids, [0, NEGATIVE_REQUEST_BIT]);
+        let guidance = batch.requests[0].guidance.as_ref().unwrap();
+        assert_eq!(guidance.negative_id, NEGATIVE_REQUEST_BIT);
+        assert_eq!(batch.requests[1].truncate, 2);
+        assert!(batch.requests[1].guidance.is_none());
+        assert_eq!(state.entries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_guidance_max_size() {
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 64,
+            ..QueueConfig::default()
+        });
+        let mut guards = Vec::new();
+        for i in 0..3 {
+            let (mut entry, guard) = default_entry();
+            if i == 0 {
+                entry.request.input_length = 3;
+                entry.request.guidance = Some(ValidGuidance {
+                    scale: 1.5,
+                    inputs: vec![Chunk::Text("negative".to_string())],
+                    input_ids: Arc::new(vec![1, 2]),
+                    input_length: 2,
+                });
+            }
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The negative sequence counts towards the size of the batch
+        assert!(state.next_batch(None, Some(1), 64, 64).await.is_none());
+        assert_eq!(state.entries.len(), 3);
+        let (entries, batch, _) = state.next_batch(None, Some(3), 64, 64).await.unwrap();
+        let mut ids: Vec<&u64> = entries.keys().collect();
+        ids.sort();
+        assert_eq!(ids, [&0, &1]);
+        assert_eq!(sequences(&entries), 3);
+        assert_eq!(batch.requests.len(), 3);
+        assert_eq!(state.entries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_history() {
+        let mut state = State::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+
+        state.next_batch(None, None, 2, 16).await.unwrap();
+
+        assert_eq!(state.batch_history.len(), 1);
+        let record = &state.batch_history[0];
+        assert_eq!(record.id, 0);
+        assert_eq!(record.size, 2);
+        assert_eq!(record.prefill_tokens, 2);
+        assert_eq!(record.prefill_token_budget, 2);
+        assert_eq!(record.decode_tokens, 2);
+        assert_eq!(record.token_budget, 16);
+    }
+
     #[tokio::test]
     async fn test_queue_append() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry, _guard) = default_entry();
         queue.append(entry);
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_empty() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
 
         assert!(queue.next_batch(None, None, 1, 1).await.is_none());
         assert!(queue.next_batch(Some(1), None, 1, 1).await.is_none());
@@ -715,7 +2158,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_min_size() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -748,7 +2194,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_max_size() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -764,7 +2213,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_budget() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -789,7 +2241,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_speculate() {
-        let queue = Queue::new(true, 1, false, None, 2, 16, false);
+        let queue = Queue::new(QueueConfig {
+            requires_padding: true,
+            speculate: 2,
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -808,10 +2265,64 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_dropped_receiver() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
         let (entry, _) = default_entry();
         queue.append(entry);
 
         assert!(queue.next_batch(None, None, 1, 1).await.is_none());
     }
+
+    #[tokio::test]
+    async fn test_queue_close() {
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let (queued, mut queued_rx) = default_entry();
+        queue.append(queued);
+        queue.close();
+        let (appended, mut appended_rx) = default_entry();
+        queue.append(appended);
+
+        for rx in [&mut queued_rx, &mut appended_rx] {
+            assert!(matches!(
+                rx.recv().await,
+                Some(Err(InferError::BackendUnavailable(_)))
+            ));
+        }
+        assert!(queue.next_batch(None, None, 1, 1).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queue_close_hands_over() {
+        let queue = Queue::new(QueueConfig {
+            max_batch_total_tokens: 16,
+            ..QueueConfig::default()
+        });
+        let (handover, mut handover_rx) = mpsc::unbounded_channel();
+        queue.set_handover(handover);
+        let (queued, _queued_rx) = default_entry();
+        queue.append(queued);
+        // A preempted entry already streamed its first tokens
+        let (mut resumed, mut resumed_rx) = default_entry();
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.push(1, "a", false);
+        resumed.checkpoint = Some(checkpoint);
+        queue.append(resumed);
+        queue.close();
+        let (appended, _appended_rx) = default_entry();
+        queue.append(appended);
+
+        // The entries that did not start go to the other replica, in their order
+        assert!(handover_rx.recv().await.is_some());
+        assert!(matches!(
+            resumed_rx.recv().await,
+            Some(Err(InferError::BackendUnavailable(_)))
+        ));
+        assert!(handover_rx.recv().await.is_some());
+        assert!(handover_rx.try_recv().is_err());
+    }
 }
@@ -1,10 +1,14 @@
 use crate::block_allocator::{Allocator, BlockAllocation};
+use crate::client::BlockSwap;
+use crate::swap::{HostCache, Swaps};
 use slotmap::{DefaultKey, SlotMap};
 use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeSet, HashMap, VecDeque},
     sync::Arc,
 };
+use text_generation_router::SessionStats;
 
 fn hash(slice: &[u32]) -> u64 {
     assert!(!slice.is_empty());
@@ -27,16 +31,40 @@ pub struct RadixAllocator {
     /// Blocks that are immediately available for allocation.
     free_blocks: Vec<u32>,
 
-    #[allow(dead_code)]
-    // This isn't used because the prefix need to match without the windowing
-    // mecanism. This at worst is overallocating, not necessarily being wrong.
+    /// Attention window of the model. Allocations longer than the window only
+    /// hold the blocks of one window, see `allocate_windowed`.
     window_size: Option<u32>,
 
     block_size: u32,
+
+    /// Sessions whose last turn is pinned in the trie.
+    sessions: HashMap<String, Session>,
+
+    /// Time after which an idle session is unpinned.
+    session_ttl: Duration,
+
+    /// Blocks of the preempted requests kept in the host memory of the shards.
+    host_cache: Option<HostCache>,
+
+    /// Copies to run on the shards before the last allocation is used.
+    swaps: Swaps,
+
+    /// Time of the compactions along with the trie time at that point, which tells how long
+    /// ago the trie nodes were accessed without a clock in the trie.
+    compactions: VecDeque<(Instant, u64)>,
+
+    /// Blocks evicted from the trie since they were last reported.
+    evicted_blocks: usize,
 }
 
 impl RadixAllocator {
-    pub fn new(block_size: u32, n_blocks: u32, window_size: Option<u32>) -> Self {
+    pub fn new(
+        block_size: u32,
+        n_blocks: u32,
+        window_size: Option<u32>,
+        session_ttl: Duration,
+        swap_blocks: u32,
+    ) -> Self {
         RadixAllocator {
             allocation_id: 0,
             allocations: HashMap::new(),
@@ -46,11 +74,25 @@ impl RadixAllocator {
             free_blocks: (1..n_blocks).collect(),
             window_size,
             block_size,
+            sessions: HashMap::new(),
+            session_ttl,
+            host_cache: (swap_blocks > 0).then(|| HostCache::new(block_size, swap_blocks)),
+            swaps: Swaps::default(),
+            compactions: VecDeque::new(),
+            evicted_blocks: 0,
         }
     }
 
+    /// Evict `n_blocks` from the trie. Their KV is dropped, the host memory only keeps the
+    /// blocks of the preempted requests.
+    fn evict(&mut self, n_blocks: usize) -> Vec<u32> {
+        let evicted = self.cache_blocks.evict(n_blocks);
+        self.evicted_blocks += evicted.len();
+        evicted
+    }
+
     fn alloc_or_reclaim(&mut self, n_blocks_needed: usize) -> Option<Vec<u32>> {
-        if self.free_blocks.len() < n_blocks_needed {
+        while self.free_blocks.len() < n_blocks_needed {
             // This is a bit annoying, we first extend the free list and then
             // split it off again below. This is because we need to put it on
             // the free list if we cannot allocate enough blocks. This is only
@@ -60,10 +102,14 @@ impl RadixAllocator {
                 "Free blocks {}  need {n_blocks_needed}",
                 self.free_blocks.len()
             );
-            self.free_blocks.extend(
-                self.cache_blocks
-                    .evict(n_blocks_needed - self.free_blocks.len()),
-            );
+            let evicted = self.evict(n_blocks_needed - self.free_blocks.len());
+            self.free_blocks.extend(evicted);
+
+            // Under memory pressure, pinned sessions are given up, least
+            // recently used first.
+            if self.free_blocks.len() < n_blocks_needed && !self.unpin_lru_session() {
+                break;
+            }
         }
 
         if self.free_blocks.len() >= n_blocks_needed {
@@ -75,15 +121,87 @@ impl RadixAllocator {
             None
         }
     }
-}
 
-// Allocator trait
-impl Allocator for RadixAllocator {
-    fn allocate(
+    /// Release the trie node pinned by a session, if any.
+    fn unpin_session(cache_blocks: &mut RadixTrie, session: &mut Session) {
+        if let Some(node_id) = session.pinned_node.take() {
+            cache_blocks
+                .decref(node_id)
+                .expect("Failed to decrement refcount");
+            session.pinned_tokens = 0;
+        }
+    }
+
+    /// Unpin the least recently used session. Returns `false` when no
+    /// session is pinned.
+    fn unpin_lru_session(&mut self) -> bool {
+        let lru = self
+            .sessions
+            .iter_mut()
+            .filter(|(_, session)| session.pinned_node.is_some())
+            .min_by_key(|(_, session)| session.last_used);
+        match lru {
+            Some((session_id, session)) => {
+                tracing::debug!("Unpinning session {session_id} to reclaim blocks");
+                Self::unpin_session(&mut self.cache_blocks, session);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the sessions that have been idle for longer than the TTL.
+    fn expire_sessions(&mut self) {
+        let now = Instant::now();
+        let session_ttl = self.session_ttl;
+        let cache_blocks = &mut self.cache_blocks;
+        self.sessions.retain(|_, session| {
+            let expired =
+                session.active == 0 && now.duration_since(session.last_used) >= session_ttl;
+            if expired {
+                Self::unpin_session(cache_blocks, session);
+            }
+            !expired
+        });
+    }
+
+    fn compact_(&mut self, prefix_ttl: Option<Duration>) -> usize {
+        self.expire_sessions();
+
+        let mut released = 0;
+        if let Some(prefix_ttl) = prefix_ttl {
+            let now = Instant::now();
+            self.compactions.push_back((now, self.cache_blocks.time));
+            // Trie time of the last compaction at least `prefix_ttl` ago
+            let mut expired_time = None;
+            while let Some(&(compacted_at, time)) = self.compactions.front() {
+                if now.duration_since(compacted_at) < prefix_ttl {
+                    break;
+                }
+                expired_time = Some(time);
+                self.compactions.pop_front();
+            }
+            if let Some(expired_time) = expired_time {
+                let blocks = self.cache_blocks.evict_unused_since(expired_time);
+                released = blocks.len();
+                self.free_blocks.extend(blocks);
+            }
+        }
+
+        self.free_blocks.sort_unstable();
+        released
+    }
+
+    fn allocate_(
         &mut self,
         tokens: u32,
         prefill_tokens: Option<Arc<Vec<u32>>>,
+        session_id: Option<String>,
     ) -> Option<BlockAllocation> {
+        if let Some(window_size) = self.window_size.filter(|&window_size| tokens > window_size) {
+            return self.allocate_windowed(tokens, window_size);
+        }
+
         let mut blocks = vec![];
         let prefix_node = if let Some(prefill_tokens) = prefill_tokens.as_ref() {
             let node_id = self
@@ -100,18 +218,32 @@ impl Allocator for RadixAllocator {
             .incref(prefix_node)
             .expect("Failed to increment refcount");
 
-        let prefix_len = blocks.len() * self.block_size as usize;
-        let suffix_len = tokens - prefix_len as u32;
+        let cached_prefix_len = blocks.len() * self.block_size as usize;
+        let suffix_len = tokens - cached_prefix_len as u32;
 
         let suffix_blocks = (suffix_len + self.block_size - 1) / self.block_size;
 
-        tracing::info!("Prefix {prefix_len} - Suffix {suffix_len}");
+        tracing::info!("Prefix {cached_prefix_len} - Suffix {suffix_len}");
+
+        // The blocks that follow the cached prefix may still be in the host
+        // memory, in which case they are copied back instead of prefilled.
+        // They are looked up before reclaiming, which could overwrite them.
+        let mut host_blocks = Vec::new();
+        if let Some(host_cache) = self.host_cache.as_mut() {
+            host_cache.tick();
+            if let Some(prefill_tokens) = prefill_tokens.as_ref() {
+                host_blocks = host_cache.find(
+                    prefill_tokens.as_slice(),
+                    cached_prefix_len / self.block_size as usize,
+                );
+            }
+        }
 
         match self.alloc_or_reclaim(suffix_blocks as usize) {
             Some(suffix_blocks) => blocks.extend(suffix_blocks),
             None => {
                 tracing::debug!("Cannot allocate {:?}", self.cache_blocks);
-                tracing::debug!("Found {prefix_len} prefix tokens need {suffix_blocks} suffix blocks for {tokens} tokens");
+                tracing::debug!("Found {cached_prefix_len} prefix tokens need {suffix_blocks} suffix blocks for {tokens} tokens");
                 tracing::debug!("Block size {}", self.block_size);
                 self.cache_blocks
                     .decref(prefix_node)
@@ -120,6 +252,17 @@ impl Allocator for RadixAllocator {
             }
         }
 
+        // Restored blocks are only added to the trie when the allocation is freed.
+        let prefix_blocks = cached_prefix_len / self.block_size as usize;
+        let mut prefix_len = cached_prefix_len;
+        for (host_block, &device_block) in host_blocks.into_iter().zip(&blocks[prefix_blocks..]) {
+            self.swaps.swap_in.push(BlockSwap {
+                device_block,
+                host_block,
+            });
+            prefix_len += self.block_size as usize;
+        }
+
         // 1:1 mapping of blocks and slots.
         let slots = if self.block_size == 1 {
             blocks.clone()
@@ -136,10 +279,25 @@ impl Allocator for RadixAllocator {
             slots
         };
 
+        if let Some(session_id) = session_id.as_ref() {
+            let session = self.sessions.entry(session_id.clone()).or_default();
+            // The prefix found for this turn is now referenced by the
+            // allocation itself, the pin of the previous turn can go.
+            Self::unpin_session(&mut self.cache_blocks, session);
+            let prefill_len = prefill_tokens.as_ref().map(|t| t.len()).unwrap_or(0);
+            session.active += 1;
+            session.requests += 1;
+            session.cache_hit_tokens += prefix_len as u64;
+            session.cache_miss_tokens += prefill_len.saturating_sub(prefix_len) as u64;
+            session.last_used = Instant::now();
+        }
+
         let allocation = RadixAllocation {
             prefix_node,
-            cached_prefix_len: prefix_len,
+            cached_prefix_len,
             prefill_tokens: prefill_tokens.clone(),
+            session_id,
+            duplicate_blocks: Vec::new(),
         };
 
         self.allocation_id += 1;
@@ -151,10 +309,58 @@ impl Allocator for RadixAllocator {
             blocks,
             slots,
             prefix_len: prefix_len as u32,
+            generated_tokens: None,
+            prefill_cached: false,
+            preempted: false,
+            share: None,
         })
     }
 
-    fn free(&mut self, blocks: Vec<u32>, allocation_id: u64) {
+    /// Allocate the blocks of a sequence of `tokens` attending to the last `window_size` ones.
+    /// The KV that slid out of the window is overwritten, so the sequence cycles through the
+    /// blocks of one window instead of holding its whole history. Its prompt is neither looked
+    /// up nor cached: its first tokens do not stay in the blocks.
+    fn allocate_windowed(&mut self, tokens: u32, window_size: u32) -> Option<BlockAllocation> {
+        let window_blocks = window_size.div_ceil(self.block_size);
+        let blocks = self.alloc_or_reclaim(window_blocks as usize)?;
+        let slots = blocks
+            .iter()
+            .cycle()
+            .flat_map(|block_id| (block_id * self.block_size)..((block_id + 1) * self.block_size))
+            .take(tokens as usize)
+            .collect();
+
+        // Referenced like the allocations without a prompt
+        let prefix_node = self.cache_blocks.root_id();
+        self.cache_blocks
+            .incref(prefix_node)
+            .expect("Failed to increment refcount");
+        self.allocation_id += 1;
+        self.allocations.insert(
+            self.allocation_id,
+            RadixAllocation {
+                prefix_node,
+                cached_prefix_len: 0,
+                prefill_tokens: None,
+                session_id: None,
+                duplicate_blocks: Vec::new(),
+            },
+        );
+
+        Some(BlockAllocation {
+            allocation_id: self.allocation_id,
+            block_allocator: None,
+            blocks,
+            slots,
+            prefix_len: 0,
+            generated_tokens: None,
+            prefill_cached: false,
+            preempted: false,
+            share: None,
+        })
+    }
+
+    fn free_(&mut self, blocks: Vec<u32>, allocation_id: u64, generated_tokens: &[u32]) {
         let allocation = match self.allocations.remove(&allocation_id) {
             Some(allocation) => allocation,
             None => unreachable!("Tried to free an unknown allocation."),
@@ -163,8 +369,23 @@ impl Allocator for RadixAllocator {
         self.cache_blocks
             .decref(allocation.prefix_node)
             .expect("Failed to decrement refcount");
+        self.free_blocks.extend(&allocation.duplicate_blocks);
+
+        // The KV of the generated tokens follows the prefill in the blocks, so
+        // sessions cache both. The last generated token was never fed to the
+        // model and has no KV yet.
+        let cached_tokens = match allocation.prefill_tokens {
+            Some(prefill_tokens) if generated_tokens.len() > 1 => {
+                let mut tokens = prefill_tokens.as_ref().clone();
+                tokens.extend(&generated_tokens[..generated_tokens.len() - 1]);
+                tokens.truncate(blocks.len() * self.block_size as usize);
+                Some(Arc::new(tokens))
+            }
+            prefill_tokens => prefill_tokens,
+        };
 
-        if let Some(prefill_tokens) = allocation.prefill_tokens {
+        let mut pinned = None;
+        if let Some(prefill_tokens) = cached_tokens {
             let prefill_tokens = prefill_tokens.as_slice();
 
             // If there are prefill tokens that did not come from the cache,
@@ -202,9 +423,178 @@ impl Allocator for RadixAllocator {
             // Free non-prefill blocks.
             self.free_blocks
                 .extend(&blocks[prefill_tokens.len() / self.block_size as usize..]);
+
+            // Keep the turn in the trie until the next turn of the session.
+            let aligned =
+                (prefill_tokens.len() / self.block_size as usize) * self.block_size as usize;
+            if allocation.session_id.is_some() && aligned > 0 {
+                let mut pinned_blocks = Vec::new();
+                let node_id = self
+                    .cache_blocks
+                    .find(&prefill_tokens[..aligned], &mut pinned_blocks);
+                self.cache_blocks
+                    .incref(node_id)
+                    .expect("Failed to increment refcount");
+                pinned = Some((node_id, pinned_blocks.len() * self.block_size as usize));
+            }
         } else {
             self.free_blocks.extend(blocks);
         }
+
+        if let Some(session_id) = allocation.session_id {
+            // Unwrap, sessions with active allocations are never expired.
+            let session = self.sessions.get_mut(&session_id).expect("Unknown session");
+            if let Some((node_id, pinned_tokens)) = pinned {
+                Self::unpin_session(&mut self.cache_blocks, session);
+                session.pinned_node = Some(node_id);
+                session.pinned_tokens = pinned_tokens;
+            }
+            session.active -= 1;
+            session.last_used = Instant::now();
+        }
+
+        self.expire_sessions();
+    }
+
+    fn cache_prefill_(&mut self, blocks: &[u32], allocation_id: u64) {
+        let block_size = self.block_size as usize;
+        let Some(allocation) = self.allocations.get_mut(&allocation_id) else {
+            return;
+        };
+        let Some(prefill_tokens) = allocation.prefill_tokens.clone() else {
+            return;
+        };
+        let aligned = (prefill_tokens.len() / block_size) * block_size;
+        if aligned <= allocation.cached_prefix_len {
+            return;
+        }
+
+        let prefix_len = self
+            .cache_blocks
+            .insert(&prefill_tokens[..aligned], &blocks[..aligned / block_size])
+            // Unwrap, failing is a programming error.
+            .expect("Failed to store prefill tokens");
+
+        // The allocation now references its whole prompt in the trie.
+        let mut cached_blocks = Vec::new();
+        let node_id = self
+            .cache_blocks
+            .find(&prefill_tokens[..aligned], &mut cached_blocks);
+        self.cache_blocks
+            .incref(node_id)
+            .expect("Failed to increment refcount");
+        self.cache_blocks
+            .decref(allocation.prefix_node)
+            .expect("Failed to decrement refcount");
+
+        // An identical prompt was cached while this one was prefilled. The sequence keeps
+        // using its own copy of these blocks until it is freed.
+        let cached_len = cached_blocks.len() * block_size;
+        let duplicate_len = prefix_len.min(cached_len);
+        if duplicate_len > allocation.cached_prefix_len {
+            allocation.duplicate_blocks.extend(
+                &blocks[allocation.cached_prefix_len / block_size..duplicate_len / block_size],
+            );
+        }
+
+        allocation.prefix_node = node_id;
+        allocation.cached_prefix_len = cached_len;
+    }
+}
+
+// Allocator trait
+impl Allocator for RadixAllocator {
+    fn allocate(
+        &mut self,
+        tokens: u32,
+        prefill_tokens: Option<Arc<Vec<u32>>>,
+    ) -> Option<BlockAllocation> {
+        self.expire_sessions();
+        self.allocate_(tokens, prefill_tokens, None)
+    }
+
+    fn free(&mut self, blocks: Vec<u32>, allocation_id: u64) {
+        self.free_(blocks, allocation_id, &[])
+    }
+
+    fn allocate_session(
+        &mut self,
+        tokens: u32,
+        prefill_tokens: Option<Arc<Vec<u32>>>,
+        session_id: String,
+    ) -> Option<BlockAllocation> {
+        self.expire_sessions();
+        self.allocate_(tokens, prefill_tokens, Some(session_id))
+    }
+
+    fn free_session(&mut self, blocks: Vec<u32>, allocation_id: u64, generated_tokens: Vec<u32>) {
+        self.free_(blocks, allocation_id, &generated_tokens)
+    }
+
+    fn cache_prefill(&mut self, blocks: &[u32], allocation_id: u64) {
+        self.cache_prefill_(blocks, allocation_id)
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    fn cached_blocks(&self) -> usize {
+        self.cache_blocks.evictable_blocks()
+    }
+
+    fn take_swaps(&mut self) -> Swaps {
+        std::mem::take(&mut self.swaps)
+    }
+
+    fn forget_swapped(&mut self, host_blocks: &[u32]) {
+        if let Some(host_cache) = self.host_cache.as_mut() {
+            host_cache.forget(host_blocks);
+        }
+    }
+
+    fn swap_out_preempted(&mut self, blocks: &[u32], allocation_id: u64, generated_tokens: &[u32]) {
+        let Some(host_cache) = self.host_cache.as_mut() else {
+            return;
+        };
+        let Some(prefill_tokens) = self
+            .allocations
+            .get(&allocation_id)
+            .and_then(|allocation| allocation.prefill_tokens.as_ref())
+        else {
+            return;
+        };
+        // As for the sessions, the last generated token has no KV yet
+        let mut tokens = prefill_tokens.as_ref().clone();
+        tokens.extend(&generated_tokens[..generated_tokens.len().saturating_sub(1)]);
+        let full_blocks = (tokens.len() / self.block_size as usize).min(blocks.len());
+        tokens.truncate(full_blocks * self.block_size as usize);
+        self.swaps
+            .swap_out
+            .extend(host_cache.swap_out(&tokens, &blocks[..full_blocks]));
+    }
+
+    fn take_evicted_blocks(&mut self) -> usize {
+        std::mem::take(&mut self.evicted_blocks)
+    }
+
+    fn compact(&mut self, prefix_ttl: Option<Duration>) -> usize {
+        self.compact_(prefix_ttl)
+    }
+
+    fn session_stats(&self) -> Vec<SessionStats> {
+        let now = Instant::now();
+        self.sessions
+            .iter()
+            .map(|(session_id, session)| SessionStats {
+                session_id: session_id.clone(),
+                requests: session.requests,
+                cache_hit_tokens: session.cache_hit_tokens,
+                cache_miss_tokens: session.cache_miss_tokens,
+                pinned_tokens: session.pinned_tokens as u32,
+                idle_ms: now.duration_since(session.last_used).as_millis() as u64,
+            })
+            .collect()
     }
 }
 
@@ -212,6 +602,37 @@ struct RadixAllocation {
     prefix_node: NodeId,
     cached_prefix_len: usize,
     prefill_tokens: Option<Arc<Vec<u32>>>,
+    session_id: Option<String>,
+    /// Blocks of the prompt whose KV was cached by another allocation, freed with this one
+    duplicate_blocks: Vec<u32>,
+}
+
+/// KV cache kept on behalf of a conversation.
+struct Session {
+    /// Trie node holding the last turn of the session.
+    pinned_node: Option<NodeId>,
+    /// Number of tokens held by `pinned_node` and its ancestors.
+    pinned_tokens: usize,
+    /// Number of allocations of the session that were not freed yet.
+    active: usize,
+    last_used: Instant,
+    requests: u64,
+    cache_hit_tokens: u64,
+    cache_miss_tokens: u64,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            pinned_node: None,
+            pinned_tokens: 0,
+            active: 0,
+            last_used: Instant::now(),
+            requests: 0,
+            cache_hit_tokens: 0,
+            cache_miss_tokens: 0,
+        }
+    }
 }
 
 // Radix trie that is heavily inspired by radix attention from sglang.
@@ -295,10 +716,14 @@ impl RadixTrie {
                 assert_eq!(shared_prefix_len % self.block_size, 0);
                 blocks.extend(&child.blocks[..shared_prefix_len / self.block_size]);
 
+                // Return the child when it holds the end of the prefix, so
+                // that referencing the node protects all the found blocks.
                 let key = &key[shared_prefix_len..];
-                if !key.is_empty() {
-                    node_id = self.find_(child_id, key, blocks);
-                }
+                node_id = if key.is_empty() {
+                    child_id
+                } else {
+                    self.find_(child_id, key, blocks)
+                };
             }
         }
 
@@ -357,6 +782,17 @@ impl RadixTrie {
     /// Returns the evicted blocks. When the length is less than `n_blocks`,
     /// not enough blocks could be evicted.
     pub fn evict(&mut self, n_blocks: usize) -> Vec<u32> {
+        self.evict_prefixes(n_blocks)
+            .into_iter()
+            .flat_map(|(_, blocks)| blocks)
+            .collect()
+    }
+
+    /// Evict `n_blocks` from the trie.
+    ///
+    /// Returns the evicted blocks along with the tokens of the prefix that
+    /// ends with them, one entry per evicted node.
+    pub fn evict_prefixes(&mut self, n_blocks: usize) -> Vec<(Vec<u32>, Vec<u32>)> {
         // NOTE: we don't return Result here. If any of the unwrapping fails,
         // it's a programming error in the trie implementation, not a user
         // error caused by e.g. an invalid argument.
@@ -365,10 +801,11 @@ impl RadixTrie {
         // evict n_blocks and return `None` if we can't. We are now needlessly
         // evicting prefixes from the cache in such a case.
         let mut evicted = Vec::new();
+        let mut n_evicted = 0;
         tracing::debug!("Evicting in search of {n_blocks}");
 
         while let Some((last_access, node_id)) = self.leaves.pop_first() {
-            let blocks_needed = n_blocks.saturating_sub(evicted.len());
+            let blocks_needed = n_blocks.saturating_sub(n_evicted);
             tracing::debug!("Evicting node {node_id:?} ");
 
             let node = self.nodes.get(node_id).expect("Leave does not exist");
@@ -377,13 +814,15 @@ impl RadixTrie {
                 "Leaf must have refcount of 0, got {}",
                 node.ref_count
             );
+            let tokens = self.prefix_tokens(node_id);
 
             if blocks_needed >= node.blocks.len() {
                 // We need to evict the whole node if we need more blocks than it has.
                 let node = self.remove_node(node_id);
-                evicted.extend(node.blocks);
+                n_evicted += node.blocks.len();
+                evicted.push((tokens, node.blocks));
 
-                if evicted.len() >= n_blocks {
+                if n_evicted >= n_blocks {
                     break;
                 }
             } else {
@@ -395,7 +834,7 @@ impl RadixTrie {
                 let truncate_blocks = node.blocks.len() - blocks_needed;
                 let truncate_tokens = truncate_blocks * self.block_size;
                 node.key.truncate(truncate_tokens);
-                evicted.extend(node.blocks.split_off(truncate_blocks));
+                evicted.push((tokens, node.blocks.split_off(truncate_blocks)));
                 self.leaves.insert((last_access, node_id));
                 break;
             }
@@ -404,6 +843,59 @@ impl RadixTrie {
         evicted
     }
 
+    /// Evict the nodes that were not accessed after the trie time `time`.
+    ///
+    /// Returns the evicted blocks.
+    pub fn evict_unused_since(&mut self, time: u64) -> Vec<u32> {
+        let mut evicted = Vec::new();
+        // Accessing a node accesses its parents, a parent is evicted after its children
+        while let Some(&(last_accessed, node_id)) = self.leaves.first() {
+            if last_accessed > time {
+                break;
+            }
+            self.leaves.pop_first();
+            evicted.extend(self.remove_node(node_id).blocks);
+        }
+        evicted
+    }
+
+    /// Number of blocks that eviction can reclaim: those of the nodes whose subtree is not
+    /// referenced by anything but the trie itself.
+    pub fn evictable_blocks(&self) -> usize {
+        self.evictable_blocks_(self.root).1
+    }
+
+    /// Whether the subtree of a node is evictable, and its evictable blocks.
+    fn evictable_blocks_(&self, node_id: NodeId) -> (bool, usize) {
+        let node = &self.nodes[node_id];
+        let mut evictable = true;
+        let mut blocks = 0;
+        for &child_id in node.children.values() {
+            let (child_evictable, child_blocks) = self.evictable_blocks_(child_id);
+            evictable &= child_evictable;
+            blocks += child_blocks;
+        }
+        // Every child holds a reference to its parent
+        if node_id != self.root && evictable && node.ref_count == node.children.len() {
+            blocks += node.blocks.len();
+        } else {
+            evictable = false;
+        }
+        (evictable, blocks)
+    }
+
+    /// Tokens from the root of the trie up to the end of a node.
+    fn prefix_tokens(&self, node_id: NodeId) -> Vec<u32> {
+        let mut keys = Vec::new();
+        let mut node_id = Some(node_id);
+        while let Some(id) = node_id {
+            let node = &self.nodes[id];
+            keys.push(node.key.as_slice());
+            node_id = node.parent;
+        }
+        keys.into_iter().rev().flatten().copied().collect()
+    }
+
     /// Insert a prefill along with its blocks.
     ///
     /// This method returns the length of the prefix that was already
@@ -633,9 +1125,11 @@ mod tests {
 
     use super::*;
 
+    const SESSION_TTL: Duration = Duration::from_secs(300);
+
     #[test]
     fn allocator_block_size() {
-        let mut cache = RadixAllocator::new(2, 12, None);
+        let mut cache = RadixAllocator::new(2, 12, None, SESSION_TTL, 0);
         let allocation = cache.allocate(8, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
         assert_eq!(allocation.blocks, vec![8, 9, 10, 11]);
         assert_eq!(allocation.slots, vec![16, 17, 18, 19, 20, 21, 22, 23]);
@@ -650,7 +1144,7 @@ mod tests {
 
     #[test]
     fn allocator_block_size_non_aligned() {
-        let mut cache = RadixAllocator::new(2, 12, None);
+        let mut cache = RadixAllocator::new(2, 12, None, SESSION_TTL, 0);
         let allocation = cache.allocate(7, Some(Arc::new(vec![0, 1, 2]))).unwrap();
         assert_eq!(allocation.blocks, vec![8, 9, 10, 11]);
         assert_eq!(allocation.slots, vec![16, 17, 18, 19, 20, 21, 22]);
@@ -663,9 +1157,30 @@ mod tests {
         assert_eq!(allocation.prefix_len, 2);
     }
 
+    #[test]
+    fn allocator_windowed_allocations() {
+        let mut cache = RadixAllocator::new(2, 12, Some(4), SESSION_TTL, 0);
+        // Within the window, the prompt is cached
+        let allocation = cache.allocate(4, Some(Arc::new(vec![0, 1]))).unwrap();
+        assert_eq!(allocation.blocks, vec![10, 11]);
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+        assert_eq!(cache.cached_blocks(), 1);
+
+        // Past the window, the blocks of one window are reused
+        let allocation = cache.allocate(10, Some(Arc::new(vec![0, 1, 2]))).unwrap();
+        assert_eq!(allocation.blocks.len(), 2);
+        assert_eq!(allocation.prefix_len, 0);
+        assert_eq!(allocation.slots.len(), 10);
+        assert_eq!(allocation.slots[..4], allocation.slots[4..8]);
+        assert_eq!(cache.free_blocks(), 8);
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+        assert_eq!(cache.free_blocks(), 10);
+        assert_eq!(cache.cached_blocks(), 1);
+    }
+
     #[test]
     fn allocator_reuses_prefixes() {
-        let mut cache = RadixAllocator::new(1, 12, None);
+        let mut cache = RadixAllocator::new(1, 12, None, SESSION_TTL, 0);
         let allocation = cache.allocate(8, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
         assert_eq!(allocation.blocks, vec![4, 5, 6, 7, 8, 9, 10, 11]);
         assert_eq!(allocation.blocks, allocation.slots);
@@ -677,9 +1192,46 @@ mod tests {
         assert_eq!(allocation.prefix_len, 4);
     }
 
+    #[test]
+    fn allocator_counts_cached_blocks() {
+        let mut cache = RadixAllocator::new(1, 12, None, SESSION_TTL, 0);
+        let allocation = cache.allocate(8, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
+        assert_eq!(cache.free_blocks(), 3);
+        assert_eq!(cache.cached_blocks(), 0);
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+
+        // The prompt stays in the trie until it is evicted
+        assert_eq!(cache.free_blocks(), 7);
+        assert_eq!(cache.cached_blocks(), 4);
+
+        // Blocks referenced by an allocation, or by one of their descendants, are pinned
+        let allocation = cache
+            .allocate(6, Some(Arc::new(vec![0, 1, 2, 3, 4, 5])))
+            .unwrap();
+        assert_eq!(allocation.prefix_len, 4);
+        assert_eq!(cache.cached_blocks(), 0);
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+        assert_eq!(cache.free_blocks(), 5);
+        assert_eq!(cache.cached_blocks(), 6);
+    }
+
+    #[test]
+    fn allocator_counts_evicted_blocks() {
+        let mut cache = RadixAllocator::new(1, 12, None, SESSION_TTL, 0);
+        let allocation = cache.allocate(8, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+        assert_eq!(cache.take_evicted_blocks(), 0);
+
+        // 7 blocks are free, the other 3 come from the cached prompt
+        let allocation = cache.allocate(10, Some(Arc::new(vec![4, 5]))).unwrap();
+        assert_eq!(allocation.prefix_len, 0);
+        assert_eq!(cache.take_evicted_blocks(), 3);
+        assert_eq!(cache.take_evicted_blocks(), 0);
+    }
+
     #[test]
     fn allocator_collects_older_prefixes_first() {
-        let mut cache = RadixAllocator::new(1, 7, None);
+        let mut cache = RadixAllocator::new(1, 7, None, SESSION_TTL, 0);
         let allocation1 = cache.allocate(4, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
         assert_eq!(allocation1.blocks, vec![3, 4, 5, 6]);
         assert_eq!(allocation1.prefix_len, 0);
@@ -699,7 +1251,7 @@ mod tests {
 
     #[test]
     fn allocator_frees_fully_overlapping_prefills() {
-        let mut cache = RadixAllocator::new(1, 10, None);
+        let mut cache = RadixAllocator::new(1, 10, None, SESSION_TTL, 0);
         let allocation1 = cache.allocate(4, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
         let allocation2 = cache.allocate(4, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
 
@@ -715,7 +1267,7 @@ mod tests {
 
     #[test]
     fn allocator_frees_partially_overlapping_prefills() {
-        let mut cache = RadixAllocator::new(1, 20, None);
+        let mut cache = RadixAllocator::new(1, 20, None, SESSION_TTL, 0);
         let allocation1 = cache.allocate(4, Some(Arc::new(vec![0, 1]))).unwrap();
         assert_eq!(allocation1.blocks, vec![16, 17, 18, 19]);
         assert_eq!(allocation1.prefix_len, 0);
@@ -755,6 +1307,186 @@ mod tests {
         assert_eq!(cache.free_blocks.len(), 11);
     }
 
+    #[test]
+    fn allocator_shares_cached_prefill() {
+        let mut cache = RadixAllocator::new(2, 12, None, SESSION_TTL, 0);
+        let allocation1 = cache
+            .allocate(8, Some(Arc::new(vec![0, 1, 2, 3, 4])))
+            .unwrap();
+        assert_eq!(allocation1.blocks, vec![8, 9, 10, 11]);
+        assert_eq!(allocation1.prefix_len, 0);
+
+        // The aligned part of the prompt is shared once it is computed.
+        cache.cache_prefill(&allocation1.blocks, allocation1.allocation_id);
+        let allocation2 = cache
+            .allocate(8, Some(Arc::new(vec![0, 1, 2, 3, 4])))
+            .unwrap();
+        assert_eq!(allocation2.blocks, vec![8, 9, 6, 7]);
+        assert_eq!(allocation2.prefix_len, 4);
+
+        // The shared blocks are not evicted while in use.
+        assert!(cache.allocate(12, None).is_none());
+
+        cache.free(allocation1.blocks.clone(), allocation1.allocation_id);
+        cache.free(allocation2.blocks.clone(), allocation2.allocation_id);
+
+        // 12 blocks, of which 1 reserved for health checks, 2 for the cached blocks.
+        assert_eq!(cache.free_blocks.len(), 9);
+    }
+
+    #[test]
+    fn allocator_frees_duplicate_cached_prefill() {
+        let mut cache = RadixAllocator::new(1, 10, None, SESSION_TTL, 0);
+        let allocation1 = cache.allocate(4, Some(Arc::new(vec![0, 1, 2]))).unwrap();
+        let allocation2 = cache.allocate(4, Some(Arc::new(vec![0, 1, 2]))).unwrap();
+
+        cache.cache_prefill(&allocation1.blocks, allocation1.allocation_id);
+        cache.cache_prefill(&allocation2.blocks, allocation2.allocation_id);
+
+        cache.free(allocation2.blocks.clone(), allocation2.allocation_id);
+        cache.free(allocation1.blocks.clone(), allocation1.allocation_id);
+
+        let allocation3 = cache.allocate(4, Some(Arc::new(vec![0, 1, 2]))).unwrap();
+        assert_eq!(allocation3.blocks[..3], allocation1.blocks[..3]);
+        assert_eq!(allocation3.prefix_len, 3);
+
+        // 10 blocks, of which 1 reserved for health checks, 3 cached, 1 for allocation3.
+        assert_eq!(cache.free_blocks.len(), 5);
+    }
+
+    #[test]
+    fn allocator_pins_session_turns() {
+        let mut cache = RadixAllocator::new(1, 12, None, SESSION_TTL, 0);
+        let session_id = "session".to_string();
+
+        let allocation = cache
+            .allocate_session(6, Some(Arc::new(vec![0, 1, 2, 3])), session_id.clone())
+            .unwrap();
+        assert_eq!(allocation.prefix_len, 0);
+        cache.free_session(
+            allocation.blocks.clone(),
+            allocation.allocation_id,
+            vec![4, 5, 6],
+        );
+
+        // The prefill and the generated tokens with a KV are pinned.
+        let stats = cache.session_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].pinned_tokens, 6);
+        assert_eq!(stats[0].cache_miss_tokens, 4);
+
+        let allocation = cache
+            .allocate_session(
+                8,
+                Some(Arc::new(vec![0, 1, 2, 3, 4, 5, 7])),
+                session_id.clone(),
+            )
+            .unwrap();
+        assert_eq!(allocation.prefix_len, 6);
+        cache.free_session(allocation.blocks.clone(), allocation.allocation_id, vec![8]);
+
+        let stats = cache.session_stats();
+        assert_eq!(stats[0].requests, 2);
+        assert_eq!(stats[0].cache_hit_tokens, 6);
+        assert_eq!(stats[0].cache_miss_tokens, 5);
+        assert_eq!(stats[0].pinned_tokens, 7);
+
+        // The session is unpinned when the blocks are needed.
+        let allocation = cache.allocate(11, Some(Arc::new(vec![9; 11]))).unwrap();
+        assert_eq!(allocation.prefix_len, 0);
+        assert_eq!(cache.session_stats()[0].pinned_tokens, 0);
+    }
+
+    #[test]
+    fn allocator_expires_idle_sessions() {
+        let mut cache = RadixAllocator::new(1, 12, None, Duration::ZERO, 0);
+        let allocation = cache
+            .allocate_session(4, Some(Arc::new(vec![0, 1, 2, 3])), "session".to_string())
+            .unwrap();
+        cache.free_session(allocation.blocks.clone(), allocation.allocation_id, vec![]);
+
+        assert!(cache.session_stats().is_empty());
+    }
+
+    #[test]
+    fn allocator_compaction_releases_unused_prefixes() {
+        let mut cache = RadixAllocator::new(1, 12, None, SESSION_TTL, 0);
+        let allocation = cache.allocate(8, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+        assert_eq!(cache.cached_blocks(), 4);
+
+        // Without a TTL, the prefixes are kept and only the free list is sorted.
+        assert_eq!(cache.compact(None), 0);
+        assert_eq!(cache.cached_blocks(), 4);
+        let mut sorted = cache.free_blocks.clone();
+        sorted.sort();
+        assert_eq!(cache.free_blocks, sorted);
+
+        // A long TTL keeps the recent prefixes.
+        assert_eq!(cache.compact(Some(Duration::from_secs(3600))), 0);
+        assert_eq!(cache.cached_blocks(), 4);
+
+        assert_eq!(cache.compact(Some(Duration::ZERO)), 4);
+        assert_eq!(cache.cached_blocks(), 0);
+        assert_eq!(cache.free_blocks(), 11);
+        let allocation = cache.allocate(8, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
+        assert_eq!(allocation.prefix_len, 0);
+    }
+
+    #[test]
+    fn allocator_swaps_in_preempted_blocks() {
+        let mut cache = RadixAllocator::new(1, 8, None, SESSION_TTL, 8);
+        let allocation = cache.allocate(6, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
+        let blocks = allocation.blocks.clone();
+        // Preempted after generating 4 and 5, the KV of 5 is not computed yet
+        cache.swap_out_preempted(&blocks, allocation.allocation_id, &[4, 5]);
+        cache.free_session(blocks.clone(), allocation.allocation_id, vec![4, 5]);
+        let swaps = cache.take_swaps();
+        assert_eq!(
+            swaps
+                .swap_out
+                .iter()
+                .map(|swap| swap.device_block)
+                .collect::<Vec<_>>(),
+            blocks[..5]
+        );
+        assert!(swaps.swap_in.is_empty());
+
+        // The blocks evicted from the prefix cache are not copied
+        let allocation = cache.allocate(7, Some(Arc::new(vec![9; 7]))).unwrap();
+        assert!(cache.take_swaps().is_empty());
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+
+        // The next round of the request copies them back instead of prefilling them again.
+        let allocation = cache
+            .allocate(7, Some(Arc::new(vec![0, 1, 2, 3, 4, 5])))
+            .unwrap();
+        assert_eq!(allocation.prefix_len, 5);
+        let swaps = cache.take_swaps();
+        assert_eq!(swaps.swap_in.len(), 5);
+        assert_eq!(
+            swaps
+                .swap_in
+                .iter()
+                .map(|swap| swap.device_block)
+                .collect::<Vec<_>>(),
+            allocation.blocks[..4]
+        );
+    }
+
+    #[test]
+    fn trie_evict_prefixes_returns_tokens() {
+        let mut trie = RadixTrie::new(1);
+        trie.insert(&[0, 1, 2], &[0, 1, 2]).unwrap();
+        trie.insert(&[0, 1, 3], &[0, 1, 3]).unwrap();
+
+        assert_eq!(trie.evict_prefixes(1), vec![(vec![0, 1, 2], vec![2])]);
+        assert_eq!(
+            trie.evict_prefixes(3),
+            vec![(vec![0, 1, 3], vec![3]), (vec![0, 1], vec![0, 1])]
+        );
+    }
+
     #[test]
     fn trie_insertions_have_correct_prefix_len() {
         let mut trie = RadixTrie::new(1);
@@ -0,0 +1,894 @@
+/// Load balancing across replicas of the model
+use crate::backend::BackendV3;
+use crate::launcher::Launcher;
+use crate::queue::Entry;
+use crate::supervisor::Replica;
+use crate::{connect_replica, ReplicaConfig};
+use async_trait::async_trait;
+use futures::future::join_all;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use text_generation_router::infer::swap::{ModelSwap, ShardCheck, SwapDrain};
+use text_generation_router::infer::{Backend, InferError, InferStreamResponse};
+use text_generation_router::validation::ValidGenerateRequest;
+use text_generation_router::{
+    BatchRecord, CacheStats, IterationTimings, LoadAdapterRequest, LoraAdapterInfo, QueuedRequest,
+    SessionStats, ShardInfo, SwapModelRequest,
+};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+/// Interval between two checks of the crashed and unhealthy replicas
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Interval between two checks of the requests left on the shards being replaced
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Previous backends of the replicas switched over, and the limits of the requests of the new
+/// ones
+type SwitchedReplicas = (Vec<(Arc<BackendV3>, ReplicaConfig)>, (usize, usize));
+
+/// Independent shard-sets serving the same model.
+///
+/// Every replica has its own queue and batching task, so continuous batching works as with a
+/// single shard-set. New requests go to the least loaded healthy replica. A replica whose
+/// shards go down is reconnected in the background, and its queued requests start over on
+/// the other replicas. Its running requests, that already streamed tokens, fail with an error
+/// their clients can retry.
+pub struct Replicas {
+    replicas: Arc<[Arc<Replica>]>,
+    /// Cancelled to stop reconnecting the replicas, before they are stopped
+    supervision: CancellationToken,
+}
+
+impl Replicas {
+    pub(crate) fn new(replicas: Vec<(BackendV3, ReplicaConfig)>) -> Self {
+        assert!(!replicas.is_empty(), "At least one replica is required");
+        let (handover, handover_receiver) = mpsc::unbounded_channel();
+        let replicas: Arc<[Arc<Replica>]> = replicas
+            .into_iter()
+            .map(|(backend, config)| Arc::new(Replica::new(backend, config, handover.clone())))
+            .collect();
+
+        let supervision = CancellationToken::new();
+        tokio::spawn(supervision_task(replicas.clone(), supervision.clone()));
+        tokio::spawn(handover_task(
+            replicas.clone(),
+            handover_receiver,
+            supervision.clone(),
+        ));
+
+        Self {
+            replicas,
+            supervision,
+        }
+    }
+
+    /// Current backends of the replicas
+    fn backends(&self) -> Vec<Arc<BackendV3>> {
+        self.replicas
+            .iter()
+            .map(|replica| replica.backend())
+            .collect()
+    }
+
+    /// Pick the replica for a new request
+    fn route(&self, request: &ValidGenerateRequest) -> Arc<BackendV3> {
+        // Keep the turns of a session on the replica that caches its KV
+        if let Some(session_id) = &request.session_id {
+            let mut hasher = DefaultHasher::new();
+            session_id.hash(&mut hasher);
+            let replica = self.replicas[hasher.finish() as usize % self.replicas.len()].backend();
+            if replica.is_healthy() {
+                return replica;
+            }
+        }
+
+        self.least_loaded()
+    }
+
+    /// Limits of the requests accepted by the replicas
+    pub(crate) fn token_limits(&self) -> Option<(usize, usize)> {
+        let config = self.replicas[0].config();
+        config.max_input_tokens.zip(config.max_total_tokens)
+    }
+
+    /// Connect every replica to a new shard-set and switch them over once they are all warmed
+    /// up and accepted by `check`. The launcher starts the shard-set of `revision` when no
+    /// `master_shard_uds_paths` are given. The old shard-sets are stopped in the background
+    /// once their running requests are done, or after the drain timeout.
+    pub(crate) async fn swap(
+        &self,
+        request: SwapModelRequest,
+        launcher: Option<&Launcher>,
+        check: ShardCheck,
+    ) -> Result<ModelSwap, InferError> {
+        let SwapModelRequest {
+            revision,
+            master_shard_uds_paths,
+            cuda_visible_devices,
+            drain_timeout_secs,
+            ..
+        } = request;
+        let held: Vec<&Arc<Replica>> = self
+            .replicas
+            .iter()
+            .take_while(|replica| replica.hold())
+            .collect();
+        if held.len() < self.replicas.len() {
+            held.iter().for_each(|replica| replica.release());
+            return Err(InferError::SwapInProgress);
+        }
+        let result = self
+            .start_swap(
+                revision,
+                master_shard_uds_paths,
+                cuda_visible_devices,
+                launcher,
+                &check,
+            )
+            .await;
+        self.replicas.iter().for_each(|replica| replica.release());
+        let (previous, (max_input_tokens, max_total_tokens)) = result?;
+
+        metrics::counter!("tgi_backend_swap").increment(1);
+        let (sender, drain) = watch::channel(SwapDrain {
+            running: previous.iter().map(|(backend, _)| backend.load()).sum(),
+            ..SwapDrain::default()
+        });
+        tokio::spawn(drain_task(
+            previous,
+            launcher.cloned(),
+            Duration::from_secs(drain_timeout_secs),
+            sender,
+        ));
+        Ok(ModelSwap {
+            replicas: self.replicas.len(),
+            max_input_tokens,
+            max_total_tokens,
+            drain,
+        })
+    }
+
+    /// Start the new shard-set with the launcher unless `master_shard_uds_paths` are given,
+    /// and switch the replicas over to them. The shard-set started is stopped again if it
+    /// cannot be used.
+    async fn start_swap(
+        &self,
+        revision: Option<String>,
+        master_shard_uds_paths: Vec<String>,
+        cuda_visible_devices: Option<String>,
+        launcher: Option<&Launcher>,
+        check: &ShardCheck,
+    ) -> Result<SwitchedReplicas, InferError> {
+        if !master_shard_uds_paths.is_empty() {
+            if master_shard_uds_paths.len() != self.replicas.len() {
+                return Err(InferError::SwapError(format!(
+                    "{} shard-set(s) given for {} replica(s)",
+                    master_shard_uds_paths.len(),
+                    self.replicas.len()
+                )));
+            }
+            return self.connect_swap(master_shard_uds_paths, None, check).await;
+        }
+
+        let launcher = launcher.ok_or_else(|| {
+            InferError::SwapError(
+                "the router was not started by the launcher, `master_shard_uds_paths` must be \
+                 given"
+                    .to_string(),
+            )
+        })?;
+        if self.replicas.len() != 1 || self.replicas[0].config().shard_set.is_none() {
+            return Err(InferError::SwapError(
+                "the launcher only starts the shards of the model served by its own shards, \
+                 `master_shard_uds_paths` must be given"
+                    .to_string(),
+            ));
+        }
+        let launched = launcher.spawn(revision, cuda_visible_devices).await?;
+        let result = self
+            .connect_swap(
+                vec![launched.master_shard_uds_path],
+                Some(launched.shard_set),
+                check,
+            )
+            .await;
+        if result.is_err() {
+            if let Err(err) = launcher.stop(launched.shard_set).await {
+                tracing::error!("Unable to stop shard-set {}: {err}", launched.shard_set);
+            }
+        }
+        result
+    }
+
+    /// Warm up the new shard-sets and switch the replicas over to them, returning the previous
+    /// backends and the limits of the requests of the new ones. The replicas are left as they
+    /// are if a shard-set cannot be used.
+    async fn connect_swap(
+        &self,
+        master_shard_uds_paths: Vec<String>,
+        shard_set: Option<usize>,
+        check: &ShardCheck,
+    ) -> Result<SwitchedReplicas, InferError> {
+        let mut configs = Vec::with_capacity(self.replicas.len());
+        for (replica, master_shard_uds_path) in self.replicas.iter().zip(master_shard_uds_paths) {
+            let mut config = replica.config();
+            // The prefill shards hold the same model as the decode shards
+            if config.prefill_shard_uds_path.is_some() {
+                return Err(InferError::SwapError(
+                    "disaggregated replicas cannot be switched over".to_string(),
+                ));
+            }
+            config.master_shard_uds_path = master_shard_uds_path;
+            config.shard_set = shard_set;
+            // Another revision may have another context window
+            config.max_input_tokens = config.requested_max_input_tokens;
+            config.max_total_tokens = config.requested_max_total_tokens;
+            configs.push(config);
+        }
+
+        // As at startup, the other replicas must accept the same requests as the first one
+        let mut backends = Vec::with_capacity(configs.len());
+        let mut limits = None;
+        let mut error = None;
+        for config in configs.iter_mut() {
+            if let Some((max_input_tokens, max_total_tokens)) = limits {
+                config.max_input_tokens = Some(max_input_tokens);
+                config.max_total_tokens = Some(max_total_tokens);
+            }
+            match connect_replica(config).await {
+                Ok((backend, info)) => {
+                    let checked = backend
+                        .shard_info()
+                        .map_or(Ok(()), |shard_info| check(&shard_info));
+                    backends.push(backend);
+                    if let Err(err) = checked {
+                        error = Some(InferError::SwapError(err));
+                        break;
+                    }
+                    limits.get_or_insert((info.max_input_tokens, info.max_total_tokens));
+                }
+                Err(err) => {
+                    error = Some(InferError::SwapError(err.to_string()));
+                    break;
+                }
+            }
+        }
+        // The new shards serve the adapters loaded at runtime as well
+        if error.is_none() {
+            let results = join_all(self.replicas.iter().zip(&backends).map(
+                |(replica, backend)| backend.restore_adapters(replica.backend().runtime_adapters()),
+            ))
+            .await;
+            if let Some(Err(err)) = results.into_iter().find(Result::is_err) {
+                error = Some(InferError::SwapError(format!(
+                    "unable to load the adapters in the new shards: {err}"
+                )));
+            }
+        }
+        let limits = match (error, limits) {
+            (None, Some(limits)) => limits,
+            (error, _) => {
+                join_all(backends.iter().map(|backend| backend.stop())).await;
+                return Err(error
+                    .unwrap_or_else(|| InferError::SwapError("no shard-set given".to_string())));
+            }
+        };
+
+        let previous = self
+            .replicas
+            .iter()
+            .zip(backends.into_iter().zip(configs))
+            .map(|(replica, (backend, mut config))| {
+                config.max_input_tokens = Some(limits.0);
+                config.max_total_tokens = Some(limits.1);
+                replica.switch(backend, config)
+            })
+            .collect();
+        Ok((previous, limits))
+    }
+
+    /// Healthy replica with the fewest requests in flight
+    fn least_loaded(&self) -> Arc<BackendV3> {
+        let backends = self.backends();
+        backends
+            .iter()
+            .filter(|replica| replica.is_healthy())
+            .min_by_key(|replica| replica.load())
+            // No replica is healthy: the least loaded one will surface the error
+            .or_else(|| backends.iter().min_by_key(|replica| replica.load()))
+            .expect("At least one replica is required")
+            .clone()
+    }
+}
+
+#[async_trait]
+impl Backend for Replicas {
+    #[instrument(skip_all)]
+    fn schedule(
+        &self,
+        request: ValidGenerateRequest,
+    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        self.route(&request).schedule(request)
+    }
+
+    fn shares_prompt_kv(&self) -> bool {
+        // Requests with the same prompt are not routed to the same replica
+        self.replicas.len() == 1 && self.replicas[0].backend().shares_prompt_kv()
+    }
+
+    fn supports_token_healing(&self) -> bool {
+        self.replicas[0].backend().supports_token_healing()
+    }
+
+    fn supports_logit_bias(&self) -> bool {
+        self.replicas[0].backend().supports_logit_bias()
+    }
+
+    fn supports_presence_penalty(&self) -> bool {
+        self.replicas[0].backend().supports_presence_penalty()
+    }
+
+    fn supports_min_p(&self) -> bool {
+        self.replicas[0].backend().supports_min_p()
+    }
+
+    fn supports_no_repeat_ngram_size(&self) -> bool {
+        self.replicas[0].backend().supports_no_repeat_ngram_size()
+    }
+
+    fn supports_guidance(&self) -> bool {
+        self.replicas[0].backend().supports_guidance()
+    }
+
+    fn lora_adapters(&self) -> Option<HashMap<String, u32>> {
+        self.replicas[0].backend().lora_adapters()
+    }
+
+    fn supports_watermark_key(&self) -> bool {
+        self.replicas[0].backend().supports_watermark_key()
+    }
+
+    fn supports_stop_token_ids(&self) -> bool {
+        self.replicas[0].backend().supports_stop_token_ids()
+    }
+
+    fn supports_min_new_tokens(&self) -> bool {
+        self.replicas[0].backend().supports_min_new_tokens()
+    }
+
+    fn supports_deadlines(&self) -> bool {
+        self.replicas[0].backend().supports_deadlines()
+    }
+
+    fn shard_info(&self) -> Option<ShardInfo> {
+        self.replicas[0].backend().shard_info()
+    }
+
+    fn loaded_adapters(&self) -> Vec<LoraAdapterInfo> {
+        self.replicas[0].backend().loaded_adapters()
+    }
+
+    async fn load_adapter(
+        &self,
+        request: LoadAdapterRequest,
+    ) -> Result<LoraAdapterInfo, InferError> {
+        // Any replica can serve the requests of the adapter, so they all load it or none does
+        let backends = self.backends();
+        let results = join_all(
+            backends
+                .iter()
+                .map(|replica| replica.load_adapter(request.clone())),
+        )
+        .await;
+        if results.iter().any(Result::is_err) {
+            for (replica, result) in backends.iter().zip(&results) {
+                if result.is_ok() {
+                    let _ = replica.unload_adapter(&request.id).await;
+                }
+            }
+            return results.into_iter().find(Result::is_err).unwrap();
+        }
+        results
+            .into_iter()
+            .next()
+            .expect("At least one replica is required")
+    }
+
+    async fn unload_adapter(&self, id: &str) -> Result<(), InferError> {
+        let backends = self.backends();
+        join_all(backends.iter().map(|replica| replica.unload_adapter(id)))
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    fn supports_embeddings(&self) -> bool {
+        self.replicas[0].backend().supports_embeddings()
+    }
+
+    #[instrument(skip_all)]
+    async fn embed(
+        &self,
+        model: Option<&str>,
+        input_ids: Vec<Vec<u32>>,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        self.least_loaded().embed(model, input_ids).await
+    }
+
+    async fn health(&self, current_health: bool) -> bool {
+        let backends = self.backends();
+        let health = join_all(
+            backends
+                .iter()
+                .map(|replica| replica.health(current_health)),
+        )
+        .await;
+        // The router is healthy as long as one replica can serve requests
+        health.into_iter().any(|healthy| healthy)
+    }
+
+    fn start_health(&self) -> bool {
+        true
+    }
+
+    async fn batch_history(&self) -> Vec<BatchRecord> {
+        let backends = self.backends();
+        let mut history: Vec<BatchRecord> =
+            join_all(backends.iter().map(|replica| replica.batch_history()))
+                .await
+                .into_iter()
+                .flatten()
+                .collect();
+        history.sort_by_key(|batch| batch.timestamp);
+        history
+    }
+
+    fn iteration_timings(&self) -> Vec<IterationTimings> {
+        let mut timings: Vec<IterationTimings> = self
+            .backends()
+            .iter()
+            .flat_map(|replica| replica.iteration_timings())
+            .collect();
+        timings.sort_by_key(|iteration| iteration.timestamp);
+        timings
+    }
+
+    async fn session_stats(&self) -> Vec<SessionStats> {
+        let backends = self.backends();
+        join_all(backends.iter().map(|replica| replica.session_stats()))
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    async fn queued_requests(&self) -> Vec<QueuedRequest> {
+        let backends = self.backends();
+        join_all(backends.iter().map(|replica| replica.queued_requests()))
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    async fn cache_stats(&self) -> Vec<CacheStats> {
+        let backends = self.backends();
+        join_all(backends.iter().map(|replica| replica.cache_stats()))
+            .await
+            .into_iter()
+            .enumerate()
+            .flat_map(|(index, stats)| {
+                stats.into_iter().map(move |stats| CacheStats {
+                    replica: index,
+                    ..stats
+                })
+            })
+            .collect()
+    }
+
+    fn waiting_served_ratio(&self) -> Option<f32> {
+        // All the replicas share the same settings
+        self.replicas[0].backend().waiting_served_ratio()
+    }
+
+    fn set_waiting_served_ratio(&self, waiting_served_ratio: f32) {
+        for replica in self.backends() {
+            replica.set_waiting_served_ratio(waiting_served_ratio);
+        }
+    }
+
+    async fn shutdown(&self) {
+        // A stopped backend would look crashed to the supervision task
+        self.supervision.cancel();
+        let backends = self.backends();
+        join_all(backends.iter().map(|replica| replica.stop())).await;
+    }
+}
+
+/// Let the requests of the backends that no longer receive any finish, for up to `timeout`,
+/// then stop them and the shard-sets the launcher started for them. `progress` reports the
+/// requests left.
+async fn drain_task(
+    previous: Vec<(Arc<BackendV3>, ReplicaConfig)>,
+    launcher: Option<Launcher>,
+    timeout: Duration,
+    progress: watch::Sender<SwapDrain>,
+) {
+    let start = Instant::now();
+    loop {
+        let running = previous.iter().map(|(backend, _)| backend.load()).sum();
+        progress.send_modify(|drain| drain.running = running);
+        if running == 0 || start.elapsed() >= timeout {
+            break;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    let interrupted = join_all(previous.iter().map(|(backend, _)| drain_backend(backend))).await;
+    for (_, config) in &previous {
+        if let (Some(launcher), Some(shard_set)) = (&launcher, config.shard_set) {
+            // The shards of the previous revision would keep their GPU memory
+            if let Err(err) = launcher.stop(shard_set).await {
+                tracing::error!("Unable to stop shard-set {shard_set}: {err}");
+            }
+        }
+    }
+    progress.send_modify(|drain| {
+        drain.running = 0;
+        drain.interrupted = interrupted.into_iter().sum();
+        drain.done = true;
+    });
+}
+
+/// Stop a backend that no longer receives any request, interrupting the requests left.
+/// Returns the requests that were interrupted.
+async fn drain_backend(backend: &BackendV3) -> usize {
+    let interrupted = backend.load();
+    if interrupted > 0 {
+        tracing::warn!("Interrupting {interrupted} request(s) of the old shards");
+        backend.shut_down();
+    }
+    backend.stop().await;
+    interrupted
+}
+
+/// Background task queueing the requests handed over by the replicas whose shards went down
+/// on the other ones
+async fn handover_task(
+    replicas: Arc<[Arc<Replica>]>,
+    mut receiver: mpsc::UnboundedReceiver<Entry>,
+    supervision: CancellationToken,
+) {
+    loop {
+        let entry = tokio::select! {
+            _ = supervision.cancelled() => return,
+            entry = receiver.recv() => match entry {
+                Some(entry) => entry,
+                None => return,
+            },
+        };
+        adopt(&replicas, entry);
+    }
+}
+
+/// Queue a request handed over by a replica on the least loaded replica that can still serve
+/// it, or fail it if none can
+fn adopt(replicas: &[Arc<Replica>], entry: Entry) {
+    if entry.response_tx.is_closed() {
+        return;
+    }
+    let backend = replicas
+        .iter()
+        .map(|replica| replica.backend())
+        .filter(|backend| backend.is_healthy() && !backend.is_crashed())
+        .min_by_key(|backend| backend.load());
+    match backend {
+        Some(backend) => {
+            metrics::counter!("tgi_request_handover").increment(1);
+            backend.adopt(entry);
+        }
+        None => {
+            metrics::counter!("tgi_request_failure", "err" => "backend_unavailable").increment(1);
+            let _ = entry.response_tx.send(Err(InferError::BackendUnavailable(
+                "lost the connection to the shards".to_string(),
+            )));
+        }
+    }
+}
+
+/// Background task reconnecting the replicas whose shards went down, and bringing back the
+/// ones that failed an inference call
+async fn supervision_task(replicas: Arc<[Arc<Replica>]>, supervision: CancellationToken) {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = supervision.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        for (i, replica) in replicas.iter().enumerate() {
+            let backend = replica.backend();
+            if backend.is_crashed() {
+                replica.reconnect(i);
+                continue;
+            }
+            // A single replica is checked by the health route
+            if replicas.len() == 1 || backend.is_healthy() {
+                continue;
+            }
+            if backend.health(false).await {
+                tracing::info!("Replica {i} is healthy again");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::fake_shard::{FakeModel, FakeShard};
+    use crate::queue::tests::default_entry;
+    use crate::SchedulingPolicy;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    const MODEL: FakeModel = FakeModel {
+        vocab_size: 32000,
+        max_input_tokens: 1000,
+        max_total_tokens: 2000,
+    };
+
+    fn config(master_shard_uds_path: &str, shard_set: Option<usize>) -> ReplicaConfig {
+        ReplicaConfig {
+            max_input_tokens: None,
+            max_total_tokens: None,
+            requested_max_input_tokens: None,
+            requested_max_total_tokens: None,
+            master_shard_uds_path: master_shard_uds_path.to_string(),
+            shard_set,
+            prefill_shard_uds_path: None,
+            draft_shard_uds_path: None,
+            waiting_served_ratio: 1.2,
+            target_ttft: None,
+            target_decode_latency: None,
+            max_batch_prefill_tokens: 512,
+            max_batch_total_tokens: None,
+            max_waiting_tokens: 20,
+            max_batch_size: None,
+            session_ttl: Duration::from_secs(60),
+            kv_compaction_interval: None,
+            prefix_cache_ttl: None,
+            max_request_token_share: None,
+            fairness_queue_depth: None,
+            tenant_weights: None,
+            scheduling_policy: SchedulingPolicy::default(),
+            short_prompts: None,
+            prompt_lookup_max_ngram: None,
+            preemption_queue_size: None,
+            warmup_retries: 0,
+            warmup_shapes: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Replicas of the model served by `shards`, connected as at startup
+    async fn replicas(shards: &[&FakeShard], shard_set: Option<usize>) -> Replicas {
+        let mut replicas = Vec::new();
+        for shard in shards {
+            let mut config = config(&shard.uds_path, shard_set);
+            let (backend, info) = connect_replica(&config).await.unwrap();
+            config.max_input_tokens = Some(info.max_input_tokens);
+            config.max_total_tokens = Some(info.max_total_tokens);
+            replicas.push((backend, config));
+        }
+        Replicas::new(replicas)
+    }
+
+    fn swap_request(master_shard_uds_paths: Vec<String>) -> SwapModelRequest {
+        SwapModelRequest {
+            model: None,
+            revision: None,
+            master_shard_uds_paths,
+            cuda_visible_devices: None,
+            drain_timeout_secs: 10,
+        }
+    }
+
+    fn same_vocabulary(vocab_size: u32) -> ShardCheck {
+        Box::new(move |shard_info: &ShardInfo| {
+            (shard_info.vocab_size == vocab_size)
+                .then_some(())
+                .ok_or_else(|| "other vocabulary".to_string())
+        })
+    }
+
+    async fn drained(mut drain: watch::Receiver<SwapDrain>) -> SwapDrain {
+        let drain = drain.wait_for(|drain| drain.done).await.unwrap();
+        *drain
+    }
+
+    #[tokio::test]
+    async fn test_connect_swap() {
+        let old = FakeShard::start("swap-old", MODEL);
+        // The new revision has a longer context
+        let new = FakeShard::start(
+            "swap-new",
+            FakeModel {
+                max_input_tokens: 3000,
+                max_total_tokens: 4000,
+                ..MODEL
+            },
+        );
+        let replicas = replicas(&[&old], None).await;
+        assert_eq!(replicas.token_limits(), Some((1000, 2000)));
+
+        let swap = replicas
+            .swap(
+                swap_request(vec![new.uds_path.clone()]),
+                None,
+                same_vocabulary(32000),
+            )
+            .await
+            .unwrap();
+        assert_eq!(swap.replicas, 1);
+        // The limits are derived again from the new shards
+        assert_eq!((swap.max_input_tokens, swap.max_total_tokens), (3000, 4000));
+        assert_eq!(replicas.token_limits(), Some((3000, 4000)));
+        let config = replicas.replicas[0].config();
+        assert_eq!(config.master_shard_uds_path, new.uds_path);
+        assert_eq!(new.warmups(), 1);
+
+        let drain = drained(swap.drain).await;
+        assert_eq!(drain.interrupted, 0);
+        // The replica can be swapped again
+        assert!(replicas.replicas[0].hold());
+        replicas.replicas[0].release();
+        replicas.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_swap_refused() {
+        let old = FakeShard::start("refused-old", MODEL);
+        let other = FakeShard::start(
+            "refused-other",
+            FakeModel {
+                vocab_size: 128256,
+                ..MODEL
+            },
+        );
+        let replicas = replicas(&[&old], None).await;
+
+        // One shard-set per replica
+        let result = replicas
+            .swap(
+                swap_request(vec![other.uds_path.clone(), other.uds_path.clone()]),
+                None,
+                same_vocabulary(32000),
+            )
+            .await;
+        assert!(matches!(result, Err(InferError::SwapError(_))));
+        assert_eq!(other.warmups(), 0);
+
+        // The new shards are warmed up then refused by the check
+        let result = replicas
+            .swap(
+                swap_request(vec![other.uds_path.clone()]),
+                None,
+                same_vocabulary(32000),
+            )
+            .await;
+        assert!(
+            matches!(result, Err(InferError::SwapError(message)) if message == "other vocabulary")
+        );
+        assert_eq!(other.warmups(), 1);
+        let config = replicas.replicas[0].config();
+        assert_eq!(config.master_shard_uds_path, old.uds_path);
+        assert_eq!(replicas.token_limits(), Some((1000, 2000)));
+
+        // Without the launcher, the shards must be given
+        let result = replicas
+            .swap(swap_request(Vec::new()), None, same_vocabulary(32000))
+            .await;
+        assert!(matches!(result, Err(InferError::SwapError(_))));
+        replicas.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_swap_with_launcher() {
+        let old = FakeShard::start("launcher-old", MODEL);
+        let new = FakeShard::start("launcher-new", MODEL);
+        let uds_path =
+            std::env::temp_dir().join(format!("tgi-swap-launcher-{}", std::process::id()));
+        let _ = std::fs::remove_file(&uds_path);
+        let listener = UnixListener::bind(&uds_path).unwrap();
+        let answers = [
+            format!(
+                r#"{{"status": "spawned", "shard_set": 1, "master_shard_uds_path": "{}"}}"#,
+                new.uds_path
+            ),
+            r#"{"status": "stopped", "shard_set": 0}"#.to_string(),
+        ];
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for answer in answers {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                requests.push(serde_json::from_str::<serde_json::Value>(&line).unwrap());
+                stream
+                    .get_mut()
+                    .write_all(format!("{answer}\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+            requests
+        });
+        let launcher = Launcher::new(uds_path.to_string_lossy().into_owned());
+
+        // The launcher started the first shard-set
+        let replicas = replicas(&[&old], Some(0)).await;
+        let request = SwapModelRequest {
+            revision: Some("v2".to_string()),
+            ..swap_request(Vec::new())
+        };
+        let swap = replicas
+            .swap(request, Some(&launcher), same_vocabulary(32000))
+            .await
+            .unwrap();
+        assert_eq!(replicas.replicas[0].config().shard_set, Some(1));
+        drained(swap.drain).await;
+
+        // The old shard-set is stopped once drained, freeing its GPU memory
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                serde_json::json!({"command": "spawn", "revision": "v2", "cuda_visible_devices": null}),
+                serde_json::json!({"command": "stop", "shard_set": 0}),
+            ]
+        );
+        replicas.shutdown().await;
+        let _ = std::fs::remove_file(&uds_path);
+    }
+
+    #[tokio::test]
+    async fn test_adopt() {
+        let crashed = FakeShard::start("adopt-crashed", MODEL);
+        let healthy = FakeShard::start("adopt-healthy", MODEL);
+        let replicas = replicas(&[&crashed, &healthy], None).await;
+        replicas.replicas[0].backend().shut_down();
+
+        // The request reaches the shards of the other replica, that cannot prefill it
+        let (entry, mut response_rx) = default_entry();
+        adopt(&replicas.replicas, entry);
+        assert!(matches!(
+            response_rx.recv().await,
+            Some(Err(InferError::GenerationError(_)))
+        ));
+
+        // No replica is left to serve it
+        replicas.replicas[1].backend().shut_down();
+        let (entry, mut response_rx) = default_entry();
+        adopt(&replicas.replicas, entry);
+        assert!(matches!(
+            response_rx.recv().await,
+            Some(Err(InferError::BackendUnavailable(_)))
+        ));
+        replicas.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_drain_backend() {
+        let shard = FakeShard::start("drain", MODEL);
+        let (backend, _) = connect_replica(&config(&shard.uds_path, None))
+            .await
+            .unwrap();
+        // Nothing runs on the backend, it is stopped right away
+        assert_eq!(drain_backend(&backend).await, 0);
+        assert_eq!(backend.load(), 0);
+    }
+}
@@ -0,0 +1,310 @@
+/// Adjustment of the scheduler to a latency objective
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of recent times to first token the percentile is computed on
+const WINDOW: usize = 128;
+/// Number of new times to first token between two adjustments of the ratio
+const ADJUSTMENT_INTERVAL: usize = 16;
+/// Percentile of the times to first token compared to the target
+const PERCENTILE: f64 = 0.95;
+/// Factor applied to the ratio when the target is missed
+const DECREASE: f32 = 0.8;
+/// Factor applied to the ratio when the percentile is well below the target
+const INCREASE: f32 = 1.1;
+/// Step added on top of `INCREASE`, so that the ratio can grow back from 0
+const INCREASE_STEP: f32 = 0.01;
+/// Fraction of the target below which decoding is favored again
+const HEADROOM: f64 = 0.8;
+
+/// Controller of the `waiting_served_ratio` of a backend, driven by the p95 time to first token.
+///
+/// A lower ratio interrupts the running batch for smaller batches of waiting requests, which
+/// lowers the time to first token at the expense of the decoding speed. The ratio is lowered
+/// while the target is missed, and raised back, up to the configured `waiting_served_ratio`,
+/// while the p95 is comfortably below the target. A ratio set through the runtime config
+/// becomes the new maximum.
+#[derive(Debug)]
+pub(crate) struct TtftController {
+    target: Duration,
+    /// Bits of the ratio used by the batching task
+    waiting_served_ratio: Arc<AtomicU32>,
+    /// Last ratio set by the controller
+    ratio: f32,
+    max_ratio: f32,
+    ttfts: VecDeque<Duration>,
+    /// Times to first token recorded since the last adjustment
+    new_ttfts: usize,
+}
+
+impl TtftController {
+    /// The ratio starts from, and never goes above, its current value
+    pub(crate) fn new(target: Duration, waiting_served_ratio: Arc<AtomicU32>) -> Self {
+        let ratio = f32::from_bits(waiting_served_ratio.load(Ordering::Relaxed));
+        Self {
+            target,
+            waiting_served_ratio,
+            ratio,
+            max_ratio: ratio,
+            ttfts: VecDeque::with_capacity(WINDOW),
+            new_ttfts: 0,
+        }
+    }
+
+    /// Record the times to first token of the requests of a prefill
+    pub(crate) fn record(&mut self, ttfts: impl IntoIterator<Item = Duration>) {
+        let ratio = f32::from_bits(self.waiting_served_ratio.load(Ordering::Relaxed));
+        if ratio != self.ratio {
+            // Changed through the runtime config
+            self.max_ratio = ratio;
+        }
+        self.ratio = self.adjust(ratio, ttfts);
+        if self.ratio != ratio {
+            tracing::debug!("waiting_served_ratio adjusted to {}", self.ratio);
+            self.waiting_served_ratio
+                .store(self.ratio.to_bits(), Ordering::Relaxed);
+            metrics::gauge!("tgi_batch_waiting_served_ratio").set(self.ratio as f64);
+        }
+    }
+
+    /// Ratio to use from now on, given the new times to first token
+    fn adjust(&mut self, ratio: f32, ttfts: impl IntoIterator<Item = Duration>) -> f32 {
+        for ttft in ttfts {
+            if self.ttfts.len() == WINDOW {
+                self.ttfts.pop_front();
+            }
+            self.ttfts.push_back(ttft);
+            self.new_ttfts += 1;
+        }
+        if self.new_ttfts < ADJUSTMENT_INTERVAL {
+            return ratio;
+        }
+        self.new_ttfts = 0;
+
+        let p95 = self.percentile();
+        let ratio = if p95 > self.target {
+            ratio * DECREASE
+        } else if p95.as_secs_f64() < self.target.as_secs_f64() * HEADROOM {
+            ratio * INCREASE + INCREASE_STEP
+        } else {
+            ratio
+        };
+        ratio.clamp(0.0, self.max_ratio)
+    }
+
+    fn percentile(&self) -> Duration {
+        percentile(&self.ttfts)
+    }
+}
+
+/// Controller of the largest batch the batching task decodes, driven by the p95 latency of the
+/// decode steps.
+///
+/// Every request of a batch gets one token per step, so a larger batch has a higher
+/// throughput but a slower time per output token. The cap is lowered while the target is
+/// missed, and raised back while the p95 is comfortably below the target and the batches fill
+/// the cap. It is lifted once it reaches `max_batch_size`.
+#[derive(Debug)]
+pub(crate) struct DecodeLatencyController {
+    target: Duration,
+    max_batch_size: Option<usize>,
+    /// Largest batch size, None while the target is met without one
+    cap: Option<usize>,
+    latencies: VecDeque<Duration>,
+    /// Decode steps recorded since the last adjustment
+    new_latencies: usize,
+    /// Largest batch decoded since the last adjustment
+    largest_batch: usize,
+}
+
+impl DecodeLatencyController {
+    pub(crate) fn new(target: Duration, max_batch_size: Option<usize>) -> Self {
+        let controller = Self {
+            target,
+            max_batch_size,
+            cap: None,
+            latencies: VecDeque::with_capacity(WINDOW),
+            new_latencies: 0,
+            largest_batch: 0,
+        };
+        // Reported from the start, not only once the cap first changes
+        controller.set_gauge();
+        controller
+    }
+
+    /// Largest batch size that keeps the decode steps under the target
+    pub(crate) fn cap(&self) -> Option<usize> {
+        self.cap
+    }
+
+    /// Record the latency of a decode step of `batch_size` requests
+    pub(crate) fn record(&mut self, latency: Duration, batch_size: usize) {
+        let cap = self.adjust(latency, batch_size);
+        if cap != self.cap {
+            tracing::debug!("Decode batch size cap adjusted to {cap:?}");
+            self.cap = cap;
+            self.set_gauge();
+        }
+    }
+
+    fn set_gauge(&self) {
+        let gauge = self.cap.or(self.max_batch_size).unwrap_or(0);
+        metrics::gauge!("tgi_batch_decode_size_cap").set(gauge as f64);
+    }
+
+    /// Cap to use from now on, given the new decode step
+    fn adjust(&mut self, latency: Duration, batch_size: usize) -> Option<usize> {
+        if self.latencies.len() == WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+        self.largest_batch = self.largest_batch.max(batch_size);
+        self.new_latencies += 1;
+        if self.new_latencies < ADJUSTMENT_INTERVAL {
+            return self.cap;
+        }
+        self.new_latencies = 0;
+        let largest_batch = std::mem::take(&mut self.largest_batch);
+
+        let p95 = percentile(&self.latencies);
+        if p95 > self.target {
+            let cap = self.cap.unwrap_or(largest_batch).min(largest_batch);
+            Some(((cap as f32 * DECREASE) as usize).max(1))
+        } else if p95.as_secs_f64() < self.target.as_secs_f64() * HEADROOM {
+            // Smaller batches than the cap say nothing of the latency of a larger one
+            self.cap
+                .map(|cap| match largest_batch >= cap {
+                    true => cap + (cap / 10).max(1),
+                    false => cap,
+                })
+                .filter(|&cap| self.max_batch_size.map_or(true, |max| cap < max))
+        } else {
+            self.cap
+        }
+    }
+}
+
+fn percentile(durations: &VecDeque<Duration>) -> Duration {
+    let mut durations: Vec<Duration> = durations.iter().copied().collect();
+    durations.sort_unstable();
+    let index = ((durations.len() as f64 * PERCENTILE).ceil() as usize).saturating_sub(1);
+    durations[index]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn new_controller(target: Duration, ratio: f32) -> TtftController {
+        TtftController::new(target, Arc::new(AtomicU32::new(ratio.to_bits())))
+    }
+
+    #[test]
+    fn test_adjusts_every_interval() {
+        let mut controller = new_controller(ms(500), 1.2);
+        let ratio = controller.adjust(1.2, vec![ms(1000); ADJUSTMENT_INTERVAL - 1]);
+        assert_eq!(ratio, 1.2);
+        let ratio = controller.adjust(ratio, [ms(1000)]);
+        assert_eq!(ratio, 1.2 * DECREASE);
+    }
+
+    #[test]
+    fn test_missed_target_lowers_ratio() {
+        let mut controller = new_controller(ms(500), 1.2);
+        let mut ratio = 1.2;
+        for _ in 0..20 {
+            ratio = controller.adjust(ratio, vec![ms(2000); ADJUSTMENT_INTERVAL]);
+        }
+        assert!(ratio < 0.05);
+        assert!(ratio >= 0.0);
+    }
+
+    #[test]
+    fn test_headroom_raises_ratio_up_to_max() {
+        let mut controller = new_controller(ms(500), 1.2);
+        let mut ratio = 0.0;
+        ratio = controller.adjust(ratio, vec![ms(100); ADJUSTMENT_INTERVAL]);
+        assert_eq!(ratio, INCREASE_STEP);
+        for _ in 0..100 {
+            ratio = controller.adjust(ratio, vec![ms(100); ADJUSTMENT_INTERVAL]);
+        }
+        assert_eq!(ratio, 1.2);
+
+        // Close to the target: the ratio is kept
+        let mut controller = new_controller(ms(500), 1.2);
+        let ratio = controller.adjust(0.5, vec![ms(450); ADJUSTMENT_INTERVAL]);
+        assert_eq!(ratio, 0.5);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let mut controller = new_controller(ms(500), 1.2);
+        // 95 fast prefills and 5 slow ones: the p95 is still fast
+        let ttfts: Vec<Duration> = (0..100)
+            .map(|i| ms(if i < 95 { 100 } else { 5000 }))
+            .collect();
+        controller.adjust(1.0, ttfts);
+        assert_eq!(controller.percentile(), ms(100));
+        controller.adjust(1.0, [ms(5000)]);
+        assert_eq!(controller.percentile(), ms(5000));
+    }
+
+    #[test]
+    fn test_decode_latency_cap() {
+        let mut controller = DecodeLatencyController::new(ms(50), Some(64));
+        let mut cap = None;
+        for _ in 0..ADJUSTMENT_INTERVAL {
+            cap = controller.adjust(ms(100), 40);
+        }
+        assert_eq!(cap, Some(32));
+        controller.cap = cap;
+
+        // Missed again with the smaller batches
+        for _ in 0..ADJUSTMENT_INTERVAL {
+            cap = controller.adjust(ms(100), 32);
+        }
+        assert_eq!(cap, Some(25));
+        controller.cap = cap;
+
+        // Fast steps of batches below the cap keep it
+        controller.latencies.clear();
+        for _ in 0..ADJUSTMENT_INTERVAL {
+            cap = controller.adjust(ms(10), 10);
+        }
+        assert_eq!(cap, Some(25));
+
+        // The cap grows back while the batches fill it, and is lifted at `max_batch_size`
+        for _ in 0..ADJUSTMENT_INTERVAL {
+            cap = controller.adjust(ms(10), 25);
+        }
+        assert_eq!(cap, Some(27));
+        controller.cap = Some(60);
+        for _ in 0..ADJUSTMENT_INTERVAL {
+            cap = controller.adjust(ms(10), 60);
+        }
+        assert_eq!(cap, None);
+    }
+
+    #[test]
+    fn test_record() {
+        let waiting_served_ratio = Arc::new(AtomicU32::new(1.0f32.to_bits()));
+        let mut controller = TtftController::new(ms(500), waiting_served_ratio.clone());
+        controller.record(vec![ms(1000); ADJUSTMENT_INTERVAL]);
+        let ratio = f32::from_bits(waiting_served_ratio.load(Ordering::Relaxed));
+        assert_eq!(ratio, DECREASE);
+
+        // A ratio set through the runtime config caps the next ones
+        waiting_served_ratio.store(0.5f32.to_bits(), Ordering::Relaxed);
+        for _ in 0..50 {
+            controller.record(vec![ms(100); ADJUSTMENT_INTERVAL]);
+        }
+        let ratio = f32::from_bits(waiting_served_ratio.load(Ordering::Relaxed));
+        assert_eq!(ratio, 0.5);
+    }
+}
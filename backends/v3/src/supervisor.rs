@@ -0,0 +1,149 @@
+/// Reconnection of the replicas whose shards went down
+use crate::backend::BackendV3;
+use crate::client::ClientError;
+use crate::queue::Entry;
+use crate::{connect_replica, ReplicaConfig};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use text_generation_router::infer::Backend;
+use tokio::sync::mpsc;
+
+/// Delay between two connection attempts to shards that are still down
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shard-set whose backend is replaced when the shards die and come back, e.g. after being
+/// restarted by their orchestrator
+pub(crate) struct Replica {
+    backend: RwLock<Arc<BackendV3>>,
+    /// Parameters of the current connection, with the limits of the first one
+    config: RwLock<ReplicaConfig>,
+    /// Whether a reconnection or a switch over to another shard-set is running
+    busy: AtomicBool,
+    /// Receives the queued requests of the backends whose shards went down
+    handover: mpsc::UnboundedSender<Entry>,
+}
+
+impl Replica {
+    pub(crate) fn new(
+        backend: BackendV3,
+        config: ReplicaConfig,
+        handover: mpsc::UnboundedSender<Entry>,
+    ) -> Self {
+        backend.set_handover(handover.clone());
+        Self {
+            backend: RwLock::new(Arc::new(backend)),
+            config: RwLock::new(config),
+            busy: AtomicBool::new(false),
+            handover,
+        }
+    }
+
+    /// Current backend of the replica
+    pub(crate) fn backend(&self) -> Arc<BackendV3> {
+        self.backend.read().unwrap().clone()
+    }
+
+    pub(crate) fn config(&self) -> ReplicaConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Tear down the backend of the replica and connect a new one once the shards are back
+    pub(crate) fn reconnect(self: &Arc<Self>, index: usize) {
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tokio::spawn(reconnect_task(self.clone(), index));
+    }
+
+    /// Keep the replica from reconnecting until `release`, so that it can be switched over
+    /// to another shard-set. Returns false if it is already reconnecting or switching.
+    pub(crate) fn hold(&self) -> bool {
+        !self.busy.swap(true, Ordering::SeqCst)
+    }
+
+    pub(crate) fn release(&self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+
+    /// Send the new requests to `backend`, connected with `config`, and return the previous
+    /// backend with its config
+    pub(crate) fn switch(
+        &self,
+        backend: BackendV3,
+        config: ReplicaConfig,
+    ) -> (Arc<BackendV3>, ReplicaConfig) {
+        let previous = self.backend();
+        if let Some(waiting_served_ratio) = previous.waiting_served_ratio() {
+            backend.set_waiting_served_ratio(waiting_served_ratio);
+        }
+        backend.set_handover(self.handover.clone());
+        let config = std::mem::replace(&mut *self.config.write().unwrap(), config);
+        let backend = std::mem::replace(&mut *self.backend.write().unwrap(), Arc::new(backend));
+        (backend, config)
+    }
+}
+
+async fn reconnect_task(replica: Arc<Replica>, index: usize) {
+    let crashed = replica.backend();
+    crashed.shut_down();
+    tracing::error!("The shards of replica {index} went down, reconnecting");
+
+    let config = replica.config();
+    let adapters = crashed.runtime_adapters();
+    loop {
+        // Warms up the shards again, as they lost their KV cache
+        match connect_replica(&config).await {
+            Ok((backend, _)) => {
+                // The requests of the adapters that do not load again fail on this replica
+                if let Err(err) = backend.restore_adapters(adapters.clone()).await {
+                    tracing::error!("Unable to load the adapters of replica {index} again: {err}");
+                }
+                replica.switch(backend, config);
+                metrics::counter!("tgi_backend_reconnect").increment(1);
+                tracing::info!("Replica {index} reconnected");
+                break;
+            }
+            Err(err) => {
+                tracing::warn!("Unable to reconnect replica {index}: {err}");
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+            }
+        }
+    }
+    replica.release();
+}
+
+/// Whether an inference call failed because the shards are gone, rather than because of the
+/// batch
+pub(crate) fn is_shard_down(err: &ClientError) -> bool {
+    match err {
+        ClientError::Connection(_) => true,
+        // Calls cut by a dying shard end with a transport error of the channel
+        ClientError::Generation(message) => {
+            let message = message.to_lowercase();
+            ["transport error", "broken pipe", "connection reset"]
+                .iter()
+                .any(|pattern| message.contains(pattern))
+        }
+        ClientError::EmptyResults => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_shard_down() {
+        assert!(is_shard_down(&ClientError::Connection(
+            "error trying to connect: No such file or directory".to_string()
+        )));
+        assert!(is_shard_down(&ClientError::Generation(
+            "transport error".to_string()
+        )));
+        assert!(!is_shard_down(&ClientError::Generation(
+            "CUDA out of memory. Tried to allocate 2.00 GiB".to_string()
+        )));
+        assert!(!is_shard_down(&ClientError::EmptyResults));
+    }
+}
@@ -0,0 +1,251 @@
+/// Host memory tier of the KV cache, holding the blocks of the preempted requests
+use crate::client::{BlockSwap, ShardedClient};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Shards whose preempted KV cache blocks are swapped to their host memory
+#[derive(Debug, Clone)]
+pub(crate) struct SwapSpace {
+    pub(crate) client: ShardedClient,
+    /// Number of blocks that fit in the host memory of the shards
+    pub(crate) blocks: u32,
+}
+
+/// Copies to run on the shards before the blocks of an allocation are used
+#[derive(Debug, Default)]
+pub struct Swaps {
+    /// Device blocks of the preempted allocations to copy to the host memory
+    pub swap_out: Vec<BlockSwap>,
+    /// Host blocks to copy back to the device blocks of the allocation
+    pub swap_in: Vec<BlockSwap>,
+}
+
+impl Swaps {
+    pub fn is_empty(&self) -> bool {
+        self.swap_out.is_empty() && self.swap_in.is_empty()
+    }
+}
+
+/// Hashes of every aligned prefix of `tokens`, one per block. The hash of a block covers all
+/// the tokens before it, as the KV of a block depends on them.
+pub(crate) fn block_hashes(tokens: &[u32], block_size: usize) -> Vec<u64> {
+    let mut hashes = Vec::with_capacity(tokens.len() / block_size);
+    let mut previous = 0;
+    for block in tokens.chunks_exact(block_size) {
+        let mut hasher = std::hash::DefaultHasher::new();
+        previous.hash(&mut hasher);
+        block.hash(&mut hasher);
+        previous = hasher.finish();
+        hashes.push(previous);
+    }
+    hashes
+}
+
+#[derive(Debug)]
+struct HostBlock {
+    block: u32,
+    last_accessed: u64,
+}
+
+/// Blocks of the preempted requests that are kept in the host memory of the shards.
+///
+/// Blocks are identified by the hash of the tokens up to their end, so the next round of a
+/// preempted request, whose prompt ends with its generated tokens, can swap them back in
+/// instead of computing their KV again. The least recently
+/// used blocks are overwritten when the host memory is full.
+#[derive(Debug)]
+pub struct HostCache {
+    block_size: usize,
+    free_blocks: Vec<u32>,
+    blocks: HashMap<u64, HostBlock>,
+    /// Cached blocks ordered by increasing recency
+    lru: BTreeSet<(u64, u64)>,
+    /// Number of allocations so far, used as access time
+    time: u64,
+}
+
+impl HostCache {
+    pub fn new(block_size: u32, n_blocks: u32) -> Self {
+        Self {
+            block_size: block_size as usize,
+            free_blocks: (0..n_blocks).rev().collect(),
+            blocks: HashMap::new(),
+            lru: BTreeSet::new(),
+            time: 0,
+        }
+    }
+
+    /// Number of blocks in the host memory
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Start a new allocation. The blocks used by an allocation are not overwritten until the
+    /// next one starts, as their copies run together on the shards.
+    pub fn tick(&mut self) {
+        self.time += 1;
+    }
+
+    /// Keep the freed `blocks`, which hold the KV of the last tokens of `tokens`.
+    ///
+    /// Returns the copies to run on the shards. Blocks that are already in the host memory are
+    /// not copied again.
+    pub fn swap_out(&mut self, tokens: &[u32], blocks: &[u32]) -> Vec<BlockSwap> {
+        let hashes = block_hashes(tokens, self.block_size);
+        let hashes = &hashes[hashes.len().saturating_sub(blocks.len())..];
+
+        let mut swaps = Vec::new();
+        for (&hash, &device_block) in hashes.iter().zip(blocks) {
+            if self.touch(hash) {
+                continue;
+            }
+            let Some(host_block) = self.free_blocks.pop().or_else(|| self.evict()) else {
+                break;
+            };
+            self.blocks.insert(
+                hash,
+                HostBlock {
+                    block: host_block,
+                    last_accessed: self.time,
+                },
+            );
+            self.lru.insert((self.time, hash));
+            swaps.push(BlockSwap {
+                device_block,
+                host_block,
+            });
+        }
+        swaps
+    }
+
+    /// Host blocks holding the KV of `tokens`, starting at block `from_block` and stopping at
+    /// the first block that is not in the host memory.
+    pub fn find(&mut self, tokens: &[u32], from_block: usize) -> Vec<u32> {
+        let hashes = block_hashes(tokens, self.block_size);
+        let mut found = Vec::new();
+        for &hash in hashes.iter().skip(from_block) {
+            if !self.touch(hash) {
+                break;
+            }
+            found.push(self.blocks[&hash].block);
+        }
+        found
+    }
+
+    /// Drop host blocks whose content could not be copied
+    pub fn forget(&mut self, host_blocks: &[u32]) {
+        let forgotten: Vec<(u64, u64)> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| host_blocks.contains(&block.block))
+            .map(|(&hash, block)| (block.last_accessed, hash))
+            .collect();
+        for (last_accessed, hash) in forgotten {
+            self.lru.remove(&(last_accessed, hash));
+            let block = self.blocks.remove(&hash).expect("Unknown host block");
+            self.free_blocks.push(block.block);
+        }
+    }
+
+    /// Mark a block as used. Returns `false` if the block is not in the host memory.
+    fn touch(&mut self, hash: u64) -> bool {
+        let Some(block) = self.blocks.get_mut(&hash) else {
+            return false;
+        };
+        self.lru.remove(&(block.last_accessed, hash));
+        block.last_accessed = self.time;
+        self.lru.insert((self.time, hash));
+        true
+    }
+
+    /// Free the least recently used block, unless it was used by the current allocation
+    fn evict(&mut self) -> Option<u32> {
+        let &(last_accessed, hash) = self.lru.first()?;
+        if last_accessed == self.time {
+            return None;
+        }
+        self.lru.pop_first();
+        self.blocks.remove(&hash).map(|block| block.block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_hashes_depend_on_prefix() {
+        let a = block_hashes(&[1, 2, 3, 4], 2);
+        let b = block_hashes(&[5, 6, 3, 4], 2);
+        assert_eq!(a.len(), 2);
+        assert_ne!(a[1], b[1]);
+        assert_eq!(block_hashes(&[1, 2, 3], 2), a[..1].to_vec());
+    }
+
+    #[test]
+    fn test_host_cache_swap_out_and_find() {
+        let mut cache = HostCache::new(2, 4);
+        let swaps = cache.swap_out(&[1, 2, 3, 4, 5, 6], &[8, 9]);
+        assert_eq!(
+            swaps
+                .iter()
+                .map(|swap| swap.device_block)
+                .collect::<Vec<_>>(),
+            vec![8, 9]
+        );
+        assert_eq!(cache.len(), 2);
+
+        // The first block was not swapped out
+        assert!(cache.find(&[1, 2, 3, 4, 5, 6], 0).is_empty());
+        let found = cache.find(&[1, 2, 3, 4, 5, 6, 7], 1);
+        assert_eq!(
+            found,
+            swaps.iter().map(|swap| swap.host_block).collect::<Vec<_>>()
+        );
+
+        // Blocks already in the host memory are not copied again
+        assert!(cache.swap_out(&[1, 2, 3, 4], &[10]).is_empty());
+    }
+
+    #[test]
+    fn test_host_cache_evicts_least_recently_used() {
+        let mut cache = HostCache::new(1, 2);
+        cache.tick();
+        cache.swap_out(&[1], &[1]);
+        cache.tick();
+        cache.swap_out(&[2], &[2]);
+        cache.tick();
+        cache.find(&[1], 0);
+
+        cache.tick();
+        let swaps = cache.swap_out(&[3], &[3]);
+        assert_eq!(swaps.len(), 1);
+        assert_eq!(cache.find(&[2], 0), Vec::<u32>::new());
+        assert_eq!(cache.find(&[1], 0).len(), 1);
+        assert_eq!(cache.find(&[3], 0).len(), 1);
+    }
+
+    #[test]
+    fn test_host_cache_keeps_blocks_of_current_allocation() {
+        let mut cache = HostCache::new(1, 2);
+        cache.tick();
+        assert_eq!(cache.swap_out(&[1, 2], &[1, 2]).len(), 2);
+        assert!(cache.swap_out(&[3], &[3]).is_empty());
+
+        cache.tick();
+        assert_eq!(cache.swap_out(&[3], &[3]).len(), 1);
+    }
+
+    #[test]
+    fn test_host_cache_forget() {
+        let mut cache = HostCache::new(1, 1);
+        let swaps = cache.swap_out(&[1], &[1]);
+        cache.forget(&[swaps[0].host_block]);
+        assert!(cache.is_empty());
+        assert_eq!(cache.swap_out(&[2], &[2]).len(), 1);
+    }
+}
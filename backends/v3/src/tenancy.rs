@@ -0,0 +1,84 @@
+/// Weighted fair sharing of the queue between tenants
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Past this number of tracked tenants, the tenants without queued entries are forgotten
+const MAX_TENANTS: usize = 10_000;
+
+/// Orders the entries of the tenants by start-time fair queuing.
+///
+/// Every queued entry gets a start tag: the finish tag of the previous entry of its tenant, or
+/// the virtual time if the tenant had caught up. An entry costs `1 / weight` of virtual time, so
+/// within a priority the requests of backlogged tenants are dequeued in proportion to their
+/// weights and a tenant sending many requests only delays its own.
+#[derive(Debug)]
+pub(crate) struct FairShare {
+    /// Weight of the tenants, 1.0 for the ones not listed
+    weights: Arc<HashMap<String, f64>>,
+    /// Highest start tag of the entries added to a batch
+    virtual_time: f64,
+    /// Finish tag of the last queued entry of every tenant
+    finish_tags: HashMap<String, f64>,
+}
+
+impl FairShare {
+    pub(crate) fn new(weights: Arc<HashMap<String, f64>>) -> Self {
+        Self {
+            weights,
+            virtual_time: 0.0,
+            finish_tags: HashMap::new(),
+        }
+    }
+
+    /// Start tag of a new entry of `tenant`. Requests without a tenant share one.
+    pub(crate) fn tag(&mut self, tenant: Option<&str>) -> f64 {
+        let tenant = tenant.unwrap_or_default();
+        let weight = self.weights.get(tenant).copied().unwrap_or(1.0);
+
+        if self.finish_tags.len() >= MAX_TENANTS && !self.finish_tags.contains_key(tenant) {
+            let virtual_time = self.virtual_time;
+            self.finish_tags.retain(|_, finish| *finish > virtual_time);
+        }
+        let finish = self
+            .finish_tags
+            .entry(tenant.to_string())
+            .or_insert(self.virtual_time);
+        let start = finish.max(self.virtual_time);
+        *finish = start + 1.0 / weight;
+        start
+    }
+
+    /// Advance the virtual time when an entry is added to a batch
+    pub(crate) fn start(&mut self, tag: f64) {
+        self.virtual_time = self.virtual_time.max(tag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlogged_tenant_does_not_delay_others() {
+        let mut fair_share = FairShare::new(Arc::default());
+        let noisy: Vec<f64> = (0..4).map(|_| fair_share.tag(Some("noisy"))).collect();
+        assert_eq!(noisy, vec![0.0, 1.0, 2.0, 3.0]);
+        // Goes right after the first request of the noisy tenant
+        assert_eq!(fair_share.tag(Some("quiet")), 0.0);
+
+        fair_share.start(2.0);
+        // A tenant that was idle starts at the virtual time
+        assert_eq!(fair_share.tag(None), 2.0);
+        assert_eq!(fair_share.tag(Some("noisy")), 4.0);
+    }
+
+    #[test]
+    fn test_weights() {
+        let weights = HashMap::from([("large".to_string(), 4.0)]);
+        let mut fair_share = FairShare::new(Arc::new(weights));
+        let large: Vec<f64> = (0..4).map(|_| fair_share.tag(Some("large"))).collect();
+        assert_eq!(large, vec![0.0, 0.25, 0.5, 0.75]);
+        assert_eq!(fair_share.tag(Some("small")), 0.0);
+        assert_eq!(fair_share.tag(Some("small")), 1.0);
+    }
+}
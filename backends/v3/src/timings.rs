@@ -0,0 +1,117 @@
+/// Phase timings of the decode iterations of the batching loop
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use text_generation_router::IterationTimings;
+
+/// Number of iterations kept for `/debug/timings`
+const TIMINGS_HISTORY_SIZE: usize = 256;
+
+/// Time spent in each phase of the current iteration
+#[derive(Debug, Default)]
+pub(crate) struct Phases {
+    pub prefill: Duration,
+    pub concatenate: Duration,
+    pub decode: Duration,
+    pub forward: Duration,
+    /// Generations handled, dispatch included
+    pub generations: Duration,
+    pub dispatch: Duration,
+    pub filter: Duration,
+}
+
+impl Phases {
+    fn timings(&self, batch_id: u64, size: usize, total: Duration) -> IterationTimings {
+        IterationTimings {
+            batch_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            size: size as u32,
+            prefill_us: self.prefill.as_micros() as u64,
+            concatenate_us: self.concatenate.as_micros() as u64,
+            decode_us: self.decode.as_micros() as u64,
+            forward_us: self.forward.as_micros() as u64,
+            postprocess_us: self.generations.saturating_sub(self.dispatch).as_micros() as u64,
+            dispatch_us: self.dispatch.as_micros() as u64,
+            filter_us: self.filter.as_micros() as u64,
+            total_us: total.as_micros() as u64,
+        }
+    }
+}
+
+/// Last iterations of a batching task, shared with its backend
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IterationLog(Arc<Mutex<VecDeque<IterationTimings>>>);
+
+impl IterationLog {
+    /// Record the `phases` of an iteration decoding `size` requests of `batch_id`, which took
+    /// `total`
+    pub(crate) fn record(&self, phases: &Phases, batch_id: u64, size: usize, total: Duration) {
+        let timings = phases.timings(batch_id, size, total);
+        for (phase, duration) in [
+            ("prefill", timings.prefill_us),
+            ("decode", timings.decode_us),
+            ("postprocess", timings.postprocess_us),
+            ("dispatch", timings.dispatch_us),
+            ("filter", timings.filter_us),
+        ] {
+            metrics::histogram!("tgi_batch_iteration_phase_duration", "phase" => phase)
+                .record(duration as f64 / 1e6);
+        }
+        tracing::debug!(
+            batch_id,
+            prefill_us = timings.prefill_us,
+            concatenate_us = timings.concatenate_us,
+            decode_us = timings.decode_us,
+            forward_us = timings.forward_us,
+            postprocess_us = timings.postprocess_us,
+            dispatch_us = timings.dispatch_us,
+            filter_us = timings.filter_us,
+            total_us = timings.total_us,
+            "Decode iteration"
+        );
+
+        let mut history = self.0.lock().unwrap();
+        if history.len() == TIMINGS_HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(timings);
+    }
+
+    /// Recorded iterations, oldest first
+    pub(crate) fn history(&self) -> Vec<IterationTimings> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iteration_log() {
+        let log = IterationLog::default();
+        let phases = Phases {
+            decode: Duration::from_millis(20),
+            forward: Duration::from_millis(18),
+            generations: Duration::from_micros(300),
+            dispatch: Duration::from_micros(100),
+            ..Default::default()
+        };
+        for batch_id in 0..TIMINGS_HISTORY_SIZE as u64 + 2 {
+            log.record(&phases, batch_id, 4, Duration::from_millis(21));
+        }
+
+        let history = log.history();
+        assert_eq!(history.len(), TIMINGS_HISTORY_SIZE);
+        assert_eq!(history[0].batch_id, 2);
+        let timings = &history[0];
+        assert_eq!(timings.size, 4);
+        assert_eq!(timings.decode_us, 20000);
+        assert_eq!(timings.postprocess_us, 200);
+        assert_eq!(timings.dispatch_us, 100);
+        assert_eq!(timings.total_us, 21000);
+    }
+}
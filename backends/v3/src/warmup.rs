@@ -0,0 +1,384 @@
+/// Warmup on the representative shapes of the traffic, so that the shards capture their CUDA
+/// graphs and compile their kernels before serving
+use crate::client::{
+    Batch, Chunk, ClientError, GrammarType, InfoResponse, Input, NextTokenChooserParameters,
+    Request, ShardedClient, StoppingCriteriaParameters,
+};
+use serde::Serialize;
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+/// Shape of the batches to run during warmup
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+pub struct WarmupShape {
+    #[schema(example = "1024")]
+    pub input_tokens: u32,
+    #[schema(example = "256")]
+    pub output_tokens: u32,
+    #[schema(example = "8")]
+    pub batch_size: u32,
+}
+
+impl FromStr for WarmupShape {
+    type Err = String;
+
+    /// Parse `<INPUT_TOKENS>:<OUTPUT_TOKENS>:<BATCH_SIZE>`
+    fn from_str(shape: &str) -> Result<Self, Self::Err> {
+        let dimensions = shape
+            .split(':')
+            .map(|dimension| dimension.trim().parse::<u32>().ok().filter(|&d| d > 0))
+            .collect::<Option<Vec<_>>>();
+        match dimensions.as_deref() {
+            Some(&[input_tokens, output_tokens, batch_size]) => Ok(Self {
+                input_tokens,
+                output_tokens,
+                batch_size,
+            }),
+            _ => Err(
+                "must be `<INPUT_TOKENS>:<OUTPUT_TOKENS>:<BATCH_SIZE>` with dimensions > 0"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// Tokens left to the generation, at most, by a prompt limit derived from a context window
+const MAX_GENERATION_HEADROOM: usize = 2048;
+
+/// Limits of a replica, known once its shards are warmed up
+pub(crate) struct WarmupLimits {
+    pub(crate) max_input_tokens: usize,
+    pub(crate) max_total_tokens: usize,
+    pub(crate) max_batch_prefill_tokens: u32,
+    pub(crate) max_batch_total_tokens: u32,
+    pub(crate) max_batch_size: Option<usize>,
+}
+
+impl WarmupShape {
+    /// Tokens of the KV cache used by every request of the shape
+    fn request_tokens(&self, speculate: u32) -> u64 {
+        self.input_tokens as u64 + self.output_tokens as u64 + speculate as u64 - 1
+    }
+
+    /// Why the shape can never be scheduled on the replica, if it cannot
+    fn unschedulable(&self, limits: &WarmupLimits, shard_info: &InfoResponse) -> Option<String> {
+        // The dimensions are only bounded by u32, their products are computed in u64
+        let tokens = self.input_tokens as u64 + self.output_tokens as u64;
+        let batch_size = self.batch_size as u64;
+        if self.input_tokens as usize > limits.max_input_tokens {
+            return Some(format!(
+                "more than {} input tokens",
+                limits.max_input_tokens
+            ));
+        }
+        if tokens > limits.max_total_tokens as u64 {
+            return Some(format!(
+                "more than {} total tokens",
+                limits.max_total_tokens
+            ));
+        }
+        if limits
+            .max_batch_size
+            .is_some_and(|max_batch_size| self.batch_size as usize > max_batch_size)
+        {
+            return Some("larger than `max_batch_size`".to_string());
+        }
+        if batch_size * self.input_tokens as u64 > limits.max_batch_prefill_tokens as u64 {
+            return Some(format!(
+                "more than {} prefill tokens",
+                limits.max_batch_prefill_tokens
+            ));
+        }
+        let request_tokens = self.request_tokens(shard_info.speculate);
+        let block_size = shard_info.block_size as u64;
+        let batch_tokens = if shard_info.requires_padding {
+            batch_size.saturating_mul(request_tokens)
+        } else {
+            // Whole blocks are allocated to every request, block 0 is reserved for health checks
+            batch_size
+                .saturating_mul(request_tokens.div_ceil(block_size) * block_size)
+                .saturating_add(block_size)
+        };
+        if batch_tokens > limits.max_batch_total_tokens as u64 {
+            return Some(format!(
+                "more than {} batch total tokens",
+                limits.max_batch_total_tokens
+            ));
+        }
+        None
+    }
+
+    /// Batch of `batch_size` requests of `input_tokens` tokens, generating `output_tokens`
+    /// tokens each. With paged attention, the requests get contiguous blocks after block 0.
+    /// The shape must be schedulable, its batch then fits in the u32 `max_batch_total_tokens`.
+    fn batch(&self, shard_info: &InfoResponse) -> Batch {
+        let request_tokens = self.request_tokens(shard_info.speculate) as u32;
+        let block_size = shard_info.block_size;
+        let request_blocks = request_tokens.div_ceil(block_size);
+        let requests = (0..self.batch_size)
+            .map(|id| {
+                let (blocks, slots) = if shard_info.requires_padding {
+                    (Vec::new(), Vec::new())
+                } else {
+                    let first_block = 1 + id * request_blocks;
+                    let blocks: Vec<u32> = (first_block..first_block + request_blocks).collect();
+                    let first_slot = first_block * block_size;
+                    (blocks, (first_slot..first_slot + request_tokens).collect())
+                };
+                Request {
+                    id: id as u64,
+                    inputs: "_test ".repeat(self.input_tokens as usize),
+                    input_chunks: Some(Input {
+                        chunks: vec![
+                            Chunk::Text("_test ".repeat(self.input_tokens as usize)).into()
+                        ],
+                    }),
+                    // The input is truncated on the server side to have the exact size
+                    truncate: self.input_tokens,
+                    add_special_tokens: true,
+                    prefill_logprobs: false,
+                    parameters: Some(NextTokenChooserParameters {
+                        temperature: 1.0,
+                        top_k: 0,
+                        top_p: 1.0,
+                        typical_p: 1.0,
+                        do_sample: false,
+                        seed: 0,
+                        repetition_penalty: 1.0,
+                        frequency_penalty: 0.0,
+                        watermark: false,
+                        grammar: String::new(),
+                        grammar_type: GrammarType::None as i32,
+                        speculate: None,
+                        sampling_step: 0,
+                        healing_token_id: None,
+                        logit_bias: Default::default(),
+                        presence_penalty: 0.0,
+                        min_p: 0.0,
+                        watermark_key: 0,
+                        no_repeat_ngram_size: 0,
+                    }),
+                    stopping_parameters: Some(StoppingCriteriaParameters {
+                        max_new_tokens: self.output_tokens,
+                        stop_sequences: vec![],
+                        ignore_eos_token: true,
+                        stop_token_ids: vec![],
+                        min_new_tokens: 0,
+                    }),
+                    top_n_tokens: 0,
+                    blocks,
+                    slots,
+                    cache_len: 0,
+                    adapter_id: None,
+                    chunk_len: None,
+                    adapters: vec![],
+                    guidance: None,
+                }
+            })
+            .collect();
+        Batch {
+            id: 0,
+            requests,
+            size: self.batch_size,
+            max_tokens: self.batch_size * request_tokens,
+            max_blocks: if shard_info.requires_padding {
+                0
+            } else {
+                request_blocks
+            },
+        }
+    }
+
+    /// Prefill the batch of the shape and decode all its tokens
+    async fn run(
+        &self,
+        client: &mut ShardedClient,
+        shard_info: &InfoResponse,
+    ) -> Result<(), ClientError> {
+        let (_, mut next_batch, _) = client.prefill(self.batch(shard_info), None).await?;
+        while let Some(batch) = next_batch {
+            (_, next_batch, _) = client.decode(vec![batch], Vec::new()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// `max_input_tokens` and `max_total_tokens` filled, when they are not set, from a context
+/// `window` of tokens: the requests can take the whole window and the prompts leave an eighth
+/// of it, up to `MAX_GENERATION_HEADROOM` tokens, to the generation. Without prefill chunking,
+/// the prompts are also kept under `max_prefill_input_tokens`. None if the `max_input_tokens`
+/// of the user leave no token of the window to the generation.
+pub(crate) fn context_limits(
+    window: usize,
+    max_input_tokens: Option<usize>,
+    max_total_tokens: Option<usize>,
+    max_prefill_input_tokens: Option<usize>,
+) -> Option<(usize, usize)> {
+    let max_total_tokens = match (max_total_tokens, max_input_tokens) {
+        (Some(max_total_tokens), _) => max_total_tokens,
+        (None, Some(max_input_tokens)) if max_input_tokens >= window => return None,
+        (None, _) => window,
+    };
+    let max_input_tokens = max_input_tokens.unwrap_or_else(|| {
+        let headroom = (max_total_tokens / 8).clamp(1, MAX_GENERATION_HEADROOM);
+        let max_input_tokens = max_total_tokens.saturating_sub(headroom);
+        max_prefill_input_tokens.map_or(max_input_tokens, |tokens| max_input_tokens.min(tokens))
+    });
+    Some((max_input_tokens, max_total_tokens))
+}
+
+/// Run the batches of the `shapes` that can be scheduled on the replica, and return them. A
+/// shape that fails is skipped: it only costs a slower first batch of that shape.
+pub(crate) async fn warmup_shapes(
+    client: &mut ShardedClient,
+    shard_info: &InfoResponse,
+    shapes: &[WarmupShape],
+    limits: &WarmupLimits,
+) -> Result<Vec<WarmupShape>, ClientError> {
+    let mut warmed_up = Vec::with_capacity(shapes.len());
+    for shape in shapes {
+        if let Some(reason) = shape.unschedulable(limits, shard_info) {
+            tracing::warn!("Skipping warmup shape {shape:?}: {reason}");
+            continue;
+        }
+        tracing::info!("Warming up shape {shape:?}");
+        match shape.run(client, shard_info).await {
+            Ok(()) => warmed_up.push(*shape),
+            Err(err) => tracing::warn!("Warmup shape {shape:?} failed: {err}"),
+        }
+        // Free the KV cache of the shape, finished or not
+        client.clear_cache(None).await?;
+    }
+    Ok(warmed_up)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard_info(requires_padding: bool) -> InfoResponse {
+        InfoResponse {
+            requires_padding,
+            block_size: 16,
+            speculate: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_warmup_shape() {
+        assert_eq!(
+            "1024:256:8".parse::<WarmupShape>(),
+            Ok(WarmupShape {
+                input_tokens: 1024,
+                output_tokens: 256,
+                batch_size: 8,
+            })
+        );
+        assert!("1024:256".parse::<WarmupShape>().is_err());
+        assert!("1024:0:8".parse::<WarmupShape>().is_err());
+        assert!("1024:256:8:1".parse::<WarmupShape>().is_err());
+        assert!("a:256:8".parse::<WarmupShape>().is_err());
+    }
+
+    #[test]
+    fn test_warmup_shape_batch() {
+        let shape = WarmupShape {
+            input_tokens: 20,
+            output_tokens: 5,
+            batch_size: 2,
+        };
+        let batch = shape.batch(&shard_info(false));
+        assert_eq!(batch.size, 2);
+        assert_eq!(batch.max_tokens, 48);
+        assert_eq!(batch.max_blocks, 2);
+        assert_eq!(batch.requests[0].blocks, vec![1, 2]);
+        assert_eq!(batch.requests[1].blocks, vec![3, 4]);
+        assert_eq!(batch.requests[1].slots.len(), 24);
+        assert_eq!(batch.requests[1].slots[0], 48);
+
+        let batch = shape.batch(&shard_info(true));
+        assert!(batch.requests[0].blocks.is_empty());
+        assert_eq!(batch.max_blocks, 0);
+    }
+
+    #[test]
+    fn test_warmup_shape_limits() {
+        let limits = WarmupLimits {
+            max_input_tokens: 1024,
+            max_total_tokens: 2048,
+            max_batch_prefill_tokens: 4096,
+            max_batch_total_tokens: 16 * 16,
+            max_batch_size: None,
+        };
+        let shape = |input_tokens, output_tokens, batch_size| WarmupShape {
+            input_tokens,
+            output_tokens,
+            batch_size,
+        };
+        // 3 requests of 5 blocks, and the health check block
+        assert!(shape(40, 40, 3)
+            .unschedulable(&limits, &shard_info(false))
+            .is_none());
+        assert!(shape(40, 40, 4)
+            .unschedulable(&limits, &shard_info(false))
+            .is_some());
+        assert!(shape(2000, 1, 1)
+            .unschedulable(&limits, &shard_info(false))
+            .is_some());
+        assert!(shape(1024, 1, 8)
+            .unschedulable(&limits, &shard_info(true))
+            .is_some());
+        // The dimensions multiply without overflowing
+        let limits = WarmupLimits {
+            max_input_tokens: u32::MAX as usize,
+            max_total_tokens: usize::MAX,
+            max_batch_prefill_tokens: u32::MAX,
+            max_batch_total_tokens: u32::MAX,
+            max_batch_size: None,
+        };
+        assert!(shape(u32::MAX, u32::MAX, u32::MAX)
+            .unschedulable(&limits, &shard_info(false))
+            .is_some());
+        assert!(shape(1, u32::MAX, u32::MAX)
+            .unschedulable(&limits, &shard_info(false))
+            .is_some());
+        assert!(shape(1 << 16, 1, 1 << 16)
+            .unschedulable(&limits, &shard_info(true))
+            .is_some());
+    }
+
+    #[test]
+    fn test_context_limits() {
+        assert_eq!(context_limits(4096, None, None, None), Some((3584, 4096)));
+        assert_eq!(
+            context_limits(131072, None, None, None),
+            Some((129024, 131072))
+        );
+        // Prompts fit in a prefill without chunking
+        assert_eq!(
+            context_limits(4096, None, None, Some(2048)),
+            Some((2048, 4096))
+        );
+        // The limits of the user are kept
+        assert_eq!(
+            context_limits(4096, Some(1000), None, None),
+            Some((1000, 4096))
+        );
+        assert_eq!(
+            context_limits(4096, None, Some(2000), None),
+            Some((1750, 2000))
+        );
+        assert_eq!(
+            context_limits(4096, Some(10), Some(20), None),
+            Some((10, 20))
+        );
+        // Unless the prompts of the user do not fit in the window
+        assert_eq!(context_limits(4096, Some(8000), None, None), None);
+        assert_eq!(context_limits(4096, Some(4096), None, None), None);
+        assert_eq!(
+            context_limits(4096, Some(8000), Some(8192), None),
+            Some((8000, 8192))
+        );
+    }
+}
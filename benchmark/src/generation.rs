@@ -154,12 +154,16 @@ async fn prefill(
                 max_new_tokens: decode_length,
                 stop_sequences: vec![],
                 ignore_eos_token: true, // Will not stop even if a eos token is generated
+                stop_token_ids: vec![],
+                min_new_tokens: 0,
             }),
             top_n_tokens: top_n_tokens.unwrap_or(0),
             blocks: vec![],
             slots: vec![],
             cache_len: 0,
             chunk_len: None,
+            adapters: vec![],
+            guidance: None,
             adapter_id: None,
         })
         .collect();
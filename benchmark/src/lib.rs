@@ -47,6 +47,14 @@ pub async fn run(
         watermark,
         grammar: String::new(),
         grammar_type: GrammarType::None as i32,
+        speculate: None,
+        sampling_step: 0,
+        healing_token_id: None,
+        logit_bias: Default::default(),
+        presence_penalty: 0.0,
+        min_p: 0.0,
+        watermark_key: 0,
+        no_repeat_ngram_size: 0,
     };
 
     // Initialize terminal properties
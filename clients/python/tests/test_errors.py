@@ -33,6 +33,20 @@ def test_validation_error():
     assert isinstance(parse_error(400, payload), ValidationError)
 
 
+def test_structured_validation_error():
+    payload = {
+        "error": {"type": "validation", "param": "max_new_tokens", "message": "test"}
+    }
+    error = parse_error(422, payload)
+    assert isinstance(error, ValidationError)
+    assert str(error) == "test"
+
+
+def test_structured_queue_full_error():
+    payload = {"error": {"type": "queue_full", "message": "test"}}
+    assert isinstance(parse_error(429, payload), OverloadedError)
+
+
 def test_bad_request_error():
     payload = {"error": "test"}
     assert isinstance(parse_error(400, payload), BadRequestError)
@@ -13,6 +13,7 @@ from text_generation.types import (
     Request,
     Parameters,
     Grammar,
+    AdapterWeight,
     CompletionRequest,
     Completion,
     CompletionComplete,
@@ -77,6 +78,7 @@ class Client:
         self,
         prompt: str,
         frequency_penalty: Optional[float] = None,
+        presence_penalty: Optional[float] = None,
         max_tokens: Optional[int] = None,
         repetition_penalty: Optional[float] = None,
         seed: Optional[int] = None,
@@ -95,6 +97,10 @@ class Client:
                 The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
+            presence_penalty (`float`):
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             max_tokens (`int`):
                 Maximum number of generated tokens
             repetition_penalty (`float`):
@@ -116,6 +122,7 @@ class Client:
             model="tgi",
             prompt=prompt,
             frequency_penalty=frequency_penalty,
+            presence_penalty=presence_penalty,
             max_tokens=max_tokens,
             repetition_penalty=repetition_penalty,
             seed=seed,
@@ -166,7 +173,7 @@ class Client:
         messages: List[Message],
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
-        logit_bias: Optional[List[float]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
         logprobs: Optional[bool] = None,
         top_logprobs: Optional[int] = None,
         max_tokens: Optional[int] = None,
@@ -194,8 +201,8 @@ class Client:
                 The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
-            logit_bias (`List[float]`):
-                Adjust the likelihood of specified tokens
+            logit_bias (`Dict[int, float]`):
+                Bias added to the logits of token ids, from -100 to 100
             logprobs (`bool`):
                 Include log probabilities in the response
             top_logprobs (`int`):
@@ -205,8 +212,9 @@ class Client:
             n (`int`):
                 Generate `n` completions
             presence_penalty (`float`):
-                The parameter for presence penalty. 0.0 means no penalty. See [this
-                paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             stream (`bool`):
                 Stream the response
             seed (`int`):
@@ -288,21 +296,29 @@ class Client:
         prompt: str,
         do_sample: bool = False,
         max_new_tokens: int = 20,
+        min_new_tokens: Optional[int] = None,
         best_of: Optional[int] = None,
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
+        presence_penalty: Optional[float] = None,
         return_full_text: bool = False,
         seed: Optional[int] = None,
         stop_sequences: Optional[List[str]] = None,
+        stop_token_ids: Optional[List[int]] = None,
         temperature: Optional[float] = None,
         top_k: Optional[int] = None,
         top_p: Optional[float] = None,
         truncate: Optional[int] = None,
         typical_p: Optional[float] = None,
+        min_p: Optional[float] = None,
+        no_repeat_ngram_size: Optional[int] = None,
+        guidance_scale: Optional[float] = None,
+        negative_prompt: Optional[str] = None,
         watermark: bool = False,
         decoder_input_details: bool = False,
         top_n_tokens: Optional[int] = None,
         grammar: Optional[Grammar] = None,
+        adapters: Optional[List[AdapterWeight]] = None,
     ) -> Response:
         """
         Given a prompt, generate the following text
@@ -314,21 +330,30 @@ class Client:
                 Activate logits sampling
             max_new_tokens (`int`):
                 Maximum number of generated tokens
+            min_new_tokens (`int`):
+                Minimum number of generated tokens, before which the end of sequence token and
+                the stop sequences do not end the generation
             best_of (`int`):
                 Generate best_of sequences and return the one if the highest token logprobs
             repetition_penalty (`float`):
                 The parameter for repetition penalty. 1.0 means no penalty. See [this
                 paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
             frequency_penalty (`float`):
-                The parameter for frequency penalty. 1.0 means no penalty
+                The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
+            presence_penalty (`float`):
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             return_full_text (`bool`):
                 Whether to prepend the prompt to the generated text
             seed (`int`):
                 Random sampling seed
             stop_sequences (`List[str]`):
                 Stop generating tokens if a member of `stop_sequences` is generated
+            stop_token_ids (`List[int]`):
+                Stop generating tokens if a member of `stop_token_ids` is generated
             temperature (`float`):
                 The value used to module the logits distribution.
             top_k (`int`):
@@ -341,6 +366,15 @@ class Client:
             typical_p (`float`):
                 Typical Decoding mass
                 See [Typical Decoding for Natural Language Generation](https://arxiv.org/abs/2202.00666) for more information
+            min_p (`float`):
+                Only sample from the tokens whose probability is at least `min_p` times the probability of the most
+                likely token
+            no_repeat_ngram_size (`int`):
+                Size of the n-grams that can only occur once in the text, prompt included
+            guidance_scale (`float`):
+                Scale of classifier-free guidance, pushing the generation away from `negative_prompt`
+            negative_prompt (`str`):
+                Prompt that the generation is guided away from, empty by default. Requires `guidance_scale`
             watermark (`bool`):
                 Watermarking with [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226)
             decoder_input_details (`bool`):
@@ -350,6 +384,8 @@ class Client:
             grammar (`Grammar`):
                 Whether to use a grammar for the generation and the grammar to use. Grammars will constrain the generation
                 of the text to match a regular expression or JSON schema.
+            adapters (`List[AdapterWeight]`):
+                LoRA adapters of the server applied together, each scaled by its weight
 
         Returns:
             Response: generated response
@@ -360,20 +396,28 @@ class Client:
             details=True,
             do_sample=do_sample,
             max_new_tokens=max_new_tokens,
+            min_new_tokens=min_new_tokens,
             repetition_penalty=repetition_penalty,
             frequency_penalty=frequency_penalty,
+            presence_penalty=presence_penalty,
             return_full_text=return_full_text,
             seed=seed,
             stop=stop_sequences if stop_sequences is not None else [],
+            stop_token_ids=stop_token_ids if stop_token_ids is not None else [],
             temperature=temperature,
             top_k=top_k,
             top_p=top_p,
             truncate=truncate,
             typical_p=typical_p,
+            min_p=min_p,
+            no_repeat_ngram_size=no_repeat_ngram_size,
+            guidance_scale=guidance_scale,
+            negative_prompt=negative_prompt,
             watermark=watermark,
             decoder_input_details=decoder_input_details,
             top_n_tokens=top_n_tokens,
             grammar=grammar,
+            adapters=adapters,
         )
         request = Request(inputs=prompt, stream=False, parameters=parameters)
 
@@ -394,19 +438,27 @@ class Client:
         prompt: str,
         do_sample: bool = False,
         max_new_tokens: int = 20,
+        min_new_tokens: Optional[int] = None,
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
+        presence_penalty: Optional[float] = None,
         return_full_text: bool = False,
         seed: Optional[int] = None,
         stop_sequences: Optional[List[str]] = None,
+        stop_token_ids: Optional[List[int]] = None,
         temperature: Optional[float] = None,
         top_k: Optional[int] = None,
         top_p: Optional[float] = None,
         truncate: Optional[int] = None,
         typical_p: Optional[float] = None,
+        min_p: Optional[float] = None,
+        no_repeat_ngram_size: Optional[int] = None,
+        guidance_scale: Optional[float] = None,
+        negative_prompt: Optional[str] = None,
         watermark: bool = False,
         top_n_tokens: Optional[int] = None,
         grammar: Optional[Grammar] = None,
+        adapters: Optional[List[AdapterWeight]] = None,
     ) -> Iterator[StreamResponse]:
         """
         Given a prompt, generate the following stream of tokens
@@ -418,19 +470,28 @@ class Client:
                 Activate logits sampling
             max_new_tokens (`int`):
                 Maximum number of generated tokens
+            min_new_tokens (`int`):
+                Minimum number of generated tokens, before which the end of sequence token and
+                the stop sequences do not end the generation
             repetition_penalty (`float`):
                 The parameter for repetition penalty. 1.0 means no penalty. See [this
                 paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
             frequency_penalty (`float`):
-                The parameter for frequency penalty. 1.0 means no penalty
+                The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
+            presence_penalty (`float`):
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             return_full_text (`bool`):
                 Whether to prepend the prompt to the generated text
             seed (`int`):
                 Random sampling seed
             stop_sequences (`List[str]`):
                 Stop generating tokens if a member of `stop_sequences` is generated
+            stop_token_ids (`List[int]`):
+                Stop generating tokens if a member of `stop_token_ids` is generated
             temperature (`float`):
                 The value used to module the logits distribution.
             top_k (`int`):
@@ -443,6 +504,15 @@ class Client:
             typical_p (`float`):
                 Typical Decoding mass
                 See [Typical Decoding for Natural Language Generation](https://arxiv.org/abs/2202.00666) for more information
+            min_p (`float`):
+                Only sample from the tokens whose probability is at least `min_p` times the probability of the most
+                likely token
+            no_repeat_ngram_size (`int`):
+                Size of the n-grams that can only occur once in the text, prompt included
+            guidance_scale (`float`):
+                Scale of classifier-free guidance, pushing the generation away from `negative_prompt`
+            negative_prompt (`str`):
+                Prompt that the generation is guided away from, empty by default. Requires `guidance_scale`
             watermark (`bool`):
                 Watermarking with [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226)
             top_n_tokens (`int`):
@@ -450,6 +520,8 @@ class Client:
             grammar (`Grammar`):
                 Whether to use a grammar for the generation and the grammar to use. Grammars will constrain the generation
                 of the text to match a regular expression or JSON schema.
+            adapters (`List[AdapterWeight]`):
+                LoRA adapters of the server applied together, each scaled by its weight
 
         Returns:
             Iterator[StreamResponse]: stream of generated tokens
@@ -461,19 +533,27 @@ class Client:
             decoder_input_details=False,
             do_sample=do_sample,
             max_new_tokens=max_new_tokens,
+            min_new_tokens=min_new_tokens,
             repetition_penalty=repetition_penalty,
             frequency_penalty=frequency_penalty,
+            presence_penalty=presence_penalty,
             return_full_text=return_full_text,
             seed=seed,
             stop=stop_sequences if stop_sequences is not None else [],
+            stop_token_ids=stop_token_ids if stop_token_ids is not None else [],
             temperature=temperature,
             top_k=top_k,
             top_p=top_p,
             truncate=truncate,
             typical_p=typical_p,
+            min_p=min_p,
+            no_repeat_ngram_size=no_repeat_ngram_size,
+            guidance_scale=guidance_scale,
+            negative_prompt=negative_prompt,
             watermark=watermark,
             top_n_tokens=top_n_tokens,
             grammar=grammar,
+            adapters=adapters,
         )
         request = Request(inputs=prompt, stream=True, parameters=parameters)
 
@@ -560,6 +640,7 @@ class AsyncClient:
         self,
         prompt: str,
         frequency_penalty: Optional[float] = None,
+        presence_penalty: Optional[float] = None,
         max_tokens: Optional[int] = None,
         repetition_penalty: Optional[float] = None,
         seed: Optional[int] = None,
@@ -578,6 +659,10 @@ class AsyncClient:
                 The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
+            presence_penalty (`float`):
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             max_tokens (`int`):
                 Maximum number of generated tokens
             repetition_penalty (`float`):
@@ -599,6 +684,7 @@ class AsyncClient:
             model="tgi",
             prompt=prompt,
             frequency_penalty=frequency_penalty,
+            presence_penalty=presence_penalty,
             max_tokens=max_tokens,
             repetition_penalty=repetition_penalty,
             seed=seed,
@@ -648,7 +734,7 @@ class AsyncClient:
         messages: List[Message],
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
-        logit_bias: Optional[List[float]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
         logprobs: Optional[bool] = None,
         top_logprobs: Optional[int] = None,
         max_tokens: Optional[int] = None,
@@ -676,8 +762,8 @@ class AsyncClient:
                 The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
-            logit_bias (`List[float]`):
-                Adjust the likelihood of specified tokens
+            logit_bias (`Dict[int, float]`):
+                Bias added to the logits of token ids, from -100 to 100
             logprobs (`bool`):
                 Include log probabilities in the response
             top_logprobs (`int`):
@@ -687,8 +773,9 @@ class AsyncClient:
             n (`int`):
                 Generate `n` completions
             presence_penalty (`float`):
-                The parameter for presence penalty. 0.0 means no penalty. See [this
-                paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             stream (`bool`):
                 Stream the response
             seed (`int`):
@@ -774,21 +861,29 @@ class AsyncClient:
         prompt: str,
         do_sample: bool = False,
         max_new_tokens: int = 20,
+        min_new_tokens: Optional[int] = None,
         best_of: Optional[int] = None,
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
+        presence_penalty: Optional[float] = None,
         return_full_text: bool = False,
         seed: Optional[int] = None,
         stop_sequences: Optional[List[str]] = None,
+        stop_token_ids: Optional[List[int]] = None,
         temperature: Optional[float] = None,
         top_k: Optional[int] = None,
         top_p: Optional[float] = None,
         truncate: Optional[int] = None,
         typical_p: Optional[float] = None,
+        min_p: Optional[float] = None,
+        no_repeat_ngram_size: Optional[int] = None,
+        guidance_scale: Optional[float] = None,
+        negative_prompt: Optional[str] = None,
         watermark: bool = False,
         decoder_input_details: bool = False,
         top_n_tokens: Optional[int] = None,
         grammar: Optional[Grammar] = None,
+        adapters: Optional[List[AdapterWeight]] = None,
     ) -> Response:
         """
         Given a prompt, generate the following text asynchronously
@@ -800,21 +895,30 @@ class AsyncClient:
                 Activate logits sampling
             max_new_tokens (`int`):
                 Maximum number of generated tokens
+            min_new_tokens (`int`):
+                Minimum number of generated tokens, before which the end of sequence token and
+                the stop sequences do not end the generation
             best_of (`int`):
                 Generate best_of sequences and return the one if the highest token logprobs
             repetition_penalty (`float`):
                 The parameter for repetition penalty. 1.0 means no penalty. See [this
                 paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
             frequency_penalty (`float`):
-                The parameter for frequency penalty. 1.0 means no penalty
+                The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
+            presence_penalty (`float`):
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             return_full_text (`bool`):
                 Whether to prepend the prompt to the generated text
             seed (`int`):
                 Random sampling seed
             stop_sequences (`List[str]`):
                 Stop generating tokens if a member of `stop_sequences` is generated
+            stop_token_ids (`List[int]`):
+                Stop generating tokens if a member of `stop_token_ids` is generated
             temperature (`float`):
                 The value used to module the logits distribution.
             top_k (`int`):
@@ -827,6 +931,15 @@ class AsyncClient:
             typical_p (`float`):
                 Typical Decoding mass
                 See [Typical Decoding for Natural Language Generation](https://arxiv.org/abs/2202.00666) for more information
+            min_p (`float`):
+                Only sample from the tokens whose probability is at least `min_p` times the probability of the most
+                likely token
+            no_repeat_ngram_size (`int`):
+                Size of the n-grams that can only occur once in the text, prompt included
+            guidance_scale (`float`):
+                Scale of classifier-free guidance, pushing the generation away from `negative_prompt`
+            negative_prompt (`str`):
+                Prompt that the generation is guided away from, empty by default. Requires `guidance_scale`
             watermark (`bool`):
                 Watermarking with [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226)
             decoder_input_details (`bool`):
@@ -836,6 +949,8 @@ class AsyncClient:
             grammar (`Grammar`):
                 Whether to use a grammar for the generation and the grammar to use. Grammars will constrain the generation
                 of the text to match a regular expression or JSON schema.
+            adapters (`List[AdapterWeight]`):
+                LoRA adapters of the server applied together, each scaled by its weight
 
         Returns:
             Response: generated response
@@ -848,19 +963,27 @@ class AsyncClient:
             decoder_input_details=decoder_input_details,
             do_sample=do_sample,
             max_new_tokens=max_new_tokens,
+            min_new_tokens=min_new_tokens,
             repetition_penalty=repetition_penalty,
             frequency_penalty=frequency_penalty,
+            presence_penalty=presence_penalty,
             return_full_text=return_full_text,
             seed=seed,
             stop=stop_sequences if stop_sequences is not None else [],
+            stop_token_ids=stop_token_ids if stop_token_ids is not None else [],
             temperature=temperature,
             top_k=top_k,
             top_p=top_p,
             truncate=truncate,
             typical_p=typical_p,
+            min_p=min_p,
+            no_repeat_ngram_size=no_repeat_ngram_size,
+            guidance_scale=guidance_scale,
+            negative_prompt=negative_prompt,
             watermark=watermark,
             top_n_tokens=top_n_tokens,
             grammar=grammar,
+            adapters=adapters,
         )
         request = Request(inputs=prompt, stream=False, parameters=parameters)
 
@@ -879,19 +1002,27 @@ class AsyncClient:
         prompt: str,
         do_sample: bool = False,
         max_new_tokens: int = 20,
+        min_new_tokens: Optional[int] = None,
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
+        presence_penalty: Optional[float] = None,
         return_full_text: bool = False,
         seed: Optional[int] = None,
         stop_sequences: Optional[List[str]] = None,
+        stop_token_ids: Optional[List[int]] = None,
         temperature: Optional[float] = None,
         top_k: Optional[int] = None,
         top_p: Optional[float] = None,
         truncate: Optional[int] = None,
         typical_p: Optional[float] = None,
+        min_p: Optional[float] = None,
+        no_repeat_ngram_size: Optional[int] = None,
+        guidance_scale: Optional[float] = None,
+        negative_prompt: Optional[str] = None,
         watermark: bool = False,
         top_n_tokens: Optional[int] = None,
         grammar: Optional[Grammar] = None,
+        adapters: Optional[List[AdapterWeight]] = None,
     ) -> AsyncIterator[StreamResponse]:
         """
         Given a prompt, generate the following stream of tokens asynchronously
@@ -903,19 +1034,28 @@ class AsyncClient:
                 Activate logits sampling
             max_new_tokens (`int`):
                 Maximum number of generated tokens
+            min_new_tokens (`int`):
+                Minimum number of generated tokens, before which the end of sequence token and
+                the stop sequences do not end the generation
             repetition_penalty (`float`):
                 The parameter for repetition penalty. 1.0 means no penalty. See [this
                 paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
             frequency_penalty (`float`):
-                The parameter for frequency penalty. 1.0 means no penalty
+                The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
+            presence_penalty (`float`):
+                The parameter for presence penalty. 0.0 means no penalty
+                Penalize new tokens once if they appear in the text so far, whatever their frequency,
+                increasing the model's likelihood to talk about new topics.
             return_full_text (`bool`):
                 Whether to prepend the prompt to the generated text
             seed (`int`):
                 Random sampling seed
             stop_sequences (`List[str]`):
                 Stop generating tokens if a member of `stop_sequences` is generated
+            stop_token_ids (`List[int]`):
+                Stop generating tokens if a member of `stop_token_ids` is generated
             temperature (`float`):
                 The value used to module the logits distribution.
             top_k (`int`):
@@ -928,6 +1068,15 @@ class AsyncClient:
             typical_p (`float`):
                 Typical Decoding mass
                 See [Typical Decoding for Natural Language Generation](https://arxiv.org/abs/2202.00666) for more information
+            min_p (`float`):
+                Only sample from the tokens whose probability is at least `min_p` times the probability of the most
+                likely token
+            no_repeat_ngram_size (`int`):
+                Size of the n-grams that can only occur once in the text, prompt included
+            guidance_scale (`float`):
+                Scale of classifier-free guidance, pushing the generation away from `negative_prompt`
+            negative_prompt (`str`):
+                Prompt that the generation is guided away from, empty by default. Requires `guidance_scale`
             watermark (`bool`):
                 Watermarking with [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226)
             top_n_tokens (`int`):
@@ -935,6 +1084,8 @@ class AsyncClient:
             grammar (`Grammar`):
                 Whether to use a grammar for the generation and the grammar to use. Grammars will constrain the generation
                 of the text to match a regular expression or JSON schema.
+            adapters (`List[AdapterWeight]`):
+                LoRA adapters of the server applied together, each scaled by its weight
 
         Returns:
             AsyncIterator[StreamResponse]: stream of generated tokens
@@ -946,19 +1097,27 @@ class AsyncClient:
             decoder_input_details=False,
             do_sample=do_sample,
             max_new_tokens=max_new_tokens,
+            min_new_tokens=min_new_tokens,
             repetition_penalty=repetition_penalty,
             frequency_penalty=frequency_penalty,
+            presence_penalty=presence_penalty,
             return_full_text=return_full_text,
             seed=seed,
             stop=stop_sequences if stop_sequences is not None else [],
+            stop_token_ids=stop_token_ids if stop_token_ids is not None else [],
             temperature=temperature,
             top_k=top_k,
             top_p=top_p,
             truncate=truncate,
             typical_p=typical_p,
+            min_p=min_p,
+            no_repeat_ngram_size=no_repeat_ngram_size,
+            guidance_scale=guidance_scale,
+            negative_prompt=negative_prompt,
             watermark=watermark,
             top_n_tokens=top_n_tokens,
             grammar=grammar,
+            adapters=adapters,
         )
         request = Request(inputs=prompt, stream=True, parameters=parameters)
 
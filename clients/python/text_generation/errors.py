@@ -1,4 +1,4 @@
-from typing import Dict
+from typing import Any, Dict
 
 
 # Text Generation Inference Errors
@@ -63,14 +63,14 @@ class UnknownError(Exception):
         super().__init__(message)
 
 
-def parse_error(status_code: int, payload: Dict[str, str]) -> Exception:
+def parse_error(status_code: int, payload: Dict[str, Any]) -> Exception:
     """
     Parse error given an HTTP status code and a json payload
 
     Args:
         status_code (`int`):
             HTTP status code
-        payload (`Dict[str, str]`):
+        payload (`Dict[str, Any]`):
             Json payload
 
     Returns:
@@ -79,13 +79,17 @@ def parse_error(status_code: int, payload: Dict[str, str]) -> Exception:
     """
     # Try to parse a Text Generation Inference error
     message = payload["error"]
-    if "error_type" in payload:
-        error_type = payload["error_type"]
+    error_type = payload.get("error_type")
+    # Structured errors: {"error": {"type": ..., "param": ..., "message": ...}}
+    if isinstance(message, dict):
+        error_type = message.get("type")
+        message = message.get("message", "")
+    if error_type is not None:
         if error_type == "generation":
             return GenerationError(message)
         if error_type == "incomplete_generation":
             return IncompleteGenerationError(message)
-        if error_type == "overloaded":
+        if error_type in ("overloaded", "queue_full"):
             return OverloadedError(message)
         if error_type == "validation":
             return ValidationError(message)
@@ -1,6 +1,6 @@
 from enum import Enum
 from pydantic import BaseModel, field_validator, ConfigDict
-from typing import Optional, List, Union, Any
+from typing import Dict, Optional, List, Union, Any
 
 from text_generation.errors import ValidationError
 
@@ -19,6 +19,13 @@ class Grammar(BaseModel):
     value: Union[str, dict]
 
 
+class AdapterWeight(BaseModel):
+    # Id of a LoRA adapter of the server
+    id: str
+    # Weight of the adapter in the mixture
+    weight: float
+
+
 class ToolCall(BaseModel):
     # Id of the tool call
     id: int
@@ -85,10 +92,14 @@ class CompletionRequest(BaseModel):
     # The parameter for repetition penalty. 1.0 means no penalty.
     # See [this paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
     repetition_penalty: Optional[float] = None
-    # The parameter for frequency penalty. 1.0 means no penalty
+    # The parameter for frequency penalty. 0.0 means no penalty
     # Penalize new tokens based on their existing frequency in the text so far,
     # decreasing the model's likelihood to repeat the same line verbatim.
     frequency_penalty: Optional[float] = None
+    # The parameter for presence penalty. 0.0 means no penalty
+    # Penalize new tokens once if they appear in the text so far, whatever their frequency,
+    # increasing the model's likelihood to talk about new topics.
+    presence_penalty: Optional[float] = None
     # Maximum number of tokens to generate
     max_tokens: Optional[int] = None
     # Flag to indicate streaming response
@@ -132,12 +143,12 @@ class ChatRequest(BaseModel):
     # The parameter for repetition penalty. 1.0 means no penalty.
     # See [this paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
     repetition_penalty: Optional[float] = None
-    # The parameter for frequency penalty. 1.0 means no penalty
+    # The parameter for frequency penalty. 0.0 means no penalty
     # Penalize new tokens based on their existing frequency in the text so far,
     # decreasing the model's likelihood to repeat the same line verbatim.
     frequency_penalty: Optional[float] = None
-    # Bias values for token selection
-    logit_bias: Optional[List[float]] = None
+    # Bias added to the logits of token ids, from -100 to 100
+    logit_bias: Optional[Dict[int, float]] = None
     # Whether to return log probabilities
     logprobs: Optional[bool] = None
     # Number of most likely tokens to return at each position
@@ -205,17 +216,26 @@ class Parameters(BaseModel):
     do_sample: bool = False
     # Maximum number of generated tokens
     max_new_tokens: int = 20
+    # Minimum number of generated tokens, before which the end of sequence token and the
+    # stop sequences do not end the generation
+    min_new_tokens: Optional[int] = None
     # The parameter for repetition penalty. 1.0 means no penalty.
     # See [this paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
     repetition_penalty: Optional[float] = None
-    # The parameter for frequency penalty. 1.0 means no penalty
+    # The parameter for frequency penalty. 0.0 means no penalty
     # Penalize new tokens based on their existing frequency in the text so far,
     # decreasing the model's likelihood to repeat the same line verbatim.
     frequency_penalty: Optional[float] = None
+    # The parameter for presence penalty. 0.0 means no penalty
+    # Penalize new tokens once if they appear in the text so far, whatever their frequency,
+    # increasing the model's likelihood to talk about new topics.
+    presence_penalty: Optional[float] = None
     # Whether to prepend the prompt to the generated text
     return_full_text: bool = False
     # Stop generating tokens if a member of `stop_sequences` is generated
     stop: List[str] = []
+    # Stop generating tokens if a member of `stop_token_ids` is generated
+    stop_token_ids: List[int] = []
     # Random sampling seed
     seed: Optional[int] = None
     # The value used to module the logits distribution.
@@ -230,6 +250,14 @@ class Parameters(BaseModel):
     # Typical Decoding mass
     # See [Typical Decoding for Natural Language Generation](https://arxiv.org/abs/2202.00666) for more information
     typical_p: Optional[float] = None
+    # Only sample from the tokens whose probability is at least `min_p` times the probability of the most likely token
+    min_p: Optional[float] = None
+    # Size of the n-grams that can only occur once in the text, prompt included
+    no_repeat_ngram_size: Optional[int] = None
+    # Scale of classifier-free guidance, pushing the generation away from `negative_prompt`
+    guidance_scale: Optional[float] = None
+    # Prompt that the generation is guided away from, empty by default
+    negative_prompt: Optional[str] = None
     # Generate best_of sequences and return the one if the highest token logprobs
     best_of: Optional[int] = None
     # Watermarking with [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226)
@@ -242,6 +270,8 @@ class Parameters(BaseModel):
     top_n_tokens: Optional[int] = None
     # grammar to use for generation
     grammar: Optional[Grammar] = None
+    # LoRA adapters applied together, each scaled by its weight
+    adapters: Optional[List[AdapterWeight]] = None
 
     @field_validator("best_of")
     def valid_best_of(cls, field_value, values):
@@ -256,6 +286,7 @@ class Parameters(BaseModel):
                 | (values.data["top_k"] is not None)
                 | (values.data["top_p"] is not None)
                 | (values.data["typical_p"] is not None)
+                | (values.data["min_p"] is not None)
             )
             if field_value > 1 and not sampling:
                 raise ValidationError("you must use sampling when `best_of` is > 1")
@@ -270,8 +301,14 @@ class Parameters(BaseModel):
 
     @field_validator("frequency_penalty")
     def valid_frequency_penalty(cls, v):
-        if v is not None and v <= 0:
-            raise ValidationError("`frequency_penalty` must be strictly positive")
+        if v is not None and (v < -2 or v > 2):
+            raise ValidationError("`frequency_penalty` must be between -2 and 2")
+        return v
+
+    @field_validator("presence_penalty")
+    def valid_presence_penalty(cls, v):
+        if v is not None and (v < -2 or v > 2):
+            raise ValidationError("`presence_penalty` must be between -2 and 2")
         return v
 
     @field_validator("seed")
@@ -310,6 +347,47 @@ class Parameters(BaseModel):
             raise ValidationError("`typical_p` must be > 0.0 and < 1.0")
         return v
 
+    @field_validator("min_p")
+    def valid_min_p(cls, v):
+        if v is not None and (v < 0 or v > 1.0):
+            raise ValidationError("`min_p` must be >= 0.0 and <= 1.0")
+        return v
+
+    @field_validator("no_repeat_ngram_size")
+    def valid_no_repeat_ngram_size(cls, v):
+        if v is not None and v <= 0:
+            raise ValidationError("`no_repeat_ngram_size` must be strictly positive")
+        return v
+
+    @field_validator("adapters")
+    def valid_adapters(cls, v):
+        if v is not None and len(v) == 0:
+            raise ValidationError("`adapters` must not be empty")
+        return v
+
+    @field_validator("guidance_scale")
+    def valid_guidance_scale(cls, v):
+        if v is not None and not 0 < v < float("inf"):
+            raise ValidationError("`guidance_scale` must be strictly positive and finite")
+        return v
+
+    @field_validator("negative_prompt")
+    def valid_negative_prompt(cls, v, values):
+        if v is not None and values.data.get("guidance_scale") is None:
+            raise ValidationError("`negative_prompt` requires `guidance_scale`")
+        return v
+
+    @field_validator("min_new_tokens")
+    def valid_min_new_tokens(cls, v, values):
+        if v is None:
+            return v
+        if v < 0:
+            raise ValidationError("`min_new_tokens` must be positive")
+        max_new_tokens = values.data.get("max_new_tokens")
+        if max_new_tokens is not None and v > max_new_tokens:
+            raise ValidationError("`min_new_tokens` must be <= `max_new_tokens`")
+        return v
+
     @field_validator("top_n_tokens")
     def valid_top_n_tokens(cls, v):
         if v is not None and v <= 0:
@@ -387,6 +465,12 @@ class FinishReason(str, Enum):
     EndOfSequenceToken = "eos_token"
     # the model generated a text included in `stop_sequences`
     StopSequence = "stop_sequence"
+    # the request exceeded its deadline
+    Timeout = "timeout"
+    # an output filter of the server flagged the generated text
+    ContentFilter = "content_filter"
+    # the client aborted the generation
+    Cancelled = "cancelled"
 
 
 # Additional sequences when using the `best_of` parameter
@@ -0,0 +1,473 @@
+//! Async client of the Text Generation Inference router HTTP API
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), text_generation_router_client::ClientError> {
+//! use futures::StreamExt;
+//! use text_generation_router_client::{Client, GenerateParameters};
+//!
+//! let client = Client::new("http://localhost:3000")?;
+//! let parameters = GenerateParameters {
+//!     max_new_tokens: Some(20),
+//!     ..Default::default()
+//! };
+//! let response = client.generate("Why is the sky blue?", &parameters).await?;
+//! println!("{}", response.generated_text);
+//!
+//! let mut stream = Box::pin(client.generate_stream("Why is the sky blue?", &parameters).await?);
+//! while let Some(response) = stream.next().await {
+//!     print!("{}", response?.token.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod sse;
+mod types;
+
+pub use types::{
+    AdapterWeight, BestOfSequence, ChatCompletion, ChatCompletionChoice, ChatCompletionChunk,
+    ChatCompletionChunkChoice, ChatCompletionDelta, ChatRequest, Details, FinishReason,
+    FunctionCall, GenerateParameters, GenerateResponse, GenerationTimings, Info, Message,
+    OutputMessage, PrefillToken, SimpleToken, StreamDetails, StreamResponse, Token, ToolCall,
+    Usage,
+};
+
+use futures::{stream, Stream};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, RETRY_AFTER};
+use reqwest::{Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sse::SseParser;
+use std::collections::VecDeque;
+use std::time::Duration;
+use thiserror::Error;
+use types::{
+    ErrorResponse, GenerateBatchRequest, GenerateBatchResult, GenerateRequest,
+    StreamingChatRequest, TokenizeRequest,
+};
+
+/// Longest delay between two attempts, whatever the number of retries. A router asking to
+/// wait longer is not retried.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Header of the requests that the router generates once, whatever the number of retries
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Invalid router URL: {0}")]
+    Url(String),
+    #[error("Request to the router failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// Error returned by the router, as a response or as an event of a stream
+    #[error("{message}")]
+    Api {
+        /// `None` for the errors sent in a stream
+        status: Option<StatusCode>,
+        /// Machine readable kind of the error, e.g. `validation`, `queue_full` or `generation`
+        error_type: String,
+        /// Request parameter responsible for the error
+        param: Option<String>,
+        message: String,
+    },
+    #[error("Unable to parse the response of the router: {0}")]
+    Deserialize(#[from] serde_json::Error),
+}
+
+/// Settings of a [`Client`]
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Sent as a bearer token
+    pub api_key: Option<String>,
+    /// Timeout of a whole request, including the streaming of its response
+    pub timeout: Option<Duration>,
+    /// Number of times a request is sent again after a connection error, or when the router
+    /// is overloaded (`429` and `503`). A used up quota is not retried.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each retry up to a minute. A `Retry-After` from
+    /// the router takes precedence, unless it is longer than a minute: the request then fails.
+    pub retry_backoff: Duration,
+    /// Send the generation requests with an `Idempotency-Key`, the same for all their retries,
+    /// so that the ones that timed out are retried too without being generated twice. Requires
+    /// the router to be started with `--idempotency-cache-size`.
+    pub idempotency_keys: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            timeout: None,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            idempotency_keys: false,
+        }
+    }
+}
+
+/// Client of a router
+#[derive(Clone, Debug)]
+pub struct Client {
+    base_url: Url,
+    client: reqwest::Client,
+    max_retries: u32,
+    retry_backoff: Duration,
+    idempotency_keys: bool,
+}
+
+impl Client {
+    /// Client of the router at `base_url`, e.g. `http://localhost:3000`
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        Self::with_config(base_url, ClientConfig::default())
+    }
+
+    pub fn with_config(base_url: &str, config: ClientConfig) -> Result<Self, ClientError> {
+        let mut base_url =
+            Url::parse(base_url).map_err(|err| ClientError::Url(format!("{base_url}: {err}")))?;
+        // Join the routes to the path of the URL, if any
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = config.api_key {
+            let mut value = HeaderValue::from_str(&format!("Bearer {api_key}"))
+                .map_err(|_| ClientError::Url("The API key is not a valid header".to_string()))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let mut client = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = config.timeout {
+            client = client.timeout(timeout);
+        }
+
+        Ok(Self {
+            base_url,
+            client: client.build()?,
+            max_retries: config.max_retries,
+            retry_backoff: config.retry_backoff,
+            idempotency_keys: config.idempotency_keys,
+        })
+    }
+
+    /// Information about the model and the limits of the router
+    pub async fn info(&self) -> Result<Info, ClientError> {
+        let response = self.send("info", None::<&()>).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Tokens of `inputs`, as tokenized by the router
+    pub async fn tokenize(&self, inputs: &str) -> Result<Vec<SimpleToken>, ClientError> {
+        let response = self
+            .send("tokenize", Some(&TokenizeRequest { inputs }))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn generate(
+        &self,
+        inputs: &str,
+        parameters: &GenerateParameters,
+    ) -> Result<GenerateResponse, ClientError> {
+        let request = GenerateRequest { inputs, parameters };
+        let response = self.send("generate", Some(&request)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Generate the `inputs` in one call. The results are in the order of the `inputs`, with the
+    /// error of each prompt that failed.
+    pub async fn generate_batch(
+        &self,
+        inputs: &[&str],
+        parameters: &GenerateParameters,
+    ) -> Result<Vec<Result<GenerateResponse, ClientError>>, ClientError> {
+        let request = GenerateBatchRequest { inputs, parameters };
+        let response = self.send("generate_batch", Some(&request)).await?;
+        let results: Vec<GenerateBatchResult> = response.json().await?;
+        Ok(results
+            .into_iter()
+            .map(|result| match result {
+                GenerateBatchResult::Generation(response) => Ok(response),
+                GenerateBatchResult::Error(error) => Err(error.into_client_error(None)),
+            })
+            .collect())
+    }
+
+    /// Stream of the generated tokens. The stream ends after the first error.
+    pub async fn generate_stream(
+        &self,
+        inputs: &str,
+        parameters: &GenerateParameters,
+    ) -> Result<impl Stream<Item = Result<StreamResponse, ClientError>>, ClientError> {
+        let request = GenerateRequest { inputs, parameters };
+        let response = self.send("generate_stream", Some(&request)).await?;
+        Ok(events(response))
+    }
+
+    /// Chat completion, through the OpenAI compatible route
+    pub async fn chat(&self, request: &ChatRequest) -> Result<ChatCompletion, ClientError> {
+        let request = StreamingChatRequest {
+            request,
+            stream: false,
+        };
+        let response = self.send("v1/chat/completions", Some(&request)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Stream of the chunks of a chat completion. The stream ends after the first error.
+    pub async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+    ) -> Result<impl Stream<Item = Result<ChatCompletionChunk, ClientError>>, ClientError> {
+        let request = StreamingChatRequest {
+            request,
+            stream: true,
+        };
+        let response = self.send("v1/chat/completions", Some(&request)).await?;
+        Ok(events(response))
+    }
+
+    /// Send a `POST` of `body`, or a `GET` without a body, and retry it while the router is
+    /// unreachable or overloaded
+    async fn send<B: Serialize>(
+        &self,
+        route: &str,
+        body: Option<&B>,
+    ) -> Result<Response, ClientError> {
+        let url = self
+            .base_url
+            .join(route)
+            .map_err(|err| ClientError::Url(err.to_string()))?;
+        let idempotency_key =
+            (self.idempotency_keys && body.is_some()).then(|| uuid::Uuid::new_v4().to_string());
+        // A request that timed out may still be generated, it is only sent again if the router
+        // can tell the retry apart
+        let retry_timeouts = body.is_none() || idempotency_key.is_some();
+        let mut attempt = 0;
+        loop {
+            let mut request = match body {
+                Some(body) => self.client.post(url.clone()).json(body),
+                None => self.client.get(url.clone()),
+            };
+            if let Some(idempotency_key) = &idempotency_key {
+                request = request.header(IDEMPOTENCY_KEY, idempotency_key);
+            }
+            let delay = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let retryable = matches!(
+                        response.status(),
+                        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+                    );
+                    if !retryable || attempt == self.max_retries {
+                        return Err(api_error(response).await);
+                    }
+                    let delay = retry_after(&response);
+                    let error = api_error(response).await;
+                    if !should_retry(&error, delay) {
+                        return Err(error);
+                    }
+                    delay
+                }
+                Err(err)
+                    if (err.is_connect() || (err.is_timeout() && retry_timeouts))
+                        && attempt < self.max_retries =>
+                {
+                    None
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let delay = delay.map_or_else(
+                || backoff(self.retry_backoff, attempt),
+                |delay| delay.min(MAX_RETRY_DELAY),
+            );
+            attempt += 1;
+            tracing::debug!("Retrying {route} in {delay:?}, attempt {attempt}");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+impl ErrorResponse {
+    fn into_client_error(self, status: Option<StatusCode>) -> ClientError {
+        ClientError::Api {
+            status,
+            error_type: self.error.error_type,
+            param: self.error.param,
+            message: self.error.message,
+        }
+    }
+}
+
+/// Delay before the retry following `attempt`, doubled at every attempt
+fn backoff(retry_backoff: Duration, attempt: u32) -> Duration {
+    retry_backoff
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Whether a request answered with a retryable `error` is sent again. A used up quota resets
+/// after hours, and so may any delay longer than `MAX_RETRY_DELAY`.
+fn should_retry(error: &ClientError, retry_after: Option<Duration>) -> bool {
+    let quota_exceeded = matches!(
+        error,
+        ClientError::Api { error_type, .. } if error_type == "quota_exceeded"
+    );
+    !quota_exceeded && retry_after.map_or(true, |retry_after| retry_after <= MAX_RETRY_DELAY)
+}
+
+/// Delay requested by the router before the next attempt
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
+async fn api_error(response: Response) -> ClientError {
+    let status = response.status();
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(err) => return err.into(),
+    };
+    match serde_json::from_slice::<ErrorResponse>(&body) {
+        Ok(error) => error.into_client_error(Some(status)),
+        // Errors of a proxy in front of the router
+        Err(_) => ClientError::Api {
+            status: Some(status),
+            error_type: "unknown".to_string(),
+            param: None,
+            message: String::from_utf8_lossy(&body).into_owned(),
+        },
+    }
+}
+
+/// Parse the data of an event: a `T`, or an error of the router
+fn parse_event<T: DeserializeOwned>(data: &str) -> Result<T, ClientError> {
+    let value: serde_json::Value = serde_json::from_str(data)?;
+    if value.get("error").is_some() {
+        let error: ErrorResponse = serde_json::from_value(value)?;
+        return Err(error.into_client_error(None));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+struct EventState {
+    response: Response,
+    parser: SseParser,
+    pending: VecDeque<String>,
+    done: bool,
+}
+
+/// Events of a streaming response, until `[DONE]`, the end of the response or an error
+fn events<T: DeserializeOwned>(response: Response) -> impl Stream<Item = Result<T, ClientError>> {
+    let state = EventState {
+        response,
+        parser: SseParser::default(),
+        pending: VecDeque::new(),
+        done: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if state.done {
+                return None;
+            }
+            if let Some(data) = state.pending.pop_front() {
+                if data == "[DONE]" {
+                    return None;
+                }
+                let event = parse_event(&data);
+                state.done = event.is_err();
+                return Some((event, state));
+            }
+            match state.response.chunk().await {
+                Ok(Some(chunk)) => state.pending.extend(state.parser.push(&chunk)),
+                Ok(None) => return None,
+                Err(err) => {
+                    state.done = true;
+                    return Some((Err(err.into()), state));
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event() {
+        let response: StreamResponse = parse_event(
+            r#"{"index": 0, "token": {"id": 1, "text": "a", "logprob": -0.5, "special": false},
+            "generated_text": null, "details": null}"#,
+        )
+        .unwrap();
+        assert_eq!(response.token.text, "a");
+
+        let error = parse_event::<StreamResponse>(
+            r#"{"error": {"type": "generation", "message": "Request failed during generation"}}"#,
+        )
+        .unwrap_err();
+        match error {
+            ClientError::Api {
+                status,
+                error_type,
+                message,
+                ..
+            } => {
+                assert!(status.is_none());
+                assert_eq!(error_type, "generation");
+                assert_eq!(message, "Request failed during generation");
+            }
+            error => panic!("Unexpected error {error:?}"),
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let retry_backoff = Duration::from_millis(500);
+        assert_eq!(backoff(retry_backoff, 0), retry_backoff);
+        assert_eq!(backoff(retry_backoff, 2), Duration::from_secs(2));
+        assert_eq!(backoff(retry_backoff, 10), MAX_RETRY_DELAY);
+        assert_eq!(backoff(retry_backoff, 40), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let error = |error_type: &str| ClientError::Api {
+            status: Some(StatusCode::TOO_MANY_REQUESTS),
+            error_type: error_type.to_string(),
+            param: None,
+            message: String::new(),
+        };
+        assert!(should_retry(&error("rate_limited"), None));
+        assert!(should_retry(&error("rate_limited"), Some(MAX_RETRY_DELAY)));
+        assert!(!should_retry(
+            &error("rate_limited"),
+            Some(MAX_RETRY_DELAY + Duration::from_secs(1))
+        ));
+        assert!(!should_retry(&error("quota_exceeded"), None));
+        assert!(!should_retry(
+            &error("quota_exceeded"),
+            Some(Duration::from_secs(1))
+        ));
+    }
+
+    #[test]
+    fn test_base_url() {
+        let client = Client::new("http://localhost:3000").unwrap();
+        assert_eq!(
+            client.base_url.join("generate").unwrap().as_str(),
+            "http://localhost:3000/generate"
+        );
+        let client = Client::new("http://localhost/tgi").unwrap();
+        assert_eq!(
+            client
+                .base_url
+                .join("v1/chat/completions")
+                .unwrap()
+                .as_str(),
+            "http://localhost/tgi/v1/chat/completions"
+        );
+        assert!(matches!(Client::new("/generate"), Err(ClientError::Url(_))));
+    }
+}
@@ -0,0 +1,68 @@
+/// Parsing of the server-sent events of the streaming routes
+
+/// Incremental parser of an event stream, fed with the chunks of the response body.
+///
+/// Only the `data` field of the events is kept: the router does not name its events. The
+/// chunks may split a line, or a UTF-8 character, anywhere.
+#[derive(Debug, Default)]
+pub(crate) struct SseParser {
+    /// Bytes of the current, incomplete, line
+    line: Vec<u8>,
+    /// Data lines of the current event
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Data of the events completed by `chunk`
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = std::mem::take(&mut self.line);
+            let line = String::from_utf8_lossy(&line);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() {
+                // A blank line dispatches the event
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(value) = line.strip_prefix("data:") {
+                self.data
+                    .push(value.strip_prefix(' ').unwrap_or(value).to_string());
+            }
+            // Comments, such as the keep-alives, and the other fields are ignored
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let mut parser = SseParser::default();
+        let events = parser.push(b"data: {\"a\": 1}\n\ndata:[DONE]\r\n\r\n");
+        assert_eq!(events, vec!["{\"a\": 1}", "[DONE]"]);
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        let mut parser = SseParser::default();
+        let body = "data: h\u{e9}llo\n\n: keep-alive\n\ndata: a\ndata: b\n\n".as_bytes();
+        let mut events = Vec::new();
+        for chunk in body.chunks(3) {
+            events.extend(parser.push(chunk));
+        }
+        assert_eq!(events, vec!["h\u{e9}llo", "a\nb"]);
+
+        // Incomplete event
+        assert!(parser.push(b"data: c\n").is_empty());
+        assert_eq!(parser.push(b"\n"), vec!["c"]);
+    }
+}
@@ -0,0 +1,376 @@
+/// Requests and responses of the router API
+use serde::{Deserialize, Serialize};
+
+/// Parameters of `generate` and `generate_stream`. The unset ones use the defaults of the
+/// router.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GenerateParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typical_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_repeat_ngram_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guidance_scale: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub do_sample: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_new_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_new_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_full_text: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_token_ids: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncate: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoder_input_details: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_n_tokens: Option<u32>,
+    /// Grammar constraining the generation, e.g. `{"type": "json", "value": {..}}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter_id: Option<String>,
+    /// LoRA adapters applied together, each scaled by its weight. Exclusive with `adapter_id`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub adapters: Vec<AdapterWeight>,
+}
+
+/// LoRA adapter of a mixture and its weight in the mixture
+#[derive(Clone, Debug, Serialize)]
+pub struct AdapterWeight {
+    pub id: String,
+    pub weight: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GenerateRequest<'a> {
+    pub inputs: &'a str,
+    pub parameters: &'a GenerateParameters,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GenerateBatchRequest<'a> {
+    pub inputs: &'a [&'a str],
+    pub parameters: &'a GenerateParameters,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum GenerateBatchResult {
+    Generation(GenerateResponse),
+    Error(ErrorResponse),
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenizeRequest<'a> {
+    pub inputs: &'a str,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PrefillToken {
+    pub id: u32,
+    pub text: String,
+    pub logprob: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Token {
+    pub id: u32,
+    pub text: String,
+    pub logprob: Option<f32>,
+    pub special: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Length,
+    #[serde(rename = "eos_token")]
+    EndOfSequenceToken,
+    StopSequence,
+    Timeout,
+    ContentFilter,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BestOfSequence {
+    pub generated_text: String,
+    pub finish_reason: FinishReason,
+    pub generated_tokens: u32,
+    pub seed: Option<u64>,
+    pub prefill: Vec<PrefillToken>,
+    pub tokens: Vec<Token>,
+    #[serde(default)]
+    pub top_tokens: Vec<Vec<Token>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Details {
+    pub finish_reason: FinishReason,
+    pub generated_tokens: u32,
+    pub seed: Option<u64>,
+    pub prefill: Vec<PrefillToken>,
+    pub tokens: Vec<Token>,
+    #[serde(default)]
+    pub best_of_sequences: Option<Vec<BestOfSequence>>,
+    #[serde(default)]
+    pub top_tokens: Vec<Vec<Token>>,
+    #[serde(default)]
+    pub timings: Option<GenerationTimings>,
+}
+
+/// Timings of a generation, and the batches it shared with the other requests
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GenerationTimings {
+    pub queue_time: f64,
+    pub prefill_time: f64,
+    pub decode_time: f64,
+    pub tokens_per_second: f64,
+    pub batches: u32,
+    pub mean_batch_size: f64,
+    pub max_batch_size: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GenerateResponse {
+    pub generated_text: String,
+    #[serde(default)]
+    pub details: Option<Details>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StreamDetails {
+    pub finish_reason: FinishReason,
+    pub generated_tokens: u32,
+    pub seed: Option<u64>,
+    pub input_length: u32,
+    #[serde(default)]
+    pub timings: Option<GenerationTimings>,
+}
+
+/// Event of `generate_stream`, one per generated token
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StreamResponse {
+    pub index: u32,
+    pub token: Token,
+    #[serde(default)]
+    pub top_tokens: Vec<Token>,
+    /// Set on the last token
+    pub generated_text: Option<String>,
+    /// Set on the last token
+    pub details: Option<StreamDetails>,
+}
+
+/// Token of a tokenized text, with its byte offsets in the text
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SimpleToken {
+    pub id: u32,
+    pub text: String,
+    pub start: usize,
+    pub stop: usize,
+    #[serde(default)]
+    pub special: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Info {
+    pub model_id: String,
+    pub model_sha: Option<String>,
+    pub model_pipeline_tag: Option<String>,
+    #[serde(default)]
+    pub served_models: Vec<String>,
+    pub max_concurrent_requests: usize,
+    pub max_best_of: usize,
+    pub max_stop_sequences: usize,
+    pub max_input_tokens: usize,
+    pub max_total_tokens: usize,
+    pub validation_workers: usize,
+    pub max_client_batch_size: usize,
+    pub router: String,
+    pub version: String,
+    pub sha: Option<String>,
+    pub docker_label: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// Request of `chat` and `chat_stream`. The unset parameters use the defaults of the router.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChatRequest {
+    /// Model serving the request, the default one of the router if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Number of choices to generate, not supported by `chat_stream`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_token_ids: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    /// Tools in the OpenAI format
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StreamingChatRequest<'a> {
+    #[serde(flatten)]
+    pub request: &'a ChatRequest,
+    pub stream: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FunctionCall {
+    pub name: Option<String>,
+    /// A JSON object in the complete messages, a fragment of it in the chunks of a stream
+    pub arguments: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+/// Message generated by the model: a text, or calls to the tools
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OutputMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatCompletionChoice {
+    pub index: u32,
+    pub message: OutputMessage,
+    #[serde(default)]
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatCompletion {
+    pub id: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub choices: Vec<ChatCompletionChoice>,
+    pub usage: Usage,
+}
+
+/// Tokens of a chunk: a text delta, or a tool call delta
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatCompletionDelta {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatCompletionChunkChoice {
+    pub index: u32,
+    pub delta: ChatCompletionDelta,
+    #[serde(default)]
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: Option<String>,
+}
+
+/// Event of `chat_stream`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub choices: Vec<ChatCompletionChunkChoice>,
+    /// Set on the last chunk
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+/// Error body of the routes, also sent as an event by the streaming routes
+#[derive(Debug, Deserialize)]
+pub(crate) struct ErrorResponse {
+    pub error: ErrorDetails,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ErrorDetails {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    #[serde(default)]
+    pub param: Option<String>,
+    pub message: String,
+}
@@ -0,0 +1,312 @@
+/// Options of the launcher read from a TOML or YAML file. The file sets the environment
+/// variables of its options, so the environment and then the command line take precedence.
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::env;
+use std::ffi::OsString;
+use std::path::Path;
+use thiserror::Error;
+
+/// Option pointing to the config file, read before the others are parsed
+const CONFIG_FILE_OPTION: &str = "config_file";
+
+/// Options whose values `--print-config` hides
+const SECRET_OPTIONS: [&str; 4] = [
+    "api_key",
+    "audit_hash_key",
+    "ngrok_authtoken",
+    "response_cache_redis_url",
+];
+
+/// Options whose values `--print-config` hides when they are webhooks, whose URLs often carry
+/// their credentials
+const WEBHOOK_OPTIONS: [&str; 1] = ["audit_log"];
+
+fn is_secret(id: &str, values: &[Cow<'_, str>]) -> bool {
+    SECRET_OPTIONS.contains(&id)
+        || (WEBHOOK_OPTIONS.contains(&id)
+            && values
+                .iter()
+                .any(|value| value.starts_with("http://") || value.starts_with("https://")))
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ConfigFileError {
+    #[error("Unable to read the config file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Config file {0} must end with `.toml`, `.yaml` or `.yml`")]
+    Format(String),
+    #[error("Invalid TOML config file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid YAML config file: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("The config file must be a table of options")]
+    NotATable,
+    #[error("Unknown option `{0}` in the config file")]
+    UnknownOption(String),
+    #[error("Invalid value of `{0}` in the config file: {1}")]
+    InvalidValue(String, String),
+}
+
+/// Set the environment variables of the options of the config file, if any, that the
+/// environment does not set already. Returns the ids of the options set from the file.
+pub(crate) fn load(command: &Command) -> Result<Vec<String>, ConfigFileError> {
+    let Some(path) = path(env::args_os().skip(1), command) else {
+        return Ok(Vec::new());
+    };
+    let content =
+        std::fs::read_to_string(&path).map_err(|err| ConfigFileError::Read(path.clone(), err))?;
+    let options = match Path::new(&path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str::<Value>(&content)?,
+        Some("yaml" | "yml") => serde_yaml::from_str::<Value>(&content)?,
+        _ => return Err(ConfigFileError::Format(path)),
+    };
+
+    let mut loaded = Vec::new();
+    for (id, env_name, value) in env_values(command, options)? {
+        if env::var_os(&env_name).is_none() {
+            env::set_var(&env_name, value);
+            loaded.push(id);
+        }
+    }
+    Ok(loaded)
+}
+
+/// Path of the config file, from the command line or its environment variable
+fn path(args: impl Iterator<Item = OsString>, command: &Command) -> Option<String> {
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_id() == CONFIG_FILE_OPTION)?;
+    let flag = format!("--{}", arg.get_long()?);
+    let mut args = args.map(|arg| arg.to_string_lossy().into_owned());
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix(&format!("{flag}=")) {
+            return Some(path.to_string());
+        }
+    }
+    env::var(arg.get_env()?).ok()
+}
+
+/// `(id, environment variable, value)` of the options of the file
+fn env_values(
+    command: &Command,
+    options: Value,
+) -> Result<Vec<(String, String, String)>, ConfigFileError> {
+    let Value::Object(options) = options else {
+        return Err(ConfigFileError::NotATable);
+    };
+    let mut values = Vec::new();
+    for (key, value) in options {
+        // `max-batch-size` as on the command line, or `max_batch_size`
+        let id = key.replace('-', "_");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && id != CONFIG_FILE_OPTION);
+        let Some((arg, env_name)) = arg.and_then(|arg| Some((arg, arg.get_env()?))) else {
+            return Err(ConfigFileError::UnknownOption(key));
+        };
+        if let Some(value) = env_value(arg, &value)
+            .map_err(|err| ConfigFileError::InvalidValue(key.clone(), err.to_string()))?
+        {
+            values.push((id, env_name.to_string_lossy().into_owned(), value));
+        }
+    }
+    Ok(values)
+}
+
+/// Value of the environment variable of `arg`, None to leave it unset
+fn env_value(arg: &Arg, value: &Value) -> Result<Option<String>, &'static str> {
+    let scalar = |value: &Value| match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Number(value) => Ok(value.to_string()),
+        _ => Err("expected a string, a number or a boolean"),
+    };
+    match value {
+        Value::Null => Ok(None),
+        Value::Array(values) => {
+            let values = values.iter().map(scalar).collect::<Result<Vec<_>, _>>()?;
+            match arg.get_value_delimiter() {
+                Some(delimiter) => Ok(Some(values.join(&delimiter.to_string()))),
+                None if values.len() <= 1 => Ok(values.into_iter().next()),
+                None => Err("the option takes a single value"),
+            }
+        }
+        value => scalar(value).map(Some),
+    }
+}
+
+/// The resolved options, in the TOML format of the config file, with the source of each
+pub(crate) fn resolved(command: &Command, matches: &ArgMatches, loaded: &[String]) -> String {
+    let mut config = String::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if arg.get_env().is_none() || id == CONFIG_FILE_OPTION {
+            continue;
+        }
+        let Some(values) = matches.get_raw(id) else {
+            continue;
+        };
+        let values: Vec<_> = values.map(|value| value.to_string_lossy()).collect();
+        let value = if is_secret(id, &values) {
+            toml_value("<redacted>")
+        } else if values.len() == 1 {
+            toml_value(&values[0])
+        } else {
+            let values: Vec<_> = values.iter().map(|value| toml_value(value)).collect();
+            format!("[{}]", values.join(", "))
+        };
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "command line",
+            Some(ValueSource::EnvVariable) if loaded.iter().any(|loaded| loaded == id) => {
+                "config file"
+            }
+            Some(ValueSource::EnvVariable) => "environment",
+            _ => "default",
+        };
+        config.push_str(&format!("{id} = {value} # {source}\n"));
+    }
+    config
+}
+
+/// `value` as a TOML boolean, number or string
+fn toml_value(value: &str) -> String {
+    if value.parse::<bool>().is_ok() || value.parse::<i64>().is_ok() {
+        return value.to_string();
+    }
+    match value.parse::<f64>() {
+        Ok(number) if number.is_finite() && value.contains('.') => value.to_string(),
+        _ => toml::Value::String(value.to_string()).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("launcher")
+            .arg(
+                Arg::new("config_file")
+                    .long("config-file")
+                    .env("CONFIG_FILE"),
+            )
+            .arg(Arg::new("model_id").long("model-id").env("MODEL_ID"))
+            .arg(
+                Arg::new("cuda_graphs")
+                    .long("cuda-graphs")
+                    .env("CUDA_GRAPHS")
+                    .value_delimiter(','),
+            )
+            .arg(
+                Arg::new("max_batch_size")
+                    .long("max-batch-size")
+                    .env("MAX_BATCH_SIZE"),
+            )
+            .arg(Arg::new("env").long("env"))
+            .arg(Arg::new("api_key").long("api-key").env("API_KEY"))
+            .arg(Arg::new("audit_log").long("audit-log").env("AUDIT_LOG"))
+    }
+
+    #[test]
+    fn test_config_file_path() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            path(args(&["--config-file", "a.toml"]).into_iter(), &command()),
+            Some("a.toml".to_string())
+        );
+        assert_eq!(
+            path(args(&["--config-file=b.yaml"]).into_iter(), &command()),
+            Some("b.yaml".to_string())
+        );
+    }
+
+    #[test]
+    fn test_config_file_env_values() {
+        let options: Value = toml::from_str(
+            r#"
+            model-id = "bigscience/bloom"
+            cuda_graphs = [1, 2, 4]
+            max_batch_size = 32
+            "#,
+        )
+        .unwrap();
+        let mut values = env_values(&command(), options).unwrap();
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                (
+                    "cuda_graphs".to_string(),
+                    "CUDA_GRAPHS".to_string(),
+                    "1,2,4".to_string()
+                ),
+                (
+                    "max_batch_size".to_string(),
+                    "MAX_BATCH_SIZE".to_string(),
+                    "32".to_string()
+                ),
+                (
+                    "model_id".to_string(),
+                    "MODEL_ID".to_string(),
+                    "bigscience/bloom".to_string()
+                ),
+            ]
+        );
+
+        let options: Value = serde_yaml::from_str("max_batch_size: 32\nunknown: 1").unwrap();
+        assert!(matches!(
+            env_values(&command(), options),
+            Err(ConfigFileError::UnknownOption(_))
+        ));
+        // Without an environment variable
+        let options: Value = serde_yaml::from_str("env: true").unwrap();
+        assert!(matches!(
+            env_values(&command(), options),
+            Err(ConfigFileError::UnknownOption(_))
+        ));
+        let options: Value = serde_yaml::from_str("model_id: [a, b]").unwrap();
+        assert!(matches!(
+            env_values(&command(), options),
+            Err(ConfigFileError::InvalidValue(..))
+        ));
+    }
+
+    #[test]
+    fn test_resolved_config() {
+        let matches = command()
+            .try_get_matches_from(["launcher", "--model-id", "gpt2", "--cuda-graphs", "1,2"])
+            .unwrap();
+        assert_eq!(
+            resolved(&command(), &matches, &[]),
+            "model_id = \"gpt2\" # command line\ncuda_graphs = [1, 2] # command line\n"
+        );
+
+        // The secrets are not printed
+        let matches = command()
+            .try_get_matches_from([
+                "launcher",
+                "--api-key",
+                "secret",
+                "--audit-log",
+                "https://hooks.example.com/services/secret",
+            ])
+            .unwrap();
+        assert_eq!(
+            resolved(&command(), &matches, &[]),
+            "api_key = \"<redacted>\" # command line\naudit_log = \"<redacted>\" # command line\n"
+        );
+        let matches = command()
+            .try_get_matches_from(["launcher", "--audit-log", "/var/log/tgi/audit.jsonl"])
+            .unwrap();
+        assert_eq!(
+            resolved(&command(), &matches, &[]),
+            "audit_log = \"/var/log/tgi/audit.jsonl\" # command line\n"
+        );
+    }
+}
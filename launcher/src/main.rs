@@ -1,4 +1,4 @@
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
 use hf_hub::{
     api::sync::{Api, ApiBuilder},
     Repo, RepoType,
@@ -6,16 +6,19 @@ use hf_hub::{
 use nix::sys::signal::{self, Signal};
 use nix::unistd::Pid;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::OsString;
 use std::io::{BufRead, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::Path;
 use std::process::{Child, Command, ExitStatus, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::TryRecvError;
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
@@ -26,6 +29,7 @@ use std::{
 use thiserror::Error;
 use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
+mod config_file;
 mod env_runtime;
 mod gpu;
 
@@ -520,6 +524,29 @@ impl std::fmt::Display for RopeScaling {
     }
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SchedulingPolicy {
+    /// By priority, then by tenant share and arrival order
+    Priority,
+    /// As `priority`, except that the requests that waited for more than half of the time
+    /// before their `deadline_ms` go first, earliest deadline first
+    Edf,
+}
+
+impl std::fmt::Display for SchedulingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // To keep in track with the router
+        match self {
+            SchedulingPolicy::Priority => {
+                write!(f, "priority")
+            }
+            SchedulingPolicy::Edf => {
+                write!(f, "edf")
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, ValueEnum)]
 pub enum UsageStatsLevel {
     /// Default option, usage statistics are collected anonymously
@@ -548,7 +575,7 @@ impl std::fmt::Display for UsageStatsLevel {
 }
 
 /// App Configuration
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     /// The name of the model to load.
@@ -565,7 +592,8 @@ struct Args {
     revision: Option<String>,
 
     /// The number of tokenizer workers used for payload validation and truncation inside the
-    /// router.
+    /// router. Each worker has its own tokenizer and takes the next request of a queue of 64
+    /// requests per worker, shared by all of them.
     #[clap(default_value = "2", long, env)]
     validation_workers: usize,
 
@@ -597,6 +625,13 @@ struct Args {
     #[clap(long, env)]
     speculate: Option<usize>,
 
+    /// The longest n-gram the router looks up in the prompt and the generated tokens
+    /// to draft the speculative tokens of the requests. Replaces the n-gram speculation
+    /// of the shards, which only matches the last token. Ignored when the model has
+    /// medusa or mlp speculator heads.
+    #[clap(long, env)]
+    prompt_lookup_max_ngram: Option<usize>,
+
     /// The dtype to be forced upon the model. This option cannot be used with `--quantize`.
     #[clap(long, env, value_enum)]
     dtype: Option<Dtype>,
@@ -607,6 +642,12 @@ struct Args {
     #[clap(long, env, value_enum)]
     kv_cache_dtype: Option<KVCacheDtype>,
 
+    /// Host memory, in GiB per shard, used to keep the KV cache blocks of the requests preempted
+    /// with `--preemption-queue-size`. Their next round copies the blocks back to the GPU
+    /// instead of computing them again. Requires prefix caching.
+    #[clap(long, env)]
+    kv_cache_swap_space: Option<f32>,
+
     /// Whether you want to execute hub modelling code. Explicitly passing a `revision` is
     /// encouraged when loading a model with custom code to ensure no malicious code has been
     /// contributed in a newer revision.
@@ -630,7 +671,7 @@ struct Args {
     /// the EOS token, and enable more complex "prompting" where users can preprompt
     /// the model in a specific way and define their "own" stop token aligned with
     /// their prompt.
-    #[clap(default_value = "4", long, env)]
+    #[clap(default_value = "16", long, env)]
     max_stop_sequences: usize,
 
     /// This is the maximum allowed value for clients to set `top_n_tokens`.
@@ -645,7 +686,8 @@ struct Args {
     /// for users. The larger this value, the longer prompt users can send which
     /// can impact the overall memory required to handle the load.
     /// Please note that some models have a finite range of sequence they can handle.
-    /// Default to min(max_allocatable, max_position_embeddings) - 1
+    /// Default to `max_total_tokens` minus an eighth of it, leaving at most 2048 tokens to
+    /// the generation, and to at most `max_batch_prefill_tokens` without prefill chunking
     #[clap(long, env)]
     max_input_tokens: Option<usize>,
 
@@ -661,7 +703,7 @@ struct Args {
     /// `1511` max_new_tokens.
     /// The larger this value, the larger amount each request will be in your RAM
     /// and the less effective batching can be.
-    /// Default to min(max_allocatable, max_position_embeddings)
+    /// Default to the context length of the model, reduced to fit in the KV cache
     #[clap(long, env)]
     max_total_tokens: Option<usize>,
 
@@ -678,6 +720,21 @@ struct Args {
     #[clap(default_value = "0.3", long, env)]
     waiting_served_ratio: f32,
 
+    /// The target p95 time to first token, in milliseconds. When set, the scheduler lowers
+    /// the ratio of waiting queries vs running queries while the p95 time to first token of
+    /// the recent queries is above the target, to prefill the waiting queries sooner, and
+    /// raises it back up to `waiting_served_ratio` once it is well below.
+    /// Ignored by the models that support prefill chunking.
+    #[clap(long, env)]
+    target_ttft_ms: Option<u64>,
+
+    /// The target p95 latency of a decode step, in milliseconds. When set, the scheduler
+    /// lowers the number of running requests it admits in a batch while the p95 latency of the
+    /// recent decode steps is above the target, and raises it back up to `max_batch_size` once
+    /// it is well below.
+    #[clap(long, env)]
+    target_decode_latency_ms: Option<u64>,
+
     /// Limits the number of tokens for the prefill operation.
     /// Since this operation take the most memory and is compute bound, it is interesting
     /// to limit the number of requests that can be sent.
@@ -744,6 +801,11 @@ struct Args {
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
 
+    /// The port of the gRPC API of the router, serving the generate and chat
+    /// routes next to the HTTP API. Disabled if unset.
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
+
     /// The name of the socket for gRPC communication between the webserver
     /// and the shards.
     #[clap(default_value = "/tmp/text-generation-server", long, env)]
@@ -774,6 +836,12 @@ struct Args {
     #[clap(long, env)]
     disable_custom_kernels: bool,
 
+    /// Force the shards to use deterministic CUDA kernels. The tokens sampled with a seed
+    /// already do not depend on the batch, but the logits they are sampled from can vary
+    /// with the kernels picked for the size of the batch. Slower.
+    #[clap(long, env)]
+    deterministic: bool,
+
     /// Limit the CUDA available memory.
     /// The allowed value equals the total visible memory multiplied by cuda-memory-fraction.
     #[clap(default_value = "1.0", long, env)]
@@ -815,6 +883,25 @@ struct Args {
     #[clap(long, env)]
     api_key: Option<String>,
 
+    /// Path to a JSON file with the API keys allowed to call the router, in the form
+    /// `{"keys": {"<key>": {"scopes": ["generate", "admin", "metrics"], "models": ["<model>"]}}}`.
+    /// The `generate` scope gives access to the generation routes, `admin` to `/admin/*`,
+    /// `/drain` and `/metrics/sessions`, and `metrics` to the other `/metrics*` routes, which are
+    /// only protected when this file is given.
+    /// Without `models`, a key can use all the served models. A key can also have a `name`, the
+    /// one of its usage on `/usage` and `/metrics`, and a `quota` of `daily_tokens` and
+    /// `monthly_tokens` after which its requests are refused until the next UTC day or month.
+    /// `--api-key` is added with all the scopes. Without any key, `/drain` and `/metrics/sessions`
+    /// are only open to the local clients.
+    #[clap(long, env)]
+    api_keys_path: Option<String>,
+
+    /// Path to a file where the router saves the token usage of the API keys, every 10 seconds
+    /// and when it stops, and restores it from when it starts. Without it, the usage and the
+    /// quotas restart from zero with the router. The file can only be used by one router.
+    #[clap(long, env)]
+    usage_path: Option<String>,
+
     #[clap(long, env)]
     watermark_gamma: Option<f32>,
     #[clap(long, env)]
@@ -837,6 +924,12 @@ struct Args {
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
 
+    /// The path to a Jinja chat template. It replaces the `chat_template` of the model
+    /// to render the messages of the chat endpoint, with the same variables
+    /// (`messages`, `tools`, `bos_token`, `eos_token`, `add_generation_prompt`...).
+    #[clap(long, env)]
+    chat_template_path: Option<String>,
+
     /// Disable outlines grammar constrained generation.
     /// This is a feature that allows you to generate text that follows a specific grammar.
     #[clap(long, env)]
@@ -846,7 +939,8 @@ struct Args {
     #[clap(long, short, action)]
     env: bool,
 
-    /// Control the maximum number of inputs that a client can send in a single request
+    /// Control the maximum number of inputs that a client can send in a single request.
+    /// It also bounds the number of choices `n` of the OpenAI routes, for all their prompts.
     #[clap(default_value = "4", long, env)]
     max_client_batch_size: usize,
 
@@ -867,13 +961,341 @@ struct Args {
     #[clap(default_value = "2000000", long, env)]
     payload_limit: usize,
 
-    /// Enables prefill logprobs
+    /// Time in seconds during which the KV cache of an idle session is kept
+    /// for the next request of the session.
+    ///
+    /// Sessions are only kept when prefix caching is enabled.
+    #[clap(default_value = "300", long, env)]
+    session_ttl: u64,
+
+    /// Time in seconds without any allocation or release of KV cache blocks after which
+    /// the router compacts its block allocator: the free blocks are sorted, the idle
+    /// sessions are released and, with `prefix_cache_ttl`, the stale prefixes are evicted.
+    /// Never compacted if unset.
+    #[clap(long, env)]
+    kv_compaction_interval: Option<u64>,
+
+    /// Time in seconds after which a prefix cache entry that was not used is released by
+    /// the compactions. Without it, prefixes are only evicted to make room for new requests.
+    #[clap(long, env)]
+    prefix_cache_ttl: Option<u64>,
+
+    /// The maximum share of `max_batch_total_tokens`, between 0 and 1, a single request may
+    /// hold in a scheduling round, prompt included. A request needing more generates its
+    /// tokens over several rounds and is queued again between them, so long generations
+    /// cannot take over the batch.
+    #[clap(long, env)]
+    max_request_token_share: Option<f32>,
+
+    /// The number of queued requests from which the requests continued in a new round
+    /// are queued behind the new requests of the same priority.
+    #[clap(long, env)]
+    fairness_queue_depth: Option<usize>,
+
+    /// The number of queued requests from which the longest running request is preempted
+    /// when the waiting requests do not fit in the batch. It ends its round at its next
+    /// token and is queued again, with the KV of its tokens kept in the prefix cache.
+    #[clap(long, env)]
+    preemption_queue_size: Option<usize>,
+
+    /// Share the queue between tenants instead of serving the requests of a priority in
+    /// arrival order, so that a tenant sending many requests only delays its own.
+    /// Implied by `--tenant-weight`.
+    #[clap(long, env)]
+    tenant_fair_share: bool,
+
+    /// The weight of a tenant in the queue, as `<TENANT>=<WEIGHT>`. A backlogged tenant
+    /// gets a share of the dequeued requests proportional to its weight. Tenants that
+    /// are not listed have a weight of 1.
+    #[clap(long, env, value_delimiter = ';')]
+    tenant_weight: Vec<String>,
+
+    /// The order in which the queued requests are added to the batches, `priority` by default.
+    /// `edf` lets the requests whose `deadline_ms` is at risk go first.
+    #[clap(long, env)]
+    scheduling_policy: Option<SchedulingPolicy>,
+
+    /// The number of prompt tokens below which a prompt is short. With
+    /// `short_prompt_prefill_share`, a share of the prefill budget of every batch is kept for
+    /// the short prompts, which then get their first token quickly even behind a flood of long
+    /// prompts. The long prompts that do not fit in the rest of the budget wait for the next
+    /// batches, in their order. A long prompt that cannot be chunked uses the whole budget once
+    /// it reaches the front of the queue, and the long prompts share it when no short prompt is
+    /// waiting.
+    #[clap(long, env, requires = "short_prompt_prefill_share")]
+    short_prompt_tokens: Option<u32>,
+
+    /// The share of the prefill budget of every batch, between 0 and 1, that only the prompts
+    /// shorter than `short_prompt_tokens` can use.
+    #[clap(long, env, requires = "short_prompt_tokens")]
+    short_prompt_prefill_share: Option<f32>,
+
+    /// The header naming the tenant of a request, e.g. `x-tenant-id`, when the routes are not
+    /// authenticated. With API keys, the tenant of a request is the name of its key.
+    #[clap(long, env)]
+    tenant_header: Option<String>,
+
+    /// The number of responses kept in memory by the response cache. Requests that are
+    /// greedy, or sample with a `seed`, are answered from the cache when an identical request
+    /// of the same tenant was already generated by the same model and revision. The cached
+    /// responses still count towards the rate limits and quotas of the clients. The least
+    /// recently used responses are evicted first. Only the non-streaming routes use the cache.
+    #[clap(long, env)]
+    response_cache_size: Option<usize>,
+
+    /// The number of seconds a response stays in the response cache.
+    #[clap(default_value = "3600", long, env)]
+    response_cache_ttl: u64,
+
+    /// The URL of a Redis server storing the responses of the response cache instead of the
+    /// router memory, e.g. `redis://:<password>@cache:6379/0`, so that routers can share them.
+    /// Redis evicts the responses following its `maxmemory-policy`.
+    #[clap(long, env)]
+    response_cache_redis_url: Option<String>,
+
+    /// The number of responses kept for the requests with an `Idempotency-Key` header. A
+    /// request sent again with the same key, while the first one runs or after it completed,
+    /// gets the response or the stream of the first one instead of being generated twice.
+    /// Reusing a key for a different request is refused with a `422`. The responses take up to
+    /// 256MB, and a request is cancelled once the first one and all its retries disconnected.
+    #[clap(long, env)]
+    idempotency_cache_size: Option<usize>,
+
+    /// The number of seconds the completed responses of the idempotency keys are kept.
+    #[clap(default_value = "3600", long, env)]
+    idempotency_ttl: u64,
+
+    /// The number of latest events kept per SSE stream, to be sent again to the clients that
+    /// reconnect. The events of the streams are numbered, and a client sending the request again
+    /// with the `x-request-id` of its stream and a `Last-Event-ID` header gets the events after
+    /// that one, then follows the stream. The generations go on while their client reconnects.
+    /// Without API keys, only the streams whose `x-request-id` the server generated can be
+    /// resumed.
+    #[clap(long, env)]
+    stream_resume_events: Option<usize>,
+
+    /// The number of seconds a resumable stream keeps generating without any client, and is kept
+    /// for once it ended.
+    #[clap(default_value = "30", long, env)]
+    stream_resume_timeout: u64,
+
+    /// Prompt templates that the requests to `/generate` and `/generate_stream` can name with
+    /// `template`, with their `variables`, instead of sending their `inputs`. Either a
+    /// directory of `<name>.jinja` files, or a JSON file of
+    /// `{"<name>": {"template": "...", "version": "..."}}`. The name and version of the
+    /// template are returned in the details of the response.
+    #[clap(long, env)]
+    prompt_templates_path: Option<String>,
+
+    /// The maximum number of images of a request to a vision-language model.
+    #[clap(long, env)]
+    max_images_per_request: Option<usize>,
+
+    /// The images of the requests with more pixels (width times height) are downscaled by the
+    /// router to this number of pixels, keeping their aspect ratio, before the image tokens of
+    /// the prompt are counted and the images are sent to the shards. The images with more than
+    /// 16 times this number of pixels are refused before they are decoded.
+    #[clap(long, env)]
+    max_image_pixels: Option<usize>,
+
+    /// Start even when the number of tokens of the tokenizer cannot match the number of
+    /// embeddings of the model. By default, the router fails at startup, as the prompts may
+    /// have token ids the model does not have and the generated token ids may not be decoded.
+    #[clap(long, env)]
+    allow_vocab_mismatch: bool,
+
+    /// Where the router records every generation: `stdout`, a file path to append JSON
+    /// lines to, or an `http://` or `https://` webhook receiving one JSON `POST` per record.
+    /// A record has the request parameters, a hash of the prompt and of the tenant, the token
+    /// counts, the latencies and the finish reason or error of the generation.
+    #[clap(long, env)]
+    audit_log: Option<String>,
+
+    /// The fraction of the generations recorded in the audit log, from 0 to 1.
+    #[clap(default_value = "1.0", long, env)]
+    audit_sample_rate: f64,
+
+    /// The number of characters at the start of the prompts kept in the audit log.
+    #[clap(default_value = "256", long, env)]
+    audit_prompt_length: usize,
+
+    /// Keep only the hash of the prompts in the audit log, for deployments where prompts may
+    /// hold personal data.
+    #[clap(long, env)]
+    audit_hash_prompts: bool,
+
+    /// The secret key of the HMAC hashes of the prompts and of the tenants in the audit log, so
+    /// that they cannot be recovered by hashing guesses. Without it, a random key is drawn at
+    /// startup and the hashes of two runs cannot be compared.
+    #[clap(long, env)]
+    audit_hash_key: Option<String>,
+
+    /// Path of a JSON file with the keys of the watermarked generations, of the form
+    /// `{"active": "2024-06", "keys": [{"id": "2024-06", "key": 1234}]}`. The requests with
+    /// `watermark` use the active key, and `/watermark/verify` scores the texts with all the
+    /// keys. To rotate the keys, edit the file and call `/admin/watermark/keys/reload`.
+    #[clap(long, env)]
+    watermark_keys_path: Option<String>,
+
+    /// Path of a JSON file with the filters of the generated texts, of the form
+    /// `{"deny": ["(?i)\\bpassword:"], "callout": {"url": "http://moderation/check"},
+    /// "holdback_tokens": 8}`. The streamed tokens are held back until the filters checked
+    /// them, and the generations they flag end with the `content_filter` finish reason. The
+    /// generations also end when the callout fails, unless it sets `"fail_open": true`.
+    #[clap(long, env)]
+    content_filter_path: Option<String>,
+
+    /// Path of a JSON file with the transformations of the prompts and of the generated
+    /// texts per model, of the form `{"my-model": [{"name": "strip_reasoning"},
+    /// {"name": "replace_output", "from": "<|im_end|>"}]}`. The `"*"` entry applies to the
+    /// models without their own. The transforms are `strip_reasoning` (`start`, `end` and
+    /// `started` options), `replace_prompt` and `replace_output` (`from` and `to` options).
+    #[clap(long, env)]
+    transforms_path: Option<String>,
+
+    /// The `max_new_tokens` of the requests that do not set it. By default, they generate
+    /// up to `max_total_tokens`.
+    #[clap(long, env)]
+    default_max_new_tokens: Option<u32>,
+
+    /// The largest `max_new_tokens` of a request. Larger values are lowered to this ceiling,
+    /// with a warning in the `warnings` of the response.
+    #[clap(long, env)]
+    max_new_tokens_ceiling: Option<u32>,
+
+    /// The largest `max_new_tokens` of a streamed request, `max_new_tokens_ceiling` by default.
+    #[clap(long, env)]
+    stream_max_new_tokens_ceiling: Option<u32>,
+
+    /// Path of a JSON file with the `max_new_tokens` limits per model, of the form
+    /// `{"default": {"default": 256, "ceiling": 2048}, "models": {"my-adapter":
+    /// {"stream_ceiling": 4096}}}`. The models are the `served_models` of `/info` or the
+    /// adapter ids, and their unset limits are the default ones. The CLI limits take
+    /// precedence over the default ones of the file.
+    #[clap(long, env)]
+    max_new_tokens_config_path: Option<String>,
+
+    /// The maximum number of requests waiting for their first token. Past this
+    /// point, new requests are rejected with a `429` status code and a `Retry-After`
+    /// header derived from the current decode throughput.
+    #[clap(long, env)]
+    max_queue_size: Option<usize>,
+
+    /// The maximum estimated time in seconds a new request would wait in the queue.
+    /// The wait is estimated from the `max_new_tokens` of the queued requests and the
+    /// current decode throughput. Past this point, new requests are rejected with a
+    /// `429` status code and a `Retry-After` header.
+    #[clap(long, env)]
+    max_queue_wait: Option<u64>,
+
+    /// The interval in seconds between two generations run through the queue and the
+    /// shards to check that the model still answers. `/health` then returns the result
+    /// of the last one, and a generation taking longer than the interval fails it. The
+    /// generations are queued ahead of the requests of the normal and low priorities.
+    #[clap(long, env)]
+    health_check_interval: Option<u64>,
+
+    /// The interval in seconds between two `: ping` comments sent on the streams, 15 by
+    /// default. Until their first token, the streaming requests also receive a
+    /// `: queue_position <n>` comment at this interval.
+    #[clap(long, env)]
+    stream_heartbeat_interval: Option<u64>,
+
+    /// The directory of a journal of the requests waiting in the queue. Requests that did not
+    /// start when the router crashed or restarted are generated again on the next start, and
+    /// their outcomes are appended to `replayed.jsonl` in this directory.
+    #[clap(long, env)]
+    queue_journal_path: Option<String>,
+
+    /// Another model to serve next to `model_id`, as `<NAME>=<MASTER_SHARD_UDS_PATH>`, whose
+    /// shards are started separately. Requests select it with the `model` field of the OpenAI
+    /// routes or with the `adapter_id` parameter. `<NAME>` is the Hub id or the local path of
+    /// its tokenizer, and its requests are validated within the limits of its own shards.
+    /// Repeat the same name for every replica of a model.
+    #[clap(long, env, value_delimiter = ';')]
+    served_model: Vec<String>,
+
+    /// The master shard of another shard-set of `model_id`, started separately, that computes
+    /// the prompts before the shards of this launcher decode them. The KV of the prompts is
+    /// copied between the shards of the same rank, so both sets must use the same sharding
+    /// and block size, and this one needs prefix caching.
+    #[clap(long, env)]
+    prefill_shard_uds_path: Option<String>,
+
+    /// The master shard of a small draft model, started separately with the tokenizer of
+    /// `model_id` and without `--speculate`, that proposes the speculative tokens verified by
+    /// the shards of this launcher, which need `--speculate`. Its KV cache uses the blocks of
+    /// this one, so it needs the same block size, the same prefill chunking and room for at least
+    /// as many tokens.
+    #[clap(long, env)]
+    draft_shard_uds_path: Option<String>,
+
+    /// The number of requests per second allowed for each API key, with bursts of up to one
+    /// second of requests. Past this rate, requests are rejected with a `429` status code
+    /// and `x-ratelimit-*` headers. Clients are identified by the name of their API key, or by
+    /// their address when the routes do not need a key.
+    #[clap(long, env)]
+    rate_limit_requests: Option<f64>,
+
+    /// The number of generated tokens per minute allowed for each API key. A client over
+    /// its budget is rejected with a `429` status code until its tokens are paid back.
+    #[clap(long, env)]
+    rate_limit_tokens: Option<u32>,
+
+    /// The number of requests that each API key can have in flight, including the streams
+    /// still being sent. Past this number, requests are rejected with a `429` status code,
+    /// regardless of the capacity of the queue.
+    #[clap(long, env)]
+    rate_limit_concurrent_requests: Option<u32>,
+
+    /// Path to a JSON file with the rate limits of specific API keys, in the form
+    /// `{"default": {"requests_per_second": 10}, "keys": {"<name>": {"tokens_per_minute": 1000}}}`,
+    /// by the `name` of the keys of `--api-keys-path`.
+    /// `--rate-limit-requests`, `--rate-limit-tokens` and `--rate-limit-concurrent-requests`
+    /// override the `default` limits, which also accept `max_concurrent_requests`.
+    #[clap(long, env)]
+    rate_limit_config_path: Option<String>,
+
+    /// The number of times the model warmup is retried, with exponential backoff,
+    /// when the shards run out of memory. If the warmup still fails and
+    /// `max_batch_total_tokens` is set, the shards skip memory probing and allocate
+    /// the KV cache for `max_batch_total_tokens` tokens instead. Any other warmup
+    /// error stops the server right away.
+    #[clap(default_value = "3", long, env)]
+    warmup_retries: u32,
+
+    /// A shape of the traffic to run after the warmup, as
+    /// `<INPUT_TOKENS>:<OUTPUT_TOKENS>:<BATCH_SIZE>`, so that the kernels and CUDA graphs
+    /// it uses are ready before the first requests. Shapes that exceed the limits of the
+    /// model are skipped. Unless they are disabled, CUDA graphs are also captured for the
+    /// batch sizes of the shapes.
+    #[clap(long, env, value_delimiter = ',')]
+    warmup_shape: Vec<String>,
+
+    /// Let the router start shard-sets of other revisions of the model for
+    /// `POST /admin/model/swap`, and stop the ones it no longer uses. The router asks for them
+    /// on the `{shard_uds_path}-launcher` socket, which only the user of the launcher can
+    /// connect to. Without it, the model can only be swapped for shards started separately.
+    #[clap(long, env)]
+    enable_model_swap: bool,
+
+    /// Deprecated, has no effect
     ///
-    /// Logprobs in the prompt are deactivated by default because they consume
-    /// a large amount of VRAM (especially for long prompts).
-    /// Using this flag reallows users to ask for them.
+    /// The logprobs of the prompt are computed for the requests that ask for them with
+    /// `decoder_input_details`, and only for those, so they no longer need to be enabled.
     #[clap(long, env)]
     enable_prefill_logprobs: bool,
+
+    /// A TOML (`.toml`) or YAML (`.yaml`) file setting any of these options by name, e.g.
+    /// `max_batch_size = 32`. The file sets the environment variables of its options, so the
+    /// environment variables and then the command line take precedence over it.
+    #[clap(long, env)]
+    config_file: Option<String>,
+
+    /// Print the resolved options, in the format of `--config-file`, and exit. The API key, the
+    /// audit hash key, the ngrok token, the Redis URL and the webhook URLs are redacted.
+    #[clap(long)]
+    print_config: bool,
 }
 
 #[derive(Debug)]
@@ -890,6 +1312,7 @@ fn shard_manager(
     speculate: Option<usize>,
     dtype: Option<Dtype>,
     kv_cache_dtype: Option<KVCacheDtype>,
+    kv_cache_swap_space: Option<f32>,
     trust_remote_code: bool,
     uds_path: String,
     rank: usize,
@@ -899,6 +1322,7 @@ fn shard_manager(
     huggingface_hub_cache: Option<String>,
     weights_cache_override: Option<String>,
     disable_custom_kernels: bool,
+    deterministic: bool,
     watermark_gamma: Option<f32>,
     watermark_delta: Option<f32>,
     cuda_graphs: Vec<usize>,
@@ -909,10 +1333,10 @@ fn shard_manager(
     max_batch_size: Option<usize>,
     max_input_tokens: Option<usize>,
     lora_adapters: Option<String>,
-    enable_prefill_logprobs: bool,
     otlp_endpoint: Option<String>,
     otlp_service_name: String,
     log_level: LevelFilter,
+    cuda_visible_devices: Option<String>,
     status_sender: mpsc::Sender<ShardStatus>,
     shutdown: Arc<AtomicBool>,
     _shutdown_sender: mpsc::Sender<()>,
@@ -1011,6 +1435,12 @@ fn shard_manager(
     envs.push(("MASTER_PORT".into(), master_port.to_string().into()));
     envs.push(("TORCH_NCCL_AVOID_RECORD_STREAMS".into(), "1".into()));
 
+    // The GPUs of a shard-set started by the router, next to the ones of the current shard-set
+    if let Some(cuda_visible_devices) = cuda_visible_devices {
+        envs.retain(|(name, _)| name != "CUDA_VISIBLE_DEVICES");
+        envs.push(("CUDA_VISIBLE_DEVICES".into(), cuda_visible_devices.into()));
+    }
+
     // CUDA memory fraction
     envs.push((
         "CUDA_MEMORY_FRACTION".into(),
@@ -1053,17 +1483,18 @@ fn shard_manager(
     if let Some(max_batch_size) = max_batch_size {
         envs.push(("MAX_BATCH_SIZE".into(), max_batch_size.to_string().into()));
     }
+    if let Some(kv_cache_swap_space) = kv_cache_swap_space {
+        envs.push((
+            "KV_CACHE_SWAP_SPACE".into(),
+            kv_cache_swap_space.to_string().into(),
+        ));
+    }
 
     // Lora Adapters
     if let Some(lora_adapters) = lora_adapters {
         envs.push(("LORA_ADAPTERS".into(), lora_adapters.into()));
     }
 
-    // Logprobs
-    if enable_prefill_logprobs {
-        envs.push(("REQUEST_LOGPROBS".into(), "1".into()));
-    }
-
     // If huggingface_hub_cache is some, pass it to the shard
     // Useful when running inside a docker container
     if let Some(huggingface_hub_cache) = huggingface_hub_cache {
@@ -1097,6 +1528,12 @@ fn shard_manager(
         envs.push(("DISABLE_CUSTOM_KERNELS".into(), "True".into()))
     }
 
+    // Deterministic kernels, cuBLAS needs a fixed workspace for them
+    if deterministic {
+        envs.push(("DETERMINISTIC".into(), "1".into()));
+        envs.push(("CUBLAS_WORKSPACE_CONFIG".into(), ":4096:8".into()));
+    }
+
     // Watermark Gamma
     if let Some(watermark_gamma) = watermark_gamma {
         envs.push(("WATERMARK_GAMMA".into(), watermark_gamma.to_string().into()))
@@ -1216,6 +1653,255 @@ fn shutdown_shards(shutdown: Arc<AtomicBool>, shutdown_receiver: &mpsc::Receiver
     let _ = shutdown_receiver.recv();
 }
 
+/// Shards of `model_id` started by the launcher: the first ones, or the ones started by the
+/// router to switch the model over to another revision
+struct ShardSet {
+    shutdown: Arc<AtomicBool>,
+    shutdown_receiver: mpsc::Receiver<()>,
+    status_receiver: mpsc::Receiver<ShardStatus>,
+}
+
+#[derive(Default)]
+struct ShardSetsState {
+    /// Running shard-sets by their number, 0 for the first one
+    shard_sets: BTreeMap<usize, ShardSet>,
+    /// Whether the launcher is shutting down, and the other shard-sets must not be kept
+    closed: bool,
+}
+
+/// Running shard-sets, shared by the main thread and the control socket
+#[derive(Clone, Default)]
+struct ShardSets(Arc<Mutex<ShardSetsState>>);
+
+impl ShardSets {
+    /// Keep track of a shard-set that started, or stop it if the launcher is shutting down
+    fn insert(&self, id: usize, shard_set: ShardSet) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.closed {
+            drop(state);
+            shutdown_shards(shard_set.shutdown, &shard_set.shutdown_receiver);
+            return false;
+        }
+        state.shard_sets.insert(id, shard_set);
+        true
+    }
+
+    /// Shard-set and rank of a shard that crashed
+    fn crashed(&self) -> Option<(usize, usize)> {
+        let state = self.0.lock().unwrap();
+        state.shard_sets.iter().find_map(|(id, shard_set)| {
+            match shard_set.status_receiver.try_recv() {
+                Ok(ShardStatus::Failed(rank)) => Some((*id, rank)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Stop the shards of a shard-set, returning false if it is not running
+    fn stop(&self, id: usize) -> bool {
+        let shard_set = self.0.lock().unwrap().shard_sets.remove(&id);
+        match shard_set {
+            Some(shard_set) => {
+                shutdown_shards(shard_set.shutdown, &shard_set.shutdown_receiver);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop the shards of every shard-set
+    fn shutdown(&self) {
+        let shard_sets = {
+            let mut state = self.0.lock().unwrap();
+            state.closed = true;
+            std::mem::take(&mut state.shard_sets)
+        };
+        for shard_set in shard_sets.into_values() {
+            shutdown_shards(shard_set.shutdown, &shard_set.shutdown_receiver);
+        }
+    }
+}
+
+/// Socket on which the router asks the launcher to start and stop shard-sets
+fn control_uds_path(shard_uds_path: &str) -> String {
+    format!("{shard_uds_path}-launcher")
+}
+
+/// Listen on the control socket, that only the user of the launcher can connect to
+fn bind_control(control_uds_path: &str) -> Result<UnixListener, LauncherError> {
+    if Path::new(control_uds_path).exists() {
+        fs::remove_file(control_uds_path).map_err(LauncherError::Control)?;
+    }
+    let listener = UnixListener::bind(control_uds_path).map_err(LauncherError::Control)?;
+    fs::set_permissions(control_uds_path, fs::Permissions::from_mode(0o600))
+        .map_err(LauncherError::Control)?;
+    Ok(listener)
+}
+
+/// Request of the router on the control socket, one JSON object per connection
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum ControlRequest {
+    /// Start the shards of another revision of `model_id`, answered once they are ready
+    Spawn {
+        revision: Option<String>,
+        cuda_visible_devices: Option<String>,
+    },
+    /// Stop a shard-set that no longer serves any request, freeing its GPU memory
+    Stop { shard_set: usize },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum ControlResponse {
+    Spawned {
+        shard_set: usize,
+        master_shard_uds_path: String,
+    },
+    Stopped {
+        shard_set: usize,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Starts the shard-sets the router switches the model over to, with the settings of the first
+/// shard-set
+struct Control {
+    next_shard_set: AtomicUsize,
+    num_shard: usize,
+    args: Args,
+    cuda_graphs: Vec<usize>,
+    max_total_tokens: Option<usize>,
+    max_input_tokens: Option<usize>,
+    quantize: Option<Quantization>,
+    max_log_level: LevelFilter,
+    shard_sets: ShardSets,
+    running: Arc<AtomicBool>,
+}
+
+impl Control {
+    /// Answer the requests of the router, each on its own thread so that a shard-set that
+    /// takes long to download and start does not hold up stopping the others
+    fn serve(self, listener: UnixListener) {
+        let control = Arc::new(self);
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let control = control.clone();
+            thread::spawn(move || control.answer(stream));
+        }
+    }
+
+    fn answer(&self, mut stream: UnixStream) {
+        let mut line = String::new();
+        if BufReader::new(&stream).read_line(&mut line).is_err() {
+            return;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(ControlRequest::Spawn {
+                revision,
+                cuda_visible_devices,
+            }) => {
+                let shard_set = self.next_shard_set.fetch_add(1, Ordering::SeqCst);
+                match self.spawn(shard_set, revision, cuda_visible_devices) {
+                    Ok(master_shard_uds_path) => ControlResponse::Spawned {
+                        shard_set,
+                        master_shard_uds_path,
+                    },
+                    Err(err) => ControlResponse::Error {
+                        message: err.to_string(),
+                    },
+                }
+            }
+            Ok(ControlRequest::Stop { shard_set }) => {
+                tracing::info!("Stopping shard-set {shard_set}");
+                if self.shard_sets.stop(shard_set) {
+                    ControlResponse::Stopped { shard_set }
+                } else {
+                    ControlResponse::Error {
+                        message: format!("shard-set {shard_set} is not running"),
+                    }
+                }
+            }
+            Err(err) => ControlResponse::Error {
+                message: err.to_string(),
+            },
+        };
+        let mut response = serde_json::to_string(&response).unwrap();
+        response.push('\n');
+        let _ = stream.write_all(response.as_bytes());
+    }
+
+    /// Download and start the shards of `revision`, returning the socket of their master shard
+    fn spawn(
+        &self,
+        shard_set: usize,
+        revision: Option<String>,
+        cuda_visible_devices: Option<String>,
+    ) -> Result<String, LauncherError> {
+        if let Some(devices) = &cuda_visible_devices {
+            if devices.split(',').count() != self.num_shard {
+                return Err(LauncherError::ArgumentValidation(format!(
+                    "`cuda_visible_devices` must name {} device(s). Given: {devices}",
+                    self.num_shard
+                )));
+            }
+        }
+        tracing::info!("Starting shard-set {shard_set}");
+        download_convert_model(
+            &self.args.model_id,
+            revision.as_deref(),
+            self.args.trust_remote_code,
+            self.args.huggingface_hub_cache.as_deref(),
+            self.args.weights_cache_override.as_deref(),
+            self.running.clone(),
+            true,
+        )?;
+
+        let mut args = self.args.clone();
+        args.revision = revision;
+        args.shard_uds_path = format!("{}-swap{shard_set}", self.args.shard_uds_path);
+        // Both shard-sets communicate within themselves at the same time
+        args.master_port = self.args.master_port + shard_set;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+        let (status_sender, status_receiver) = mpsc::channel();
+        spawn_shards(
+            self.num_shard,
+            &args,
+            self.cuda_graphs.clone(),
+            self.max_total_tokens,
+            self.max_input_tokens,
+            self.quantize,
+            self.max_log_level,
+            cuda_visible_devices,
+            shutdown.clone(),
+            &shutdown_receiver,
+            shutdown_sender,
+            &status_receiver,
+            status_sender,
+            self.running.clone(),
+        )?;
+
+        // Stopped right away if the launcher is shutting down
+        let started = self.shard_sets.insert(
+            shard_set,
+            ShardSet {
+                shutdown,
+                shutdown_receiver,
+                status_receiver,
+            },
+        );
+        if !started {
+            return Err(LauncherError::ShardCannotStart);
+        }
+        Ok(format!("{}-0", args.shard_uds_path))
+    }
+}
+
 fn num_cuda_devices() -> Option<usize> {
     let devices = match env::var("CUDA_VISIBLE_DEVICES") {
         Ok(devices) => devices,
@@ -1352,6 +2038,8 @@ fn find_num_shards(
 enum LauncherError {
     #[error("Invalid argument: {0}")]
     ArgumentValidation(String),
+    #[error(transparent)]
+    ConfigFile(#[from] config_file::ConfigFileError),
     #[error("not enough cuda devices: {0}")]
     NotEnoughCUDADevices(String),
     #[error("Download error")]
@@ -1366,6 +2054,8 @@ enum LauncherError {
     WebserverFailed,
     #[error("Webserver cannot start")]
     WebserverCannotStart,
+    #[error("Unable to open the control socket: {0}")]
+    Control(io::Error),
 }
 
 fn download_convert_model(
@@ -1521,6 +2211,7 @@ fn spawn_shards(
     max_input_tokens: Option<usize>,
     quantize: Option<Quantization>,
     max_log_level: LevelFilter,
+    cuda_visible_devices: Option<String>,
     shutdown: Arc<AtomicBool>,
     shutdown_receiver: &mpsc::Receiver<()>,
     shutdown_sender: mpsc::Sender<()>,
@@ -1544,9 +2235,11 @@ fn spawn_shards(
         let speculate = args.speculate;
         let dtype = args.dtype;
         let kv_cache_dtype = args.kv_cache_dtype;
+        let kv_cache_swap_space = args.kv_cache_swap_space;
         let trust_remote_code = args.trust_remote_code;
         let master_port = args.master_port;
         let disable_custom_kernels = args.disable_custom_kernels;
+        let deterministic = args.deterministic;
         let watermark_gamma = args.watermark_gamma;
         let watermark_delta = args.watermark_delta;
         let cuda_graphs_clone = cuda_graphs.clone();
@@ -1555,7 +2248,7 @@ fn spawn_shards(
         let rope_factor = args.rope_factor;
         let max_batch_size = args.max_batch_size;
         let lora_adapters = args.lora_adapters.clone();
-        let enable_prefill_logprobs = args.enable_prefill_logprobs;
+        let cuda_visible_devices = cuda_visible_devices.clone();
         thread::spawn(move || {
             shard_manager(
                 model_id,
@@ -1564,6 +2257,7 @@ fn spawn_shards(
                 speculate,
                 dtype,
                 kv_cache_dtype,
+                kv_cache_swap_space,
                 trust_remote_code,
                 uds_path,
                 rank,
@@ -1573,6 +2267,7 @@ fn spawn_shards(
                 huggingface_hub_cache,
                 weights_cache_override,
                 disable_custom_kernels,
+                deterministic,
                 watermark_gamma,
                 watermark_delta,
                 cuda_graphs_clone,
@@ -1583,10 +2278,10 @@ fn spawn_shards(
                 max_batch_size,
                 max_input_tokens,
                 lora_adapters,
-                enable_prefill_logprobs,
                 otlp_endpoint,
                 otlp_service_name,
                 max_log_level,
+                cuda_visible_devices,
                 status_sender,
                 shutdown,
                 shutdown_sender,
@@ -1754,8 +2449,7 @@ fn spawn_webserver(
     max_input_tokens: Option<usize>,
     max_total_tokens: Option<usize>,
     max_batch_prefill_tokens: u32,
-    shutdown: Arc<AtomicBool>,
-    shutdown_receiver: &mpsc::Receiver<()>,
+    shard_sets: &ShardSets,
 ) -> Result<Child, LauncherError> {
     // All shard started
     // Start webserver
@@ -1789,7 +2483,17 @@ fn spawn_webserver(
         args.model_id,
         "--payload-limit".to_string(),
         args.payload_limit.to_string(),
+        "--session-ttl".to_string(),
+        args.session_ttl.to_string(),
+        "--warmup-retries".to_string(),
+        args.warmup_retries.to_string(),
     ];
+    if args.enable_model_swap {
+        router_args.extend_from_slice(&[
+            "--launcher-uds-path".to_string(),
+            control_uds_path(&args.shard_uds_path),
+        ]);
+    }
     if let Some(max_input_tokens) = max_input_tokens {
         router_args.extend_from_slice(&[
             "--max-input-tokens".to_string(),
@@ -1818,12 +2522,259 @@ fn spawn_webserver(
         router_args.push(tokenizer_config_path.to_string());
     }
 
+    // Chat template path
+    if let Some(ref chat_template_path) = args.chat_template_path {
+        router_args.push("--chat-template-path".to_string());
+        router_args.push(chat_template_path.to_string());
+    }
+
     // Model optional max batch total tokens
     if let Some(max_batch_total_tokens) = args.max_batch_total_tokens {
         router_args.push("--max-batch-total-tokens".to_string());
         router_args.push(max_batch_total_tokens.to_string());
     }
 
+    // Router optional queue limits
+    if let Some(max_queue_size) = args.max_queue_size {
+        router_args.push("--max-queue-size".to_string());
+        router_args.push(max_queue_size.to_string());
+    }
+    if let Some(max_queue_wait) = args.max_queue_wait {
+        router_args.push("--max-queue-wait".to_string());
+        router_args.push(max_queue_wait.to_string());
+    }
+
+    // Router optional health probes
+    if let Some(health_check_interval) = args.health_check_interval {
+        router_args.push("--health-check-interval".to_string());
+        router_args.push(health_check_interval.to_string());
+    }
+
+    // Router optional stream heartbeats
+    if let Some(stream_heartbeat_interval) = args.stream_heartbeat_interval {
+        router_args.push("--stream-heartbeat-interval".to_string());
+        router_args.push(stream_heartbeat_interval.to_string());
+    }
+
+    // Router optional queue journal
+    if let Some(ref queue_journal_path) = args.queue_journal_path {
+        router_args.push("--queue-journal-path".to_string());
+        router_args.push(queue_journal_path.to_string());
+    }
+
+    // Router optional prefill shards
+    if let Some(ref prefill_shard_uds_path) = args.prefill_shard_uds_path {
+        router_args.push("--prefill-shard-uds-path".to_string());
+        router_args.push(prefill_shard_uds_path.to_string());
+    }
+
+    // Router optional draft model
+    if let Some(ref draft_shard_uds_path) = args.draft_shard_uds_path {
+        router_args.push("--draft-shard-uds-path".to_string());
+        router_args.push(draft_shard_uds_path.to_string());
+    }
+
+    // Other models served by the router
+    for served_model in args.served_model.iter() {
+        router_args.push("--served-model".to_string());
+        router_args.push(served_model.to_string());
+    }
+
+    // Router optional fairness policy
+    if let Some(max_request_token_share) = args.max_request_token_share {
+        router_args.push("--max-request-token-share".to_string());
+        router_args.push(max_request_token_share.to_string());
+    }
+    if let Some(fairness_queue_depth) = args.fairness_queue_depth {
+        router_args.push("--fairness-queue-depth".to_string());
+        router_args.push(fairness_queue_depth.to_string());
+    }
+    if let Some(preemption_queue_size) = args.preemption_queue_size {
+        router_args.push("--preemption-queue-size".to_string());
+        router_args.push(preemption_queue_size.to_string());
+    }
+    if args.tenant_fair_share {
+        router_args.push("--tenant-fair-share".to_string());
+    }
+    for tenant_weight in args.tenant_weight.iter() {
+        router_args.push("--tenant-weight".to_string());
+        router_args.push(tenant_weight.to_string());
+    }
+    if let Some(scheduling_policy) = args.scheduling_policy {
+        router_args.push("--scheduling-policy".to_string());
+        router_args.push(scheduling_policy.to_string());
+    }
+    if let Some(short_prompt_tokens) = args.short_prompt_tokens {
+        router_args.push("--short-prompt-tokens".to_string());
+        router_args.push(short_prompt_tokens.to_string());
+    }
+    if let Some(short_prompt_prefill_share) = args.short_prompt_prefill_share {
+        router_args.push("--short-prompt-prefill-share".to_string());
+        router_args.push(short_prompt_prefill_share.to_string());
+    }
+    for warmup_shape in args.warmup_shape.iter() {
+        router_args.push("--warmup-shape".to_string());
+        router_args.push(warmup_shape.to_string());
+    }
+    if let Some(ref tenant_header) = args.tenant_header {
+        router_args.push("--tenant-header".to_string());
+        router_args.push(tenant_header.to_string());
+    }
+
+    // Router optional response cache
+    if let Some(response_cache_size) = args.response_cache_size {
+        router_args.push("--response-cache-size".to_string());
+        router_args.push(response_cache_size.to_string());
+    }
+    router_args.push("--response-cache-ttl".to_string());
+    router_args.push(args.response_cache_ttl.to_string());
+    if let Some(ref response_cache_redis_url) = args.response_cache_redis_url {
+        router_args.push("--response-cache-redis-url".to_string());
+        router_args.push(response_cache_redis_url.to_string());
+    }
+
+    // Router optional idempotency keys
+    if let Some(idempotency_cache_size) = args.idempotency_cache_size {
+        router_args.push("--idempotency-cache-size".to_string());
+        router_args.push(idempotency_cache_size.to_string());
+    }
+    router_args.push("--idempotency-ttl".to_string());
+    router_args.push(args.idempotency_ttl.to_string());
+
+    // Router optional resumable streams
+    if let Some(stream_resume_events) = args.stream_resume_events {
+        router_args.push("--stream-resume-events".to_string());
+        router_args.push(stream_resume_events.to_string());
+    }
+    router_args.push("--stream-resume-timeout".to_string());
+    router_args.push(args.stream_resume_timeout.to_string());
+
+    // Router optional prompt templates
+    if let Some(ref prompt_templates_path) = args.prompt_templates_path {
+        router_args.push("--prompt-templates-path".to_string());
+        router_args.push(prompt_templates_path.to_string());
+    }
+
+    // Router optional image limits
+    if let Some(max_images_per_request) = args.max_images_per_request {
+        router_args.push("--max-images-per-request".to_string());
+        router_args.push(max_images_per_request.to_string());
+    }
+    if let Some(max_image_pixels) = args.max_image_pixels {
+        router_args.push("--max-image-pixels".to_string());
+        router_args.push(max_image_pixels.to_string());
+    }
+
+    // Router vocabulary check
+    if args.allow_vocab_mismatch {
+        router_args.push("--allow-vocab-mismatch".to_string());
+    }
+
+    // Router optional audit log
+    if let Some(ref audit_log) = args.audit_log {
+        router_args.push("--audit-log".to_string());
+        router_args.push(audit_log.to_string());
+        router_args.push("--audit-sample-rate".to_string());
+        router_args.push(args.audit_sample_rate.to_string());
+        router_args.push("--audit-prompt-length".to_string());
+        router_args.push(args.audit_prompt_length.to_string());
+        if args.audit_hash_prompts {
+            router_args.push("--audit-hash-prompts".to_string());
+        }
+    }
+
+    // Router optional KV cache compaction
+    if let Some(kv_compaction_interval) = args.kv_compaction_interval {
+        router_args.push("--kv-compaction-interval".to_string());
+        router_args.push(kv_compaction_interval.to_string());
+    }
+    if let Some(prefix_cache_ttl) = args.prefix_cache_ttl {
+        router_args.push("--prefix-cache-ttl".to_string());
+        router_args.push(prefix_cache_ttl.to_string());
+    }
+
+    // Router optional time to first token objective
+    if let Some(target_ttft_ms) = args.target_ttft_ms {
+        router_args.push("--target-ttft-ms".to_string());
+        router_args.push(target_ttft_ms.to_string());
+    }
+
+    // Router optional decode step latency objective
+    if let Some(target_decode_latency_ms) = args.target_decode_latency_ms {
+        router_args.push("--target-decode-latency-ms".to_string());
+        router_args.push(target_decode_latency_ms.to_string());
+    }
+
+    // Router optional watermark keys, scored with the gamma of the shards
+    if let Some(ref watermark_keys_path) = args.watermark_keys_path {
+        router_args.push("--watermark-keys-path".to_string());
+        router_args.push(watermark_keys_path.to_string());
+        if let Some(watermark_gamma) = args.watermark_gamma {
+            router_args.push("--watermark-gamma".to_string());
+            router_args.push(watermark_gamma.to_string());
+        }
+    }
+
+    // Router optional content filters
+    if let Some(ref content_filter_path) = args.content_filter_path {
+        router_args.push("--content-filter-path".to_string());
+        router_args.push(content_filter_path.to_string());
+    }
+
+    // Router optional transforms
+    if let Some(ref transforms_path) = args.transforms_path {
+        router_args.push("--transforms-path".to_string());
+        router_args.push(transforms_path.to_string());
+    }
+
+    // Router optional max new tokens limits
+    if let Some(default_max_new_tokens) = args.default_max_new_tokens {
+        router_args.push("--default-max-new-tokens".to_string());
+        router_args.push(default_max_new_tokens.to_string());
+    }
+    if let Some(max_new_tokens_ceiling) = args.max_new_tokens_ceiling {
+        router_args.push("--max-new-tokens-ceiling".to_string());
+        router_args.push(max_new_tokens_ceiling.to_string());
+    }
+    if let Some(stream_max_new_tokens_ceiling) = args.stream_max_new_tokens_ceiling {
+        router_args.push("--stream-max-new-tokens-ceiling".to_string());
+        router_args.push(stream_max_new_tokens_ceiling.to_string());
+    }
+    if let Some(ref max_new_tokens_config_path) = args.max_new_tokens_config_path {
+        router_args.push("--max-new-tokens-config-path".to_string());
+        router_args.push(max_new_tokens_config_path.to_string());
+    }
+
+    // Router optional prompt lookup speculation
+    if let Some(prompt_lookup_max_ngram) = args.prompt_lookup_max_ngram {
+        router_args.push("--prompt-lookup-max-ngram".to_string());
+        router_args.push(prompt_lookup_max_ngram.to_string());
+    }
+
+    // Router optional gRPC API
+    if let Some(grpc_port) = args.grpc_port {
+        router_args.push("--grpc-port".to_string());
+        router_args.push(grpc_port.to_string());
+    }
+
+    // Router optional rate limits
+    if let Some(rate_limit_requests) = args.rate_limit_requests {
+        router_args.push("--rate-limit-requests".to_string());
+        router_args.push(rate_limit_requests.to_string());
+    }
+    if let Some(rate_limit_tokens) = args.rate_limit_tokens {
+        router_args.push("--rate-limit-tokens".to_string());
+        router_args.push(rate_limit_tokens.to_string());
+    }
+    if let Some(rate_limit_concurrent_requests) = args.rate_limit_concurrent_requests {
+        router_args.push("--rate-limit-concurrent-requests".to_string());
+        router_args.push(rate_limit_concurrent_requests.to_string());
+    }
+    if let Some(ref rate_limit_config_path) = args.rate_limit_config_path {
+        router_args.push("--rate-limit-config-path".to_string());
+        router_args.push(rate_limit_config_path.to_string());
+    }
+
     // Router optional max batch size
     if let Some(max_batch_size) = args.max_batch_size {
         router_args.push("--max-batch-size".to_string());
@@ -1866,6 +2817,14 @@ fn spawn_webserver(
         router_args.push("--api-key".to_string());
         router_args.push(api_key);
     }
+    if let Some(api_keys_path) = args.api_keys_path {
+        router_args.push("--api-keys-path".to_string());
+        router_args.push(api_keys_path);
+    }
+    if let Some(usage_path) = args.usage_path {
+        router_args.push("--usage-path".to_string());
+        router_args.push(usage_path);
+    }
     // Ngrok
     if args.ngrok {
         router_args.push("--ngrok".to_string());
@@ -1883,6 +2842,11 @@ fn spawn_webserver(
         envs.push(("HF_TOKEN".into(), api_token.into()))
     };
 
+    // Secret of the audit log, kept out of the command line of the router
+    if let Some(audit_hash_key) = args.audit_hash_key {
+        envs.push(("AUDIT_HASH_KEY".into(), audit_hash_key.into()))
+    };
+
     // Parse Compute type
     if let Ok(compute_type) = env::var("COMPUTE_TYPE") {
         envs.push(("COMPUTE_TYPE".into(), compute_type.into()))
@@ -1908,7 +2872,7 @@ fn spawn_webserver(
                 tracing::error!("{}", err);
             }
 
-            shutdown_shards(shutdown, shutdown_receiver);
+            shard_sets.shutdown();
             return Err(LauncherError::WebserverCannotStart);
         }
     };
@@ -1954,8 +2918,18 @@ fn terminate(process_name: &str, mut process: Child, timeout: Duration) -> io::R
 }
 
 fn main() -> Result<(), LauncherError> {
+    // The options of the config file are environment variables by the time the others are parsed
+    let config_file_options = config_file::load(&Args::command())?;
+    let matches = Args::command().get_matches();
     // Pattern match configuration
-    let args: Args = Args::parse();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if args.print_config {
+        print!(
+            "{}",
+            config_file::resolved(&Args::command(), &matches, &config_file_options)
+        );
+        return Ok(());
+    }
 
     // Filter events with LOG_LEVEL
     let varname = "LOG_LEVEL";
@@ -1992,6 +2966,12 @@ fn main() -> Result<(), LauncherError> {
         tracing::info!("{}", env_runtime);
     }
 
+    if let Some(config_file) = &args.config_file {
+        tracing::info!(
+            "Loaded {} options from {config_file}",
+            config_file_options.len()
+        );
+    }
     tracing::info!("{:#?}", args);
 
     let config: Option<Config> = get_config(&args.model_id, &args.revision).ok();
@@ -2000,6 +2980,15 @@ fn main() -> Result<(), LauncherError> {
 
     let (prefix_caching, attention) = resolve_attention(&config, &args.lora_adapters);
     tracing::info!("Using attention {attention} - Prefix caching {prefix_caching}");
+    // The blocks copied back from the host memory are a prefix of the prompt of the next round
+    if args.kv_cache_swap_space.is_some()
+        && !matches!(prefix_caching.to_lowercase().as_str(), "1" | "true")
+    {
+        return Err(LauncherError::ArgumentValidation(
+            "`--kv-cache-swap-space` requires prefix caching, which is disabled for this model"
+                .to_string(),
+        ));
+    }
     std::env::set_var("PREFIX_CACHING", prefix_caching);
     std::env::set_var("ATTENTION", attention);
 
@@ -2068,11 +3057,15 @@ fn main() -> Result<(), LauncherError> {
         }
     }
 
+    if args.enable_prefill_logprobs {
+        tracing::warn!("`--enable-prefill-logprobs` is deprecated and has no effect, the logprobs of the prompt are computed for the requests that ask for them.");
+    }
+
     if matches!(args.quantize, Some(Quantization::Bitsandbytes)) {
         tracing::warn!("Bitsandbytes is deprecated, use `eetq` instead, which provides better latencies overall and is drop-in in most cases.");
     }
     let quantize = args.quantize.or(quantize);
-    let cuda_graphs = match (&args.cuda_graphs, &quantize) {
+    let mut cuda_graphs: Vec<usize> = match (&args.cuda_graphs, &quantize) {
         (Some(cuda_graphs), _) => cuda_graphs.iter().cloned().filter(|&c| c > 0).collect(),
         #[allow(deprecated)]
         (
@@ -2096,6 +3089,18 @@ fn main() -> Result<(), LauncherError> {
             cuda_graphs
         }
     };
+    if !cuda_graphs.is_empty() {
+        // The decodes of the warmup shapes run on graphs of their own batch size, a request
+        // decoding its speculative tokens with its next token
+        let tokens_per_request = args.speculate.unwrap_or(0) + 1;
+        let warmup_batch_sizes = args.warmup_shape.iter().filter_map(|shape| {
+            let batch_size = shape.split(':').nth(2)?.trim().parse::<usize>().ok()?;
+            batch_size.checked_mul(tokens_per_request)
+        });
+        cuda_graphs.extend(warmup_batch_sizes);
+        cuda_graphs.sort_unstable();
+        cuda_graphs.dedup();
+    }
 
     if args.validation_workers == 0 {
         return Err(LauncherError::ArgumentValidation(
@@ -2212,11 +3217,12 @@ fn main() -> Result<(), LauncherError> {
     spawn_shards(
         num_shard,
         &args,
-        cuda_graphs,
+        cuda_graphs.clone(),
         max_total_tokens,
         max_input_tokens,
         quantize,
         max_log_level,
+        None,
         shutdown.clone(),
         &shutdown_receiver,
         shutdown_sender,
@@ -2231,25 +3237,54 @@ fn main() -> Result<(), LauncherError> {
         return Ok(());
     }
 
+    let shard_sets = ShardSets::default();
+    shard_sets.insert(
+        0,
+        ShardSet {
+            shutdown,
+            shutdown_receiver,
+            status_receiver,
+        },
+    );
+
+    // The router starts the shards of other revisions and stops the ones it no longer uses
+    if args.enable_model_swap {
+        let control_uds_path = control_uds_path(&args.shard_uds_path);
+        let listener = bind_control(&control_uds_path).inspect_err(|_| shard_sets.shutdown())?;
+        let control = Control {
+            next_shard_set: AtomicUsize::new(1),
+            num_shard,
+            args: args.clone(),
+            cuda_graphs,
+            max_total_tokens,
+            max_input_tokens,
+            quantize,
+            max_log_level,
+            shard_sets: shard_sets.clone(),
+            running: running.clone(),
+        };
+        thread::spawn(move || control.serve(listener));
+    }
+
     let mut webserver = spawn_webserver(
         num_shard,
         args,
         max_input_tokens,
         max_total_tokens,
         max_batch_prefill_tokens,
-        shutdown.clone(),
-        &shutdown_receiver,
-    )
-    .inspect_err(|_| {
-        shutdown_shards(shutdown.clone(), &shutdown_receiver);
-    })?;
+        &shard_sets,
+    )?;
 
     // Default exit code
     let mut exit_code = Ok(());
 
     while running.load(Ordering::SeqCst) {
-        if let Ok(ShardStatus::Failed(rank)) = status_receiver.try_recv() {
-            tracing::error!("Shard {rank} crashed");
+        if let Some((shard_set, rank)) = shard_sets.crashed() {
+            if shard_set == 0 {
+                tracing::error!("Shard {rank} crashed");
+            } else {
+                tracing::error!("Shard {rank} of shard-set {shard_set} crashed");
+            }
             exit_code = Err(LauncherError::ShardFailed);
             break;
         };
@@ -2257,7 +3292,7 @@ fn main() -> Result<(), LauncherError> {
         match webserver.try_wait().unwrap() {
             Some(_) => {
                 tracing::error!("Webserver Crashed");
-                shutdown_shards(shutdown, &shutdown_receiver);
+                shard_sets.shutdown();
                 return Err(LauncherError::WebserverFailed);
             }
             None => {
@@ -2268,7 +3303,7 @@ fn main() -> Result<(), LauncherError> {
 
     // Graceful termination
     terminate("webserver", webserver, Duration::from_secs(90)).unwrap();
-    shutdown_shards(shutdown, &shutdown_receiver);
+    shard_sets.shutdown();
 
     exit_code
 }
@@ -0,0 +1,440 @@
+/// API key authentication of the routes, with per-key scopes and models
+use crate::quota::{Exceeded, Quota, TokenUsage};
+use crate::{ErrorResponse, KeyUsage};
+use axum::extract::{ConnectInfo, Request};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use thiserror::Error;
+
+tokio::task_local! {
+    /// API key of the request being handled
+    static KEY: Arc<ApiKey>;
+}
+
+/// Group of routes a key can be allowed to call
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    /// Generation, chat, completion, embedding and tokenization routes
+    Generate,
+    /// `/admin/*`, `/drain` and `/metrics/sessions` routes
+    Admin,
+    /// Other `/metrics*` routes
+    Metrics,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Generate => "generate",
+            Scope::Admin => "admin",
+            Scope::Metrics => "metrics",
+        }
+    }
+}
+
+/// Permissions of one API key
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ApiKey {
+    /// Name of the key in its usage, the start of the SHA-256 of the key if unset
+    #[serde(default)]
+    name: Option<String>,
+    scopes: Vec<Scope>,
+    /// Models the key can generate with, all of the served ones if unset
+    #[serde(default)]
+    models: Option<Vec<String>>,
+    #[serde(default)]
+    quota: Quota,
+    #[serde(skip)]
+    usage: TokenUsage,
+}
+
+impl ApiKey {
+    /// Permissions of the `--api-key` key
+    pub(crate) fn unrestricted() -> Self {
+        Self {
+            name: None,
+            scopes: vec![Scope::Generate, Scope::Admin, Scope::Metrics],
+            models: None,
+            quota: Quota::default(),
+            usage: TokenUsage::default(),
+        }
+    }
+
+    pub(crate) fn allows_model(&self, model: &str) -> bool {
+        self.models
+            .as_ref()
+            .map_or(true, |models| models.iter().any(|m| m == model))
+    }
+
+    /// Name of the key, which does not reveal it
+    pub(crate) fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_default()
+    }
+
+    /// Count the prompt and generated tokens of a request of this key
+    pub(crate) fn record_tokens(&self, prompt_tokens: u32, completion_tokens: u32) {
+        self.usage
+            .record(self.name(), prompt_tokens, completion_tokens);
+    }
+
+    /// The quota of this key that is used up, if any
+    pub(crate) fn exceeded_quota(&self) -> Option<Exceeded> {
+        self.usage.check(&self.quota)
+    }
+
+    pub(crate) fn usage(&self) -> KeyUsage {
+        self.usage.report(self.name(), &self.quota)
+    }
+
+    pub(crate) fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub(crate) fn token_usage(&self) -> &TokenUsage {
+        &self.usage
+    }
+}
+
+/// SHA-256 of a key, which identifies it in the saved usage without revealing it
+fn digest(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Name of an unnamed key in its usage, which does not reveal the key
+fn fingerprint(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .take(4)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Content of the `--api-keys-path` file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeysConfig {
+    keys: HashMap<String, ApiKey>,
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Unable to read the API keys: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Invalid API keys: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Reason a request is refused
+#[derive(Debug, PartialEq)]
+pub(crate) enum Denied {
+    /// Missing or unknown key
+    Unauthenticated,
+    /// Known key without the scope of the route
+    Forbidden(Scope),
+    /// Remote client of an admin route open without API keys
+    Remote,
+}
+
+impl Denied {
+    pub(crate) fn message(&self) -> String {
+        match self {
+            Denied::Unauthenticated => "Invalid or missing API key".to_string(),
+            Denied::Forbidden(scope) => {
+                format!("API key does not have the `{}` scope", scope.as_str())
+            }
+            Denied::Remote => "Route only open to local clients without API keys".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for Denied {
+    fn into_response(self) -> Response {
+        let (status, error_type) = match self {
+            Denied::Unauthenticated => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            Denied::Forbidden(_) | Denied::Remote => (StatusCode::FORBIDDEN, "forbidden"),
+        };
+        (status, Json(ErrorResponse::new(error_type, self.message()))).into_response()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ApiKeys {
+    keys: HashMap<String, Arc<ApiKey>>,
+    /// Whether the metrics routes need a key, only when the keys have scopes
+    pub(crate) protects_metrics: bool,
+}
+
+impl ApiKeys {
+    /// Build the keys from the CLI key and the optional keys file.
+    /// Returns `None` if no key is defined, the routes are then open to everyone.
+    pub(crate) fn new(
+        api_key: Option<String>,
+        config_path: Option<String>,
+    ) -> Result<Option<Self>, AuthError> {
+        let protects_metrics = config_path.is_some();
+        let config = match config_path {
+            Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            None => ApiKeysConfig {
+                keys: HashMap::new(),
+            },
+        };
+        let mut keys: HashMap<String, ApiKey> = config.keys;
+        if let Some(api_key) = api_key {
+            keys.insert(api_key, ApiKey::unrestricted());
+        }
+        let keys = keys
+            .into_iter()
+            .map(|(key, mut permissions)| {
+                permissions.name.get_or_insert_with(|| fingerprint(&key));
+                (key, Arc::new(permissions))
+            })
+            .collect();
+
+        if keys.is_empty() && !protects_metrics {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            keys,
+            protects_metrics,
+        }))
+    }
+
+    /// Key of the `authorization` header value, if it is allowed to call the routes of `scope`
+    pub(crate) fn authorize(
+        &self,
+        authorization: Option<&str>,
+        scope: Scope,
+    ) -> Result<Arc<ApiKey>, Denied> {
+        let key = authorization
+            .and_then(bearer_token)
+            .and_then(|key| self.keys.get(key))
+            .ok_or(Denied::Unauthenticated)?;
+        if !key.has_scope(scope) {
+            return Err(Denied::Forbidden(scope));
+        }
+        Ok(key.clone())
+    }
+
+    /// Keys by their `digest`
+    pub(crate) fn by_digest(&self) -> impl Iterator<Item = (String, &ApiKey)> {
+        self.keys
+            .iter()
+            .map(|(key, permissions)| (digest(key), &**permissions))
+    }
+
+    /// Usage of all the keys, by name
+    pub(crate) fn usage(&self) -> Vec<KeyUsage> {
+        let mut usage: Vec<KeyUsage> = self.keys.values().map(|key| key.usage()).collect();
+        usage.sort_by(|a, b| a.name.cmp(&b.name));
+        usage
+    }
+}
+
+/// Token of a `Bearer` `authorization` header value, whose scheme is case-insensitive
+pub(crate) fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim_start_matches(' '))
+}
+
+/// API key of the request being handled, if the routes are authenticated
+pub(crate) fn current_key() -> Option<Arc<ApiKey>> {
+    KEY.try_with(|key| key.clone()).ok()
+}
+
+/// Run `future` on behalf of `key`, for work spawned outside of the request task
+pub(crate) async fn scope<F: Future>(key: Option<Arc<ApiKey>>, future: F) -> F::Output {
+    match key {
+        Some(key) => KEY.scope(key, future).await,
+        None => future.await,
+    }
+}
+
+/// Middleware refusing the requests without a key allowed to call the routes of `scope`
+pub(crate) async fn authenticate(
+    api_keys: Arc<ApiKeys>,
+    scope: Scope,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match api_keys.authorize(authorization, scope) {
+        Ok(key) => KEY.scope(key, next.run(request)).await,
+        Err(denied) => {
+            metrics::counter!("tgi_request_failure", "err" => "unauthorized").increment(1);
+            denied.into_response()
+        }
+    }
+}
+
+/// Middleware refusing the requests that do not come from the loopback interface, for the
+/// admin routes served without API keys
+pub(crate) async fn local_only(request: Request, next: Next) -> Response {
+    if is_local(&request) {
+        next.run(request).await
+    } else {
+        metrics::counter!("tgi_request_failure", "err" => "unauthorized").increment(1);
+        Denied::Remote.into_response()
+    }
+}
+
+/// Whether the request comes from the loopback interface, unknown peers are remote
+fn is_local(request: &Request) -> bool {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .is_some_and(|ConnectInfo(addr)| addr.ip().to_canonical().is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_keys(config: &str) -> ApiKeys {
+        let config: ApiKeysConfig = serde_json::from_str(config).unwrap();
+        ApiKeys {
+            keys: config
+                .keys
+                .into_iter()
+                .map(|(key, mut permissions)| {
+                    permissions.name.get_or_insert_with(|| fingerprint(&key));
+                    (key, Arc::new(permissions))
+                })
+                .collect(),
+            protects_metrics: true,
+        }
+    }
+
+    #[test]
+    fn test_no_keys() {
+        assert!(ApiKeys::new(None, None).unwrap().is_none());
+        let api_keys = ApiKeys::new(Some("secret".to_string()), None)
+            .unwrap()
+            .unwrap();
+        assert!(!api_keys.protects_metrics);
+        for scope in [Scope::Generate, Scope::Admin, Scope::Metrics] {
+            assert!(api_keys.authorize(Some("Bearer secret"), scope).is_ok());
+        }
+        // The scheme is case-insensitive
+        for authorization in ["bearer secret", "BEARER  secret"] {
+            assert!(api_keys
+                .authorize(Some(authorization), Scope::Generate)
+                .is_ok());
+        }
+        assert!(api_keys
+            .authorize(Some("Basic secret"), Scope::Generate)
+            .is_err());
+    }
+
+    #[test]
+    fn test_scopes() {
+        let api_keys = api_keys(
+            r#"{"keys": {
+                "app": {"scopes": ["generate"]},
+                "ops": {"scopes": ["admin", "metrics"]}
+            }}"#,
+        );
+        assert!(api_keys
+            .authorize(Some("Bearer app"), Scope::Generate)
+            .is_ok());
+        assert_eq!(
+            api_keys.authorize(Some("Bearer app"), Scope::Admin).err(),
+            Some(Denied::Forbidden(Scope::Admin))
+        );
+        assert!(api_keys.authorize(Some("Bearer ops"), Scope::Admin).is_ok());
+        assert_eq!(
+            api_keys
+                .authorize(Some("Bearer ops"), Scope::Generate)
+                .err(),
+            Some(Denied::Forbidden(Scope::Generate))
+        );
+        for authorization in [None, Some("app"), Some("Bearer other")] {
+            assert_eq!(
+                api_keys.authorize(authorization, Scope::Generate).err(),
+                Some(Denied::Unauthenticated)
+            );
+        }
+    }
+
+    #[test]
+    fn test_models() {
+        let api_keys = api_keys(
+            r#"{"keys": {
+                "app": {"scopes": ["generate"], "models": ["base", "adapter"]},
+                "all": {"scopes": ["generate"]}
+            }}"#,
+        );
+        let app = api_keys
+            .authorize(Some("Bearer app"), Scope::Generate)
+            .unwrap();
+        assert!(app.allows_model("adapter"));
+        assert!(!app.allows_model("other"));
+        let all = api_keys
+            .authorize(Some("Bearer all"), Scope::Generate)
+            .unwrap();
+        assert!(all.allows_model("other"));
+    }
+
+    #[test]
+    fn test_usage() {
+        let api_keys = api_keys(
+            r#"{"keys": {
+                "app": {"name": "app", "scopes": ["generate"], "quota": {"daily_tokens": 10}},
+                "secret": {"scopes": ["generate"]}
+            }}"#,
+        );
+        let app = api_keys
+            .authorize(Some("Bearer app"), Scope::Generate)
+            .unwrap();
+        app.record_tokens(8, 2);
+        assert!(app.exceeded_quota().is_some());
+
+        let usage = api_keys.usage();
+        assert_eq!(usage[0].name, "2bb80d53");
+        assert_eq!(usage[0].daily_tokens, 0);
+        assert_eq!(usage[1].name, "app");
+        assert_eq!(usage[1].prompt_tokens, 8);
+        assert_eq!(usage[1].daily_quota, Some(10));
+    }
+
+    #[test]
+    fn test_unknown_scope() {
+        let config = r#"{"keys": {"app": {"scopes": ["everything"]}}}"#;
+        assert!(serde_json::from_str::<ApiKeysConfig>(config).is_err());
+    }
+
+    #[test]
+    fn test_is_local() {
+        let request = |peer: Option<&str>| {
+            let mut request = Request::new(axum::body::Body::empty());
+            if let Some(peer) = peer {
+                let addr: SocketAddr = peer.parse().unwrap();
+                request.extensions_mut().insert(ConnectInfo(addr));
+            }
+            request
+        };
+        assert!(is_local(&request(Some("127.0.0.1:8080"))));
+        assert!(is_local(&request(Some("[::1]:8080"))));
+        assert!(is_local(&request(Some("[::ffff:127.0.0.1]:8080"))));
+        assert!(!is_local(&request(Some("10.0.0.1:8080"))));
+        assert!(!is_local(&request(None)));
+    }
+}
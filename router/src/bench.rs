@@ -0,0 +1,543 @@
+/// Replay of a trace of generation requests against a running router or the mock backend,
+/// reporting the latencies of the generations
+use crate::infer::mock::MockBackend;
+use crate::infer::{Infer, InferStreamResponse};
+use crate::{GenerateParameters, GenerateRequest};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+
+/// Tokens generated on the mock backend by the requests that do not set `max_new_tokens`
+const MOCK_MAX_NEW_TOKENS: u32 = 256;
+/// Number of distinct errors listed by the report
+const REPORTED_ERRORS: usize = 10;
+
+#[derive(Debug, Error)]
+pub enum BenchError {
+    #[error("Unable to read the trace {0}: {1}")]
+    Read(String, io::Error),
+    #[error("Invalid request on line {0} of the trace: {1}")]
+    Parse(usize, serde_json::Error),
+    #[error("Invalid timestamp on line {0} of the trace: must be a number of seconds >= 0")]
+    Timestamp(usize),
+    #[error("The trace has no request")]
+    Empty,
+    #[error("`--speedup` must be > 0")]
+    Speedup,
+    #[error("Unable to create the HTTP client: {0}")]
+    Client(reqwest::Error),
+}
+
+/// Replay a JSONL trace of requests and report their latencies
+#[derive(Debug, clap::Args)]
+pub struct BenchArgs {
+    /// Trace to replay, one JSON request per line: `{"timestamp": 0.5, "inputs": "...",
+    /// "parameters": {...}}`. The `parameters` are the ones of `/generate`, and the
+    /// requests are sent at the differences of their `timestamp`s, in seconds.
+    trace: PathBuf,
+    /// Router the requests are sent to, on `/generate_stream`
+    #[clap(default_value = "http://localhost:3000", long)]
+    url: String,
+    /// API key sent with the requests, if the router requires one
+    #[clap(long)]
+    api_key: Option<String>,
+    /// Replay the trace on the mock backend, generating this many tokens per second and
+    /// request, instead of on a router. The requests go through the validation and the
+    /// scheduling of the router, with prompts tokenized on whitespace.
+    #[clap(long)]
+    mock_tokens_per_second: Option<f64>,
+    /// Speed of the replay relative to the trace, e.g. `2` sends the requests twice as fast
+    #[clap(default_value = "1.0", long)]
+    speedup: f64,
+    /// Print the report as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// Line of the trace
+#[derive(Debug, Deserialize)]
+struct TraceRequest {
+    /// Time at which the request is sent, in seconds. Only the differences between the requests
+    /// matter.
+    #[serde(default)]
+    timestamp: f64,
+    inputs: String,
+    /// Sent untouched, checked against the parameters of the router when the trace is read
+    #[serde(default)]
+    parameters: Map<String, Value>,
+    /// `max_new_tokens` of the parameters
+    #[serde(skip)]
+    max_new_tokens: Option<u32>,
+}
+
+fn parse_trace(trace: &str) -> Result<Vec<TraceRequest>, BenchError> {
+    let mut requests = Vec::new();
+    for (index, line) in trace.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parse_error = |err: serde_json::Error| BenchError::Parse(index + 1, err);
+        let mut request: TraceRequest = serde_json::from_str(line).map_err(parse_error)?;
+        // Negative, not finite or too large to wait for
+        if Duration::try_from_secs_f64(request.timestamp).is_err() {
+            return Err(BenchError::Timestamp(index + 1));
+        }
+        let parameters: GenerateParameters =
+            serde_json::from_value(Value::Object(request.parameters.clone()))
+                .map_err(parse_error)?;
+        request.max_new_tokens = parameters.max_new_tokens;
+        requests.push(request);
+    }
+    if requests.is_empty() {
+        return Err(BenchError::Empty);
+    }
+    // Replayed from the first request
+    let start = requests
+        .iter()
+        .map(|request| request.timestamp)
+        .fold(f64::INFINITY, f64::min);
+    for request in requests.iter_mut() {
+        request.timestamp -= start;
+    }
+    Ok(requests)
+}
+
+/// Where the requests are replayed
+enum Target {
+    Router {
+        client: reqwest::Client,
+        url: String,
+        api_key: Option<String>,
+    },
+    Mock(Infer),
+}
+
+impl Target {
+    async fn replay(&self, request: &TraceRequest) -> Result<Sample, String> {
+        match self {
+            Target::Router {
+                client,
+                url,
+                api_key,
+            } => replay_router(client, url, api_key.as_deref(), request).await,
+            Target::Mock(infer) => replay_mock(infer, request).await,
+        }
+    }
+}
+
+/// Body of `/generate_stream` for a request of the trace
+fn body(request: &TraceRequest) -> Value {
+    let mut body = serde_json::json!({ "inputs": request.inputs });
+    if !request.parameters.is_empty() {
+        body["parameters"] = Value::Object(request.parameters.clone());
+    }
+    body
+}
+
+async fn replay_router(
+    client: &reqwest::Client,
+    url: &str,
+    api_key: Option<&str>,
+    request: &TraceRequest,
+) -> Result<Sample, String> {
+    let body = body(request);
+    let mut builder = client
+        .post(format!("{}/generate_stream", url.trim_end_matches('/')))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    if let Some(api_key) = api_key {
+        builder = builder.bearer_auth(api_key);
+    }
+
+    let mut timer = Timer::new();
+    let mut response = builder.send().await.map_err(|err| err.to_string())?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("HTTP status {status}"));
+    }
+    let mut events = SseParser::default();
+    while let Some(chunk) = response.chunk().await.map_err(|err| err.to_string())? {
+        for data in events.push(&chunk) {
+            let event: Value = serde_json::from_str(&data).map_err(|err| err.to_string())?;
+            if let Some(error) = event.get("error") {
+                let message = error.get("message").and_then(Value::as_str);
+                return Err(message.unwrap_or("error event").to_string());
+            }
+            if event.get("token").is_some() {
+                timer.token();
+            }
+        }
+    }
+    timer.finish()
+}
+
+async fn replay_mock(infer: &Infer, request: &TraceRequest) -> Result<Sample, String> {
+    let mut body = body(request);
+    if request.max_new_tokens.is_none() {
+        body["parameters"]["max_new_tokens"] = MOCK_MAX_NEW_TOKENS.into();
+    }
+    let request: GenerateRequest = serde_json::from_value(body).map_err(|err| err.to_string())?;
+
+    let mut timer = Timer::new();
+    let (_permit, _input_length, responses) = infer
+        .generate_stream(request)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut responses = std::pin::pin!(responses);
+    while let Some(response) = responses.next().await {
+        if matches!(
+            response.map_err(|err| err.to_string())?,
+            InferStreamResponse::Intermediate { .. } | InferStreamResponse::End { .. }
+        ) {
+            timer.token();
+        }
+    }
+    timer.finish()
+}
+
+/// Data of the unnamed events of an SSE stream, which carry the tokens and the errors
+#[derive(Debug, Default)]
+struct SseParser {
+    /// Bytes of the line that did not end yet
+    line: Vec<u8>,
+    /// Name of the current event, if it has one
+    event: Option<String>,
+    data: Option<String>,
+}
+
+impl SseParser {
+    /// Data of the events that `chunk` ends
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            self.line.clear();
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() {
+                let event = self.event.take();
+                if let Some(data) = self.data.take() {
+                    if event.is_none() {
+                        events.push(data);
+                    }
+                }
+            } else if let Some(event) = line.strip_prefix("event:") {
+                self.event = Some(event.trim_start().to_string());
+            } else if let Some(data) = line.strip_prefix("data:") {
+                let data = data.strip_prefix(' ').unwrap_or(data);
+                match self.data.as_mut() {
+                    Some(buffer) => {
+                        buffer.push('\n');
+                        buffer.push_str(data);
+                    }
+                    None => self.data = Some(data.to_string()),
+                }
+            }
+        }
+        events
+    }
+}
+
+/// Timings of a replayed request
+#[derive(Debug)]
+struct Sample {
+    /// Time to the first token
+    ttft: Duration,
+    /// Mean time between the tokens after the first one
+    tpot: Option<Duration>,
+    latency: Duration,
+    output_tokens: u32,
+}
+
+struct Timer {
+    sent: Instant,
+    first_token: Option<Instant>,
+    last_token: Option<Instant>,
+    tokens: u32,
+}
+
+impl Timer {
+    fn new() -> Self {
+        Self {
+            sent: Instant::now(),
+            first_token: None,
+            last_token: None,
+            tokens: 0,
+        }
+    }
+
+    fn token(&mut self) {
+        let now = Instant::now();
+        self.first_token.get_or_insert(now);
+        self.last_token = Some(now);
+        self.tokens += 1;
+    }
+
+    fn finish(self) -> Result<Sample, String> {
+        let (Some(first_token), Some(last_token)) = (self.first_token, self.last_token) else {
+            return Err("no token generated".to_string());
+        };
+        Ok(Sample {
+            ttft: first_token - self.sent,
+            tpot: (self.tokens > 1).then(|| (last_token - first_token) / (self.tokens - 1)),
+            latency: self.sent.elapsed(),
+            output_tokens: self.tokens,
+        })
+    }
+}
+
+/// Percentiles of a latency, in milliseconds
+#[derive(Debug, PartialEq, Serialize)]
+struct Percentiles {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+}
+
+impl Percentiles {
+    /// Nearest-rank percentiles, None without any value
+    fn new(mut values: Vec<Duration>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        let percentile = |p: f64| {
+            let rank = ((p * values.len() as f64).ceil() as usize).clamp(1, values.len());
+            values[rank - 1].as_secs_f64() * 1000.0
+        };
+        Some(Self {
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    requests: usize,
+    failures: usize,
+    /// Time from the first request sent to the last response received, in seconds
+    duration_s: f64,
+    /// Successful requests per second
+    request_throughput: f64,
+    /// Generated tokens per second
+    token_throughput: f64,
+    ttft_ms: Option<Percentiles>,
+    tpot_ms: Option<Percentiles>,
+    latency_ms: Option<Percentiles>,
+    /// Most frequent errors of the failed requests, with their count
+    errors: BTreeMap<String, usize>,
+}
+
+impl Report {
+    fn new(results: Vec<Result<Sample, String>>, duration: Duration) -> Self {
+        let requests = results.len();
+        let mut samples = Vec::with_capacity(requests);
+        let mut errors = BTreeMap::new();
+        for result in results {
+            match result {
+                Ok(sample) => samples.push(sample),
+                Err(err) => *errors.entry(err).or_insert(0) += 1,
+            }
+        }
+        let failures = requests - samples.len();
+        let mut errors: Vec<_> = errors.into_iter().collect();
+        errors.sort_by(|a, b| b.1.cmp(&a.1));
+        errors.truncate(REPORTED_ERRORS);
+
+        let duration_s = duration.as_secs_f64();
+        let per_second = |count: f64| {
+            if duration_s > 0.0 {
+                count / duration_s
+            } else {
+                0.0
+            }
+        };
+        let output_tokens: u64 = samples.iter().map(|s| s.output_tokens as u64).sum();
+        Self {
+            requests,
+            failures,
+            duration_s,
+            request_throughput: per_second(samples.len() as f64),
+            token_throughput: per_second(output_tokens as f64),
+            ttft_ms: Percentiles::new(samples.iter().map(|s| s.ttft).collect()),
+            tpot_ms: Percentiles::new(samples.iter().filter_map(|s| s.tpot).collect()),
+            latency_ms: Percentiles::new(samples.iter().map(|s| s.latency).collect()),
+            errors: errors.into_iter().collect(),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Requests: {} ({} failed) in {:.2}s",
+            self.requests, self.failures, self.duration_s
+        )?;
+        writeln!(
+            f,
+            "Throughput: {:.2} requests/s, {:.2} tokens/s",
+            self.request_throughput, self.token_throughput
+        )?;
+        writeln!(f, "{:<12}{:>12}{:>12}{:>12}", "", "p50", "p90", "p99")?;
+        for (name, percentiles) in [
+            ("TTFT (ms)", &self.ttft_ms),
+            ("TPOT (ms)", &self.tpot_ms),
+            ("E2E (ms)", &self.latency_ms),
+        ] {
+            match percentiles {
+                Some(p) => writeln!(f, "{name:<12}{:>12.2}{:>12.2}{:>12.2}", p.p50, p.p90, p.p99)?,
+                None => writeln!(f, "{name:<12}{:>12}{:>12}{:>12}", "-", "-", "-")?,
+            }
+        }
+        for (err, count) in &self.errors {
+            writeln!(f, "Error ({count} requests): {err}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Send the requests of the trace at their time, and wait for all their generations
+async fn replay(trace: Vec<TraceRequest>, target: Target, speedup: f64) -> Report {
+    let target = Arc::new(target);
+    let start = Instant::now();
+    let handles: Vec<_> = trace
+        .into_iter()
+        .map(|request| {
+            let target = target.clone();
+            let send_at = start + Duration::from_secs_f64(request.timestamp / speedup);
+            tokio::spawn(async move {
+                tokio::time::sleep_until(send_at).await;
+                target.replay(&request).await
+            })
+        })
+        .collect();
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        results.push(handle.await.unwrap_or_else(|err| Err(err.to_string())));
+    }
+    Report::new(results, start.elapsed())
+}
+
+/// `bench` subcommand of the router binaries
+pub async fn run(args: BenchArgs) -> Result<(), BenchError> {
+    if args.speedup.is_nan() || args.speedup <= 0.0 {
+        return Err(BenchError::Speedup);
+    }
+    let path = args.trace.display().to_string();
+    let trace = std::fs::read_to_string(&args.trace).map_err(|err| BenchError::Read(path, err))?;
+    let trace = parse_trace(&trace)?;
+
+    let target = match args.mock_tokens_per_second {
+        // All the requests of the trace may run at once
+        Some(tokens_per_second) => {
+            Target::Mock(MockBackend::new(tokens_per_second).infer(trace.len()))
+        }
+        None => Target::Router {
+            client: reqwest::Client::builder()
+                .build()
+                .map_err(BenchError::Client)?,
+            url: args.url,
+            api_key: args.api_key,
+        },
+    };
+    let report = replay(trace, target, args.speedup).await;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print!("{report}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trace() {
+        let trace = parse_trace(
+            "{\"timestamp\": 10.5, \"inputs\": \"a b\", \"parameters\": {\"max_new_tokens\": 4}}\n\
+            \n\
+            {\"timestamp\": 12.0, \"inputs\": \"c\"}\n",
+        )
+        .unwrap();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].timestamp, 0.0);
+        assert_eq!(trace[0].max_new_tokens, Some(4));
+        assert_eq!(trace[1].timestamp, 1.5);
+        assert_eq!(trace[1].max_new_tokens, None);
+
+        // The parameters are the ones of the router
+        let err = parse_trace(
+            "{\"inputs\": \"a\"}\n{\"inputs\": \"b\", \"parameters\": {\"max_new_tokens\": \"x\"}}",
+        )
+        .unwrap_err();
+        assert!(matches!(err, BenchError::Parse(2, _)));
+        let err = parse_trace("{\"inputs\": \"a\"}\n{\"timestamp\": -1.0, \"inputs\": \"b\"}")
+            .unwrap_err();
+        assert!(matches!(err, BenchError::Timestamp(2)));
+        let err = parse_trace("{\"timestamp\": 1e300, \"inputs\": \"a\"}").unwrap_err();
+        assert!(matches!(err, BenchError::Timestamp(1)));
+        assert!(matches!(parse_trace("\n"), Err(BenchError::Empty)));
+    }
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = SseParser::default();
+        assert!(parser
+            .push(b"event: queue_position\ndata: {\"queue_position\": 1}\n\n: ping\n\ndata: {\"to")
+            .is_empty());
+        assert_eq!(
+            parser.push(b"ken\": 1}\r\n\r\ndata:{}\n\n"),
+            ["{\"token\": 1}", "{}"]
+        );
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let values = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(
+            Percentiles::new(values),
+            Some(Percentiles {
+                p50: 50.0,
+                p90: 90.0,
+                p99: 99.0,
+            })
+        );
+        assert_eq!(Percentiles::new(vec![]), None);
+    }
+
+    #[tokio::test]
+    async fn test_replay_mock() {
+        let trace = parse_trace(
+            "{\"inputs\": \"a\", \"parameters\": {\"max_new_tokens\": 3}}\n\
+            {\"inputs\": \"b\", \"parameters\": {\"max_new_tokens\": 1}}\n\
+            {\"timestamp\": 0.01, \"inputs\": \"c\", \"parameters\": {\"max_new_tokens\": 0}}",
+        )
+        .unwrap();
+        let infer = MockBackend::default().infer(trace.len());
+        let report = replay(trace, Target::Mock(infer), 1.0).await;
+        assert_eq!(report.requests, 3);
+        // The router rejects the last request
+        assert_eq!(report.failures, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.ttft_ms.is_some());
+        // Only the first request generates more than one token
+        assert!(report.tpot_ms.is_some());
+        assert!(report.token_throughput > 0.0);
+    }
+}
@@ -0,0 +1,612 @@
+/// gRPC frontend of the router, served next to the HTTP API.
+///
+/// The requests are converted to the requests of the HTTP routes and go through the same
+/// validation, queue and backend, with the rate limits, quotas and tenants of the HTTP routes.
+use crate::auth::{self, ApiKey, ApiKeys, Denied, Scope};
+use crate::infer::{Infer, InferError};
+use crate::rate_limit::{self, InFlight, RateLimitedClient, RateLimiter};
+use crate::server::{generate_internal, generate_stream_internal, ComputeType, StreamEvent};
+use crate::tenant;
+use crate::{
+    default_parameters, AdapterWeight as HttpAdapterWeight, ChatRequest as HttpChatRequest,
+    ErrorResponse, GenerateParameters as HttpGenerateParameters,
+    GenerateRequest as HttpGenerateRequest, GrammarType, Info, Priority as HttpPriority,
+    StreamResponse, TruncationSide as HttpTruncationSide,
+};
+use axum::http::{HeaderName, StatusCode};
+use axum::{Extension, Json};
+use futures::{Stream, StreamExt};
+use pb::router::v1::text_generation_router_server::{
+    TextGenerationRouter, TextGenerationRouterServer,
+};
+use pb::router::v1::{
+    ChatRequest, ChatResponse, ChatStreamResponse, Details, FinishReason, GenerateParameters,
+    GenerateRequest, GenerateResponse, GenerateStreamResponse, Priority, Token, TopTokens,
+    TruncationSide, Usage,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+mod pb;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+#[derive(Clone)]
+pub(crate) struct RouterService {
+    infer: Infer,
+    compute_type: ComputeType,
+    info: Info,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    tenant_header: Option<HeaderName>,
+}
+
+/// Client of an admitted request, whose slot is held until the end of its response
+struct Admission {
+    key: Option<Arc<ApiKey>>,
+    tenant: Option<String>,
+    client: Option<RateLimitedClient>,
+    in_flight: Option<InFlight>,
+}
+
+impl Admission {
+    /// Run `future` on behalf of the client, as the middlewares of the HTTP routes
+    async fn scope<F: Future>(&self, future: F) -> F::Output {
+        let future = rate_limit::scope(self.client.clone(), future);
+        auth::scope(self.key.clone(), tenant::scope(self.tenant.clone(), future)).await
+    }
+}
+
+impl RouterService {
+    pub(crate) fn new(
+        infer: Infer,
+        compute_type: ComputeType,
+        info: Info,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        tenant_header: Option<HeaderName>,
+    ) -> Self {
+        Self {
+            infer,
+            compute_type,
+            info,
+            rate_limiter,
+            tenant_header,
+        }
+    }
+
+    /// Apply the rate limits of the client of a request authenticated by `serve`, and record
+    /// its tenant
+    fn admit<T>(&self, request: &Request<T>) -> Result<Admission, Status> {
+        let key = request.extensions().get::<Arc<ApiKey>>().cloned();
+        let metadata = |name: &str| {
+            request
+                .metadata()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let header = self
+            .tenant_header
+            .as_ref()
+            .and_then(|name| metadata(name.as_str()));
+        let tenant = tenant::identify_value(key.as_deref().map(ApiKey::name), header);
+        let (client, in_flight) = match &self.rate_limiter {
+            Some(rate_limiter) => {
+                let peer = request.remote_addr().map(|addr| addr.ip());
+                rate_limiter
+                    .admit(key.as_deref(), peer)
+                    .map_err(|limited| {
+                        let mut status = Status::resource_exhausted(limited.message);
+                        let retry_after = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                        status
+                            .metadata_mut()
+                            .insert("retry-after", retry_after.to_string().parse().unwrap());
+                        status
+                    })?
+            }
+            None => (None, None),
+        };
+        Ok(Admission {
+            key,
+            tenant,
+            client,
+            in_flight,
+        })
+    }
+
+    /// Name of the model of a chat request, as on the HTTP route
+    fn model_id(&self, model: Option<&str>) -> String {
+        match model {
+            Some("tgi") | None => self.info.model_id.clone(),
+            Some(model) => model.to_string(),
+        }
+    }
+
+    /// Templated generate request of a chat request
+    fn chat_request(&self, request: ChatRequest) -> Result<HttpGenerateRequest, Status> {
+        let response_format = request
+            .json_schema
+            .as_deref()
+            .map(parse_json_schema)
+            .transpose()?
+            .map(|schema| serde_json::json!({"type": "json", "value": schema}));
+        let messages: Vec<_> = request
+            .messages
+            .iter()
+            .map(|message| serde_json::json!({"role": message.role, "content": message.content}))
+            .collect();
+        // Deserialized like the body of the HTTP route, to get the same defaults
+        let chat: HttpChatRequest = serde_json::from_value(serde_json::json!({
+            "model": request.model,
+            "messages": messages,
+            "max_tokens": request.max_tokens,
+            "temperature": request.temperature,
+            "top_p": request.top_p,
+            "frequency_penalty": request.frequency_penalty,
+            "presence_penalty": request.presence_penalty,
+            "seed": request.seed,
+            "stop": request.stop,
+            "stop_token_ids": request.stop_token_ids,
+            "response_format": response_format,
+            "priority": HttpPriority::from(request.priority()),
+            "session_id": request.session_id,
+            "timeout_ms": request.timeout_ms,
+            "deadline_ms": request.deadline_ms,
+        }))
+        .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let (mut generate_request, _) = chat.try_into_generate(&self.infer).map_err(infer_error)?;
+        // For the usage
+        generate_request.parameters.details = true;
+        Ok(generate_request)
+    }
+}
+
+#[tonic::async_trait]
+impl TextGenerationRouter for RouterService {
+    async fn generate(
+        &self,
+        request: Request<GenerateRequest>,
+    ) -> Result<Response<GenerateResponse>, Status> {
+        let admission = self.admit(&request)?;
+        let request = generate_request(request.into_inner())?;
+        let (_, input_length, Json(response)) = admission
+            .scope(generate_internal(
+                Extension(self.infer.clone()),
+                self.compute_type.clone(),
+                Json(request),
+                tracing::Span::current(),
+            ))
+            .await
+            .map_err(error_response)?;
+
+        Ok(Response::new(GenerateResponse {
+            generated_text: response.generated_text,
+            details: response.details.map(|details| Details {
+                finish_reason: FinishReason::from(&details.finish_reason).into(),
+                generated_tokens: details.generated_tokens,
+                seed: details.seed,
+                input_length,
+                prefill: details
+                    .prefill
+                    .into_iter()
+                    .map(|token| Token {
+                        id: token.id,
+                        text: token.text,
+                        logprob: token.logprob,
+                        special: false,
+                    })
+                    .collect(),
+                tokens: details.tokens.into_iter().map(Token::from).collect(),
+                top_tokens: details
+                    .top_tokens
+                    .into_iter()
+                    .map(|tokens| TopTokens {
+                        tokens: tokens.into_iter().map(Token::from).collect(),
+                    })
+                    .collect(),
+            }),
+        }))
+    }
+
+    type GenerateStreamStream = ResponseStream<GenerateStreamResponse>;
+
+    async fn generate_stream(
+        &self,
+        request: Request<GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let admission = self.admit(&request)?;
+        let request = generate_request(request.into_inner())?;
+        let (_, stream) = admission
+            .scope(generate_stream_internal(
+                self.infer.clone(),
+                self.compute_type.clone(),
+                Json(request),
+                tracing::Span::current(),
+            ))
+            .await;
+
+        // The stream stays in flight until it is done
+        let in_flight = admission.in_flight;
+        let stream = tokens(stream).map(move |response| {
+            let _in_flight = &in_flight;
+            let response = response.map_err(infer_error)?;
+            Ok(GenerateStreamResponse {
+                index: response.index,
+                token: Some(response.token.into()),
+                top_tokens: response.top_tokens.into_iter().map(Token::from).collect(),
+                generated_text: response.generated_text,
+                details: response.details.map(|details| Details {
+                    finish_reason: FinishReason::from(&details.finish_reason).into(),
+                    generated_tokens: details.generated_tokens,
+                    seed: details.seed,
+                    input_length: details.input_length,
+                    prefill: Vec::new(),
+                    tokens: Vec::new(),
+                    top_tokens: Vec::new(),
+                }),
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn chat(&self, request: Request<ChatRequest>) -> Result<Response<ChatResponse>, Status> {
+        let admission = self.admit(&request)?;
+        let request = request.into_inner();
+        let model = self.model_id(request.model.as_deref());
+        let generate_request = self.chat_request(request)?;
+        let (_, input_length, Json(response)) = admission
+            .scope(generate_internal(
+                Extension(self.infer.clone()),
+                self.compute_type.clone(),
+                Json(generate_request),
+                tracing::Span::current(),
+            ))
+            .await
+            .map_err(error_response)?;
+
+        let (finish_reason, generated_tokens) = response
+            .details
+            .map(|details| (details.finish_reason.format(true), details.generated_tokens))
+            .unwrap_or_default();
+        Ok(Response::new(ChatResponse {
+            model,
+            content: response.generated_text,
+            finish_reason,
+            usage: Some(usage(input_length, generated_tokens)),
+        }))
+    }
+
+    type ChatStreamStream = ResponseStream<ChatStreamResponse>;
+
+    async fn chat_stream(
+        &self,
+        request: Request<ChatRequest>,
+    ) -> Result<Response<Self::ChatStreamStream>, Status> {
+        let admission = self.admit(&request)?;
+        let request = request.into_inner();
+        let model = self.model_id(request.model.as_deref());
+        let generate_request = self.chat_request(request)?;
+        let (_, stream) = admission
+            .scope(generate_stream_internal(
+                self.infer.clone(),
+                self.compute_type.clone(),
+                Json(generate_request),
+                tracing::Span::current(),
+            ))
+            .await;
+
+        let in_flight = admission.in_flight;
+        let stream = tokens(stream).map(move |response| {
+            let _in_flight = &in_flight;
+            let response = response.map_err(infer_error)?;
+            let content = if response.token.special {
+                String::new()
+            } else {
+                response.token.text
+            };
+            let (finish_reason, usage) = match response.details {
+                Some(details) => (
+                    Some(details.finish_reason.format(true)),
+                    Some(usage(details.input_length, details.generated_tokens)),
+                ),
+                None => (None, None),
+            };
+            Ok(ChatStreamResponse {
+                model: model.clone(),
+                content,
+                finish_reason,
+                usage,
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Serve the gRPC API on `addr` until `shutdown` resolves. The clients authenticate with the
+/// same `authorization` metadata as the HTTP `Authorization` header, with a key of the
+/// `generate` scope.
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    service: RouterService,
+    api_keys: Option<Arc<ApiKeys>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    let authenticate = move |mut request: Request<()>| {
+        let Some(api_keys) = &api_keys else {
+            return Ok(request);
+        };
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        match api_keys.authorize(authorization, Scope::Generate) {
+            Ok(key) => {
+                if let Some(exceeded) = key.exceeded_quota() {
+                    metrics::counter!("tgi_request_failure", "err" => "quota_exceeded")
+                        .increment(1);
+                    return Err(Status::resource_exhausted(exceeded.message()));
+                }
+                request.extensions_mut().insert(key);
+                Ok(request)
+            }
+            Err(denied @ Denied::Unauthenticated) => Err(Status::unauthenticated(denied.message())),
+            Err(denied) => Err(Status::permission_denied(denied.message())),
+        }
+    };
+
+    tracing::info!("Serving the gRPC API on {addr}");
+    Server::builder()
+        .add_service(TextGenerationRouterServer::with_interceptor(
+            service,
+            authenticate,
+        ))
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+fn generate_request(request: GenerateRequest) -> Result<HttpGenerateRequest, Status> {
+    let parameters = request.parameters.unwrap_or_default();
+    let grammar = match (&parameters.json_schema, &parameters.regex) {
+        (Some(_), Some(_)) => {
+            return Err(Status::invalid_argument(
+                "`json_schema` and `regex` are mutually exclusive",
+            ))
+        }
+        (Some(json_schema), None) => Some(GrammarType::Json(parse_json_schema(json_schema)?)),
+        (None, Some(regex)) => Some(GrammarType::Regex(regex.clone())),
+        (None, None) => None,
+    };
+    let priority = parameters.priority().into();
+    let truncation_side = parameters
+        .truncation_side
+        .map(|_| parameters.truncation_side().into());
+    let GenerateParameters {
+        best_of,
+        temperature,
+        repetition_penalty,
+        frequency_penalty,
+        presence_penalty,
+        top_k,
+        top_p,
+        typical_p,
+        min_p,
+        no_repeat_ngram_size,
+        guidance_scale,
+        negative_prompt,
+        do_sample,
+        max_new_tokens,
+        min_new_tokens,
+        return_full_text,
+        stop,
+        stop_token_ids,
+        truncate,
+        watermark,
+        details,
+        decoder_input_details,
+        seed,
+        top_n_tokens,
+        adapter_id,
+        adapters,
+        session_id,
+        timeout_ms,
+        deadline_ms,
+        ..
+    } = parameters;
+    let adapters = (!adapters.is_empty()).then(|| {
+        adapters
+            .into_iter()
+            .map(|adapter| HttpAdapterWeight {
+                id: adapter.id,
+                weight: adapter.weight,
+            })
+            .collect()
+    });
+    let default = default_parameters();
+
+    Ok(HttpGenerateRequest {
+        inputs: request.inputs,
+        template: None,
+        variables: None,
+        parameters: HttpGenerateParameters {
+            best_of: best_of.map(|best_of| best_of as usize),
+            temperature,
+            repetition_penalty,
+            frequency_penalty,
+            presence_penalty,
+            top_k,
+            top_p,
+            typical_p,
+            min_p,
+            no_repeat_ngram_size,
+            guidance_scale,
+            negative_prompt,
+            do_sample: do_sample.unwrap_or(default.do_sample),
+            max_new_tokens,
+            min_new_tokens,
+            return_full_text,
+            stop,
+            stop_token_ids,
+            truncate: truncate.map(|truncate| truncate as usize),
+            truncation_side,
+            watermark,
+            details,
+            decoder_input_details,
+            seed,
+            top_n_tokens,
+            grammar,
+            adapter_id,
+            adapters,
+            priority: Some(priority),
+            session_id,
+            timeout_ms,
+            deadline_ms,
+            ..default
+        },
+        add_special_tokens: true,
+    })
+}
+
+fn parse_json_schema(json_schema: &str) -> Result<serde_json::Value, Status> {
+    serde_json::from_str(json_schema)
+        .map_err(|err| Status::invalid_argument(format!("Invalid `json_schema`: {err}")))
+}
+
+fn usage(prompt_tokens: u32, completion_tokens: u32) -> Usage {
+    Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+/// Tokens of a stream of the HTTP routes, the queue positions are only sent to the SSE streams
+fn tokens(
+    stream: impl Stream<Item = Result<StreamEvent, InferError>>,
+) -> impl Stream<Item = Result<StreamResponse, InferError>> {
+    stream.filter_map(|event| {
+        futures::future::ready(match event {
+            Ok(StreamEvent::Queued(_) | StreamEvent::Prefilling) => None,
+            Ok(StreamEvent::Token(response)) => Some(Ok(response)),
+            Err(err) => Some(Err(err)),
+        })
+    })
+}
+
+fn infer_error(err: InferError) -> Status {
+    error_response(err.into())
+}
+
+/// gRPC status of an error of the HTTP routes. Its `type` is sent in the `x-error-type`
+/// metadata.
+fn error_response((status_code, Json(response)): (StatusCode, Json<ErrorResponse>)) -> Status {
+    let code = match status_code {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::NOT_IMPLEMENTED => Code::Unimplemented,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        status_code if status_code.as_u16() == 499 => Code::Cancelled,
+        _ => Code::Internal,
+    };
+    let mut status = Status::new(code, response.error.message);
+    if let Ok(error_type) = response.error.error_type.parse() {
+        status.metadata_mut().insert("x-error-type", error_type);
+    }
+    status
+}
+
+impl From<Priority> for HttpPriority {
+    fn from(priority: Priority) -> Self {
+        match priority {
+            Priority::Normal => HttpPriority::Normal,
+            Priority::Low => HttpPriority::Low,
+            Priority::High => HttpPriority::High,
+        }
+    }
+}
+
+impl From<TruncationSide> for HttpTruncationSide {
+    fn from(side: TruncationSide) -> Self {
+        match side {
+            TruncationSide::Left => HttpTruncationSide::Left,
+            TruncationSide::Right => HttpTruncationSide::Right,
+            TruncationSide::Middle => HttpTruncationSide::Middle,
+        }
+    }
+}
+
+impl From<&crate::FinishReason> for FinishReason {
+    fn from(finish_reason: &crate::FinishReason) -> Self {
+        match finish_reason {
+            crate::FinishReason::Length => FinishReason::Length,
+            crate::FinishReason::EndOfSequenceToken => FinishReason::EosToken,
+            crate::FinishReason::StopSequence => FinishReason::StopSequence,
+            crate::FinishReason::Timeout => FinishReason::Timeout,
+            crate::FinishReason::ContentFilter => FinishReason::ContentFilter,
+            crate::FinishReason::Cancelled => FinishReason::Cancelled,
+        }
+    }
+}
+
+impl From<crate::Token> for Token {
+    fn from(token: crate::Token) -> Self {
+        Self {
+            id: token.id,
+            text: token.text,
+            logprob: token.logprob,
+            special: token.special,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_request() {
+        let request = generate_request(GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: Some(GenerateParameters {
+                max_new_tokens: Some(10),
+                regex: Some("[0-9]+".to_string()),
+                priority: Priority::High.into(),
+                ..Default::default()
+            }),
+        })
+        .unwrap();
+        assert_eq!(request.inputs, "Hello");
+        assert_eq!(request.parameters.max_new_tokens, Some(10));
+        assert_eq!(request.parameters.priority, Some(HttpPriority::High));
+        assert_eq!(
+            request.parameters.grammar,
+            Some(GrammarType::Regex("[0-9]+".to_string()))
+        );
+        // Same default as the HTTP routes
+        assert!(request.parameters.do_sample);
+
+        let err = generate_request(GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: Some(GenerateParameters {
+                json_schema: Some("{".to_string()),
+                ..Default::default()
+            }),
+        })
+        .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let status = infer_error(InferError::QueueFull);
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("x-error-type").unwrap(), "queue_full");
+    }
+}
@@ -0,0 +1,634 @@
+/// Idempotency keys, so that the retries of a request get its response instead of generating it
+/// again.
+///
+/// The first request is generated in a task of its own, so that its retries can join it while
+/// its client reconnects, and it is cancelled once the last of them is gone.
+use crate::{auth, rate_limit, request_id, ErrorResponse};
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::http::header::{AUTHORIZATION, UPGRADE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::StreamExt;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::Instrument;
+
+/// Header carrying the key, chosen by the client and sent again with the retries
+static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Header set on the responses replayed to a retry
+static IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+/// Longest key accepted from a client, requests with longer ones are not deduplicated
+const MAX_KEY_LENGTH: usize = 256;
+/// Bytes of the responses kept by the store, the requests are generated without deduplication
+/// while the running ones fill it
+const MAX_RECORDED_BYTES: usize = 256 * 1024 * 1024;
+
+/// Response of a request, recorded while it is generated
+#[derive(Debug, Default)]
+struct Recorded {
+    /// Status and headers, once the route answered
+    head: Option<(StatusCode, HeaderMap)>,
+    /// Chunks of the body received so far
+    chunks: Vec<Bytes>,
+    /// Total length of `chunks`
+    bytes: usize,
+    /// When the whole body was received
+    completed: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Recording {
+    recorded: Mutex<Recorded>,
+    /// Notified of every change of `recorded`
+    updates: watch::Sender<()>,
+}
+
+impl Recording {
+    fn new() -> Self {
+        Self {
+            recorded: Mutex::new(Recorded::default()),
+            updates: watch::Sender::new(()),
+        }
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Recorded)) {
+        update(&mut self.recorded.lock().unwrap());
+        self.updates.send_replace(());
+    }
+
+    fn subscribe(self: &Arc<Self>) -> Subscriber {
+        Subscriber {
+            recording: self.clone(),
+            updates: self.updates.subscribe(),
+        }
+    }
+}
+
+/// Client waiting for the response of a recording, which is generated as long as it has one
+#[derive(Debug)]
+struct Subscriber {
+    recording: Arc<Recording>,
+    updates: watch::Receiver<()>,
+}
+
+impl Subscriber {
+    /// Response replaying the recording, from its first chunk and until its last one
+    async fn replay(self) -> Response {
+        let Subscriber {
+            recording,
+            mut updates,
+        } = self;
+        let (status, headers) = loop {
+            {
+                let recorded = recording.recorded.lock().unwrap();
+                if let Some(head) = recorded.head.clone() {
+                    break head;
+                }
+                // Its other clients left before the route answered
+                if recorded.completed.is_some() {
+                    return (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        Json(ErrorResponse::new(
+                            "cancelled",
+                            "The request with this `Idempotency-Key` was cancelled, retry it",
+                        )),
+                    )
+                        .into_response();
+                }
+            }
+            // The sender lives as long as the recording
+            let _ = updates.changed().await;
+        };
+        let body = async_stream::stream! {
+            let mut sent = 0;
+            loop {
+                let (chunks, completed) = {
+                    let recorded = recording.recorded.lock().unwrap();
+                    (recorded.chunks[sent..].to_vec(), recorded.completed.is_some())
+                };
+                sent += chunks.len();
+                for chunk in chunks {
+                    yield Ok::<_, Infallible>(chunk);
+                }
+                if completed {
+                    break;
+                }
+                let _ = updates.changed().await;
+            }
+        };
+        let mut response = Response::new(Body::from_stream(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        response
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Fingerprint of the request that used the key first
+    fingerprint: u64,
+    recording: Arc<Recording>,
+}
+
+/// Outcome of the lookup of a key
+#[derive(Debug)]
+enum Lookup {
+    /// First request with the key, to record
+    Record(Subscriber),
+    /// Retry of a running or recently completed request
+    Replay(Subscriber),
+    /// Key used by a different request
+    Conflict,
+    /// No room to record the request, which is generated without deduplication
+    Full,
+}
+
+/// Responses of the requests with an idempotency key, kept while they run and for `ttl` after
+#[derive(Debug)]
+pub(crate) struct IdempotencyStore {
+    capacity: usize,
+    ttl: Duration,
+    /// Largest request body read to fingerprint the request
+    payload_limit: usize,
+    /// Bytes of the recorded responses past which the completed ones are forgotten
+    max_bytes: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyStore {
+    /// Store of up to `size` responses, None if `size` is not set
+    pub(crate) fn new(size: Option<usize>, ttl: Duration, payload_limit: usize) -> Option<Self> {
+        let capacity = size.filter(|&size| size > 0)?;
+        Some(Self {
+            capacity,
+            ttl,
+            payload_limit,
+            max_bytes: MAX_RECORDED_BYTES,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn lookup(&self, key: &str, fingerprint: u64) -> Lookup {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let expired = |entry: &Entry| {
+            entry
+                .recording
+                .recorded
+                .lock()
+                .unwrap()
+                .completed
+                .is_some_and(|completed| now.saturating_duration_since(completed) >= self.ttl)
+        };
+        if let Some(entry) = entries.get(key).filter(|entry| !expired(entry)) {
+            if entry.fingerprint != fingerprint {
+                return Lookup::Conflict;
+            }
+            // Subscribed under the lock, so that the request is not cancelled in between
+            return Lookup::Replay(entry.recording.subscribe());
+        }
+
+        let bytes = |entries: &HashMap<String, Entry>| -> usize {
+            let bytes = |entry: &Entry| entry.recording.recorded.lock().unwrap().bytes;
+            entries.values().map(bytes).sum()
+        };
+        if entries.len() >= self.capacity || bytes(&entries) >= self.max_bytes {
+            entries.retain(|_, entry| !expired(entry));
+        }
+        while (entries.len() >= self.capacity && !entries.contains_key(key))
+            || bytes(&entries) >= self.max_bytes
+        {
+            // Make room by forgetting the oldest completed response, never a running one
+            let oldest = entries
+                .iter()
+                .filter_map(|(key, entry)| {
+                    let completed = entry.recording.recorded.lock().unwrap().completed;
+                    completed.map(|completed| (completed, key.clone()))
+                })
+                .min();
+            match oldest {
+                Some((_, oldest)) => {
+                    entries.remove(&oldest);
+                }
+                None => return Lookup::Full,
+            }
+        }
+        let recording = Arc::new(Recording::new());
+        let subscriber = recording.subscribe();
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint,
+                recording,
+            },
+        );
+        Lookup::Record(subscriber)
+    }
+
+    /// Resolve once the recording of `key` has no subscriber left, forgetting it so that no
+    /// retry joins it
+    async fn abandoned(&self, key: &str, recording: &Recording) {
+        loop {
+            recording.updates.closed().await;
+            let mut entries = self.entries.lock().unwrap();
+            if recording.updates.receiver_count() == 0 {
+                if entries
+                    .get(key)
+                    .is_some_and(|entry| std::ptr::eq(&*entry.recording, recording))
+                {
+                    entries.remove(key);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Forget the response of `key`, so that the next retry generates the request again
+    fn forget(&self, key: &str, recording: &Arc<Recording>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .get(key)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.recording, recording))
+        {
+            entries.remove(key);
+        }
+    }
+}
+
+/// Whether a retry should get the response instead of trying again: the requests refused for
+/// lack of capacity and the failed ones are generated again
+fn is_final(status: StatusCode) -> bool {
+    status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error()
+}
+
+fn fingerprint(request: &Request, body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.method().hash(&mut hasher);
+    request.uri().path().hash(&mut hasher);
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Generate `request` and record its response, until it is complete or all the clients waiting
+/// for it are gone
+fn record(
+    store: Arc<IdempotencyStore>,
+    key: String,
+    recording: Arc<Recording>,
+    request: Request,
+    next: Next,
+) {
+    // The task outlives the request task and its context
+    let future = async move {
+        let generation = async {
+            let response = next.run(request).await;
+            let (parts, body) = response.into_parts();
+            let status = parts.status;
+            recording.update(|recorded| recorded.head = Some((status, parts.headers)));
+
+            let mut body = body.into_data_stream();
+            let mut failed = false;
+            let mut oversized = false;
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) => recording.update(|recorded| {
+                        recorded.bytes += chunk.len();
+                        recorded.chunks.push(chunk);
+                        oversized = recorded.bytes > store.max_bytes;
+                    }),
+                    Err(err) => {
+                        tracing::error!("Idempotent response failed: {err}");
+                        failed = true;
+                        break;
+                    }
+                }
+                // Sent to the clients already waiting, but not kept for the retries
+                if oversized {
+                    store.forget(&key, &recording);
+                }
+            }
+            if failed || !is_final(status) {
+                store.forget(&key, &recording);
+            }
+        };
+        tokio::select! {
+            _ = generation => {}
+            _ = store.abandoned(&key, &recording) => {
+                tracing::info!("Idempotent request cancelled, its clients are gone");
+            }
+        }
+        // Dropping the generation stopped it
+        recording.update(|recorded| recorded.completed = Some(Instant::now()));
+    };
+    let future = request_id::scope(
+        request_id::current_scope(),
+        auth::scope(
+            auth::current_key(),
+            rate_limit::scope(rate_limit::current_client(), future),
+        ),
+    );
+    tokio::spawn(future.instrument(tracing::Span::current()));
+}
+
+/// Middleware answering the retries of the requests with an `Idempotency-Key` header with the
+/// response of the first request, while it runs and for a while after it completed
+pub(crate) async fn idempotency(
+    store: Arc<IdempotencyStore>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request
+        .headers()
+        .get(&IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LENGTH)
+    else {
+        return next.run(request).await;
+    };
+    // The request of a WebSocket is only sent once the connection is upgraded
+    if request.headers().contains_key(UPGRADE) {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(
+                ErrorResponse::new(
+                    "validation",
+                    "`Idempotency-Key` is not supported on WebSocket connections",
+                )
+                .with_param("Idempotency-Key"),
+            ),
+        )
+            .into_response();
+    }
+    // The keys of a client cannot collide with the ones of another client
+    let mut hasher = DefaultHasher::new();
+    request.headers().get(AUTHORIZATION).hash(&mut hasher);
+    let key = format!("{:016x}:{key}", hasher.finish());
+
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, store.payload_limit).await {
+        Ok(body) => body,
+        Err(_) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(ErrorResponse::new(
+                    "validation",
+                    "Request body exceeds the payload limit",
+                )),
+            )
+                .into_response();
+        }
+    };
+    let request = Request::from_parts(parts, Body::from(body.clone()));
+
+    match store.lookup(&key, fingerprint(&request, &body)) {
+        Lookup::Record(subscriber) => {
+            record(store, key, subscriber.recording.clone(), request, next);
+            subscriber.replay().await
+        }
+        Lookup::Replay(subscriber) => {
+            metrics::counter!("tgi_request_idempotent_replay").increment(1);
+            let mut response = subscriber.replay().await;
+            response.headers_mut().insert(
+                IDEMPOTENT_REPLAYED.clone(),
+                HeaderValue::from_static("true"),
+            );
+            response
+        }
+        Lookup::Conflict => {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(
+                    ErrorResponse::new(
+                        "validation",
+                        "`Idempotency-Key` was already used by a different request",
+                    )
+                    .with_param("Idempotency-Key"),
+                ),
+            )
+                .into_response()
+        }
+        Lookup::Full => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(size: usize, ttl: Duration) -> IdempotencyStore {
+        IdempotencyStore::new(Some(size), ttl, 1024).unwrap()
+    }
+
+    fn complete(recording: &Arc<Recording>) {
+        recording.update(|recorded| {
+            recorded.head = Some((StatusCode::OK, HeaderMap::new()));
+            recorded.chunks.push(Bytes::from_static(b"done"));
+            recorded.completed = Some(Instant::now());
+        });
+    }
+
+    #[test]
+    fn test_idempotency_lookup() {
+        assert!(IdempotencyStore::new(None, Duration::from_secs(60), 1024).is_none());
+        let store = store(8, Duration::from_secs(60));
+        let Lookup::Record(Subscriber { recording, .. }) = store.lookup("a", 1) else {
+            panic!("Expected the first request to be recorded");
+        };
+        // Running or completed, the retries get the same recording
+        assert!(
+            matches!(store.lookup("a", 1), Lookup::Replay(r) if Arc::ptr_eq(&r.recording, &recording))
+        );
+        complete(&recording);
+        assert!(
+            matches!(store.lookup("a", 1), Lookup::Replay(r) if Arc::ptr_eq(&r.recording, &recording))
+        );
+        assert!(matches!(store.lookup("a", 2), Lookup::Conflict));
+
+        store.forget("a", &recording);
+        assert!(matches!(store.lookup("a", 2), Lookup::Record(_)));
+    }
+
+    #[test]
+    fn test_idempotency_capacity() {
+        let store = store(2, Duration::from_secs(60));
+        let Lookup::Record(Subscriber {
+            recording: first, ..
+        }) = store.lookup("a", 1)
+        else {
+            panic!("Expected a recording");
+        };
+        assert!(matches!(store.lookup("b", 1), Lookup::Record(_)));
+        // Both requests are running
+        assert!(matches!(store.lookup("c", 1), Lookup::Full));
+        complete(&first);
+        assert!(matches!(store.lookup("c", 1), Lookup::Record(_)));
+        assert!(matches!(store.lookup("a", 1), Lookup::Full));
+    }
+
+    #[test]
+    fn test_idempotency_ttl() {
+        let store = store(8, Duration::ZERO);
+        let Lookup::Record(Subscriber { recording, .. }) = store.lookup("a", 1) else {
+            panic!("Expected a recording");
+        };
+        assert!(matches!(store.lookup("a", 1), Lookup::Replay(_)));
+        complete(&recording);
+        assert!(matches!(store.lookup("a", 2), Lookup::Record(_)));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_replay() {
+        let recording = Arc::new(Recording::new());
+        let replay = tokio::spawn(recording.subscribe().replay());
+        recording.update(|recorded| {
+            recorded.head = Some((StatusCode::OK, HeaderMap::new()));
+            recorded.chunks.push(Bytes::from_static(b"data: 1\n\n"));
+        });
+        recording.update(|recorded| {
+            recorded.chunks.push(Bytes::from_static(b"data: 2\n\n"));
+            recorded.completed = Some(Instant::now());
+        });
+        let response = replay.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"data: 1\n\ndata: 2\n\n");
+    }
+
+    #[test]
+    fn test_idempotency_max_bytes() {
+        let mut store = store(8, Duration::from_secs(60));
+        store.max_bytes = 8;
+        let Lookup::Record(Subscriber { recording, .. }) = store.lookup("a", 1) else {
+            panic!("Expected a recording");
+        };
+        recording.update(|recorded| recorded.bytes = 8);
+        // The running response fills the store
+        assert!(matches!(store.lookup("b", 1), Lookup::Full));
+        complete(&recording);
+        assert!(matches!(store.lookup("b", 1), Lookup::Record(_)));
+        assert!(matches!(store.lookup("a", 1), Lookup::Record(_)));
+    }
+
+    /// Serve a route counting its calls behind the middleware, the route streams the chunks
+    /// sent to `chunks` and reports on `dropped` when its body is dropped
+    async fn serve(
+        store: IdempotencyStore,
+        chunks: Arc<Mutex<Option<tokio::sync::mpsc::UnboundedReceiver<&'static str>>>>,
+        dropped: tokio::sync::mpsc::UnboundedSender<()>,
+    ) -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct DropGuard(tokio::sync::mpsc::UnboundedSender<()>);
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                let _ = self.0.send(());
+            }
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let store = Arc::new(store);
+        let route = {
+            let calls = calls.clone();
+            move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let mut chunks = chunks.lock().unwrap().take().unwrap();
+                let guard = DropGuard(dropped.clone());
+                let body = async_stream::stream! {
+                    let _guard = guard;
+                    while let Some(chunk) = chunks.recv().await {
+                        yield Ok::<_, Infallible>(chunk);
+                    }
+                };
+                async move { Body::from_stream(body) }
+            }
+        };
+        let app = axum::Router::new()
+            .route("/generate", axum::routing::post(route))
+            .layer(axum::middleware::from_fn(
+                move |request: Request, next: Next| idempotency(store.clone(), request, next),
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/generate", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, calls)
+    }
+
+    fn post(url: &str, key: &str) -> reqwest::RequestBuilder {
+        reqwest::Client::new()
+            .post(url)
+            .header("idempotency-key", key)
+            .body("{}")
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_middleware() {
+        use std::sync::atomic::Ordering;
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (dropped, _) = tokio::sync::mpsc::unbounded_channel();
+        let chunks = Arc::new(Mutex::new(Some(receiver)));
+        let (url, calls) = serve(store(8, Duration::from_secs(60)), chunks, dropped).await;
+
+        let first = post(&url, "a").send().await.unwrap();
+        // The retry joins the running request
+        let retry = post(&url, "a").send().await.unwrap();
+        assert_eq!(retry.headers().get("idempotent-replayed").unwrap(), "true");
+        sender.send("data: 1\n\n").unwrap();
+        drop(sender);
+        assert_eq!(first.text().await.unwrap(), "data: 1\n\n");
+        assert_eq!(retry.text().await.unwrap(), "data: 1\n\n");
+
+        // And the later ones get the completed response
+        let replay = post(&url, "a").send().await.unwrap();
+        assert_eq!(replay.text().await.unwrap(), "data: 1\n\n");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let conflict = post(&url, "a")
+            .body("{\"inputs\": 1}")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(conflict.status().as_u16(), 422);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_cancelled() {
+        use std::sync::atomic::Ordering;
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (dropped, mut dropped_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let chunks = Arc::new(Mutex::new(Some(receiver)));
+        let (url, calls) = serve(store(8, Duration::from_secs(60)), chunks.clone(), dropped).await;
+
+        let first = post(&url, "a").send().await.unwrap();
+        sender.send("data: 1\n\n").unwrap();
+        drop(first);
+        // The generation stops once its only client is gone
+        tokio::time::timeout(Duration::from_secs(5), dropped_receiver.recv())
+            .await
+            .unwrap();
+
+        // And the retry generates the request again
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        *chunks.lock().unwrap() = Some(receiver);
+        let retry = post(&url, "a").send().await.unwrap();
+        assert!(retry.headers().get("idempotent-replayed").is_none());
+        sender.send("data: 2\n\n").unwrap();
+        drop(sender);
+        assert_eq!(retry.text().await.unwrap(), "data: 2\n\n");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
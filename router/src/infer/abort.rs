@@ -0,0 +1,198 @@
+use crate::auth::{self, ApiKey};
+use crate::{request_id, tenant};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Client of a generation, the only one allowed to abort it
+#[derive(Debug)]
+pub(crate) struct Owner {
+    tenant: Option<String>,
+    key: Option<Arc<ApiKey>>,
+    /// Whether the router generated the id of the request, which only its client knows
+    generated_id: bool,
+}
+
+impl Owner {
+    /// Client of the request being handled
+    pub(crate) fn current() -> Self {
+        Self::new(
+            tenant::current(),
+            auth::current_key(),
+            request_id::is_generated(),
+        )
+    }
+
+    pub(crate) fn new(
+        tenant: Option<String>,
+        key: Option<Arc<ApiKey>>,
+        generated_id: bool,
+    ) -> Self {
+        Self {
+            tenant,
+            key,
+            generated_id,
+        }
+    }
+
+    /// Whether `other` is the client of the generation of this owner
+    pub(crate) fn is(&self, other: &Self) -> bool {
+        let same_key = match (&self.key, &other.key) {
+            (Some(key), Some(other)) => Arc::ptr_eq(key, other),
+            // Without API keys the clients are told apart by the id of the request only, and
+            // the tenant header is sent by the clients too: the id must be one that the router
+            // generated, as the ones chosen by the clients can be guessed
+            (None, None) => self.generated_id,
+            _ => false,
+        };
+        self.tenant == other.tenant && same_key
+    }
+}
+
+#[derive(Debug)]
+struct Registered {
+    generation: u64,
+    owner: Owner,
+    aborted: watch::Sender<bool>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Generations per request id. Several with `best_of`, or when clients reuse an id.
+    requests: HashMap<String, Vec<Registered>>,
+    next_generation: u64,
+}
+
+/// Generations in flight, by the `x-request-id` of their request
+#[derive(Debug, Default)]
+pub(crate) struct Aborts {
+    state: Mutex<State>,
+}
+
+impl Aborts {
+    /// Register a generation of request `id`, until the returned handle is dropped
+    pub(crate) fn register(self: &Arc<Self>, id: String, owner: Owner) -> AbortHandle {
+        let (aborted, receiver) = watch::channel(false);
+        let mut state = self.state.lock().unwrap();
+        let generation = state.next_generation;
+        state.next_generation += 1;
+        state
+            .requests
+            .entry(id.clone())
+            .or_default()
+            .push(Registered {
+                generation,
+                owner,
+                aborted,
+            });
+        AbortHandle {
+            aborts: self.clone(),
+            id,
+            generation,
+            receiver,
+        }
+    }
+
+    /// Abort the generations of request `id` of `owner`. Returns false if there are none.
+    pub(crate) fn abort(&self, id: &str, owner: &Owner) -> bool {
+        let state = self.state.lock().unwrap();
+        let mut found = false;
+        for registered in state.requests.get(id).into_iter().flatten() {
+            if registered.owner.is(owner) {
+                registered.aborted.send_replace(true);
+                found = true;
+            }
+        }
+        found
+    }
+}
+
+/// Registration of a generation, to poll for its abort
+#[derive(Debug)]
+pub(crate) struct AbortHandle {
+    aborts: Arc<Aborts>,
+    id: String,
+    generation: u64,
+    receiver: watch::Receiver<bool>,
+}
+
+impl AbortHandle {
+    pub(crate) fn is_aborted(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolve once the generation is aborted
+    pub(crate) async fn aborted(&mut self) {
+        // The sender lives as long as the registration, so this only returns once aborted
+        let _ = self.receiver.wait_for(|aborted| *aborted).await;
+    }
+}
+
+impl Drop for AbortHandle {
+    fn drop(&mut self) {
+        let mut state = self.aborts.state.lock().unwrap();
+        if let Some(registered) = state.requests.get_mut(&self.id) {
+            registered.retain(|registered| registered.generation != self.generation);
+            if registered.is_empty() {
+                state.requests.remove(&self.id);
+            }
+        }
+    }
+}
+
+/// Resolve once the generation of `handle` is aborted, never without a handle
+pub(crate) async fn aborted(handle: &mut Option<AbortHandle>) {
+    match handle {
+        Some(handle) => handle.aborted().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(tenant: &str) -> Owner {
+        Owner::new(Some(tenant.to_string()), None, true)
+    }
+
+    #[tokio::test]
+    async fn test_abort_generations() {
+        let aborts = Arc::new(Aborts::default());
+        let mut first = aborts.register("a".to_string(), owner("tenant"));
+        let second = aborts.register("a".to_string(), owner("tenant"));
+        let other = aborts.register("a".to_string(), owner("other"));
+
+        assert!(!aborts.abort("b", &owner("tenant")));
+        assert!(aborts.abort("a", &owner("tenant")));
+        first.aborted().await;
+        assert!(second.is_aborted());
+        // Another client can not abort the generations of the id it reused
+        assert!(!other.is_aborted());
+
+        drop((first, second, other));
+        assert!(!aborts.abort("a", &owner("tenant")));
+        assert!(aborts.state.lock().unwrap().requests.is_empty());
+    }
+
+    #[test]
+    fn test_owner() {
+        let key = Arc::new(ApiKey::unrestricted());
+        let owner = Owner::new(None, Some(key.clone()), false);
+        assert!(owner.is(&Owner::new(None, Some(key.clone()), false)));
+        assert!(!owner.is(&Owner::new(
+            None,
+            Some(Arc::new(ApiKey::unrestricted())),
+            false
+        )));
+        assert!(!owner.is(&Owner::new(None, None, false)));
+        assert!(!owner.is(&Owner::new(Some("tenant".to_string()), Some(key), false)));
+
+        // Without a key, anyone sending the id could claim the generation
+        let anonymous = Owner::new(None, None, true);
+        assert!(anonymous.is(&Owner::new(None, None, false)));
+        assert!(!anonymous.is(&Owner::new(Some("tenant".to_string()), None, false)));
+        let chosen_id = Owner::new(Some("tenant".to_string()), None, false);
+        assert!(!chosen_id.is(&Owner::new(Some("tenant".to_string()), None, false)));
+    }
+}
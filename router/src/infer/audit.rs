@@ -0,0 +1,490 @@
+/// Audit log of the generations, written to a file, stdout or a webhook
+use crate::infer::{GeneratedText, InferError};
+use crate::{FinishReason, GenerateRequest};
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Number of records waiting for the sink, past which new records are dropped
+const AUDIT_QUEUE_SIZE: usize = 4096;
+/// Time given to the webhook to accept a record
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of bytes of the HMAC-SHA256 digests kept in the records
+const HASH_BYTES: usize = 16;
+/// Block size of SHA-256, the size of the HMAC keys
+const SHA256_BLOCK_BYTES: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("The audit sample rate must be between 0 and 1, got {0}")]
+    SampleRate(f64),
+    #[error("Unable to open the audit log {0}: {1}")]
+    File(String, io::Error),
+    #[error("Unable to create the audit webhook client: {0}")]
+    Webhook(reqwest::Error),
+}
+
+/// Destination of the serialized records
+#[async_trait]
+trait AuditSink {
+    async fn write(&mut self, record: &[u8]) -> io::Result<()>;
+}
+
+/// JSON lines on the standard output, next to the logs of the router
+struct StdoutSink;
+
+#[async_trait]
+impl AuditSink for StdoutSink {
+    async fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        let line = line(record);
+        // A blocked stdout must not block a thread of the runtime
+        blocking(move || {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&line)?;
+            stdout.flush()
+        })
+        .await
+    }
+}
+
+/// JSON lines appended to a file, which is created if needed
+struct FileSink {
+    file: Arc<File>,
+}
+
+#[async_trait]
+impl AuditSink for FileSink {
+    async fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        let line = line(record);
+        let file = self.file.clone();
+        // A single write, so that a crash does not interleave two records
+        blocking(move || (&*file).write_all(&line)).await
+    }
+}
+
+fn line(record: &[u8]) -> Vec<u8> {
+    let mut line = Vec::with_capacity(record.len() + 1);
+    line.extend_from_slice(record);
+    line.push(b'\n');
+    line
+}
+
+/// Run a write to a file on the blocking threads of the runtime
+async fn blocking(write: impl FnOnce() -> io::Result<()> + Send + 'static) -> io::Result<()> {
+    tokio::task::spawn_blocking(write)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// One JSON `POST` per record
+struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl AuditSink for WebhookSink {
+    async fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        self.client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(record.to_vec())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// Sink of `--audit-log`: `stdout`, an `http://` or `https://` webhook URL, or a file path
+fn open_sink(sink: &str) -> Result<Box<dyn AuditSink + Send>, AuditError> {
+    if sink == "stdout" {
+        return Ok(Box::new(StdoutSink));
+    }
+    if sink.starts_with("http://") || sink.starts_with("https://") {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .map_err(AuditError::Webhook)?;
+        return Ok(Box::new(WebhookSink {
+            client,
+            url: sink.to_string(),
+        }));
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(sink)
+        .map_err(|err| AuditError::File(sink.to_string(), err))?;
+    Ok(Box::new(FileSink {
+        file: Arc::new(file),
+    }))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AuditStatus {
+    /// The generation ended with a finish reason
+    Success,
+    /// The request was refused or its generation failed
+    Error,
+    /// The generation stopped before its end, usually because its client went away
+    Cancelled,
+}
+
+/// Audit record of a generation
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    id: String,
+    /// `x-request-id` of the request, also found in its traces
+    request_id: Option<String>,
+    /// Unix timestamp in milliseconds of the arrival of the request
+    timestamp: u64,
+    /// Hash of the tenant, which is the API key of the client without a tenant header
+    tenant_hash: Option<String>,
+    prompt_hash: String,
+    /// Start of the prompt, unless the prompts are only hashed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+    parameters: Value,
+    status: AuditStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    finish_reason: Option<FinishReason>,
+    input_tokens: Option<u32>,
+    generated_tokens: u32,
+    /// Whether the response came from the response cache
+    cached: bool,
+    queue_time_ms: Option<u64>,
+    inference_time_ms: Option<u64>,
+    total_time_ms: u64,
+}
+
+/// Secret key of the hashes of the records, so that the prompts and the API keys cannot be
+/// recovered by hashing guesses
+struct HashKey([u8; SHA256_BLOCK_BYTES]);
+
+impl HashKey {
+    fn new(key: &[u8]) -> Self {
+        let mut block = [0; SHA256_BLOCK_BYTES];
+        if key.len() > SHA256_BLOCK_BYTES {
+            block[..32].copy_from_slice(&Sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        Self(block)
+    }
+
+    /// HMAC-SHA256 of `value` (RFC 2104)
+    fn hmac(&self, value: &[u8]) -> [u8; 32] {
+        let pad = |byte: u8| self.0.map(|key_byte| key_byte ^ byte);
+        let inner = Sha256::new()
+            .chain_update(pad(0x36))
+            .chain_update(value)
+            .finalize();
+        Sha256::new()
+            .chain_update(pad(0x5c))
+            .chain_update(inner)
+            .finalize()
+            .into()
+    }
+
+    /// Hex of the first `HASH_BYTES` of the HMAC of `value`
+    fn hash(&self, value: &str) -> String {
+        self.hmac(value.as_bytes())
+            .iter()
+            .take(HASH_BYTES)
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// Records of a sample of the generations, written by a background task so that a slow sink
+/// does not hold the requests back. Records are dropped when the sink falls too far behind.
+pub(crate) struct AuditLog {
+    sender: mpsc::Sender<AuditRecord>,
+    hash_key: HashKey,
+    sample_rate: f64,
+    /// Number of characters of the prompts kept in the records
+    prompt_length: usize,
+    /// Whether only the hash of the prompts is kept
+    hash_prompts: bool,
+}
+
+impl AuditLog {
+    /// Audit log writing to `sink`, None if it is not set. Without `hash_key`, the hashes are
+    /// keyed with a random key, and cannot be compared with the ones of another run.
+    pub(crate) fn new(
+        sink: Option<String>,
+        sample_rate: f64,
+        prompt_length: usize,
+        hash_prompts: bool,
+        hash_key: Option<String>,
+    ) -> Result<Option<Self>, AuditError> {
+        let Some(sink) = sink else {
+            return Ok(None);
+        };
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(AuditError::SampleRate(sample_rate));
+        }
+        let hash_key = match hash_key {
+            Some(hash_key) => HashKey::new(hash_key.as_bytes()),
+            None => {
+                tracing::warn!(
+                    "No `--audit-hash-key`, the hashes of the audit log are keyed for this run only"
+                );
+                HashKey::new(&rand::random::<[u8; 32]>())
+            }
+        };
+        let sink = open_sink(&sink)?;
+        let (sender, receiver) = mpsc::channel(AUDIT_QUEUE_SIZE);
+        tokio::spawn(audit_task(sink, receiver));
+        Ok(Some(Self {
+            sender,
+            hash_key,
+            sample_rate,
+            prompt_length,
+            hash_prompts,
+        }))
+    }
+
+    /// Start the record of `request`, None if the request is not part of the sample
+    pub(crate) fn entry(
+        &self,
+        request: &GenerateRequest,
+        tenant: Option<&str>,
+        request_id: Option<String>,
+    ) -> Option<AuditEntry> {
+        if self.sample_rate < 1.0 && rand::random::<f64>() >= self.sample_rate {
+            return None;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let prompt =
+            (!self.hash_prompts).then(|| request.inputs.chars().take(self.prompt_length).collect());
+        let record = AuditRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            request_id,
+            timestamp,
+            tenant_hash: tenant.map(|tenant| self.hash_key.hash(tenant)),
+            prompt_hash: self.hash_key.hash(&request.inputs),
+            prompt,
+            parameters: serde_json::to_value(&request.parameters).unwrap_or_default(),
+            status: AuditStatus::Cancelled,
+            error: None,
+            finish_reason: None,
+            input_tokens: None,
+            generated_tokens: 0,
+            cached: false,
+            queue_time_ms: None,
+            inference_time_ms: None,
+            total_time_ms: 0,
+        };
+        Some(AuditEntry {
+            sender: self.sender.clone(),
+            record: Some(record),
+            start: Instant::now(),
+        })
+    }
+}
+
+/// Record of a generation in progress, sent to the audit log when the generation ends. It is
+/// recorded as cancelled if it is dropped before.
+pub(crate) struct AuditEntry {
+    sender: mpsc::Sender<AuditRecord>,
+    record: Option<AuditRecord>,
+    start: Instant,
+}
+
+impl AuditEntry {
+    pub(crate) fn set_input_length(&mut self, input_length: u32) {
+        if let Some(record) = self.record.as_mut() {
+            record.input_tokens = Some(input_length);
+        }
+    }
+
+    /// Mark the response as served from the response cache
+    pub(crate) fn set_cached(&mut self) {
+        if let Some(record) = self.record.as_mut() {
+            record.cached = true;
+        }
+    }
+
+    pub(crate) fn succeed(
+        mut self,
+        generated_text: &GeneratedText,
+        queued: Instant,
+        start: Instant,
+    ) {
+        if let Some(record) = self.record.as_mut() {
+            record.status = AuditStatus::Success;
+            record.finish_reason = Some(generated_text.finish_reason.clone());
+            record.generated_tokens = generated_text.generated_tokens;
+            record.queue_time_ms = Some(start.saturating_duration_since(queued).as_millis() as u64);
+            record.inference_time_ms = Some(start.elapsed().as_millis() as u64);
+        }
+    }
+
+    pub(crate) fn fail(mut self, err: &InferError) {
+        if let Some(record) = self.record.as_mut() {
+            record.status = AuditStatus::Error;
+            record.error = Some(err.error_type().to_string());
+        }
+    }
+}
+
+impl Drop for AuditEntry {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.total_time_ms = self.start.elapsed().as_millis() as u64;
+            if self.sender.try_send(record).is_err() {
+                metrics::counter!("tgi_audit_record_dropped").increment(1);
+            }
+        }
+    }
+}
+
+async fn audit_task(
+    mut sink: Box<dyn AuditSink + Send>,
+    mut receiver: mpsc::Receiver<AuditRecord>,
+) {
+    while let Some(record) = receiver.recv().await {
+        let record = serde_json::to_vec(&record).expect("Audit records are serializable");
+        if let Err(err) = sink.write(&record).await {
+            tracing::warn!("Unable to write an audit record: {err}");
+            metrics::counter!("tgi_audit_record_dropped").increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GenerateParameters;
+
+    fn audit_log(sample_rate: f64, hash_prompts: bool) -> (AuditLog, mpsc::Receiver<AuditRecord>) {
+        let (sender, receiver) = mpsc::channel(AUDIT_QUEUE_SIZE);
+        let audit_log = AuditLog {
+            sender,
+            hash_key: HashKey::new(b"key"),
+            sample_rate,
+            prompt_length: 7,
+            hash_prompts,
+        };
+        (audit_log, receiver)
+    }
+
+    fn request() -> GenerateRequest {
+        GenerateRequest {
+            inputs: "What is Deep Learning?".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters::default(),
+        }
+    }
+
+    #[test]
+    fn test_records() {
+        let (audit_log, mut receiver) = audit_log(1.0, false);
+        let mut entry = audit_log
+            .entry(
+                &request(),
+                Some("secret-key"),
+                Some("client-42".to_string()),
+            )
+            .unwrap();
+        entry.set_input_length(6);
+        let now = Instant::now();
+        entry.succeed(
+            &GeneratedText {
+                text: " A branch of machine learning".to_string(),
+                generated_tokens: 5,
+                finish_reason: FinishReason::EndOfSequenceToken,
+                seed: None,
+                batches: Default::default(),
+            },
+            now,
+            now,
+        );
+        let record = receiver.try_recv().unwrap();
+        assert_eq!(record.status, AuditStatus::Success);
+        assert_eq!(record.prompt.as_deref(), Some("What is"));
+        assert_eq!(
+            record.prompt_hash,
+            HashKey::new(b"key").hash("What is Deep Learning?")
+        );
+        assert_eq!(record.prompt_hash.len(), 2 * HASH_BYTES);
+        assert_ne!(record.tenant_hash.as_deref(), Some("secret-key"));
+        assert_eq!(record.input_tokens, Some(6));
+        assert_eq!(record.generated_tokens, 5);
+        assert_eq!(record.request_id.as_deref(), Some("client-42"));
+
+        // Dropped before the end of its generation
+        let entry = audit_log.entry(&request(), None, None).unwrap();
+        drop(entry);
+        let record = receiver.try_recv().unwrap();
+        assert_eq!(record.status, AuditStatus::Cancelled);
+
+        let entry = audit_log.entry(&request(), None, None).unwrap();
+        entry.fail(&InferError::QueueFull);
+        let record = receiver.try_recv().unwrap();
+        assert_eq!(record.status, AuditStatus::Error);
+        assert_eq!(record.error.as_deref(), Some("queue_full"));
+    }
+
+    #[test]
+    fn test_hashed_prompts() {
+        let (audit_log, mut receiver) = audit_log(1.0, true);
+        drop(audit_log.entry(&request(), None, None));
+        let record = receiver.try_recv().unwrap();
+        assert_eq!(record.prompt, None);
+        assert_eq!(
+            record.prompt_hash,
+            HashKey::new(b"key").hash("What is Deep Learning?")
+        );
+        // The hash depends on the key
+        assert_ne!(
+            record.prompt_hash,
+            HashKey::new(b"other key").hash("What is Deep Learning?")
+        );
+    }
+
+    #[test]
+    fn test_hmac() {
+        let hex = |digest: [u8; 32]| -> String {
+            digest.iter().map(|byte| format!("{byte:02x}")).collect()
+        };
+        // Test cases 2 and 6 of RFC 4231
+        assert_eq!(
+            hex(HashKey::new(b"Jefe").hmac(b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(HashKey::new(&[0xaa; 131])
+                .hmac(b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let (audit_log, _receiver) = audit_log(0.0, false);
+        assert!(audit_log.entry(&request(), None, None).is_none());
+        assert!(matches!(
+            AuditLog::new(Some("stdout".to_string()), 1.5, 256, false, None),
+            Err(AuditError::SampleRate(_))
+        ));
+    }
+}
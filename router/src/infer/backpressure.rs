@@ -0,0 +1,242 @@
+use crate::infer::InferError;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Weight of the last sample in the decode throughput moving average
+const THROUGHPUT_SMOOTHING: f64 = 0.2;
+/// Minimum duration of a throughput sample
+const SAMPLE_WINDOW: Duration = Duration::from_secs(1);
+/// Samples longer than this contain idle time and are discarded
+const MAX_SAMPLE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Admission control for the requests waiting to be started by the backend.
+///
+/// The queue wait is estimated from the tokens the queued requests may generate and from the
+/// recent decode throughput of the server.
+#[derive(Debug)]
+pub(crate) struct Backpressure {
+    /// Maximum number of queued requests
+    max_queue_size: Option<usize>,
+    /// Maximum estimated queue wait
+    max_queue_wait: Option<Duration>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Tickets of the requests that did not receive their first token yet, in arrival order
+    waiting: BTreeSet<u64>,
+    /// Ticket of the next queued request
+    next_ticket: u64,
+    /// Sum of the `max_new_tokens` of the queued requests
+    queued_tokens: u64,
+    /// Smoothed decode throughput, in tokens per second
+    throughput: Option<f64>,
+    /// Start of the current throughput sample
+    sample_start: Instant,
+    /// Tokens generated since the start of the current sample
+    sample_tokens: u64,
+}
+
+impl State {
+    fn estimated_wait(&self) -> Option<Duration> {
+        let throughput = self.throughput.filter(|throughput| *throughput > 0.0)?;
+        Some(Duration::from_secs_f64(
+            self.queued_tokens as f64 / throughput,
+        ))
+    }
+}
+
+impl Backpressure {
+    pub(crate) fn new(max_queue_size: Option<usize>, max_queue_wait: Option<Duration>) -> Self {
+        Self {
+            max_queue_size,
+            max_queue_wait,
+            state: Mutex::new(State {
+                waiting: BTreeSet::new(),
+                next_ticket: 0,
+                queued_tokens: 0,
+                throughput: None,
+                sample_start: Instant::now(),
+                sample_tokens: 0,
+            }),
+        }
+    }
+
+    /// Refuse a new request if the queue is over one of its limits
+    pub(crate) fn admit(&self) -> Result<(), InferError> {
+        let state = self.state.lock().unwrap();
+        let full = self
+            .max_queue_size
+            .is_some_and(|max_queue_size| state.waiting.len() >= max_queue_size);
+        let slow = match (self.max_queue_wait, state.estimated_wait()) {
+            (Some(max_queue_wait), Some(estimated_wait)) => estimated_wait > max_queue_wait,
+            _ => false,
+        };
+        if full || slow {
+            return Err(InferError::QueueFull);
+        }
+        Ok(())
+    }
+
+    /// Number of seconds after which a rejected client should retry
+    pub(crate) fn retry_after(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        let wait = state
+            .estimated_wait()
+            .unwrap_or_default()
+            .saturating_sub(self.max_queue_wait.unwrap_or_default());
+        wait.as_secs_f64().ceil().max(1.0) as u64
+    }
+
+    /// Track a request until its first token
+    pub(crate) fn enqueue(self: &Arc<Self>, max_new_tokens: u32) -> QueuedRequest {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.insert(ticket);
+        state.queued_tokens += max_new_tokens as u64;
+        metrics::gauge!("tgi_queue_estimated_wait")
+            .set(state.estimated_wait().unwrap_or_default().as_secs_f64());
+        QueuedRequest {
+            backpressure: self.clone(),
+            ticket,
+            max_new_tokens,
+            started: false,
+        }
+    }
+
+    fn dequeue(&self, ticket: u64, max_new_tokens: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.waiting.remove(&ticket);
+        state.queued_tokens -= max_new_tokens as u64;
+    }
+
+    /// Number of requests queued before the one of `ticket` that are still waiting
+    fn position(&self, ticket: u64) -> usize {
+        let state = self.state.lock().unwrap();
+        state.waiting.range(..ticket).count()
+    }
+
+    /// Account for a generated token in the decode throughput
+    pub(crate) fn record_token(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.sample_tokens += 1;
+
+        let elapsed = state.sample_start.elapsed();
+        if elapsed < SAMPLE_WINDOW {
+            return;
+        }
+        if elapsed <= MAX_SAMPLE_WINDOW {
+            let sample = state.sample_tokens as f64 / elapsed.as_secs_f64();
+            state.throughput = Some(match state.throughput {
+                Some(throughput) => {
+                    THROUGHPUT_SMOOTHING * sample + (1.0 - THROUGHPUT_SMOOTHING) * throughput
+                }
+                None => sample,
+            });
+        }
+        state.sample_start = Instant::now();
+        state.sample_tokens = 0;
+    }
+}
+
+/// Request that is counted in the queue until it is started or dropped
+#[derive(Debug)]
+pub(crate) struct QueuedRequest {
+    backpressure: Arc<Backpressure>,
+    ticket: u64,
+    max_new_tokens: u32,
+    started: bool,
+}
+
+impl QueuedRequest {
+    /// The request received its first token and left the queue
+    pub(crate) fn start(&mut self) {
+        if !self.started {
+            self.started = true;
+            self.backpressure.dequeue(self.ticket, self.max_new_tokens);
+        }
+    }
+
+    /// Number of requests ahead of this one in the queue of this router. The backend may
+    /// still start them in another order.
+    pub(crate) fn position(&self) -> usize {
+        if self.started {
+            return 0;
+        }
+        self.backpressure.position(self.ticket)
+    }
+}
+
+impl Drop for QueuedRequest {
+    fn drop(&mut self) {
+        self.start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit_max_queue_size() {
+        let backpressure = Arc::new(Backpressure::new(Some(2), None));
+        let _first = backpressure.enqueue(10);
+        let mut second = backpressure.enqueue(10);
+        assert!(matches!(backpressure.admit(), Err(InferError::QueueFull)));
+
+        second.start();
+        assert!(backpressure.admit().is_ok());
+    }
+
+    #[test]
+    fn test_admit_dropped_request() {
+        let backpressure = Arc::new(Backpressure::new(Some(1), None));
+        let queued = backpressure.enqueue(10);
+        assert!(backpressure.admit().is_err());
+
+        drop(queued);
+        assert!(backpressure.admit().is_ok());
+    }
+
+    #[test]
+    fn test_position() {
+        let backpressure = Arc::new(Backpressure::new(None, None));
+        let mut first = backpressure.enqueue(10);
+        let second = backpressure.enqueue(10);
+        let third = backpressure.enqueue(10);
+        assert_eq!(first.position(), 0);
+        assert_eq!(third.position(), 2);
+
+        first.start();
+        assert_eq!(first.position(), 0);
+        assert_eq!(third.position(), 1);
+        drop(second);
+        assert_eq!(third.position(), 0);
+    }
+
+    #[test]
+    fn test_admit_max_queue_wait() {
+        let backpressure = Arc::new(Backpressure::new(None, Some(Duration::from_secs(10))));
+        backpressure.state.lock().unwrap().throughput = Some(100.0);
+
+        // 500 tokens at 100 tokens/s
+        let _first = backpressure.enqueue(500);
+        assert!(backpressure.admit().is_ok());
+
+        // 1500 tokens at 100 tokens/s
+        let _second = backpressure.enqueue(1000);
+        assert!(backpressure.admit().is_err());
+        assert_eq!(backpressure.retry_after(), 5);
+    }
+
+    #[test]
+    fn test_admit_unknown_throughput() {
+        let backpressure = Arc::new(Backpressure::new(None, Some(Duration::from_secs(1))));
+        let _queued = backpressure.enqueue(100_000);
+        assert!(backpressure.admit().is_ok());
+        assert_eq!(backpressure.retry_after(), 1);
+    }
+}
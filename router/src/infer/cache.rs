@@ -0,0 +1,629 @@
+/// Exact-match cache of the responses to deterministic requests
+use crate::infer::{BatchResidency, GeneratedText, InferResponse};
+use crate::{FinishReason, GenerateRequest, PrefillToken, Token};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+/// Prefix of the keys of the responses in Redis
+const REDIS_KEY_PREFIX: &str = "tgi:response:";
+/// Port of the Redis URLs that do not have one
+const REDIS_DEFAULT_PORT: u16 = 6379;
+/// Time given to a Redis command before the request is generated as if it missed the cache
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+/// Connections opened to Redis at once, the other commands wait for one of them
+const REDIS_MAX_CONNECTIONS: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum ResponseCacheError {
+    #[error("Invalid response cache Redis URL: {0}")]
+    RedisUrl(String),
+}
+
+/// Storage of the serialized responses, evicting them once their TTL is over
+#[async_trait]
+trait CacheStore {
+    async fn get(&self, key: &str) -> Option<Vec<u8>>;
+
+    async fn put(&self, key: String, value: Vec<u8>);
+}
+
+/// Response, without the instants of the generation that produced it
+#[derive(Serialize, Deserialize)]
+struct CachedResponse {
+    input_length: u32,
+    prefill: Vec<PrefillToken>,
+    tokens: Vec<Token>,
+    text: String,
+    generated_tokens: u32,
+    finish_reason: FinishReason,
+    seed: Option<u64>,
+    top_tokens: Vec<Vec<Token>>,
+}
+
+impl From<&InferResponse> for CachedResponse {
+    fn from(response: &InferResponse) -> Self {
+        Self {
+            input_length: response._input_length,
+            prefill: response.prefill.clone(),
+            tokens: response.tokens.clone(),
+            text: response.generated_text.text.clone(),
+            generated_tokens: response.generated_text.generated_tokens,
+            finish_reason: response.generated_text.finish_reason.clone(),
+            seed: response.generated_text.seed,
+            top_tokens: response.top_tokens.clone(),
+        }
+    }
+}
+
+impl From<CachedResponse> for InferResponse {
+    fn from(response: CachedResponse) -> Self {
+        // A hit is served right away, it spends no time in the queue or the model
+        let now = tokio::time::Instant::now();
+        Self {
+            _input_length: response.input_length,
+            prefill: response.prefill,
+            tokens: response.tokens,
+            generated_text: GeneratedText {
+                text: response.text,
+                generated_tokens: response.generated_tokens,
+                finish_reason: response.finish_reason,
+                seed: response.seed,
+                batches: BatchResidency::default(),
+            },
+            queued: now,
+            start: now,
+            first_token: now,
+            top_tokens: response.top_tokens,
+        }
+    }
+}
+
+/// Responses to the requests that always generate the same tokens, i.e. the greedy requests and
+/// the ones with a fixed seed, served again to the identical requests of the same tenant
+pub(crate) struct ResponseCache {
+    store: Box<dyn CacheStore + Send + Sync>,
+    /// Revision of the served model, so that the routers of other revisions do not share
+    /// its responses
+    model_sha: RwLock<Option<String>>,
+    /// Incremented when the shards of a model are swapped, to stop serving their responses
+    epoch: AtomicU64,
+}
+
+impl ResponseCache {
+    /// Cache of up to `size` responses in memory, or of the responses stored in Redis if
+    /// `redis_url` is set. None if neither is set.
+    pub(crate) fn new(
+        size: Option<usize>,
+        ttl: Duration,
+        redis_url: Option<String>,
+        model_sha: Option<String>,
+    ) -> Result<Option<Self>, ResponseCacheError> {
+        let store: Box<dyn CacheStore + Send + Sync> = match (redis_url, size) {
+            (Some(url), _) => Box::new(RedisStore::new(&url, ttl)?),
+            (None, Some(size)) if size > 0 => Box::new(MemoryStore::new(size, ttl)),
+            (None, _) => return Ok(None),
+        };
+        Ok(Some(Self {
+            store,
+            model_sha: RwLock::new(model_sha),
+            epoch: AtomicU64::new(0),
+        }))
+    }
+
+    /// Stop serving the responses cached so far, once the shards of a model were swapped
+    pub(crate) fn invalidate(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Key the responses with the revision of the model served by the new shards
+    pub(crate) fn set_model_sha(&self, model_sha: Option<String>) {
+        *self.model_sha.write().unwrap() = model_sha;
+        self.invalidate();
+    }
+
+    /// Key of the response of `model` to `request`, None if the request samples tokens
+    /// without a seed or continues another request.
+    ///
+    /// The key is the hash of the request itself with its keys sorted, of the model and of its
+    /// revision, so that only identical requests to the same model share a response.
+    pub(crate) fn key(
+        &self,
+        request: &GenerateRequest,
+        model: &str,
+        default_temperature: f32,
+        tenant: Option<&str>,
+    ) -> Option<String> {
+        let parameters = &request.parameters;
+        if parameters.continue_from.is_some() {
+            return None;
+        }
+        let sampling = parameters.do_sample
+            || parameters.temperature.is_some()
+            || parameters.top_k.is_some()
+            || parameters.top_p.is_some()
+            || parameters.typical_p.is_some()
+            || parameters.min_p.is_some()
+            || default_temperature != 1.0;
+        if sampling && parameters.seed.is_none() {
+            return None;
+        }
+
+        let mut key = json!({
+            "inputs": request.inputs,
+            "add_special_tokens": request.add_special_tokens,
+            "parameters": parameters,
+            "default_temperature": default_temperature,
+            "tenant": tenant,
+            "model": model,
+            "model_sha": *self.model_sha.read().unwrap(),
+            "epoch": self.epoch.load(Ordering::SeqCst),
+        });
+        // The metadata does not change the generation
+        if let Some(parameters) = key["parameters"].as_object_mut() {
+            parameters.remove("metadata");
+        }
+        let digest = Sha256::digest(canonical(key).to_string().as_bytes());
+        Some(digest.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Option<InferResponse> {
+        let response = self
+            .store
+            .get(key)
+            .await
+            .and_then(|value| serde_json::from_slice::<CachedResponse>(&value).ok());
+        match response {
+            Some(response) => {
+                metrics::counter!("tgi_response_cache_hit").increment(1);
+                Some(response.into())
+            }
+            None => {
+                metrics::counter!("tgi_response_cache_miss").increment(1);
+                None
+            }
+        }
+    }
+
+    /// Store a complete response. The ones cut by their timeout or aborted would not be
+    /// generated again.
+    pub(crate) async fn put(&self, key: String, response: &InferResponse) {
+        if matches!(
+            response.generated_text.finish_reason,
+            FinishReason::Timeout | FinishReason::Cancelled
+        ) {
+            return;
+        }
+        let value = serde_json::to_vec(&CachedResponse::from(response))
+            .expect("Cached responses are serializable");
+        self.store.put(key, value).await;
+    }
+}
+
+/// Sort the keys of the objects of `value`, whatever the order of the maps they come from
+fn canonical(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let sorted: BTreeMap<String, Value> = object
+                .into_iter()
+                .map(|(key, value)| (key, canonical(value)))
+                .collect();
+            Value::Object(sorted.into_iter().collect::<Map<String, Value>>())
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonical).collect()),
+        value => value,
+    }
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: Vec<u8>,
+    inserted: Instant,
+    /// Tick of the last use of the entry
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    entries: HashMap<String, MemoryEntry>,
+    /// Keys of the entries by tick of their last use, the least recently used first
+    usage: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl MemoryState {
+    fn remove(&mut self, key: &str) -> Option<MemoryEntry> {
+        let entry = self.entries.remove(key)?;
+        self.usage.remove(&entry.used);
+        Some(entry)
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.usage.remove(&entry.used);
+            entry.used = self.tick;
+            self.usage.insert(self.tick, key.to_string());
+        }
+    }
+}
+
+/// Least recently used responses of this router
+#[derive(Debug)]
+struct MemoryStore {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStore {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.entries.get(key)?;
+        // Expired entries are dropped when they are looked up or evicted
+        if entry.inserted.elapsed() >= self.ttl {
+            state.remove(key);
+            return None;
+        }
+        let value = entry.value.clone();
+        state.touch(key);
+        Some(value)
+    }
+
+    async fn put(&self, key: String, value: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        state.entries.insert(
+            key.clone(),
+            MemoryEntry {
+                value,
+                inserted: Instant::now(),
+                used: 0,
+            },
+        );
+        state.touch(&key);
+        while state.entries.len() > self.capacity {
+            let Some((_, key)) = state.usage.pop_first() else {
+                break;
+            };
+            state.entries.remove(&key);
+        }
+    }
+}
+
+/// Reply of Redis to the commands of the store
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Bulk(Option<Vec<u8>>),
+}
+
+/// Responses shared by all the routers using the same Redis server. Redis evicts them when their
+/// TTL is over, or earlier following its `maxmemory-policy`, e.g. `allkeys-lru`.
+///
+/// Failing commands are logged and treated as misses, so that an unreachable cache slows the
+/// requests down by at most `REDIS_TIMEOUT`.
+struct RedisStore {
+    address: String,
+    username: Option<String>,
+    password: Option<String>,
+    database: Option<u32>,
+    ttl: Duration,
+    /// Connections of the finished commands, reused by the next ones
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+    /// Bounds the number of connections, idle or sending a command
+    connections: Semaphore,
+}
+
+impl RedisStore {
+    /// Store of the Redis server at `redis://[[<username>]:<password>@]<host>[:<port>][/<db>]`
+    fn new(url: &str, ttl: Duration) -> Result<Self, ResponseCacheError> {
+        let invalid = |reason: &str| ResponseCacheError::RedisUrl(format!("{url}: {reason}"));
+        let parsed = reqwest::Url::parse(url).map_err(|err| invalid(&err.to_string()))?;
+        if parsed.scheme() != "redis" {
+            return Err(invalid("the scheme must be `redis`"));
+        }
+        let host = parsed.host_str().ok_or_else(|| invalid("missing host"))?;
+        let port = parsed.port().unwrap_or(REDIS_DEFAULT_PORT);
+        let database = match parsed.path().trim_start_matches('/') {
+            "" => None,
+            database => Some(
+                database
+                    .parse()
+                    .map_err(|_| invalid("the path must be a database number"))?,
+            ),
+        };
+        Ok(Self {
+            address: format!("{host}:{port}"),
+            username: Some(parsed.username().to_string()).filter(|username| !username.is_empty()),
+            password: parsed.password().map(str::to_string),
+            database,
+            ttl,
+            idle: Mutex::new(Vec::new()),
+            connections: Semaphore::new(REDIS_MAX_CONNECTIONS),
+        })
+    }
+
+    async fn connect(&self) -> io::Result<BufStream<TcpStream>> {
+        let mut stream = BufStream::new(TcpStream::connect(&self.address).await?);
+        if let Some(password) = &self.password {
+            let mut command = vec!["AUTH".as_bytes()];
+            command.extend(self.username.as_deref().map(str::as_bytes));
+            command.push(password.as_bytes());
+            send(&mut stream, &command).await?;
+        }
+        if let Some(database) = self.database {
+            let database = database.to_string();
+            send(&mut stream, &[b"SELECT".as_slice(), database.as_bytes()]).await?;
+        }
+        Ok(stream)
+    }
+
+    async fn command(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        tokio::time::timeout(REDIS_TIMEOUT, async {
+            let _permit = self
+                .connections
+                .acquire()
+                .await
+                .expect("The semaphore is never closed");
+            let idle = self.idle.lock().unwrap().pop();
+            let mut connection = match idle {
+                Some(connection) => connection,
+                None => self.connect().await?,
+            };
+            // The replies of a failed connection may be out of step with its commands, so it
+            // is dropped instead of being reused
+            let reply = send(&mut connection, args).await?;
+            self.idle.lock().unwrap().push(connection);
+            Ok(reply)
+        })
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key = format!("{REDIS_KEY_PREFIX}{key}");
+        match self.command(&[b"GET".as_slice(), key.as_bytes()]).await {
+            Ok(Reply::Bulk(value)) => value,
+            Ok(reply) => {
+                tracing::warn!("Unexpected reply of the response cache: {reply:?}");
+                None
+            }
+            Err(err) => {
+                tracing::warn!("Unable to read the response cache: {err}");
+                None
+            }
+        }
+    }
+
+    async fn put(&self, key: String, value: Vec<u8>) {
+        let key = format!("{REDIS_KEY_PREFIX}{key}");
+        let ttl = self.ttl.as_secs().max(1).to_string();
+        let command: [&[u8]; 5] = [
+            b"SET",
+            key.as_bytes(),
+            value.as_slice(),
+            b"EX",
+            ttl.as_bytes(),
+        ];
+        if let Err(err) = self.command(&command).await {
+            tracing::warn!("Unable to write the response cache: {err}");
+        }
+    }
+}
+
+/// Send a command to Redis and read its reply
+async fn send(stream: &mut BufStream<TcpStream>, args: &[&[u8]]) -> io::Result<Reply> {
+    stream.write_all(&encode(args)).await?;
+    stream.flush().await?;
+    read_reply(stream).await
+}
+
+/// Command as a RESP array of bulk strings
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut buffer = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buffer.extend(format!("${}\r\n", arg.len()).as_bytes());
+        buffer.extend(*arg);
+        buffer.extend(b"\r\n");
+    }
+    buffer
+}
+
+async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Reply> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches("\r\n");
+    let Some(kind) = line.chars().next() else {
+        return Err(invalid("empty reply".to_string()));
+    };
+    let rest = &line[1..];
+    match kind {
+        '+' => Ok(Reply::Status(rest.to_string())),
+        '-' => Err(io::Error::other(rest.to_string())),
+        '$' => {
+            let length: i64 = rest
+                .parse()
+                .map_err(|_| invalid(format!("invalid bulk length: {rest}")))?;
+            if length < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut value = vec![0; length as usize + 2];
+            reader.read_exact(&mut value).await?;
+            value.truncate(length as usize);
+            Ok(Reply::Bulk(Some(value)))
+        }
+        _ => Err(invalid(format!("unsupported reply: {line}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GenerateParameters;
+
+    fn request(parameters: GenerateParameters) -> GenerateRequest {
+        GenerateRequest {
+            inputs: "What is Deep Learning?".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters,
+        }
+    }
+
+    fn response_cache(model_sha: Option<&str>) -> ResponseCache {
+        ResponseCache::new(
+            Some(8),
+            Duration::from_secs(60),
+            None,
+            model_sha.map(str::to_string),
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_key() {
+        let cache = response_cache(Some("e985a63c"));
+        let greedy = request(GenerateParameters::default());
+        let key = cache.key(&greedy, "base", 1.0, None).unwrap();
+        assert_eq!(key.len(), 64);
+        assert_eq!(cache.key(&greedy, "base", 1.0, None), Some(key.clone()));
+        assert_ne!(
+            cache.key(&greedy, "base", 1.0, Some("team-a")),
+            Some(key.clone())
+        );
+        // Neither another model nor another revision of the model share the response
+        assert_ne!(cache.key(&greedy, "adapter", 1.0, None), Some(key.clone()));
+        assert_ne!(
+            response_cache(Some("0b5942cc")).key(&greedy, "base", 1.0, None),
+            Some(key.clone())
+        );
+        // The responses of the swapped shards are not served anymore
+        cache.invalidate();
+        assert_ne!(cache.key(&greedy, "base", 1.0, None), Some(key));
+        // The default temperature makes the greedy requests sample
+        assert_eq!(cache.key(&greedy, "base", 0.7, None), None);
+
+        let sampling = request(GenerateParameters {
+            do_sample: true,
+            ..Default::default()
+        });
+        assert_eq!(cache.key(&sampling, "base", 1.0, None), None);
+        let seeded = request(GenerateParameters {
+            do_sample: true,
+            seed: Some(42),
+            ..Default::default()
+        });
+        assert!(cache.key(&seeded, "base", 1.0, None).is_some());
+        // The text of the continued request is only known once resolved
+        let continued = request(GenerateParameters {
+            continue_from: Some("3f9c2b1e".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(cache.key(&continued, "base", 1.0, None), None);
+
+        let with_metadata = request(GenerateParameters {
+            metadata: Some(HashMap::from([("user".to_string(), json!("abc"))])),
+            ..Default::default()
+        });
+        assert_eq!(
+            cache.key(&with_metadata, "base", 1.0, None),
+            cache.key(&greedy, "base", 1.0, None)
+        );
+    }
+
+    #[test]
+    fn test_canonical() {
+        let value = canonical(json!({"b": [{"d": 1, "c": 2}], "a": null}));
+        assert_eq!(value.to_string(), r#"{"a":null,"b":[{"c":2,"d":1}]}"#);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_lru() {
+        let store = MemoryStore::new(2, Duration::from_secs(60));
+        store.put("a".to_string(), b"1".to_vec()).await;
+        store.put("b".to_string(), b"2".to_vec()).await;
+        assert_eq!(store.get("a").await, Some(b"1".to_vec()));
+
+        // "b" is the least recently used
+        store.put("c".to_string(), b"3".to_vec()).await;
+        assert_eq!(store.get("b").await, None);
+        assert_eq!(store.get("a").await, Some(b"1".to_vec()));
+        assert_eq!(store.get("c").await, Some(b"3".to_vec()));
+
+        store.put("a".to_string(), b"4".to_vec()).await;
+        assert_eq!(store.get("a").await, Some(b"4".to_vec()));
+        assert_eq!(store.state.lock().unwrap().usage.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_ttl() {
+        let store = MemoryStore::new(2, Duration::ZERO);
+        store.put("a".to_string(), b"1".to_vec()).await;
+        assert_eq!(store.get("a").await, None);
+        assert!(store.state.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_redis_url() {
+        let store = RedisStore::new("redis://:secret@cache:6380/2", Duration::ZERO).unwrap();
+        assert_eq!(store.address, "cache:6380");
+        assert_eq!(store.username, None);
+        assert_eq!(store.password.as_deref(), Some("secret"));
+        assert_eq!(store.database, Some(2));
+
+        let store = RedisStore::new("redis://cache", Duration::ZERO).unwrap();
+        assert_eq!(store.address, "cache:6379");
+        assert_eq!(store.database, None);
+        assert!(RedisStore::new("http://cache", Duration::ZERO).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resp() {
+        assert_eq!(
+            encode(&[b"GET".as_slice(), b"key".as_slice()]),
+            b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".to_vec()
+        );
+
+        let mut replies: &[u8] = b"+OK\r\n$5\r\nva\r\nl\r\n$-1\r\n-ERR wrong\r\n";
+        assert_eq!(
+            read_reply(&mut replies).await.unwrap(),
+            Reply::Status("OK".to_string())
+        );
+        assert_eq!(
+            read_reply(&mut replies).await.unwrap(),
+            Reply::Bulk(Some(b"va\r\nl".to_vec()))
+        );
+        assert_eq!(read_reply(&mut replies).await.unwrap(), Reply::Bulk(None));
+        assert!(read_reply(&mut replies).await.is_err());
+    }
+}
@@ -0,0 +1,443 @@
+/// Moderation of the generated texts, which ends the generations that trip a filter
+use crate::infer::holdback::StreamedToken;
+use crate::infer::{GeneratedText, InferError, InferStreamResponse};
+use crate::FinishReason;
+use async_stream::stream;
+use async_trait::async_trait;
+use futures::Stream;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+
+#[derive(Debug, Error)]
+pub enum ContentFilterError {
+    #[error("Unable to read the content filters {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Unable to parse the content filters {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("Invalid deny-list pattern: {0}")]
+    Deny(#[from] regex::Error),
+    #[error("Unable to create the content filter callout client: {0}")]
+    Callout(reqwest::Error),
+    #[error("`holdback_tokens` must be > 0")]
+    HoldbackTokens,
+}
+
+/// Filter of the chain, checking the text generated so far
+#[async_trait]
+trait OutputFilter {
+    /// Name of the filter in the metrics
+    fn name(&self) -> &'static str;
+
+    /// Whether `text` must not be returned to the client
+    async fn flags(&self, text: &str) -> bool;
+}
+
+/// Regular expressions of the texts that must not be generated
+struct DenyList {
+    patterns: RegexSet,
+}
+
+#[async_trait]
+impl OutputFilter for DenyList {
+    fn name(&self) -> &'static str {
+        "deny_list"
+    }
+
+    async fn flags(&self, text: &str) -> bool {
+        self.patterns.is_match(text)
+    }
+}
+
+#[derive(Serialize)]
+struct CalloutRequest<'a> {
+    text: &'a str,
+}
+
+#[derive(Deserialize)]
+struct CalloutResponse {
+    flagged: bool,
+}
+
+/// Moderation service called with `{"text": ...}`, which answers `{"flagged": true}` for the
+/// texts to stop. The texts are flagged when the service fails, unless `fail_open` is set.
+struct Callout {
+    client: reqwest::Client,
+    url: String,
+    fail_open: bool,
+}
+
+impl Callout {
+    async fn call(&self, text: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let body = serde_json::to_vec(&CalloutRequest { text })?;
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        let response: CalloutResponse = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(response.flagged)
+    }
+}
+
+#[async_trait]
+impl OutputFilter for Callout {
+    fn name(&self) -> &'static str {
+        "callout"
+    }
+
+    async fn flags(&self, text: &str) -> bool {
+        self.call(text).await.unwrap_or_else(|err| {
+            metrics::counter!("tgi_content_filter_callout_failure").increment(1);
+            tracing::warn!("Content filter callout failed: {err}");
+            !self.fail_open
+        })
+    }
+}
+
+/// Content of the `--content-filter-path` file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ContentFilterConfig {
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    callout: Option<CalloutConfig>,
+    #[serde(default = "default_holdback_tokens")]
+    holdback_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CalloutConfig {
+    url: String,
+    #[serde(default = "default_callout_timeout_ms")]
+    timeout_ms: u64,
+    /// Let the texts through when the callout fails, instead of ending their generations
+    #[serde(default)]
+    fail_open: bool,
+}
+
+fn default_holdback_tokens() -> usize {
+    8
+}
+
+fn default_callout_timeout_ms() -> u64 {
+    1000
+}
+
+/// Chain of output filters, read from a JSON file of the form
+/// `{"deny": ["(?i)\\bpassword:"], "callout": {"url": "http://moderation/check"}, "holdback_tokens": 8}`.
+///
+/// The generations are stopped when the callout fails, unless it sets `"fail_open": true`.
+///
+/// The streamed tokens are held back until the filters checked the text they end, which they
+/// do every `holdback_tokens` tokens and at the end of the generation. A generation tripping a
+/// filter ends with the `content_filter` finish reason, without the held tokens.
+pub(crate) struct ContentFilter {
+    filters: Vec<Box<dyn OutputFilter + Send + Sync>>,
+    holdback_tokens: usize,
+}
+
+impl ContentFilter {
+    /// Returns `None` without a filters file
+    pub(crate) fn new(path: Option<String>) -> Result<Option<Self>, ContentFilterError> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| ContentFilterError::Read(path.clone(), err))?;
+        let config: ContentFilterConfig = serde_json::from_str(&content)
+            .map_err(|err| ContentFilterError::Parse(path.clone(), err))?;
+        Self::from_config(config).map(Some)
+    }
+
+    fn from_config(config: ContentFilterConfig) -> Result<Self, ContentFilterError> {
+        if config.holdback_tokens == 0 {
+            return Err(ContentFilterError::HoldbackTokens);
+        }
+        let mut filters: Vec<Box<dyn OutputFilter + Send + Sync>> = Vec::new();
+        if !config.deny.is_empty() {
+            filters.push(Box::new(DenyList {
+                patterns: RegexSet::new(&config.deny)?,
+            }));
+        }
+        if let Some(callout) = config.callout {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_millis(callout.timeout_ms))
+                .build()
+                .map_err(ContentFilterError::Callout)?;
+            filters.push(Box::new(Callout {
+                client,
+                url: callout.url,
+                fail_open: callout.fail_open,
+            }));
+        }
+        Ok(Self {
+            filters,
+            holdback_tokens: config.holdback_tokens,
+        })
+    }
+
+    /// Name of the first filter flagging `text`
+    async fn check(&self, text: &str) -> Option<&'static str> {
+        for filter in &self.filters {
+            if filter.flags(text).await {
+                return Some(filter.name());
+            }
+        }
+        None
+    }
+}
+
+/// Apply the `content_filter` to the tokens of a generation `stream` whose request has `seed`
+pub(crate) fn moderate<'a>(
+    content_filter: Option<Arc<ContentFilter>>,
+    seed: u64,
+    stream: impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a,
+) -> impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a {
+    stream! {
+        let mut stream = std::pin::pin!(stream);
+        let Some(content_filter) = content_filter else {
+            while let Some(response) = stream.next().await {
+                yield response;
+            }
+            return;
+        };
+
+        // Text of the checked and held tokens, and length of the checked part
+        let mut text = String::new();
+        let mut checked = 0;
+        let mut held: Vec<StreamedToken> = Vec::new();
+        let mut generated_tokens = 0;
+        let mut first_token = None;
+
+        // Dropping the generation stream when a filter trips cancels the request in the backend
+        while let Some(response) = stream.next().await {
+            match response {
+                Ok(InferStreamResponse::Intermediate { token, top_tokens }) => {
+                    first_token.get_or_insert_with(Instant::now);
+                    generated_tokens += 1;
+                    if !token.special {
+                        text.push_str(&token.text);
+                    }
+                    held.push((token, top_tokens));
+                    if held.len() < content_filter.holdback_tokens {
+                        continue;
+                    }
+                    if let Some(filter) = content_filter.check(&text).await {
+                        let (mut token, top_tokens) = held.pop().unwrap();
+                        token.text.clear();
+                        let start = first_token.unwrap();
+                        yield Ok(InferStreamResponse::End {
+                            token,
+                            top_tokens,
+                            generated_text: filtered(filter, &text[..checked], generated_tokens, Some(seed)),
+                            start,
+                            queued: start,
+                        });
+                        return;
+                    }
+                    checked = text.len();
+                    for (token, top_tokens) in held.drain(..) {
+                        yield Ok(InferStreamResponse::Intermediate { token, top_tokens });
+                    }
+                }
+                Ok(InferStreamResponse::End { mut token, top_tokens, generated_text, start, queued }) => {
+                    if !token.special {
+                        text.push_str(&token.text);
+                    }
+                    if let Some(filter) = content_filter.check(&text).await {
+                        token.text.clear();
+                        yield Ok(InferStreamResponse::End {
+                            token,
+                            top_tokens,
+                            generated_text: GeneratedText {
+                                batches: generated_text.batches,
+                                ..filtered(filter, &text[..checked], generated_text.generated_tokens, generated_text.seed)
+                            },
+                            start,
+                            queued,
+                        });
+                        return;
+                    }
+                    for (token, top_tokens) in held.drain(..) {
+                        yield Ok(InferStreamResponse::Intermediate { token, top_tokens });
+                    }
+                    yield Ok(InferStreamResponse::End { token, top_tokens, generated_text, start, queued });
+                    return;
+                }
+                response => yield response,
+            }
+        }
+    }
+}
+
+fn filtered(
+    filter: &'static str,
+    text: &str,
+    generated_tokens: u32,
+    seed: Option<u64>,
+) -> GeneratedText {
+    metrics::counter!("tgi_request_content_filtered", "filter" => filter).increment(1);
+    tracing::info!("Generation stopped by the `{filter}` content filter");
+    GeneratedText {
+        text: text.to_string(),
+        generated_tokens,
+        finish_reason: FinishReason::ContentFilter,
+        seed,
+        batches: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Token;
+
+    fn content_filter(deny: &[&str], holdback_tokens: usize) -> Arc<ContentFilter> {
+        Arc::new(
+            ContentFilter::from_config(ContentFilterConfig {
+                deny: deny.iter().map(|pattern| pattern.to_string()).collect(),
+                callout: None,
+                holdback_tokens,
+            })
+            .unwrap(),
+        )
+    }
+
+    fn token(text: &str) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        }
+    }
+
+    fn generation(texts: &[&str]) -> Vec<Result<InferStreamResponse, InferError>> {
+        let (last, intermediate) = texts.split_last().unwrap();
+        let mut responses: Vec<_> = intermediate
+            .iter()
+            .map(|text| {
+                Ok(InferStreamResponse::Intermediate {
+                    token: token(text),
+                    top_tokens: vec![],
+                })
+            })
+            .collect();
+        responses.push(Ok(InferStreamResponse::End {
+            token: token(last),
+            top_tokens: vec![],
+            generated_text: GeneratedText {
+                text: texts.concat(),
+                generated_tokens: texts.len() as u32,
+                finish_reason: FinishReason::EndOfSequenceToken,
+                seed: Some(0),
+                batches: Default::default(),
+            },
+            start: Instant::now(),
+            queued: Instant::now(),
+        }));
+        responses
+    }
+
+    /// Texts of the streamed tokens, and the generated text and finish reason of the end
+    async fn run(
+        content_filter: Arc<ContentFilter>,
+        texts: &[&str],
+    ) -> (Vec<String>, String, FinishReason) {
+        let stream = moderate(
+            Some(content_filter),
+            0,
+            tokio_stream::iter(generation(texts)),
+        );
+        let mut stream = std::pin::pin!(stream);
+        let mut streamed = Vec::new();
+        while let Some(response) = stream.next().await {
+            match response.unwrap() {
+                InferStreamResponse::Intermediate { token, .. } => streamed.push(token.text),
+                InferStreamResponse::End { generated_text, .. } => {
+                    return (streamed, generated_text.text, generated_text.finish_reason)
+                }
+                _ => {}
+            }
+        }
+        panic!("The generation did not end");
+    }
+
+    #[tokio::test]
+    async fn test_unflagged_generation() {
+        let (streamed, text, finish_reason) =
+            run(content_filter(&["secret"], 2), &["Hello", " world", "!"]).await;
+        assert_eq!(streamed, ["Hello", " world"]);
+        assert_eq!(text, "Hello world!");
+        assert!(matches!(finish_reason, FinishReason::EndOfSequenceToken));
+    }
+
+    #[tokio::test]
+    async fn test_flagged_generation_drops_held_tokens() {
+        let (streamed, text, finish_reason) = run(
+            content_filter(&["(?i)secret"], 3),
+            &["The", " code", " is", " SEC", "RET", " now", "!"],
+        )
+        .await;
+        // The match spans the held tokens, which are never streamed
+        assert_eq!(streamed, ["The", " code", " is"]);
+        assert_eq!(text, "The code is");
+        assert!(matches!(finish_reason, FinishReason::ContentFilter));
+    }
+
+    #[tokio::test]
+    async fn test_flagged_last_token() {
+        let (streamed, text, finish_reason) =
+            run(content_filter(&["secret"], 4), &["a", " secret"]).await;
+        assert!(streamed.is_empty());
+        assert_eq!(text, "");
+        assert!(matches!(finish_reason, FinishReason::ContentFilter));
+    }
+
+    #[tokio::test]
+    async fn test_callout_failure() {
+        let callout = |fail_open: bool| {
+            let config: ContentFilterConfig = serde_json::from_value(serde_json::json!({
+                "callout": {"url": "http://127.0.0.1:1/check", "fail_open": fail_open},
+            }))
+            .unwrap();
+            Arc::new(ContentFilter::from_config(config).unwrap())
+        };
+        // The moderation service is unreachable
+        let (streamed, _, finish_reason) = run(callout(false), &["Hello", "!"]).await;
+        assert!(streamed.is_empty());
+        assert!(matches!(finish_reason, FinishReason::ContentFilter));
+        let (_, text, finish_reason) = run(callout(true), &["Hello", "!"]).await;
+        assert_eq!(text, "Hello!");
+        assert!(matches!(finish_reason, FinishReason::EndOfSequenceToken));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = ContentFilterConfig {
+            deny: vec!["(".to_string()],
+            callout: None,
+            holdback_tokens: 8,
+        };
+        assert!(matches!(
+            ContentFilter::from_config(config),
+            Err(ContentFilterError::Deny(_))
+        ));
+        let config: ContentFilterConfig =
+            serde_json::from_str(r#"{"holdback_tokens": 0}"#).unwrap();
+        assert!(matches!(
+            ContentFilter::from_config(config),
+            Err(ContentFilterError::HoldbackTokens)
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::infer::abort::Owner;
+use crate::validation::ValidationError;
+use crate::GenerateRequest;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Number of finished generations kept to be continued
+const MAX_CONTINUATIONS: usize = 1024;
+
+/// Generation that a later request can continue with `continue_from`
+#[derive(Debug)]
+struct Continuation {
+    owner: Owner,
+    /// Inputs of the request followed by the text it generated
+    text: String,
+    /// Session of the request, which still holds its KV cache
+    session_id: Option<String>,
+    adapter_id: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    continuations: HashMap<String, Continuation>,
+    /// Request ids, from the oldest generation to the latest
+    order: VecDeque<String>,
+}
+
+/// Latest finished generations, by the `x-request-id` of their request
+#[derive(Debug, Default)]
+pub(crate) struct Continuations {
+    state: Mutex<State>,
+}
+
+impl Continuations {
+    /// Record the generation of request `id`, with the inputs of the request
+    pub(crate) fn insert(
+        &self,
+        id: String,
+        owner: Owner,
+        request: GenerateRequest,
+        generated_text: &str,
+    ) {
+        let continuation = Continuation {
+            owner,
+            text: request.inputs + generated_text,
+            session_id: request.parameters.session_id,
+            adapter_id: request.parameters.adapter_id,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state
+            .continuations
+            .insert(id.clone(), continuation)
+            .is_some()
+        {
+            state.order.retain(|recorded| *recorded != id);
+        }
+        state.order.push_back(id);
+        if state.order.len() > MAX_CONTINUATIONS {
+            if let Some(oldest) = state.order.pop_front() {
+                state.continuations.remove(&oldest);
+            }
+        }
+    }
+
+    /// Prepend the text of the generation that `request` continues to its inputs, and keep the
+    /// request in the session of that generation to reuse its KV cache
+    pub(crate) fn resolve(
+        &self,
+        request: &mut GenerateRequest,
+        owner: &Owner,
+    ) -> Result<(), ValidationError> {
+        let Some(id) = request.parameters.continue_from.take() else {
+            return Ok(());
+        };
+        let state = self.state.lock().unwrap();
+        // The generations of other clients are unknown to this one
+        let continuation = state
+            .continuations
+            .get(&id)
+            .filter(|continuation| continuation.owner.is(owner))
+            .ok_or(ValidationError::UnknownContinuation(id))?;
+        request.inputs = format!("{}{}", continuation.text, request.inputs);
+        let parameters = &mut request.parameters;
+        if parameters.adapter_id.is_none() && parameters.adapters.is_none() {
+            parameters.adapter_id.clone_from(&continuation.adapter_id);
+        }
+        if parameters.session_id.is_none() {
+            parameters.session_id.clone_from(&continuation.session_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GenerateParameters;
+
+    fn request(inputs: &str, parameters: GenerateParameters) -> GenerateRequest {
+        GenerateRequest {
+            inputs: inputs.to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters,
+        }
+    }
+
+    fn owner(tenant: &str) -> Owner {
+        Owner::new(Some(tenant.to_string()), None, true)
+    }
+
+    #[test]
+    fn test_continue_generation() {
+        let continuations = Continuations::default();
+        let parameters = GenerateParameters {
+            session_id: Some("conversation-42".to_string()),
+            ..Default::default()
+        };
+        continuations.insert(
+            "a".to_string(),
+            owner("tenant"),
+            request("My name is", parameters),
+            " Olivier",
+        );
+
+        let mut continued = request(
+            " and I",
+            GenerateParameters {
+                continue_from: Some("a".to_string()),
+                ..Default::default()
+            },
+        );
+        continuations
+            .resolve(&mut continued, &owner("tenant"))
+            .unwrap();
+        assert_eq!(continued.inputs, "My name is Olivier and I");
+        assert_eq!(
+            continued.parameters.session_id.as_deref(),
+            Some("conversation-42")
+        );
+        assert_eq!(continued.parameters.continue_from, None);
+
+        let mut other = request(
+            "",
+            GenerateParameters {
+                continue_from: Some("a".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            continuations.resolve(&mut other, &owner("other")),
+            Err(ValidationError::UnknownContinuation(_))
+        ));
+    }
+
+    #[test]
+    fn test_continuations_eviction() {
+        let continuations = Continuations::default();
+        for id in 0..=MAX_CONTINUATIONS {
+            let request = request("", Default::default());
+            continuations.insert(id.to_string(), owner("tenant"), request, "text");
+        }
+        let mut oldest = request(
+            "",
+            GenerateParameters {
+                continue_from: Some("0".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(continuations
+            .resolve(&mut oldest, &owner("tenant"))
+            .is_err());
+        assert_eq!(
+            continuations.state.lock().unwrap().continuations.len(),
+            MAX_CONTINUATIONS
+        );
+    }
+}
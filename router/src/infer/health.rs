@@ -0,0 +1,122 @@
+/// Health checks exercising the whole inference stack
+use crate::infer::{Infer, InferError, InferStreamResponse};
+use crate::{default_parameters, GenerateParameters, GenerateRequest, Priority};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::MissedTickBehavior;
+use tokio_stream::StreamExt;
+
+/// Prompt of the probe generations
+const PROBE_INPUTS: &str = "health";
+/// A prefill and a decode
+const PROBE_NEW_TOKENS: u32 = 2;
+
+/// Outcome of the recent generations
+#[derive(Debug)]
+pub(crate) struct InferenceHealth {
+    /// Unix timestamp in seconds of the last generation that reached its end, 0 if none did
+    last_success: AtomicU64,
+    /// Whether health probes run in the background
+    probing: bool,
+    /// Whether the last health probe failed
+    probe_failed: AtomicBool,
+}
+
+impl InferenceHealth {
+    pub(crate) fn new(probing: bool) -> Self {
+        Self {
+            last_success: AtomicU64::new(0),
+            probing,
+            probe_failed: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn record_success(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.last_success.store(now, Ordering::Relaxed);
+    }
+
+    /// Unix timestamp in seconds of the last generation that reached its end
+    pub(crate) fn last_success(&self) -> Option<u64> {
+        Some(self.last_success.load(Ordering::Relaxed)).filter(|&timestamp| timestamp > 0)
+    }
+
+    /// Result of the last health probe, `None` if health probes are disabled
+    pub(crate) fn probe_result(&self) -> Option<bool> {
+        self.probing
+            .then(|| !self.probe_failed.load(Ordering::Relaxed))
+    }
+}
+
+/// Background task generating a few tokens every `interval` through the queue and the shards,
+/// so that hung shards are detected even when the health checks of the process succeed.
+/// A probe taking longer than `interval` fails. Probes are queued with the high priority, ahead
+/// of the client requests of the other priorities, so that a long queue is not taken for hung
+/// shards.
+pub(crate) async fn probe_task(infer: Infer, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let result = match tokio::time::timeout(interval, probe(&infer)).await {
+            Ok(result) => result,
+            Err(_) => Err(InferError::GenerationTimeout),
+        };
+        if let Err(err) = &result {
+            tracing::error!("Health probe failed: {err}");
+        }
+        metrics::counter!("tgi_health_probe", "success" => result.is_ok().to_string()).increment(1);
+        infer
+            .inference_health
+            .probe_failed
+            .store(result.is_err(), Ordering::Relaxed);
+    }
+}
+
+async fn probe(infer: &Infer) -> Result<(), InferError> {
+    let request = GenerateRequest {
+        inputs: PROBE_INPUTS.to_string(),
+        template: None,
+        variables: None,
+        add_special_tokens: true,
+        parameters: GenerateParameters {
+            max_new_tokens: Some(PROBE_NEW_TOKENS),
+            priority: Some(Priority::High),
+            ..default_parameters()
+        },
+    };
+    let mut valid_request = infer.validation().validate(request).await?;
+    // Run the decode even if the model ends the generation on its first token
+    valid_request.stopping_parameters.ignore_eos_token = true;
+
+    let mut stream = infer.backend.schedule(valid_request)?;
+    while let Some(response) = stream.next().await {
+        if let InferStreamResponse::End { .. } = response? {
+            infer.inference_health.record_success();
+            return Ok(());
+        }
+    }
+    Err(InferError::IncompleteGeneration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inference_health() {
+        let health = InferenceHealth::new(false);
+        assert_eq!(health.probe_result(), None);
+        assert_eq!(health.last_success(), None);
+        health.record_success();
+        assert!(health.last_success().is_some());
+
+        let health = InferenceHealth::new(true);
+        assert_eq!(health.probe_result(), Some(true));
+        health.probe_failed.store(true, Ordering::Relaxed);
+        assert_eq!(health.probe_result(), Some(false));
+    }
+}
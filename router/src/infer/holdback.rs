@@ -0,0 +1,179 @@
+/// Streaming-safe handling of the stop sequences
+use crate::{FinishReason, Token};
+use std::collections::VecDeque;
+
+/// Streamed token with its top tokens
+pub(crate) type StreamedToken = (Token, Vec<Token>);
+
+/// Withholds the streamed tokens whose text could be the beginning of a stop sequence.
+///
+/// The shards only detect a stop sequence once its last token is generated, so the tokens
+/// before it would otherwise already be streamed. Withheld tokens are released as soon as the
+/// following text rules out a match, and the stop sequence itself is cut from the stream.
+#[derive(Debug)]
+pub(crate) struct StopHoldback {
+    stop_sequences: Vec<String>,
+    /// Tokens not streamed yet
+    held: VecDeque<StreamedToken>,
+    /// Concatenated text of the held tokens
+    text: String,
+}
+
+impl StopHoldback {
+    pub(crate) fn new(stop_sequences: Vec<String>) -> Self {
+        Self {
+            stop_sequences: stop_sequences
+                .into_iter()
+                .filter(|stop| !stop.is_empty())
+                .collect(),
+            held: VecDeque::new(),
+            text: String::new(),
+        }
+    }
+
+    /// Add a generated token and return the tokens that can be streamed
+    pub(crate) fn push(&mut self, token: Token, top_tokens: Vec<Token>) -> Vec<StreamedToken> {
+        if self.stop_sequences.is_empty() {
+            return vec![(token, top_tokens)];
+        }
+        self.text.push_str(&token.text);
+        self.held.push_back((token, top_tokens));
+
+        // Release the tokens that end before a possible stop sequence
+        let keep = self.text.len() - self.partial_match_len();
+        let mut released = Vec::new();
+        let mut offset = 0;
+        while let Some((token, _)) = self.held.front() {
+            if offset + token.text.len() > keep {
+                break;
+            }
+            offset += token.text.len();
+            released.push(self.held.pop_front().unwrap());
+        }
+        self.text.drain(..offset);
+        released
+    }
+
+    /// Add the last generated token and return the tokens to stream before it, and the last
+    /// token itself. The text of a matched stop sequence is removed from the returned tokens.
+    pub(crate) fn finish(
+        &mut self,
+        token: Token,
+        top_tokens: Vec<Token>,
+        finish_reason: &FinishReason,
+    ) -> (Vec<StreamedToken>, StreamedToken) {
+        if self.stop_sequences.is_empty() {
+            return (Vec::new(), (token, top_tokens));
+        }
+        self.text.push_str(&token.text);
+        self.held.push_back((token, top_tokens));
+
+        let mut cut = self.text.len();
+        if matches!(finish_reason, FinishReason::StopSequence) {
+            if let Some(stop) = self
+                .stop_sequences
+                .iter()
+                .filter(|stop| self.text.ends_with(stop.as_str()))
+                .max_by_key(|stop| stop.len())
+            {
+                cut -= stop.len();
+            }
+        }
+
+        let (mut last, last_top_tokens) = self.held.pop_back().unwrap();
+        let mut released = Vec::new();
+        let mut offset = 0;
+        for (mut token, top_tokens) in self.held.drain(..) {
+            if offset >= cut {
+                // Part of the stop sequence
+                break;
+            }
+            if offset + token.text.len() > cut {
+                token.text.truncate(cut - offset);
+            }
+            offset += token.text.len();
+            released.push((token, top_tokens));
+        }
+        last.text
+            .truncate(cut.saturating_sub(offset).min(last.text.len()));
+        self.text.clear();
+
+        (released, (last, last_top_tokens))
+    }
+
+    /// Length of the longest end of the held text that starts a stop sequence
+    fn partial_match_len(&self) -> usize {
+        self.stop_sequences
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .filter(|&len| stop.is_char_boundary(len))
+                    .find(|&len| self.text.ends_with(&stop[..len]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(text: &str) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        }
+    }
+
+    fn texts(tokens: &[StreamedToken]) -> Vec<&str> {
+        tokens
+            .iter()
+            .map(|(token, _)| token.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_holdback_releases_ruled_out_prefix() {
+        let mut holdback = StopHoldback::new(vec!["\nUser:".to_string()]);
+        assert_eq!(texts(&holdback.push(token("Hello"), vec![])), ["Hello"]);
+        assert!(holdback.push(token("\n"), vec![]).is_empty());
+        assert!(holdback.push(token("Us"), vec![]).is_empty());
+        assert_eq!(
+            texts(&holdback.push(token("eful"), vec![])),
+            ["\n", "Us", "eful"]
+        );
+    }
+
+    #[test]
+    fn test_holdback_cuts_stop_sequence() {
+        let mut holdback = StopHoldback::new(vec!["\nUser:".to_string()]);
+        assert_eq!(texts(&holdback.push(token("Hi"), vec![])), ["Hi"]);
+        assert!(holdback.push(token(" there\n"), vec![]).is_empty());
+        assert!(holdback.push(token("User"), vec![]).is_empty());
+
+        let (released, (last, _)) =
+            holdback.finish(token(":"), vec![], &FinishReason::StopSequence);
+        assert_eq!(texts(&released), [" there"]);
+        assert_eq!(last.text, "");
+    }
+
+    #[test]
+    fn test_holdback_flushes_on_length() {
+        let mut holdback = StopHoldback::new(vec!["###".to_string()]);
+        assert!(holdback.push(token("#"), vec![]).is_empty());
+
+        let (released, (last, _)) = holdback.finish(token("#"), vec![], &FinishReason::Length);
+        assert_eq!(texts(&released), ["#"]);
+        assert_eq!(last.text, "#");
+    }
+
+    #[test]
+    fn test_holdback_without_stop_sequences() {
+        let mut holdback = StopHoldback::new(vec![]);
+        assert_eq!(texts(&holdback.push(token("a"), vec![])), ["a"]);
+    }
+}
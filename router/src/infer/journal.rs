@@ -0,0 +1,450 @@
+/// Journal of the queued requests, replayed when the router starts again
+use crate::infer::{collect_response, Infer, InferError};
+use crate::{FinishReason, GenerateParameters, GenerateRequest};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Queued and dequeued records, in the journal directory
+const JOURNAL_FILE: &str = "queue.jsonl";
+/// Outcomes of the replayed requests, in the journal directory
+const REPLAYED_FILE: &str = "replayed.jsonl";
+/// Number of records of dequeued requests from which the journal file is rewritten
+const COMPACTION_THRESHOLD: usize = 1024;
+/// Delay before replaying again a request that the server could not admit
+const REPLAY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Record {
+    Queued { id: u64, request: JournaledRequest },
+    Dequeued { id: u64 },
+}
+
+/// Record of a queued request in the journal: the request as sent by its client, with the seed
+/// drawn by the validation so that a replay samples the same tokens
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct JournaledRequest {
+    inputs: String,
+    parameters: GenerateParameters,
+    add_special_tokens: bool,
+}
+
+impl From<&GenerateRequest> for JournaledRequest {
+    fn from(request: &GenerateRequest) -> Self {
+        Self {
+            inputs: request.inputs.clone(),
+            parameters: request.parameters.clone(),
+            add_special_tokens: request.add_special_tokens,
+        }
+    }
+}
+
+impl From<JournaledRequest> for GenerateRequest {
+    fn from(request: JournaledRequest) -> Self {
+        Self {
+            inputs: request.inputs,
+            parameters: request.parameters,
+            add_special_tokens: request.add_special_tokens,
+            // Journaled after the rendering of their template
+            template: None,
+            variables: None,
+        }
+    }
+}
+
+/// Append-only file of the requests waiting for their first token.
+///
+/// A request is journaled once it is validated and dequeued when it generates its first token,
+/// fails or is cancelled by its client. The requests still queued when the router crashes or is
+/// restarted are replayed on the next start, and their outcomes are appended to
+/// `replayed.jsonl` as their clients are gone.
+///
+/// The files are written by a thread of the journal, so that the requests never wait for the
+/// disk.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    /// Set on shutdown, so that the requests dropped with the server stay in the journal
+    closed: AtomicBool,
+    next_id: AtomicU64,
+    /// Records for the writer thread, until the journal is dropped
+    sender: Option<mpsc::Sender<Command>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Write for the writer thread
+#[derive(Debug)]
+enum Command {
+    Queued {
+        id: u64,
+        line: String,
+    },
+    Dequeued {
+        id: u64,
+    },
+    /// Outcome of a replayed request
+    Replayed(String),
+    /// Number of queued requests
+    #[cfg(test)]
+    Len(mpsc::Sender<usize>),
+}
+
+/// Files of the journal, owned by the writer thread
+#[derive(Debug)]
+struct State {
+    dir: PathBuf,
+    file: File,
+    /// Serialized records of the queued requests
+    queued: BTreeMap<u64, String>,
+    /// Number of records of the file that belong to dequeued requests
+    obsolete: usize,
+}
+
+impl State {
+    fn write(&mut self, command: Command) {
+        match command {
+            Command::Queued { id, line } => {
+                if let Err(err) = append(&mut self.file, &line) {
+                    tracing::error!("Failed to journal request: {err}");
+                }
+                self.queued.insert(id, line);
+            }
+            Command::Dequeued { id } => {
+                if self.queued.remove(&id).is_none() {
+                    return;
+                }
+                let line = serde_json::to_string(&Record::Dequeued { id }).unwrap();
+                if let Err(err) = append(&mut self.file, &line) {
+                    tracing::error!("Failed to journal request: {err}");
+                }
+
+                // The queued record and this one
+                self.obsolete += 2;
+                if self.obsolete >= COMPACTION_THRESHOLD {
+                    match rewrite(&self.dir, &self.queued) {
+                        Ok(file) => {
+                            self.file = file;
+                            self.obsolete = 0;
+                        }
+                        Err(err) => tracing::error!("Failed to compact the journal: {err}"),
+                    }
+                }
+            }
+            Command::Replayed(line) => {
+                let result = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(REPLAYED_FILE))
+                    .and_then(|mut file| append(&mut file, &line));
+                if let Err(err) = result {
+                    tracing::error!("Failed to record the outcome of a replayed request: {err}");
+                }
+            }
+            #[cfg(test)]
+            Command::Len(sender) => {
+                let _ = sender.send(self.queued.len());
+            }
+        }
+    }
+}
+
+impl Journal {
+    /// Open the journal of `dir`, and return it with the requests that were still queued when
+    /// the previous router stopped
+    pub(crate) fn open(
+        dir: impl AsRef<Path>,
+    ) -> std::io::Result<(Arc<Self>, Vec<(u64, JournaledRequest)>)> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut queued = BTreeMap::new();
+        let mut next_id = 0;
+        match File::open(dir.join(JOURNAL_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match serde_json::from_str(&line) {
+                        Ok(Record::Queued { id, request }) => {
+                            next_id = next_id.max(id + 1);
+                            queued.insert(id, (line, request));
+                        }
+                        Ok(Record::Dequeued { id }) => {
+                            queued.remove(&id);
+                        }
+                        // The last record is cut if the router crashed while writing it
+                        Err(err) => tracing::warn!("Skipping invalid journal record: {err}"),
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let (queued, requests): (BTreeMap<u64, String>, Vec<(u64, JournaledRequest)>) = queued
+            .into_iter()
+            .map(|(id, (line, request))| ((id, line), (id, request)))
+            .unzip();
+        let file = rewrite(&dir, &queued)?;
+
+        let mut state = State {
+            dir,
+            file,
+            queued,
+            obsolete: 0,
+        };
+        let (sender, receiver) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("journal".to_string())
+            .spawn(move || {
+                for command in receiver {
+                    state.write(command);
+                }
+            })?;
+        let journal = Arc::new(Self {
+            closed: AtomicBool::new(false),
+            next_id: AtomicU64::new(next_id),
+            sender: Some(sender),
+            writer: Some(writer),
+        });
+        Ok((journal, requests))
+    }
+
+    fn send(&self, command: Command) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(command);
+        }
+    }
+
+    /// Journal a request until it leaves the queue
+    pub(crate) fn enqueue(self: &Arc<Self>, request: &GenerateRequest) -> JournalEntry {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let record = Record::Queued {
+            id,
+            request: request.into(),
+        };
+        match serde_json::to_string(&record) {
+            Ok(line) => self.send(Command::Queued { id, line }),
+            Err(err) => tracing::error!("Failed to serialize request: {err}"),
+        }
+        self.entry(id)
+    }
+
+    /// Entry of a request that is already in the journal
+    pub(crate) fn entry(self: &Arc<Self>, id: u64) -> JournalEntry {
+        JournalEntry {
+            journal: self.clone(),
+            id,
+            dequeued: false,
+        }
+    }
+
+    /// Remove a request from the journal. Does nothing if it is not in the journal.
+    pub(crate) fn dequeue(&self, id: u64) {
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        self.send(Command::Dequeued { id });
+    }
+
+    /// Keep the requests that are still queued for the next start of the router
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn record_outcome(&self, outcome: &ReplayOutcome) {
+        self.send(Command::Replayed(serde_json::to_string(outcome).unwrap()));
+    }
+
+    /// Number of requests in the journal, once the previous records are written
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        let (sender, receiver) = mpsc::channel();
+        self.send(Command::Len(sender));
+        receiver.recv().unwrap()
+    }
+}
+
+impl Drop for Journal {
+    /// Write the last records before the journal is opened again
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Journaled request that is dequeued when it receives its first token or is dropped
+#[derive(Debug)]
+pub(crate) struct JournalEntry {
+    journal: Arc<Journal>,
+    id: u64,
+    dequeued: bool,
+}
+
+impl JournalEntry {
+    pub(crate) fn dequeue(&mut self) {
+        if !self.dequeued {
+            self.dequeued = true;
+            self.journal.dequeue(self.id);
+        }
+    }
+}
+
+impl Drop for JournalEntry {
+    fn drop(&mut self) {
+        self.dequeue();
+    }
+}
+
+/// Write a record in a single call, so that concurrent processes do not interleave them
+fn append(file: &mut File, line: &str) -> std::io::Result<()> {
+    file.write_all(format!("{line}\n").as_bytes())
+}
+
+/// Replace the journal file by the records of the queued requests and open it for appending
+fn rewrite(dir: &Path, queued: &BTreeMap<u64, String>) -> std::io::Result<File> {
+    let path = dir.join(JOURNAL_FILE);
+    let tmp_path = dir.join(format!("{JOURNAL_FILE}.tmp"));
+    let mut tmp = File::create(&tmp_path)?;
+    for line in queued.values() {
+        append(&mut tmp, line)?;
+    }
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayOutcome {
+    id: u64,
+    inputs: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Generate the requests left in the journal by the previous router
+pub(crate) fn replay(infer: Infer, requests: Vec<(u64, JournaledRequest)>) {
+    tracing::info!("Replaying {} journaled requests", requests.len());
+    for (id, request) in requests {
+        tokio::spawn(replay_request(infer.clone(), id, request));
+    }
+}
+
+async fn replay_request(infer: Infer, id: u64, request: JournaledRequest) {
+    let Some(journal) = infer.journal.clone() else {
+        return;
+    };
+    let inputs = request.inputs.clone();
+    let result = loop {
+        match infer
+            .generate_stream_journaled(request.clone().into(), Some(id))
+            .await
+        {
+            Ok((_permit, input_length, stream)) => {
+                break collect_response(stream, input_length, false).await;
+            }
+            // Refused before it could be queued, e.g. while the shards reconnect
+            Err(
+                InferError::Overloaded(_)
+                | InferError::QueueFull
+                | InferError::BackendUnavailable(_),
+            ) => {
+                tokio::time::sleep(REPLAY_RETRY_DELAY).await;
+            }
+            // Left in the journal for the next start
+            Err(InferError::Draining) => return,
+            Err(err) => break Err(err),
+        }
+    };
+
+    // Still in the journal if it failed before its journal entry was created
+    journal.dequeue(id);
+    let outcome = match result {
+        Ok(response) => ReplayOutcome {
+            id,
+            inputs,
+            generated_text: Some(response.generated_text.text),
+            finish_reason: Some(response.generated_text.finish_reason),
+            error: None,
+        },
+        Err(err) => {
+            tracing::error!("Replayed request {id} failed: {err}");
+            ReplayOutcome {
+                id,
+                inputs,
+                generated_text: None,
+                finish_reason: None,
+                error: Some(err.to_string()),
+            }
+        }
+    };
+    journal.record_outcome(&outcome);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_parameters;
+
+    fn request(inputs: &str) -> GenerateRequest {
+        GenerateRequest {
+            inputs: inputs.to_string(),
+            template: None,
+            variables: None,
+            parameters: GenerateParameters {
+                seed: Some(42),
+                ..default_parameters()
+            },
+            add_special_tokens: false,
+        }
+    }
+
+    #[test]
+    fn test_journal_keeps_queued_requests() {
+        let dir = std::env::temp_dir().join(format!("tgi-journal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (journal, requests) = Journal::open(&dir).unwrap();
+        assert!(requests.is_empty());
+        let mut started = journal.enqueue(&request("started"));
+        let queued = journal.enqueue(&request("queued"));
+        drop(journal.enqueue(&request("cancelled")));
+        started.dequeue();
+        assert_eq!(journal.len(), 1);
+
+        // The router stops before the request is started
+        journal.close();
+        drop(queued);
+        drop(started);
+        drop(journal);
+
+        let (journal, requests) = Journal::open(&dir).unwrap();
+        assert_eq!(requests.len(), 1);
+        let (id, replayed) = &requests[0];
+        assert_eq!(replayed.inputs, "queued");
+        assert_eq!(replayed.parameters.seed, Some(42));
+        assert!(!replayed.add_special_tokens);
+
+        // New requests do not take the id of a queued one
+        let entry = journal.enqueue(&request("new"));
+        assert!(entry.id > *id);
+
+        journal.dequeue(*id);
+        drop(entry);
+        drop(journal);
+        let (_, requests) = Journal::open(&dir).unwrap();
+        assert!(requests.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
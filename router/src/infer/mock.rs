@@ -0,0 +1,321 @@
+/// Backend generating synthetic tokens without a model, to run the router in tests and in local
+/// development without GPUs or Python shards
+use crate::infer::{Backend, GeneratedText, Infer, InferError, InferStreamResponse};
+use crate::validation::{
+    ValidGenerateRequest, ValidParameters, ValidStoppingParameters, Validation,
+};
+use crate::{
+    FinishReason, HubProcessorConfig, HubTokenizerConfig, PrefillToken, Priority, Token, Tokenizer,
+};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// Number of distinct tokens that the mock backend generates
+const MOCK_VOCAB_SIZE: u32 = 1000;
+/// Limits of the requests validated for the mock backend
+const MOCK_MAX_INPUT_TOKENS: usize = 1 << 20;
+const MOCK_MAX_TOTAL_TOKENS: usize = 1 << 21;
+
+/// Deterministic backend: the `i`-th generated token of every request is ` tok<i>`, with the
+/// id `i`, at a fixed rate. Generations end on `max_new_tokens` or a stop token id.
+#[derive(Clone, Debug)]
+pub struct MockBackend {
+    /// Delay between two tokens of a request, and before its first one
+    token_interval: Duration,
+}
+
+impl MockBackend {
+    /// Backend generating `tokens_per_second` tokens per request and per second, or as fast as
+    /// possible if it is not positive
+    pub fn new(tokens_per_second: f64) -> Self {
+        let token_interval = if tokens_per_second > 0.0 {
+            Duration::from_secs_f64(1.0 / tokens_per_second)
+        } else {
+            Duration::ZERO
+        };
+        Self { token_interval }
+    }
+
+    /// Token generated at `step`
+    pub fn token(step: u32) -> Token {
+        let id = step % MOCK_VOCAB_SIZE;
+        Token {
+            id,
+            text: format!(" tok{id}"),
+            logprob: 0.0,
+            special: false,
+        }
+    }
+
+    /// Tokenizer splitting the prompts on whitespace, into the tokens that the backend generates
+    /// and an unknown token for the other words
+    pub fn tokenizer() -> Tokenizer {
+        let mut vocab: serde_json::Map<String, serde_json::Value> = (0..MOCK_VOCAB_SIZE)
+            .map(|id| (format!("tok{id}"), id.into()))
+            .collect();
+        vocab.insert("[UNK]".to_string(), MOCK_VOCAB_SIZE.into());
+        let tokenizer = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]"},
+        });
+        Tokenizer::Rust(tokenizers::Tokenizer::from_str(&tokenizer.to_string()).unwrap())
+    }
+
+    /// Router state validating the requests with the mock tokenizer before generating them on
+    /// this backend
+    pub(crate) fn infer(self, max_concurrent_requests: usize) -> Infer {
+        let validation = Validation::new(
+            1,
+            Self::tokenizer(),
+            None,
+            None,
+            1,
+            4,
+            5,
+            MOCK_MAX_INPUT_TOKENS,
+            MOCK_MAX_TOTAL_TOKENS,
+            false,
+        );
+        Infer::new(
+            self,
+            validation,
+            max_concurrent_requests,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "mock".to_string(),
+            HashMap::new(),
+            None,
+            HubTokenizerConfig::default(),
+            HubProcessorConfig::default(),
+        )
+    }
+
+    /// Greedy request of `input_length` prompt tokens, as validated by the router
+    #[cfg(test)]
+    pub(crate) fn request(input_length: u32, max_new_tokens: u32) -> ValidGenerateRequest {
+        ValidGenerateRequest {
+            inputs: vec![],
+            input_ids: Some(Arc::new((0..input_length).collect())),
+            input_length,
+            truncate: 0,
+            add_special_tokens: true,
+            decoder_input_details: false,
+            parameters: ValidParameters {
+                temperature: 1.0,
+                top_k: 0,
+                top_p: 1.0,
+                typical_p: 1.0,
+                min_p: 0.0,
+                no_repeat_ngram_size: 0,
+                do_sample: false,
+                seed: 0,
+                repetition_penalty: 1.0,
+                frequency_penalty: 0.0,
+                presence_penalty: 0.0,
+                watermark: false,
+                watermark_key: 0,
+                grammar: None,
+                speculate: None,
+                healing_token_id: None,
+                logit_bias: HashMap::new(),
+            },
+            stopping_parameters: ValidStoppingParameters {
+                max_new_tokens,
+                max_total_new_tokens: max_new_tokens,
+                stop_sequences: vec![],
+                stop_token_ids: vec![],
+                min_new_tokens: 0,
+                ignore_eos_token: false,
+            },
+            top_n_tokens: 0,
+            adapter_id: None,
+            adapters: Vec::new(),
+            priority: Priority::Normal,
+            session_id: None,
+            generated_tokens: 0,
+            healed_prefix: None,
+            guidance: None,
+            tenant: None,
+            request_id: None,
+            deadline: None,
+            prompt_group: None,
+        }
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new(0.0)
+    }
+}
+
+#[async_trait]
+impl Backend for MockBackend {
+    fn schedule(
+        &self,
+        request: ValidGenerateRequest,
+    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(generate(request, self.token_interval, sender));
+        Ok(UnboundedReceiverStream::new(receiver))
+    }
+
+    async fn health(&self, _current_health: bool) -> bool {
+        true
+    }
+
+    fn start_health(&self) -> bool {
+        true
+    }
+
+    fn supports_stop_token_ids(&self) -> bool {
+        true
+    }
+}
+
+async fn generate(
+    request: ValidGenerateRequest,
+    token_interval: Duration,
+    sender: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+) {
+    let queued = Instant::now();
+    let start = Instant::now();
+    if request.decoder_input_details {
+        let prefill = request
+            .input_ids
+            .iter()
+            .flat_map(|input_ids| input_ids.iter())
+            .map(|&id| PrefillToken {
+                id,
+                text: String::new(),
+                logprob: 0.0,
+            })
+            .collect();
+        if sender
+            .send(Ok(InferStreamResponse::Prefill(prefill)))
+            .is_err()
+        {
+            return;
+        }
+    }
+
+    let stopping_parameters = &request.stopping_parameters;
+    let mut text = String::new();
+    for step in 0..stopping_parameters.max_new_tokens {
+        tokio::time::sleep(token_interval).await;
+        let token = MockBackend::token(step);
+        text.push_str(&token.text);
+        let generated_tokens = step + 1;
+        let finish_reason = if stopping_parameters.stop_token_ids.contains(&token.id) {
+            Some(FinishReason::StopSequence)
+        } else if generated_tokens == stopping_parameters.max_new_tokens {
+            Some(FinishReason::Length)
+        } else {
+            None
+        };
+        let response = match finish_reason {
+            None => InferStreamResponse::Intermediate {
+                token,
+                top_tokens: Vec::new(),
+            },
+            Some(finish_reason) => InferStreamResponse::End {
+                token,
+                top_tokens: Vec::new(),
+                generated_text: GeneratedText {
+                    text: std::mem::take(&mut text),
+                    generated_tokens,
+                    finish_reason,
+                    seed: request
+                        .parameters
+                        .do_sample
+                        .then_some(request.parameters.seed),
+                    batches: Default::default(),
+                },
+                start,
+                queued,
+            },
+        };
+        let end = matches!(response, InferStreamResponse::End { .. });
+        // The client is gone
+        if sender.send(Ok(response)).is_err() || end {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn request(max_new_tokens: u32, stop_token_ids: Vec<u32>) -> ValidGenerateRequest {
+        let mut request = MockBackend::request(2, max_new_tokens);
+        request.decoder_input_details = true;
+        request.stopping_parameters.stop_token_ids = stop_token_ids;
+        request
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_generates_tokens() {
+        let backend = MockBackend::default();
+        let responses: Vec<_> = backend
+            .schedule(request(3, vec![]))
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(responses.len(), 4);
+        match &responses[0] {
+            Ok(InferStreamResponse::Prefill(prefill)) => assert_eq!(prefill.len(), 2),
+            _ => panic!("Expected the prefill first"),
+        }
+        match &responses[3] {
+            Ok(InferStreamResponse::End { generated_text, .. }) => {
+                assert_eq!(generated_text.text, " tok0 tok1 tok2");
+                assert_eq!(generated_text.generated_tokens, 3);
+                assert!(matches!(generated_text.finish_reason, FinishReason::Length));
+            }
+            _ => panic!("Expected the end last"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_stop_token_ids() {
+        let backend = MockBackend::default();
+        let responses: Vec<_> = backend
+            .schedule(request(10, vec![1]))
+            .unwrap()
+            .collect()
+            .await;
+        match responses.last() {
+            Some(Ok(InferStreamResponse::End { generated_text, .. })) => {
+                assert_eq!(generated_text.text, " tok0 tok1");
+                assert!(matches!(
+                    generated_text.finish_reason,
+                    FinishReason::StopSequence
+                ));
+            }
+            _ => panic!("Expected the end last"),
+        }
+    }
+}
@@ -1,29 +1,71 @@
 // pub(crate) mod v2;
+pub(crate) mod abort;
+pub(crate) mod audit;
+mod backpressure;
+pub(crate) mod cache;
 mod chat_template;
+pub(crate) mod content_filter;
+mod continuation;
+mod health;
+pub(crate) mod holdback;
+pub(crate) mod journal;
+pub mod mock;
+pub mod swap;
 pub mod tool_grammar;
+pub(crate) mod transform;
+pub(crate) mod watermark;
 
-use crate::validation::{ValidGenerateRequest, Validation, ValidationError};
+use crate::auth;
+use crate::prompt_templates::PromptTemplateVersion;
+use crate::rate_limit;
+use crate::request_id;
+use crate::tenant;
+use crate::validation::{
+    PromptGroup, ValidGenerateRequest, Validation, ValidationError, ValidationLimits,
+};
 use crate::Tool;
 use crate::{
-    ChatTemplateVersions, FinishReason, GenerateRequest, HubProcessorConfig, HubTokenizerConfig,
-    Message, PrefillToken, Token,
+    AdapterWeight, BatchRecord, CacheStats, ChatTemplateVersions, FinishReason, GenerateParameters,
+    GenerateRequest, HubProcessorConfig, HubTokenizerConfig, IterationTimings, LoadAdapterRequest,
+    LoraAdapterInfo, Message, PrefillToken, QueuedRequest, RuntimeConfig, SessionStats, ShardInfo,
+    SwapModelRequest, SwapModelStatus, Token,
 };
+use abort::{Aborts, Owner};
 use async_stream::stream;
 use async_trait::async_trait;
+use audit::AuditLog;
 use axum::response::sse::Event;
+use backpressure::Backpressure;
+use cache::ResponseCache;
 use chat_template::ChatTemplate;
+use content_filter::ContentFilter;
+use continuation::Continuations;
 use futures::future::try_join_all;
 use futures::Stream;
+use health::InferenceHealth;
+use journal::Journal;
 use minijinja::ErrorKind;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use swap::{ModelFiles, ModelSwap, ShardCheck, SwapTracker};
 use thiserror::Error;
-use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
 use tracing::instrument;
+use transform::Transforms;
+use watermark::WatermarkKeys;
+
+/// Interval of the keep-alive comments of the streams without `--stream-heartbeat-interval`
+const DEFAULT_STREAM_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Largest total rank of the adapters mixed by a request, above which the shards could not
+/// run the mixture with their LoRA kernels
+const MAX_ADAPTERS_RANK: u32 = 128;
 
 #[async_trait]
 pub trait Backend {
@@ -34,27 +76,255 @@ pub trait Backend {
 
     async fn health(&self, current_health: bool) -> bool;
 
+    /// Whether the requests of a `PromptGroup` are batched together to prefill their prompt
+    /// once
+    fn shares_prompt_kv(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards of at least one model can embed their inputs
+    fn supports_embeddings(&self) -> bool {
+        false
+    }
+
+    /// Embed every tokenized input with the shards of `model`, or of the default model, outside
+    /// of the generation queue
+    async fn embed(
+        &self,
+        _model: Option<&str>,
+        _input_ids: Vec<Vec<u32>>,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        Err(InferError::EmbeddingsUnsupported)
+    }
+
     /// The state of the health on startup
     /// Typically false, or true if the backend includes
     /// a warmup phase.
     fn start_health(&self) -> bool {
         false
     }
+
+    /// Most recent batches formed by the backend, oldest first
+    async fn batch_history(&self) -> Vec<BatchRecord> {
+        Vec::new()
+    }
+
+    /// Phase timings of the most recent decode iterations of the backend, oldest first
+    fn iteration_timings(&self) -> Vec<IterationTimings> {
+        Vec::new()
+    }
+
+    /// Sessions whose KV cache is kept by the backend
+    async fn session_stats(&self) -> Vec<SessionStats> {
+        Vec::new()
+    }
+
+    /// KV cache usage of the block allocators of the backend
+    async fn cache_stats(&self) -> Vec<CacheStats> {
+        Vec::new()
+    }
+
+    /// Requests waiting in the queues of the backend, by queue in their order
+    async fn queued_requests(&self) -> Vec<QueuedRequest> {
+        Vec::new()
+    }
+
+    /// Ratio of waiting requests to running requests from which the backend adds a new batch
+    /// to the running one, if the backend has this setting
+    fn waiting_served_ratio(&self) -> Option<f32> {
+        None
+    }
+
+    /// Change the `waiting_served_ratio` for the batches formed from now on
+    fn set_waiting_served_ratio(&self, _waiting_served_ratio: f32) {}
+
+    /// Names of the models served by the backend, the default one first, if the backend
+    /// serves several of them. A request selects one of them with its `adapter_id`.
+    fn served_models(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Limits of the requests of one of the served models, if the backend serves several of
+    /// them
+    fn model_token_limits(&self, _model: &str) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Model and kernels of the shards of one of the served models, if the backend serves
+    /// several of them
+    fn model_shard_info(&self, _model: &str) -> Option<ShardInfo> {
+        None
+    }
+
+    /// Whether the shards constrain the first token of a generation to the prompt text that
+    /// token healing removed
+    fn supports_token_healing(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards add the `logit_bias` of the requests to their logits
+    fn supports_logit_bias(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards apply the `presence_penalty` of the requests
+    fn supports_presence_penalty(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards filter the tokens below the `min_p` of the requests
+    fn supports_min_p(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards ban the tokens repeating an n-gram of `no_repeat_ngram_size` tokens
+    fn supports_no_repeat_ngram_size(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards generate the negative prompts of the requests using classifier-free
+    /// guidance, and combine their logits
+    fn supports_guidance(&self) -> bool {
+        false
+    }
+
+    /// LoRA adapters that the shards mix for the requests setting `adapters`, with their rank.
+    /// None if the shards do not mix adapters.
+    fn lora_adapters(&self) -> Option<HashMap<String, u32>> {
+        None
+    }
+
+    /// Whether the shards watermark the generations with the key set by the router
+    fn supports_watermark_key(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards end the generations on the `stop_token_ids` of the requests
+    fn supports_stop_token_ids(&self) -> bool {
+        false
+    }
+
+    /// Whether the shards keep generating until the `min_new_tokens` of the requests
+    fn supports_min_new_tokens(&self) -> bool {
+        false
+    }
+
+    /// Whether the backend fails the requests still queued at their `deadline_ms`
+    fn supports_deadlines(&self) -> bool {
+        false
+    }
+
+    /// Model and kernels of the shards, if the backend knows them
+    fn shard_info(&self) -> Option<ShardInfo> {
+        None
+    }
+
+    /// LoRA adapters loaded in the shards, with the memory of their weights
+    fn loaded_adapters(&self) -> Vec<LoraAdapterInfo> {
+        Vec::new()
+    }
+
+    /// Load a LoRA adapter in the shards, which download its weights, for the requests with
+    /// its `adapter_id`
+    async fn load_adapter(
+        &self,
+        _request: LoadAdapterRequest,
+    ) -> Result<LoraAdapterInfo, InferError> {
+        Err(InferError::AdapterLoadingUnsupported)
+    }
+
+    /// Unload a LoRA adapter that no running request uses
+    async fn unload_adapter(&self, _id: &str) -> Result<(), InferError> {
+        Err(InferError::AdapterLoadingUnsupported)
+    }
+
+    /// Warm up new shard-sets of a model and switch its requests over to them once `check`
+    /// accepted each of them. The old shard-sets are stopped in the background once their
+    /// running requests are done.
+    async fn swap_model(
+        &self,
+        _request: SwapModelRequest,
+        _check: ShardCheck,
+    ) -> Result<ModelSwap, InferError> {
+        Err(InferError::SwapUnsupported)
+    }
+
+    /// Stop the backend once the server stopped. No batch is started anymore, the running
+    /// ones are stopped and the KV cache of the shards is freed before it returns.
+    async fn shutdown(&self) {}
+}
+
+/// Model served next to the default one, whose requests are validated with its own tokenizer
+/// and limits and whose chats are rendered with its own template
+pub(crate) struct ServedModel {
+    validation: Validation,
+    chat_template: Option<ChatTemplate>,
+}
+
+impl ServedModel {
+    pub(crate) fn new(
+        validation: Validation,
+        tokenizer_config: HubTokenizerConfig,
+        processor_config: HubProcessorConfig,
+    ) -> Self {
+        Self {
+            validation,
+            chat_template: chat_template(tokenizer_config, processor_config),
+        }
+    }
 }
 
 /// Inference struct
 #[derive(Clone)]
 pub struct Infer {
-    /// Validation
-    validation: Validation,
+    /// Validation, replaced when the model is swapped for another revision
+    validation: Arc<RwLock<Validation>>,
+    /// The other served models by name, replaced when they are swapped
+    served_models: Arc<RwLock<HashMap<String, ServedModel>>>,
     /// Request backend
     backend: Arc<dyn Backend + Send + Sync>,
-    /// Chat template
-    chat_template: Option<ChatTemplate>,
+    /// Chat template of the revision of the model
+    chat_template: Arc<RwLock<Option<ChatTemplate>>>,
+    /// Revision of the model on the Hub
+    model_sha: Arc<RwLock<Option<String>>>,
+    /// Last switch over of a model to new shard-sets
+    swaps: Arc<SwapTracker>,
     /// Inference limit
     limit_concurrent_requests: Arc<Semaphore>,
     /// Backend health
     backend_health: Arc<AtomicBool>,
+    /// Maximum number of concurrent requests
+    max_concurrent_requests: usize,
+    /// Whether new requests are refused while in-flight generations finish
+    draining: Arc<watch::Sender<bool>>,
+    /// Number of WebSocket connections still open, served outside of the HTTP connections
+    upgraded_connections: Arc<watch::Sender<usize>>,
+    /// Queue admission control
+    backpressure: Arc<Backpressure>,
+    /// Held while the runtime config is changed, so that concurrent changes are not mixed
+    runtime_config_lock: Arc<Mutex<()>>,
+    /// Outcome of the recent generations and health probes
+    inference_health: Arc<InferenceHealth>,
+    /// Journal of the queued requests
+    journal: Option<Arc<Journal>>,
+    /// Responses of the deterministic requests
+    response_cache: Option<Arc<ResponseCache>>,
+    /// Records of the generations
+    audit_log: Option<Arc<AuditLog>>,
+    /// Keys of the watermarked generations
+    watermark_keys: Option<Arc<WatermarkKeys>>,
+    /// Output filters ending the generations they flag
+    content_filter: Option<Arc<ContentFilter>>,
+    /// Transformations of the prompts and generated texts of the models
+    transforms: Option<Arc<Transforms>>,
+    /// Model of the requests without an `adapter_id`
+    default_model: Arc<str>,
+    /// Interval of the keep-alive comments and of the queue positions of the streams
+    stream_heartbeat_interval: Duration,
+    /// Generations that their clients can abort
+    aborts: Arc<Aborts>,
+    /// Finished generations that later requests can continue
+    continuations: Arc<Continuations>,
 }
 
 impl Infer {
@@ -63,20 +333,23 @@ impl Infer {
         backend: impl Backend + Send + Sync + 'static,
         validation: Validation,
         max_concurrent_requests: usize,
+        max_queue_size: Option<usize>,
+        max_queue_wait: Option<Duration>,
+        health_check_interval: Option<Duration>,
+        stream_heartbeat_interval: Option<Duration>,
+        journal: Option<Arc<Journal>>,
+        response_cache: Option<Arc<ResponseCache>>,
+        audit_log: Option<Arc<AuditLog>>,
+        watermark_keys: Option<Arc<WatermarkKeys>>,
+        content_filter: Option<Arc<ContentFilter>>,
+        transforms: Option<Arc<Transforms>>,
+        default_model: String,
+        served_models: HashMap<String, ServedModel>,
+        model_sha: Option<String>,
         tokenizer_config: HubTokenizerConfig,
         processor_config: HubProcessorConfig,
     ) -> Self {
-        let chat_template = tokenizer_config
-            .chat_template
-            .or(processor_config.chat_template)
-            .and_then(|t| match t {
-                ChatTemplateVersions::Single(template) => Some(template),
-                ChatTemplateVersions::Multiple(templates) => templates
-                    .into_iter()
-                    .find(|t| t.name == "default")
-                    .map(|t| t.template),
-            })
-            .map(|t| ChatTemplate::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token));
+        let chat_template = chat_template(tokenizer_config, processor_config);
 
         // Inference limit with a semaphore
         let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
@@ -84,17 +357,43 @@ impl Infer {
         // Backend health
         let backend_health = Arc::new(AtomicBool::new(backend.start_health()));
 
-        Self {
-            validation,
+        let infer = Self {
+            validation: Arc::new(RwLock::new(validation)),
+            served_models: Arc::new(RwLock::new(served_models)),
             backend: Arc::new(backend),
-            chat_template,
+            chat_template: Arc::new(RwLock::new(chat_template)),
+            model_sha: Arc::new(RwLock::new(model_sha)),
+            swaps: Arc::new(SwapTracker::default()),
             limit_concurrent_requests: semaphore,
             backend_health,
+            max_concurrent_requests,
+            draining: Arc::new(watch::Sender::new(false)),
+            upgraded_connections: Arc::new(watch::Sender::new(0)),
+            backpressure: Arc::new(Backpressure::new(max_queue_size, max_queue_wait)),
+            runtime_config_lock: Arc::new(Mutex::new(())),
+            inference_health: Arc::new(InferenceHealth::new(health_check_interval.is_some())),
+            journal,
+            response_cache,
+            audit_log,
+            watermark_keys,
+            content_filter,
+            transforms,
+            default_model: default_model.into(),
+            stream_heartbeat_interval: stream_heartbeat_interval
+                .unwrap_or(DEFAULT_STREAM_HEARTBEAT_INTERVAL),
+            aborts: Arc::new(Aborts::default()),
+            continuations: Arc::new(Continuations::default()),
+        };
+
+        if let Some(interval) = health_check_interval {
+            tokio::spawn(health::probe_task(infer.clone(), interval));
         }
+
+        infer
     }
 
     /// Add a new request to the queue and return a stream of InferStreamResponse
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(metadata = ?request.parameters.metadata))]
     pub(crate) async fn generate_stream<'a>(
         &'a self,
         request: GenerateRequest,
@@ -106,6 +405,108 @@ impl Infer {
         ),
         InferError,
     > {
+        self.generate_stream_journaled(request, None).await
+    }
+
+    /// Same as `generate_stream`, for a request that is already in the journal with id
+    /// `journal_id`
+    pub(crate) async fn generate_stream_journaled<'a>(
+        &'a self,
+        mut request: GenerateRequest,
+        journal_id: Option<u64>,
+    ) -> Result<
+        (
+            OwnedSemaphorePermit,
+            u32, // input_length
+            impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a,
+        ),
+        InferError,
+    > {
+        let owner = Owner::current();
+        let continued = self.continuations.resolve(&mut request, &owner);
+        let mut audit = self.audit_log.as_ref().and_then(|audit_log| {
+            audit_log.entry(
+                &request,
+                tenant::current().as_deref(),
+                request_id::current(),
+            )
+        });
+        if let Err(err) = continued {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            tracing::error!("{err}");
+            let err = InferError::from(err);
+            if let Some(audit) = audit {
+                audit.fail(&err);
+            }
+            return Err(err);
+        }
+        // Recorded once generated, for the requests continuing this one
+        let mut continuation = request_id::current().map(|id| (id, owner, request.clone()));
+        let (permit, input_length, response_stream) =
+            match self.schedule_stream(request, journal_id).await {
+                Ok(scheduled) => scheduled,
+                Err(err) => {
+                    if let Some(audit) = audit {
+                        audit.fail(&err);
+                    }
+                    return Err(err);
+                }
+            };
+        if let Some(audit) = audit.as_mut() {
+            audit.set_input_length(input_length);
+        }
+
+        // Dropping the entry before the end of the stream records a cancelled generation
+        let final_stream = stream! {
+            let mut response_stream = std::pin::pin!(response_stream);
+            while let Some(response) = response_stream.next().await {
+                match &response {
+                    Ok(InferStreamResponse::End { generated_text, start, queued, .. }) => {
+                        if let Some((id, owner, request)) = continuation.take() {
+                            self.continuations.insert(id, owner, request, &generated_text.text);
+                        }
+                        if let Some(audit) = audit.take() {
+                            audit.succeed(generated_text, *queued, *start);
+                        }
+                    }
+                    Err(err) => {
+                        if let Some(audit) = audit.take() {
+                            audit.fail(err);
+                        }
+                    }
+                    _ => {}
+                }
+                yield response;
+            }
+        };
+        Ok((permit, input_length, final_stream))
+    }
+
+    async fn schedule_stream<'a>(
+        &'a self,
+        mut request: GenerateRequest,
+        journal_id: Option<u64>,
+    ) -> Result<
+        (
+            OwnedSemaphorePermit,
+            u32, // input_length
+            impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a,
+        ),
+        InferError,
+    > {
+        // Refuse new requests once draining started
+        if self.is_draining() {
+            metrics::counter!("tgi_request_failure", "err" => "draining").increment(1);
+            return Err(InferError::Draining);
+        }
+        self.check_model(self.model(&request))?;
+
+        // Refuse new requests when the queue is too long
+        self.backpressure.admit().inspect_err(|err| {
+            metrics::counter!("tgi_request_failure", "err" => "queue_full").increment(1);
+            tracing::error!("{err}");
+        })?;
+
         // Limit concurrent requests by acquiring a permit from the semaphore
         let permit = self
             .clone()
@@ -117,19 +518,152 @@ impl Infer {
                 err
             })?;
 
+        // The time budget includes the validation and the time spent in the queue
+        let deadline = request
+            .parameters
+            .timeout_ms
+            .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+        let queue_deadline = request
+            .parameters
+            .deadline_ms
+            .map(|deadline_ms| Instant::now() + Duration::from_millis(deadline_ms));
+
+        if request.parameters.token_healing && !self.backend.supports_token_healing() {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::TokenHealingUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        if !request.parameters.stop_token_ids.is_empty() && !self.backend.supports_stop_token_ids()
+        {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::StopTokenIdsUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        if request.parameters.min_new_tokens.is_some() && !self.backend.supports_min_new_tokens() {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::MinNewTokensUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        if request.parameters.deadline_ms.is_some() && !self.backend.supports_deadlines() {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::DeadlineMsUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        let biased = request
+            .parameters
+            .logit_bias
+            .as_ref()
+            .is_some_and(|bias| !bias.is_empty());
+        if biased && !self.backend.supports_logit_bias() {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::LogitBiasUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        let presence_penalized = request
+            .parameters
+            .presence_penalty
+            .is_some_and(|penalty| penalty != 0.0);
+        if presence_penalized && !self.backend.supports_presence_penalty() {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::PresencePenaltyUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        let min_p_filtered = request.parameters.min_p.is_some_and(|min_p| min_p != 0.0);
+        if min_p_filtered && !self.backend.supports_min_p() {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::MinPUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        if request.parameters.no_repeat_ngram_size.is_some()
+            && !self.backend.supports_no_repeat_ngram_size()
+        {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::NoRepeatNgramSizeUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        if request.parameters.guidance_scale.is_some() && !self.backend.supports_guidance() {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let err = ValidationError::GuidanceUnsupported;
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
+        if let Some(adapters) = &request.parameters.adapters {
+            if let Err(err) = check_adapters(adapters, self.backend.lora_adapters()) {
+                metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                tracing::error!("{err}");
+                return Err(err.into());
+            }
+        }
+        let watermark_key = match &self.watermark_keys {
+            Some(watermark_keys) if request.parameters.watermark => {
+                if !self.backend.supports_watermark_key() {
+                    metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                    let err = ValidationError::WatermarkKeyUnsupported;
+                    tracing::error!("{err}");
+                    return Err(err.into());
+                }
+                // Chosen once, the continuations of the request keep the same key
+                watermark_keys.active_key()
+            }
+            _ => 0,
+        };
+
+        let transforms = self
+            .transforms
+            .as_ref()
+            .and_then(|transforms| transforms.for_model(self.model(&request)));
+        // The replayed requests were journaled with their transformed prompt
+        if let (Some(transforms), None) = (&transforms, journal_id) {
+            request.inputs = transforms.prompt(request.inputs);
+        }
+
+        // The continuations of the request do not wait for the other sequences of its prompt
+        let prompt_group = request.parameters.prompt_group.take();
+
         // Validate request
         let mut local_request = request.clone();
-        let valid_request = self.validation.validate(request).await.map_err(|err| {
+        let validation = self.validation_of(self.model(&request));
+        let mut valid_request = validation.validate(request).await.map_err(|err| {
             metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
             err
         })?;
+        valid_request.tenant = tenant::current();
+        valid_request.request_id = request_id::current();
+        valid_request.deadline = queue_deadline;
+        valid_request.prompt_group = prompt_group;
+        valid_request.parameters.watermark_key = watermark_key;
 
         let seed = valid_request.parameters.seed;
         local_request.parameters.seed = Some(seed);
         let input_length = valid_request.input_length;
         let max_total_new_tokens = valid_request.stopping_parameters.max_total_new_tokens;
+        let min_new_tokens = valid_request.stopping_parameters.min_new_tokens;
+        // Removed from the prompt by token healing, and regenerated by the first token
+        let healed_prefix = valid_request.healed_prefix.clone();
+        // The stream is polled outside of the task of the request
+        let tenant = valid_request.tenant.clone();
+        let queued_request_id = valid_request.request_id.clone();
+        let mut queued_request = self
+            .backpressure
+            .enqueue(valid_request.stopping_parameters.max_new_tokens);
+        let rate_limited_client = rate_limit::current_client();
+        let api_key = auth::current_key();
+        // Kept in the journal until the first token, to be replayed if the router stops before
+        let mut journal_entry = self.journal.as_ref().map(|journal| match journal_id {
+            Some(id) => journal.entry(id),
+            None => journal.enqueue(&local_request),
+        });
         let mut generation_stream = self.backend.schedule(valid_request)?;
+        let mut abort = request_id::current().map(|id| self.aborts.register(id, Owner::current()));
 
         // Wrap generation stream to update the backend health if the stream contains an error
         let final_stream = stream! {
@@ -137,34 +671,181 @@ impl Infer {
             let mut first_start = None;
             let mut first_queued = None;
             let mut all_generated_text: Option<GeneratedText> = None;
+            // Only needed to end the generation early, on timeout or abort
+            let mut streamed_text = String::new();
+            let mut prefill_start = None;
+            let mut started = false;
 
-            while let Some(response) = generation_stream.next().await {
+            loop {
+                let response = if total_generated_tokens == 0 {
+                    let heartbeat = Instant::now() + self.stream_heartbeat_interval;
+                    let timeout = deadline.map_or(heartbeat, |deadline| deadline.min(heartbeat));
+                    let response = tokio::select! {
+                        response = tokio::time::timeout_at(timeout, generation_stream.next()) => Some(response),
+                        _ = abort::aborted(&mut abort) => None,
+                    };
+                    // Dropping the generation stream releases the queue entry of the request
+                    let Some(response) = response else {
+                        metrics::counter!("tgi_request_failure", "err" => "cancelled").increment(1);
+                        yield Err(InferError::Cancelled);
+                        break;
+                    };
+                    match response {
+                        Ok(response) => response,
+                        // Without any token, there is nothing to end the generation with
+                        Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                            metrics::counter!("tgi_request_failure", "err" => "timeout").increment(1);
+                            yield Err(InferError::GenerationTimeout);
+                            break;
+                        }
+                        Err(_) => {
+                            yield Ok(InferStreamResponse::Queued { position: queued_request.position() });
+                            continue;
+                        }
+                    }
+                } else {
+                    generation_stream.next().await
+                };
+                let Some(response) = response else {
+                    break;
+                };
                 let response = response.inspect_err(|_err| {
                     self.backend_health.store(false, Ordering::SeqCst);
                 })?;
 
                 match response {
-                    InferStreamResponse::Prefill(_) => yield Ok(response),
-                    InferStreamResponse::Intermediate { .. } => {
-                        total_generated_tokens += 1;
+                    InferStreamResponse::Queued { .. } => yield Ok(response),
+                    // Also sent by the later rounds of the request
+                    InferStreamResponse::Started => {
+                        if !started {
+                            started = true;
+                            yield Ok(response);
+                        }
+                    }
+                    InferStreamResponse::Prefill(_) => {
+                        prefill_start = Some(Instant::now());
                         yield Ok(response);
                     }
-                    InferStreamResponse::End { token, top_tokens,generated_text, start, queued  } => {
+                    InferStreamResponse::Intermediate { mut token, top_tokens } => {
+                        total_generated_tokens += 1;
+                        if let (1, Some(prefix)) = (total_generated_tokens, &healed_prefix) {
+                            strip_healed_prefix(&mut token.text, prefix);
+                        }
+                        queued_request.start();
+                        if let Some(entry) = journal_entry.as_mut() {
+                            entry.dequeue();
+                        }
+                        self.backpressure.record_token();
+                        if let Some(client) = &rate_limited_client {
+                            client.record_tokens(1);
+                        }
+                        if let Some(key) = &api_key {
+                            // The prompt is counted with the first token, once it was prefilled
+                            let prompt_tokens = if total_generated_tokens == 1 { input_length } else { 0 };
+                            key.record_tokens(prompt_tokens, 1);
+                        }
+                        if (deadline.is_some() || abort.is_some()) && !token.special {
+                            streamed_text.push_str(&token.text);
+                        }
+
+                        let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                        let aborted = abort.as_ref().is_some_and(|abort| abort.is_aborted());
+                        if timed_out || aborted {
+                            // Dropping the generation stream cancels the request in the backend
+                            let finish_reason = if timed_out {
+                                metrics::counter!("tgi_request_timeout").increment(1);
+                                FinishReason::Timeout
+                            } else {
+                                metrics::counter!("tgi_request_cancelled").increment(1);
+                                FinishReason::Cancelled
+                            };
+                            let start = first_start.or(prefill_start).unwrap_or_else(Instant::now);
+                            yield Ok(InferStreamResponse::End {
+                                token,
+                                top_tokens,
+                                generated_text: GeneratedText {
+                                    text: streamed_text,
+                                    generated_tokens: total_generated_tokens,
+                                    finish_reason,
+                                    seed: Some(seed),
+                                    // The batches of the cancelled round are not reported
+                                    batches: all_generated_text
+                                        .as_ref()
+                                        .map(|generated_text| generated_text.batches)
+                                        .unwrap_or_default(),
+                                },
+                                start,
+                                queued: first_queued.unwrap_or(start),
+                            });
+                            break;
+                        }
+                        yield Ok(InferStreamResponse::Intermediate { token, top_tokens });
+                    }
+                    InferStreamResponse::End { mut token, top_tokens, mut generated_text, start, queued  } => {
+                        self.inference_health.record_success();
                         total_generated_tokens += 1;
+                        if let Some(prefix) = &healed_prefix {
+                            if total_generated_tokens == 1 {
+                                strip_healed_prefix(&mut token.text, prefix);
+                            }
+                            // Only the first round starts with the healed token
+                            if all_generated_text.is_none() {
+                                strip_healed_prefix(&mut generated_text.text, prefix);
+                            }
+                        }
+                        queued_request.start();
+                        if let Some(entry) = journal_entry.as_mut() {
+                            entry.dequeue();
+                        }
+                        self.backpressure.record_token();
+                        if let Some(client) = &rate_limited_client {
+                            client.record_tokens(1);
+                        }
+                        if let Some(key) = &api_key {
+                            let prompt_tokens = if total_generated_tokens == 1 { input_length } else { 0 };
+                            key.record_tokens(prompt_tokens, 1);
+                        }
                         first_start = first_start.or(Some(start));
                         first_queued = first_queued.or(Some(queued));
+                        if (deadline.is_some() || abort.is_some()) && !token.special {
+                            streamed_text.push_str(&token.text);
+                        }
                         if let Some(v) = all_generated_text.as_mut() {
                                 v.text.push_str(&generated_text.text);
                                 v.generated_tokens = total_generated_tokens;
                                 v.finish_reason = generated_text.finish_reason.clone();
+                                v.batches.merge(&generated_text.batches);
                         };
 
                         if matches!(generated_text.finish_reason, FinishReason::Length) && total_generated_tokens < max_total_new_tokens {
                             local_request.inputs.push_str(&generated_text.text);
+                            // The prompt now ends with generated tokens
+                            local_request.parameters.token_healing = false;
+                            // The negative sequence generated the same tokens
+                            if local_request.parameters.guidance_scale.is_some() {
+                                local_request
+                                    .parameters
+                                    .negative_prompt
+                                    .get_or_insert_with(String::new)
+                                    .push_str(&generated_text.text);
+                            }
+                            if let Some(max_new_tokens) = local_request.parameters.max_new_tokens.as_mut() {
+                                // The backend may end a round before `max_new_tokens`
+                                *max_new_tokens = max_total_new_tokens - total_generated_tokens;
+                            }
+                            if local_request.parameters.min_new_tokens.is_some() {
+                                local_request.parameters.min_new_tokens = Some(min_new_tokens.saturating_sub(total_generated_tokens));
+                            }
                             all_generated_text = all_generated_text.or(Some(generated_text));
 
-                            let valid_request = match self.validation.validate(local_request.clone()).await {
-                                Ok(valid_request) => valid_request,
+                            let valid_request = match self.validation_of(self.model(&local_request)).validate(local_request.clone()).await {
+                                Ok(mut valid_request) => {
+                                    valid_request.generated_tokens = total_generated_tokens;
+                                    valid_request.tenant = tenant.clone();
+                                    valid_request.request_id = queued_request_id.clone();
+                                    valid_request.parameters.watermark_key = watermark_key;
+                                    valid_request
+                                }
                                 Err(err) => {
                                     tracing::debug!("Failed to continue request: {err}");
                                     yield Ok(InferStreamResponse::End {token, top_tokens, generated_text: all_generated_text.unwrap(), start: first_start.unwrap(), queued: first_queued.unwrap() });
@@ -193,6 +874,10 @@ impl Infer {
                 }
             }
         };
+        // The content filters check the texts that the clients receive
+        let final_stream = transform::rewrite(transforms, final_stream);
+        let final_stream =
+            content_filter::moderate(self.content_filter.clone(), seed, final_stream);
 
         Ok((permit, input_length, final_stream))
     }
@@ -204,11 +889,14 @@ impl Infer {
         request: GenerateRequest,
     ) -> Result<tokenizers::Encoding, InferError> {
         // Tokenize request
+        let validation = self.validation_of(self.model(&request));
         let inputs = request.inputs;
         let add_special_tokens = request.add_special_tokens;
-        let truncate = request.parameters.truncate;
-        let encoding = self
-            .validation
+        let truncate = request.parameters.truncate.map(|truncate| {
+            let side = request.parameters.truncation_side.unwrap_or_default();
+            (truncate, side)
+        });
+        let encoding = validation
             .tokenize(inputs, add_special_tokens, truncate)
             .await
             .map_err(|err| {
@@ -220,22 +908,133 @@ impl Infer {
         Ok(encoding.0)
     }
 
-    /// Apply the chat template to the chat request
+    /// Token ids of a text, without the special tokens
+    #[instrument(skip_all)]
+    pub(crate) async fn encode(&self, text: String) -> Result<Vec<u32>, InferError> {
+        let (encoding, _) = self
+            .validation()
+            .tokenize(text, false, None)
+            .await
+            .map_err(|err| {
+                tracing::error!("Tokenization {err}");
+                err
+            })?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    /// Decode token ids
+    #[instrument(skip_all)]
+    pub(crate) async fn detokenize(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<String, InferError> {
+        let text = self
+            .validation()
+            .detokenize(ids, skip_special_tokens)
+            .await
+            .map_err(|err| {
+                tracing::error!("Detokenization {err}");
+                err
+            })?;
+        Ok(text)
+    }
+
+    /// Embed the inputs with `model`, or the default model, and return the embeddings with the
+    /// total number of input tokens
+    #[instrument(skip_all)]
+    pub(crate) async fn embed(
+        &self,
+        model: Option<String>,
+        inputs: Vec<String>,
+    ) -> Result<(Vec<Vec<f32>>, usize), InferError> {
+        if self.is_draining() {
+            metrics::counter!("tgi_request_failure", "err" => "draining").increment(1);
+            return Err(InferError::Draining);
+        }
+        self.check_model(model.as_deref().unwrap_or(&self.default_model))?;
+
+        // Embeddings share the concurrency limit of the generation requests
+        let _permit = self
+            .clone()
+            .limit_concurrent_requests
+            .try_acquire_owned()
+            .map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "overloaded").increment(1);
+                tracing::error!("{err}");
+                err
+            })?;
+
+        let validation = self.validation_of(model.as_deref().unwrap_or(&self.default_model));
+        let input_ids = try_join_all(
+            inputs
+                .into_iter()
+                .map(|input| validation.validate_embedding_input(input)),
+        )
+        .await
+        .map_err(|err| {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            tracing::error!("{err}");
+            err
+        })?;
+        let input_tokens = input_ids.iter().map(Vec::len).sum();
+
+        let embeddings = self
+            .backend
+            .embed(model.as_deref(), input_ids)
+            .await
+            .inspect_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => err.error_type().to_string())
+                    .increment(1);
+                tracing::error!("{err}");
+            })?;
+        Ok((embeddings, input_tokens))
+    }
+
+    /// Apply the chat template of `model`, or of the default model, to the chat request
     #[instrument(skip_all)]
     pub(crate) fn apply_chat_template(
         &self,
+        model: Option<&str>,
         messages: Vec<Message>,
         tools_and_prompt: Option<(Vec<Tool>, String)>,
     ) -> Result<String, InferError> {
-        self.chat_template
-            .as_ref()
-            .ok_or_else(|| InferError::TemplateError(ErrorKind::TemplateNotFound.into()))?
-            .apply(messages, tools_and_prompt)
-            .map_err(|e| {
-                metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
-                tracing::error!("{e}");
-                e
-            })
+        let apply = |chat_template: Option<&ChatTemplate>| {
+            chat_template
+                .ok_or_else(|| InferError::TemplateError(ErrorKind::TemplateNotFound.into()))?
+                .apply(messages, tools_and_prompt)
+                .map_err(|e| {
+                    metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
+                    tracing::error!("{e}");
+                    e
+                })
+        };
+        let served_models = self.served_models.read().unwrap();
+        match model.and_then(|model| served_models.get(model)) {
+            Some(served_model) => apply(served_model.chat_template.as_ref()),
+            None => apply(self.chat_template.read().unwrap().as_ref()),
+        }
+    }
+
+    /// Model of a request, its adapter or the base model
+    fn model<'r>(&'r self, request: &'r GenerateRequest) -> &'r str {
+        request
+            .parameters
+            .adapter_id
+            .as_deref()
+            .unwrap_or(&self.default_model)
+    }
+
+    /// Refuse the requests for a model that the API key of the client cannot use
+    fn check_model(&self, model: &str) -> Result<(), InferError> {
+        let Some(key) = auth::current_key() else {
+            return Ok(());
+        };
+        if key.allows_model(model) {
+            return Ok(());
+        }
+        metrics::counter!("tgi_request_failure", "err" => "forbidden").increment(1);
+        Err(InferError::ModelNotAllowed(model.to_string()))
     }
 
     /// Add a new request to the queue and return a InferResponse
@@ -245,76 +1044,86 @@ impl Infer {
         request: GenerateRequest,
     ) -> Result<InferResponse, InferError> {
         let use_top_tokens = request.parameters.top_n_tokens.is_some_and(|x| x > 0);
-
-        // Create stream and keep semaphore permit as long as generate lives
-        let (_permit, _input_length, stream) = self.generate_stream(request).await?;
-
-        // Return values
-        let mut result_prefill = Vec::new();
-        let mut result_tokens = Vec::new();
-        let mut result_top_tokens = Vec::new();
-        let mut result_generated_text = None;
-        let mut result_start = None;
-        let mut result_queued = None;
-
-        let mut stream = Box::pin(stream);
-
-        // Iterate on stream
-        while let Some(response) = stream.next().await {
-            match response? {
-                // Add prefill tokens
-                InferStreamResponse::Prefill(prefill_tokens) => {
-                    result_prefill = prefill_tokens;
+        let cache_key = self.response_cache.as_ref().and_then(|cache| {
+            let default_temperature = self.validation().limits().default_temperature;
+            let tenant = tenant::current();
+            cache.key(
+                &request,
+                self.model(&request),
+                default_temperature,
+                tenant.as_deref(),
+            )
+        });
+        if let (Some(cache), Some(key)) = (&self.response_cache, &cache_key) {
+            // A hit is only served to the requests that would be generated
+            self.check_cached(&request).await?;
+            if let Some(response) = cache.get(key).await {
+                // Counted against the limits of the client as if it was generated
+                let generated_tokens = response.generated_text.generated_tokens;
+                if let Some(client) = rate_limit::current_client() {
+                    client.record_tokens(generated_tokens);
                 }
-                // Push last token
-                InferStreamResponse::Intermediate { token, top_tokens } => {
-                    result_tokens.push(token);
-                    result_top_tokens.push(top_tokens);
+                if let Some(key) = auth::current_key() {
+                    key.record_tokens(response._input_length, generated_tokens);
                 }
-                // Final message
-                // Set return values
-                InferStreamResponse::End {
-                    token,
-                    generated_text,
-                    start,
-                    queued,
-                    top_tokens,
-                } => {
-                    result_tokens.push(token);
-                    result_top_tokens.push(top_tokens);
-                    result_generated_text = Some(generated_text);
-                    result_start = Some(start);
-                    result_queued = Some(queued)
+                let audit = self.audit_log.as_ref().and_then(|audit_log| {
+                    audit_log.entry(
+                        &request,
+                        tenant::current().as_deref(),
+                        request_id::current(),
+                    )
+                });
+                if let Some(mut audit) = audit {
+                    audit.set_input_length(response._input_length);
+                    audit.set_cached();
+                    audit.succeed(&response.generated_text, response.queued, response.start);
                 }
+                return Ok(response);
             }
         }
 
-        // Check that we received a `InferStreamResponse::End` message
-        if let (Some(generated_text), Some(queued), Some(start)) =
-            (result_generated_text, result_queued, result_start)
-        {
-            Ok(InferResponse {
-                prefill: result_prefill,
-                _input_length,
-                tokens: result_tokens,
-                generated_text,
-                queued,
-                start,
-                top_tokens: if use_top_tokens {
-                    result_top_tokens
-                } else {
-                    Vec::new()
-                },
-            })
-        } else {
-            let err = InferError::IncompleteGeneration;
-            metrics::counter!("tgi_request_failure", "err" => "incomplete").increment(1);
+        // Create stream and keep semaphore permit as long as generate lives
+        let (permit, input_length, stream) = self.generate_stream(request).await?;
+        let response = collect_response(stream, input_length, use_top_tokens).await?;
+        drop(permit);
+
+        if let (Some(cache), Some(key)) = (&self.response_cache, cache_key) {
+            cache.put(key, &response).await;
+        }
+        Ok(response)
+    }
+
+    /// Refuse the requests that `schedule_stream` would refuse before their response is served
+    /// from the cache, which is shared by all the keys
+    async fn check_cached(&self, request: &GenerateRequest) -> Result<(), InferError> {
+        if self.is_draining() {
+            metrics::counter!("tgi_request_failure", "err" => "draining").increment(1);
+            return Err(InferError::Draining);
+        }
+        self.check_model(self.model(request))?;
+        self.backpressure.admit().inspect_err(|err| {
+            metrics::counter!("tgi_request_failure", "err" => "queue_full").increment(1);
             tracing::error!("{err}");
-            Err(err)
+        })?;
+        let mut request = request.clone();
+        let transforms = self
+            .transforms
+            .as_ref()
+            .and_then(|transforms| transforms.for_model(self.model(&request)));
+        if let Some(transforms) = transforms {
+            request.inputs = transforms.prompt(request.inputs);
         }
+        let validation = self.validation_of(self.model(&request));
+        validation.validate(request).await.map_err(|err| {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            tracing::error!("{err}");
+            err
+        })?;
+        Ok(())
     }
+
     /// Add best_of new requests to the queue and return a InferResponse of the sequence with
-    /// the highest log probability per token
+    /// the highest cumulative log probability
     #[instrument(skip(self, request))]
     pub(crate) async fn generate_best_of(
         &self,
@@ -322,37 +1131,77 @@ impl Infer {
         best_of: usize,
     ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
         // validate  best_of parameter separately
-        let best_of = self.validation.validate_best_of(best_of)?;
+        let best_of = self.validation().validate_best_of(best_of)?;
 
         // create multiple generate requests
-        let mut infer_responses: Vec<InferResponse> =
-            try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await?;
+        let requests = self.share_prompt(vec![request; best_of]);
+        let mut infer_responses =
+            try_join_all(requests.into_iter().map(|request| self.generate(request))).await?;
 
-        // get the sequence with the highest log probability per token
-        let mut max_index = 0;
-        let mut max_logprob: f32 = f32::MIN;
-
-        for (i, response) in infer_responses.iter().enumerate() {
-            // mean logprobs of the generated tokens
-            let sequence_logprob = response
-                .tokens
+        let max_index = most_likely(
+            infer_responses
                 .iter()
-                .map(|token| token.logprob)
-                .sum::<f32>()
-                / response.tokens.len() as f32;
-
-            // set best sequence
-            if sequence_logprob > max_logprob {
-                max_index = i;
-                max_logprob = sequence_logprob;
-            }
-        }
+                .map(|response| response.tokens.as_slice()),
+        );
         let best_response = infer_responses.remove(max_index);
         Ok((best_response, infer_responses))
     }
 
+    /// Add the `n` requests of the prompt to the queue and return their responses, in order
+    #[instrument(skip(self, request))]
+    pub(crate) async fn generate_n(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<Vec<InferResponse>, InferError> {
+        let requests = self.choice_requests(request)?;
+        try_join_all(requests.into_iter().map(|request| self.generate(request))).await
+    }
+
+    /// Requests generating the `n` choices of an OpenAI request, one per choice. With a `seed`,
+    /// choice `i` samples with `seed + i` so that the choices differ.
+    pub(crate) fn choice_requests(
+        &self,
+        mut request: GenerateRequest,
+    ) -> Result<Vec<GenerateRequest>, InferError> {
+        let Some(n) = request.parameters.n.take() else {
+            return Ok(vec![request]);
+        };
+        let n = self.validation().validate_n(n as usize)?;
+        if request
+            .parameters
+            .best_of
+            .is_some_and(|best_of| best_of > 1)
+        {
+            return Err(ValidationError::NBestOf.into());
+        }
+        let seed = request.parameters.seed;
+        let requests = (0..n)
+            .map(|i| {
+                request.parameters.seed = seed.map(|seed| seed.wrapping_add(i as u64));
+                request.clone()
+            })
+            .collect();
+        Ok(self.share_prompt(requests))
+    }
+
+    /// Let the requests of one prompt share its KV: the backend prefills it for the first one,
+    /// and forks the others from its blocks in the same batch
+    fn share_prompt(&self, mut requests: Vec<GenerateRequest>) -> Vec<GenerateRequest> {
+        if requests.len() > 1 && self.backend.shares_prompt_kv() {
+            let groups = PromptGroup::new(requests.len());
+            for (request, group) in requests.iter_mut().zip(groups) {
+                request.parameters.prompt_group = Some(group);
+            }
+        }
+        requests
+    }
+
     #[instrument(skip(self))]
     pub(crate) async fn health(&self) -> bool {
+        // The background probes already went through the whole stack
+        if let Some(healthy) = self.inference_health.probe_result() {
+            return healthy;
+        }
         let health = self
             .backend
             .health(self.backend_health.load(Ordering::SeqCst))
@@ -360,6 +1209,471 @@ impl Infer {
         self.backend_health.store(health, Ordering::SeqCst);
         health
     }
+
+    /// Unix timestamp in seconds of the last generation that reached its end
+    pub(crate) fn last_successful_inference(&self) -> Option<u64> {
+        self.inference_health.last_success()
+    }
+
+    /// Most recent batches formed by the backend
+    pub(crate) async fn batch_history(&self) -> Vec<BatchRecord> {
+        self.backend.batch_history().await
+    }
+
+    /// Phase timings of the most recent decode iterations of the backend
+    pub(crate) fn iteration_timings(&self) -> Vec<IterationTimings> {
+        self.backend.iteration_timings()
+    }
+
+    /// Sessions whose KV cache is kept by the backend
+    pub(crate) async fn session_stats(&self) -> Vec<SessionStats> {
+        self.backend.session_stats().await
+    }
+
+    /// KV cache usage of the block allocators of the backend
+    pub(crate) async fn cache_stats(&self) -> Vec<CacheStats> {
+        self.backend.cache_stats().await
+    }
+
+    /// Requests waiting in the queues of the backend
+    pub(crate) async fn queued_requests(&self) -> Vec<QueuedRequest> {
+        self.backend.queued_requests().await
+    }
+
+    /// Warning of a request whose `max_new_tokens` is lowered to the ceiling of its model
+    pub(crate) fn max_new_tokens_warning(&self, parameters: &GenerateParameters) -> Option<String> {
+        self.validation().max_new_tokens_warning(parameters)
+    }
+
+    /// Render the prompt template named by `request` into its inputs
+    pub(crate) fn render_prompt_template(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<Option<PromptTemplateVersion>, InferError> {
+        self.validation()
+            .render_prompt_template(request)
+            .map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                tracing::error!("{err}");
+                err.into()
+            })
+    }
+
+    /// Keys of the watermark, if the router is configured with some
+    /// Validation of the requests, with the tokenizer of the revision of the model
+    fn validation(&self) -> Validation {
+        self.validation.read().unwrap().clone()
+    }
+
+    /// Validation of the requests of `model`, with its own tokenizer and limits if it is one of
+    /// the other served models
+    fn validation_of(&self, model: &str) -> Validation {
+        match self.served_models.read().unwrap().get(model) {
+            Some(served_model) => served_model.validation.clone(),
+            None => self.validation(),
+        }
+    }
+
+    /// Whether `model` is served next to the default model
+    pub(crate) fn serves_other_model(&self, model: &str) -> bool {
+        self.served_models.read().unwrap().contains_key(model)
+    }
+
+    pub(crate) fn watermark_keys(&self) -> Option<&WatermarkKeys> {
+        self.watermark_keys.as_deref()
+    }
+
+    /// Settings of the validation and of the backend that can be changed at runtime
+    pub(crate) fn runtime_config(&self) -> RuntimeConfig {
+        let _guard = self.runtime_config_lock.lock().unwrap();
+        let limits = self.validation().limits();
+        RuntimeConfig {
+            default_temperature: limits.default_temperature,
+            max_new_tokens: limits.max_new_tokens,
+            max_stop_sequences: limits.max_stop_sequences,
+            waiting_served_ratio: self.backend.waiting_served_ratio(),
+        }
+    }
+
+    /// Apply a new runtime config. The `waiting_served_ratio` is ignored if the backend does not
+    /// have this setting.
+    pub(crate) fn set_runtime_config(&self, config: RuntimeConfig) {
+        let _guard = self.runtime_config_lock.lock().unwrap();
+        self.validation().set_limits(ValidationLimits {
+            default_temperature: config.default_temperature,
+            max_new_tokens: config.max_new_tokens,
+            max_stop_sequences: config.max_stop_sequences,
+        });
+        if let Some(waiting_served_ratio) = config.waiting_served_ratio {
+            self.backend.set_waiting_served_ratio(waiting_served_ratio);
+        }
+    }
+
+    /// Number of seconds after which a rejected client should retry
+    pub(crate) fn retry_after(&self) -> u64 {
+        self.backpressure.retry_after()
+    }
+
+    /// Stop accepting new requests and let the in-flight generations finish
+    pub(crate) fn drain(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Resolves once the server is draining
+    pub(crate) async fn draining(&self) {
+        let _ = self
+            .draining
+            .subscribe()
+            .wait_for(|draining| *draining)
+            .await;
+    }
+
+    /// Count a connection upgraded to a WebSocket until the returned guard is dropped. The
+    /// HTTP server does not wait for these connections when it shuts down.
+    pub(crate) fn upgraded_connection(&self) -> UpgradedConnection {
+        self.upgraded_connections.send_modify(|count| *count += 1);
+        UpgradedConnection(self.upgraded_connections.clone())
+    }
+
+    pub(crate) fn upgraded_connections(&self) -> usize {
+        *self.upgraded_connections.borrow()
+    }
+
+    /// Resolves once the upgraded connections are all closed
+    pub(crate) async fn wait_upgraded_connections(&self) {
+        let _ = self
+            .upgraded_connections
+            .subscribe()
+            .wait_for(|count| *count == 0)
+            .await;
+    }
+
+    /// Abort the queued or running generations of request `request_id` of the current client.
+    /// Returns false if it has none.
+    pub(crate) fn abort(&self, request_id: &str) -> bool {
+        self.aborts.abort(request_id, &Owner::current())
+    }
+
+    pub(crate) fn loaded_adapters(&self) -> Vec<LoraAdapterInfo> {
+        self.backend.loaded_adapters()
+    }
+
+    pub(crate) async fn load_adapter(
+        &self,
+        request: LoadAdapterRequest,
+    ) -> Result<LoraAdapterInfo, InferError> {
+        self.backend.load_adapter(request).await
+    }
+
+    pub(crate) async fn unload_adapter(&self, id: &str) -> Result<(), InferError> {
+        self.backend.unload_adapter(id).await
+    }
+
+    /// Record a swap of the shards of a model, refused while the previous one is running
+    pub(crate) fn begin_swap(
+        &self,
+        request: &SwapModelRequest,
+    ) -> Result<SwapModelStatus, InferError> {
+        let model = request
+            .model
+            .clone()
+            .unwrap_or_else(|| self.default_model.to_string());
+        self.swaps.begin(model, request.revision.clone())
+    }
+
+    /// Switch a model over to the new shard-sets of a swap recorded by `begin_swap`, once
+    /// `files` are loaded for the revision they serve. The old shard-sets are drained and
+    /// stopped in the background, `swap_status` reports their progress.
+    pub(crate) async fn swap_model(
+        &self,
+        request: SwapModelRequest,
+        files: impl Future<Output = Result<Option<ModelFiles>, InferError>>,
+    ) {
+        let model = request
+            .model
+            .clone()
+            .unwrap_or_else(|| self.default_model.to_string());
+        match self.switch_model(request, files).await {
+            Ok(replicas) => {
+                tracing::info!("Switched {replicas} replica(s) of `{model}` over to new shards")
+            }
+            Err(err) => {
+                tracing::error!("{err}");
+                self.swaps.fail(err.to_string());
+            }
+        }
+    }
+
+    async fn switch_model(
+        &self,
+        request: SwapModelRequest,
+        files: impl Future<Output = Result<Option<ModelFiles>, InferError>>,
+    ) -> Result<usize, InferError> {
+        let files = files.await?;
+        let model = request
+            .model
+            .clone()
+            .filter(|model| self.serves_other_model(model));
+        let check: ShardCheck = match (&files, &model) {
+            (Some(files), _) => files.shard_check(),
+            (None, Some(model)) => swap::same_vocabulary(self.backend.model_shard_info(model)),
+            (None, None) => swap::same_vocabulary(self.backend.shard_info()),
+        };
+        let swap: ModelSwap = self.backend.swap_model(request, check).await?;
+        match model {
+            Some(model) => self.reload_served_model(
+                &model,
+                files,
+                swap.max_input_tokens,
+                swap.max_total_tokens,
+            ),
+            None => self.reload(files, swap.max_input_tokens, swap.max_total_tokens),
+        }
+        self.swaps.switched(swap.replicas, swap.drain);
+        Ok(swap.replicas)
+    }
+
+    /// Validate the requests of one of the other served models with the tokenizer of the
+    /// revision served by its new shards, within their limits, and render their chats with its
+    /// template
+    fn reload_served_model(
+        &self,
+        model: &str,
+        files: Option<ModelFiles>,
+        max_input_tokens: usize,
+        max_total_tokens: usize,
+    ) {
+        let mut served_models = self.served_models.write().unwrap();
+        if let Some(served_model) = served_models.get_mut(model) {
+            served_model.validation = match files {
+                Some(files) => {
+                    served_model.chat_template =
+                        chat_template(files.tokenizer_config, files.processor_config);
+                    served_model.validation.with_tokenizer(
+                        files.tokenizer,
+                        files.config,
+                        files.preprocessor_config,
+                        max_input_tokens,
+                        max_total_tokens,
+                    )
+                }
+                None => served_model
+                    .validation
+                    .with_token_limits(max_input_tokens, max_total_tokens),
+            };
+        }
+        // The new shards may generate other tokens, and the cached responses are keyed with the
+        // revision of the default model only
+        if let Some(cache) = &self.response_cache {
+            cache.invalidate();
+        }
+    }
+
+    /// Validate the requests with the tokenizer of the revision served by new shards, within
+    /// their limits, and render their chats with its template
+    fn reload(&self, files: Option<ModelFiles>, max_input_tokens: usize, max_total_tokens: usize) {
+        let mut validation = self.validation.write().unwrap();
+        let Some(files) = files else {
+            *validation = validation.with_token_limits(max_input_tokens, max_total_tokens);
+            // The new shards may still generate other tokens
+            if let Some(cache) = &self.response_cache {
+                cache.invalidate();
+            }
+            return;
+        };
+        let ModelFiles {
+            tokenizer,
+            tokenizer_config,
+            processor_config,
+            config,
+            preprocessor_config,
+            model_sha,
+            ..
+        } = files;
+        *validation = validation.with_tokenizer(
+            tokenizer,
+            config,
+            preprocessor_config,
+            max_input_tokens,
+            max_total_tokens,
+        );
+        *self.chat_template.write().unwrap() = chat_template(tokenizer_config, processor_config);
+        if let Some(cache) = &self.response_cache {
+            cache.set_model_sha(model_sha.clone());
+        }
+        *self.model_sha.write().unwrap() = model_sha;
+    }
+
+    /// Progress of the last swap of the shards of a model, None if none was swapped
+    pub(crate) fn swap_status(&self) -> Option<SwapModelStatus> {
+        self.swaps.status()
+    }
+
+    /// Revision of the model served by the shards
+    pub(crate) fn model_sha(&self) -> Option<String> {
+        self.model_sha.read().unwrap().clone()
+    }
+
+    /// Maximum number of tokens of the prompts and of the whole requests
+    pub(crate) fn token_limits(&self) -> (usize, usize) {
+        let validation = self.validation.read().unwrap();
+        (validation.max_input_length(), validation.max_total_tokens())
+    }
+
+    pub(crate) fn shard_info(&self) -> Option<ShardInfo> {
+        self.backend.shard_info()
+    }
+
+    /// Stop the backend, once the requests of the server are done
+    pub(crate) async fn shutdown(&self) {
+        self.backend.shutdown().await;
+    }
+
+    pub(crate) fn stream_heartbeat_interval(&self) -> Duration {
+        self.stream_heartbeat_interval
+    }
+
+    pub(crate) fn supports_embeddings(&self) -> bool {
+        self.backend.supports_embeddings()
+    }
+
+    /// Number of requests currently holding a concurrency permit
+    pub(crate) fn in_flight(&self) -> usize {
+        self.max_concurrent_requests - self.limit_concurrent_requests.available_permits()
+    }
+}
+
+/// WebSocket connection counted by `Infer::upgraded_connection`
+pub(crate) struct UpgradedConnection(Arc<watch::Sender<usize>>);
+
+impl Drop for UpgradedConnection {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Gather the messages of a generation stream in a single response
+async fn collect_response(
+    stream: impl Stream<Item = Result<InferStreamResponse, InferError>>,
+    _input_length: u32,
+    use_top_tokens: bool,
+) -> Result<InferResponse, InferError> {
+    // Return values
+    let mut result_prefill = Vec::new();
+    let mut result_tokens = Vec::new();
+    let mut result_top_tokens = Vec::new();
+    let mut result_generated_text = None;
+    let mut result_start = None;
+    let mut result_queued = None;
+    let mut result_first_token = None;
+
+    let mut stream = Box::pin(stream);
+
+    // Iterate on stream
+    while let Some(response) = stream.next().await {
+        match response? {
+            InferStreamResponse::Queued { .. } | InferStreamResponse::Started => {}
+            // Add prefill tokens
+            InferStreamResponse::Prefill(prefill_tokens) => {
+                result_prefill = prefill_tokens;
+            }
+            // Push last token
+            InferStreamResponse::Intermediate { token, top_tokens } => {
+                result_first_token.get_or_insert_with(Instant::now);
+                result_tokens.push(token);
+                result_top_tokens.push(top_tokens);
+            }
+            // Final message
+            // Set return values
+            InferStreamResponse::End {
+                token,
+                generated_text,
+                start,
+                queued,
+                top_tokens,
+            } => {
+                result_first_token.get_or_insert_with(Instant::now);
+                result_tokens.push(token);
+                result_top_tokens.push(top_tokens);
+                result_generated_text = Some(generated_text);
+                result_start = Some(start);
+                result_queued = Some(queued)
+            }
+        }
+    }
+
+    // Check that we received a `InferStreamResponse::End` message
+    if let (Some(generated_text), Some(queued), Some(start)) =
+        (result_generated_text, result_queued, result_start)
+    {
+        Ok(InferResponse {
+            prefill: result_prefill,
+            _input_length,
+            tokens: result_tokens,
+            generated_text,
+            queued,
+            start,
+            first_token: result_first_token.unwrap_or(start),
+            top_tokens: if use_top_tokens {
+                result_top_tokens
+            } else {
+                Vec::new()
+            },
+        })
+    } else {
+        let err = InferError::IncompleteGeneration;
+        metrics::counter!("tgi_request_failure", "err" => "incomplete").increment(1);
+        tracing::error!("{err}");
+        Err(err)
+    }
+}
+
+/// Check that the shards can mix the `adapters` of a request, given their LoRA adapters
+/// Template of the chats of the model, the default one if it has several
+fn chat_template(
+    tokenizer_config: HubTokenizerConfig,
+    processor_config: HubProcessorConfig,
+) -> Option<ChatTemplate> {
+    tokenizer_config
+        .chat_template
+        .or(processor_config.chat_template)
+        .and_then(|t| match t {
+            ChatTemplateVersions::Single(template) => Some(template),
+            ChatTemplateVersions::Multiple(templates) => templates
+                .into_iter()
+                .find(|t| t.name == "default")
+                .map(|t| t.template),
+        })
+        .map(|t| ChatTemplate::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token))
+}
+
+fn check_adapters(
+    adapters: &[AdapterWeight],
+    lora_adapters: Option<HashMap<String, u32>>,
+) -> Result<(), ValidationError> {
+    let lora_adapters = lora_adapters.ok_or(ValidationError::AdaptersUnsupported)?;
+    let mut rank = 0;
+    for adapter in adapters {
+        rank += lora_adapters
+            .get(&adapter.id)
+            .ok_or_else(|| ValidationError::UnknownAdapter(adapter.id.clone()))?;
+    }
+    if rank > MAX_ADAPTERS_RANK {
+        return Err(ValidationError::AdaptersRank(MAX_ADAPTERS_RANK, rank));
+    }
+    Ok(())
+}
+
+/// Remove from the text of the first generated token the end of the prompt that token healing
+/// cut, so that the generation continues the prompt of the client
+fn strip_healed_prefix(text: &mut String, prefix: &str) {
+    if text.starts_with(prefix) {
+        text.drain(..prefix.len());
+    }
 }
 
 #[derive(Debug)]
@@ -368,10 +1682,53 @@ pub struct GeneratedText {
     pub generated_tokens: u32,
     pub finish_reason: FinishReason,
     pub seed: Option<u64>,
+    /// Batches the request was generated in
+    pub batches: BatchResidency,
+}
+
+/// Batches that a request shared with the other requests
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BatchResidency {
+    /// Forward passes of the request
+    pub batches: u32,
+    /// Sum of the sizes of these batches, the request included
+    pub batched_requests: u64,
+    /// Size of the largest of these batches
+    pub max_batch_size: u32,
+}
+
+impl BatchResidency {
+    /// Count a forward pass of `batch_size` requests
+    pub fn record(&mut self, batch_size: usize) {
+        self.batches += 1;
+        self.batched_requests += batch_size as u64;
+        self.max_batch_size = self.max_batch_size.max(batch_size as u32);
+    }
+
+    /// Add the batches of another round of the same request
+    pub(crate) fn merge(&mut self, other: &Self) {
+        self.batches += other.batches;
+        self.batched_requests += other.batched_requests;
+        self.max_batch_size = self.max_batch_size.max(other.max_batch_size);
+    }
+
+    /// Mean size of the batches, the request included
+    pub(crate) fn mean_batch_size(&self) -> f64 {
+        if self.batches == 0 {
+            return 0.0;
+        }
+        self.batched_requests as f64 / self.batches as f64
+    }
 }
 
 #[derive(Debug)]
 pub enum InferStreamResponse {
+    // Sent by the router at the heartbeat interval until the first token
+    Queued {
+        position: usize,
+    },
+    // Sent by the backends when the request enters a batch, before its prefill
+    Started,
     // Optional first message
     Prefill(Vec<PrefillToken>),
     // Intermediate messages
@@ -400,6 +1757,8 @@ pub(crate) struct InferResponse {
     pub(crate) generated_text: GeneratedText,
     pub(crate) queued: Instant,
     pub(crate) start: Instant,
+    /// Instant when the first token was received, the end of the prefill
+    pub(crate) first_token: Instant,
     pub(crate) top_tokens: Vec<Vec<Token>>,
 }
 
@@ -423,6 +1782,36 @@ pub enum InferError {
     ToolError(String),
     #[error("Stream event serialization error")]
     StreamSerializationError(String),
+    #[error("Server is draining and does not accept new requests")]
+    Draining,
+    #[error("Model is overloaded, the request queue is full")]
+    QueueFull,
+    #[error("Model does not support embeddings")]
+    EmbeddingsUnsupported,
+    #[error("Request did not generate any token within `timeout_ms`")]
+    GenerationTimeout,
+    #[error("Request was still queued at its `deadline_ms`")]
+    DeadlineExceeded,
+    #[error("Request was aborted before generating any token")]
+    Cancelled,
+    #[error("Model shards are unavailable, the request can be retried: {0}")]
+    BackendUnavailable(String),
+    #[error("API key is not allowed to use model `{0}`")]
+    ModelNotAllowed(String),
+    #[error("Backend does not support loading LoRA adapters at runtime")]
+    AdapterLoadingUnsupported,
+    #[error("LoRA adapter `{0}` is not loaded")]
+    AdapterNotLoaded(String),
+    #[error("LoRA adapter `{0}` is already loaded")]
+    AdapterAlreadyLoaded(String),
+    #[error("Shards could not load or unload the LoRA adapter: {0}")]
+    AdapterError(String),
+    #[error("Backend cannot switch over to new shards")]
+    SwapUnsupported,
+    #[error("The shards of the model are already being replaced or reconnected")]
+    SwapInProgress,
+    #[error("Could not switch over to the new shards: {0}")]
+    SwapError(String),
 }
 
 impl InferError {
@@ -437,6 +1826,21 @@ impl InferError {
             InferError::MissingTemplateVariable(_) => "missing_template_variable",
             InferError::ToolError(_) => "tool_error",
             InferError::StreamSerializationError(_) => "stream_serialization_error",
+            InferError::Draining => "draining",
+            InferError::QueueFull => "queue_full",
+            InferError::EmbeddingsUnsupported => "embeddings_unsupported",
+            InferError::GenerationTimeout => "timeout",
+            InferError::DeadlineExceeded => "deadline_exceeded",
+            InferError::Cancelled => "cancelled",
+            InferError::BackendUnavailable(_) => "backend_unavailable",
+            InferError::ModelNotAllowed(_) => "forbidden",
+            InferError::AdapterLoadingUnsupported => "adapter_loading_unsupported",
+            InferError::AdapterNotLoaded(_) => "adapter_not_loaded",
+            InferError::AdapterAlreadyLoaded(_) => "adapter_already_loaded",
+            InferError::AdapterError(_) => "adapter",
+            InferError::SwapUnsupported => "swap_unsupported",
+            InferError::SwapInProgress => "swap_in_progress",
+            InferError::SwapError(_) => "swap",
         }
     }
 
@@ -452,6 +1856,20 @@ impl InferError {
     }
 }
 
+/// Index of the sequence with the highest cumulative log probability of its generated tokens
+fn most_likely<'a>(sequences: impl Iterator<Item = &'a [Token]>) -> usize {
+    let mut max_index = 0;
+    let mut max_logprob = f32::NEG_INFINITY;
+    for (i, tokens) in sequences.enumerate() {
+        let sequence_logprob = tokens.iter().map(|token| token.logprob).sum::<f32>();
+        if sequence_logprob > max_logprob {
+            max_index = i;
+            max_logprob = sequence_logprob;
+        }
+    }
+    max_index
+}
+
 #[derive(Serialize)]
 pub struct APIError {
     message: String,
@@ -462,3 +1880,32 @@ pub struct APIError {
 pub struct OpenaiErrorEvent {
     error: APIError,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(logprobs: &[f32]) -> Vec<Token> {
+        logprobs
+            .iter()
+            .map(|&logprob| Token {
+                id: 0,
+                text: String::new(),
+                logprob,
+                special: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_most_likely() {
+        // The short sequence has the lowest mean logprob, but the highest cumulative one
+        let sequences = [
+            tokens(&[-0.5, -0.5, -0.5, -0.5]),
+            tokens(&[-1.5]),
+            tokens(&[-0.1, -0.1, -3.0]),
+        ];
+        assert_eq!(most_likely(sequences.iter().map(Vec::as_slice)), 1);
+        assert_eq!(most_likely(std::iter::empty()), 0);
+    }
+}
@@ -0,0 +1,242 @@
+/// Switch over of a model to new shard-sets, reported by `GET /admin/model/swap`
+use crate::config::Config;
+use crate::infer::InferError;
+use crate::{
+    HubPreprocessorConfig, HubProcessorConfig, HubTokenizerConfig, ShardInfo, SwapModelStatus,
+    SwapState, Tokenizer,
+};
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// Check of the info of a new shard-set, before it receives any request
+pub type ShardCheck = Box<dyn Fn(&ShardInfo) -> Result<(), String> + Send + Sync>;
+
+/// Requests left on the old shard-sets of a model
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SwapDrain {
+    /// Requests still running
+    pub running: usize,
+    /// Requests interrupted at the end of the drain timeout
+    pub interrupted: usize,
+    /// Whether the old shard-sets are stopped
+    pub done: bool,
+}
+
+/// Model switched over to new shard-sets by `Backend::swap_model`
+pub struct ModelSwap {
+    /// Replicas switched over to their new shard-set
+    pub replicas: usize,
+    /// Limits of the requests, derived again for the new shard-sets
+    pub max_input_tokens: usize,
+    pub max_total_tokens: usize,
+    /// Progress of the old shard-sets
+    pub drain: watch::Receiver<SwapDrain>,
+}
+
+/// Tokenizer and configuration of the revision of the model served by the new shard-sets
+pub(crate) struct ModelFiles {
+    pub(crate) tokenizer: Tokenizer,
+    pub(crate) tokenizer_config: HubTokenizerConfig,
+    pub(crate) processor_config: HubProcessorConfig,
+    pub(crate) config: Option<Config>,
+    pub(crate) preprocessor_config: Option<HubPreprocessorConfig>,
+    pub(crate) model_sha: Option<String>,
+    /// Number of tokens that the shards of the revision must have, unknown with a Python
+    /// tokenizer or when a mismatch is allowed
+    pub(crate) vocab_size: Option<usize>,
+}
+
+impl ModelFiles {
+    /// Check that the shards of the revision have the vocabulary of its tokenizer
+    pub(crate) fn shard_check(&self) -> ShardCheck {
+        let vocab_size = self.vocab_size;
+        Box::new(move |shard_info: &ShardInfo| match vocab_size {
+            Some(vocab_size)
+                if crate::validation::vocab_size_mismatch(
+                    vocab_size,
+                    shard_info.vocab_size as usize,
+                ) =>
+            {
+                Err(format!(
+                    "the tokenizer of the revision has {vocab_size} tokens and its model {}",
+                    shard_info.vocab_size
+                ))
+            }
+            _ => Ok(()),
+        })
+    }
+}
+
+/// Check that new shards of the current revision have the vocabulary of the current ones, as
+/// the router keeps tokenizing the prompts for them with its tokenizer
+pub(crate) fn same_vocabulary(current: Option<ShardInfo>) -> ShardCheck {
+    let vocab_size = current.map(|shard_info| shard_info.vocab_size);
+    Box::new(move |shard_info: &ShardInfo| match vocab_size {
+        Some(vocab_size) if vocab_size != shard_info.vocab_size => Err(
+            "the vocabulary of the new model is not the one of the current model, set the \
+             `revision` of the new shards"
+                .to_string(),
+        ),
+        _ => Ok(()),
+    })
+}
+
+/// Last swap of the models
+#[derive(Default)]
+pub(crate) struct SwapTracker {
+    last: Mutex<Option<(SwapModelStatus, Option<watch::Receiver<SwapDrain>>)>>,
+}
+
+impl SwapTracker {
+    /// Record a new swap, unless the previous one is still running
+    pub(crate) fn begin(
+        &self,
+        model: String,
+        revision: Option<String>,
+    ) -> Result<SwapModelStatus, InferError> {
+        let mut last = self.last.lock().unwrap();
+        if let Some((status, drain)) = last.as_ref() {
+            if matches!(
+                current_state(status, drain.as_ref()),
+                SwapState::Starting | SwapState::Draining
+            ) {
+                return Err(InferError::SwapInProgress);
+            }
+        }
+        let status = SwapModelStatus {
+            model,
+            revision,
+            state: SwapState::Starting,
+            replicas: 0,
+            draining: 0,
+            interrupted: 0,
+            error: None,
+        };
+        *last = Some((status.clone(), None));
+        Ok(status)
+    }
+
+    /// The new shard-sets serve the requests, the old ones report their drain
+    pub(crate) fn switched(&self, replicas: usize, drain: watch::Receiver<SwapDrain>) {
+        if let Some((status, last_drain)) = self.last.lock().unwrap().as_mut() {
+            status.state = SwapState::Draining;
+            status.replicas = replicas;
+            *last_drain = Some(drain);
+        }
+    }
+
+    pub(crate) fn fail(&self, error: String) {
+        if let Some((status, _)) = self.last.lock().unwrap().as_mut() {
+            status.state = SwapState::Failed;
+            status.error = Some(error);
+        }
+    }
+
+    /// Progress of the last swap, None if no model was swapped
+    pub(crate) fn status(&self) -> Option<SwapModelStatus> {
+        let last = self.last.lock().unwrap();
+        let (status, drain) = last.as_ref()?;
+        let mut status = status.clone();
+        if let Some(drain) = drain {
+            let drain = *drain.borrow();
+            status.draining = drain.running;
+            status.interrupted = drain.interrupted;
+        }
+        status.state = current_state(&status, drain.as_ref());
+        Some(status)
+    }
+}
+
+fn current_state(
+    status: &SwapModelStatus,
+    drain: Option<&watch::Receiver<SwapDrain>>,
+) -> SwapState {
+    match (status.state, drain) {
+        (SwapState::Draining, Some(drain)) if drain.borrow().done => SwapState::Done,
+        (state, _) => state,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard_info(vocab_size: u32) -> ShardInfo {
+        ShardInfo {
+            model_dtype: "torch.float16".to_string(),
+            model_device_type: "cuda".to_string(),
+            context_length: None,
+            vocab_size,
+            quantization: None,
+            attention_impl: "paged".to_string(),
+            kernel_versions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_swap_tracker() {
+        let tracker = SwapTracker::default();
+        assert!(tracker.status().is_none());
+
+        let status = tracker.begin("model".to_string(), None).unwrap();
+        assert_eq!(status.state, SwapState::Starting);
+        assert!(matches!(
+            tracker.begin("model".to_string(), None),
+            Err(InferError::SwapInProgress)
+        ));
+
+        let (sender, drain) = watch::channel(SwapDrain {
+            running: 2,
+            ..SwapDrain::default()
+        });
+        tracker.switched(1, drain);
+        let status = tracker.status().unwrap();
+        assert_eq!(status.state, SwapState::Draining);
+        assert_eq!((status.replicas, status.draining), (1, 2));
+        assert!(tracker.begin("model".to_string(), None).is_err());
+
+        sender.send_replace(SwapDrain {
+            running: 0,
+            interrupted: 1,
+            done: true,
+        });
+        let status = tracker.status().unwrap();
+        assert_eq!(status.state, SwapState::Done);
+        assert_eq!((status.draining, status.interrupted), (0, 1));
+
+        // The next swap can start, and its failure is reported
+        tracker
+            .begin("model".to_string(), Some("v2".to_string()))
+            .unwrap();
+        tracker.fail("no space left on device".to_string());
+        let status = tracker.status().unwrap();
+        assert_eq!(status.state, SwapState::Failed);
+        assert_eq!(status.error.as_deref(), Some("no space left on device"));
+        assert_eq!(status.revision.as_deref(), Some("v2"));
+    }
+
+    #[test]
+    fn test_shard_checks() {
+        let check = same_vocabulary(Some(shard_info(32000)));
+        assert!(check(&shard_info(32000)).is_ok());
+        assert!(check(&shard_info(32064)).is_err());
+        assert!(same_vocabulary(None)(&shard_info(32064)).is_ok());
+
+        let files = ModelFiles {
+            tokenizer: Tokenizer::Python {
+                tokenizer_name: "model".to_string(),
+                revision: None,
+                trust_remote_code: false,
+            },
+            tokenizer_config: HubTokenizerConfig::default(),
+            processor_config: HubProcessorConfig::default(),
+            config: None,
+            preprocessor_config: None,
+            model_sha: None,
+            vocab_size: Some(32000),
+        };
+        // The embeddings of the model can be padded
+        assert!(files.shard_check()(&shard_info(32064)).is_ok());
+        assert!(files.shard_check()(&shard_info(31000)).is_err());
+    }
+}
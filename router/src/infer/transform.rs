@@ -0,0 +1,502 @@
+/// Per-model transformations of the prompts and of the generated texts, for the quirks of
+/// the deployed models
+use crate::infer::{InferError, InferStreamResponse};
+use async_stream::stream;
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio_stream::StreamExt;
+
+/// Names of the transforms compiled into the router
+const TRANSFORMS: [&str; 3] = ["strip_reasoning", "replace_prompt", "replace_output"];
+
+/// Entry of the configuration applying to the models without their own
+const ANY_MODEL: &str = "*";
+
+#[derive(Debug, Error)]
+pub enum TransformError {
+    #[error("Unable to read the transforms {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Unable to parse the transforms {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("Unknown transform `{0}`, the transforms are {list}", list = TRANSFORMS.join(", "))]
+    Unknown(String),
+    #[error("Invalid options of the `{0}` transform: {1}")]
+    Options(&'static str, String),
+}
+
+/// Transformation of the requests and responses of a model. The prompts are transformed
+/// before their validation, so after their chat template.
+trait Transform {
+    fn prompt(&self, inputs: String) -> String {
+        inputs
+    }
+
+    /// Rewriter of the generated text of one request, `None` to leave the text as is
+    fn output(&self) -> Option<Box<dyn Rewriter + Send>> {
+        None
+    }
+}
+
+/// Rewriting of a text streamed in pieces. It can hold the end of a piece back until the
+/// next pieces tell how to rewrite it.
+trait Rewriter {
+    /// Rewritten text of `text` and of the held text that it completes
+    fn push(&mut self, text: &str) -> String;
+
+    /// Rest of the held text, at the end of the generation
+    fn finish(&mut self) -> String;
+}
+
+/// Length of the longest end of `text` that starts `pattern`, without the whole `pattern`
+fn partial_match_len(text: &str, pattern: &str) -> usize {
+    (1..pattern.len())
+        .rev()
+        .filter(|&len| pattern.is_char_boundary(len))
+        .find(|&len| text.ends_with(&pattern[..len]))
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StripReasoningOptions {
+    #[serde(default = "default_reasoning_start")]
+    start: String,
+    #[serde(default = "default_reasoning_end")]
+    end: String,
+    /// Whether the generations start inside the reasoning, for the chat templates ending
+    /// with the start tag
+    #[serde(default)]
+    started: bool,
+}
+
+fn default_reasoning_start() -> String {
+    "<think>".to_string()
+}
+
+fn default_reasoning_end() -> String {
+    "</think>".to_string()
+}
+
+/// Removes the reasoning between its `start` and `end` tags, and the whitespace after it
+struct StripReasoning {
+    options: StripReasoningOptions,
+}
+
+impl Transform for StripReasoning {
+    fn output(&self) -> Option<Box<dyn Rewriter + Send>> {
+        Some(Box::new(ReasoningRewriter {
+            start: self.options.start.clone(),
+            end: self.options.end.clone(),
+            reasoning: self.options.started,
+            after_reasoning: false,
+            held: String::new(),
+        }))
+    }
+}
+
+struct ReasoningRewriter {
+    start: String,
+    end: String,
+    /// Whether the held text is inside the reasoning
+    reasoning: bool,
+    /// Whether the reasoning just ended, so that the next whitespace is dropped
+    after_reasoning: bool,
+    held: String,
+}
+
+impl Rewriter for ReasoningRewriter {
+    fn push(&mut self, text: &str) -> String {
+        self.held.push_str(text);
+        let mut rewritten = String::new();
+        loop {
+            if self.after_reasoning {
+                let answer = self.held.trim_start();
+                if answer.is_empty() {
+                    self.held.clear();
+                    return rewritten;
+                }
+                self.held = answer.to_string();
+                self.after_reasoning = false;
+            }
+            let tag = if self.reasoning {
+                &self.end
+            } else {
+                &self.start
+            };
+            if let Some(index) = self.held.find(tag.as_str()) {
+                if !self.reasoning {
+                    rewritten.push_str(&self.held[..index]);
+                }
+                self.held.drain(..index + tag.len());
+                self.after_reasoning = self.reasoning;
+                self.reasoning = !self.reasoning;
+                continue;
+            }
+            let released = self.held.len() - partial_match_len(&self.held, tag);
+            if !self.reasoning {
+                rewritten.push_str(&self.held[..released]);
+            }
+            self.held.drain(..released);
+            return rewritten;
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        // A reasoning that never ended is dropped whole
+        let held = std::mem::take(&mut self.held);
+        if self.reasoning {
+            String::new()
+        } else {
+            held
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplaceOptions {
+    from: String,
+    #[serde(default)]
+    to: String,
+}
+
+/// Replaces every occurrence of `from` in the prompts with `to`
+struct ReplacePrompt {
+    options: ReplaceOptions,
+}
+
+impl Transform for ReplacePrompt {
+    fn prompt(&self, inputs: String) -> String {
+        inputs.replace(&self.options.from, &self.options.to)
+    }
+}
+
+/// Replaces every occurrence of `from` in the generated texts with `to`, such as the stop
+/// token texts that a model generates as plain text
+struct ReplaceOutput {
+    options: ReplaceOptions,
+}
+
+impl Transform for ReplaceOutput {
+    fn output(&self) -> Option<Box<dyn Rewriter + Send>> {
+        Some(Box::new(ReplaceRewriter {
+            from: self.options.from.clone(),
+            to: self.options.to.clone(),
+            held: String::new(),
+        }))
+    }
+}
+
+struct ReplaceRewriter {
+    from: String,
+    to: String,
+    held: String,
+}
+
+impl Rewriter for ReplaceRewriter {
+    fn push(&mut self, text: &str) -> String {
+        self.held.push_str(text);
+        let matched = self
+            .held
+            .match_indices(self.from.as_str())
+            .last()
+            .map_or(0, |(index, _)| index + self.from.len());
+        let released = self.held.len() - partial_match_len(&self.held[matched..], &self.from);
+        let rewritten = self.held[..released].replace(&self.from, &self.to);
+        self.held.drain(..released);
+        rewritten
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+fn options<T: DeserializeOwned>(
+    name: &'static str,
+    options: Map<String, Value>,
+) -> Result<T, TransformError> {
+    serde_json::from_value(Value::Object(options))
+        .map_err(|err| TransformError::Options(name, err.to_string()))
+}
+
+fn replace_options(
+    name: &'static str,
+    spec: Map<String, Value>,
+) -> Result<ReplaceOptions, TransformError> {
+    let replace: ReplaceOptions = options(name, spec)?;
+    if replace.from.is_empty() {
+        return Err(TransformError::Options(
+            name,
+            "`from` must not be empty".to_string(),
+        ));
+    }
+    Ok(replace)
+}
+
+/// Registry of the compiled-in transforms, by their name in the configuration
+fn build(spec: TransformSpec) -> Result<Box<dyn Transform + Send + Sync>, TransformError> {
+    Ok(match spec.name.as_str() {
+        "strip_reasoning" => {
+            let options: StripReasoningOptions = options("strip_reasoning", spec.options)?;
+            if options.start.is_empty() || options.end.is_empty() {
+                return Err(TransformError::Options(
+                    "strip_reasoning",
+                    "the tags must not be empty".to_string(),
+                ));
+            }
+            Box::new(StripReasoning { options })
+        }
+        "replace_prompt" => Box::new(ReplacePrompt {
+            options: replace_options("replace_prompt", spec.options)?,
+        }),
+        "replace_output" => Box::new(ReplaceOutput {
+            options: replace_options("replace_output", spec.options)?,
+        }),
+        _ => return Err(TransformError::Unknown(spec.name)),
+    })
+}
+
+/// Transform of the `--transforms-path` file, its name and its options
+#[derive(Debug, Deserialize)]
+struct TransformSpec {
+    name: String,
+    #[serde(flatten)]
+    options: Map<String, Value>,
+}
+
+/// Transforms of a model, applied in their order
+pub(crate) struct Chain {
+    transforms: Vec<Box<dyn Transform + Send + Sync>>,
+}
+
+impl Chain {
+    pub(crate) fn prompt(&self, inputs: String) -> String {
+        self.transforms
+            .iter()
+            .fold(inputs, |inputs, transform| transform.prompt(inputs))
+    }
+
+    fn rewriters(&self) -> Rewriters {
+        Rewriters(
+            self.transforms
+                .iter()
+                .filter_map(|transform| transform.output())
+                .collect(),
+        )
+    }
+}
+
+/// Rewriters of a request, each one rewriting the output of the previous one
+struct Rewriters(Vec<Box<dyn Rewriter + Send>>);
+
+impl Rewriters {
+    fn push(&mut self, text: &str) -> String {
+        self.0
+            .iter_mut()
+            .fold(text.to_string(), |text, rewriter| rewriter.push(&text))
+    }
+
+    fn finish(&mut self) -> String {
+        let mut text = String::new();
+        for rewriter in &mut self.0 {
+            text = rewriter.push(&text);
+            text.push_str(&rewriter.finish());
+        }
+        text
+    }
+
+    fn rewrite(mut self, text: &str) -> String {
+        let mut rewritten = self.push(text);
+        rewritten.push_str(&self.finish());
+        rewritten
+    }
+}
+
+/// Chains of transforms by model, read from a JSON file of the form
+/// `{"my-model": [{"name": "strip_reasoning"}, {"name": "replace_output", "from": "<|im_end|>"}]}`.
+/// The `"*"` entry applies to the models without their own entry.
+pub(crate) struct Transforms {
+    models: HashMap<String, Arc<Chain>>,
+}
+
+impl Transforms {
+    /// Returns `None` without a transforms file
+    pub(crate) fn new(path: Option<String>) -> Result<Option<Self>, TransformError> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| TransformError::Read(path.clone(), err))?;
+        let config: HashMap<String, Vec<TransformSpec>> = serde_json::from_str(&content)
+            .map_err(|err| TransformError::Parse(path.clone(), err))?;
+        Self::from_config(config).map(Some)
+    }
+
+    fn from_config(config: HashMap<String, Vec<TransformSpec>>) -> Result<Self, TransformError> {
+        let models = config
+            .into_iter()
+            .map(|(model, specs)| {
+                let transforms = specs.into_iter().map(build).collect::<Result<_, _>>()?;
+                Ok((model, Arc::new(Chain { transforms })))
+            })
+            .collect::<Result<_, TransformError>>()?;
+        Ok(Self { models })
+    }
+
+    pub(crate) fn for_model(&self, model: &str) -> Option<Arc<Chain>> {
+        self.models
+            .get(model)
+            .or_else(|| self.models.get(ANY_MODEL))
+            .cloned()
+    }
+}
+
+/// Rewrite the streamed tokens and the generated text of a generation `stream` with the
+/// output transforms of its model
+pub(crate) fn rewrite<'a>(
+    chain: Option<Arc<Chain>>,
+    stream: impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a,
+) -> impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a {
+    stream! {
+        let mut stream = std::pin::pin!(stream);
+        let mut rewriters = chain
+            .as_ref()
+            .map(|chain| chain.rewriters())
+            .filter(|rewriters| !rewriters.0.is_empty());
+        let Some(rewriters) = rewriters.as_mut() else {
+            while let Some(response) = stream.next().await {
+                yield response;
+            }
+            return;
+        };
+        let chain = chain.unwrap();
+
+        while let Some(response) = stream.next().await {
+            match response {
+                Ok(InferStreamResponse::Intermediate { mut token, top_tokens }) => {
+                    if !token.special {
+                        token.text = rewriters.push(&token.text);
+                    }
+                    yield Ok(InferStreamResponse::Intermediate { token, top_tokens });
+                }
+                Ok(InferStreamResponse::End { mut token, top_tokens, mut generated_text, start, queued }) => {
+                    // The held text comes before the text of a special last token
+                    let mut text = if token.special {
+                        String::new()
+                    } else {
+                        rewriters.push(&token.text)
+                    };
+                    text.push_str(&rewriters.finish());
+                    if token.special {
+                        text.push_str(&token.text);
+                    }
+                    token.text = text;
+                    generated_text.text = chain.rewriters().rewrite(&generated_text.text);
+                    yield Ok(InferStreamResponse::End { token, top_tokens, generated_text, start, queued });
+                    return;
+                }
+                response => yield response,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(config: &str) -> Transforms {
+        Transforms::from_config(serde_json::from_str(config).unwrap()).unwrap()
+    }
+
+    /// Text streamed by the output transforms of `model` for the pieces `texts`
+    fn streamed(transforms: &Transforms, model: &str, texts: &[&str]) -> Vec<String> {
+        let mut rewriters = transforms.for_model(model).unwrap().rewriters();
+        let mut streamed: Vec<String> = texts.iter().map(|text| rewriters.push(text)).collect();
+        streamed.push(rewriters.finish());
+        streamed
+    }
+
+    #[test]
+    fn test_strip_reasoning() {
+        let transforms = config(r#"{"*": [{"name": "strip_reasoning"}]}"#);
+        let pieces = [
+            "Hi <th",
+            "ink>let me",
+            " think</",
+            "think>\n",
+            "\n Hel",
+            "lo <",
+        ];
+        assert_eq!(
+            streamed(&transforms, "any", &pieces),
+            ["Hi ", "", "", "", "Hel", "lo ", "<"]
+        );
+        let chain = transforms.for_model("any").unwrap();
+        assert_eq!(chain.rewriters().rewrite(&pieces.concat()), "Hi Hello <");
+
+        // The reasoning of a generation that stopped before its end is dropped
+        let transforms = config(r#"{"*": [{"name": "strip_reasoning", "started": true}]}"#);
+        assert_eq!(
+            streamed(&transforms, "any", &["Let me", " think"]),
+            ["", "", ""]
+        );
+    }
+
+    #[test]
+    fn test_transforms_by_model() {
+        let transforms = config(
+            r#"{
+                "chat": [
+                    {"name": "replace_prompt", "from": "<sys>", "to": "<|system|>"},
+                    {"name": "replace_output", "from": "<|im_end|>"}
+                ],
+                "*": [{"name": "replace_output", "from": "a", "to": "b"}]
+            }"#,
+        );
+        let chat = transforms.for_model("chat").unwrap();
+        assert_eq!(
+            chat.prompt("<sys>Be brief".to_string()),
+            "<|system|>Be brief"
+        );
+        assert_eq!(
+            streamed(&transforms, "chat", &["Done<|im", "_end|>", "<|"]),
+            ["Done", "", "", "<|"]
+        );
+        assert_eq!(streamed(&transforms, "base", &["banana"]), ["bbnbnb", ""]);
+        assert_eq!(
+            transforms
+                .for_model("base")
+                .unwrap()
+                .prompt("a".to_string()),
+            "a"
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = serde_json::from_str(r#"{"*": [{"name": "uppercase"}]}"#).unwrap();
+        assert!(matches!(
+            Transforms::from_config(config),
+            Err(TransformError::Unknown(_))
+        ));
+        let config =
+            serde_json::from_str(r#"{"*": [{"name": "replace_output", "form": "a"}]}"#).unwrap();
+        assert!(matches!(
+            Transforms::from_config(config),
+            Err(TransformError::Options("replace_output", _))
+        ));
+        let config =
+            serde_json::from_str(r#"{"*": [{"name": "replace_prompt", "from": ""}]}"#).unwrap();
+        assert!(matches!(
+            Transforms::from_config(config),
+            Err(TransformError::Options("replace_prompt", _))
+        ));
+    }
+}
@@ -0,0 +1,296 @@
+/// Server-configured keys of the watermark, and its detection in the texts
+use crate::WatermarkKeyScore;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::RwLock;
+use thiserror::Error;
+
+/// z-score from which a text is considered watermarked
+pub(crate) const DETECTION_Z_SCORE: f64 = 4.0;
+/// The greenlists are computed on 32 bits
+const MASK: u64 = 0xFFFF_FFFF;
+
+#[derive(Debug, Error)]
+pub enum WatermarkError {
+    #[error("The watermark gamma must be strictly between 0 and 1, got {0}")]
+    Gamma(f64),
+    #[error("Unable to read the watermark keys {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Unable to parse the watermark keys {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("The active watermark key `{0}` is not one of the keys")]
+    UnknownActive(String),
+    #[error("The watermark key `{0}` is listed more than once")]
+    DuplicateId(String),
+    #[error("The watermark key `{0}` must not be 0")]
+    ZeroKey(String),
+}
+
+/// Content of the keys file
+#[derive(Debug, Deserialize)]
+struct KeySet {
+    /// Id of the key of the new generations
+    active: String,
+    /// Every key that the texts are scored with, including the retired ones
+    keys: Vec<Key>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Key {
+    id: String,
+    key: u64,
+}
+
+impl KeySet {
+    fn read(path: &str) -> Result<Self, WatermarkError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| WatermarkError::Read(path.to_string(), err))?;
+        let key_set: Self = serde_json::from_str(&content)
+            .map_err(|err| WatermarkError::Parse(path.to_string(), err))?;
+
+        let mut ids = HashSet::new();
+        for key in &key_set.keys {
+            if !ids.insert(key.id.as_str()) {
+                return Err(WatermarkError::DuplicateId(key.id.clone()));
+            }
+            // 0 is the default key of the shards
+            if key.key == 0 {
+                return Err(WatermarkError::ZeroKey(key.id.clone()));
+            }
+        }
+        if !ids.contains(key_set.active.as_str()) {
+            return Err(WatermarkError::UnknownActive(key_set.active.clone()));
+        }
+        Ok(key_set)
+    }
+
+    fn active_key(&self) -> u64 {
+        // Checked when the file is read
+        self.keys
+            .iter()
+            .find(|key| key.id == self.active)
+            .unwrap()
+            .key
+    }
+}
+
+/// Keys of the watermark, read from a JSON file of the form
+/// `{"active": "2024-06", "keys": [{"id": "2024-06", "key": 1234}, {"id": "2024-01", "key": 5678}]}`.
+///
+/// The watermarked generations use the active key. To rotate the keys, a new key is added to
+/// the file and made active, and the file is reloaded: the texts generated with the previous
+/// keys are still detected until they are removed from the file.
+pub(crate) struct WatermarkKeys {
+    path: String,
+    /// Fraction of the vocabulary in the greenlists, as set on the shards
+    gamma: f64,
+    key_set: RwLock<KeySet>,
+}
+
+impl WatermarkKeys {
+    pub(crate) fn new(path: Option<String>, gamma: f64) -> Result<Option<Self>, WatermarkError> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        if gamma <= 0.0 || gamma >= 1.0 {
+            return Err(WatermarkError::Gamma(gamma));
+        }
+        let key_set = RwLock::new(KeySet::read(&path)?);
+        Ok(Some(Self {
+            path,
+            gamma,
+            key_set,
+        }))
+    }
+
+    /// Read the keys file again. The current keys are kept if it is invalid.
+    pub(crate) fn reload(&self) -> Result<(), WatermarkError> {
+        let key_set = KeySet::read(&self.path)?;
+        tracing::info!(
+            "Watermark keys reloaded, active key: {}, {} keys",
+            key_set.active,
+            key_set.keys.len()
+        );
+        *self.key_set.write().unwrap() = key_set;
+        Ok(())
+    }
+
+    /// Key of the new watermarked generations
+    pub(crate) fn active_key(&self) -> u64 {
+        self.key_set.read().unwrap().active_key()
+    }
+
+    /// Id of the active key and ids of all the keys
+    pub(crate) fn ids(&self) -> (String, Vec<String>) {
+        let key_set = self.key_set.read().unwrap();
+        let ids = key_set.keys.iter().map(|key| key.id.clone()).collect();
+        (key_set.active.clone(), ids)
+    }
+
+    /// Score the tokens of a text with the key `key_id`, or with all the keys. `None` if there
+    /// is no key `key_id`.
+    pub(crate) fn score(
+        &self,
+        input_ids: &[u32],
+        key_id: Option<&str>,
+    ) -> Option<Vec<WatermarkKeyScore>> {
+        let key_set = self.key_set.read().unwrap();
+        let scores: Vec<WatermarkKeyScore> = key_set
+            .keys
+            .iter()
+            .filter(|key| key_id.map_or(true, |key_id| key.id == key_id))
+            .map(|key| {
+                let green_tokens = green_tokens(key.key, input_ids, self.gamma);
+                WatermarkKeyScore {
+                    key_id: key.id.clone(),
+                    green_tokens,
+                    z_score: z_score(green_tokens, scored_tokens(input_ids), self.gamma),
+                }
+            })
+            .collect();
+        (key_id.is_none() || !scores.is_empty()).then_some(scores)
+    }
+}
+
+/// Number of tokens of a text that can be scored: the first one has no previous token
+pub(crate) fn scored_tokens(input_ids: &[u32]) -> u32 {
+    input_ids.len().saturating_sub(1) as u32
+}
+
+/// 32 bits hash of the greenlists, computed the same way by the shards
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 16;
+    x = (x * 0x7FEB_352D) & MASK;
+    x ^= x >> 15;
+    x = (x * 0x6A09_E667) & MASK;
+    x ^ (x >> 16)
+}
+
+/// Whether `token_id` is in the greenlist of the tokens following `prev_token_id`
+fn is_green(key: u64, prev_token_id: u32, token_id: u32, gamma: f64) -> bool {
+    let seed = mix((key ^ (key >> 32) ^ prev_token_id as u64) & MASK);
+    let hash = mix((seed + token_id as u64 * 0x9E37_79B1) & MASK);
+    hash < (gamma * (1u64 << 32) as f64) as u64
+}
+
+fn green_tokens(key: u64, input_ids: &[u32], gamma: f64) -> u32 {
+    input_ids
+        .windows(2)
+        .filter(|pair| is_green(key, pair[0], pair[1], gamma))
+        .count() as u32
+}
+
+fn z_score(green_tokens: u32, scored_tokens: u32, gamma: f64) -> f64 {
+    if scored_tokens == 0 {
+        return 0.0;
+    }
+    let scored_tokens = scored_tokens as f64;
+    let expected = gamma * scored_tokens;
+    (green_tokens as f64 - expected) / (scored_tokens * gamma * (1.0 - gamma)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a keys file written with `content`
+    fn keys_file(name: &str, content: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("tgi-watermark-{name}-{}.json", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_greenlists() {
+        // Same values as the tests of the watermark of the shards
+        let key = 0x0123_4567_89AB_CDEF;
+        let greenlist: Vec<u32> = (0..16).filter(|&id| is_green(key, 42, id, 0.5)).collect();
+        assert_eq!(greenlist, vec![0, 1, 2, 3, 6, 9, 10, 11, 13, 14, 15]);
+        let greenlist: Vec<u32> = (0..16)
+            .filter(|&id| is_green(15485863, 7, id, 0.25))
+            .collect();
+        assert_eq!(greenlist, vec![4, 10, 15]);
+    }
+
+    #[test]
+    fn test_score() {
+        let path = keys_file(
+            "score",
+            r#"{"active": "new", "keys": [{"id": "old", "key": 1}, {"id": "new", "key": 2}]}"#,
+        );
+        let keys = WatermarkKeys::new(Some(path), 0.5).unwrap().unwrap();
+        assert_eq!(keys.active_key(), 2);
+
+        // Greedy watermarked generation: always pick the first green token
+        let mut input_ids = vec![7];
+        while input_ids.len() < 200 {
+            let prev_token_id = *input_ids.last().unwrap();
+            let token_id = (0u32..)
+                .find(|&id| is_green(2, prev_token_id, id, 0.5))
+                .unwrap();
+            input_ids.push(token_id);
+        }
+        let scores = keys.score(&input_ids, None).unwrap();
+        assert_eq!(scores.len(), 2);
+        assert!(scores[0].z_score < DETECTION_Z_SCORE);
+        assert_eq!(scores[1].key_id, "new");
+        assert_eq!(scores[1].green_tokens, 199);
+        assert!(scores[1].z_score > DETECTION_Z_SCORE);
+
+        let scores = keys.score(&input_ids, Some("old")).unwrap();
+        assert_eq!(scores.len(), 1);
+        assert_eq!(scores[0].key_id, "old");
+        assert!(keys.score(&input_ids, Some("missing")).is_none());
+        assert_eq!(keys.score(&[1], None).unwrap()[0].z_score, 0.0);
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = keys_file(
+            "reload",
+            r#"{"active": "a", "keys": [{"id": "a", "key": 1}]}"#,
+        );
+        let keys = WatermarkKeys::new(Some(path.clone()), 0.5)
+            .unwrap()
+            .unwrap();
+
+        // Rotation: the previous key is still listed
+        std::fs::write(
+            &path,
+            r#"{"active": "b", "keys": [{"id": "a", "key": 1}, {"id": "b", "key": 3}]}"#,
+        )
+        .unwrap();
+        keys.reload().unwrap();
+        assert_eq!(keys.active_key(), 3);
+        assert_eq!(
+            keys.ids(),
+            ("b".to_string(), vec!["a".to_string(), "b".to_string()])
+        );
+
+        // An invalid file keeps the current keys
+        std::fs::write(&path, r#"{"active": "c", "keys": [{"id": "a", "key": 1}]}"#).unwrap();
+        assert!(matches!(
+            keys.reload(),
+            Err(WatermarkError::UnknownActive(_))
+        ));
+        assert_eq!(keys.active_key(), 3);
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let path = keys_file(
+            "invalid",
+            r#"{"active": "a", "keys": [{"id": "a", "key": 0}]}"#,
+        );
+        assert!(matches!(
+            WatermarkKeys::new(Some(path.clone()), 0.5),
+            Err(WatermarkError::ZeroKey(_))
+        ));
+        assert!(matches!(
+            WatermarkKeys::new(Some(path), 1.0),
+            Err(WatermarkError::Gamma(_))
+        ));
+        assert!(WatermarkKeys::new(None, 0.5).unwrap().is_none());
+    }
+}
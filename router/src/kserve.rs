@@ -71,7 +71,7 @@ pub struct MetadataServerResponse {
     responses(
         (status = 200, description = "Service is live", body = LiveReponse),
         (status = 404, description = "Service not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"type": "generation", "message": "No response"}}))
     )
 )]
 pub async fn kserve_health_live() -> Json<LiveResponse> {
@@ -86,7 +86,7 @@ pub async fn kserve_health_live() -> Json<LiveResponse> {
     responses(
         (status = 200, description = "Service is ready", body = ReadyResponse),
         (status = 404, description = "Service not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"type": "generation", "message": "No response"}}))
     )
 )]
 pub async fn kserve_health_ready() -> Json<ReadyResponse> {
@@ -101,7 +101,7 @@ pub async fn kserve_health_ready() -> Json<ReadyResponse> {
     responses(
         (status = 200, description = "Metadata retrieved", body = MetadataServerResponse),
         (status = 404, description = "Service not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"type": "generation", "message": "No response"}}))
     )
 )]
 pub async fn kerve_server_metadata() -> Json<MetadataServerResponse> {
@@ -124,7 +124,7 @@ pub async fn kerve_server_metadata() -> Json<MetadataServerResponse> {
     responses(
         (status = 200, description = "Model version metadata retrieved", body = MetadataServerResponse),
         (status = 404, description = "Model or version not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"type": "generation", "message": "No response"}}))
     )
 )]
 pub async fn kserve_model_metadata(
@@ -145,7 +145,7 @@ pub async fn kserve_model_metadata(
     responses(
         (status = 200, description = "Model version is ready", body = ReadyResponse),
         (status = 404, description = "Model or version not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"type": "generation", "message": "No response"}}))
     )
 )]
 pub async fn kserve_model_metadata_ready(
@@ -163,7 +163,7 @@ pub async fn kserve_model_metadata_ready(
     responses(
         (status = 200, description = "Inference executed successfully", body = InferenceOutput),
         (status = 404, description = "Model or version not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"type": "generation", "message": "No response"}}))
     )
 )]
 pub async fn kserve_model_infer(
@@ -179,10 +179,7 @@ pub async fn kserve_model_infer(
             std::str::from_utf8(&input.data).map_err(|e| {
                 (
                     StatusCode::UNPROCESSABLE_ENTITY,
-                    Json(ErrorResponse {
-                        error: e.to_string(),
-                        error_type: "utf8".to_string(),
-                    }),
+                    Json(ErrorResponse::new("validation", e.to_string()).with_param("inputs")),
                 )
             })
         })
@@ -191,10 +188,10 @@ pub async fn kserve_model_infer(
     if str_inputs.len() != payload.outputs.len() {
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Inputs and outputs length mismatch".to_string(),
-                error_type: "length mismatch".to_string(),
-            }),
+            Json(
+                ErrorResponse::new("validation", "Inputs and outputs length mismatch")
+                    .with_param("outputs"),
+            ),
         ));
     }
 
@@ -204,7 +201,10 @@ pub async fn kserve_model_infer(
         .map(|(str_input, output)| {
             let generate_request = GenerateRequest {
                 inputs: str_input.to_string(),
+                template: None,
+                variables: None,
                 parameters: payload.parameters.clone(),
+                add_special_tokens: true,
             };
             let infer = infer.clone();
             let compute_type = compute_type.clone();
@@ -224,10 +224,10 @@ pub async fn kserve_model_infer(
                     .map_err(|_| {
                         (
                             StatusCode::INTERNAL_SERVER_ERROR,
-                            Json(ErrorResponse {
-                                error: "Incomplete generation".into(),
-                                error_type: "Incomplete generation".into(),
-                            }),
+                            Json(ErrorResponse::new(
+                                "incomplete_generation",
+                                "Incomplete generation",
+                            )),
                         )
                     })
             }
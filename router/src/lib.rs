@@ -1,26 +1,42 @@
 /// Text Generation Inference Webserver
 pub mod config;
+mod grpc;
 pub mod infer;
 pub mod server;
 pub mod validation;
 
+mod auth;
+pub mod bench;
+mod idempotency;
 #[cfg(feature = "kserve")]
 mod kserve;
+mod lifecycle;
 pub mod logging;
+mod max_new_tokens;
+mod prompt_templates;
+mod quota;
+mod rate_limit;
+mod request_id;
 
 mod sagemaker;
+mod stream_resume;
+mod tenant;
 pub mod usage_stats;
 mod vertex;
+mod websocket;
 
 use crate::infer::tool_grammar::ToolGrammar;
 use crate::infer::{Infer, InferError};
+use crate::prompt_templates::PromptTemplateVersion;
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use tokenizers::Encoding;
 use tracing::warn;
 use utoipa::ToSchema;
-use validation::Validation;
+use validation::{PromptGroup, Validation};
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
@@ -67,6 +83,12 @@ trait TokenizerTrait {
         query: String,
         add_special_tokens: bool,
     ) -> Result<tokenizers::Encoding, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn decode_trait(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>>;
 }
 
 impl TokenizerTrait for tokenizers::Tokenizer {
@@ -77,6 +99,14 @@ impl TokenizerTrait for tokenizers::Tokenizer {
     ) -> Result<tokenizers::Encoding, Box<dyn std::error::Error + Send + Sync>> {
         self.encode(query, add_special_tokens)
     }
+
+    fn decode_trait(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        self.decode(&ids, skip_special_tokens)
+    }
 }
 
 impl<'a> TokenizerTrait for PyTokenizer<'a> {
@@ -105,6 +135,21 @@ impl<'a> TokenizerTrait for PyTokenizer<'a> {
             std::collections::HashMap::new(), //sequence_ranges
         ))
     }
+
+    fn decode_trait(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let py = self.0.py();
+        let kwargs = [
+            ("token_ids", ids.into_py(py)),
+            ("skip_special_tokens", skip_special_tokens.into_py(py)),
+        ]
+        .into_py_dict_bound(py);
+        let decode = self.0.getattr("decode")?;
+        Ok(decode.call((), Some(&kwargs))?.extract()?)
+    }
 }
 
 /// Hub type
@@ -203,17 +248,248 @@ impl HubProcessorConfig {
 #[derive(Clone, Debug, Deserialize, ToSchema, Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 #[serde(tag = "type", content = "value")]
+#[serde(try_from = "GrammarTypeDeserializer")]
 pub(crate) enum GrammarType {
     /// A string that represents a [JSON Schema](https://json-schema.org/).
     ///
     /// JSON Schema is a declarative language that allows to annotate JSON documents
     /// with types and descriptions.
     #[serde(rename = "json")]
-    #[serde(alias = "json_object")]
     #[schema(example = json ! ({"properties": {"location":{"type": "string"}}}))]
     Json(serde_json::Value),
     #[serde(rename = "regex")]
     Regex(String),
+    /// Any JSON object, checked by a JSON parser instead of a schema compiled to a regex
+    #[serde(rename = "json_object")]
+    JsonObject,
+}
+
+/// Accepts the TGI `{"type": "json", "value": ...}` form as well as the OpenAI
+/// `{"type": "json_schema", "json_schema": {"schema": ...}}` and `{"type": "json_schema", "schema": ...}` forms.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum GrammarTypeDeserializer {
+    #[serde(rename = "json")]
+    Json { value: serde_json::Value },
+    /// With a `value`, the schema of the object like `json`
+    #[serde(rename = "json_object")]
+    JsonObject {
+        #[serde(default)]
+        value: Option<serde_json::Value>,
+    },
+    #[serde(rename = "regex")]
+    Regex { value: String },
+    #[serde(rename = "json_schema")]
+    JsonSchema {
+        #[serde(default)]
+        schema: Option<serde_json::Value>,
+        #[serde(default)]
+        json_schema: Option<JsonSchemaFormat>,
+    },
+}
+
+#[derive(Deserialize)]
+struct JsonSchemaFormat {
+    schema: serde_json::Value,
+}
+
+impl TryFrom<GrammarTypeDeserializer> for GrammarType {
+    type Error = String;
+
+    fn try_from(value: GrammarTypeDeserializer) -> Result<Self, Self::Error> {
+        match value {
+            GrammarTypeDeserializer::Json { value } => Ok(GrammarType::Json(value)),
+            GrammarTypeDeserializer::JsonObject { value } => {
+                Ok(value.map_or(GrammarType::JsonObject, GrammarType::Json))
+            }
+            GrammarTypeDeserializer::Regex { value } => Ok(GrammarType::Regex(value)),
+            GrammarTypeDeserializer::JsonSchema {
+                schema,
+                json_schema,
+            } => match (schema, json_schema) {
+                (Some(schema), None) | (None, Some(JsonSchemaFormat { schema })) => {
+                    Ok(GrammarType::Json(schema))
+                }
+                (None, None) => Err("`json_schema` grammar requires a `schema`".to_string()),
+                (Some(_), Some(_)) => {
+                    Err("`schema` and `json_schema` are mutually exclusive".to_string())
+                }
+            },
+        }
+    }
+}
+
+/// Scheduling class of a request.
+///
+/// Higher classes are batched ahead of lower ones by backends that support it.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, ToSchema, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Background traffic, served when nothing more urgent is queued
+    Low,
+    #[default]
+    Normal,
+    /// Latency sensitive traffic, e.g. interactive chat
+    High,
+}
+
+/// Tokens dropped from a prompt longer than `truncate`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TruncationSide {
+    /// Keep the end of the prompt
+    #[default]
+    Left,
+    /// Keep the start of the prompt
+    Right,
+    /// Keep the start and the end of the prompt, e.g. the instructions and the question around
+    /// a long document
+    Middle,
+}
+
+/// LoRA adapter of a mixture and its weight in the mixture
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, PartialEq)]
+pub struct AdapterWeight {
+    /// Id of one of the LoRA adapters loaded by the server
+    #[schema(example = "predibase/customer_support")]
+    pub id: String,
+    #[schema(example = 0.7)]
+    pub weight: f32,
+}
+
+/// Format of the events of a stream
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamMode {
+    /// One event per token, or per chunk of tokens, in the format of the route
+    #[default]
+    Deltas,
+    /// Typed events of the lifecycle of the generation: `queued`, `prefilling`, `token`,
+    /// `tool_call_delta` and `done`, each with its timestamp
+    Events,
+}
+
+/// Model and kernels of the shards, as reported by the shards of the default model
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ShardInfo {
+    #[schema(example = "torch.float16")]
+    pub model_dtype: String,
+    #[schema(example = "cuda")]
+    pub model_device_type: String,
+    /// Maximum number of positions of the model, if its config sets it
+    #[schema(nullable = true, example = 8192)]
+    pub context_length: Option<u32>,
+    #[schema(example = 128256)]
+    pub vocab_size: u32,
+    /// Quantization of the weights, null if they are not quantized
+    #[schema(nullable = true, example = "awq")]
+    pub quantization: Option<String>,
+    #[schema(example = "flashinfer")]
+    pub attention_impl: String,
+    /// Versions of the kernel packages installed on the shards
+    #[schema(example = json!({"torch": "2.4.0", "flashinfer": "0.1.6"}))]
+    pub kernel_versions: BTreeMap<String, String>,
+}
+
+/// LoRA adapter loaded in the shards
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct LoraAdapterInfo {
+    #[schema(example = "predibase/customer_support")]
+    pub id: String,
+    /// Rank of the adapter, before its sharding
+    #[schema(example = 16)]
+    pub rank: u32,
+    /// Bytes of the weights of the adapter on the device of each shard
+    #[schema(example = 27262976)]
+    pub memory_bytes: u64,
+}
+
+/// LoRA adapter to load with `POST /admin/adapters`
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct LoadAdapterRequest {
+    /// Id of the adapter on the Hub, which the requests then use as `adapter_id`
+    #[schema(example = "predibase/customer_support")]
+    pub id: String,
+    /// Directory of the weights of the adapter on the shards, instead of the Hub
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub path: Option<String>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "main")]
+    pub revision: Option<String>,
+}
+
+/// New shard-sets of a served model to switch over to with `POST /admin/model/swap`, e.g. for
+/// a new revision of the model
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct SwapModelRequest {
+    /// Model whose shards are replaced, the default model if unset
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub model: Option<String>,
+    /// Revision of the model served by the new shard-sets. The router loads its tokenizer,
+    /// chat template and model info, and the launcher starts its shards when no
+    /// `master_shard_uds_paths` are given. The revision of the current shards if unset.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "refs/pr/1")]
+    pub revision: Option<String>,
+    /// Socket of the master shard of the new shard-set of every replica, started separately,
+    /// in the order of the replicas. If empty, the launcher starts the new shard-set of a
+    /// model served by the single replica it started.
+    #[serde(default)]
+    #[schema(example = json!(["/tmp/text-generation-server-v2-0"]))]
+    pub master_shard_uds_paths: Vec<String>,
+    /// GPUs of the shard-set started by the launcher, as in `CUDA_VISIBLE_DEVICES`, so that
+    /// it does not share the GPUs of the current shards. The GPUs of the launcher if unset.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "4,5,6,7")]
+    pub cuda_visible_devices: Option<String>,
+    /// Seconds left to the requests running on the old shard-sets to finish, before they are
+    /// interrupted with an error their clients can retry
+    #[serde(default = "default_drain_timeout_secs")]
+    #[schema(default = 300, example = 300)]
+    pub drain_timeout_secs: u64,
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    300
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SwapState {
+    /// The new shard-sets are started and warmed up, the old ones still serve the requests
+    Starting,
+    /// The new shard-sets serve the requests, the old ones finish theirs before being stopped
+    Draining,
+    /// The old shard-sets are stopped
+    Done,
+    /// The new shard-sets could not be used, the old ones still serve the requests
+    Failed,
+}
+
+/// Progress of the last `POST /admin/model/swap`
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct SwapModelStatus {
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model: String,
+    #[schema(nullable = true, example = "refs/pr/1")]
+    pub revision: Option<String>,
+    pub state: SwapState,
+    /// Replicas switched over to their new shard-set
+    #[schema(example = 1)]
+    pub replicas: usize,
+    /// Requests still running on the old shard-sets
+    #[schema(example = 3)]
+    pub draining: usize,
+    /// Requests of the old shard-sets interrupted at the end of the drain timeout
+    #[schema(example = 0)]
+    pub interrupted: usize,
+    /// Why the new shard-sets could not be used
+    #[schema(nullable = true, example = "null")]
+    pub error: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, ToSchema)]
@@ -223,12 +499,17 @@ pub struct Info {
     pub model_id: String,
     #[schema(nullable = true, example = "e985a63cdc139290c5f700ff1929f0b5942cced2")]
     pub model_sha: Option<String>,
-    // #[schema(example = "torch.float16")]
-    // pub model_dtype: String,
-    // #[schema(example = "cuda")]
-    // pub model_device_type: String,
+    /// Null if the backend does not report it
+    #[schema(nullable = true)]
+    pub shard_info: Option<ShardInfo>,
     #[schema(nullable = true, example = "text-generation")]
     pub model_pipeline_tag: Option<String>,
+    /// Models served by the router, `model_id` first. The `model` field of the OpenAI requests
+    /// or the `adapter_id` parameter selects one of them.
+    #[schema(example = json!(["bigscience/blomm-560m"]))]
+    pub served_models: Vec<String>,
+    /// LoRA adapters currently loaded in the shards of `model_id`
+    pub lora_adapters: Vec<LoraAdapterInfo>,
 
     /// Router Parameters
     #[schema(example = "128")]
@@ -257,7 +538,7 @@ pub struct Info {
     pub docker_label: Option<&'static str>,
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, Default)]
 #[cfg_attr(test, derive(PartialEq))]
 pub(crate) struct GenerateParameters {
     /// Generate best_of sequences and return the one if the highest token logprobs.
@@ -265,6 +546,11 @@ pub(crate) struct GenerateParameters {
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
     pub best_of: Option<usize>,
 
+    /// Return the other `best_of` sequences in `details.best_of_sequences`. Implies `details`.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub return_all_sequences: bool,
+
     /// The value used to module the logits distribution.
     #[serde(default)]
     #[schema(
@@ -286,8 +572,8 @@ pub(crate) struct GenerateParameters {
     )]
     pub repetition_penalty: Option<f32>,
 
-    /// The parameter for frequency penalty. 1.0 means no penalty
-    /// Penalize new tokens based on their existing frequency in the text so far,
+    /// The parameter for frequency penalty. 0.0 means no penalty
+    /// Penalize new tokens based on their existing frequency in the generated text so far,
     /// decreasing the model's likelihood to repeat the same line verbatim.
     #[serde(default)]
     #[schema(
@@ -298,6 +584,18 @@ pub(crate) struct GenerateParameters {
     )]
     pub frequency_penalty: Option<f32>,
 
+    /// The parameter for presence penalty. 0.0 means no penalty
+    /// Penalize new tokens once if they appear in the generated text so far, whatever their
+    /// frequency, increasing the model's likelihood to talk about new topics.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = -2.0,
+        nullable = true,
+        default = "null",
+        example = 0.1
+    )]
+    pub presence_penalty: Option<f32>,
+
     /// The number of highest probability vocabulary tokens to keep for top-k-filtering.
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
@@ -326,6 +624,46 @@ pub(crate) struct GenerateParameters {
     )]
     pub typical_p: Option<f32>,
 
+    /// Minimum probability of a token, relative to the probability of the most likely token.
+    /// Tokens less likely than `min_p` times the most likely one are filtered out.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.05
+    )]
+    pub min_p: Option<f32>,
+
+    /// Size of the n-grams that can only occur once in the text, prompt included: the tokens
+    /// that would repeat one are banned. Unlike the penalties, it stops short loops outright.
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 3)]
+    pub no_repeat_ngram_size: Option<u32>,
+
+    /// Scale of classifier-free guidance: the tokens are chosen from the logits of the prompt
+    /// pushed away from the logits of `negative_prompt`, 1.0 leaving them unchanged. The
+    /// negative prompt runs as a second sequence next to the request.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        nullable = true,
+        default = "null",
+        example = 1.5
+    )]
+    pub guidance_scale: Option<f32>,
+
+    /// Prompt that the generation is guided away from, with `guidance_scale`. Defaults to an
+    /// empty prompt, so that the generation is guided away from the unconditional one.
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        example = "A dull and repetitive answer."
+    )]
+    pub negative_prompt: Option<String>,
+
     /// Activate logits sampling.
     #[serde(default)]
     #[schema(default = "false", example = true)]
@@ -336,6 +674,13 @@ pub(crate) struct GenerateParameters {
     #[schema(nullable = true, default = "1024", example = "20")]
     pub max_new_tokens: Option<u32>,
 
+    /// Minimum number of tokens to generate. The end of sequence token, `stop` and
+    /// `stop_token_ids` do not end the generation before it. Must be <= `max_new_tokens`, and
+    /// cannot be combined with a `grammar`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub min_new_tokens: Option<u32>,
+
     /// Whether to prepend the prompt to the generated text
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = false)]
@@ -343,14 +688,28 @@ pub(crate) struct GenerateParameters {
 
     /// Stop generating tokens if a member of `stop` is generated.
     #[serde(default)]
-    #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
+    #[schema(inline, max_items = 16, example = json ! (["photographer"]))]
     pub stop: Vec<String>,
 
+    /// Stop generating tokens if a member of `stop_token_ids` is generated. Unlike `stop`, the
+    /// ids are matched before detokenization, the stop token is kept in the generated text.
+    #[serde(default)]
+    #[schema(inline, max_items = 16, example = json ! ([128009]))]
+    pub stop_token_ids: Vec<u32>,
+
     /// Truncate inputs tokens to the given size.
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub truncate: Option<usize>,
 
+    /// Side the tokens of a prompt longer than `truncate` are dropped from, `left` by default.
+    /// When set without `truncate`, a prompt that does not fit the limits of the server is
+    /// truncated to fit instead of being rejected. `right` and `middle` require a fast
+    /// tokenizer and a text-only prompt.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "middle")]
+    pub truncation_side: Option<TruncationSide>,
+
     /// Watermarking with [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226).
     #[serde(default)]
     #[schema(default = "false", example = true)]
@@ -367,6 +726,8 @@ pub(crate) struct GenerateParameters {
     pub decoder_input_details: bool,
 
     /// Random sampling seed.
+    /// A request sampled with the same seed, inputs and parameters generates the same tokens
+    /// whatever the other requests batched with it.
     #[serde(default)]
     #[schema(
         exclusive_minimum = 0,
@@ -386,26 +747,130 @@ pub(crate) struct GenerateParameters {
     #[schema(nullable = true, default = "null", example = "null")]
     pub grammar: Option<GrammarType>,
 
-    /// Lora adapter id
+    /// Lora adapter id, or the name of one of the `served_models` of `/info`
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub adapter_id: Option<String>,
+
+    /// LoRA adapters applied together, each scaled by its weight: the generation uses the
+    /// weighted sum of their updates. Cannot be combined with `adapter_id`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!([
+        {"id": "predibase/customer_support", "weight": 0.7},
+        {"id": "predibase/conllpp", "weight": 0.3}
+    ]))]
+    pub adapters: Option<Vec<AdapterWeight>>,
+
+    /// Scheduling priority of the request.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "high")]
+    pub priority: Option<Priority>,
+
+    /// Maximum number of speculative tokens accepted per decoding step.
+    /// Must not exceed the model speculation. `0` disables speculation for this request.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = 0)]
+    pub speculate: Option<u32>,
+
+    /// Conversation the request belongs to. The KV cache of the request is kept after it
+    /// completes, so that the next request of the same session only prefills its new tokens.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "conversation-42")]
+    pub session_id: Option<String>,
+
+    /// `x-request-id` of a finished request to continue: its prompt and generated text are
+    /// prepended to the inputs, and the request joins its session, if any, to reuse the KV
+    /// cache instead of prefilling the text again. Only the latest requests can be continued.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "3f9c2b1e")]
+    pub continue_from: Option<String>,
+
+    /// Wall-clock budget of the request in milliseconds, counted from its admission. Once it
+    /// is spent, the generation ends with the `timeout` finish reason. A request that did not
+    /// generate any token within the budget fails.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = 30000
+    )]
+    pub timeout_ms: Option<u64>,
+
+    /// Time in milliseconds, counted from its admission, by which the request must leave the
+    /// queue. The `edf` scheduling policy prefers the requests whose deadline is close, and a
+    /// request still queued at its deadline fails with the `deadline_exceeded` error.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = 2000
+    )]
+    pub deadline_ms: Option<u64>,
+
+    /// Back up the last token of the prompt and constrain the first generated token to start
+    /// with its text, so that a prompt ending in the middle of a word or with a trailing space
+    /// does not skew the generation. Requires a fast tokenizer and a text-only prompt.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub token_healing: bool,
+
+    /// Bias added to the logits of tokens before sampling, keyed by token id. Values range from
+    /// -100 to 100: -100 bans a token, 100 makes it almost certain.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"1": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+
+    /// Opaque object recorded in the traces and the audit log of the request, and sent back in
+    /// its response. It does not change the generation.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"user_session": "abc-123"}))]
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
+
+    /// Format of the events of `/generate_stream`
+    #[serde(default)]
+    #[schema(default = "deltas", example = "events")]
+    pub stream_mode: StreamMode,
+
+    /// Set internally on the streamed requests, whose `max_new_tokens` can have another ceiling
+    #[serde(skip)]
+    pub stream: bool,
+
+    /// Set internally by the OpenAI routes generating `n` choices for the prompt. The choices
+    /// after the first one are returned in `details.best_of_sequences`.
+    #[serde(skip)]
+    pub n: Option<u32>,
+
+    /// Set internally on the requests generating the sequences of one prompt, so that the
+    /// backend prefills it once for all of them
+    #[serde(skip)]
+    pub prompt_group: Option<PromptGroup>,
 }
 
 fn default_parameters() -> GenerateParameters {
     GenerateParameters {
         best_of: None,
+        return_all_sequences: false,
         temperature: None,
         repetition_penalty: None,
         frequency_penalty: None,
+        presence_penalty: None,
         top_k: None,
         top_p: None,
         typical_p: None,
+        min_p: None,
+        no_repeat_ngram_size: None,
+        guidance_scale: None,
+        negative_prompt: None,
         do_sample: true,
         max_new_tokens: None,
+        min_new_tokens: None,
         return_full_text: None,
         stop: Vec::new(),
+        stop_token_ids: Vec::new(),
         truncate: None,
+        truncation_side: None,
         watermark: false,
         details: false,
         decoder_input_details: false,
@@ -413,6 +878,20 @@ fn default_parameters() -> GenerateParameters {
         top_n_tokens: None,
         grammar: None,
         adapter_id: None,
+        adapters: None,
+        priority: None,
+        speculate: None,
+        session_id: None,
+        continue_from: None,
+        timeout_ms: None,
+        deadline_ms: None,
+        token_healing: false,
+        logit_bias: None,
+        metadata: None,
+        stream_mode: StreamMode::Deltas,
+        stream: false,
+        n: None,
+        prompt_group: None,
     }
 }
 
@@ -475,6 +954,22 @@ pub struct CompletionRequest {
     #[schema(nullable = true, example = 0.95)]
     pub top_p: Option<f32>,
 
+    /// Typical Decoding mass
+    /// See [Typical Decoding for Natural Language Generation](https://arxiv.org/abs/2202.00666) for more information.
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.95)]
+    pub typical_p: Option<f32>,
+
+    /// Only sample from the tokens whose probability is at least `min_p` times the probability of the most likely token.
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.05)]
+    pub min_p: Option<f32>,
+
+    /// Watermark the generated text, with the watermark that `/watermark/verify` detects.
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub watermark: bool,
+
     #[serde(default = "bool::default")]
     pub stream: bool,
 
@@ -495,10 +990,52 @@ pub struct CompletionRequest {
     #[schema(example = "1.0")]
     pub frequency_penalty: Option<f32>,
 
-    /// Up to 4 sequences where the API will stop generating further tokens.
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far,
+    /// increasing the model's likelihood to talk about new topics
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.1)]
+    pub presence_penalty: Option<f32>,
+
+    /// Up to 16 sequences where the API will stop generating further tokens.
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub stop: Option<Vec<String>>,
+
+    /// Up to 16 token ids where the API will stop generating further tokens.
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub stop_token_ids: Option<Vec<u32>>,
+
+    /// Minimum number of tokens to generate, before which the end of sequence token, `stop`
+    /// and `stop_token_ids` do not end the generation. Must be <= `max_tokens`, and cannot be
+    /// combined with a grammar.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub min_tokens: Option<u32>,
+
+    /// Include the log probabilities of the generated tokens, along with the `logprobs` most likely alternatives
+    /// at each position.
+    #[serde(default)]
+    #[schema(nullable = true, example = 5)]
+    pub logprobs: Option<u32>,
+
+    /// Echo back the prompt in addition to the completion. With `logprobs`, the log probabilities of the
+    /// prompt tokens are returned too.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub echo: bool,
+
+    /// Generates `best_of` completions server-side and returns the one with the highest cumulative log probability.
+    /// Cannot be used when streaming.
+    #[serde(default)]
+    #[schema(nullable = true, example = 1)]
+    pub best_of: Option<usize>,
+
+    /// How many completions to generate for each prompt, returned as separate choices. The completions share the
+    /// prefill of the prompt when prefix caching is enabled. Cannot be used with `best_of`.
+    #[serde(default)]
+    #[schema(nullable = true, example = 2)]
+    pub n: Option<u32>,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -526,10 +1063,99 @@ pub(crate) struct CompletionFinal {
 pub(crate) struct CompletionComplete {
     pub index: u32,
     pub text: String,
-    pub logprobs: Option<Vec<f32>>,
+    pub logprobs: Option<CompletionLogprobs>,
     pub finish_reason: String,
 }
 
+impl CompletionComplete {
+    /// Choices of one prompt from `first_index`: its generated text, then the other sequences
+    /// generated for the prompt. The logprobs include the ones of the prompt when it is echoed.
+    pub(crate) fn prompt_choices(
+        first_index: usize,
+        generated_text: String,
+        details: Details,
+        logprobs: bool,
+        echo: bool,
+    ) -> Vec<Self> {
+        let first = BestOfSequence {
+            generated_text,
+            finish_reason: details.finish_reason,
+            generated_tokens: details.generated_tokens,
+            seed: details.seed,
+            prefill: details.prefill,
+            tokens: details.tokens,
+            top_tokens: details.top_tokens,
+        };
+        std::iter::once(first)
+            .chain(details.best_of_sequences.unwrap_or_default())
+            .enumerate()
+            .map(|(i, sequence)| {
+                let prefill: &[PrefillToken] = if echo { &sequence.prefill } else { &[] };
+                Self {
+                    index: (first_index + i) as u32,
+                    logprobs: logprobs.then(|| {
+                        CompletionLogprobs::new(prefill, &sequence.tokens, &sequence.top_tokens, 0)
+                    }),
+                    finish_reason: sequence.finish_reason.format(true),
+                    text: sequence.generated_text,
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, ToSchema, Default, Debug, PartialEq)]
+pub(crate) struct CompletionLogprobs {
+    #[schema(example = json ! (["Deep", " Learning"]))]
+    pub tokens: Vec<String>,
+    /// `null` for the first token of an echoed prompt
+    pub token_logprobs: Vec<Option<f32>>,
+    /// `null` for echoed prompt tokens
+    pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+    /// Character offset of each token in the returned text
+    pub text_offset: Vec<u32>,
+}
+
+impl CompletionLogprobs {
+    /// Logprobs of `prefill` followed by `tokens`, the first token starting at `text_offset`
+    pub(crate) fn new(
+        prefill: &[PrefillToken],
+        tokens: &[Token],
+        top_tokens: &[Vec<Token>],
+        mut text_offset: u32,
+    ) -> Self {
+        let mut logprobs = CompletionLogprobs::default();
+        let mut push = |text: &str, logprob: Option<f32>, top: Option<HashMap<String, f32>>| {
+            logprobs.tokens.push(text.to_string());
+            logprobs.token_logprobs.push(logprob);
+            logprobs.top_logprobs.push(top);
+            logprobs.text_offset.push(text_offset);
+            text_offset += text.chars().count() as u32;
+        };
+
+        for token in prefill {
+            push(
+                &token.text,
+                (!token.logprob.is_nan()).then_some(token.logprob),
+                None,
+            );
+        }
+        for (i, token) in tokens.iter().enumerate() {
+            let top = top_tokens
+                .get(i)
+                .filter(|t| !t.is_empty())
+                .map(|top_tokens| {
+                    top_tokens
+                        .iter()
+                        .map(|t| (t.text.clone(), t.logprob))
+                        .collect()
+                });
+            push(&token.text, Some(token.logprob), top);
+        }
+        logprobs
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub(crate) struct Chunk {
     pub id: String,
@@ -549,6 +1175,13 @@ pub(crate) struct ChatCompletion {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionComplete>,
     pub usage: Usage,
+    /// `metadata` of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json!({"user_session": "abc-123"}))]
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
+    /// Changes made to the request to fit the limits of the model
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
@@ -635,6 +1268,84 @@ pub(crate) struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    /// Timings of the generation, only sent at the end of a stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub timings: Option<GenerationTimings>,
+}
+
+impl Usage {
+    pub(crate) fn from_stream_details(details: &StreamDetails) -> Self {
+        Self {
+            prompt_tokens: details.input_length,
+            completion_tokens: details.generated_tokens,
+            total_tokens: details.input_length + details.generated_tokens,
+            timings: Some(details.timings.clone()),
+        }
+    }
+
+    /// Count the tokens of another choice generated for the same prompt, whose timings are
+    /// the ones of the first choice
+    pub(crate) fn add_choice(&mut self, choice: &Usage) {
+        self.completion_tokens += choice.completion_tokens;
+        self.total_tokens += choice.completion_tokens;
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, Default, PartialEq)]
+pub(crate) struct GenerationTimings {
+    /// Seconds between the validation of the request and the start of its prefill
+    #[schema(example = 0.012)]
+    pub queue_time: f64,
+    /// Seconds between the start of the prefill and the first generated token
+    #[schema(example = 0.154)]
+    pub prefill_time: f64,
+    /// Seconds between the first generated token and the last one
+    #[schema(example = 0.081)]
+    pub decode_time: f64,
+    /// Generated tokens per second of inference, prefill included, 0 when the inference was
+    /// too short to be measured
+    #[schema(example = 42.5)]
+    pub tokens_per_second: f64,
+    /// Forward passes of the model that generated the tokens of the request
+    #[schema(example = 10)]
+    pub batches: u32,
+    /// Mean number of requests in these batches, this request included
+    #[schema(example = 3.5)]
+    pub mean_batch_size: f64,
+    /// Number of requests in the largest of these batches
+    #[schema(example = 6)]
+    pub max_batch_size: u32,
+}
+
+impl GenerationTimings {
+    /// Timings of a generation queued at `queued`, prefilled from `start` to `first_token` and
+    /// finished at `end`
+    pub(crate) fn new(
+        queued: tokio::time::Instant,
+        start: tokio::time::Instant,
+        first_token: tokio::time::Instant,
+        end: tokio::time::Instant,
+        generated_text: &infer::GeneratedText,
+    ) -> Self {
+        let batches = &generated_text.batches;
+        // A zero duration would give an infinite rate, that JSON has no number for
+        let inference_time = (end - start).as_secs_f64();
+        let tokens_per_second = if inference_time > 0.0 {
+            generated_text.generated_tokens as f64 / inference_time
+        } else {
+            0.0
+        };
+        Self {
+            queue_time: (start - queued).as_secs_f64(),
+            prefill_time: (first_token - start).as_secs_f64(),
+            decode_time: (end - first_token).as_secs_f64(),
+            tokens_per_second,
+            batches: batches.batches,
+            mean_batch_size: batches.mean_batch_size(),
+            max_batch_size: batches.max_batch_size,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -658,30 +1369,6 @@ impl ChatCompletion {
         tool_calls: Option<Vec<ToolCall>>,
         prompt_tokens: u32,
     ) -> Self {
-        let message = match (output, tool_calls) {
-            (Some(content), None) => OutputMessage::ChatMessage(TextMessage {
-                role: "assistant".into(),
-                content,
-            }),
-            (None, Some(tool_calls)) => OutputMessage::ToolCall(ToolCallMessage {
-                role: "assistant".to_string(),
-                tool_calls,
-            }),
-            (Some(output), Some(_)) => {
-                warn!("Received both chat and tool call");
-                OutputMessage::ChatMessage(TextMessage {
-                    role: "assistant".into(),
-                    content: output,
-                })
-            }
-            (None, None) => {
-                warn!("Didn't receive an answer");
-                OutputMessage::ChatMessage(TextMessage {
-                    role: "assistant".into(),
-                    content: "".to_string(),
-                })
-            }
-        };
         Self {
             id: String::new(),
             created,
@@ -689,7 +1376,7 @@ impl ChatCompletion {
             system_fingerprint,
             choices: vec![ChatCompletionComplete {
                 index: 0,
-                message,
+                message: OutputMessage::new(output, tool_calls),
                 logprobs: return_logprobs
                     .then(|| ChatCompletionLogprobs::from((details.tokens, details.top_tokens))),
                 finish_reason: details.finish_reason.format(true),
@@ -698,10 +1385,33 @@ impl ChatCompletion {
                 prompt_tokens,
                 completion_tokens: details.generated_tokens,
                 total_tokens: prompt_tokens + details.generated_tokens,
+                timings: None,
             },
+            metadata: None,
+            warnings: Vec::new(),
         }
     }
+
+    /// Add the choice of another sequence generated for the prompt
+    pub(crate) fn push_choice(
+        &mut self,
+        output: Option<String>,
+        tool_calls: Option<Vec<ToolCall>>,
+        sequence: BestOfSequence,
+        return_logprobs: bool,
+    ) {
+        self.choices.push(ChatCompletionComplete {
+            index: self.choices.len() as u32,
+            message: OutputMessage::new(output, tool_calls),
+            logprobs: return_logprobs
+                .then(|| ChatCompletionLogprobs::from((sequence.tokens, sequence.top_tokens))),
+            finish_reason: sequence.finish_reason.format(true),
+        });
+        self.usage.completion_tokens += sequence.generated_tokens;
+        self.usage.total_tokens += sequence.generated_tokens;
+    }
 }
+
 #[derive(Clone, Serialize, ToSchema)]
 pub(crate) struct ChatCompletionChunk {
     pub id: String,
@@ -712,6 +1422,14 @@ pub(crate) struct ChatCompletionChunk {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionChoice>,
     pub usage: Option<Usage>,
+    /// `metadata` of the request, sent with the chunk of the finish reason
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json!({"user_session": "abc-123"}))]
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
+    /// Changes made to the request to fit the limits of the model, sent with the chunk of the
+    /// finish reason
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -796,6 +1514,35 @@ impl ChatCompletionChunk {
                 finish_reason,
             }],
             usage,
+            metadata: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Chunk of the choice `index` of the completion
+    pub(crate) fn with_index(mut self, index: u32) -> Self {
+        for choice in &mut self.choices {
+            choice.index = index;
+        }
+        self
+    }
+
+    /// Last chunk of a stream with `stream_options.include_usage`, without any choice
+    pub(crate) fn usage(
+        model: String,
+        system_fingerprint: String,
+        created: u64,
+        usage: Usage,
+    ) -> Self {
+        Self {
+            id: String::new(),
+            created,
+            model,
+            system_fingerprint,
+            choices: vec![],
+            usage: Some(usage),
+            metadata: None,
+            warnings: Vec::new(),
         }
     }
 }
@@ -817,14 +1564,14 @@ pub(crate) struct ChatRequest {
     #[schema(example = "1.0")]
     pub frequency_penalty: Option<f32>,
 
-    /// UNUSED
     /// Modify the likelihood of specified tokens appearing in the completion. Accepts a JSON object that maps tokens
     /// (specified by their token ID in the tokenizer) to an associated bias value from -100 to 100. Mathematically,
     /// the bias is added to the logits generated by the model prior to sampling. The exact effect will vary per model,
     /// but values between -1 and 1 should decrease or increase likelihood of selection; values like -100 or 100 should
     /// result in a ban or exclusive selection of the relevant token.
     #[serde(default)]
-    pub logit_bias: Option<Vec<f32>>,
+    #[schema(nullable = true, example = json!({"1": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
 
     /// Whether to return log probabilities of the output tokens or not. If true, returns the log probabilities of each
     /// output token returned in the content of message.
@@ -843,9 +1590,8 @@ pub(crate) struct ChatRequest {
     #[schema(default = "1024", example = "32")]
     pub max_tokens: Option<u32>,
 
-    /// UNUSED
-    /// How many chat completion choices to generate for each input message. Note that you will be charged based on the
-    /// number of generated tokens across all of the choices. Keep n as 1 to minimize costs.
+    /// How many chat completion choices to generate for each input message. The choices share the prefill of the prompt
+    /// when prefix caching is enabled. Cannot be used with the `events` stream mode.
     #[serde(default)]
     #[schema(nullable = true, example = "2")]
     pub n: Option<u32>,
@@ -856,11 +1602,23 @@ pub(crate) struct ChatRequest {
     #[schema(nullable = true, example = 0.1)]
     pub presence_penalty: Option<f32>,
 
-    /// Up to 4 sequences where the API will stop generating further tokens.
+    /// Up to 16 sequences where the API will stop generating further tokens.
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub stop: Option<Vec<String>>,
 
+    /// Up to 16 token ids where the API will stop generating further tokens.
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub stop_token_ids: Option<Vec<u32>>,
+
+    /// Minimum number of tokens to generate, before which the end of sequence token, `stop`
+    /// and `stop_token_ids` do not end the generation. Must be <= `max_tokens`, and cannot be
+    /// combined with a grammar.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub min_tokens: Option<u32>,
+
     #[serde(default = "bool::default")]
     pub stream: bool,
 
@@ -881,6 +1639,22 @@ pub(crate) struct ChatRequest {
     #[schema(nullable = true, example = 0.95)]
     pub top_p: Option<f32>,
 
+    /// Typical Decoding mass
+    /// See [Typical Decoding for Natural Language Generation](https://arxiv.org/abs/2202.00666) for more information.
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.95)]
+    pub typical_p: Option<f32>,
+
+    /// Only sample from the tokens whose probability is at least `min_p` times the probability of the most likely token.
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.05)]
+    pub min_p: Option<f32>,
+
+    /// Watermark the generated text, with the watermark that `/watermark/verify` detects.
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub watermark: bool,
+
     /// A list of tools the model may call. Currently, only functions are supported as a tool. Use this to provide a list of
     /// functions the model may generate JSON inputs for.
     #[serde(default)]
@@ -911,6 +1685,51 @@ pub(crate) struct ChatRequest {
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub stream_options: Option<StreamOptions>,
+
+    /// Format of the events of the stream. With `events`, the stream sends typed lifecycle
+    /// events instead of the chat completion chunks.
+    #[serde(default)]
+    #[schema(default = "deltas", example = "events")]
+    pub stream_mode: StreamMode,
+
+    /// Scheduling priority of the request.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "high")]
+    pub priority: Option<Priority>,
+
+    /// Conversation the request belongs to. The KV cache of the request is kept after it
+    /// completes, so that the next turn of the conversation only prefills the new messages.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "conversation-42")]
+    pub session_id: Option<String>,
+
+    /// Wall-clock budget of the request in milliseconds. Once it is spent, the completion ends
+    /// with the `timeout` finish reason.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = 30000
+    )]
+    pub timeout_ms: Option<u64>,
+
+    /// Time in milliseconds by which the request must leave the queue, or fail with the
+    /// `deadline_exceeded` error.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = 2000
+    )]
+    pub deadline_ms: Option<u64>,
+
+    /// Opaque object recorded in the traces and the audit log of the request, and sent back in
+    /// its response.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"user_session": "abc-123"}))]
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
 }
 
 impl ChatRequest {
@@ -921,6 +1740,8 @@ impl ChatRequest {
             messages,
             seed,
             stop,
+            stop_token_ids,
+            min_tokens,
             tools,
             tool_choice,
             tool_prompt,
@@ -929,16 +1750,28 @@ impl ChatRequest {
             presence_penalty,
             frequency_penalty,
             top_p,
+            typical_p,
+            min_p,
+            watermark,
             top_logprobs,
+            priority,
+            session_id,
+            timeout_ms,
+            deadline_ms,
+            logit_bias,
+            metadata,
+            stream,
+            stream_mode,
+            n,
             ..
         } = self;
 
-        let repetition_penalty = presence_penalty.map(|x| x + 2.0);
         let max_new_tokens = max_tokens;
         let tool_prompt = tool_prompt
             .filter(|s| !s.is_empty())
             .unwrap_or_else(default_tool_prompt);
         let stop = stop.unwrap_or_default();
+        let stop_token_ids = stop_token_ids.unwrap_or_default();
         // enable greedy only when temperature is 0
         let (do_sample, temperature) = match temperature {
             Some(temperature) if temperature == 0.0 => (false, None),
@@ -953,7 +1786,7 @@ impl ChatRequest {
 
         let (inputs, grammar, using_tools) = match response_format {
             Some(format) => {
-                let inputs = infer.apply_chat_template(messages, None)?;
+                let inputs = infer.apply_chat_template(model.as_deref(), messages, None)?;
                 (inputs, Some(format), false)
             }
             None => {
@@ -962,6 +1795,7 @@ impl ChatRequest {
                         Some((updated_tools, tool_schema)) => {
                             let grammar = GrammarType::Json(serde_json::json!(tool_schema));
                             let inputs: String = infer.apply_chat_template(
+                                model.as_deref(),
                                 messages,
                                 Some((updated_tools, tool_prompt)),
                             )?;
@@ -969,13 +1803,14 @@ impl ChatRequest {
                         }
                         None => {
                             // same as if no response_format or tools are set
-                            let inputs = infer.apply_chat_template(messages, None)?;
+                            let inputs =
+                                infer.apply_chat_template(model.as_deref(), messages, None)?;
                             (inputs, None, false)
                         }
                     }
                 } else {
                     // if no response_format or tools are set simply apply the chat template to generate inputs
-                    let inputs = infer.apply_chat_template(messages, None)?;
+                    let inputs = infer.apply_chat_template(model.as_deref(), messages, None)?;
                     (inputs, None, false)
                 }
             }
@@ -984,27 +1819,52 @@ impl ChatRequest {
         Ok((
             GenerateRequest {
                 inputs: inputs.to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: false,
                 parameters: GenerateParameters {
                     best_of: None,
+                    return_all_sequences: false,
                     temperature,
-                    repetition_penalty,
+                    repetition_penalty: None,
                     frequency_penalty,
+                    presence_penalty,
                     top_k: None,
                     top_p,
-                    typical_p: None,
+                    typical_p,
+                    min_p,
+                    no_repeat_ngram_size: None,
+                    guidance_scale: None,
+                    negative_prompt: None,
                     do_sample,
                     max_new_tokens,
+                    min_new_tokens: min_tokens,
                     return_full_text: None,
                     stop,
+                    stop_token_ids,
                     truncate: None,
-                    watermark: false,
+                    truncation_side: None,
+                    watermark,
                     details: true,
                     decoder_input_details: false,
                     seed,
                     top_n_tokens: top_logprobs,
                     grammar,
                     adapter_id: model.filter(|m| *m != "tgi").map(String::from),
+                    adapters: None,
+                    priority,
+                    speculate: None,
+                    session_id,
+                    continue_from: None,
+                    timeout_ms,
+                    deadline_ms,
+                    token_healing: false,
+                    logit_bias,
+                    metadata,
+                    stream_mode,
+                    stream,
+                    n,
+                    prompt_group: None,
                 },
             },
             using_tools,
@@ -1016,8 +1876,13 @@ impl ChatRequest {
 #[cfg_attr(test, derive(Debug, PartialEq))]
 struct StreamOptions {
     /// If set, an additional chunk will be streamed before the data: [DONE] message. The usage field on this chunk shows the token usage statistics for the entire request, and the choices field will always be an empty array. All other chunks will also include a usage field, but with a null value.
+    #[serde(default)]
     #[schema(example = "true")]
     include_usage: bool,
+    /// Number of generated tokens to coalesce into each streamed chunk. Defaults to 1, which sends one chunk per token. The final chunk may hold fewer tokens.
+    #[serde(default)]
+    #[schema(nullable = true, example = "4", minimum = 1)]
+    chunk_tokens: Option<u32>,
 }
 
 pub fn default_tool_prompt() -> String {
@@ -1251,13 +2116,52 @@ pub(crate) enum OutputMessage {
     ToolCall(ToolCallMessage),
 }
 
+impl OutputMessage {
+    fn new(output: Option<String>, tool_calls: Option<Vec<ToolCall>>) -> Self {
+        match (output, tool_calls) {
+            (Some(content), None) => OutputMessage::ChatMessage(TextMessage {
+                role: "assistant".into(),
+                content,
+            }),
+            (None, Some(tool_calls)) => OutputMessage::ToolCall(ToolCallMessage {
+                role: "assistant".to_string(),
+                tool_calls,
+            }),
+            (Some(output), Some(_)) => {
+                warn!("Received both chat and tool call");
+                OutputMessage::ChatMessage(TextMessage {
+                    role: "assistant".into(),
+                    content: output,
+                })
+            }
+            (None, None) => {
+                warn!("Didn't receive an answer");
+                OutputMessage::ChatMessage(TextMessage {
+                    role: "assistant".into(),
+                    content: "".to_string(),
+                })
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 #[cfg_attr(test, derive(PartialEq))]
 pub(crate) struct GenerateRequest {
+    /// Empty when `template` is set
+    #[serde(default)]
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
+    /// Name of a prompt template of the server, rendered with `variables` into the inputs
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "summarize_v2")]
+    pub template: Option<String>,
+    /// Variables of `template`, which must set all the variables it uses and only those
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"text": "My name is Olivier and I"}))]
+    pub variables: Option<HashMap<String, serde_json::Value>>,
 
     /// This is used internally because some requests
     /// already contain the templated input therefore
@@ -1272,11 +2176,18 @@ fn default_true() -> bool {
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct CompatGenerateRequest {
+    #[serde(default)]
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
     #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "summarize_v2")]
+    pub template: Option<String>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"text": "My name is Olivier and I"}))]
+    pub variables: Option<HashMap<String, serde_json::Value>>,
+    #[serde(default)]
     #[schema(default = "false")]
     pub stream: bool,
 }
@@ -1287,11 +2198,13 @@ impl From<CompatGenerateRequest> for GenerateRequest {
             inputs: req.inputs,
             add_special_tokens: true,
             parameters: req.parameters,
+            template: req.template,
+            variables: req.variables,
         }
     }
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
 pub struct PrefillToken {
     #[schema(example = 0)]
     pub id: u32,
@@ -1301,7 +2214,7 @@ pub struct PrefillToken {
     pub logprob: f32,
 }
 
-#[derive(Debug, Serialize, ToSchema, Clone)]
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
 pub struct Token {
     #[schema(example = 0)]
     pub id: u32,
@@ -1323,10 +2236,13 @@ pub struct SimpleToken {
     start: usize,
     #[schema(example = 2)]
     stop: usize,
+    /// Whether the token is a special token added by the tokenizer, e.g. the BOS token
+    #[schema(example = false)]
+    special: bool,
 }
 
-#[derive(Debug, Serialize, ToSchema, Clone)]
-#[serde(rename_all(serialize = "snake_case"))]
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
+#[serde(rename_all = "snake_case")]
 #[schema(example = "Length")]
 pub enum FinishReason {
     #[schema(rename = "length")]
@@ -1336,6 +2252,12 @@ pub enum FinishReason {
     EndOfSequenceToken,
     #[schema(rename = "stop_sequence")]
     StopSequence,
+    #[schema(rename = "timeout")]
+    Timeout,
+    #[schema(rename = "content_filter")]
+    ContentFilter,
+    #[schema(rename = "cancelled")]
+    Cancelled,
 }
 
 impl std::fmt::Display for FinishReason {
@@ -1344,6 +2266,9 @@ impl std::fmt::Display for FinishReason {
             FinishReason::Length => write!(f, "length"),
             FinishReason::EndOfSequenceToken => write!(f, "eos_token"),
             FinishReason::StopSequence => write!(f, "stop_sequence"),
+            FinishReason::Timeout => write!(f, "timeout"),
+            FinishReason::ContentFilter => write!(f, "content_filter"),
+            FinishReason::Cancelled => write!(f, "cancelled"),
         }
     }
 }
@@ -1387,6 +2312,10 @@ pub(crate) struct Details {
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub top_tokens: Vec<Vec<Token>>,
+    pub timings: GenerationTimings,
+    /// Prompt template of the request, if it named one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_template: Option<PromptTemplateVersion>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1395,6 +2324,31 @@ pub(crate) struct GenerateResponse {
     pub generated_text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub details: Option<Details>,
+    /// `metadata` of the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json!({"user_session": "abc-123"}))]
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
+    /// Changes made to the request to fit the limits of the model
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["`max_new_tokens` was lowered from 8192 to 4096, the maximum of the model"]))]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct GenerateBatchRequest {
+    /// Prompts, generated independently with the same parameters
+    #[schema(example = json!(["My name is Olivier and I", "The capital of France is"]))]
+    pub inputs: Vec<String>,
+    #[serde(default = "default_parameters")]
+    pub parameters: GenerateParameters,
+}
+
+/// Result of one of the prompts of a batch: its generation, or the error of its request
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum GenerateBatchResult {
+    Generation(GenerateResponse),
+    Error(ErrorResponse),
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1407,6 +2361,363 @@ pub(crate) struct ChatTokenizeResponse {
 #[serde(transparent)]
 pub(crate) struct TokenizeResponse(Vec<SimpleToken>);
 
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct DetokenizeRequest {
+    /// Token ids to decode
+    #[schema(example = json!([1, 15043, 3186]))]
+    pub ids: Vec<u32>,
+
+    /// Leave the special tokens out of the text
+    #[serde(default = "default_true")]
+    #[schema(default = "true", example = true)]
+    pub skip_special_tokens: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DetokenizeResponse {
+    #[schema(example = "Hello world")]
+    pub text: String,
+}
+
+/// Summary of a batch formed by the scheduler
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct BatchRecord {
+    #[schema(example = 42)]
+    pub id: u64,
+    /// Unix timestamp in milliseconds
+    #[schema(example = 1706270835000u64)]
+    pub timestamp: u64,
+    /// Number of requests in the batch
+    #[schema(example = 8)]
+    pub size: u32,
+    /// Tokens prefilled by the batch
+    #[schema(example = 1024)]
+    pub prefill_tokens: u32,
+    /// Prefill token budget the batch was formed with
+    #[schema(example = 4096)]
+    pub prefill_token_budget: u32,
+    /// Sum of the `max_new_tokens` of the batch requests
+    #[schema(example = 512)]
+    pub decode_tokens: u32,
+    /// Total token budget the batch was formed with
+    #[schema(example = 16000)]
+    pub token_budget: u32,
+    /// Mean time spent in the queue by the batch requests, in milliseconds
+    #[schema(example = 12)]
+    pub mean_queue_time_ms: u64,
+    /// Longest time spent in the queue by a batch request, in milliseconds
+    #[schema(example = 30)]
+    pub max_queue_time_ms: u64,
+}
+
+/// Time spent in each phase of a decode iteration of the batching loop, in microseconds
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct IterationTimings {
+    /// ID of the decoded batch
+    #[schema(example = 42)]
+    pub batch_id: u64,
+    /// Unix timestamp in milliseconds of the end of the iteration
+    #[schema(example = 1706270835000u64)]
+    pub timestamp: u64,
+    /// Number of requests decoded
+    #[schema(example = 8)]
+    pub size: u32,
+    /// Admission of the waiting requests: forming their batch and prefilling it
+    #[schema(example = 0)]
+    pub prefill_us: u64,
+    /// Concatenation of the admitted batch to the running one, by the shards
+    #[schema(example = 0)]
+    pub concatenate_us: u64,
+    /// Decode RPC, concatenation and forward included
+    #[schema(example = 21000)]
+    pub decode_us: u64,
+    /// Forward of the model, by the shards
+    #[schema(example = 19500)]
+    pub forward_us: u64,
+    /// Bookkeeping of the generated tokens, before they are streamed
+    #[schema(example = 120)]
+    pub postprocess_us: u64,
+    /// Sending the generated tokens to the streams of the requests
+    #[schema(example = 80)]
+    pub dispatch_us: u64,
+    /// Removal of the finished requests from the batch of the shards
+    #[schema(example = 900)]
+    pub filter_us: u64,
+    /// Whole iteration
+    #[schema(example = 22500)]
+    pub total_us: u64,
+}
+
+/// Request waiting in the queue of the backend
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct QueuedRequest {
+    /// `x-request-id` of the request
+    #[schema(nullable = true, example = "5f1c2ab0-8d3e-4c5b-9f7a-2e6d4b8c1a90")]
+    pub request_id: Option<String>,
+    /// Position of the request in its queue, the next one to be batched first
+    #[schema(example = 0)]
+    pub position: usize,
+    /// Time spent in the queue, in milliseconds
+    #[schema(example = 1250)]
+    pub age_ms: u64,
+    #[schema(example = 812)]
+    pub input_tokens: u32,
+    #[schema(example = 256)]
+    pub max_new_tokens: u32,
+    /// Tokens generated by the previous rounds of a continued request
+    #[schema(example = 0)]
+    pub generated_tokens: u32,
+    pub priority: Priority,
+    /// Tenant of the request, the name of its API key, which never reveals the key itself
+    #[schema(nullable = true, example = "team-a")]
+    pub tenant: Option<String>,
+    #[schema(nullable = true, example = "my-lora")]
+    pub adapter_id: Option<String>,
+    /// Number of higher priority requests that were queued ahead of this one
+    #[schema(example = 2)]
+    pub overtaken: u32,
+    /// Time left before the `deadline_ms` of the request, in milliseconds
+    #[schema(nullable = true, example = 3750)]
+    pub deadline_in_ms: Option<u64>,
+}
+
+/// Prefix cache usage of a session
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct SessionStats {
+    /// Id of the session, hashed when served: the first 8 bytes of its SHA-256 in hex
+    #[schema(example = "c5119362c78ef8e9")]
+    pub session_id: String,
+    /// Number of requests scheduled for the session
+    #[schema(example = 3)]
+    pub requests: u64,
+    /// Prompt tokens that were found in the cache
+    #[schema(example = 812)]
+    pub cache_hit_tokens: u64,
+    /// Prompt tokens that had to be prefilled
+    #[schema(example = 230)]
+    pub cache_miss_tokens: u64,
+    /// Tokens currently kept in the cache for the next request of the session
+    #[schema(example = 1024)]
+    pub pinned_tokens: u32,
+    /// Time since the session was last used, in milliseconds
+    #[schema(example = 5300)]
+    pub idle_ms: u64,
+}
+
+impl SessionStats {
+    /// Stats without the id of the session, which its clients can hash to find their own
+    pub(crate) fn hashed(self) -> Self {
+        let digest = Sha256::digest(self.session_id.as_bytes());
+        Self {
+            session_id: digest
+                .iter()
+                .take(8)
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+            ..self
+        }
+    }
+}
+
+/// KV cache usage of the block allocator of a replica
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct CacheStats {
+    /// Model served by the replica
+    #[schema(example = "bigscience/bloom")]
+    pub model: String,
+    /// Index of the replica among the replicas of the model
+    #[schema(example = 0)]
+    pub replica: usize,
+    /// Number of tokens held by a block
+    #[schema(example = 16)]
+    pub block_size: u32,
+    /// Bytes of GPU memory held by a block over all the shards, unset if the shards do not
+    /// report it
+    #[schema(nullable = true, example = 2097152)]
+    pub block_bytes: Option<u64>,
+    /// Blocks of the KV cache, without the block reserved for health checks
+    #[schema(example = 2047)]
+    pub total_blocks: u32,
+    /// Blocks holding no KV
+    #[schema(example = 1200)]
+    pub free_blocks: u32,
+    /// Blocks held by the running requests and the pinned sessions
+    #[schema(example = 512)]
+    pub pinned_blocks: u32,
+    /// Blocks only held by the prefix cache, reclaimed when the free blocks run out
+    #[schema(example = 335)]
+    pub cached_blocks: u32,
+    /// Fraction of the slots of the blocks held by the running requests that hold no token
+    #[schema(example = 0.04)]
+    pub fragmentation: f32,
+    /// Fraction of the prompt tokens found in the prefix cache since the router started
+    #[schema(nullable = true, example = 0.62)]
+    pub prefix_cache_hit_rate: Option<f32>,
+}
+
+/// Settings of the validation and of the scheduler that can be changed while the router is running
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, PartialEq)]
+pub(crate) struct RuntimeConfig {
+    /// Temperature of the requests that do not set one
+    #[schema(example = 1.0)]
+    pub default_temperature: f32,
+    /// Maximum `max_new_tokens` of a request. Requests that do not set `max_new_tokens` generate up to this
+    /// number of tokens.
+    #[schema(nullable = true, example = 512)]
+    pub max_new_tokens: Option<u32>,
+    /// Maximum number of stop sequences of a request
+    #[schema(example = 4)]
+    pub max_stop_sequences: usize,
+    /// Ratio of waiting requests to running requests from which a new batch is added to the running one.
+    /// `null` if the backend does not have this setting.
+    #[schema(nullable = true, example = 0.3)]
+    pub waiting_served_ratio: Option<f32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct HealthResponse {
+    /// Unix timestamp in seconds of the last generation that reached its end
+    #[schema(nullable = true, example = 1706270835)]
+    pub last_successful_inference: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DrainResponse {
+    /// Number of generations still running
+    #[schema(example = 3)]
+    pub in_flight: usize,
+    /// Number of WebSocket connections still open, including the ones streaming a generation
+    #[schema(example = 1)]
+    pub websocket_connections: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct WatermarkVerifyRequest {
+    /// Text to score
+    #[schema(example = "My name is Olivier and I")]
+    pub text: String,
+    /// Score the text with this key only, instead of all the keys
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "2024-06")]
+    pub key_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct WatermarkKeyScore {
+    #[schema(example = "2024-06")]
+    pub key_id: String,
+    /// Tokens of the text in the greenlist of their previous token
+    #[schema(example = 48)]
+    pub green_tokens: u32,
+    /// Number of standard deviations between the green tokens and the green tokens expected
+    /// in a text that is not watermarked
+    #[schema(example = 6.2)]
+    pub z_score: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct WatermarkVerifyResponse {
+    /// Whether the best score is above the detection threshold
+    #[schema(example = true)]
+    pub detected: bool,
+    /// Key with the best score
+    #[schema(nullable = true, example = "2024-06")]
+    pub key_id: Option<String>,
+    /// Tokens of the text that were scored, all but the first one
+    #[schema(example = 64)]
+    pub scored_tokens: u32,
+    pub scores: Vec<WatermarkKeyScore>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct WatermarkKeysResponse {
+    /// Key of the new watermarked generations
+    #[schema(example = "2024-06")]
+    pub active: String,
+    /// Keys that the texts are scored with
+    #[schema(example = json!(["2024-01", "2024-06"]))]
+    pub keys: Vec<String>,
+}
+
+/// Tokens used by an API key since the router started
+#[derive(Serialize, ToSchema)]
+pub(crate) struct KeyUsage {
+    #[schema(example = "search-app")]
+    pub name: String,
+    #[schema(example = 125000)]
+    pub prompt_tokens: u64,
+    #[schema(example = 48000)]
+    pub completion_tokens: u64,
+    /// Prompt and generated tokens of the current UTC day
+    #[schema(example = 12000)]
+    pub daily_tokens: u64,
+    #[schema(nullable = true, example = 100000)]
+    pub daily_quota: Option<u64>,
+    /// Prompt and generated tokens of the current UTC month
+    #[schema(example = 173000)]
+    pub monthly_tokens: u64,
+    #[schema(nullable = true, example = 2000000)]
+    pub monthly_quota: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct UsageResponse {
+    pub keys: Vec<KeyUsage>,
+}
+
+/// Totals of the queued requests
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub(crate) struct QueueStats {
+    #[schema(example = 12)]
+    pub requests: usize,
+    #[schema(example = 9744)]
+    pub input_tokens: u64,
+    #[schema(example = 3072)]
+    pub max_new_tokens: u64,
+    #[schema(example = 4100)]
+    pub oldest_age_ms: u64,
+    #[schema(example = 1250)]
+    pub mean_age_ms: u64,
+    /// Number of queued requests by priority
+    #[schema(value_type = Object, example = json!({"high": 2, "normal": 10}))]
+    pub priorities: BTreeMap<Priority, usize>,
+    /// Number of queued requests by tenant, named after their API keys, without the requests
+    /// of no tenant
+    #[schema(example = json!({"team-a": 9, "team-b": 3}))]
+    pub tenants: BTreeMap<String, usize>,
+}
+
+impl QueueStats {
+    pub(crate) fn new(requests: &[QueuedRequest]) -> Self {
+        let mut stats = Self {
+            requests: requests.len(),
+            ..Default::default()
+        };
+        let mut total_age_ms = 0;
+        for request in requests {
+            stats.input_tokens += request.input_tokens as u64;
+            stats.max_new_tokens += request.max_new_tokens as u64;
+            stats.oldest_age_ms = stats.oldest_age_ms.max(request.age_ms);
+            total_age_ms += request.age_ms;
+            *stats.priorities.entry(request.priority).or_default() += 1;
+            if let Some(tenant) = &request.tenant {
+                *stats.tenants.entry(tenant.clone()).or_default() += 1;
+            }
+        }
+        if !requests.is_empty() {
+            stats.mean_age_ms = total_age_ms / requests.len() as u64;
+        }
+        stats
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct QueueResponse {
+    pub stats: QueueStats,
+    /// Queued requests, by queue in their order
+    pub requests: Vec<QueuedRequest>,
+}
+
 #[derive(Serialize, ToSchema)]
 pub(crate) struct StreamDetails {
     #[schema(example = "length")]
@@ -1417,6 +2728,10 @@ pub(crate) struct StreamDetails {
     pub seed: Option<u64>,
     #[schema(example = 1)]
     pub input_length: u32,
+    /// Also sent in the usage of the OpenAI routes
+    pub timings: GenerationTimings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_template: Option<PromptTemplateVersion>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1429,12 +2744,51 @@ pub(crate) struct StreamResponse {
     pub generated_text: Option<String>,
     #[schema(nullable = true, default = "null")]
     pub details: Option<StreamDetails>,
+    /// `metadata` of the request, sent with the last token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, default = "null", example = json!({"user_session": "abc-123"}))]
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
+    /// Changes made to the request to fit the limits of the model, sent with the last token
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
+/// Error body of every route: `{"error": {"type": .., "param": .., "message": ..}}`
 #[derive(Serialize, ToSchema)]
 pub(crate) struct ErrorResponse {
-    pub error: String,
+    pub error: ErrorDetails,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorDetails {
+    /// Machine readable kind of the error, e.g. `validation`, `queue_full`, `overloaded`
+    /// or `generation`
+    #[serde(rename = "type")]
+    #[schema(example = "validation")]
     pub error_type: String,
+    /// Request parameter responsible for the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "max_new_tokens")]
+    pub param: Option<String>,
+    #[schema(example = "`max_new_tokens` must be strictly positive")]
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub(crate) fn new(error_type: &str, message: impl Into<String>) -> Self {
+        Self {
+            error: ErrorDetails {
+                error_type: error_type.to_string(),
+                param: None,
+                message: message.into(),
+            },
+        }
+    }
+
+    pub(crate) fn with_param(mut self, param: &str) -> Self {
+        self.error.param = Some(param.to_string());
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -1465,6 +2819,44 @@ impl Default for ModelsInfo {
     }
 }
 
+#[derive(Clone, Deserialize, Serialize, ToSchema, Debug)]
+pub(crate) struct EmbeddingRequest {
+    /// UNUSED
+    #[schema(example = "mistralai/Mistral-7B-Instruct-v0.2")]
+    /// ID of the model to use.
+    pub model: Option<String>,
+
+    /// The text or texts to embed.
+    #[schema(example = "What is Deep Learning?")]
+    pub input: Prompt,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct Embedding {
+    #[schema(example = "embedding")]
+    pub object: String,
+    #[schema(example = json!([0.0023064255, -0.009327292, 0.015797347]))]
+    pub embedding: Vec<f32>,
+    #[schema(example = 0)]
+    pub index: usize,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct EmbeddingResponse {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<Embedding>,
+    #[schema(example = "mistralai/Mistral-7B-Instruct-v0.2")]
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1656,11 +3048,287 @@ mod tests {
         assert!(matches!(
             request.stream_options,
             Some(StreamOptions {
-                include_usage: true
+                include_usage: true,
+                chunk_tokens: None,
             })
         ));
     }
 
+    #[test]
+    fn test_chat_stream_options_chunk_tokens() {
+        let json = json!({
+            "model": "",
+            "stream_options": {"chunk_tokens": 4},
+            "messages": [{
+                "role": "user",
+                "content": "Hello"
+            }]
+        });
+        let request: ChatRequest = serde_json::from_str(json.to_string().as_str()).unwrap();
+
+        assert!(matches!(
+            request.stream_options,
+            Some(StreamOptions {
+                include_usage: false,
+                chunk_tokens: Some(4),
+            })
+        ));
+    }
+
+    fn token(text: &str, logprob: f32) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob,
+            special: false,
+        }
+    }
+
+    fn sequence(generated_text: &str, finish_reason: FinishReason) -> BestOfSequence {
+        BestOfSequence {
+            generated_text: generated_text.to_string(),
+            finish_reason,
+            generated_tokens: 2,
+            seed: None,
+            prefill: vec![PrefillToken {
+                id: 0,
+                text: "Hi".to_string(),
+                logprob: f32::NAN,
+            }],
+            tokens: vec![token(" a", -0.5), token(" b", -0.25)],
+            top_tokens: Vec::new(),
+        }
+    }
+
+    fn details(sequences: Vec<BestOfSequence>) -> Details {
+        Details {
+            finish_reason: FinishReason::EndOfSequenceToken,
+            generated_tokens: 1,
+            seed: None,
+            prefill: Vec::new(),
+            tokens: vec![token(" c", -1.0)],
+            best_of_sequences: Some(sequences),
+            top_tokens: Vec::new(),
+            timings: GenerationTimings::default(),
+            prompt_template: None,
+        }
+    }
+
+    #[test]
+    fn test_completion_prompt_choices() {
+        // The second prompt of a request with `n: 3`
+        let details = details(vec![
+            sequence(" a b", FinishReason::Length),
+            sequence(" a b", FinishReason::StopSequence),
+        ]);
+        let choices = CompletionComplete::prompt_choices(3, " c".to_string(), details, true, true);
+        assert_eq!(
+            choices
+                .iter()
+                .map(|choice| (
+                    choice.index,
+                    choice.text.as_str(),
+                    choice.finish_reason.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (3, " c", "stop"),
+                (4, " a b", "length"),
+                (5, " a b", "stop_sequence"),
+            ]
+        );
+        // The echoed prompt comes first, without a logprob
+        let logprobs = choices[1].logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.tokens, vec!["Hi", " a", " b"]);
+        assert_eq!(logprobs.token_logprobs, vec![None, Some(-0.5), Some(-0.25)]);
+
+        let details = Details {
+            best_of_sequences: None,
+            ..details(Vec::new())
+        };
+        let choices =
+            CompletionComplete::prompt_choices(0, " c".to_string(), details, false, false);
+        assert_eq!(choices.len(), 1);
+        assert_eq!(choices[0].index, 0);
+        assert!(choices[0].logprobs.is_none());
+    }
+
+    #[test]
+    fn test_chat_completion_choices() {
+        let mut completion = ChatCompletion::new(
+            "model".to_string(),
+            "fp".to_string(),
+            Some(" c".to_string()),
+            1,
+            details(Vec::new()),
+            true,
+            None,
+            5,
+        );
+        completion.push_choice(
+            Some(" a b".to_string()),
+            None,
+            sequence(" a b", FinishReason::Length),
+            true,
+        );
+        let completion = serde_json::to_value(completion).unwrap();
+        assert_eq!(completion["choices"][0]["index"], 0);
+        assert_eq!(completion["choices"][0]["message"]["content"], " c");
+        assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+        assert_eq!(completion["choices"][1]["index"], 1);
+        assert_eq!(completion["choices"][1]["message"]["content"], " a b");
+        assert_eq!(completion["choices"][1]["finish_reason"], "length");
+        assert_eq!(
+            completion["choices"][1]["logprobs"]["content"][1]["token"],
+            " b"
+        );
+        // The prompt is counted once
+        assert_eq!(
+            completion["usage"],
+            json!({"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8})
+        );
+    }
+
+    #[test]
+    fn test_chat_usage_choices() {
+        let mut usage = Usage {
+            prompt_tokens: 5,
+            completion_tokens: 2,
+            total_tokens: 7,
+            timings: None,
+        };
+        usage.add_choice(&Usage {
+            prompt_tokens: 5,
+            completion_tokens: 3,
+            total_tokens: 8,
+            timings: None,
+        });
+        assert_eq!(
+            (
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens
+            ),
+            (5, 5, 10)
+        );
+        let chunk = ChatCompletionChunk::new(
+            "model".to_string(),
+            "fp".to_string(),
+            Some(" a".to_string()),
+            None,
+            1,
+            None,
+            None,
+            None,
+        )
+        .with_index(2);
+        assert_eq!(
+            serde_json::to_value(chunk).unwrap()["choices"][0]["index"],
+            2
+        );
+    }
+
+    #[test]
+    fn test_chat_usage_chunk() {
+        let details = StreamDetails {
+            finish_reason: FinishReason::Length,
+            generated_tokens: 10,
+            seed: None,
+            input_length: 5,
+            timings: GenerationTimings {
+                queue_time: 0.5,
+                prefill_time: 0.25,
+                decode_time: 0.25,
+                tokens_per_second: 20.0,
+                batches: 10,
+                mean_batch_size: 1.5,
+                max_batch_size: 2,
+            },
+            prompt_template: None,
+        };
+        let usage = Usage::from_stream_details(&details);
+        let chunk = ChatCompletionChunk::usage("model".to_string(), "fp".to_string(), 1, usage);
+        assert_eq!(
+            serde_json::to_value(chunk).unwrap(),
+            json!({
+                "id": "",
+                "created": 1,
+                "model": "model",
+                "system_fingerprint": "fp",
+                "choices": [],
+                "usage": {
+                    "prompt_tokens": 5,
+                    "completion_tokens": 10,
+                    "total_tokens": 15,
+                    "timings": {
+                        "queue_time": 0.5,
+                        "prefill_time": 0.25,
+                        "decode_time": 0.25,
+                        "tokens_per_second": 20.0,
+                        "batches": 10,
+                        "mean_batch_size": 1.5,
+                        "max_batch_size": 2
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_session_stats_hashed() {
+        let stats = SessionStats {
+            session_id: "conversation-42".to_string(),
+            requests: 3,
+            cache_hit_tokens: 812,
+            cache_miss_tokens: 230,
+            pinned_tokens: 1024,
+            idle_ms: 5300,
+        };
+        let hashed = stats.clone().hashed();
+        assert_eq!(hashed.session_id, "c5119362c78ef8e9");
+        assert_eq!(hashed.requests, 3);
+    }
+
+    #[test]
+    fn test_generation_timings() {
+        let mut batches = infer::BatchResidency::default();
+        for batch_size in [4, 2, 3] {
+            batches.record(batch_size);
+        }
+        let generated_text = infer::GeneratedText {
+            text: "text".to_string(),
+            generated_tokens: 3,
+            finish_reason: FinishReason::Length,
+            seed: None,
+            batches,
+        };
+        let queued = tokio::time::Instant::now();
+        let start = queued + std::time::Duration::from_millis(500);
+        let first_token = start + std::time::Duration::from_millis(250);
+        let end = first_token + std::time::Duration::from_millis(250);
+        let timings = GenerationTimings::new(queued, start, first_token, end, &generated_text);
+        assert_eq!(
+            timings,
+            GenerationTimings {
+                queue_time: 0.5,
+                prefill_time: 0.25,
+                decode_time: 0.25,
+                tokens_per_second: 6.0,
+                batches: 3,
+                mean_batch_size: 3.0,
+                max_batch_size: 4,
+            }
+        );
+
+        // Generated within a tick of the clock
+        let timings = GenerationTimings::new(queued, queued, queued, queued, &generated_text);
+        assert_eq!(timings.tokens_per_second, 0.0);
+        assert_eq!(
+            serde_json::to_value(&timings).unwrap()["tokens_per_second"],
+            serde_json::json!(0.0)
+        );
+    }
+
     #[test]
     fn openai_output() {
         let message = OutputMessage::ChatMessage(TextMessage {
@@ -1730,4 +3398,137 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_completion_logprobs() {
+        let prefill = vec![
+            PrefillToken {
+                id: 0,
+                text: "Hello".to_string(),
+                logprob: f32::NAN,
+            },
+            PrefillToken {
+                id: 1,
+                text: " world".to_string(),
+                logprob: -1.0,
+            },
+        ];
+        let token = |text: &str, logprob: f32| Token {
+            id: 2,
+            text: text.to_string(),
+            logprob,
+            special: false,
+        };
+        let tokens = vec![token("!", -0.5)];
+        let top_tokens = vec![vec![token("!", -0.5), token(".", -1.5)]];
+
+        let logprobs = CompletionLogprobs::new(&prefill, &tokens, &top_tokens, 0);
+        assert_eq!(logprobs.tokens, vec!["Hello", " world", "!"]);
+        assert_eq!(logprobs.token_logprobs, vec![None, Some(-1.0), Some(-0.5)]);
+        assert_eq!(logprobs.text_offset, vec![0, 5, 11]);
+        assert_eq!(logprobs.top_logprobs[0], None);
+        assert_eq!(
+            logprobs.top_logprobs[2],
+            Some(HashMap::from([
+                ("!".to_string(), -0.5),
+                (".".to_string(), -1.5)
+            ]))
+        );
+
+        let logprobs = CompletionLogprobs::new(&[], &tokens, &[vec![]], 3);
+        assert_eq!(logprobs.text_offset, vec![3]);
+        assert_eq!(logprobs.top_logprobs, vec![None]);
+    }
+
+    #[test]
+    fn test_grammar_type_deserialization() {
+        let schema = json!({"properties": {"location": {"type": "string"}}});
+
+        let grammar: GrammarType =
+            serde_json::from_value(json!({"type": "json", "value": schema})).unwrap();
+        assert_eq!(grammar, GrammarType::Json(schema.clone()));
+
+        let grammar: GrammarType =
+            serde_json::from_value(json!({"type": "json_object", "value": schema})).unwrap();
+        assert_eq!(grammar, GrammarType::Json(schema.clone()));
+        let grammar: GrammarType = serde_json::from_value(json!({"type": "json_object"})).unwrap();
+        assert_eq!(grammar, GrammarType::JsonObject);
+
+        let grammar: GrammarType =
+            serde_json::from_value(json!({"type": "json_schema", "schema": schema})).unwrap();
+        assert_eq!(grammar, GrammarType::Json(schema.clone()));
+
+        let grammar: GrammarType = serde_json::from_value(json!({
+            "type": "json_schema",
+            "json_schema": {"name": "weather", "schema": schema, "strict": true}
+        }))
+        .unwrap();
+        assert_eq!(grammar, GrammarType::Json(schema.clone()));
+
+        let grammar: GrammarType =
+            serde_json::from_value(json!({"type": "regex", "value": "[0-9]+"})).unwrap();
+        assert_eq!(grammar, GrammarType::Regex("[0-9]+".to_string()));
+
+        assert!(serde_json::from_value::<GrammarType>(json!({"type": "json_schema"})).is_err());
+    }
+
+    #[test]
+    fn test_error_response_serialization() {
+        let err = InferError::ValidationError(validation::ValidationError::NegativeMaxNewTokens);
+        let response = serde_json::to_value(ErrorResponse::from(&err)).unwrap();
+        assert_eq!(
+            response,
+            json!({"error": {
+                "type": "validation",
+                "param": "max_new_tokens",
+                "message": "Input validation error: `max_new_tokens` must be strictly positive"
+            }})
+        );
+
+        let response = serde_json::to_value(ErrorResponse::from(&InferError::QueueFull)).unwrap();
+        assert_eq!(
+            response,
+            json!({"error": {
+                "type": "queue_full",
+                "message": "Model is overloaded, the request queue is full"
+            }})
+        );
+    }
+
+    #[test]
+    fn test_queue_stats() {
+        let request = |age_ms, priority, tenant: Option<&str>| QueuedRequest {
+            request_id: None,
+            position: 0,
+            age_ms,
+            input_tokens: 100,
+            max_new_tokens: 20,
+            generated_tokens: 0,
+            priority,
+            tenant: tenant.map(String::from),
+            adapter_id: None,
+            overtaken: 0,
+            deadline_in_ms: None,
+        };
+        let stats = QueueStats::new(&[
+            request(300, Priority::High, Some("a")),
+            request(100, Priority::Normal, Some("b")),
+            request(200, Priority::Normal, None),
+        ]);
+        assert_eq!(stats.requests, 3);
+        assert_eq!(stats.input_tokens, 300);
+        assert_eq!(stats.max_new_tokens, 60);
+        assert_eq!(stats.oldest_age_ms, 300);
+        assert_eq!(stats.mean_age_ms, 200);
+        assert_eq!(
+            serde_json::to_value(&stats.priorities).unwrap(),
+            json!({"high": 1, "normal": 2})
+        );
+        assert_eq!(
+            serde_json::to_value(&stats.tenants).unwrap(),
+            json!({"a": 1, "b": 1})
+        );
+
+        assert_eq!(QueueStats::new(&[]), QueueStats::default());
+    }
 }
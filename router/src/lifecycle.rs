@@ -0,0 +1,227 @@
+/// Typed events of the streams with `"stream_mode": "events"`, sent instead of the bare deltas
+use crate::infer::InferError;
+use crate::server::StreamEvent;
+use crate::{FinishReason, StreamDetails, StreamResponse, Token, Usage};
+use axum::response::sse::Event;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Event of the lifecycle of a generation, sent with its type as SSE event name
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum LifecycleEvent {
+    /// Sent until the first token, while the request waits behind `position` requests
+    Queued { position: usize, timestamp: u64 },
+    /// The request entered a batch and its prompt is being processed
+    Prefilling { timestamp: u64 },
+    /// Generated text, of one token or of a chunk of tokens
+    Token {
+        text: String,
+        tokens: Vec<Token>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        top_tokens: Vec<Vec<Token>>,
+        timestamp: u64,
+    },
+    /// Text of the arguments of the tool call being generated
+    ToolCallDelta { arguments: String, timestamp: u64 },
+    /// The generation ended, no event follows
+    Done {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        finish_reason: Option<FinishReason>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        generated_text: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        details: Option<StreamDetails>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage: Option<Usage>,
+        /// `metadata` of the request
+        #[serde(skip_serializing_if = "Option::is_none")]
+        metadata: Option<HashMap<String, serde_json::Value>>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+        timestamp: u64,
+    },
+}
+
+/// Milliseconds since the UNIX epoch
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl LifecycleEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            LifecycleEvent::Queued { .. } => "queued",
+            LifecycleEvent::Prefilling { .. } => "prefilling",
+            LifecycleEvent::Token { .. } => "token",
+            LifecycleEvent::ToolCallDelta { .. } => "tool_call_delta",
+            LifecycleEvent::Done { .. } => "done",
+        }
+    }
+
+    /// `token` event of the tokens of a stream, or `tool_call_delta` for a tool call
+    pub(crate) fn tokens(responses: &[StreamResponse], tool_call: bool) -> Self {
+        let text: String = responses
+            .iter()
+            .filter(|response| tool_call || !response.token.special)
+            .map(|response| response.token.text.as_str())
+            .collect();
+        if tool_call {
+            return LifecycleEvent::ToolCallDelta {
+                arguments: text,
+                timestamp: timestamp(),
+            };
+        }
+        LifecycleEvent::Token {
+            text,
+            tokens: responses
+                .iter()
+                .map(|response| response.token.clone())
+                .collect(),
+            top_tokens: responses
+                .iter()
+                .filter(|response| !response.top_tokens.is_empty())
+                .map(|response| response.top_tokens.clone())
+                .collect(),
+            timestamp: timestamp(),
+        }
+    }
+
+    /// `done` event of a chat completion, from the last token of its stream
+    pub(crate) fn chat_done(last: &StreamResponse, details: &StreamDetails) -> Self {
+        LifecycleEvent::Done {
+            finish_reason: Some(details.finish_reason.clone()),
+            generated_text: None,
+            details: None,
+            usage: Some(Usage::from_stream_details(details)),
+            metadata: last.metadata.clone(),
+            warnings: last.warnings.clone(),
+            timestamp: timestamp(),
+        }
+    }
+
+    /// `done` event of a stream that stopped before the details of its last token
+    pub(crate) fn done() -> Self {
+        LifecycleEvent::Done {
+            finish_reason: None,
+            generated_text: None,
+            details: None,
+            usage: None,
+            metadata: None,
+            warnings: Vec::new(),
+            timestamp: timestamp(),
+        }
+    }
+
+    pub(crate) fn into_event(self) -> Event {
+        Event::default()
+            .event(self.name())
+            .json_data(&self)
+            .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into())
+    }
+}
+
+/// Lifecycle events of an event of `/generate_stream`
+pub(crate) fn generate_events(event: Result<StreamEvent, InferError>) -> Vec<Event> {
+    match event {
+        Ok(event) => generate_lifecycle(event)
+            .into_iter()
+            .map(LifecycleEvent::into_event)
+            .collect(),
+        Err(err) => vec![Event::from(err).event("error")],
+    }
+}
+
+/// Lifecycle events of an event of `/generate_stream`, before their SSE encoding
+pub(crate) fn generate_lifecycle(event: StreamEvent) -> Vec<LifecycleEvent> {
+    match event {
+        StreamEvent::Queued(position) => vec![LifecycleEvent::Queued {
+            position,
+            timestamp: timestamp(),
+        }],
+        StreamEvent::Prefilling => vec![LifecycleEvent::Prefilling {
+            timestamp: timestamp(),
+        }],
+        StreamEvent::Token(mut response) => {
+            let done = response.generated_text.take().map(|generated_text| {
+                let details = response.details.take();
+                LifecycleEvent::Done {
+                    finish_reason: details
+                        .as_ref()
+                        .map(|details| details.finish_reason.clone()),
+                    generated_text: Some(generated_text),
+                    details,
+                    usage: None,
+                    metadata: response.metadata.take(),
+                    warnings: std::mem::take(&mut response.warnings),
+                    timestamp: timestamp(),
+                }
+            });
+            let token = LifecycleEvent::tokens(std::slice::from_ref(&response), false);
+            std::iter::once(token).chain(done).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(text: &str, special: bool) -> StreamResponse {
+        StreamResponse {
+            index: 1,
+            token: Token {
+                id: 0,
+                text: text.to_string(),
+                logprob: -0.5,
+                special,
+            },
+            top_tokens: Vec::new(),
+            generated_text: None,
+            details: None,
+            metadata: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_token_events() {
+        let responses = [response("Hello", false), response("</s>", true)];
+        let LifecycleEvent::Token {
+            text,
+            tokens,
+            top_tokens,
+            ..
+        } = LifecycleEvent::tokens(&responses, false)
+        else {
+            panic!("expected a token event");
+        };
+        assert_eq!(text, "Hello");
+        assert_eq!(tokens.len(), 2);
+        assert!(top_tokens.is_empty());
+
+        let responses = [response("{\"location\":", false)];
+        assert!(matches!(
+            LifecycleEvent::tokens(&responses, true),
+            LifecycleEvent::ToolCallDelta { arguments, .. } if arguments == "{\"location\":"
+        ));
+    }
+
+    #[test]
+    fn test_lifecycle_event_format() {
+        let event = LifecycleEvent::Queued {
+            position: 3,
+            timestamp: 1700000000000,
+        };
+        assert_eq!(event.name(), "queued");
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"type": "queued", "position": 3, "timestamp": 1700000000000u64})
+        );
+    }
+}
@@ -0,0 +1,198 @@
+/// Defaults and ceilings of the `max_new_tokens` of the requests, per model
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// `max_new_tokens` limits of a model
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Limits {
+    /// `max_new_tokens` of the requests that do not set it
+    pub(crate) default: Option<u32>,
+    /// Largest `max_new_tokens` of a request, larger values are clamped
+    pub(crate) ceiling: Option<u32>,
+    /// Largest `max_new_tokens` of a streamed request, `ceiling` if unset
+    pub(crate) stream_ceiling: Option<u32>,
+}
+
+impl Limits {
+    /// The limits set in `self`, completed with the ones of `other`
+    fn or(self, other: Self) -> Self {
+        Self {
+            default: self.default.or(other.default),
+            ceiling: self.ceiling.or(other.ceiling),
+            stream_ceiling: self.stream_ceiling.or(other.stream_ceiling),
+        }
+    }
+
+    /// Largest `max_new_tokens` of a request, streamed or not
+    pub(crate) fn ceiling(&self, stream: bool) -> Option<u32> {
+        if stream {
+            self.stream_ceiling.or(self.ceiling)
+        } else {
+            self.ceiling
+        }
+    }
+
+    fn check(&self, model: &str) -> Result<(), MaxNewTokensError> {
+        let invalid = |reason: &str| -> Result<(), MaxNewTokensError> {
+            Err(MaxNewTokensError::Invalid(
+                model.to_string(),
+                reason.to_string(),
+            ))
+        };
+        if [self.default, self.ceiling, self.stream_ceiling].contains(&Some(0)) {
+            return invalid("the limits must be strictly positive");
+        }
+        if let Some(default) = self.default {
+            let below = |ceiling: Option<u32>| ceiling.map_or(true, |ceiling| default <= ceiling);
+            if !below(self.ceiling(false)) || !below(self.ceiling(true)) {
+                return invalid("`default` must be <= `ceiling` and `stream_ceiling`");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Content of the `--max-new-tokens-config-path` file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaxNewTokensConfig {
+    /// Limits of the models that are not listed in `models`
+    #[serde(default)]
+    default: Limits,
+    /// Limits per model name or adapter id, completed with the default ones
+    #[serde(default)]
+    models: HashMap<String, Limits>,
+}
+
+#[derive(Debug, Error)]
+pub enum MaxNewTokensError {
+    #[error("Unable to read the max new tokens config: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Invalid max new tokens config: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid `max_new_tokens` limits of {0}: {1}")]
+    Invalid(String, String),
+}
+
+#[derive(Debug)]
+pub(crate) struct MaxNewTokensLimits {
+    default: Limits,
+    models: HashMap<String, Limits>,
+    /// Limits of the requests without `adapter_id`, which go to the default model
+    unnamed: Limits,
+}
+
+impl MaxNewTokensLimits {
+    /// Build the limits from the CLI values and the optional config file.
+    /// Returns `None` if no model has a limit.
+    pub(crate) fn new(
+        default: Option<u32>,
+        ceiling: Option<u32>,
+        stream_ceiling: Option<u32>,
+        config_path: Option<String>,
+    ) -> Result<Option<Self>, MaxNewTokensError> {
+        let config = match config_path {
+            Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            None => MaxNewTokensConfig::default(),
+        };
+        // The CLI takes precedence over the default limits of the file
+        let cli = Limits {
+            default,
+            ceiling,
+            stream_ceiling,
+        };
+        Self::from_config(config, cli)
+    }
+
+    fn from_config(
+        config: MaxNewTokensConfig,
+        cli: Limits,
+    ) -> Result<Option<Self>, MaxNewTokensError> {
+        let default = cli.or(config.default);
+        default.check("the default model")?;
+        let models = config
+            .models
+            .into_iter()
+            .map(|(model, limits)| {
+                let limits = limits.or(default);
+                limits.check(&model)?;
+                Ok((model, limits))
+            })
+            .collect::<Result<HashMap<_, _>, MaxNewTokensError>>()?;
+
+        if default == Limits::default() && models.values().all(|limits| *limits == default) {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            default,
+            models,
+            unnamed: default,
+        }))
+    }
+
+    /// Apply the limits of `model` to the requests without `adapter_id`
+    pub(crate) fn with_default_model(mut self, model: &str) -> Self {
+        self.unnamed = self.model(Some(model));
+        self
+    }
+
+    /// Limits of the requests to `model`, the `adapter_id` of the request
+    pub(crate) fn model(&self, model: Option<&str>) -> Limits {
+        match model {
+            Some(model) => self.models.get(model).copied().unwrap_or(self.default),
+            None => self.unnamed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(config: &str) -> Result<Option<MaxNewTokensLimits>, MaxNewTokensError> {
+        MaxNewTokensLimits::from_config(serde_json::from_str(config)?, Limits::default())
+    }
+
+    #[test]
+    fn test_max_new_tokens_limits() {
+        let limits = config(
+            r#"{
+                "default": {"default": 256, "ceiling": 1024},
+                "models": {"base": {"stream_ceiling": 4096, "ceiling": 2048}, "small": {}}
+            }"#,
+        )
+        .unwrap()
+        .unwrap()
+        .with_default_model("base");
+
+        let base = limits.model(Some("base"));
+        assert_eq!(base.default, Some(256));
+        assert_eq!(base.ceiling(false), Some(2048));
+        assert_eq!(base.ceiling(true), Some(4096));
+        assert_eq!(limits.model(None), base);
+
+        let small = limits.model(Some("small"));
+        assert_eq!(small.ceiling(false), Some(1024));
+        assert_eq!(small.ceiling(true), Some(1024));
+        assert_eq!(limits.model(Some("unknown")), small);
+    }
+
+    #[test]
+    fn test_invalid_max_new_tokens_limits() {
+        assert!(config("{}").unwrap().is_none());
+        assert!(matches!(
+            config(r#"{"models": {"base": {"default": 512, "stream_ceiling": 256}}}"#),
+            Err(MaxNewTokensError::Invalid(..))
+        ));
+        assert!(matches!(
+            config(r#"{"default": {"ceiling": 0}}"#),
+            Err(MaxNewTokensError::Invalid(..))
+        ));
+        assert!(matches!(
+            config(r#"{"default": {"max": 128}}"#),
+            Err(MaxNewTokensError::Parse(..))
+        ));
+    }
+}
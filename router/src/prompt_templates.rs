@@ -0,0 +1,266 @@
+/// Registry of the prompt templates of the server, that the requests name instead of sending
+/// their whole prompt
+use crate::validation::ValidationError;
+use minijinja::{Environment, Template, UndefinedBehavior};
+use minijinja_contrib::pycompat;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Extension of the template files of a `--prompt-templates-path` directory
+const TEMPLATE_EXTENSION: &str = "jinja";
+
+#[derive(Debug, Error)]
+pub enum PromptTemplateError {
+    #[error("Unable to read the prompt templates {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Unable to parse the prompt templates {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("Invalid prompt template `{0}`: {1}")]
+    Template(String, minijinja::Error),
+}
+
+/// Template of a `--prompt-templates-path` JSON file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateConfig {
+    template: String,
+    /// Digest of the template if unset
+    version: Option<String>,
+}
+
+/// Template rendered into the inputs of a request
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct PromptTemplateVersion {
+    #[schema(example = "summarize_v2")]
+    pub name: String,
+    /// Version set in the configuration of the template, or digest of its source
+    #[schema(example = "9f86d081884c")]
+    pub version: String,
+}
+
+struct PromptTemplate {
+    template: Template<'static, 'static>,
+    version: String,
+    /// Variables used by the template, that the requests must all set
+    variables: BTreeSet<String>,
+}
+
+pub(crate) struct PromptTemplates {
+    templates: HashMap<String, PromptTemplate>,
+}
+
+impl PromptTemplates {
+    /// Load the templates of `path`, either a directory of `<name>.jinja` files or a JSON file
+    /// of `{"<name>": {"template": ..., "version": ...}}`
+    pub(crate) fn new(path: Option<String>) -> Result<Option<Self>, PromptTemplateError> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let read = |path: &Path| {
+            std::fs::read_to_string(path)
+                .map_err(|err| PromptTemplateError::Read(path.display().to_string(), err))
+        };
+
+        let configs = if Path::new(&path).is_dir() {
+            let mut configs = HashMap::new();
+            let entries = std::fs::read_dir(&path)
+                .map_err(|err| PromptTemplateError::Read(path.clone(), err))?;
+            for entry in entries {
+                let file = entry
+                    .map_err(|err| PromptTemplateError::Read(path.clone(), err))?
+                    .path();
+                if file
+                    .extension()
+                    .is_some_and(|ext| ext == TEMPLATE_EXTENSION)
+                {
+                    if let Some(name) = file.file_stem().and_then(|name| name.to_str()) {
+                        let config = TemplateConfig {
+                            template: read(&file)?,
+                            version: None,
+                        };
+                        configs.insert(name.to_string(), config);
+                    }
+                }
+            }
+            configs
+        } else {
+            serde_json::from_str(&read(Path::new(&path))?)
+                .map_err(|err| PromptTemplateError::Parse(path.clone(), err))?
+        };
+        let templates = Self::from_configs(configs)?;
+        tracing::info!("Loaded {} prompt templates", templates.templates.len());
+        Ok(Some(templates))
+    }
+
+    fn from_configs(configs: HashMap<String, TemplateConfig>) -> Result<Self, PromptTemplateError> {
+        let mut env = Box::new(Environment::new());
+        env.set_unknown_method_callback(pycompat::unknown_method_callback);
+        // A variable missing from a request is an error, not an empty string in the prompt
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        // Leaked like the chat template: the templates are read-only for the router lifetime
+        let env: &'static Environment = Box::leak(env);
+
+        let templates = configs
+            .into_iter()
+            .map(|(name, config)| {
+                let version = config.version.unwrap_or_else(|| {
+                    let digest = Sha256::digest(config.template.as_bytes());
+                    digest[..6]
+                        .iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect()
+                });
+                let template = env
+                    .template_from_str(Box::leak(config.template.into_boxed_str()))
+                    .map_err(|err| PromptTemplateError::Template(name.clone(), err))?;
+                let variables = template.undeclared_variables(false).into_iter().collect();
+                let template = PromptTemplate {
+                    template,
+                    version,
+                    variables,
+                };
+                Ok((name, template))
+            })
+            .collect::<Result<_, PromptTemplateError>>()?;
+        Ok(Self { templates })
+    }
+
+    /// Render the template `name` with `variables`, which must be exactly the ones it uses
+    pub(crate) fn render(
+        &self,
+        name: &str,
+        variables: &HashMap<String, serde_json::Value>,
+    ) -> Result<(String, PromptTemplateVersion), ValidationError> {
+        let template = self
+            .templates
+            .get(name)
+            .ok_or_else(|| ValidationError::UnknownPromptTemplate(name.to_string()))?;
+        if let Some(missing) = template
+            .variables
+            .iter()
+            .find(|variable| !variables.contains_key(*variable))
+        {
+            return Err(ValidationError::PromptTemplateVariables(format!(
+                "missing variable `{missing}`"
+            )));
+        }
+        if let Some(unknown) = variables
+            .keys()
+            .filter(|variable| !template.variables.contains(*variable))
+            .min()
+        {
+            return Err(ValidationError::PromptTemplateVariables(format!(
+                "`{name}` does not use the variable `{unknown}`"
+            )));
+        }
+
+        let inputs = template
+            .template
+            .render(variables)
+            .map_err(|err| ValidationError::PromptTemplateVariables(err.to_string()))?;
+        let version = PromptTemplateVersion {
+            name: name.to_string(),
+            version: template.version.clone(),
+        };
+        Ok((inputs, version))
+    }
+}
+
+impl fmt::Debug for PromptTemplates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.templates.keys().collect();
+        names.sort();
+        f.debug_struct("PromptTemplates")
+            .field("templates", &names)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn templates() -> PromptTemplates {
+        let configs = serde_json::from_value(json!({
+            "summarize_v2": {
+                "template": "Summarize in {{ words }} words:\n{{ text | trim }}",
+                "version": "2"
+            },
+            "translate": {
+                "template": "{% for line in lines %}{{ line.upper() }}\n{% endfor %}"
+            }
+        }))
+        .unwrap();
+        PromptTemplates::from_configs(configs).unwrap()
+    }
+
+    fn variables(variables: serde_json::Value) -> HashMap<String, serde_json::Value> {
+        serde_json::from_value(variables).unwrap()
+    }
+
+    #[test]
+    fn test_render_prompt_template() {
+        let templates = templates();
+        let (inputs, version) = templates
+            .render(
+                "summarize_v2",
+                &variables(json!({"words": 10, "text": " The text "})),
+            )
+            .unwrap();
+        assert_eq!(inputs, "Summarize in 10 words:\nThe text");
+        assert_eq!(
+            version,
+            PromptTemplateVersion {
+                name: "summarize_v2".to_string(),
+                version: "2".to_string(),
+            }
+        );
+
+        let (inputs, version) = templates
+            .render("translate", &variables(json!({"lines": ["a", "b"]})))
+            .unwrap();
+        assert_eq!(inputs, "A\nB\n");
+        // The digest of the template
+        assert_eq!(version.version.len(), 12);
+    }
+
+    #[test]
+    fn test_invalid_prompt_template_request() {
+        let templates = templates();
+        assert!(matches!(
+            templates.render("unknown", &HashMap::new()),
+            Err(ValidationError::UnknownPromptTemplate(_))
+        ));
+        assert!(matches!(
+            templates.render("summarize_v2", &variables(json!({"text": "The text"}))),
+            Err(ValidationError::PromptTemplateVariables(_))
+        ));
+        assert!(matches!(
+            templates.render(
+                "summarize_v2",
+                &variables(json!({"words": 10, "text": "The text", "tone": "formal"}))
+            ),
+            Err(ValidationError::PromptTemplateVariables(_))
+        ));
+        // `lines` is not iterable
+        assert!(matches!(
+            templates.render("translate", &variables(json!({"lines": 1}))),
+            Err(ValidationError::PromptTemplateVariables(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_prompt_template() {
+        let configs = serde_json::from_value(json!({"broken": {"template": "{{ text"}})).unwrap();
+        assert!(matches!(
+            PromptTemplates::from_configs(configs),
+            Err(PromptTemplateError::Template(..))
+        ));
+    }
+}
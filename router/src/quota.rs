@@ -0,0 +1,355 @@
+/// Token usage of the API keys, and their daily and monthly token quotas
+use crate::auth::{self, ApiKeys, AuthError};
+use crate::{ErrorResponse, KeyUsage};
+use axum::extract::Request;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+/// Interval between two saves of the usage, the tokens of the last one are lost on a crash
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Prompt and generated tokens that an API key can use per UTC day and per UTC month
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Quota {
+    daily_tokens: Option<u64>,
+    monthly_tokens: Option<u64>,
+}
+
+/// Quota that a key used up, until the start of the next period
+#[derive(Debug, PartialEq)]
+pub(crate) struct Exceeded {
+    period: &'static str,
+    pub(crate) resets_in: Duration,
+}
+
+impl Exceeded {
+    pub(crate) fn message(&self) -> String {
+        format!(
+            "The {} token quota of the API key is used up, it resets in {}s",
+            self.period,
+            self.resets_in.as_secs()
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Counters {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// Day since the UNIX epoch that `daily_tokens` counts
+    day: u64,
+    daily_tokens: u64,
+    /// Year and month that `monthly_tokens` counts
+    month: (u64, u64),
+    monthly_tokens: u64,
+}
+
+impl Counters {
+    /// Restart the counts of the periods that ended before `day`
+    fn roll(&mut self, day: u64) {
+        if day != self.day {
+            self.day = day;
+            self.daily_tokens = 0;
+        }
+        let month = month_of(day);
+        if month != self.month {
+            self.month = month;
+            self.monthly_tokens = 0;
+        }
+    }
+}
+
+/// Tokens used by one API key since the router started, or since the usage was first saved
+#[derive(Debug, Default)]
+pub(crate) struct TokenUsage(Mutex<Counters>);
+
+impl TokenUsage {
+    fn saved(&self) -> Counters {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn restore(&self, counters: Counters) {
+        *self.0.lock().unwrap() = counters;
+    }
+
+    pub(crate) fn record(&self, name: &str, prompt_tokens: u32, completion_tokens: u32) {
+        self.record_at(now(), prompt_tokens, completion_tokens);
+        let key = name.to_string();
+        metrics::counter!("tgi_key_prompt_tokens", "key" => key.clone())
+            .increment(prompt_tokens as u64);
+        metrics::counter!("tgi_key_completion_tokens", "key" => key)
+            .increment(completion_tokens as u64);
+    }
+
+    fn record_at(&self, now: u64, prompt_tokens: u32, completion_tokens: u32) {
+        let tokens = prompt_tokens as u64 + completion_tokens as u64;
+        let mut counters = self.0.lock().unwrap();
+        counters.roll(now / SECONDS_PER_DAY);
+        counters.prompt_tokens += prompt_tokens as u64;
+        counters.completion_tokens += completion_tokens as u64;
+        counters.daily_tokens += tokens;
+        counters.monthly_tokens += tokens;
+    }
+
+    /// The quota that the key used up, if any. The request that reaches a quota still
+    /// completes, the next ones are refused.
+    pub(crate) fn check(&self, quota: &Quota) -> Option<Exceeded> {
+        self.check_at(now(), quota)
+    }
+
+    fn check_at(&self, now: u64, quota: &Quota) -> Option<Exceeded> {
+        let mut counters = self.0.lock().unwrap();
+        let day = now / SECONDS_PER_DAY;
+        counters.roll(day);
+        let seconds_left = |days: u64| Duration::from_secs(days * SECONDS_PER_DAY - now);
+        if quota
+            .monthly_tokens
+            .is_some_and(|quota| counters.monthly_tokens >= quota)
+        {
+            return Some(Exceeded {
+                period: "monthly",
+                resets_in: seconds_left(next_month(day)),
+            });
+        }
+        if quota
+            .daily_tokens
+            .is_some_and(|quota| counters.daily_tokens >= quota)
+        {
+            return Some(Exceeded {
+                period: "daily",
+                resets_in: seconds_left(day + 1),
+            });
+        }
+        None
+    }
+
+    pub(crate) fn report(&self, name: &str, quota: &Quota) -> KeyUsage {
+        let mut counters = self.0.lock().unwrap();
+        counters.roll(now() / SECONDS_PER_DAY);
+        KeyUsage {
+            name: name.to_string(),
+            prompt_tokens: counters.prompt_tokens,
+            completion_tokens: counters.completion_tokens,
+            daily_tokens: counters.daily_tokens,
+            daily_quota: quota.daily_tokens,
+            monthly_tokens: counters.monthly_tokens,
+            monthly_quota: quota.monthly_tokens,
+        }
+    }
+}
+
+/// Content of the `--usage-path` file
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct SavedUsage {
+    /// Counters of the keys, by SHA-256 of the key
+    keys: HashMap<String, Counters>,
+}
+
+/// File keeping the usage of the API keys, so that their quotas survive the restarts of the
+/// router
+pub(crate) struct UsageFile {
+    api_keys: Arc<ApiKeys>,
+    path: PathBuf,
+}
+
+impl UsageFile {
+    /// Restore the usage saved in `path`, if any, and save it there every `SAVE_INTERVAL`
+    pub(crate) fn open(api_keys: Arc<ApiKeys>, path: PathBuf) -> Result<Arc<Self>, AuthError> {
+        let saved: SavedUsage = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => SavedUsage::default(),
+            Err(err) => return Err(err.into()),
+        };
+        for (digest, key) in api_keys.by_digest() {
+            // The keys removed from the keys file are forgotten
+            if let Some(counters) = saved.keys.get(&digest) {
+                key.token_usage().restore(counters.clone());
+            }
+        }
+
+        let file = Arc::new(Self { api_keys, path });
+        let saver = file.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                saver.save().await;
+            }
+        });
+        Ok(file)
+    }
+
+    /// Write the usage of the keys, off the runtime threads
+    pub(crate) async fn save(&self) {
+        let saved = SavedUsage {
+            keys: self
+                .api_keys
+                .by_digest()
+                .map(|(digest, key)| (digest, key.token_usage().saved()))
+                .collect(),
+        };
+        let path = self.path.clone();
+        let write = move || -> std::io::Result<()> {
+            // Written next to the file then moved over it, so that a crash never truncates it
+            let tmp_path = path.with_extension("tmp");
+            std::fs::write(&tmp_path, serde_json::to_vec(&saved)?)?;
+            std::fs::rename(tmp_path, path)
+        };
+        match tokio::task::spawn_blocking(write).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!("Unable to save the usage of the API keys: {err}"),
+            Err(err) => tracing::error!("Unable to save the usage of the API keys: {err}"),
+        }
+    }
+}
+
+/// Seconds since the UNIX epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Year and month of a day since the UNIX epoch, in the Gregorian calendar
+fn month_of(day: u64) -> (u64, u64) {
+    // Days since 0000-03-01, as years starting in March end with their leap day
+    let days = day + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month)
+}
+
+/// First day of the month after the month of `day`
+fn next_month(day: u64) -> u64 {
+    let month = month_of(day);
+    (day + 1..).find(|next| month_of(*next) != month).unwrap()
+}
+
+/// Middleware refusing the requests of the API keys that used up one of their quotas
+pub(crate) async fn enforce(request: Request, next: Next) -> Response {
+    let exceeded = auth::current_key().and_then(|key| key.exceeded_quota());
+    let Some(exceeded) = exceeded else {
+        return next.run(request).await;
+    };
+    metrics::counter!("tgi_request_failure", "err" => "quota_exceeded").increment(1);
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(ErrorResponse::new("quota_exceeded", exceeded.message())),
+    )
+        .into_response();
+    response.headers_mut().insert(
+        RETRY_AFTER,
+        HeaderValue::from(exceeded.resets_in.as_secs().max(1)),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-02-28T12:00:00Z
+    const NOW: u64 = 1_709_121_600;
+
+    #[test]
+    fn test_month_of() {
+        assert_eq!(month_of(0), (1970, 1));
+        assert_eq!(month_of(NOW / SECONDS_PER_DAY), (2024, 2));
+        // 2024-02-29 and 2024-03-01
+        assert_eq!(month_of(NOW / SECONDS_PER_DAY + 1), (2024, 2));
+        assert_eq!(month_of(NOW / SECONDS_PER_DAY + 2), (2024, 3));
+        assert_eq!(next_month(NOW / SECONDS_PER_DAY), NOW / SECONDS_PER_DAY + 2);
+    }
+
+    #[test]
+    fn test_quotas() {
+        let usage = TokenUsage::default();
+        let quota = Quota {
+            daily_tokens: Some(100),
+            monthly_tokens: Some(150),
+        };
+        usage.record_at(NOW, 60, 39);
+        assert_eq!(usage.check_at(NOW, &quota), None);
+
+        usage.record_at(NOW, 0, 1);
+        let exceeded = usage.check_at(NOW, &quota).unwrap();
+        assert_eq!(exceeded.period, "daily");
+        assert_eq!(exceeded.resets_in, Duration::from_secs(SECONDS_PER_DAY / 2));
+
+        // The next day only counts towards the monthly quota
+        let tomorrow = NOW + SECONDS_PER_DAY;
+        assert_eq!(usage.check_at(tomorrow, &quota), None);
+        usage.record_at(tomorrow, 50, 0);
+        let exceeded = usage.check_at(tomorrow, &quota).unwrap();
+        assert_eq!(exceeded.period, "monthly");
+        assert_eq!(exceeded.resets_in, Duration::from_secs(SECONDS_PER_DAY / 2));
+
+        // Until the first day of March
+        let march = NOW + 2 * SECONDS_PER_DAY;
+        assert_eq!(usage.check_at(march, &quota), None);
+        let counters = usage.0.lock().unwrap();
+        assert_eq!(counters.prompt_tokens, 110);
+        assert_eq!(counters.completion_tokens, 40);
+        assert_eq!(counters.monthly_tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_usage_file() {
+        let dir = std::env::temp_dir().join(format!("tgi-usage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys_path = dir.join("keys.json");
+        let keys = r#"{"keys": {"sk-app": {"name": "app", "scopes": ["generate"], "quota": {"daily_tokens": 10}}}}"#;
+        std::fs::write(&keys_path, keys).unwrap();
+        let api_keys = || {
+            let keys_path = keys_path.to_string_lossy().into_owned();
+            Arc::new(ApiKeys::new(None, Some(keys_path)).unwrap().unwrap())
+        };
+        let usage_path = dir.join("usage.json");
+        let _ = std::fs::remove_file(&usage_path);
+
+        let before = api_keys();
+        let file = UsageFile::open(before.clone(), usage_path.clone()).unwrap();
+        let key = before
+            .authorize(Some("Bearer sk-app"), auth::Scope::Generate)
+            .unwrap();
+        key.record_tokens(6, 4);
+        assert!(key.exceeded_quota().is_some());
+        file.save().await;
+        // The secret of the key is not saved
+        assert!(!std::fs::read_to_string(&usage_path)
+            .unwrap()
+            .contains("sk-app"));
+
+        // The quota is still used up after a restart
+        let after = api_keys();
+        UsageFile::open(after.clone(), usage_path.clone()).unwrap();
+        let key = after
+            .authorize(Some("Bearer sk-app"), auth::Scope::Generate)
+            .unwrap();
+        assert!(key.exceeded_quota().is_some());
+        assert_eq!(key.usage().prompt_tokens, 6);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,642 @@
+/// Per API key rate limiting of the generation routes
+use crate::auth::{self, ApiKey};
+use crate::ErrorResponse;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Past this number of tracked clients, the least recently seen ones are forgotten
+const MAX_CLIENTS: usize = 10_000;
+
+/// Retry delay of the clients at their limit of concurrent requests, which have no reset time
+const CONCURRENCY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Limit, remaining and reset (in seconds) headers of each bucket
+const REQUEST_HEADERS: [&str; 3] = [
+    "x-ratelimit-limit-requests",
+    "x-ratelimit-remaining-requests",
+    "x-ratelimit-reset-requests",
+];
+const TOKEN_HEADERS: [&str; 3] = [
+    "x-ratelimit-limit-tokens",
+    "x-ratelimit-remaining-tokens",
+    "x-ratelimit-reset-tokens",
+];
+
+tokio::task_local! {
+    /// Rate limited client of the request being handled
+    static CLIENT: RateLimitedClient;
+}
+
+/// Limits applied to one client
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Limits {
+    /// Requests per second, with bursts of up to one second of requests
+    requests_per_second: Option<f64>,
+    /// Generated tokens per minute
+    tokens_per_minute: Option<u32>,
+    /// Requests in flight, including the streams still being sent
+    max_concurrent_requests: Option<u32>,
+}
+
+impl Limits {
+    fn is_limited(&self) -> bool {
+        self.requests_per_second.is_some()
+            || self.tokens_per_minute.is_some()
+            || self.max_concurrent_requests.is_some()
+    }
+}
+
+/// Content of the `--rate-limit-config-path` file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    /// Limits of the keys that are not listed in `keys`, and of the clients without a key
+    #[serde(default)]
+    default: Limits,
+    /// Limits per API key name
+    #[serde(default)]
+    keys: HashMap<String, Limits>,
+}
+
+#[derive(Debug, Error)]
+pub enum RateLimitError {
+    #[error("Unable to read the rate limit config: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Invalid rate limit config: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    /// Refill rate per second
+    rate: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64, rate: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            available: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until `amount` is available
+    fn wait(&self, amount: f64) -> Duration {
+        Duration::from_secs_f64(((amount - self.available) / self.rate).max(0.0))
+    }
+}
+
+/// Requests of a client in flight
+#[derive(Debug)]
+struct InFlight {
+    limit: u32,
+    count: u32,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+    in_flight: Option<InFlight>,
+}
+
+impl Buckets {
+    fn new(limits: Limits) -> Self {
+        Self {
+            // Allow bursts of one second of requests
+            requests: limits
+                .requests_per_second
+                .map(|rate| Bucket::new(rate.max(1.0), rate)),
+            tokens: limits
+                .tokens_per_minute
+                .map(|capacity| Bucket::new(capacity as f64, capacity as f64 / 60.0)),
+            in_flight: limits
+                .max_concurrent_requests
+                .map(|limit| InFlight { limit, count: 0 }),
+        }
+    }
+}
+
+/// Handle on the buckets of a client, used to charge the generated tokens
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimitedClient(Arc<Mutex<Buckets>>);
+
+impl RateLimitedClient {
+    pub(crate) fn record_tokens(&self, tokens: u32) {
+        let mut buckets = self.0.lock().unwrap();
+        if let Some(bucket) = buckets.tokens.as_mut() {
+            bucket.refill(Instant::now());
+            // The bucket can go negative: the client waits until the debt is paid back
+            bucket.available -= tokens as f64;
+        }
+    }
+}
+
+/// Request of a client with a limit of concurrent requests, in flight until dropped
+#[derive(Debug)]
+struct InFlightGuard(RateLimitedClient);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Some(in_flight) = self.0 .0.lock().unwrap().in_flight.as_mut() {
+            in_flight.count -= 1;
+        }
+    }
+}
+
+/// Slot of a request in flight, in the extensions of the request for the WebSocket connections
+/// that outlive its response
+#[derive(Clone, Debug)]
+pub(crate) struct InFlight(#[allow(dead_code)] Arc<InFlightGuard>);
+
+/// Client of the request being handled, if it is rate limited
+pub(crate) fn current_client() -> Option<RateLimitedClient> {
+    CLIENT.try_with(|client| client.clone()).ok()
+}
+
+/// Run `future` on behalf of `client`, for work spawned outside of the request task
+pub(crate) async fn scope<F: Future>(client: Option<RateLimitedClient>, future: F) -> F::Output {
+    match client {
+        Some(client) => CLIENT.scope(client, future).await,
+        None => future.await,
+    }
+}
+
+/// Request refused because its client is over one of its limits
+#[derive(Debug)]
+pub(crate) struct Limited {
+    pub(crate) message: &'static str,
+    pub(crate) retry_after: Duration,
+}
+
+/// Outcome of a rate limit check, used to fill the rate limit headers
+#[derive(Debug, Default)]
+struct Check {
+    /// (limit, remaining, reset) of the request bucket
+    requests: Option<(f64, f64, Duration)>,
+    /// (limit, remaining, reset) of the token bucket
+    tokens: Option<(f64, f64, Duration)>,
+    /// Wait before the client can retry, if it is over one of its limits
+    retry_after: Option<Duration>,
+    /// Whether the client is at its limit of concurrent requests
+    concurrency_limited: bool,
+    /// Slot of the request, if it is accepted and the client has a limit of concurrent requests
+    in_flight: Option<InFlightGuard>,
+}
+
+impl Check {
+    fn message(&self) -> &'static str {
+        if self.concurrency_limited {
+            "Too many concurrent requests"
+        } else {
+            "Rate limit exceeded"
+        }
+    }
+}
+
+/// Clients are identified by the name of their API key, or by their address when the routes
+/// are open, never by a header they could change at every request
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ClientId {
+    Key(String),
+    Peer(IpAddr),
+}
+
+impl ClientId {
+    fn new(key: Option<&ApiKey>, peer: Option<IpAddr>) -> Self {
+        match key {
+            Some(key) => ClientId::Key(key.name().to_string()),
+            None => ClientId::Peer(peer.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))),
+        }
+    }
+}
+
+/// Buckets of the tracked clients, the least recently seen forgotten past `capacity`
+#[derive(Debug)]
+struct Clients {
+    capacity: usize,
+    clients: HashMap<ClientId, (RateLimitedClient, u64)>,
+    /// Clients by their last use, oldest first
+    last_used: BTreeMap<u64, ClientId>,
+    /// Incremented at every use
+    clock: u64,
+}
+
+impl Clients {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clients: HashMap::new(),
+            last_used: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get_or_insert(&mut self, id: ClientId, limits: Limits) -> RateLimitedClient {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some((client, last_used)) = self.clients.get_mut(&id) {
+            self.last_used.remove(last_used);
+            *last_used = clock;
+            self.last_used.insert(clock, id);
+            return client.clone();
+        }
+
+        if self.clients.len() >= self.capacity {
+            // The requests in flight of a forgotten client keep its old buckets
+            if let Some((_, oldest)) = self.last_used.pop_first() {
+                self.clients.remove(&oldest);
+            }
+        }
+        let client = RateLimitedClient(Arc::new(Mutex::new(Buckets::new(limits))));
+        self.clients.insert(id.clone(), (client.clone(), clock));
+        self.last_used.insert(clock, id);
+        client
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    default: Limits,
+    keys: HashMap<String, Limits>,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    /// Build the rate limiter from the CLI limits and the optional config file.
+    /// Returns `None` if no client is rate limited.
+    pub(crate) fn new(
+        requests_per_second: Option<f64>,
+        tokens_per_minute: Option<u32>,
+        max_concurrent_requests: Option<u32>,
+        config_path: Option<String>,
+    ) -> Result<Option<Self>, RateLimitError> {
+        let mut config = match config_path {
+            Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            None => RateLimitConfig::default(),
+        };
+        // The CLI takes precedence over the default limits of the file
+        config.default.requests_per_second =
+            requests_per_second.or(config.default.requests_per_second);
+        config.default.tokens_per_minute = tokens_per_minute.or(config.default.tokens_per_minute);
+        config.default.max_concurrent_requests =
+            max_concurrent_requests.or(config.default.max_concurrent_requests);
+
+        if !config.default.is_limited() && !config.keys.values().any(Limits::is_limited) {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            default: config.default,
+            keys: config.keys,
+            clients: Mutex::new(Clients::new(MAX_CLIENTS)),
+        }))
+    }
+
+    fn client(&self, id: ClientId) -> Option<RateLimitedClient> {
+        let limits = match &id {
+            ClientId::Key(name) => self.keys.get(name).copied().unwrap_or(self.default),
+            ClientId::Peer(_) => self.default,
+        };
+        if !limits.is_limited() {
+            return None;
+        }
+        Some(self.clients.lock().unwrap().get_or_insert(id, limits))
+    }
+
+    /// Count a new request of the client of `key`, or of `peer` without a key, for the gRPC
+    /// API that does not go through the `rate_limit` middleware. The request stays in flight
+    /// until its slot is dropped.
+    pub(crate) fn admit(
+        &self,
+        key: Option<&ApiKey>,
+        peer: Option<IpAddr>,
+    ) -> Result<(Option<RateLimitedClient>, Option<InFlight>), Limited> {
+        let Some(client) = self.client(ClientId::new(key, peer)) else {
+            return Ok((None, None));
+        };
+        let mut check = Self::check(&client);
+        match check.retry_after {
+            Some(retry_after) => {
+                metrics::counter!("tgi_request_failure", "err" => "rate_limited").increment(1);
+                Err(Limited {
+                    message: check.message(),
+                    retry_after,
+                })
+            }
+            None => {
+                let in_flight = check.in_flight.take();
+                Ok((
+                    Some(client),
+                    in_flight.map(|guard| InFlight(Arc::new(guard))),
+                ))
+            }
+        }
+    }
+
+    /// Count a new request of `client`. It stays in flight until `Check::in_flight` is dropped.
+    fn check(client: &RateLimitedClient) -> Check {
+        let now = Instant::now();
+        let mut buckets = client.0.lock().unwrap();
+        let mut check = Check::default();
+
+        if let Some(bucket) = buckets.tokens.as_mut() {
+            bucket.refill(now);
+            if bucket.available < 1.0 {
+                check.retry_after = Some(bucket.wait(1.0));
+            }
+        }
+        if let Some(in_flight) = buckets.in_flight.as_ref() {
+            if in_flight.count >= in_flight.limit {
+                check.concurrency_limited = true;
+                check.retry_after = Some(
+                    check
+                        .retry_after
+                        .map_or(CONCURRENCY_RETRY_AFTER, |w| w.max(CONCURRENCY_RETRY_AFTER)),
+                );
+            }
+        }
+        if let Some(bucket) = buckets.requests.as_mut() {
+            bucket.refill(now);
+            if bucket.available < 1.0 {
+                let wait = bucket.wait(1.0);
+                check.retry_after = Some(check.retry_after.map_or(wait, |w| w.max(wait)));
+            } else if check.retry_after.is_none() {
+                bucket.available -= 1.0;
+            }
+        }
+
+        check.requests = buckets.requests.as_ref().map(|bucket| {
+            (
+                bucket.capacity,
+                bucket.available,
+                bucket.wait(bucket.capacity),
+            )
+        });
+        check.tokens = buckets.tokens.as_ref().map(|bucket| {
+            (
+                bucket.capacity,
+                bucket.available,
+                bucket.wait(bucket.capacity),
+            )
+        });
+        if check.retry_after.is_none() {
+            if let Some(in_flight) = buckets.in_flight.as_mut() {
+                in_flight.count += 1;
+                check.in_flight = Some(InFlightGuard(client.clone()));
+            }
+        }
+        check
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, check: &Check) {
+    for (names, values) in [
+        (REQUEST_HEADERS, check.requests),
+        (TOKEN_HEADERS, check.tokens),
+    ] {
+        if let Some((limit, remaining, reset)) = values {
+            let [limit_name, remaining_name, reset_name] = names;
+            headers.insert(
+                HeaderName::from_static(limit_name),
+                HeaderValue::from(limit.floor() as u64),
+            );
+            headers.insert(
+                HeaderName::from_static(remaining_name),
+                HeaderValue::from(remaining.max(0.0).floor() as u64),
+            );
+            headers.insert(
+                HeaderName::from_static(reset_name),
+                HeaderValue::from(reset.as_secs_f64().ceil() as u64),
+            );
+        }
+    }
+}
+
+/// Middleware refusing the requests of the clients over their limits
+pub(crate) async fn rate_limit(
+    rate_limiter: Arc<RateLimiter>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // Set by the `authenticate` middleware when the routes need a key
+    let key = auth::current_key();
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let Some(client) = rate_limiter.client(ClientId::new(key.as_deref(), peer)) else {
+        return next.run(request).await;
+    };
+
+    let mut check = RateLimiter::check(&client);
+    let mut response = match check.retry_after {
+        Some(retry_after) => {
+            metrics::counter!("tgi_request_failure", "err" => "rate_limited").increment(1);
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(ErrorResponse::new("rate_limited", check.message())),
+            )
+                .into_response();
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs_f64().ceil().max(1.0) as u64),
+            );
+            response
+        }
+        None => {
+            let in_flight = check
+                .in_flight
+                .take()
+                .map(|guard| InFlight(Arc::new(guard)));
+            if let Some(in_flight) = &in_flight {
+                request.extensions_mut().insert(in_flight.clone());
+            }
+            let response = CLIENT.scope(client, next.run(request)).await;
+            match in_flight {
+                // A stream stays in flight until its body is done
+                Some(in_flight) => {
+                    let (parts, body) = response.into_parts();
+                    let body = body.into_data_stream().map(move |chunk| {
+                        let _in_flight = &in_flight;
+                        chunk
+                    });
+                    Response::from_parts(parts, Body::from_stream(body))
+                }
+                None => response,
+            }
+        }
+    };
+    insert_headers(response.headers_mut(), &check);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> ClientId {
+        ClientId::Key(name.to_string())
+    }
+
+    fn limiter(requests_per_second: Option<f64>, tokens_per_minute: Option<u32>) -> RateLimiter {
+        RateLimiter::new(requests_per_second, tokens_per_minute, None, None)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_no_limits() {
+        assert!(RateLimiter::new(None, None, None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_requests_per_second() {
+        let limiter = limiter(Some(2.0), None);
+        let client = limiter.client(key("key")).unwrap();
+
+        assert!(RateLimiter::check(&client).retry_after.is_none());
+        let check = RateLimiter::check(&client);
+        assert!(check.retry_after.is_none());
+        assert_eq!(check.requests.unwrap().0, 2.0);
+
+        let retry_after = RateLimiter::check(&client).retry_after.unwrap();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(500));
+
+        // Keys have their own buckets
+        let other = limiter.client(key("other")).unwrap();
+        assert!(RateLimiter::check(&other).retry_after.is_none());
+    }
+
+    #[test]
+    fn test_tokens_per_minute() {
+        let limiter = limiter(None, Some(60));
+        let client = limiter.client(key("key")).unwrap();
+        assert!(RateLimiter::check(&client).retry_after.is_none());
+
+        // 61 tokens leave the client 1 token in debt, paid back in about 2 seconds
+        client.record_tokens(61);
+        let retry_after = RateLimiter::check(&client).retry_after.unwrap();
+        assert!(retry_after > Duration::from_secs(1) && retry_after <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        let limiter = RateLimiter::new(None, None, Some(2), None)
+            .unwrap()
+            .unwrap();
+        let client = limiter.client(key("key")).unwrap();
+
+        let first = RateLimiter::check(&client);
+        let second = RateLimiter::check(&client);
+        assert!(first.in_flight.is_some() && second.in_flight.is_some());
+        let check = RateLimiter::check(&client);
+        assert!(check.concurrency_limited);
+        assert_eq!(check.retry_after, Some(CONCURRENCY_RETRY_AFTER));
+        assert!(check.in_flight.is_none());
+
+        // A finished request frees its slot
+        drop(first);
+        let check = RateLimiter::check(&client);
+        assert!(check.retry_after.is_none());
+        assert!(check.in_flight.is_some());
+    }
+
+    #[test]
+    fn test_shared_in_flight() {
+        let limiter = RateLimiter::new(None, None, Some(1), None)
+            .unwrap()
+            .unwrap();
+        let client = limiter.client(key("key")).unwrap();
+        let in_flight = InFlight(Arc::new(RateLimiter::check(&client).in_flight.unwrap()));
+
+        // The upgraded connection keeps the slot once the response is sent
+        let connection = in_flight.clone();
+        drop(in_flight);
+        assert!(RateLimiter::check(&client).concurrency_limited);
+        drop(connection);
+        assert!(RateLimiter::check(&client).in_flight.is_some());
+    }
+
+    #[test]
+    fn test_admit() {
+        let limiter = RateLimiter::new(Some(1.0), None, Some(1), None)
+            .unwrap()
+            .unwrap();
+        let api_key: ApiKey =
+            serde_json::from_str(r#"{"name": "key", "scopes": ["generate"]}"#).unwrap();
+        let (client, in_flight) = limiter.admit(Some(&api_key), None).unwrap();
+        assert!(client.is_some() && in_flight.is_some());
+
+        // Same client as the HTTP requests with the key
+        let client = limiter.client(key("key")).unwrap();
+        assert!(RateLimiter::check(&client).concurrency_limited);
+        drop(in_flight);
+        let limited = limiter.admit(Some(&api_key), None).unwrap_err();
+        assert_eq!(limited.message, "Rate limit exceeded");
+        assert!(limited.retry_after > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_key_limits() {
+        let config = r#"{"default": {"requests_per_second": 1}, "keys": {"admin": {}}}"#;
+        let config: RateLimitConfig = serde_json::from_str(config).unwrap();
+        let limiter = RateLimiter {
+            default: config.default,
+            keys: config.keys,
+            clients: Mutex::new(Clients::new(MAX_CLIENTS)),
+        };
+        assert!(limiter.client(key("admin")).is_none());
+        assert!(limiter.client(key("user")).is_some());
+        // The clients without a key get the default limits
+        let peer = ClientId::Peer(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(limiter.client(peer).is_some());
+    }
+
+    #[test]
+    fn test_clients_capacity() {
+        let limits = Limits {
+            requests_per_second: Some(1.0),
+            ..Limits::default()
+        };
+        let mut clients = Clients::new(2);
+        let first = clients.get_or_insert(key("first"), limits);
+        RateLimiter::check(&first);
+        clients.get_or_insert(key("second"), limits);
+        // The first client is seen again, the second one is now the least recently seen
+        let again = clients.get_or_insert(key("first"), limits);
+        assert!(Arc::ptr_eq(&first.0, &again.0));
+
+        clients.get_or_insert(key("third"), limits);
+        assert_eq!(clients.clients.len(), 2);
+        assert_eq!(clients.last_used.len(), 2);
+        assert!(clients.clients.contains_key(&key("first")));
+        assert!(!clients.clients.contains_key(&key("second")));
+        // The first client kept its bucket
+        assert!(RateLimiter::check(&again).retry_after.is_some());
+    }
+}
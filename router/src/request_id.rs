@@ -0,0 +1,104 @@
+/// Identifier of the requests, to correlate the logs of the router with its clients
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::future::Future;
+use tracing::Instrument;
+
+/// Header carrying the identifier, read from the requests and set on the responses
+pub(crate) static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+/// Longest identifier accepted from a client, longer ones are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 256;
+
+/// Identifier of a request
+#[derive(Clone, Debug)]
+pub(crate) struct RequestId {
+    id: String,
+    /// Whether the router generated the identifier, which then cannot be guessed by the other
+    /// clients
+    generated: bool,
+}
+
+tokio::task_local! {
+    /// Identifier of the request being handled
+    static ID: RequestId;
+}
+
+/// Identifier sent by the client, or a new one if it did not send a usable one
+fn identify(headers: &HeaderMap) -> RequestId {
+    let id = headers
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH);
+    match id {
+        Some(id) => RequestId {
+            id: id.to_string(),
+            generated: false,
+        },
+        None => RequestId {
+            id: uuid::Uuid::new_v4().to_string(),
+            generated: true,
+        },
+    }
+}
+
+/// Identifier of the request being handled
+pub(crate) fn current() -> Option<String> {
+    ID.try_with(|id| id.id.clone()).ok()
+}
+
+/// Whether the router generated the identifier of the request being handled
+pub(crate) fn is_generated() -> bool {
+    ID.try_with(|id| id.generated).unwrap_or(false)
+}
+
+/// Identifier of the request being handled, to run work spawned outside of the request task
+/// on its behalf
+pub(crate) fn current_scope() -> Option<RequestId> {
+    ID.try_with(|id| id.clone()).ok()
+}
+
+/// Run `future` on behalf of request `id`, for work spawned outside of the request task
+pub(crate) async fn scope<F: Future>(id: Option<RequestId>, future: F) -> F::Output {
+    match id {
+        Some(id) => ID.scope(id, future).await,
+        None => future.await,
+    }
+}
+
+/// Middleware running the requests in a span with their identifier, which is sent back in the
+/// `x-request-id` header of the response
+pub(crate) async fn request_id(request: Request, next: Next) -> Response {
+    let id = identify(request.headers());
+    let span = tracing::info_span!("request", request_id = %id.id);
+    let header = HeaderValue::from_str(&id.id).ok();
+    let mut response = ID.scope(id, next.run(request)).instrument(span).await;
+    if let Some(header) = header {
+        response.headers_mut().insert(REQUEST_ID.clone(), header);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identify() {
+        let mut headers = HeaderMap::new();
+        let generated = identify(&headers);
+        assert!(uuid::Uuid::parse_str(&generated.id).is_ok());
+        assert!(generated.generated);
+        assert_ne!(identify(&headers).id, generated.id);
+
+        headers.insert(&REQUEST_ID, HeaderValue::from_static("client-42"));
+        let sent = identify(&headers);
+        assert_eq!(sent.id, "client-42");
+        assert!(!sent.generated);
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+        headers.insert(&REQUEST_ID, HeaderValue::from_str(&too_long).unwrap());
+        assert_ne!(identify(&headers).id, too_long);
+    }
+}
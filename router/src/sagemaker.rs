@@ -53,13 +53,13 @@ content(
 ("text/event-stream" = SagemakerStreamResponse),
 )),
 (status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation", "error_type": "generation"})),
+example = json ! ({"error": {"type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error", "error_type": "validation"})),
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}})),
 (status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation", "error_type": "incomplete_generation"})),
+example = json ! ({"error": {"type": "incomplete_generation", "message": "Incomplete generation"}})),
 )
 )]
 #[instrument(skip_all)]
@@ -1,59 +1,92 @@
 /// HTTP Server logic
 use crate::config::Config;
-use crate::infer::{Backend, Infer, InferError, InferResponse, InferStreamResponse};
+use crate::grpc::{self, RouterService};
+use crate::idempotency::{self, IdempotencyStore};
+use crate::infer::audit::{AuditError, AuditLog};
+use crate::infer::cache::{ResponseCache, ResponseCacheError};
+use crate::infer::content_filter::{ContentFilter, ContentFilterError};
+use crate::infer::holdback::StopHoldback;
+use crate::infer::journal::{self, Journal};
+use crate::infer::swap::ModelFiles;
+use crate::infer::transform::{TransformError, Transforms};
+use crate::infer::watermark::{self, WatermarkError, WatermarkKeys};
+use crate::infer::{Backend, Infer, InferError, InferResponse, InferStreamResponse, ServedModel};
 #[cfg(feature = "kserve")]
 use crate::kserve::{
     kerve_server_metadata, kserve_health_live, kserve_health_ready, kserve_model_infer,
     kserve_model_metadata, kserve_model_metadata_ready,
 };
+use crate::lifecycle::{self, LifecycleEvent};
+use crate::max_new_tokens::{MaxNewTokensError, MaxNewTokensLimits};
+use crate::prompt_templates::{PromptTemplateError, PromptTemplateVersion, PromptTemplates};
+use crate::quota::{self, UsageFile};
+use crate::rate_limit::{self, RateLimitError, RateLimiter};
+use crate::request_id;
 use crate::sagemaker::{
     sagemaker_compatibility, SagemakerRequest, SagemakerResponse, SagemakerStreamResponse,
     __path_sagemaker_compatibility,
 };
-use crate::validation::ValidationError;
+use crate::stream_resume::{self, ResumableStreams};
+use crate::tenant;
+use crate::validation::{vocab_size_mismatch, ImageLimits, ValidationError};
 use crate::vertex::vertex_compatibility;
-use crate::ChatTokenizeResponse;
+use crate::websocket::{generate_stream_ws, MessageLimit};
 use crate::{
-    usage_stats, BestOfSequence, Details, ErrorResponse, FinishReason, FunctionName,
-    GenerateParameters, GenerateRequest, GenerateResponse, GrammarType, HubModelInfo,
-    HubProcessorConfig, HubTokenizerConfig, Info, Message, MessageChunk, MessageContent,
-    OutputMessage, PrefillToken, SimpleToken, StreamDetails, StreamOptions, StreamResponse,
-    TextMessage, Token, TokenizeResponse, Tokenizer, ToolCallDelta, ToolCallMessage, Url, Usage,
-    Validation,
+    auth, usage_stats, AdapterWeight, BatchRecord, BestOfSequence, CacheStats,
+    ChatTemplateVersions, Details, DrainResponse, ErrorDetails, ErrorResponse, FinishReason,
+    FunctionName, GenerateParameters, GenerateRequest, GenerateResponse, GenerationTimings,
+    GrammarType, HealthResponse, HubModelInfo, HubProcessorConfig, HubTokenizerConfig, Info,
+    IterationTimings, Message, MessageChunk, MessageContent, OutputMessage, PrefillToken, Priority,
+    RuntimeConfig, SessionStats, ShardInfo, SimpleToken, StreamDetails, StreamMode, StreamOptions,
+    StreamResponse, TextMessage, Token, TokenizeResponse, Tokenizer, ToolCallDelta,
+    ToolCallMessage, TruncationSide, Url, Usage, Validation,
 };
 use crate::{
     ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionComplete,
     ChatCompletionDelta, ChatCompletionLogprob, ChatCompletionLogprobs, ChatCompletionTopLogprob,
     ChatRequest, Chunk, CompatGenerateRequest, Completion, CompletionComplete, CompletionFinal,
-    CompletionRequest, CompletionType, DeltaToolCall, Function, Prompt, Tool,
+    CompletionLogprobs, CompletionRequest, CompletionType, DeltaToolCall, Function, Prompt, Tool,
 };
+use crate::{ChatTokenizeResponse, DetokenizeRequest, DetokenizeResponse};
+use crate::{Embedding, EmbeddingRequest, EmbeddingResponse, EmbeddingUsage};
 use crate::{FunctionDefinition, HubPreprocessorConfig, ToolCall, ToolChoice};
+use crate::{GenerateBatchRequest, GenerateBatchResult};
+use crate::{KeyUsage, QueueResponse, QueueStats, QueuedRequest, UsageResponse};
+use crate::{LoadAdapterRequest, LoraAdapterInfo};
 use crate::{ModelInfo, ModelsInfo};
+use crate::{SwapModelRequest, SwapModelStatus, SwapState};
+use crate::{
+    WatermarkKeyScore, WatermarkKeysResponse, WatermarkVerifyRequest, WatermarkVerifyResponse,
+};
 use async_stream::__private::AsyncStream;
-use axum::extract::{DefaultBodyLimit, Extension};
-use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::extract::{DefaultBodyLimit, Extension, Path};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use axum::{http, Json, Router};
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
+use futures::future::join_all;
 use futures::stream::StreamExt;
 use futures::stream::{FuturesOrdered, FuturesUnordered};
 use futures::Stream;
 use futures::TryStreamExt;
 use hf_hub::api::tokio::{Api, ApiBuilder, ApiRepo};
 use hf_hub::{Cache, Repo, RepoType};
-use http::header::AUTHORIZATION;
+use http::header::{CONTENT_TYPE, RETRY_AFTER};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
 use regex::Regex;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fs::File;
 use std::io::BufReader;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::select;
 use tokio::signal;
@@ -67,11 +100,13 @@ use utoipa_swagger_ui::SwaggerUi;
 fn encoding_to_tokens(encoding: &tokenizers::Encoding, input: &str) -> Vec<SimpleToken> {
     let offsets = encoding.get_offsets();
     let input_ids = encoding.get_ids();
+    let special_tokens_mask = encoding.get_special_tokens_mask();
     if offsets.len() == input_ids.len() {
         input_ids
             .iter()
             .zip(offsets)
-            .map(|(&id, &(start, stop))| {
+            .enumerate()
+            .map(|(i, (&id, &(start, stop)))| {
                 let text = input
                     .chars()
                     .skip(start)
@@ -82,6 +117,7 @@ fn encoding_to_tokens(encoding: &tokenizers::Encoding, input: &str) -> Vec<Simpl
                     text,
                     start,
                     stop,
+                    special: special_tokens_mask.get(i) == Some(&1),
                 }
             })
             .collect()
@@ -94,6 +130,7 @@ fn encoding_to_tokens(encoding: &tokenizers::Encoding, input: &str) -> Vec<Simpl
                 text: "".to_string(),
                 start: 0,
                 stop: 0,
+                special: false,
             })
             .collect()
     }
@@ -112,13 +149,13 @@ content(
 ("text/event-stream" = StreamResponse),
 )),
 (status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+example = json ! ({"error": {"type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}})),
 (status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"type": "incomplete_generation", "message": "Incomplete generation"}})),
 )
 )]
 #[instrument(skip(infer, req))]
@@ -152,9 +189,18 @@ tag = "Text Generation Inference",
 path = "/info",
 responses((status = 200, description = "Served model info", body = Info))
 )]
-#[instrument]
-async fn get_model_info(info: Extension<Info>) -> Json<Info> {
-    Json(info.0)
+#[instrument(skip(infer))]
+async fn get_model_info(info: Extension<Info>, infer: Extension<Infer>) -> Json<Info> {
+    // The shards of the model may have been swapped for another revision
+    let (max_input_tokens, max_total_tokens) = infer.token_limits();
+    Json(Info {
+        lora_adapters: infer.loaded_adapters(),
+        model_sha: infer.model_sha(),
+        shard_info: infer.shard_info(),
+        max_input_tokens,
+        max_total_tokens,
+        ..info.0
+    })
 }
 
 #[utoipa::path(
@@ -170,12 +216,17 @@ responses(
 /// Get model info
 async fn openai_get_model_info(info: Extension<Info>) -> Json<ModelsInfo> {
     Json(ModelsInfo {
-        data: vec![ModelInfo {
-            id: info.0.model_id.clone(),
-            object: "model".to_string(),
-            created: 0, // TODO: determine how to get this
-            owned_by: info.0.model_id.clone(),
-        }],
+        data: info
+            .0
+            .served_models
+            .iter()
+            .map(|model_id| ModelInfo {
+                id: model_id.clone(),
+                object: "model".to_string(),
+                created: 0, // TODO: determine how to get this
+                owned_by: model_id.clone(),
+            })
+            .collect(),
         ..Default::default()
     })
 }
@@ -215,24 +266,399 @@ get,
 tag = "Text Generation Inference",
 path = "/health",
 responses(
-(status = 200, description = "Everything is working fine"),
+(status = 200, description = "Everything is working fine", body = HealthResponse),
 (status = 503, description = "Text generation inference is down", body = ErrorResponse,
-example = json ! ({"error": "unhealthy", "error_type": "healthcheck"})),
+example = json ! ({"error": {"type": "healthcheck", "message": "unhealthy"}})),
 )
 )]
 #[instrument(skip(infer))]
 /// Health check method
-async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+///
+/// With `--health-check-interval`, returns the result of the last generation probe run through
+/// the queue and the shards instead of checking the shards directly.
+async fn health(
+    infer: Extension<Infer>,
+) -> Result<Json<HealthResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if infer.is_draining() {
+        let error = match (infer.in_flight(), infer.upgraded_connections()) {
+            (0, 0) => "drained".to_string(),
+            (in_flight, 0) => format!("draining, {in_flight} requests in flight"),
+            (in_flight, connections) => format!(
+                "draining, {in_flight} requests in flight and {connections} WebSocket connections open"
+            ),
+        };
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse::new("draining", error)),
+        ));
+    }
+
     match infer.health().await {
-        true => Ok(()),
+        true => Ok(Json(HealthResponse {
+            last_successful_inference: infer.last_successful_inference(),
+        })),
         false => Err((
             StatusCode::SERVICE_UNAVAILABLE,
-            Json(ErrorResponse {
-                error: "unhealthy".to_string(),
-                error_type: "healthcheck".to_string(),
-            }),
+            Json(ErrorResponse::new("healthcheck", "unhealthy")),
+        )),
+    }
+}
+
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/drain",
+responses(
+(status = 200, description = "Draining started", body = DrainResponse),
+)
+)]
+#[instrument(skip(infer))]
+/// Stop accepting new requests and let in-flight generations finish
+async fn drain(infer: Extension<Infer>) -> Json<DrainResponse> {
+    if !infer.is_draining() {
+        tracing::info!("Draining: new requests will be refused");
+    }
+    infer.drain();
+    Json(DrainResponse {
+        in_flight: infer.in_flight(),
+        websocket_connections: infer.upgraded_connections(),
+    })
+}
+
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/config",
+responses((status = 200, description = "Current runtime config", body = RuntimeConfig))
+)]
+/// Settings that can be changed without restarting the router
+async fn get_runtime_config(infer: Extension<Infer>) -> Json<RuntimeConfig> {
+    Json(infer.runtime_config())
+}
+
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/admin/config",
+request_body = RuntimeConfig,
+responses(
+(status = 200, description = "Runtime config applied", body = RuntimeConfig),
+(status = 422, description = "Invalid runtime config", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "`default_temperature` must be strictly positive", "param": "default_temperature"}})),
+)
+)]
+#[instrument(skip(infer))]
+/// Replace the settings of the validation and of the scheduler
+///
+/// The requests validated and the batches formed from now on use the new settings.
+async fn update_runtime_config(
+    infer: Extension<Infer>,
+    Json(config): Json<RuntimeConfig>,
+) -> Result<Json<RuntimeConfig>, (StatusCode, Json<ErrorResponse>)> {
+    let invalid = |message: &str, param: &str| {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse::new("validation", message).with_param(param)),
+        )
+    };
+    if config.default_temperature <= 0.0 {
+        return Err(invalid(
+            "`default_temperature` must be strictly positive",
+            "default_temperature",
+        ));
+    }
+    if config.max_new_tokens == Some(0) {
+        return Err(invalid(
+            "`max_new_tokens` must be strictly positive",
+            "max_new_tokens",
+        ));
+    }
+    if let Some(waiting_served_ratio) = config.waiting_served_ratio {
+        if waiting_served_ratio < 0.0 {
+            return Err(invalid(
+                "`waiting_served_ratio` must be positive",
+                "waiting_served_ratio",
+            ));
+        }
+        if infer.runtime_config().waiting_served_ratio.is_none() {
+            return Err(invalid(
+                "`waiting_served_ratio` is not supported by this backend",
+                "waiting_served_ratio",
+            ));
+        }
+    }
+
+    tracing::info!("Runtime config changed: {config:?}");
+    infer.set_runtime_config(config);
+    Ok(Json(infer.runtime_config()))
+}
+
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/admin/adapters",
+request_body = LoadAdapterRequest,
+responses(
+(status = 200, description = "Adapter loaded", body = LoraAdapterInfo),
+(status = 409, description = "Adapter already loaded", body = ErrorResponse,
+example = json ! ({"error": {"type": "adapter_already_loaded", "message": "LoRA adapter `predibase/customer_support` is already loaded"}})),
+(status = 422, description = "Adapter could not be loaded", body = ErrorResponse,
+example = json ! ({"error": {"type": "adapter", "message": "Shards could not load or unload the LoRA adapter: No adapter weights found"}})),
+(status = 501, description = "Backend cannot load adapters", body = ErrorResponse),
+)
+)]
+#[instrument(skip(infer))]
+/// Load a LoRA adapter in the shards, which download its weights
+///
+/// The requests can use it with its `adapter_id` once it is loaded.
+async fn load_adapter(
+    infer: Extension<Infer>,
+    Json(request): Json<LoadAdapterRequest>,
+) -> Result<Json<LoraAdapterInfo>, (StatusCode, Json<ErrorResponse>)> {
+    let adapter = infer.load_adapter(request).await.map_err(|err| {
+        tracing::error!("{err}");
+        err
+    })?;
+    tracing::info!("Loaded LoRA adapter `{}`", adapter.id);
+    Ok(Json(adapter))
+}
+
+#[utoipa::path(
+delete,
+tag = "Text Generation Inference",
+path = "/admin/adapters/{id}",
+params(("id" = String, Path, description = "Id of the adapter")),
+responses(
+(status = 204, description = "Adapter unloaded"),
+(status = 404, description = "Adapter not loaded", body = ErrorResponse,
+example = json ! ({"error": {"type": "adapter_not_loaded", "message": "LoRA adapter `predibase/customer_support` is not loaded"}})),
+(status = 422, description = "Adapter is used by running requests", body = ErrorResponse),
+(status = 501, description = "Backend cannot load adapters", body = ErrorResponse),
+)
+)]
+#[instrument(skip(infer))]
+/// Unload a LoRA adapter, once no running request uses it
+async fn unload_adapter(
+    infer: Extension<Infer>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    infer.unload_adapter(&id).await.map_err(|err| {
+        tracing::error!("{err}");
+        err
+    })?;
+    tracing::info!("Unloaded LoRA adapter `{id}`");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/admin/model/swap",
+request_body = SwapModelRequest,
+responses(
+(status = 202, description = "Swap started, its progress is reported by GET /admin/model/swap", body = SwapModelStatus),
+(status = 409, description = "Shards already being replaced", body = ErrorResponse,
+example = json ! ({"error": {"type": "swap_in_progress", "message": "The shards of the model are already being replaced or reconnected"}})),
+(status = 422, description = "New shards cannot serve another revision of the model", body = ErrorResponse,
+example = json ! ({"error": {"type": "swap", "message": "Could not switch over to the new shards: `llama-guard` is not a served model"}})),
+(status = 501, description = "Backend cannot switch over to new shards", body = ErrorResponse),
+)
+)]
+#[instrument(skip(infer, model_source))]
+/// Switch a model over to new shard-sets, e.g. of a new revision of the model
+///
+/// The launcher starts the new shard-set unless the sockets of shard-sets started separately
+/// are given. The new shard-sets are warmed up before they receive any request, and the model
+/// keeps serving on the old ones meanwhile. Once the requests are switched over, the router
+/// validates them with the tokenizer and the limits of the new revision, and the requests
+/// still running on the old shard-sets finish there before the launcher stops them. Nothing
+/// changes if a new shard-set fails to warm up.
+///
+/// The swap runs in the background, `GET /admin/model/swap` reports its progress.
+async fn swap_model(
+    infer: Extension<Infer>,
+    model_source: Extension<ModelSource>,
+    Json(request): Json<SwapModelRequest>,
+) -> Result<(StatusCode, Json<SwapModelStatus>), (StatusCode, Json<ErrorResponse>)> {
+    let status = infer.begin_swap(&request).map_err(|err| {
+        tracing::error!("{err}");
+        err
+    })?;
+    let infer = infer.0.clone();
+    let model_source = match &request.model {
+        Some(model) if infer.serves_other_model(model) => model_source.for_model(model),
+        _ => model_source.0.clone(),
+    };
+    let revision = request.revision.clone();
+    let files = async move {
+        let Some(revision) = revision else {
+            return Ok(None);
+        };
+        let (files, _) = load_model(&model_source, Some(revision))
+            .await
+            .map_err(|err| InferError::SwapError(err.to_string()))?;
+        Ok(Some(files))
+    };
+    tokio::spawn(
+        async move { infer.swap_model(request, files).await }.instrument(tracing::Span::current()),
+    );
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/model/swap",
+responses(
+(status = 200, description = "Progress of the last swap", body = SwapModelStatus),
+(status = 404, description = "No model was swapped", body = ErrorResponse,
+example = json ! ({"error": {"type": "not_found", "message": "No model was swapped"}})),
+)
+)]
+/// Progress of the last swap of the shards of a model, and of the drain of its old shard-sets
+async fn swap_status(
+    infer: Extension<Infer>,
+) -> Result<Json<SwapModelStatus>, (StatusCode, Json<ErrorResponse>)> {
+    infer.swap_status().map(Json).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(
+                "not_found",
+                "No model was swapped".to_string(),
+            )),
+        )
+    })
+}
+
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v3/queue",
+responses(
+(status = 200, description = "Queued requests and their totals", body = QueueResponse),
+(status = 401, description = "Invalid or missing API key", body = ErrorResponse,
+example = json ! ({"error": {"type": "unauthorized", "message": "Invalid or missing API key"}})),
+)
+)]
+/// Requests waiting in the queues of the backend, with their age, tokens, priority and
+/// tenant
+async fn get_queue(infer: Extension<Infer>) -> Json<QueueResponse> {
+    let requests = infer.queued_requests().await;
+    Json(QueueResponse {
+        stats: QueueStats::new(&requests),
+        requests,
+    })
+}
+
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/usage",
+responses(
+(status = 200, description = "Tokens used by the API keys", body = UsageResponse),
+(status = 401, description = "Invalid or missing API key", body = ErrorResponse,
+example = json ! ({"error": {"type": "unauthorized", "message": "Invalid or missing API key"}})),
+)
+)]
+/// Tokens used by the API key of the request since the router started, or by all the keys for
+/// the keys with the `admin` scope
+async fn get_usage(Extension(api_keys): Extension<Arc<auth::ApiKeys>>) -> Json<UsageResponse> {
+    let keys = match auth::current_key() {
+        Some(key) if !key.has_scope(auth::Scope::Admin) => vec![key.usage()],
+        _ => api_keys.usage(),
+    };
+    Json(UsageResponse { keys })
+}
+
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/watermark/keys",
+responses(
+(status = 200, description = "Ids of the watermark keys", body = WatermarkKeysResponse),
+(status = 404, description = "No watermark key configured", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "No watermark key configured"}})),
+)
+)]
+/// Ids of the active watermark key and of the keys that the texts are scored with
+async fn get_watermark_keys(
+    infer: Extension<Infer>,
+) -> Result<Json<WatermarkKeysResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let watermark_keys = infer.watermark_keys().ok_or_else(no_watermark_keys)?;
+    let (active, keys) = watermark_keys.ids();
+    Ok(Json(WatermarkKeysResponse { active, keys }))
+}
+
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/admin/watermark/keys/reload",
+responses(
+(status = 200, description = "Watermark keys reloaded", body = WatermarkKeysResponse),
+(status = 404, description = "No watermark key configured", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "No watermark key configured"}})),
+(status = 422, description = "Invalid watermark keys file", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "The active watermark key `2024-06` is not one of the keys"}})),
+)
+)]
+#[instrument(skip(infer))]
+/// Read the watermark keys file again, to rotate the keys
+///
+/// The new generations use the new active key. The current keys are kept if the file is
+/// invalid.
+async fn reload_watermark_keys(
+    infer: Extension<Infer>,
+) -> Result<Json<WatermarkKeysResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let watermark_keys = infer.watermark_keys().ok_or_else(no_watermark_keys)?;
+    watermark_keys.reload().map_err(|err| {
+        tracing::error!("{err}");
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse::new("validation", err.to_string())),
+        )
+    })?;
+    let (active, keys) = watermark_keys.ids();
+    Ok(Json(WatermarkKeysResponse { active, keys }))
+}
+
+fn no_watermark_keys() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new(
+            "validation",
+            "No watermark key configured",
         )),
+    )
+}
+
+#[utoipa::path(
+delete,
+tag = "Text Generation Inference",
+path = "/generate/{request_id}",
+params(("request_id" = String, Path, description = "`x-request-id` of the generation")),
+responses(
+(status = 204, description = "Generation aborted"),
+(status = 404, description = "No generation in flight with this id", body = ErrorResponse,
+example = json ! ({"error": {"type": "not_found", "message": "No generation in flight with id `a1b2`"}})),
+)
+)]
+#[instrument(skip(infer))]
+/// Abort a queued or running generation of the client
+async fn abort_generate(
+    infer: Extension<Infer>,
+    Path(request_id): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    if !infer.abort(&request_id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(
+                "not_found",
+                format!("No generation in flight with id `{request_id}`"),
+            )),
+        ));
     }
+    Ok(StatusCode::NO_CONTENT)
 }
 
 /// Generate tokens
@@ -244,13 +670,13 @@ request_body = GenerateRequest,
 responses(
 (status = 200, description = "Generated Text", body = GenerateResponse),
 (status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+example = json ! ({"error": {"type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}})),
 (status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"type": "incomplete_generation", "message": "Incomplete generation"}})),
 )
 )]
 #[instrument(
@@ -279,11 +705,12 @@ async fn generate(
 pub(crate) async fn generate_internal(
     infer: Extension<Infer>,
     ComputeType(compute_type): ComputeType,
-    Json(req): Json<GenerateRequest>,
+    Json(mut req): Json<GenerateRequest>,
     span: tracing::Span,
 ) -> Result<(HeaderMap, u32, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
     let start_time = Instant::now();
     metrics::counter!("tgi_request_count").increment(1);
+    let prompt_template = infer.render_prompt_template(&mut req)?;
 
     // Do not long ultra long inputs, like image payloads.
     tracing::debug!(
@@ -297,11 +724,23 @@ pub(crate) async fn generate_internal(
         add_prompt = Some(req.inputs.clone());
     }
 
-    let details: bool = req.parameters.details || req.parameters.decoder_input_details;
+    let details: bool = req.parameters.details
+        || req.parameters.decoder_input_details
+        || req.parameters.return_all_sequences;
+    let metadata = req.parameters.metadata.clone();
+    let warnings: Vec<String> = infer
+        .max_new_tokens_warning(&req.parameters)
+        .into_iter()
+        .collect();
 
     // Inference
-    let (response, best_of_responses) = match req.parameters.best_of {
-        Some(best_of) if best_of > 1 => {
+    let (response, best_of_responses) = match (req.parameters.best_of, req.parameters.n) {
+        (_, Some(n)) if n != 1 => {
+            let mut responses = infer.generate_n(req).await?;
+            let response = responses.remove(0);
+            (response, Some(responses))
+        }
+        (Some(best_of), _) if best_of > 1 => {
             let (response, best_of_responses) = infer.generate_best_of(req, best_of).await?;
             (response, Some(best_of_responses))
         }
@@ -336,6 +775,13 @@ pub(crate) async fn generate_internal(
                     .collect()
             });
 
+            let timings = GenerationTimings::new(
+                response.queued,
+                response.start,
+                response.first_token,
+                Instant::now(),
+                &response.generated_text,
+            );
             Some(Details {
                 finish_reason: response.generated_text.finish_reason,
                 generated_tokens: response.generated_text.generated_tokens,
@@ -344,6 +790,8 @@ pub(crate) async fn generate_internal(
                 seed: response.generated_text.seed,
                 best_of_sequences,
                 top_tokens: response.top_tokens,
+                timings,
+                prompt_template,
             })
         }
         false => None,
@@ -424,10 +872,108 @@ pub(crate) async fn generate_internal(
     let response = GenerateResponse {
         generated_text: output_text,
         details,
+        metadata,
+        warnings,
     };
     Ok((headers, input_length, Json(response)))
 }
 
+/// Generate tokens for several prompts
+///
+/// The prompts are queued as separate requests with the same parameters, and their results are
+/// returned in the order of the prompts. A prompt whose request fails gets its error in place of
+/// its generation, without failing the other prompts.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/generate_batch",
+request_body = GenerateBatchRequest,
+responses(
+(status = 200, description = "Generated Texts, or errors, in the order of the inputs",
+body = Vec<GenerateBatchResult>),
+(status = 422, description = "Too many inputs", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "param": "inputs", "message": "Number of inputs exceeds the maximum allowed batch size of 4"}})),
+)
+)]
+#[instrument(skip_all, fields(inputs = req.inputs.len(), parameters = ? req.parameters))]
+async fn generate_batch(
+    infer: Extension<Infer>,
+    Extension(ComputeType(compute_type)): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    Json(req): Json<GenerateBatchRequest>,
+) -> Result<(HeaderMap, Json<Vec<GenerateBatchResult>>), (StatusCode, Json<ErrorResponse>)> {
+    let start_time = Instant::now();
+    if req.inputs.len() > info.max_client_batch_size {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(
+                ErrorResponse::new(
+                    "validation",
+                    format!(
+                        "Number of inputs exceeds the maximum allowed batch size of {}",
+                        info.max_client_batch_size
+                    ),
+                )
+                .with_param("inputs"),
+            ),
+        ));
+    }
+
+    let span = tracing::Span::current();
+    let GenerateBatchRequest { inputs, parameters } = req;
+    let compute_characters: usize = inputs.iter().map(|inputs| inputs.chars().count()).sum();
+    // Queued concurrently, like separate HTTP calls
+    let results = join_all(inputs.into_iter().map(|inputs| {
+        let request = GenerateRequest {
+            inputs,
+            template: None,
+            variables: None,
+            parameters: parameters.clone(),
+            add_special_tokens: true,
+        };
+        generate_internal(
+            infer.clone(),
+            ComputeType(compute_type.clone()),
+            Json(request),
+            span.clone(),
+        )
+    }))
+    .await;
+
+    let mut prompt_tokens = 0u32;
+    let mut generated_tokens = 0u32;
+    let results: Vec<GenerateBatchResult> = results
+        .into_iter()
+        .map(|result| match result {
+            Ok((headers, input_length, Json(response))) => {
+                prompt_tokens += input_length;
+                generated_tokens += headers
+                    .get("x-generated-tokens")
+                    .and_then(|v| v.to_str().ok()?.parse::<u32>().ok())
+                    .unwrap_or(0);
+                GenerateBatchResult::Generation(response)
+            }
+            Err((_, Json(error))) => GenerateBatchResult::Error(error),
+        })
+        .collect();
+
+    let total_time = start_time.elapsed();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-compute-type", compute_type.parse().unwrap());
+    headers.insert(
+        "x-compute-time",
+        total_time.as_secs_f64().to_string().parse().unwrap(),
+    );
+    headers.insert(
+        "x-compute-characters",
+        compute_characters.to_string().parse().unwrap(),
+    );
+    headers.insert("x-prompt-tokens", prompt_tokens.into());
+    headers.insert("x-generated-tokens", generated_tokens.into());
+    Ok((headers, Json(results)))
+}
+
 /// Generate a stream of token using Server-Sent Events
 #[utoipa::path(
 post,
@@ -438,16 +984,16 @@ responses(
 (status = 200, description = "Generated Text", body = StreamResponse,
 content_type = "text/event-stream"),
 (status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"}),
+example = json ! ({"error": {"type": "generation", "message": "Request failed during generation"}}),
 content_type = "text/event-stream"),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"}),
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}}),
 content_type = "text/event-stream"),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"}),
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}}),
 content_type = "text/event-stream"),
 (status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"}),
+example = json ! ({"error": {"type": "incomplete_generation", "message": "Incomplete generation"}}),
 content_type = "text/event-stream"),
 )
 )]
@@ -466,45 +1012,88 @@ seed,
 async fn generate_stream(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
-    Json(req): Json<GenerateRequest>,
+    Json(mut req): Json<GenerateRequest>,
 ) -> (
     HeaderMap,
     Sse<impl Stream<Item = Result<Event, Infallible>>>,
 ) {
     let span = tracing::Span::current();
+    let keep_alive = keep_alive(&infer);
+    let stream_mode = req.parameters.stream_mode;
+    if stream_mode == StreamMode::Events {
+        // The `done` event always carries the finish reason and the usage of the generation
+        req.parameters.details = true;
+    }
     let (headers, response_stream) =
         generate_stream_internal(infer, compute_type, Json(req), span).await;
 
     let response_stream = async_stream::stream! {
         let mut response_stream = Box::pin(response_stream);
         while let Some(raw_event) = response_stream.next().await {
-            yield Ok(raw_event.map_or_else(Event::from, |token| {
-                Event::default()
+            if stream_mode == StreamMode::Events {
+                for event in lifecycle::generate_events(raw_event) {
+                    yield Ok(event);
+                }
+                continue;
+            }
+            yield Ok(match raw_event {
+                Ok(StreamEvent::Queued(position)) => queue_position_comment(position),
+                Ok(StreamEvent::Prefilling) => continue,
+                Ok(StreamEvent::Token(token)) => Event::default()
                     .json_data(token)
-                    .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into())
-            }));
+                    .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into()),
+                Err(err) => Event::from(err),
+            });
         }
     };
 
-    let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
+    let sse = Sse::new(response_stream).keep_alive(keep_alive);
     (headers, sse)
 }
 
-async fn generate_stream_internal(
+/// Item of the streams of `generate_stream_internal`
+pub(crate) enum StreamEvent {
+    /// The request did not receive its first token yet, and waits behind `position` requests
+    Queued(usize),
+    /// The request entered a batch, its first token follows its prefill
+    Prefilling,
+    Token(StreamResponse),
+}
+
+/// Comments sent on the SSE streams to keep the connections of idle streams open
+fn keep_alive(infer: &Infer) -> KeepAlive {
+    KeepAlive::new()
+        .interval(infer.stream_heartbeat_interval())
+        .text("ping")
+}
+
+/// `queue_position` comment of the SSE streams, sent until the first token. A comment leaves
+/// the stream readable by the clients that expect a token in every event.
+fn queue_position_comment(position: usize) -> Event {
+    Event::default().comment(format!("queue_position {position}"))
+}
+
+pub(crate) async fn generate_stream_internal(
     infer: Infer,
     ComputeType(compute_type): ComputeType,
-    Json(req): Json<GenerateRequest>,
+    Json(mut req): Json<GenerateRequest>,
     span: tracing::Span,
 ) -> (
     HeaderMap,
-    impl Stream<Item = Result<StreamResponse, InferError>>,
+    impl Stream<Item = Result<StreamEvent, InferError>>,
 ) {
     let start_time = Instant::now();
     metrics::counter!("tgi_request_count").increment(1);
+    // The error is sent on the stream
+    let (prompt_template, template_error) = match infer.render_prompt_template(&mut req) {
+        Ok(prompt_template) => (prompt_template, None),
+        Err(err) => (None, Some(err)),
+    };
 
     tracing::debug!("Input: {}", req.inputs);
 
     let compute_characters = req.inputs.chars().count();
+    req.parameters.stream = true;
 
     let mut headers = HeaderMap::new();
     headers.insert("x-compute-type", compute_type.parse().unwrap());
@@ -514,6 +1103,9 @@ async fn generate_stream_internal(
     );
     headers.insert("X-Accel-Buffering", "no".parse().unwrap());
 
+    // The stream is polled outside of the request task
+    let api_key = auth::current_key();
+    let request_id = request_id::current_scope();
     let stream = async_stream::stream! {
         // Inference
         let mut end_reached = false;
@@ -524,9 +1116,14 @@ async fn generate_stream_internal(
             add_prompt = Some(req.inputs.clone());
         }
         let details = req.parameters.details;
+        let metadata = req.parameters.metadata.clone();
+        let warnings: Vec<String> = infer.max_new_tokens_warning(&req.parameters).into_iter().collect();
+        let mut holdback = StopHoldback::new(req.parameters.stop.clone());
 
         let best_of = req.parameters.best_of.unwrap_or(1);
-        if best_of != 1 {
+        if let Some(err) = template_error {
+            yield Err(err);
+        } else if best_of != 1 {
             let err = InferError::from(ValidationError::BestOfStream);
             metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
@@ -537,17 +1134,23 @@ async fn generate_stream_internal(
             tracing::error!("{err}");
             yield Err(err);
         } else {
-            match infer.generate_stream(req).instrument(info_span!(parent: &span, "async_stream")).await {
+            match request_id::scope(request_id, auth::scope(api_key, infer.generate_stream(req))).instrument(info_span!(parent: &span, "async_stream")).await {
                 // Keep permit as long as generate_stream lives
                 Ok((_permit, input_length, response_stream)) => {
                     let mut index = 0;
+                    let mut first_token_time = None;
                     let mut response_stream = Box::pin(response_stream);
                     // Server-Sent Event stream
                     while let Some(response) = response_stream.next().await {
-                        index += 1;
                         match response {
                             Ok(response) => {
                                 match response {
+                                    InferStreamResponse::Queued { position } => {
+                                        yield Ok(StreamEvent::Queued(position));
+                                    }
+                                    InferStreamResponse::Started => {
+                                        yield Ok(StreamEvent::Prefilling);
+                                    }
                                     // Prefill is ignored
                                     InferStreamResponse::Prefill(_) => {}
                                     // Yield event for every new token
@@ -556,16 +1159,23 @@ async fn generate_stream_internal(
                                         top_tokens,
                                     } => {
                                         tracing::debug!(parent: &span, "Token: {:?}", token);
-
-                                        // StreamResponse
-                                        let stream_token = StreamResponse {
-                                            index,
-                                            token,
-                                            top_tokens,
-                                            generated_text: None,
-                                            details: None,
-                                        };
-                                        yield Ok(stream_token);
+                                        first_token_time.get_or_insert_with(Instant::now);
+
+                                        // Tokens that may start a stop sequence are held back
+                                        for (token, top_tokens) in holdback.push(token, top_tokens) {
+                                            index += 1;
+                                            // StreamResponse
+                                            let stream_token = StreamResponse {
+                                                index,
+                                                token,
+                                                top_tokens,
+                                                generated_text: None,
+                                                details: None,
+                                                metadata: None,
+                                                warnings: Vec::new(),
+                                            };
+                                            yield Ok(StreamEvent::Token(stream_token));
+                                        }
                                     }
                                     // Yield event for last token and compute timings
                                     InferStreamResponse::End {
@@ -575,6 +1185,18 @@ async fn generate_stream_internal(
                                         queued,
                                         top_tokens,
                                     } => {
+                                        let (released, (token, top_tokens)) =
+                                            holdback.finish(token, top_tokens, &generated_text.finish_reason);
+
+                                        // Timings
+                                        let total_time = start_time.elapsed();
+                                        let validation_time = queued - start_time;
+                                        let queue_time = start - queued;
+                                        let inference_time = Instant::now() - start;
+                                        let time_per_token = inference_time / generated_text.generated_tokens;
+                                        let first_token_time = *first_token_time.get_or_insert_with(Instant::now);
+                                        let timings = GenerationTimings::new(queued, start, first_token_time, start + inference_time, &generated_text);
+
                                         // Token details
                                         let details = match details {
                                             true => Some(StreamDetails {
@@ -582,17 +1204,12 @@ async fn generate_stream_internal(
                                                 generated_tokens: generated_text.generated_tokens,
                                                 seed: generated_text.seed,
                                                 input_length,
+                                                timings,
+                                                prompt_template,
                                             }),
                                             false => None,
                                         };
 
-                                        // Timings
-                                        let total_time = start_time.elapsed();
-                                        let validation_time = queued - start_time;
-                                        let queue_time = start - queued;
-                                        let inference_time = Instant::now() - start;
-                                        let time_per_token = inference_time / generated_text.generated_tokens;
-
                                         // Tracing metadata
                                         span.record("total_time", format!("{total_time:?}"));
                                         span.record("validation_time", format!("{validation_time:?}"));
@@ -621,15 +1238,32 @@ async fn generate_stream_internal(
                                         tracing::debug!(parent: &span, "Output: {}", output_text);
                                         tracing::info!(parent: &span, "Success");
 
+                                        for (token, top_tokens) in released {
+                                            index += 1;
+                                            let stream_token = StreamResponse {
+                                                index,
+                                                token,
+                                                top_tokens,
+                                                generated_text: None,
+                                                details: None,
+                                                metadata: None,
+                                                warnings: Vec::new(),
+                                            };
+                                            yield Ok(StreamEvent::Token(stream_token));
+                                        }
+
+                                        index += 1;
                                         let stream_token = StreamResponse {
                                             index,
                                             token,
                                             top_tokens,
                                             generated_text: Some(output_text),
-                                            details
+                                            details,
+                                            metadata,
+                                            warnings,
                                         };
 
-                                        yield Ok(stream_token);
+                                        yield Ok(StreamEvent::Token(stream_token));
                                         break;
                                     }
                                 }
@@ -676,13 +1310,13 @@ content(
 ("text/event-stream" = Chunk),
 )),
 (status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+example = json ! ({"error": {"type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}})),
 (status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"type": "incomplete_generation", "message": "Incomplete generation"}})),
 )
 )]
 #[instrument(
@@ -713,11 +1347,16 @@ pub(crate) async fn completions(
         stop,
         stream,
         temperature,
+        logprobs,
+        echo,
+        best_of,
+        n,
         ..
     } = req;
 
     let max_new_tokens = max_tokens;
     let stop = stop.unwrap_or_default();
+    let stop_token_ids = req.stop_token_ids.clone().unwrap_or_default();
     // enable greedy only when temperature is 0
     let (do_sample, temperature) = match temperature {
         Some(temperature) if temperature == 0.0 => (false, None),
@@ -729,11 +1368,13 @@ pub(crate) async fn completions(
         metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Suffix is not supported and can be achieved by preprocessing the prompt."
-                    .to_string(),
-                error_type: "suffix not supported".to_string(),
-            }),
+            Json(
+                ErrorResponse::new(
+                    "validation",
+                    "Suffix is not supported and can be achieved by preprocessing the prompt.",
+                )
+                .with_param("suffix"),
+            ),
         ));
     }
 
@@ -741,13 +1382,35 @@ pub(crate) async fn completions(
         metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: format!(
-                    "Number of prompts exceeds the maximum allowed batch size of {}",
-                    info.max_client_batch_size
-                ),
-                error_type: "batch size exceeded".to_string(),
-            }),
+            Json(
+                ErrorResponse::new(
+                    "validation",
+                    format!(
+                        "Number of prompts exceeds the maximum allowed batch size of {}",
+                        info.max_client_batch_size
+                    ),
+                )
+                .with_param("prompt"),
+            ),
+        ));
+    }
+
+    // The choices of all the prompts are generated at once
+    let choices_per_prompt = n.map_or(1, |n| n.max(1)) as usize;
+    if req.prompt.0.len() * choices_per_prompt > info.max_client_batch_size {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(
+                ErrorResponse::new(
+                    "validation",
+                    format!(
+                        "Number of choices exceeds the maximum allowed batch size of {}",
+                        info.max_client_batch_size
+                    ),
+                )
+                .with_param("n"),
+            ),
         ));
     }
 
@@ -757,27 +1420,53 @@ pub(crate) async fn completions(
         .iter()
         .map(|prompt| GenerateRequest {
             inputs: prompt.to_string(),
+            template: None,
+            variables: None,
             add_special_tokens: true,
             parameters: GenerateParameters {
-                best_of: None,
+                best_of,
+                return_all_sequences: false,
                 temperature,
                 repetition_penalty: req.repetition_penalty,
                 frequency_penalty: req.frequency_penalty,
+                presence_penalty: req.presence_penalty,
                 top_k: None,
                 top_p: req.top_p,
-                typical_p: None,
+                typical_p: req.typical_p,
+                min_p: req.min_p,
+                no_repeat_ngram_size: None,
+                guidance_scale: None,
+                negative_prompt: None,
                 do_sample,
                 max_new_tokens,
-                return_full_text: None,
+                min_new_tokens: req.min_tokens,
+                return_full_text: Some(echo && !stream),
                 stop: stop.clone(),
+                stop_token_ids: stop_token_ids.clone(),
                 truncate: None,
-                watermark: false,
+                truncation_side: None,
+                watermark: req.watermark,
                 details: true,
-                decoder_input_details: !stream,
+                // Prompt logprobs are only returned with the echoed prompt
+                decoder_input_details: echo && logprobs.is_some() && !stream,
                 seed,
-                top_n_tokens: None,
+                top_n_tokens: logprobs.filter(|n| *n > 0),
                 grammar: None,
                 adapter_id: model.as_ref().filter(|m| *m != "tgi").map(String::from),
+                adapters: None,
+                priority: None,
+                speculate: None,
+                session_id: None,
+                continue_from: None,
+                timeout_ms: None,
+                deadline_ms: None,
+                token_healing: false,
+                logit_bias: None,
+                metadata: None,
+                stream_mode: StreamMode::Deltas,
+                stream,
+                n,
+                prompt_group: None,
             },
         })
         .collect();
@@ -787,8 +1476,14 @@ pub(crate) async fn completions(
     let mut x_accel_buffering = None;
 
     if stream {
+        let keep_alive = keep_alive(&infer);
+        // Each choice is streamed on its own, after the choices of the previous prompts
+        let generate_requests = generate_requests
+            .into_iter()
+            .map(|generate_request| infer.choice_requests(generate_request))
+            .collect::<Result<Vec<_>, _>>()?;
         let mut response_streams = FuturesOrdered::new();
-        for (index, generate_request) in generate_requests.into_iter().enumerate() {
+        for (index, generate_request) in generate_requests.into_iter().flatten().enumerate() {
             let model_id = info.model_id.clone();
             let system_fingerprint =
                 format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
@@ -800,90 +1495,130 @@ pub(crate) async fn completions(
             let generate_future = async move {
                 let (header_tx, header_rx) = oneshot::channel();
                 let (sse_tx, sse_rx) = tokio::sync::mpsc::unbounded_channel();
+                let rate_limited_client = rate_limit::current_client();
+                let tenant = tenant::current();
+                let api_key = auth::current_key();
+                let request_id = request_id::current_scope();
+
+                let task = rate_limit::scope(
+                    rate_limited_client,
+                    tenant::scope(tenant, async move {
+                        let prompt = echo.then(|| generate_request.inputs.clone());
+                        let (headers, response_stream) = generate_stream_internal(
+                            infer_clone.clone(),
+                            compute_type_clone.clone(),
+                            Json(generate_request),
+                            span_clone.clone(),
+                        )
+                        .await;
+
+                        let response_stream = async_stream::stream! {
+                            let mut response_stream = Box::pin(response_stream);
+                            let mut text_offset = 0u32;
+
+                            // The prompt is sent back as its own chunk before any token
+                            if let Some(prompt) = prompt {
+                                text_offset = prompt.chars().count() as u32;
+                                let current_time = std::time::SystemTime::now()
+                                    .duration_since(std::time::UNIX_EPOCH)
+                                    .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                                    .as_secs();
+                                let message = Completion::Chunk(Chunk {
+                                    id: String::new(),
+                                    created: current_time,
+                                    choices: vec![CompletionComplete {
+                                        finish_reason: String::new(),
+                                        index: index as u32,
+                                        logprobs: None,
+                                        text: prompt,
+                                    }],
+                                    model: model_id.clone(),
+                                    system_fingerprint: system_fingerprint.clone(),
+                                });
+                                yield Ok(Event::default()
+                                    .json_data(message)
+                                    .unwrap_or_else(|_e| Event::default()));
+                            }
 
-                tokio::spawn(async move {
-                    let (headers, response_stream) = generate_stream_internal(
-                        infer_clone.clone(),
-                        compute_type_clone.clone(),
-                        Json(generate_request),
-                        span_clone.clone(),
-                    )
-                    .await;
-
-                    let response_stream = async_stream::stream! {
-                        let mut response_stream = Box::pin(response_stream);
-
-                        while let Some(stream_token) = response_stream.next().await {
-                            match stream_token {
-                                Ok(stream_token) => {
-                                    let event = Event::default();
-
-                                    let current_time = std::time::SystemTime::now()
-                                        .duration_since(std::time::UNIX_EPOCH)
-                                        .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-                                        .as_secs();
-
-                                    let message = match stream_token.details {
-                                        Some(details) => {
-                                            let completion_tokens = details.generated_tokens;
-                                            let prompt_tokens = details.input_length;
-                                            let total_tokens = prompt_tokens + completion_tokens;
-
-                                            Completion::Final(CompletionFinal {
+                            while let Some(stream_token) = response_stream.next().await {
+                                match stream_token {
+                                    Ok(StreamEvent::Queued(position)) => {
+                                        yield Ok(queue_position_comment(position));
+                                    }
+                                    Ok(StreamEvent::Prefilling) => {}
+                                    Ok(StreamEvent::Token(stream_token)) => {
+                                        let event = Event::default();
+
+                                        let current_time = std::time::SystemTime::now()
+                                            .duration_since(std::time::UNIX_EPOCH)
+                                            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                                            .as_secs();
+
+                                        let token_logprobs = logprobs.map(|_| {
+                                            CompletionLogprobs::new(
+                                                &[],
+                                                std::slice::from_ref(&stream_token.token),
+                                                std::slice::from_ref(&stream_token.top_tokens),
+                                                text_offset,
+                                            )
+                                        });
+                                        text_offset += stream_token.token.text.chars().count() as u32;
+
+                                        let message = match stream_token.details {
+                                            Some(details) => {
+                                                Completion::Final(CompletionFinal {
+                                                    id: String::new(),
+                                                    created: current_time,
+                                                    model: model_id.clone(),
+                                                    system_fingerprint: system_fingerprint.clone(),
+                                                    choices: vec![CompletionComplete {
+                                                        finish_reason: details.finish_reason.to_string(),
+                                                        index: index as u32,
+                                                        logprobs: token_logprobs,
+                                                        text: stream_token.token.text,
+                                                    }],
+                                                    usage: Usage::from_stream_details(&details),
+                                                })
+                                            }
+                                            None => Completion::Chunk(Chunk {
                                                 id: String::new(),
                                                 created: current_time,
-                                                model: model_id.clone(),
-                                                system_fingerprint: system_fingerprint.clone(),
                                                 choices: vec![CompletionComplete {
-                                                    finish_reason: details.finish_reason.to_string(),
+                                                    finish_reason: String::new(),
                                                     index: index as u32,
-                                                    logprobs: None,
+                                                    logprobs: token_logprobs,
                                                     text: stream_token.token.text,
                                                 }],
-                                                usage: Usage {
-                                                    prompt_tokens,
-                                                    completion_tokens,
-                                                    total_tokens,
-                                                },
-                                            })
-                                        }
-                                        None => Completion::Chunk(Chunk {
-                                            id: String::new(),
-                                            created: current_time,
-                                            choices: vec![CompletionComplete {
-                                                finish_reason: String::new(),
-                                                index: index as u32,
-                                                logprobs: None,
-                                                text: stream_token.token.text,
-                                            }],
-                                            model: model_id.clone(),
-                                            system_fingerprint: system_fingerprint.clone(),
-                                        }),
-                                    };
-
-                                    let event = event
-                                        .json_data(message)
-                                        .unwrap_or_else(|_e| Event::default());
-
-                                    yield Ok(event);
+                                                model: model_id.clone(),
+                                                system_fingerprint: system_fingerprint.clone(),
+                                            }),
+                                        };
+
+                                        let event = event
+                                            .json_data(message)
+                                            .unwrap_or_else(|_e| Event::default());
+
+                                        yield Ok(event);
+                                    }
+                                    Err(err) => yield Ok(err.into_openai_event()),
                                 }
-                                Err(err) => yield Ok(err.into_openai_event()),
                             }
-                        }
-                    };
+                        };
 
-                    // send and dont wait for response
-                    let _ = header_tx.send(headers);
+                        // send and dont wait for response
+                        let _ = header_tx.send(headers);
 
-                    // pin an emit messages to the sse_tx
-                    let mut sse = Box::pin(response_stream);
-                    while let Some(event) = sse.next().await {
-                        if sse_tx.send(event).is_err() {
-                            tracing::error!("Failed to send event. Receiver dropped.");
-                            break;
+                        // pin an emit messages to the sse_tx
+                        let mut sse = Box::pin(response_stream);
+                        while let Some(event) = sse.next().await {
+                            if sse_tx.send(event).is_err() {
+                                tracing::error!("Failed to send event. Receiver dropped.");
+                                break;
+                            }
                         }
-                    }
-                });
+                    }),
+                );
+                tokio::spawn(auth::scope(api_key, request_id::scope(request_id, task)));
 
                 (header_rx, sse_rx)
             };
@@ -900,10 +1635,7 @@ pub(crate) async fn completions(
                 tracing::error!("Failed to get headers: {:?}", e);
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: "Failed to get headers".to_string(),
-                        error_type: "headers".to_string(),
-                    }),
+                    Json(ErrorResponse::new("internal", "Failed to get headers")),
                 )
             })?;
             if x_compute_type.is_none() {
@@ -961,7 +1693,7 @@ pub(crate) async fn completions(
             Ok(Event::default().data("[DONE]"))
         }));
 
-        let sse = Sse::new(stream).keep_alive(KeepAlive::default());
+        let sse = Sse::new(stream).keep_alive(keep_alive);
         Ok((headers, sse).into_response())
     } else {
         let current_time = std::time::SystemTime::now()
@@ -1003,16 +1735,13 @@ pub(crate) async fn completions(
         let mut x_prompt_tokens = 0u32;
         let mut x_generated_tokens = 0u32;
 
-        let choices = generate_responses
+        let choices: Vec<_> = generate_responses
             .into_iter()
             .map(|(index, headers, input_length, Json(generation))| {
                 let details = generation.details.ok_or((
                     // this should never happen but handle if details are missing unexpectedly
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: "No details in generation".to_string(),
-                        error_type: "no details".to_string(),
-                    }),
+                    Json(ErrorResponse::new("internal", "No details in generation")),
                 ))?;
 
                 if x_compute_type.is_none() {
@@ -1060,19 +1789,31 @@ pub(crate) async fn completions(
                     .and_then(|v| v.to_str().ok()?.parse().ok())
                     .unwrap_or(0);
 
+                // The other choices of the prompt follow the first one
+                let generated_tokens = details.generated_tokens
+                    + details
+                        .best_of_sequences
+                        .iter()
+                        .flatten()
+                        .map(|sequence| sequence.generated_tokens)
+                        .sum::<u32>();
                 prompt_tokens += input_length;
-                completion_tokens += details.generated_tokens;
-                total_tokens += input_length + details.generated_tokens;
-
-                Ok(CompletionComplete {
-                    finish_reason: details.finish_reason.format(true),
-                    index: index as u32,
-                    logprobs: None,
-                    text: generation.generated_text,
-                })
+                completion_tokens += generated_tokens;
+                total_tokens += input_length + generated_tokens;
+
+                Ok(CompletionComplete::prompt_choices(
+                    index * choices_per_prompt,
+                    generation.generated_text,
+                    details,
+                    logprobs.is_some(),
+                    echo,
+                ))
             })
             .collect::<Result<Vec<_>, _>>()
-            .map_err(|(status, Json(err))| (status, Json(err)))?;
+            .map_err(|(status, Json(err))| (status, Json(err)))?
+            .into_iter()
+            .flatten()
+            .collect();
 
         let response = Completion::Final(CompletionFinal {
             id: "".to_string(),
@@ -1088,6 +1829,7 @@ pub(crate) async fn completions(
                 prompt_tokens,
                 completion_tokens,
                 total_tokens,
+                timings: None,
             },
         });
 
@@ -1117,15 +1859,30 @@ enum StreamState {
     Content { skip_close_quote: bool },
 }
 
-/// Convert a StreamResponse into an Event to be sent over SSE
+/// Item of the stream of one choice of a chat completion
+enum ChoiceEvent {
+    Event(Event),
+    /// End of the choice, with its usage if it reached the end of its generation
+    End {
+        usage: Option<Usage>,
+        done: Option<LifecycleEvent>,
+        errored: bool,
+    },
+}
+
+/// Convert one or more coalesced StreamResponses into an Event to be sent over SSE
 fn create_event_from_stream_token(
-    stream_token: &StreamResponse,
+    stream_tokens: &[StreamResponse],
     logprobs: bool,
-    stream_options: Option<StreamOptions>,
     inner_using_tools: bool,
     system_fingerprint: String,
     model_id: String,
+    stream_mode: StreamMode,
+    index: u32,
 ) -> Event {
+    if stream_mode == StreamMode::Events {
+        return LifecycleEvent::tokens(stream_tokens, inner_using_tools).into_event();
+    }
     let event = Event::default();
     let current_time = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -1133,46 +1890,37 @@ fn create_event_from_stream_token(
         .as_secs();
 
     let logprobs = logprobs.then(|| {
-        ChatCompletionLogprobs::from((stream_token.token.clone(), stream_token.top_tokens.clone()))
+        let (tokens, top_tokens): (Vec<_>, Vec<_>) = stream_tokens
+            .iter()
+            .map(|t| (t.token.clone(), t.top_tokens.clone()))
+            .unzip();
+        ChatCompletionLogprobs::from((tokens, top_tokens))
     });
 
     // replace the content with the tool calls if grammar is present
     let (content, tool_calls) = if inner_using_tools {
-        (None, Some(vec![stream_token.token.text.clone()]))
+        let text: String = stream_tokens
+            .iter()
+            .map(|t| t.token.text.as_str())
+            .collect();
+        (None, Some(vec![text]))
     } else {
-        let content = if !stream_token.token.special {
-            Some(stream_token.token.text.clone())
-        } else {
-            None
-        };
+        let mut texts = stream_tokens
+            .iter()
+            .filter(|t| !t.token.special)
+            .map(|t| t.token.text.as_str())
+            .peekable();
+        let content: Option<String> = texts.peek().is_some().then(|| texts.collect());
 
         (content, None)
     };
 
-    let (usage, finish_reason) = match &stream_token.details {
-        Some(details) => {
-            let usage = if stream_options
-                .as_ref()
-                .map(|s| s.include_usage)
-                .unwrap_or(false)
-            {
-                let completion_tokens = details.generated_tokens;
-                let prompt_tokens = details.input_length;
-                let total_tokens = prompt_tokens + completion_tokens;
-                Some(Usage {
-                    completion_tokens,
-                    prompt_tokens,
-                    total_tokens,
-                })
-            } else {
-                None
-            };
-            (usage, Some(details.finish_reason.format(true)))
-        }
-        None => (None, None),
-    };
+    let finish_reason = stream_tokens
+        .last()
+        .and_then(|t| t.details.as_ref())
+        .map(|details| details.finish_reason.format(true));
 
-    let chat_complete = CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
+    let mut chunk = ChatCompletionChunk::new(
         model_id.clone(),
         system_fingerprint.clone(),
         content,
@@ -1180,8 +1928,15 @@ fn create_event_from_stream_token(
         current_time,
         logprobs,
         finish_reason,
-        usage,
-    ));
+        None,
+    )
+    .with_index(index);
+    chunk.metadata = stream_tokens.last().and_then(|t| t.metadata.clone());
+    chunk.warnings = stream_tokens
+        .last()
+        .map(|t| t.warnings.clone())
+        .unwrap_or_default();
+    let chat_complete = CompletionType::ChatCompletionChunk(chunk);
 
     event.json_data(chat_complete).unwrap_or_else(|e| {
         println!("Failed to serialize ChatCompletionChunk: {:?}", e);
@@ -1202,13 +1957,13 @@ content(
 ("text/event-stream" = ChatCompletionChunk),
 )),
 (status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+example = json ! ({"error": {"type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}})),
 (status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"type": "incomplete_generation", "message": "Incomplete generation"}})),
 )
 )]
 #[instrument(
@@ -1235,6 +1990,7 @@ pub(crate) async fn chat_completions(
         model,
         stream,
         stream_options,
+        stream_mode,
         logprobs,
         ..
     } = chat.clone();
@@ -1242,6 +1998,24 @@ pub(crate) async fn chat_completions(
         chat.try_into_generate(&infer)?;
 
     let logprobs = logprobs.unwrap_or_default();
+    let include_usage = stream_options.as_ref().is_some_and(|s| s.include_usage);
+    let chunk_tokens = match stream_options.as_ref().and_then(|s| s.chunk_tokens) {
+        Some(0) => {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(
+                    ErrorResponse::new(
+                        "validation",
+                        "`stream_options.chunk_tokens` must be strictly positive",
+                    )
+                    .with_param("stream_options"),
+                ),
+            ));
+        }
+        Some(chunk_tokens) => chunk_tokens as usize,
+        None => 1,
+    };
 
     // extract model id from request if specified
     let model_id = match model.as_deref() {
@@ -1251,8 +2025,15 @@ pub(crate) async fn chat_completions(
     let system_fingerprint = format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
     // switch on stream
     if stream {
-        let (headers, response_stream) =
-            generate_stream_internal(infer, compute_type, Json(generate_request), span).await;
+        let keep_alive = keep_alive(&infer);
+        // Each choice is streamed on its own, the chunks carry its index
+        let generate_requests = infer.choice_requests(generate_request)?;
+        if generate_requests.len() > 1 && stream_mode == StreamMode::Events {
+            let err = InferError::from(ValidationError::NStreamEvents);
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            tracing::error!("{err}");
+            return Err(err.into());
+        }
 
         // regex to match any function name
         let function_regex = match Regex::new(r#"\{"function":\{"_name":"([^"]+)""#) {
@@ -1260,129 +2041,258 @@ pub(crate) async fn chat_completions(
             Err(e) => {
                 return Err((
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: format!("Failed to compile regex: {}", e),
-                        error_type: "regex".to_string(),
-                    }),
+                    Json(ErrorResponse::new(
+                        "internal",
+                        format!("Failed to compile regex: {}", e),
+                    )),
                 ))
             }
         };
 
-        let response_stream = async_stream::stream! {
-            let mut response_stream = Box::pin(response_stream);
-            let mut buffer = Vec::new();
-            let mut json_buffer = String::new();
-            let mut state = if using_tools {
-                StreamState::Buffering
-            } else {
-                StreamState::Content {
-                    skip_close_quote: false,
-                }
-            };
-            let mut response_as_tool = using_tools;
-            while let Some(result) = response_stream.next().await {
-                match result{
-                Ok(stream_token) => {
-                    let token_text = &stream_token.token.text.clone();
-                    match state {
-                        StreamState::Buffering => {
-                            json_buffer.push_str(&token_text.replace(" ", ""));
-                            buffer.push(stream_token);
-                            if let Some(captures) = function_regex.captures(&json_buffer) {
-                                let function_name = captures[1].to_string();
-                                if function_name == "no_tool" {
-                                    state = StreamState::BufferTrailing;
-                                    response_as_tool = false;
-                                    buffer.clear();
-                                    json_buffer.clear();
-                                } else {
-                                    state = StreamState::Content {
-                                        skip_close_quote: false,
-                                    };
-                                    // send all the buffered messages
-                                    for stream_token in &buffer {
-                                        let event = create_event_from_stream_token(
-                                            stream_token,
-                                            logprobs,
-                                            stream_options.clone(),
-                                            response_as_tool,
-                                            system_fingerprint.clone(),
-                                            model_id.clone(),
-                                        );
-                                        yield Ok::<Event, Infallible>(event);
+        let mut headers = None;
+        let mut choice_streams = Vec::with_capacity(generate_requests.len());
+        for (index, generate_request) in generate_requests.into_iter().enumerate() {
+            let index = index as u32;
+            let (choice_headers, response_stream) = generate_stream_internal(
+                infer.clone(),
+                compute_type.clone(),
+                Json(generate_request),
+                span.clone(),
+            )
+            .await;
+            headers.get_or_insert(choice_headers);
+            let function_regex = function_regex.clone();
+            let model_id = model_id.clone();
+            let system_fingerprint = system_fingerprint.clone();
+
+            let choice_stream = async_stream::stream! {
+                let mut response_stream = Box::pin(response_stream);
+                let mut buffer = Vec::new();
+                let mut json_buffer = String::new();
+                let mut state = if using_tools {
+                    StreamState::Buffering
+                } else {
+                    StreamState::Content {
+                        skip_close_quote: false,
+                    }
+                };
+                let mut response_as_tool = using_tools;
+                let mut pending = Vec::new();
+                let mut usage = None;
+                // `done` event of the `events` stream mode
+                let mut done = None;
+                let mut errored = false;
+                while let Some(result) = response_stream.next().await {
+                    match result{
+                    Ok(StreamEvent::Queued(position)) => yield ChoiceEvent::Event(match stream_mode {
+                        StreamMode::Events => LifecycleEvent::Queued { position, timestamp: lifecycle::timestamp() }.into_event(),
+                        StreamMode::Deltas => queue_position_comment(position),
+                    }),
+                    Ok(StreamEvent::Prefilling) => {
+                        if stream_mode == StreamMode::Events {
+                            yield ChoiceEvent::Event(LifecycleEvent::Prefilling { timestamp: lifecycle::timestamp() }.into_event());
+                        }
+                    }
+                    Ok(StreamEvent::Token(stream_token)) => {
+                        let token_text = &stream_token.token.text.clone();
+                        if let (StreamMode::Events, Some(details)) = (stream_mode, stream_token.details.as_ref()) {
+                            done = Some(LifecycleEvent::chat_done(&stream_token, details));
+                        }
+                        if let Some(details) = stream_token.details.as_ref() {
+                            usage = Some(Usage::from_stream_details(details));
+                        }
+                        match state {
+                            StreamState::Buffering => {
+                                json_buffer.push_str(&token_text.replace(" ", ""));
+                                buffer.push(stream_token);
+                                if let Some(captures) = function_regex.captures(&json_buffer) {
+                                    let function_name = captures[1].to_string();
+                                    if function_name == "no_tool" {
+                                        state = StreamState::BufferTrailing;
+                                        response_as_tool = false;
+                                        buffer.clear();
+                                        json_buffer.clear();
+                                    } else {
+                                        state = StreamState::Content {
+                                            skip_close_quote: false,
+                                        };
+                                        // send all the buffered messages
+                                        for stream_token in &buffer {
+                                            let event = create_event_from_stream_token(
+                                                std::slice::from_ref(stream_token),
+                                                logprobs,
+                                                response_as_tool,
+                                                system_fingerprint.clone(),
+                                                model_id.clone(),
+                                                stream_mode,
+                                                index,
+                                            );
+                                            yield ChoiceEvent::Event(event);
+                                        }
                                     }
                                 }
                             }
-                        }
-                        // if we skipped sending the buffer we need to avoid sending the following json key and quotes
-                        StreamState::BufferTrailing => {
-                            let infix_text = "\"content\":\"";
-                            json_buffer.push_str(&token_text.replace(" ", ""));
-                            // keep capturing until we find the infix text
-                            match json_buffer.find(infix_text) {
-                                Some(content_key_index) => {
-                                    json_buffer =
-                                        json_buffer[content_key_index + infix_text.len()..].to_string();
+                            // if we skipped sending the buffer we need to avoid sending the following json key and quotes
+                            StreamState::BufferTrailing => {
+                                let infix_text = "\"content\":\"";
+                                json_buffer.push_str(&token_text.replace(" ", ""));
+                                // keep capturing until we find the infix text
+                                match json_buffer.find(infix_text) {
+                                    Some(content_key_index) => {
+                                        json_buffer =
+                                            json_buffer[content_key_index + infix_text.len()..].to_string();
+                                    }
+                                    None => {
+                                        continue;
+                                    }
                                 }
-                                None => {
-                                    continue;
+                                // if there is leftover text after removing the infix text, we need to send it
+                                if !json_buffer.is_empty() && stream_mode == StreamMode::Events {
+                                    yield ChoiceEvent::Event(LifecycleEvent::Token {
+                                        text: json_buffer.clone(),
+                                        tokens: Vec::new(),
+                                        top_tokens: Vec::new(),
+                                        timestamp: lifecycle::timestamp(),
+                                    }.into_event());
+                                } else if !json_buffer.is_empty() {
+                                    let event = Event::default();
+                                    let current_time = std::time::SystemTime::now()
+                                        .duration_since(std::time::UNIX_EPOCH)
+                                        .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                                        .as_secs();
+                                    let chat_complete =
+                                        CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
+                                            model_id.clone(),
+                                            system_fingerprint.clone(),
+                                            Some(json_buffer.clone()),
+                                            None,
+                                            current_time,
+                                            None,
+                                            None,
+                                            None,
+                                        ).with_index(index));
+                                    yield ChoiceEvent::Event(event.json_data(chat_complete).unwrap_or_else(|e| {
+                                        InferError::StreamSerializationError(e.to_string()).into()
+                                    }));
                                 }
+                                // cleanup the buffers
+                                buffer.clear();
+                                json_buffer.clear();
+                                state = StreamState::Content {
+                                    skip_close_quote: true,
+                                };
                             }
-                            // if there is leftover text after removing the infix text, we need to send it
-                            if !json_buffer.is_empty() {
-                                let event = Event::default();
-                                let current_time = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-                                    .as_secs();
-                                let chat_complete =
-                                    CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
-                                        model_id.clone(),
+                            StreamState::Content { skip_close_quote } => {
+                                if skip_close_quote && token_text.contains('"') {
+                                    break;
+                                }
+
+                                // send the content once enough tokens are coalesced or generation ended
+                                let end = stream_token.details.is_some();
+                                pending.push(stream_token);
+                                if end || pending.len() >= chunk_tokens {
+                                    let event = create_event_from_stream_token(
+                                        &pending,
+                                        logprobs,
+                                        response_as_tool,
                                         system_fingerprint.clone(),
-                                        Some(json_buffer.clone()),
-                                        None,
-                                        current_time,
-                                        None,
-                                        None,
-                                        None,
-                                    ));
-                                yield Ok(event.json_data(chat_complete).unwrap_or_else(|e| {
-                                    InferError::StreamSerializationError(e.to_string()).into()
-                                }));
+                                        model_id.clone(),
+                                        stream_mode,
+                                        index,
+                                    );
+                                    pending.clear();
+
+                                    yield ChoiceEvent::Event(event);
+                                }
                             }
-                            // cleanup the buffers
-                            buffer.clear();
-                            json_buffer.clear();
-                            state = StreamState::Content {
-                                skip_close_quote: true,
-                            };
                         }
-                        StreamState::Content { skip_close_quote } => {
-                            if skip_close_quote && token_text.contains('"') {
-                                break;
-                            }
-
-                            // send the content
+                    }
+                    Err(err) => {
+                        if !pending.is_empty() {
                             let event = create_event_from_stream_token(
-                                &stream_token,
+                                &pending,
                                 logprobs,
-                                stream_options.clone(),
                                 response_as_tool,
                                 system_fingerprint.clone(),
                                 model_id.clone(),
+                                stream_mode,
+                                index,
                             );
+                            pending.clear();
+                            yield ChoiceEvent::Event(event);
+                        }
+                        errored = true;
+                        yield ChoiceEvent::Event(match stream_mode {
+                            StreamMode::Events => Event::from(err).event("error"),
+                            StreamMode::Deltas => err.into_openai_event(),
+                        })
+                    }
+                    }
+                }
+                // flush tokens still buffered when the stream stopped early
+                if !pending.is_empty() {
+                    let event = create_event_from_stream_token(
+                        &pending,
+                        logprobs,
+                        response_as_tool,
+                        system_fingerprint.clone(),
+                        model_id.clone(),
+                        stream_mode,
+                        index,
+                    );
+                    yield ChoiceEvent::Event(event);
+                }
+                yield ChoiceEvent::End { usage, done, errored };
+            };
+            choice_streams.push(Box::pin(choice_stream));
+        }
+        let headers = headers.unwrap_or_default();
 
-                            yield Ok::<Event, Infallible>(event);
+        let response_stream = async_stream::stream! {
+            let mut choices = futures::stream::select_all(choice_streams);
+            let mut usage: Option<Usage> = None;
+            let mut done = None;
+            let mut errored = false;
+            while let Some(event) = choices.next().await {
+                match event {
+                    ChoiceEvent::Event(event) => yield Ok::<Event, Infallible>(event),
+                    ChoiceEvent::End { usage: choice_usage, done: choice_done, errored: choice_errored } => {
+                        match (usage.as_mut(), choice_usage) {
+                            (Some(usage), Some(choice_usage)) => usage.add_choice(&choice_usage),
+                            (None, choice_usage) => usage = choice_usage,
+                            (Some(_), None) => {}
                         }
+                        done = done.or(choice_done);
+                        errored |= choice_errored;
                     }
                 }
-                Err(err) => yield Ok(err.into_openai_event())
+            }
+            if stream_mode == StreamMode::Events {
+                if !errored {
+                    yield Ok(done.unwrap_or_else(LifecycleEvent::done).into_event());
                 }
+                return;
+            }
+            // Usage of the whole generation, in its own chunk like the OpenAI API
+            if let Some(usage) = usage.filter(|_| include_usage) {
+                let current_time = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                    .as_secs();
+                let usage = CompletionType::ChatCompletionChunk(ChatCompletionChunk::usage(
+                    model_id.clone(),
+                    system_fingerprint.clone(),
+                    current_time,
+                    usage,
+                ));
+                yield Ok(Event::default().json_data(usage).unwrap_or_else(|e| {
+                    InferError::StreamSerializationError(e.to_string()).into()
+                }));
             }
             yield Ok::<Event, Infallible>(Event::default().data("[DONE]"));
         };
 
-        let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
+        let sse = Sse::new(response_stream).keep_alive(keep_alive);
         Ok((headers, sse).into_response())
     } else {
         let (headers, input_length, Json(generation)) =
@@ -1393,77 +2303,162 @@ pub(crate) async fn chat_completions(
             .unwrap_or_else(|_| std::time::Duration::from_secs(0))
             .as_secs();
 
-        let (tool_calls, output) = if using_tools {
-            let gen_text_value: Value =
-                serde_json::from_str(&generation.generated_text).map_err(|e| {
-                    InferError::ToolError(format!(
-                        "Failed to parse generated text: {} {:?}",
-                        e, generation.generated_text
-                    ))
-                })?;
-            let function = gen_text_value.get("function").ok_or(InferError::ToolError(
-                "No function found in generated text".to_string(),
-            ))?;
-
-            let name = function
-                .get("_name")
-                .and_then(Value::as_str)
-                .ok_or(InferError::ToolError(
-                    "No _name found in generated text".to_string(),
-                ))?
-                .to_string();
-
-            let mut arguments = function.clone();
-            if let Value::Object(ref mut props) = arguments {
-                props.remove("_name");
-            }
-            match name.as_str() {
-                "no_tool" => {
-                    // parse the content message
-                    let content_message = arguments
-                        .get("content")
-                        .and_then(Value::as_str)
-                        .ok_or_else(|| {
-                            InferError::ToolError(
-                                "No `content` found in generated text".to_string(),
-                            )
-                        })?
-                        .to_string();
-                    (None, Some(content_message))
-                }
-                _ => {
-                    let tool_calls = vec![ToolCall {
-                        id: "0".to_string(),
-                        r#type: "function".to_string(),
-                        function: FunctionDefinition {
-                            description: None,
-                            name,
-                            arguments,
-                        },
-                    }];
-                    (Some(tool_calls), None)
-                }
-            }
-        } else {
-            (None, Some(generation.generated_text))
-        };
+        let mut details = generation.details.unwrap();
+        let sequences = details.best_of_sequences.take().unwrap_or_default();
+        let (tool_calls, output) = chat_output(generation.generated_text, using_tools)?;
         // build the complete response object with the full text
-        let response = CompletionType::ChatCompletion(ChatCompletion::new(
+        let mut chat_completion = ChatCompletion::new(
             model_id,
             system_fingerprint,
             output,
             current_time,
-            generation.details.unwrap(),
+            details,
             logprobs,
             tool_calls,
             input_length,
-        ));
+        );
+        // The other choices were generated for the same prompt
+        for sequence in sequences {
+            let (tool_calls, output) = chat_output(sequence.generated_text.clone(), using_tools)?;
+            chat_completion.push_choice(output, tool_calls, sequence, logprobs);
+        }
+        chat_completion.metadata = generation.metadata;
+        chat_completion.warnings = generation.warnings;
+        let response = CompletionType::ChatCompletion(chat_completion);
 
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(response)).into_response())
     }
 }
 
+/// Message of a chat completion, the tool call when `using_tools` unless the model called
+/// `no_tool`
+fn chat_output(
+    generated_text: String,
+    using_tools: bool,
+) -> Result<(Option<Vec<ToolCall>>, Option<String>), InferError> {
+    if !using_tools {
+        return Ok((None, Some(generated_text)));
+    }
+    let gen_text_value: Value = serde_json::from_str(&generated_text).map_err(|e| {
+        InferError::ToolError(format!(
+            "Failed to parse generated text: {} {:?}",
+            e, generated_text
+        ))
+    })?;
+    let function = gen_text_value.get("function").ok_or(InferError::ToolError(
+        "No function found in generated text".to_string(),
+    ))?;
+
+    let name = function
+        .get("_name")
+        .and_then(Value::as_str)
+        .ok_or(InferError::ToolError(
+            "No _name found in generated text".to_string(),
+        ))?
+        .to_string();
+
+    let mut arguments = function.clone();
+    if let Value::Object(ref mut props) = arguments {
+        props.remove("_name");
+    }
+    match name.as_str() {
+        "no_tool" => {
+            // parse the content message
+            let content_message = arguments
+                .get("content")
+                .and_then(Value::as_str)
+                .ok_or_else(|| {
+                    InferError::ToolError("No `content` found in generated text".to_string())
+                })?
+                .to_string();
+            Ok((None, Some(content_message)))
+        }
+        _ => {
+            let tool_calls = vec![ToolCall {
+                id: "0".to_string(),
+                r#type: "function".to_string(),
+                function: FunctionDefinition {
+                    description: None,
+                    name,
+                    arguments,
+                },
+            }];
+            Ok((Some(tool_calls), None))
+        }
+    }
+}
+
+/// Embed inputs
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/embeddings",
+request_body = EmbeddingRequest,
+responses(
+(status = 200, description = "Embeddings of the inputs", body = EmbeddingResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}})),
+(status = 501, description = "Model does not support embeddings", body = ErrorResponse,
+example = json ! ({"error": {"type": "embeddings_unsupported", "message": "Model does not support embeddings"}})),
+)
+)]
+#[instrument(skip_all)]
+async fn embeddings(
+    Extension(infer): Extension<Infer>,
+    Extension(info): Extension<Info>,
+    Json(req): Json<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, (StatusCode, Json<ErrorResponse>)> {
+    metrics::counter!("tgi_request_count").increment(1);
+
+    if req.input.0.is_empty() {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse::new("validation", "`input` cannot be empty").with_param("input")),
+        ));
+    }
+    if req.input.0.len() > info.max_client_batch_size {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(
+                ErrorResponse::new(
+                    "validation",
+                    format!(
+                        "Number of inputs exceeds the maximum allowed batch size of {}",
+                        info.max_client_batch_size
+                    ),
+                )
+                .with_param("input"),
+            ),
+        ));
+    }
+
+    let (embeddings, input_tokens) = infer.embed(req.model, req.input.0).await?;
+    metrics::counter!("tgi_request_success").increment(1);
+
+    Ok(Json(EmbeddingResponse {
+        object: "list".to_string(),
+        data: embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| Embedding {
+                object: "embedding".to_string(),
+                embedding,
+                index,
+            })
+            .collect(),
+        model: info.model_id.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens: input_tokens as u32,
+            total_tokens: input_tokens as u32,
+        },
+    }))
+}
+
 /// Tokenize inputs
 #[utoipa::path(
 post,
@@ -1473,7 +2468,7 @@ request_body = GenerateRequest,
 responses(
 (status = 200, description = "Tokenized ids", body = TokenizeResponse),
 (status = 404, description = "No tokenizer found", body = ErrorResponse,
-example = json ! ({"error": "No fast tokenizer available"})),
+example = json ! ({"error": {"type": "validation", "message": "No fast tokenizer available"}})),
 )
 )]
 #[instrument(skip_all)]
@@ -1487,6 +2482,71 @@ async fn tokenize(
     Ok(Json(TokenizeResponse(tokens)))
 }
 
+/// Decode token ids with the tokenizer of the model
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/detokenize",
+request_body = DetokenizeRequest,
+responses(
+(status = 200, description = "Decoded text", body = DetokenizeResponse),
+(status = 422, description = "Tokenizer error", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "tokenizer error Could not decode"}})),
+)
+)]
+#[instrument(skip_all, fields(ids = req.ids.len()))]
+async fn detokenize(
+    Extension(infer): Extension<Infer>,
+    Json(req): Json<DetokenizeRequest>,
+) -> Result<Json<DetokenizeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let text = infer.detokenize(req.ids, req.skip_special_tokens).await?;
+    Ok(Json(DetokenizeResponse { text }))
+}
+
+/// Score a text for the watermark of the generations
+///
+/// The text is tokenized with the tokenizer of the router, and scored with the key `key_id`, or
+/// with every key.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/watermark/verify",
+request_body = WatermarkVerifyRequest,
+responses(
+(status = 200, description = "Watermark scores", body = WatermarkVerifyResponse),
+(status = 404, description = "No watermark key configured", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "No watermark key configured"}})),
+(status = 422, description = "Unknown watermark key", body = ErrorResponse,
+example = json ! ({"error": {"type": "validation", "message": "Unknown watermark key `2023-01`", "param": "key_id"}})),
+)
+)]
+#[instrument(skip_all, fields(key_id = ?req.key_id))]
+async fn verify_watermark(
+    Extension(infer): Extension<Infer>,
+    Json(req): Json<WatermarkVerifyRequest>,
+) -> Result<Json<WatermarkVerifyResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let watermark_keys = infer.watermark_keys().ok_or_else(no_watermark_keys)?;
+    let input_ids = infer.encode(req.text).await?;
+    let scores = watermark_keys
+        .score(&input_ids, req.key_id.as_deref())
+        .ok_or_else(|| {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            let message = format!("Unknown watermark key `{}`", req.key_id.unwrap_or_default());
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ErrorResponse::new("validation", message).with_param("key_id")),
+            )
+        })?;
+
+    let best = scores.iter().max_by(|a, b| a.z_score.total_cmp(&b.z_score));
+    Ok(Json(WatermarkVerifyResponse {
+        detected: best.is_some_and(|score| score.z_score > watermark::DETECTION_Z_SCORE),
+        key_id: best.map(|score| score.key_id.clone()),
+        scored_tokens: watermark::scored_tokens(&input_ids),
+        scores,
+    }))
+}
+
 /// Prometheus metrics scrape endpoint
 #[utoipa::path(
     get,
@@ -1498,6 +2558,51 @@ async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
     prom_handle.render()
 }
 
+/// Recent batches formed by the scheduler
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/metrics/batches",
+responses((status = 200, description = "Recent batches, oldest first", body = Vec<BatchRecord>))
+)]
+async fn metrics_batches(infer: Extension<Infer>) -> Json<Vec<BatchRecord>> {
+    Json(infer.batch_history().await)
+}
+
+/// Time spent in each phase of the recent decode iterations of the batching loop
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/debug/timings",
+responses((status = 200, description = "Recent iterations, oldest first", body = Vec<IterationTimings>))
+)]
+async fn debug_timings(infer: Extension<Infer>) -> Json<Vec<IterationTimings>> {
+    Json(infer.iteration_timings())
+}
+
+/// Prefix cache usage of the sessions kept by the scheduler, under the hash of their id
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/metrics/sessions",
+responses((status = 200, description = "Sessions kept in the cache", body = Vec<SessionStats>))
+)]
+async fn metrics_sessions(infer: Extension<Infer>) -> Json<Vec<SessionStats>> {
+    let sessions = infer.session_stats().await;
+    Json(sessions.into_iter().map(SessionStats::hashed).collect())
+}
+
+/// KV cache usage of the block allocators, updated live
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/metrics/cache",
+responses((status = 200, description = "KV cache usage of every replica", body = Vec<CacheStats>))
+)]
+async fn metrics_cache(infer: Extension<Infer>) -> Json<Vec<CacheStats>> {
+    Json(infer.cache_stats().await)
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct ComputeType(String);
 
@@ -1509,14 +2614,34 @@ health,
 get_model_info,
 compat_generate,
 generate,
+generate_batch,
 generate_stream,
+abort_generate,
 chat_completions,
 completions,
+embeddings,
 tokenize,
+detokenize,
 metrics,
+metrics_batches,
+debug_timings,
+metrics_sessions,
+metrics_cache,
 openai_get_model_info,
 sagemaker_compatibility,
 get_chat_tokenize,
+drain,
+get_runtime_config,
+update_runtime_config,
+verify_watermark,
+get_watermark_keys,
+reload_watermark_keys,
+load_adapter,
+unload_adapter,
+swap_model,
+swap_status,
+get_usage,
+get_queue,
 ),
 components(
 schemas(
@@ -1545,27 +2670,44 @@ ChatCompletionTopLogprob,
 ChatCompletion,
 CompletionRequest,
 CompletionComplete,
+CompletionLogprobs,
 SagemakerResponse,
 SagemakerStreamResponse,
 Chunk,
 Completion,
 CompletionFinal,
 Prompt,
+EmbeddingRequest,
+EmbeddingResponse,
+Embedding,
+EmbeddingUsage,
 GenerateParameters,
+AdapterWeight,
+Priority,
+TruncationSide,
 PrefillToken,
 Token,
 GenerateResponse,
+GenerateBatchRequest,
+GenerateBatchResult,
 TokenizeResponse,
 SimpleToken,
+DetokenizeRequest,
+DetokenizeResponse,
 BestOfSequence,
 Details,
 FinishReason,
 StreamResponse,
 StreamDetails,
 ErrorResponse,
+ErrorDetails,
 GrammarType,
 Usage,
+GenerationTimings,
+PromptTemplateVersion,
 StreamOptions,
+StreamMode,
+LifecycleEvent,
 DeltaToolCall,
 Tool,
 ToolCall,
@@ -1574,6 +2716,28 @@ FunctionDefinition,
 ToolChoice,
 ModelInfo,
 ChatTokenizeResponse,
+HealthResponse,
+DrainResponse,
+RuntimeConfig,
+WatermarkVerifyRequest,
+WatermarkVerifyResponse,
+WatermarkKeyScore,
+WatermarkKeysResponse,
+KeyUsage,
+UsageResponse,
+QueuedRequest,
+QueueStats,
+QueueResponse,
+BatchRecord,
+IterationTimings,
+SessionStats,
+CacheStats,
+ShardInfo,
+LoraAdapterInfo,
+LoadAdapterRequest,
+SwapModelRequest,
+SwapModelStatus,
+SwapState,
 )
 ),
 tags(
@@ -1655,46 +2819,45 @@ fn legacy_tokenizer_handle(config_filename: Option<&PathBuf>) -> Option<()> {
                 }
                 Some(())
             })
-    })
-}
-
-/// Serving method
-#[allow(clippy::too_many_arguments)]
-pub async fn run(
-    backend: impl Backend + Send + Sync + 'static,
-    max_concurrent_requests: usize,
-    max_best_of: usize,
-    max_stop_sequences: usize,
-    max_top_n_tokens: u32,
-    max_input_tokens: usize,
-    max_total_tokens: usize,
-    validation_workers: usize,
-    api_key: Option<String>,
+    })
+}
+
+/// Serving method
+/// Where the router reads the tokenizer and the configuration of the model, at startup and when
+/// the model is swapped for another revision
+#[derive(Clone, Debug)]
+pub(crate) struct ModelSource {
     tokenizer_name: String,
     tokenizer_config_path: Option<String>,
-    revision: Option<String>,
+    chat_template_path: Option<String>,
     trust_remote_code: bool,
-    hostname: String,
-    port: u16,
-    cors_allow_origin: Option<Vec<String>>,
-    ngrok: bool,
-    _ngrok_authtoken: Option<String>,
-    _ngrok_edge: Option<String>,
-    disable_grammar_support: bool,
-    max_client_batch_size: usize,
-    usage_stats_level: usage_stats::UsageStatsLevel,
-    payload_limit: usize,
-) -> Result<(), WebServerError> {
-    // CORS allowed origins
-    // map to go inside the option and then map to parse from String to HeaderValue
-    // Finally, convert to AllowOrigin
-    let allow_origin: Option<AllowOrigin> = cors_allow_origin.map(|cors_allow_origin| {
-        AllowOrigin::list(
-            cors_allow_origin
-                .iter()
-                .map(|origin| origin.parse::<HeaderValue>().unwrap()),
-        )
-    });
+    allow_vocab_mismatch: bool,
+}
+
+impl ModelSource {
+    /// Source of a model served next to the default one, read from its own files
+    fn for_model(&self, model: &str) -> Self {
+        Self {
+            tokenizer_name: model.to_string(),
+            tokenizer_config_path: None,
+            chat_template_path: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// Tokenizer and configuration of `revision` of the model, with its info on the Hub
+pub(crate) async fn load_model(
+    source: &ModelSource,
+    revision: Option<String>,
+) -> Result<(ModelFiles, Option<HubModelInfo>), WebServerError> {
+    let ModelSource {
+        tokenizer_name,
+        tokenizer_config_path,
+        chat_template_path,
+        trust_remote_code,
+        allow_vocab_mismatch,
+    } = source.clone();
 
     // Parse Huggingface hub token
     let authorization_token = std::env::var("HF_TOKEN")
@@ -1814,35 +2977,33 @@ pub async fn run(
     } else {
         tokenizer_config_filename.and_then(HubTokenizerConfig::from_file)
     };
-    let tokenizer_config = tokenizer_config.unwrap_or_else(|| {
+    let mut tokenizer_config = tokenizer_config.unwrap_or_else(|| {
         tracing::warn!("Could not find tokenizer config locally and no API specified");
         HubTokenizerConfig::default()
     });
+    // A template given at startup takes precedence over the template of the model
+    if let Some(chat_template_path) = chat_template_path {
+        let chat_template =
+            std::fs::read_to_string(&chat_template_path).map_err(WebServerError::ChatTemplate)?;
+        tracing::info!("Using chat template from {chat_template_path}");
+        tokenizer_config.chat_template = Some(ChatTemplateVersions::Single(chat_template));
+    }
 
-    let tokenizer: Tokenizer = {
-        use pyo3::prelude::*;
-        pyo3::Python::with_gil(|py| -> PyResult<()> {
-            py_resolve_tokenizer(py, &tokenizer_name, revision.as_deref(), trust_remote_code)?;
-            Ok(())
-        })
-        .inspect_err(|err| {
-            tracing::error!("Failed to import python tokenizer {err}");
-        })
-        .or_else(|err| {
-            let out = legacy_tokenizer_handle(config_filename.as_ref());
-            out.ok_or(err)
-        })
-        .expect("We cannot load a tokenizer");
-        let filename = "out/tokenizer.json";
-        if let Ok(tok) = tokenizers::Tokenizer::from_file(filename) {
-            Tokenizer::Rust(tok)
-        } else {
-            Tokenizer::Python {
-                tokenizer_name: tokenizer_name.clone(),
-                revision: revision.clone(),
+    // Resolving the tokenizer may download it, out of the async runtime
+    let tokenizer = {
+        let tokenizer_name = tokenizer_name.clone();
+        let revision = revision.clone();
+        let config_filename = config_filename.clone();
+        tokio::task::spawn_blocking(move || {
+            resolve_tokenizer(
+                tokenizer_name,
+                revision,
                 trust_remote_code,
-            }
-        }
+                config_filename.as_ref(),
+            )
+        })
+        .await
+        .map_err(|err| WebServerError::Tokenizer(err.to_string()))??
     };
 
     let config: Option<Config> = config_filename.and_then(|filename| {
@@ -1857,12 +3018,6 @@ pub async fn run(
                 config.ok()
             })
     });
-    let model_info = model_info.unwrap_or_else(|| HubModelInfo {
-        model_id: tokenizer_name.to_string(),
-        sha: None,
-        pipeline_tag: None,
-    });
-
     let processor_config = processor_config_filename
         .and_then(HubProcessorConfig::from_file)
         .unwrap_or_default();
@@ -1870,6 +3025,224 @@ pub async fn run(
     let preprocessor_config: Option<HubPreprocessorConfig> =
         preprocessor_config_filename.and_then(HubPreprocessorConfig::from_file);
 
+    // The Python tokenizers cannot be counted
+    let vocab_size = match &tokenizer {
+        Tokenizer::Rust(tokenizer) if !allow_vocab_mismatch => Some(tokenizer.get_vocab_size(true)),
+        _ => None,
+    };
+    let files = ModelFiles {
+        tokenizer,
+        tokenizer_config,
+        processor_config,
+        config,
+        preprocessor_config,
+        model_sha: model_info
+            .as_ref()
+            .and_then(|model_info| model_info.sha.clone()),
+        vocab_size,
+    };
+    Ok((files, model_info))
+}
+
+/// Resolve the tokenizer of `revision` of the model, falling back on a Python tokenizer
+fn resolve_tokenizer(
+    tokenizer_name: String,
+    revision: Option<String>,
+    trust_remote_code: bool,
+    config_filename: Option<&PathBuf>,
+) -> Result<Tokenizer, WebServerError> {
+    use pyo3::prelude::*;
+    pyo3::Python::with_gil(|py| -> PyResult<()> {
+        py_resolve_tokenizer(py, &tokenizer_name, revision.as_deref(), trust_remote_code)?;
+        Ok(())
+    })
+    .inspect_err(|err| {
+        tracing::error!("Failed to import python tokenizer {err}");
+    })
+    .or_else(|err| {
+        let out = legacy_tokenizer_handle(config_filename);
+        out.ok_or(err)
+    })
+    .map_err(|err| WebServerError::Tokenizer(err.to_string()))?;
+    let filename = "out/tokenizer.json";
+    if let Ok(tok) = tokenizers::Tokenizer::from_file(filename) {
+        Ok(Tokenizer::Rust(tok))
+    } else {
+        Ok(Tokenizer::Python {
+            tokenizer_name,
+            revision,
+            trust_remote_code,
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    backend: impl Backend + Send + Sync + 'static,
+    max_concurrent_requests: usize,
+    max_best_of: usize,
+    max_stop_sequences: usize,
+    max_top_n_tokens: u32,
+    max_input_tokens: usize,
+    max_total_tokens: usize,
+    validation_workers: usize,
+    api_key: Option<String>,
+    api_keys_path: Option<String>,
+    usage_path: Option<String>,
+    tokenizer_name: String,
+    tokenizer_config_path: Option<String>,
+    chat_template_path: Option<String>,
+    revision: Option<String>,
+    trust_remote_code: bool,
+    hostname: String,
+    port: u16,
+    grpc_port: Option<u16>,
+    cors_allow_origin: Option<Vec<String>>,
+    ngrok: bool,
+    _ngrok_authtoken: Option<String>,
+    _ngrok_edge: Option<String>,
+    disable_grammar_support: bool,
+    max_client_batch_size: usize,
+    usage_stats_level: usage_stats::UsageStatsLevel,
+    payload_limit: usize,
+    max_queue_size: Option<usize>,
+    max_queue_wait: Option<Duration>,
+    health_check_interval: Option<Duration>,
+    stream_heartbeat_interval: Option<Duration>,
+    queue_journal_path: Option<String>,
+    rate_limit_requests: Option<f64>,
+    rate_limit_tokens: Option<u32>,
+    rate_limit_concurrent_requests: Option<u32>,
+    rate_limit_config_path: Option<String>,
+    tenant_header: Option<String>,
+    response_cache_size: Option<usize>,
+    response_cache_ttl: Duration,
+    response_cache_redis_url: Option<String>,
+    audit_log: Option<String>,
+    audit_sample_rate: f64,
+    audit_prompt_length: usize,
+    audit_hash_prompts: bool,
+    audit_hash_key: Option<String>,
+    watermark_keys_path: Option<String>,
+    watermark_gamma: f64,
+    content_filter_path: Option<String>,
+    transforms_path: Option<String>,
+    default_max_new_tokens: Option<u32>,
+    max_new_tokens_ceiling: Option<u32>,
+    stream_max_new_tokens_ceiling: Option<u32>,
+    max_new_tokens_config_path: Option<String>,
+    idempotency_cache_size: Option<usize>,
+    idempotency_ttl: Duration,
+    stream_resume_events: Option<usize>,
+    stream_resume_timeout: Duration,
+    prompt_templates_path: Option<String>,
+    max_images_per_request: Option<usize>,
+    max_image_pixels: Option<usize>,
+    allow_vocab_mismatch: bool,
+) -> Result<(), WebServerError> {
+    let tenant_header = tenant_header
+        .map(HeaderName::try_from)
+        .transpose()
+        .map_err(WebServerError::TenantHeader)?;
+
+    // Keys allowed to call the routes
+    let api_keys = auth::ApiKeys::new(api_key, api_keys_path)?.map(Arc::new);
+    // Their usage is saved, so that their quotas survive the restarts
+    let usage_file = match (&api_keys, usage_path) {
+        (Some(api_keys), Some(path)) => Some(UsageFile::open(api_keys.clone(), path.into())?),
+        (None, Some(_)) => {
+            tracing::warn!("`--usage-path` is ignored without API keys");
+            None
+        }
+        _ => None,
+    };
+
+    // Rate limits per API key
+    let rate_limiter = RateLimiter::new(
+        rate_limit_requests,
+        rate_limit_tokens,
+        rate_limit_concurrent_requests,
+        rate_limit_config_path,
+    )?
+    .map(Arc::new);
+
+    // Records of the generations
+    let audit_log = AuditLog::new(
+        audit_log,
+        audit_sample_rate,
+        audit_prompt_length,
+        audit_hash_prompts,
+        audit_hash_key,
+    )?
+    .map(Arc::new);
+
+    // Keys of the watermarked generations
+    let watermark_keys = WatermarkKeys::new(watermark_keys_path, watermark_gamma)?.map(Arc::new);
+
+    // Filters of the generated texts
+    let content_filter = ContentFilter::new(content_filter_path)?.map(Arc::new);
+
+    // Transformations of the prompts and generated texts per model
+    let transforms = Transforms::new(transforms_path)?.map(Arc::new);
+
+    // Defaults and ceilings of `max_new_tokens` per model
+    let max_new_tokens_limits = MaxNewTokensLimits::new(
+        default_max_new_tokens,
+        max_new_tokens_ceiling,
+        stream_max_new_tokens_ceiling,
+        max_new_tokens_config_path,
+    )?;
+
+    // Templates rendered into the inputs of the requests that name them
+    let prompt_templates = PromptTemplates::new(prompt_templates_path)?;
+
+    // Responses of the requests with an `Idempotency-Key`, replayed to their retries
+    let idempotency_store =
+        IdempotencyStore::new(idempotency_cache_size, idempotency_ttl, payload_limit).map(Arc::new);
+
+    // CORS allowed origins
+    // map to go inside the option and then map to parse from String to HeaderValue
+    // Finally, convert to AllowOrigin
+    let allow_origin: Option<AllowOrigin> = cors_allow_origin.map(|cors_allow_origin| {
+        AllowOrigin::list(
+            cors_allow_origin
+                .iter()
+                .map(|origin| origin.parse::<HeaderValue>().unwrap()),
+        )
+    });
+
+    // Tokenizer and configuration of the model, read again for the revisions it is swapped for
+    let model_source = ModelSource {
+        tokenizer_name: tokenizer_name.clone(),
+        tokenizer_config_path,
+        chat_template_path,
+        trust_remote_code,
+        allow_vocab_mismatch,
+    };
+    let (files, model_info) = load_model(&model_source, revision.clone()).await?;
+    let ModelFiles {
+        tokenizer,
+        tokenizer_config,
+        processor_config,
+        config,
+        preprocessor_config,
+        ..
+    } = files;
+    let model_info = model_info.unwrap_or_else(|| HubModelInfo {
+        model_id: tokenizer_name.to_string(),
+        sha: None,
+        pipeline_tag: None,
+    });
+
+    // Exact-match cache of the deterministic responses
+    let response_cache = ResponseCache::new(
+        response_cache_size,
+        response_cache_ttl,
+        response_cache_redis_url,
+        model_info.sha.clone(),
+    )?
+    .map(Arc::new);
+
     tracing::info!("Using config {config:?}");
 
     // Only send usage stats when TGI is run in container and the function returns Some
@@ -1924,12 +3297,13 @@ pub async fn run(
         max_input_tokens,
         max_total_tokens,
         validation_workers,
-        api_key,
+        api_keys,
         config,
         (tokenizer, tokenizer_config),
         (preprocessor_config, processor_config),
         hostname,
         port,
+        grpc_port,
         ngrok,
         _ngrok_authtoken,
         _ngrok_edge,
@@ -1939,8 +3313,34 @@ pub async fn run(
         compat_return_full_text,
         allow_origin,
         payload_limit,
+        max_queue_size,
+        max_queue_wait,
+        health_check_interval,
+        stream_heartbeat_interval,
+        queue_journal_path,
+        rate_limiter,
+        tenant_header,
+        response_cache,
+        audit_log,
+        watermark_keys,
+        content_filter,
+        transforms,
+        max_new_tokens_limits,
+        idempotency_store,
+        stream_resume_events,
+        stream_resume_timeout,
+        prompt_templates,
+        ImageLimits {
+            max_images: max_images_per_request,
+            max_pixels: max_image_pixels,
+        },
+        model_source,
     )
     .await;
+    // With the tokens of the requests drained by the shutdown
+    if let Some(usage_file) = usage_file {
+        usage_file.save().await;
+    }
 
     if let Some(ua) = user_agent {
         match result {
@@ -1984,12 +3384,13 @@ async fn start(
     max_input_tokens: usize,
     max_total_tokens: usize,
     validation_workers: usize,
-    api_key: Option<String>,
+    api_keys: Option<Arc<auth::ApiKeys>>,
     config: Option<Config>,
     (tokenizer, tokenizer_config): (Tokenizer, HubTokenizerConfig),
     (preprocessor_config, processor_config): (Option<HubPreprocessorConfig>, HubProcessorConfig),
     hostname: String,
     port: u16,
+    grpc_port: Option<u16>,
     ngrok: bool,
     _ngrok_authtoken: Option<String>,
     _ngrok_edge: Option<String>,
@@ -1999,6 +3400,25 @@ async fn start(
     compat_return_full_text: bool,
     allow_origin: Option<AllowOrigin>,
     payload_limit: usize,
+    max_queue_size: Option<usize>,
+    max_queue_wait: Option<Duration>,
+    health_check_interval: Option<Duration>,
+    stream_heartbeat_interval: Option<Duration>,
+    queue_journal_path: Option<String>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    tenant_header: Option<HeaderName>,
+    response_cache: Option<Arc<ResponseCache>>,
+    audit_log: Option<Arc<AuditLog>>,
+    watermark_keys: Option<Arc<WatermarkKeys>>,
+    content_filter: Option<Arc<ContentFilter>>,
+    transforms: Option<Arc<Transforms>>,
+    max_new_tokens_limits: Option<MaxNewTokensLimits>,
+    idempotency_store: Option<Arc<IdempotencyStore>>,
+    stream_resume_events: Option<usize>,
+    stream_resume_timeout: Duration,
+    prompt_templates: Option<PromptTemplates>,
+    image_limits: ImageLimits,
+    model_source: ModelSource,
 ) -> Result<(), WebServerError> {
     // Determine the server port based on the feature and environment variable.
     let port = if cfg!(feature = "google") {
@@ -2017,7 +3437,33 @@ async fn start(
         }
     };
 
+    let shard_info = backend.shard_info();
+    // The Python tokenizers cannot be counted
+    if let (Tokenizer::Rust(tokenizer), Some(shard_info)) = (&tokenizer, &shard_info) {
+        let tokenizer_vocab_size = tokenizer.get_vocab_size(true);
+        let model_vocab_size = shard_info.vocab_size as usize;
+        if vocab_size_mismatch(tokenizer_vocab_size, model_vocab_size) {
+            if !model_source.allow_vocab_mismatch {
+                return Err(WebServerError::VocabSize(
+                    tokenizer_vocab_size,
+                    model_vocab_size,
+                ));
+            }
+            tracing::warn!(
+                "The tokenizer has {tokenizer_vocab_size} tokens and the model {model_vocab_size}: some token ids may not be decoded"
+            );
+        }
+    }
+    let served_models = backend.served_models();
+    let served_models = if served_models.is_empty() {
+        vec![model_info.model_id.clone()]
+    } else {
+        served_models
+    };
+
     // Create state
+    let max_new_tokens_limits =
+        max_new_tokens_limits.map(|limits| limits.with_default_model(&served_models[0]));
     let validation = Validation::new(
         validation_workers,
         tokenizer,
@@ -2029,16 +3475,82 @@ async fn start(
         max_input_tokens,
         max_total_tokens,
         disable_grammar_support,
-    );
+    )
+    .with_max_new_tokens_limits(max_new_tokens_limits)
+    .with_prompt_templates(prompt_templates)
+    .with_image_limits(image_limits)
+    // A client request generates at most as many sequences as it sends prompts
+    .with_max_n(max_client_batch_size);
+
+    // The other models are validated with their own tokenizer, within the limits of their
+    // shards
+    let mut other_models = HashMap::new();
+    for model in served_models.iter().skip(1) {
+        tracing::info!("Loading the tokenizer of model {model}");
+        let (files, _) = load_model(&model_source.for_model(model), None).await?;
+        if let (Some(vocab_size), Some(shard_info)) =
+            (files.vocab_size, backend.model_shard_info(model))
+        {
+            if vocab_size_mismatch(vocab_size, shard_info.vocab_size as usize) {
+                return Err(WebServerError::VocabSize(
+                    vocab_size,
+                    shard_info.vocab_size as usize,
+                ));
+            }
+        }
+        let (model_input_tokens, model_total_tokens) = backend
+            .model_token_limits(model)
+            .unwrap_or((max_input_tokens, max_total_tokens));
+        let model_validation = validation.with_tokenizer(
+            files.tokenizer,
+            files.config,
+            files.preprocessor_config,
+            model_input_tokens,
+            model_total_tokens,
+        );
+        other_models.insert(
+            model.clone(),
+            ServedModel::new(
+                model_validation,
+                files.tokenizer_config,
+                files.processor_config,
+            ),
+        );
+    }
+
+    let (journal, journaled_requests) = match queue_journal_path {
+        Some(path) => {
+            let (journal, requests) = Journal::open(path).map_err(WebServerError::Journal)?;
+            (Some(journal), requests)
+        }
+        None => (None, Vec::new()),
+    };
 
     let infer = Infer::new(
         backend,
         validation,
         max_concurrent_requests,
+        max_queue_size,
+        max_queue_wait,
+        health_check_interval,
+        stream_heartbeat_interval,
+        journal.clone(),
+        response_cache,
+        audit_log,
+        watermark_keys,
+        content_filter,
+        transforms,
+        served_models[0].clone(),
+        other_models,
+        model_info.sha.clone(),
         tokenizer_config,
         processor_config,
     );
 
+    if !journaled_requests.is_empty() {
+        journal::replay(infer.clone(), journaled_requests);
+    }
+
     // Duration buckets
     let duration_matcher = Matcher::Suffix(String::from("duration"));
     let n_duration_buckets = 35;
@@ -2068,6 +3580,15 @@ async fn start(
     // Batch size buckets
     let batch_size_matcher = Matcher::Full(String::from("tgi_batch_next_size"));
     let batch_size_buckets: Vec<f64> = (0..1024).map(|x| (x + 1) as f64).collect();
+    // Batch tokens buckets
+    let batch_tokens_matcher = Matcher::Full(String::from("tgi_batch_next_tokens"));
+    let batch_tokens_buckets: Vec<f64> = (0..24).map(|x| 2.0f64.powi(x)).collect();
+    // Batch budget usage buckets
+    let batch_budget_matcher = Matcher::Full(String::from("tgi_batch_next_budget_usage"));
+    let batch_budget_buckets: Vec<f64> = (0..20).map(|x| (x + 1) as f64 / 20.0).collect();
+    // Request blocks buckets
+    let request_blocks_matcher = Matcher::Full(String::from("tgi_request_blocks"));
+    let request_blocks_buckets: Vec<f64> = (0..16).map(|x| 2.0f64.powi(x)).collect();
     // Speculated tokens buckets
     // let skipped_matcher = Matcher::Full(String::from("tgi_request_skipped_tokens"));
     // let skipped_buckets: Vec<f64> = (0..shard_info.speculate + 1).map(|x| x as f64).collect();
@@ -2083,6 +3604,12 @@ async fn start(
         .set_buckets_for_metric(max_new_tokens_matcher, &max_new_tokens_buckets)
         .unwrap()
         .set_buckets_for_metric(batch_size_matcher, &batch_size_buckets)
+        .unwrap()
+        .set_buckets_for_metric(batch_tokens_matcher, &batch_tokens_buckets)
+        .unwrap()
+        .set_buckets_for_metric(batch_budget_matcher, &batch_budget_buckets)
+        .unwrap()
+        .set_buckets_for_metric(request_blocks_matcher, &request_blocks_buckets)
         .unwrap();
     // .set_buckets_for_metric(skipped_matcher, &skipped_buckets)
     // .unwrap();
@@ -2125,6 +3652,16 @@ async fn start(
         metrics::Unit::Count,
         "Generated tokens per request"
     );
+    metrics::describe_counter!(
+        "tgi_key_prompt_tokens",
+        metrics::Unit::Count,
+        "Prompt tokens of the requests per API key"
+    );
+    metrics::describe_counter!(
+        "tgi_key_completion_tokens",
+        metrics::Unit::Count,
+        "Generated tokens per API key"
+    );
     metrics::describe_counter!(
         "tgi_batch_inference_count",
         metrics::Unit::Count,
@@ -2135,6 +3672,11 @@ async fn start(
         metrics::Unit::Count,
         "Total number of requests"
     );
+    metrics::describe_counter!(
+        "tgi_batch_out_of_memory",
+        metrics::Unit::Count,
+        "Number of batches that ran out of memory on the shards"
+    );
     metrics::describe_counter!(
         "tgi_batch_inference_success",
         metrics::Unit::Count,
@@ -2146,6 +3688,11 @@ async fn start(
         "Current batch size"
     );
     metrics::describe_gauge!("tgi_queue_size", metrics::Unit::Count, "Current queue size");
+    metrics::describe_gauge!(
+        "tgi_queue_estimated_wait",
+        metrics::Unit::Seconds,
+        "Estimated time before a queued request starts"
+    );
     metrics::describe_gauge!(
         "tgi_batch_current_max_tokens",
         metrics::Unit::Count,
@@ -2191,6 +3738,21 @@ async fn start(
         metrics::Unit::Seconds,
         "Time spent validating the request"
     );
+    metrics::describe_gauge!(
+        "tgi_tokenizer_queue_size",
+        metrics::Unit::Count,
+        "Requests waiting for a tokenization worker"
+    );
+    metrics::describe_histogram!(
+        "tgi_tokenizer_queue_duration",
+        metrics::Unit::Seconds,
+        "Time spent waiting for a tokenization worker"
+    );
+    metrics::describe_histogram!(
+        "tgi_tokenization_duration",
+        metrics::Unit::Seconds,
+        "Time spent tokenizing or detokenizing, per operation (encode or decode)"
+    );
     metrics::describe_histogram!(
         "tgi_request_duration",
         metrics::Unit::Seconds,
@@ -2211,6 +3773,16 @@ async fn start(
         metrics::Unit::Count,
         "Batch size of the next batch"
     );
+    metrics::describe_histogram!(
+        "tgi_batch_next_tokens",
+        metrics::Unit::Count,
+        "Tokens of the next batch per phase (prefill or decode)"
+    );
+    metrics::describe_histogram!(
+        "tgi_batch_next_budget_usage",
+        metrics::Unit::Count,
+        "Fraction of the token budget used by the next batch per phase (prefill or total)"
+    );
 
     // CORS layer
     let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
@@ -2223,9 +3795,11 @@ async fn start(
     let info = Info {
         model_id: model_info.model_id,
         model_sha: model_info.sha,
-        // model_dtype: shard_info.dtype,
-        // model_device_type: shard_info.device_type,
+        shard_info,
         model_pipeline_tag: model_info.pipeline_tag,
+        served_models,
+        // Loaded and unloaded at runtime, so listed by `get_model_info`
+        lora_adapters: Vec::new(),
         max_concurrent_requests,
         max_best_of,
         max_stop_sequences,
@@ -2304,49 +3878,161 @@ async fn start(
     let mut base_routes = Router::new()
         .route("/", post(compat_generate))
         .route("/generate", post(generate))
+        .route("/generate/:request_id", delete(abort_generate))
+        .route("/generate_batch", post(generate_batch))
         .route("/generate_stream", post(generate_stream))
+        .route("/generate_stream/ws", get(generate_stream_ws))
         .route("/v1/chat/completions", post(chat_completions))
         .route("/v1/completions", post(completions))
         .route("/vertex", post(vertex_compatibility))
         .route("/invocations", post(sagemaker_compatibility))
-        .route("/tokenize", post(tokenize));
-
-    if let Some(api_key) = api_key {
-        let mut prefix = "Bearer ".to_string();
-        prefix.push_str(&api_key);
-
-        // Leak to allow FnMut
-        let api_key: &'static str = prefix.leak();
-
-        let auth = move |headers: HeaderMap,
-                         request: axum::extract::Request,
-                         next: axum::middleware::Next| async move {
-            match headers.get(AUTHORIZATION) {
-                Some(token) => match token.to_str() {
-                    Ok(token_str) if token_str.to_lowercase() == api_key.to_lowercase() => {
-                        let response = next.run(request).await;
-                        Ok(response)
-                    }
-                    _ => Err(StatusCode::UNAUTHORIZED),
-                },
-                None => Err(StatusCode::UNAUTHORIZED),
-            }
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+        .route("/watermark/verify", post(verify_watermark));
+    // The flash models cannot embed their inputs
+    if infer.supports_embeddings() {
+        base_routes = base_routes.route("/v1/embeddings", post(embeddings));
+    } else {
+        tracing::info!("The model does not support embeddings, `/v1/embeddings` is not served");
+    }
+
+    // The gRPC API applies the same limits and tenants
+    let grpc_limits = (rate_limiter.clone(), tenant_header.clone());
+
+    // Backends share their queue between the tenants
+    let tenant = move |request: axum::extract::Request, next: axum::middleware::Next| {
+        tenant::tenant(tenant_header.clone(), request, next)
+    };
+    base_routes = base_routes.layer(axum::middleware::from_fn(tenant));
+
+    // Retries with the same `Idempotency-Key` get the response of the first request
+    if let Some(idempotency_store) = idempotency_store {
+        let idempotency = move |request: axum::extract::Request, next: axum::middleware::Next| {
+            idempotency::idempotency(idempotency_store.clone(), request, next)
+        };
+        base_routes = base_routes.layer(axum::middleware::from_fn(idempotency));
+    }
+
+    // Clients reconnecting with `Last-Event-ID` get the rest of their stream
+    let resumable_streams = ResumableStreams::new(
+        stream_resume_events,
+        stream_resume_timeout,
+        infer.stream_heartbeat_interval(),
+    );
+    if let Some(resumable_streams) = resumable_streams.map(Arc::new) {
+        let stream_resume = move |request: axum::extract::Request, next: axum::middleware::Next| {
+            stream_resume::stream_resume(resumable_streams.clone(), request, next)
+        };
+        base_routes = base_routes.layer(axum::middleware::from_fn(stream_resume));
+    }
+
+    // Clients are rate limited by API key, or by address when the routes are open
+    if let Some(rate_limiter) = rate_limiter {
+        let rate_limit = move |request: axum::extract::Request, next: axum::middleware::Next| {
+            rate_limit::rate_limit(rate_limiter.clone(), request, next)
         };
+        base_routes = base_routes.layer(axum::middleware::from_fn(rate_limit));
+    }
 
-        base_routes = base_routes.layer(axum::middleware::from_fn(auth))
+    // Only the keys with the scope of a route can call it
+    let authenticate = |api_keys: &Arc<auth::ApiKeys>, scope: auth::Scope| {
+        let api_keys = api_keys.clone();
+        axum::middleware::from_fn(
+            move |request: axum::extract::Request, next: axum::middleware::Next| {
+                auth::authenticate(api_keys.clone(), scope, request, next)
+            },
+        )
+    };
+    // The sessions are admin routes, as their activity tells about the clients
+    let mut admin_routes = Router::new()
+        .route("/drain", post(drain))
+        .route("/metrics/sessions", get(metrics_sessions));
+    let mut metrics_routes = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/metrics/batches", get(metrics_batches))
+        .route("/debug/timings", get(debug_timings))
+        .route("/metrics/cache", get(metrics_cache));
+    if let Some(api_keys) = api_keys.as_ref() {
+        base_routes = base_routes
+            .layer(axum::middleware::from_fn(quota::enforce))
+            .layer(authenticate(api_keys, auth::Scope::Generate));
+        // Not refused by the quotas, to see when they reset
+        let usage_routes = Router::new()
+            .route("/usage", get(get_usage))
+            .layer(Extension(api_keys.clone()))
+            .layer(authenticate(api_keys, auth::Scope::Generate));
+        base_routes = base_routes.merge(usage_routes);
+        // Runtime settings can only be changed, and the queue inspected, by authenticated
+        // clients
+        admin_routes = admin_routes
+            .route(
+                "/admin/config",
+                get(get_runtime_config).post(update_runtime_config),
+            )
+            .route("/admin/watermark/keys", get(get_watermark_keys))
+            .route("/admin/watermark/keys/reload", post(reload_watermark_keys))
+            .route("/admin/adapters", post(load_adapter))
+            .route("/admin/adapters/:id", delete(unload_adapter))
+            .route("/admin/model/swap", get(swap_status).post(swap_model))
+            .route("/v3/queue", get(get_queue))
+            .layer(authenticate(api_keys, auth::Scope::Admin));
+        if api_keys.protects_metrics {
+            metrics_routes = metrics_routes.layer(authenticate(api_keys, auth::Scope::Metrics));
+        }
+    } else {
+        // Without keys, only the hooks of the machine serving the model can drain it or list
+        // its sessions
+        admin_routes = admin_routes.layer(axum::middleware::from_fn(auth::local_only));
     }
+    base_routes = base_routes.merge(admin_routes);
+
+    // Tell overloaded clients when the queue is expected to have room again
+    let retry_infer = infer.clone();
+    let retry_after = move |request: axum::extract::Request, next: axum::middleware::Next| {
+        let infer = retry_infer.clone();
+        async move {
+            let mut response = next.run(request).await;
+            // Rate limited responses already carry their own `Retry-After`
+            if response.status() == StatusCode::TOO_MANY_REQUESTS
+                && !response.headers().contains_key(RETRY_AFTER)
+            {
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(infer.retry_after()));
+            }
+            response
+        }
+    };
+    base_routes = base_routes.layer(axum::middleware::from_fn(retry_after));
+    // Outermost, so that the refused requests are traced and answered with their id too
+    base_routes = base_routes.layer(axum::middleware::from_fn(request_id::request_id));
     let info_routes = Router::new()
         .route("/", get(health))
         .route("/chat_tokenize", post(get_chat_tokenize))
         .route("/info", get(get_model_info))
         .route("/health", get(health))
         .route("/ping", get(health))
-        .route("/metrics", get(metrics))
-        .route("/v1/models", get(openai_get_model_info));
+        .route("/v1/models", get(openai_get_model_info))
+        .merge(metrics_routes);
 
     let compute_type =
         ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
 
+    // Same state as the HTTP routes
+    let grpc_service = grpc_port.map(|grpc_port| {
+        let (rate_limiter, tenant_header) = grpc_limits;
+        (
+            SocketAddr::new(addr.ip(), grpc_port),
+            RouterService::new(
+                infer.clone(),
+                compute_type.clone(),
+                info.clone(),
+                rate_limiter,
+                tenant_header,
+            ),
+        )
+    });
+
     // Combine routes and layers
     let mut app = Router::new()
         .merge(swagger_ui)
@@ -2392,9 +4078,12 @@ async fn start(
     app = app
         .layer(Extension(info))
         .layer(Extension(compat_return_full_text))
-        .layer(Extension(infer))
+        .layer(Extension(infer.clone()))
+        .layer(Extension(model_source))
         .layer(Extension(compute_type))
+        .layer(Extension(MessageLimit(payload_limit)))
         .layer(Extension(prom_handle.clone()))
+        .layer(axum::middleware::from_fn(json_errors))
         .layer(OtelAxumLayer::default())
         .layer(DefaultBodyLimit::max(payload_limit))
         .layer(cors_layer);
@@ -2421,10 +4110,32 @@ async fn start(
         // Run server
 
         let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-        axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_signal())
-            .await
-            .map_err(|err| WebServerError::Axum(Box::new(err)))?;
+        // The address of the clients identifies them when they have no API key
+        let http = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal());
+        let grpc = async {
+            match grpc_service {
+                Some((grpc_addr, service)) => {
+                    grpc::serve(grpc_addr, service, api_keys, shutdown_signal()).await
+                }
+                None => Ok(()),
+            }
+        };
+        let (result, grpc_result) = tokio::join!(http, grpc);
+        // The WebSocket connections outlive their HTTP connection: the ones waiting for a
+        // request are closed, the others finish their generation
+        infer.drain();
+        infer.wait_upgraded_connections().await;
+        // The requests that did not start are replayed by the next router
+        if let Some(journal) = journal {
+            journal.close();
+        }
+        infer.shutdown().await;
+        result.map_err(|err| WebServerError::Axum(Box::new(err)))?;
+        grpc_result?;
     }
     Ok(())
 }
@@ -2496,6 +4207,19 @@ async fn shutdown_signal() {
 }
 
 /// Convert to Axum supported formats
+impl From<&InferError> for ErrorResponse {
+    fn from(err: &InferError) -> Self {
+        let response = ErrorResponse::new(err.error_type(), err.to_string());
+        match err {
+            InferError::ValidationError(err) => match err.param() {
+                Some(param) => response.with_param(param),
+                None => response,
+            },
+            _ => response,
+        }
+    }
+}
+
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
         let status_code = match err {
@@ -2508,25 +4232,62 @@ impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
             InferError::MissingTemplateVariable(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::ToolError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::StreamSerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::Draining => StatusCode::SERVICE_UNAVAILABLE,
+            InferError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+            InferError::EmbeddingsUnsupported => StatusCode::NOT_IMPLEMENTED,
+            InferError::GenerationTimeout | InferError::DeadlineExceeded => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            // "Client Closed Request"
+            InferError::Cancelled => StatusCode::from_u16(499).unwrap(),
+            InferError::BackendUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            InferError::ModelNotAllowed(_) => StatusCode::FORBIDDEN,
+            InferError::AdapterLoadingUnsupported => StatusCode::NOT_IMPLEMENTED,
+            InferError::AdapterNotLoaded(_) => StatusCode::NOT_FOUND,
+            InferError::AdapterAlreadyLoaded(_) => StatusCode::CONFLICT,
+            InferError::AdapterError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::SwapUnsupported => StatusCode::NOT_IMPLEMENTED,
+            InferError::SwapInProgress => StatusCode::CONFLICT,
+            InferError::SwapError(_) => StatusCode::UNPROCESSABLE_ENTITY,
         };
 
-        (
-            status_code,
-            Json(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            }),
-        )
+        (status_code, Json(ErrorResponse::from(&err)))
+    }
+}
+
+/// Wrap the plain text rejections of the extractors, e.g. for a malformed JSON body, in an
+/// `ErrorResponse`
+async fn json_errors(request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    let response = next.run(request).await;
+    let is_text = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/plain"));
+    if !response.status().is_client_error() || !is_text {
+        return response;
     }
+
+    let (parts, body) = response.into_parts();
+    let message = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => parts
+            .status
+            .canonical_reason()
+            .unwrap_or_default()
+            .to_string(),
+    };
+    (
+        parts.status,
+        Json(ErrorResponse::new("invalid_request", message)),
+    )
+        .into_response()
 }
 
 impl From<InferError> for Event {
     fn from(err: InferError) -> Self {
         Event::default()
-            .json_data(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            })
+            .json_data(ErrorResponse::from(&err))
             .unwrap()
     }
 }
@@ -2535,4 +4296,162 @@ impl From<InferError> for Event {
 pub enum WebServerError {
     #[error("Axum error: {0}")]
     Axum(#[from] axum::BoxError),
+    #[error("Unable to read the chat template: {0}")]
+    ChatTemplate(std::io::Error),
+    #[error("Unable to load the tokenizer: {0}")]
+    Tokenizer(String),
+    #[error("Unable to open the queue journal: {0}")]
+    Journal(std::io::Error),
+    #[error("gRPC server error: {0}")]
+    Grpc(#[from] tonic::transport::Error),
+    #[error(transparent)]
+    Auth(#[from] auth::AuthError),
+    #[error(transparent)]
+    RateLimit(#[from] RateLimitError),
+    #[error(transparent)]
+    ResponseCache(#[from] ResponseCacheError),
+    #[error(transparent)]
+    Audit(#[from] AuditError),
+    #[error(transparent)]
+    Watermark(#[from] WatermarkError),
+    #[error(transparent)]
+    ContentFilter(#[from] ContentFilterError),
+    #[error(transparent)]
+    Transform(#[from] TransformError),
+    #[error(transparent)]
+    MaxNewTokens(#[from] MaxNewTokensError),
+    #[error(transparent)]
+    PromptTemplates(#[from] PromptTemplateError),
+    #[error("Invalid tenant header: {0}")]
+    TenantHeader(axum::http::header::InvalidHeaderName),
+    #[error("The tokenizer has {0} tokens, which does not match the {1} embeddings of the model: check that the tokenizer is the one of the model, or use `--allow-vocab-mismatch`")]
+    VocabSize(usize, usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::mock::MockBackend;
+
+    /// Serve the generation routes with the mock backend on a free port
+    async fn serve_mock() -> String {
+        let app = Router::new()
+            .route("/generate", post(generate))
+            .route("/generate_stream", post(generate_stream))
+            .route("/health", get(health))
+            .route("/v1/completions", post(completions))
+            .layer(Extension(MockBackend::default().infer(4)))
+            .layer(Extension(ComputeType("mock".to_string())))
+            .layer(Extension(Info {
+                model_id: "mock".to_string(),
+                model_sha: None,
+                shard_info: None,
+                model_pipeline_tag: None,
+                served_models: vec!["mock".to_string()],
+                lora_adapters: Vec::new(),
+                max_concurrent_requests: 4,
+                max_best_of: 1,
+                max_stop_sequences: 4,
+                max_input_tokens: 1024,
+                max_total_tokens: 2048,
+                validation_workers: 1,
+                max_client_batch_size: 4,
+                router: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                sha: None,
+                docker_label: None,
+            }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}")
+    }
+
+    async fn post_json(url: String, body: &str) -> (reqwest::StatusCode, String) {
+        let response = reqwest::Client::new()
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        (response.status(), response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_serve_mock_backend() {
+        let url = serve_mock().await;
+
+        let (status, body) = post_json(
+            format!("{url}/generate"),
+            r#"{"inputs": "tok1 tok2", "parameters": {"max_new_tokens": 3, "details": true}}"#,
+        )
+        .await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(response["generated_text"], " tok0 tok1 tok2");
+        assert_eq!(response["details"]["finish_reason"], "length");
+        assert_eq!(response["details"]["generated_tokens"], 3);
+
+        let (status, body) = post_json(
+            format!("{url}/generate_stream"),
+            r#"{"inputs": "tok1", "parameters": {"max_new_tokens": 2}}"#,
+        )
+        .await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        let events: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["token"]["text"], " tok0");
+        assert!(events[0]["generated_text"].is_null());
+        assert_eq!(events[1]["generated_text"], " tok0 tok1");
+
+        // The requests are validated by the router before they reach the backend
+        let (status, body) = post_json(
+            format!("{url}/generate"),
+            r#"{"inputs": "tok1", "parameters": {"max_new_tokens": 0}}"#,
+        )
+        .await;
+        assert_eq!(status, reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body.contains("max_new_tokens"));
+
+        let response = reqwest::get(format!("{url}/health")).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_completions_echo_logprobs() {
+        let url = serve_mock().await;
+
+        let (status, body) = post_json(
+            format!("{url}/v1/completions"),
+            r#"{"prompt": "tok1 tok2", "max_tokens": 2, "temperature": 0, "echo": true, "logprobs": 0}"#,
+        )
+        .await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let choice = &response["choices"][0];
+        assert_eq!(choice["text"], "tok1 tok2 tok0 tok1");
+        // The prompt tokens come first, the first one has no logprob
+        let logprobs = &choice["logprobs"];
+        assert_eq!(logprobs["tokens"].as_array().unwrap().len(), 4);
+        assert_eq!(logprobs["tokens"][2], " tok0");
+        assert_eq!(logprobs["token_logprobs"][3], 0.0);
+        assert!(logprobs["top_logprobs"][0].is_null());
+
+        // Without `echo`, only the generated tokens are returned
+        let (status, body) = post_json(
+            format!("{url}/v1/completions"),
+            r#"{"prompt": "tok1 tok2", "max_tokens": 2, "temperature": 0, "logprobs": 0}"#,
+        )
+        .await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let choice = &response["choices"][0];
+        assert_eq!(choice["text"], " tok0 tok1");
+        assert_eq!(choice["logprobs"]["tokens"].as_array().unwrap().len(), 2);
+    }
 }
@@ -0,0 +1,398 @@
+/// Resumable SSE streams: their events are numbered, and the latest ones are kept so that a
+/// client reconnecting with `Last-Event-ID` gets the events it missed
+use crate::infer::abort::Owner;
+use crate::{request_id, ErrorResponse};
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::Instrument;
+
+/// Header of the reconnections, with the id of the last event the client received
+static LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
+/// Comment sent to the resumed clients while no event comes, like the keep-alive of the streams
+const PING: &[u8] = b":ping\n\n";
+
+#[derive(Debug, Default)]
+struct Buffered {
+    /// Latest events with their id, the text of each one starting with its `id` field
+    events: VecDeque<(u64, Bytes)>,
+    next_id: u64,
+    /// The stream ended, nothing comes after `events`
+    completed: bool,
+}
+
+impl Buffered {
+    /// Id of the oldest event that can still be sent
+    fn oldest(&self) -> u64 {
+        self.events.front().map_or(self.next_id, |(id, _)| *id)
+    }
+}
+
+/// Stream of one request, generated independently of the clients following it
+#[derive(Debug)]
+struct Recording {
+    owner: Owner,
+    /// Status and headers of the response
+    head: (StatusCode, HeaderMap),
+    buffered: Mutex<Buffered>,
+    /// Notified of every new event, its receivers are the clients following the stream
+    updates: watch::Sender<()>,
+}
+
+impl Recording {
+    fn push(&self, capacity: usize, event: &[u8]) {
+        let mut buffered = self.buffered.lock().unwrap();
+        let id = buffered.next_id;
+        buffered.next_id += 1;
+        let mut text = format!("id: {id}\n").into_bytes();
+        text.extend_from_slice(event);
+        buffered.events.push_back((id, Bytes::from(text)));
+        if buffered.events.len() > capacity {
+            buffered.events.pop_front();
+        }
+        drop(buffered);
+        self.updates.send_replace(());
+    }
+
+    fn complete(&self) {
+        self.buffered.lock().unwrap().completed = true;
+        self.updates.send_replace(());
+    }
+
+    /// Resolves once no client followed the stream for `timeout`
+    async fn abandoned(&self, timeout: Duration) {
+        loop {
+            self.updates.closed().await;
+            tokio::time::sleep(timeout).await;
+            if self.updates.receiver_count() == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Response following the stream from the event after `last_event_id`, None if the stream
+    /// does not hold that event anymore
+    fn follow(
+        self: Arc<Self>,
+        last_event_id: Option<u64>,
+        heartbeat: Duration,
+    ) -> Option<Response> {
+        let mut next_id = last_event_id.map_or(0, |id| id.saturating_add(1));
+        if next_id < self.buffered.lock().unwrap().oldest() {
+            return None;
+        }
+        // Subscribed before the response is sent, for the stream to know it is followed
+        let mut updates = self.updates.subscribe();
+        let recording = self;
+        let (status, headers) = recording.head.clone();
+        let body = async_stream::stream! {
+            loop {
+                let (events, completed) = {
+                    let buffered = recording.buffered.lock().unwrap();
+                    // A client too slow for the buffer ends its stream, to resume from the
+                    // events that are left
+                    if next_id < buffered.oldest() {
+                        break;
+                    }
+                    let events: Vec<Bytes> = buffered
+                        .events
+                        .iter()
+                        .filter(|(id, _)| *id >= next_id)
+                        .map(|(_, event)| event.clone())
+                        .collect();
+                    next_id = next_id.max(buffered.next_id);
+                    (events, buffered.completed)
+                };
+                for event in events {
+                    yield Ok::<_, Infallible>(event);
+                }
+                if completed {
+                    break;
+                }
+                if tokio::time::timeout(heartbeat, updates.changed()).await.is_err() {
+                    yield Ok(Bytes::from_static(PING));
+                }
+            }
+        };
+        let mut response = Response::new(Body::from_stream(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Some(response)
+    }
+}
+
+/// Streams of the running requests by `x-request-id`, kept for `timeout` after they end
+#[derive(Debug)]
+pub(crate) struct ResumableStreams {
+    /// Latest events kept per stream
+    capacity: usize,
+    /// How long a stream is generated without any client, and kept once it ended
+    timeout: Duration,
+    /// Interval of the keep-alive comments of the resumed streams
+    heartbeat: Duration,
+    recordings: Mutex<HashMap<String, Arc<Recording>>>,
+}
+
+impl ResumableStreams {
+    /// Streams keeping up to `events` events each, None if `events` is not set
+    pub(crate) fn new(
+        events: Option<usize>,
+        timeout: Duration,
+        heartbeat: Duration,
+    ) -> Option<Self> {
+        let capacity = events.filter(|&events| events > 0)?;
+        Some(Self {
+            capacity,
+            timeout,
+            heartbeat,
+            recordings: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Record the stream of `response`, and follow it from its first event
+    fn record(self: &Arc<Self>, id: String, owner: Owner, response: Response) -> Response {
+        let (parts, body) = response.into_parts();
+        let recording = Arc::new(Recording {
+            owner,
+            head: (parts.status, parts.headers),
+            buffered: Mutex::new(Buffered::default()),
+            updates: watch::Sender::new(()),
+        });
+        let response = recording
+            .clone()
+            .follow(None, self.heartbeat)
+            .expect("Nothing was evicted from a new stream");
+        // A request reusing the id of another one replaces its stream
+        self.recordings
+            .lock()
+            .unwrap()
+            .insert(id.clone(), recording.clone());
+
+        let streams = self.clone();
+        // The stream is generated even while its client reconnects
+        let future = async move {
+            let mut body = body.into_data_stream();
+            let abandoned = recording.abandoned(streams.timeout);
+            tokio::pin!(abandoned);
+            loop {
+                let chunk = tokio::select! {
+                    chunk = body.next() => chunk,
+                    _ = &mut abandoned => {
+                        tracing::debug!("Resumable stream abandoned by its client");
+                        break;
+                    }
+                };
+                match chunk {
+                    // Each chunk of an SSE body is one event, or a keep-alive comment
+                    Some(Ok(chunk)) if !chunk.starts_with(b":") => {
+                        recording.push(streams.capacity, &chunk)
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        tracing::error!("Resumable stream failed: {err}");
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            // Dropping the body ends the generation, if it was abandoned
+            drop(body);
+            recording.complete();
+            tokio::time::sleep(streams.timeout).await;
+            streams.forget(&id, &recording);
+        };
+        tokio::spawn(future.instrument(tracing::Span::current()));
+        response
+    }
+
+    /// Stream `id` of `owner` from the event after `last_event_id`
+    fn resume(&self, id: &str, owner: &Owner, last_event_id: u64) -> Result<Response, Response> {
+        let recording = self
+            .recordings
+            .lock()
+            .unwrap()
+            .get(id)
+            // The streams of other clients are unknown to this one
+            .filter(|recording| recording.owner.is(owner))
+            .cloned();
+        let Some(recording) = recording else {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse::new(
+                    "not_found",
+                    format!("No stream to resume with id `{id}`"),
+                )),
+            )
+                .into_response());
+        };
+        recording
+            .follow(Some(last_event_id), self.heartbeat)
+            .ok_or_else(|| {
+                (
+                    StatusCode::GONE,
+                    Json(
+                        ErrorResponse::new(
+                            "stream_expired",
+                            format!("The events after {last_event_id} are not kept anymore"),
+                        )
+                        .with_param("Last-Event-ID"),
+                    ),
+                )
+                    .into_response()
+            })
+    }
+
+    fn forget(&self, id: &str, recording: &Arc<Recording>) {
+        let mut recordings = self.recordings.lock().unwrap();
+        if recordings
+            .get(id)
+            .is_some_and(|recorded| Arc::ptr_eq(recorded, recording))
+        {
+            recordings.remove(id);
+        }
+    }
+}
+
+fn is_event_stream(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/event-stream"))
+}
+
+/// Middleware numbering the events of the SSE responses, and answering the requests with a
+/// `Last-Event-ID` header with the rest of the stream of their `x-request-id`
+pub(crate) async fn stream_resume(
+    streams: Arc<ResumableStreams>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(id) = request_id::current() else {
+        return next.run(request).await;
+    };
+    let owner = Owner::current();
+    let last_event_id = request
+        .headers()
+        .get(&LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().parse::<u64>());
+    match last_event_id {
+        Some(Ok(last_event_id)) => {
+            let response = streams.resume(&id, &owner, last_event_id);
+            if response.is_ok() {
+                metrics::counter!("tgi_request_stream_resumed").increment(1);
+            }
+            response.unwrap_or_else(|response| response)
+        }
+        Some(Err(_)) => {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(
+                    ErrorResponse::new("validation", "`Last-Event-ID` must be an event id")
+                        .with_param("Last-Event-ID"),
+                ),
+            )
+                .into_response()
+        }
+        None => {
+            let response = next.run(request).await;
+            if is_event_stream(&response) && response.status().is_success() {
+                streams.record(id, owner, response)
+            } else {
+                response
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streams(capacity: usize) -> Arc<ResumableStreams> {
+        let streams = ResumableStreams::new(
+            Some(capacity),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        Arc::new(streams.unwrap())
+    }
+
+    fn event_stream(events: &'static [&'static str]) -> Response {
+        let chunks = futures::stream::iter(events)
+            .map(|event| Ok::<_, Infallible>(Bytes::from_static(event.as_bytes())));
+        let mut response = Response::new(Body::from_stream(chunks));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            axum::http::HeaderValue::from_static("text/event-stream"),
+        );
+        response
+    }
+
+    async fn body(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_number_events() {
+        assert!(ResumableStreams::new(None, Duration::ZERO, Duration::ZERO).is_none());
+        let streams = streams(8);
+        let response = streams.record(
+            "a".to_string(),
+            Owner::new(None, None, true),
+            event_stream(&["data: 1\n\n", ":ping\n\n", "data: 2\n\n"]),
+        );
+        assert_eq!(body(response).await, "id: 0\ndata: 1\n\nid: 1\ndata: 2\n\n");
+
+        let resumed = streams
+            .resume("a", &Owner::new(None, None, false), 0)
+            .unwrap();
+        assert_eq!(body(resumed).await, "id: 1\ndata: 2\n\n");
+        assert_eq!(
+            streams
+                .resume("a", &Owner::new(Some("other".to_string()), None, false), 0)
+                .unwrap_err()
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_evicted() {
+        let streams = streams(1);
+        let response = streams.record(
+            "a".to_string(),
+            Owner::new(None, None, true),
+            event_stream(&["data: 1\n\n", "data: 2\n\n", "data: 3\n\n"]),
+        );
+        // The stream is generated without its client
+        drop(response);
+        let recording = streams.recordings.lock().unwrap()["a"].clone();
+        while !recording.buffered.lock().unwrap().completed {
+            tokio::task::yield_now().await;
+        }
+        let resumed = streams
+            .resume("a", &Owner::new(None, None, false), 1)
+            .unwrap();
+        assert_eq!(body(resumed).await, "id: 2\ndata: 3\n\n");
+        let evicted = streams
+            .resume("a", &Owner::new(None, None, false), 0)
+            .unwrap_err();
+        assert_eq!(evicted.status(), StatusCode::GONE);
+    }
+}
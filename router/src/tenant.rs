@@ -0,0 +1,88 @@
+/// Tenant of the requests, used by the backends to share their queue between tenants
+use crate::auth;
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::future::Future;
+
+tokio::task_local! {
+    /// Tenant of the request being handled
+    static TENANT: String;
+}
+
+/// Name of the API key of the client, or else the tenant named by the `tenant_header` header.
+/// The header is only trusted when the routes are open, as it would let an authenticated
+/// client take the share of another tenant.
+fn identify(
+    key_name: Option<&str>,
+    headers: &HeaderMap,
+    tenant_header: Option<&HeaderName>,
+) -> Option<String> {
+    let header = tenant_header.and_then(|name| headers.get(name));
+    identify_value(key_name, header.and_then(|header| header.to_str().ok()))
+}
+
+/// Tenant of a request given the value of its tenant header, for the gRPC requests whose
+/// metadata are not a `HeaderMap`
+pub(crate) fn identify_value(key_name: Option<&str>, header: Option<&str>) -> Option<String> {
+    key_name.or(header).map(String::from)
+}
+
+/// Tenant of the request being handled, if it has one
+pub(crate) fn current() -> Option<String> {
+    TENANT.try_with(|tenant| tenant.clone()).ok()
+}
+
+/// Run `future` on behalf of `tenant`, for work spawned outside of the request task
+pub(crate) async fn scope<F: Future>(tenant: Option<String>, future: F) -> F::Output {
+    match tenant {
+        Some(tenant) => TENANT.scope(tenant, future).await,
+        None => future.await,
+    }
+}
+
+/// Middleware recording the tenant of the requests
+pub(crate) async fn tenant(
+    tenant_header: Option<HeaderName>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = auth::current_key();
+    let key_name = key.as_deref().map(auth::ApiKey::name);
+    let tenant = identify(key_name, request.headers(), tenant_header.as_ref());
+    scope(tenant, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_identify() {
+        let tenant_header = HeaderName::from_static("x-tenant-id");
+        let mut headers = HeaderMap::new();
+        assert_eq!(identify(None, &headers, Some(&tenant_header)), None);
+
+        // The secret of the key is never the tenant
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer key"),
+        );
+        assert_eq!(identify(None, &headers, Some(&tenant_header)), None);
+
+        headers.insert(&tenant_header, HeaderValue::from_static("team-a"));
+        assert_eq!(
+            identify(None, &headers, Some(&tenant_header)),
+            Some("team-a".to_string())
+        );
+        assert_eq!(identify(None, &headers, None), None);
+
+        // An authenticated client cannot name another tenant
+        assert_eq!(
+            identify(Some("team-b"), &headers, Some(&tenant_header)),
+            Some("team-b".to_string())
+        );
+    }
+}
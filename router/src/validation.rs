@@ -1,21 +1,27 @@
 use crate::config::Config;
+use crate::max_new_tokens::{Limits as MaxNewTokens, MaxNewTokensLimits};
+use crate::prompt_templates::{PromptTemplateVersion, PromptTemplates};
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
 use crate::{
-    GenerateParameters, GenerateRequest, GrammarType, HubPreprocessorConfig, Idefics2Preprocessor,
-    TokenizerTrait,
+    AdapterWeight, GenerateParameters, GenerateRequest, GrammarType, HubPreprocessorConfig,
+    Idefics2Preprocessor, Priority, TokenizerTrait, TruncationSide,
 };
 use crate::{PyTokenizer, Tokenizer};
 use base64::{engine::general_purpose::STANDARD, Engine};
-use image::{ImageFormat, ImageReader};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader};
 use jsonschema::{Draft, JSONSchema};
 use outlines_core::json_schema::to_regex as json_schema_to_regex;
+use outlines_index::prelude::{Index, Vocabulary};
 use rand::{thread_rng, Rng};
 use serde_json::Value;
 /// Payload validation logic
 use std::cmp::min;
+use std::collections::HashMap;
 use std::io::Cursor;
 use std::iter;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
@@ -23,19 +29,84 @@ use tracing::{instrument, Span};
 use {once_cell::sync::Lazy, regex::Regex};
 
 static DEFAULT_GENERATION_LENGTH: u32 = 1024;
+/// Maximum number of tokens biased by a request
+const MAX_LOGIT_BIAS_TOKENS: usize = 300;
+/// Maximum size of the serialized `metadata` of a request, which is copied in its logs
+const MAX_METADATA_BYTES: usize = 4096;
+/// Maximum number of LoRA adapters mixed by a request
+const MAX_ADAPTERS: usize = 4;
+/// Requests waiting for a tokenization worker, per worker. Once the queue is full, the
+/// validations wait for a slot before queueing theirs.
+const TOKENIZER_QUEUE_SIZE_PER_WORKER: usize = 64;
+/// The embeddings of the models are padded to a multiple of a power of two, at most this one
+const MAX_VOCAB_PADDING: usize = 1024;
+
+/// Text of the tokens of a Rust tokenizer, decoded on the first regex grammar
+#[derive(Debug)]
+struct GrammarVocabulary {
+    tokenizer: tokenizers::Tokenizer,
+    vocabulary: OnceLock<Vocabulary>,
+}
+
+impl GrammarVocabulary {
+    fn new(tokenizer: &Tokenizer, disable_grammar_support: bool) -> Option<Arc<Self>> {
+        match tokenizer {
+            Tokenizer::Rust(tokenizer) if !disable_grammar_support => Some(Arc::new(Self {
+                tokenizer: tokenizer.clone(),
+                vocabulary: OnceLock::new(),
+            })),
+            _ => None,
+        }
+    }
+
+    fn get(&self) -> &Vocabulary {
+        self.vocabulary.get_or_init(|| {
+            let vocab_size = self.tokenizer.get_vocab_size(true) as u32;
+            // Any id past the tokens stands for the end of sequence, that the index allows in
+            // its final states
+            let mut vocabulary = Vocabulary::new(vocab_size);
+            for id in 0..vocab_size {
+                match self.tokenizer.decode(&[id], true) {
+                    Ok(token) if !token.is_empty() => {
+                        let _ = vocabulary.try_insert(token, id);
+                    }
+                    _ => {}
+                }
+            }
+            vocabulary
+        })
+    }
+}
 
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Validation {
+    /// Number of tokenization workers asked for, started again with the tokenizer of another
+    /// revision of the model
+    workers: usize,
     /// Validation parameters
     max_best_of: usize,
-    max_stop_sequences: usize,
+    /// Largest number of choices of the OpenAI requests, 1 until `with_max_n`
+    max_n: usize,
     max_top_n_tokens: u32,
     max_input_length: usize,
     max_total_tokens: usize,
     disable_grammar_support: bool,
-    /// Channel to communicate with the background tokenization task
-    sender: mpsc::UnboundedSender<TokenizerRequest>,
+    /// Number of tokens of the vocabulary, unknown with a Python tokenizer
+    vocab_size: Option<usize>,
+    /// Tokens the regexes of the requests are compiled against, unknown with a Python
+    /// tokenizer
+    grammar_vocabulary: Option<Arc<GrammarVocabulary>>,
+    /// Parameters that can be changed while the router is running
+    limits: Arc<RwLock<ValidationLimits>>,
+    /// Defaults and ceilings of `max_new_tokens` per model
+    max_new_tokens_limits: Option<Arc<MaxNewTokensLimits>>,
+    /// Templates that the requests can render into their inputs
+    prompt_templates: Option<Arc<PromptTemplates>>,
+    /// Limits of the images of the requests, sent to the tokenization workers
+    image_limits: ImageLimits,
+    /// Queue of the tokenization workers
+    sender: mpsc::Sender<QueuedTokenizerRequest>,
 }
 
 impl Validation {
@@ -52,51 +123,187 @@ impl Validation {
         max_total_tokens: usize,
         disable_grammar_support: bool,
     ) -> Self {
-        let workers = if let Tokenizer::Python { .. } = &tokenizer {
-            1
-        } else {
-            workers
-        };
-        // If we have a fast tokenizer
-        let sender = {
-            // Create round robin channel
-            let (validation_sender, validation_round_robin_receiver) = mpsc::unbounded_channel();
-            let mut senders = Vec::with_capacity(workers);
-
-            // Create workers
-            for _ in 0..workers {
-                let tokenizer_clone = tokenizer.clone();
-                let config_clone = config.clone();
-                let preprocessor_config_clone = preprocessor_config.clone();
-                let (tokenizer_sender, tokenizer_receiver) = mpsc::unbounded_channel();
-                senders.push(tokenizer_sender);
-
-                // Spawn worker
-                tokio::task::spawn_blocking(move || {
-                    tokenizer_worker(
-                        tokenizer_clone,
-                        config_clone,
-                        preprocessor_config_clone,
-                        tokenizer_receiver,
-                    )
-                });
-            }
-
-            // Create tokenization round robin task
-            tokio::spawn(round_robin_task(validation_round_robin_receiver, senders));
+        let grammar_vocabulary = GrammarVocabulary::new(&tokenizer, disable_grammar_support);
+        let (sender, vocab_size) =
+            spawn_tokenizer_workers(workers, tokenizer, config, preprocessor_config);
 
-            validation_sender
+        let limits = ValidationLimits {
+            default_temperature: 1.0,
+            max_new_tokens: None,
+            max_stop_sequences,
         };
 
         Self {
+            workers,
             max_best_of,
+            max_n: 1,
             sender,
-            max_stop_sequences,
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            vocab_size,
+            grammar_vocabulary,
+            limits: Arc::new(RwLock::new(limits)),
+            max_new_tokens_limits: None,
+            prompt_templates: None,
+            image_limits: ImageLimits::default(),
+        }
+    }
+
+    /// Validation of the requests of another revision of the model, tokenized with its own
+    /// tokenizer and bounded by the limits of its shards. The other settings are kept, and the
+    /// workers of the previous tokenizer stop once its last validation is dropped.
+    pub(crate) fn with_tokenizer(
+        &self,
+        tokenizer: Tokenizer,
+        config: Option<Config>,
+        preprocessor_config: Option<HubPreprocessorConfig>,
+        max_input_length: usize,
+        max_total_tokens: usize,
+    ) -> Self {
+        let grammar_vocabulary = GrammarVocabulary::new(&tokenizer, self.disable_grammar_support);
+        let (sender, vocab_size) =
+            spawn_tokenizer_workers(self.workers, tokenizer, config, preprocessor_config);
+        Self {
+            sender,
+            vocab_size,
+            grammar_vocabulary,
+            ..self.with_token_limits(max_input_length, max_total_tokens)
+        }
+    }
+
+    /// Validation of the requests bounded by the limits of new shards
+    pub(crate) fn with_token_limits(
+        &self,
+        max_input_length: usize,
+        max_total_tokens: usize,
+    ) -> Self {
+        Self {
+            max_input_length,
+            max_total_tokens,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn max_input_length(&self) -> usize {
+        self.max_input_length
+    }
+
+    /// Reject the regexes that cannot be turned into an index of the tokens of the model before
+    /// they reach the shards, e.g. with lookarounds or backreferences, or that no sequence of
+    /// tokens can match
+    async fn check_regex(&self, regex: &str) -> Result<(), ValidationError> {
+        let Some(grammar_vocabulary) = self.grammar_vocabulary.clone() else {
+            return Regex::new(regex)
+                .map(|_| ())
+                .map_err(|e| ValidationError::InvalidGrammar(e.to_string()));
+        };
+        let regex = regex.to_string();
+        // Building the index walks the whole vocabulary
+        tokio::task::spawn_blocking(move || {
+            Index::new(&regex, grammar_vocabulary.get())
+                .map(|_| ())
+                .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))
+        })
+        .await
+        .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?
+    }
+
+    pub(crate) fn max_total_tokens(&self) -> usize {
+        self.max_total_tokens
+    }
+
+    /// Let the OpenAI requests generate up to `max_n` choices for their prompt
+    pub(crate) fn with_max_n(mut self, max_n: usize) -> Self {
+        self.max_n = max_n;
+        self
+    }
+
+    /// Limit the number and the size of the images of the requests
+    pub(crate) fn with_image_limits(mut self, image_limits: ImageLimits) -> Self {
+        self.image_limits = image_limits;
+        self
+    }
+
+    /// Let the requests name one of `prompt_templates` instead of sending their inputs
+    pub(crate) fn with_prompt_templates(
+        mut self,
+        prompt_templates: Option<PromptTemplates>,
+    ) -> Self {
+        self.prompt_templates = prompt_templates.map(Arc::new);
+        self
+    }
+
+    /// Render the prompt template of `request` into its inputs, returning the template used
+    pub(crate) fn render_prompt_template(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<Option<PromptTemplateVersion>, ValidationError> {
+        let Some(name) = request.template.as_deref() else {
+            if request.variables.is_some() {
+                return Err(ValidationError::PromptTemplateVariables(
+                    "`template` is not set".to_string(),
+                ));
+            }
+            return Ok(None);
+        };
+        let prompt_templates = self
+            .prompt_templates
+            .as_deref()
+            .ok_or(ValidationError::PromptTemplatesDisabled)?;
+        if !request.inputs.is_empty() {
+            return Err(ValidationError::PromptTemplateInputs);
         }
+        let variables = request.variables.take().unwrap_or_default();
+        let (inputs, version) = prompt_templates.render(name, &variables)?;
+        request.inputs = inputs;
+        Ok(Some(version))
+    }
+
+    /// Apply the `max_new_tokens` limits of the models to the requests
+    pub(crate) fn with_max_new_tokens_limits(
+        mut self,
+        max_new_tokens_limits: Option<MaxNewTokensLimits>,
+    ) -> Self {
+        self.max_new_tokens_limits = max_new_tokens_limits.map(Arc::new);
+        self
+    }
+
+    /// `max_new_tokens` limits of the model of a request
+    fn max_new_tokens(&self, parameters: &GenerateParameters) -> MaxNewTokens {
+        self.max_new_tokens_limits
+            .as_ref()
+            .map(|limits| limits.model(parameters.adapter_id.as_deref()))
+            .unwrap_or_default()
+    }
+
+    /// Warning sent back to a request whose `max_new_tokens` is above the ceiling of its model,
+    /// and is clamped by the validation
+    pub(crate) fn max_new_tokens_warning(&self, parameters: &GenerateParameters) -> Option<String> {
+        let max_new_tokens = parameters.max_new_tokens?;
+        let ceiling = self.max_new_tokens(parameters).ceiling(parameters.stream)?;
+        (max_new_tokens > ceiling).then(|| {
+            let requests = if parameters.stream {
+                " for streamed requests"
+            } else {
+                ""
+            };
+            format!(
+                "`max_new_tokens` was lowered from {max_new_tokens} to {ceiling}, the maximum of \
+                 the model{requests}"
+            )
+        })
+    }
+
+    /// Current parameters of the validation that can be changed at runtime
+    pub(crate) fn limits(&self) -> ValidationLimits {
+        self.limits.read().unwrap().clone()
+    }
+
+    /// Replace the parameters of the validation, for the requests validated from now on
+    pub(crate) fn set_limits(&self, limits: ValidationLimits) {
+        *self.limits.write().unwrap() = limits;
     }
 
     #[instrument(skip(self, inputs))]
@@ -104,21 +311,16 @@ impl Validation {
         &self,
         inputs: String,
         add_special_tokens: bool,
-        truncate: Option<usize>,
+        truncate: Option<(usize, TruncationSide)>,
     ) -> Result<(tokenizers::Encoding, Vec<Chunk>), ValidationError> {
-        // If we have a fast tokenizer
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
-        // Send request to the background validation task
-        // Unwrap is safe here
-        let _ = &self
-            .sender
-            .send((
-                (inputs, add_special_tokens, truncate),
-                response_sender,
-                Span::current(),
-            ))
-            .unwrap();
+        self.queue(TokenizerRequest::Encode(
+            (inputs, add_special_tokens, truncate, self.image_limits),
+            response_sender,
+            Span::current(),
+        ))
+        .await;
 
         // Await on response channel
         // Unwrap is safe here
@@ -126,21 +328,60 @@ impl Validation {
         Ok(encoding)
     }
 
+    /// Send `request` to the tokenization workers, once their queue has room for it
+    async fn queue(&self, request: TokenizerRequest) {
+        let request = QueuedTokenizerRequest {
+            request,
+            queued: Instant::now(),
+        };
+        // Unwrap is safe here: the workers live as long as the router
+        self.sender.send(request).await.unwrap();
+        metrics::gauge!("tgi_tokenizer_queue_size").increment(1.0);
+    }
+
+    #[instrument(skip(self, ids))]
+    pub async fn detokenize(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<String, ValidationError> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.queue(TokenizerRequest::Decode(
+            (ids, skip_special_tokens),
+            response_sender,
+            Span::current(),
+        ))
+        .await;
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
     #[allow(clippy::type_complexity)]
     #[instrument(skip(self, inputs))]
     async fn validate_input(
         &self,
         inputs: String,
         add_special_tokens: bool,
-        truncate: Option<usize>,
+        truncate: Option<(usize, TruncationSide)>,
         max_new_tokens: Option<u32>,
-    ) -> Result<(Vec<Chunk>, Option<Vec<u32>>, usize, u32, u32), ValidationError> {
+        token_healing: bool,
+    ) -> Result<
+        (
+            Vec<Chunk>,
+            Option<Vec<u32>>,
+            usize,
+            u32,
+            u32,
+            Option<(u32, String)>,
+        ),
+        ValidationError,
+    > {
         // If we have a fast tokenizer
-        let (encoding, inputs) = self
+        let (encoding, mut inputs) = self
             .tokenize(inputs.clone(), add_special_tokens, truncate)
             .await?;
         // Create response channel
-        let input_length = if let Some(truncate) = truncate {
+        let mut input_length = if let Some((truncate, _)) = truncate {
             std::cmp::min(encoding.len(), truncate)
         } else {
             encoding.len()
@@ -179,7 +420,18 @@ impl Validation {
         }
 
         let ids = encoding.get_ids();
-        let input_ids = ids[ids.len().saturating_sub(input_length)..].to_owned();
+        let mut input_ids = ids[ids.len().saturating_sub(input_length)..].to_owned();
+
+        // The backed up token is generated again, so it must not be the only one left
+        let healing = if token_healing && input_length > 1 {
+            back_up_last_token(&mut inputs, &encoding)?
+        } else {
+            None
+        };
+        if healing.is_some() {
+            input_ids.pop();
+            input_length -= 1;
+        }
 
         metrics::histogram!("tgi_request_input_length").record(input_length as f64);
         Ok((
@@ -188,35 +440,77 @@ impl Validation {
             input_length,
             max_new_tokens,
             max_total_new_tokens,
+            healing,
         ))
     }
 
+    /// Tokenize the input of an embedding request
+    #[instrument(skip_all)]
+    pub(crate) async fn validate_embedding_input(
+        &self,
+        input: String,
+    ) -> Result<Vec<u32>, ValidationError> {
+        if input.is_empty() {
+            return Err(EmptyInput);
+        }
+        let (encoding, _) = self.tokenize(input, true, None).await?;
+        if encoding.len() > self.max_input_length {
+            return Err(ValidationError::InputLength(
+                self.max_input_length,
+                encoding.len(),
+            ));
+        }
+        metrics::histogram!("tgi_request_input_length").record(encoding.len() as f64);
+        Ok(encoding.get_ids().to_vec())
+    }
+
     /// Validate a payload and get the number of tokens in the input
     #[instrument(skip_all)]
     pub(crate) async fn validate(
         &self,
         request: GenerateRequest,
     ) -> Result<ValidGenerateRequest, ValidationError> {
+        let model_max_new_tokens = self.max_new_tokens(&request.parameters);
         let GenerateParameters {
             best_of,
             temperature,
             repetition_penalty,
             frequency_penalty,
+            presence_penalty,
             top_k,
             top_p,
             typical_p,
+            min_p,
+            no_repeat_ngram_size,
+            guidance_scale,
+            negative_prompt,
             do_sample,
             max_new_tokens,
+            min_new_tokens,
             stop: stop_sequences,
+            stop_token_ids,
             truncate,
+            truncation_side,
             seed,
             watermark,
             decoder_input_details,
             top_n_tokens,
             grammar,
             adapter_id,
+            adapters,
+            priority,
+            speculate,
+            session_id,
+            timeout_ms,
+            deadline_ms,
+            token_healing,
+            logit_bias,
+            metadata,
+            stream,
             ..
         } = request.parameters;
+        // The same limits apply to the whole request, even if they are changed meanwhile
+        let limits = self.limits();
 
         // sampling must be true when best_of > 1
         let best_of = best_of.unwrap_or(1);
@@ -224,13 +518,14 @@ impl Validation {
             || temperature.is_some()
             || top_k.is_some()
             || top_p.is_some()
-            || typical_p.is_some();
+            || typical_p.is_some()
+            || min_p.is_some();
 
         if best_of > 1 && !sampling {
             return Err(BestOfSampling);
         }
 
-        let temperature = temperature.unwrap_or(1.0);
+        let temperature = temperature.unwrap_or(limits.default_temperature);
         if temperature <= 0.0 {
             return Err(ValidationError::Temperature);
         }
@@ -245,6 +540,11 @@ impl Validation {
             return Err(ValidationError::FrequencyPenalty);
         }
 
+        let presence_penalty = presence_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&presence_penalty) {
+            return Err(ValidationError::PresencePenalty);
+        }
+
         // Different because the proto default value is not a valid value
         // for the user
         let top_p = top_p
@@ -265,6 +565,24 @@ impl Validation {
             })
             .unwrap_or(Ok(1.0))?;
 
+        let min_p = min_p.unwrap_or(0.0);
+        if !(0.0..=1.0).contains(&min_p) {
+            return Err(ValidationError::MinP);
+        }
+
+        // The shards disable the n-gram ban with 0
+        if no_repeat_ngram_size == Some(0) {
+            return Err(ValidationError::NoRepeatNgramSize);
+        }
+        let no_repeat_ngram_size = no_repeat_ngram_size.unwrap_or(0);
+
+        if guidance_scale.is_some_and(|scale| !scale.is_finite() || scale <= 0.0) {
+            return Err(ValidationError::GuidanceScale);
+        }
+        if guidance_scale.is_none() && negative_prompt.is_some() {
+            return Err(ValidationError::NegativePromptGuidance);
+        }
+
         let top_k: u32 = top_k
             .map(|value| {
                 if value <= 0 {
@@ -277,13 +595,93 @@ impl Validation {
         if max_new_tokens == Some(0) {
             return Err(ValidationError::NegativeMaxNewTokens);
         }
+        // Clamped to the ceiling of the model, with a warning in the response
+        let model_ceiling = model_max_new_tokens.ceiling(stream);
+        let max_new_tokens = match (max_new_tokens, model_ceiling) {
+            (Some(max_new_tokens), Some(ceiling)) => Some(max_new_tokens.min(ceiling)),
+            (max_new_tokens, _) => max_new_tokens,
+        };
+        let default_max_new_tokens = model_max_new_tokens
+            .default
+            .filter(|_| max_new_tokens.is_none());
+        if let (Some(max_new_tokens), Some(ceiling)) = (max_new_tokens, limits.max_new_tokens) {
+            if max_new_tokens > ceiling {
+                return Err(ValidationError::MaxNewTokens(
+                    ceiling as usize,
+                    max_new_tokens,
+                ));
+            }
+        }
+
+        if timeout_ms == Some(0) {
+            return Err(ValidationError::TimeoutMs);
+        }
+        if deadline_ms == Some(0) {
+            return Err(ValidationError::DeadlineMs);
+        }
+
+        let logit_bias = logit_bias.unwrap_or_default();
+        if logit_bias.len() > MAX_LOGIT_BIAS_TOKENS {
+            return Err(ValidationError::LogitBiasTokens(
+                MAX_LOGIT_BIAS_TOKENS,
+                logit_bias.len(),
+            ));
+        }
+        if let Some(vocab_size) = self.vocab_size {
+            if let Some(&token_id) = logit_bias.keys().find(|&&id| id as usize >= vocab_size) {
+                return Err(ValidationError::LogitBiasTokenId(vocab_size, token_id));
+            }
+        }
+        if logit_bias
+            .values()
+            .any(|bias| !(-100.0..=100.0).contains(bias))
+        {
+            return Err(ValidationError::LogitBias);
+        }
+
+        if let Some(metadata) = metadata {
+            let size = serde_json::to_vec(&metadata).map_or(0, |metadata| metadata.len());
+            if size > MAX_METADATA_BYTES {
+                return Err(ValidationError::Metadata(MAX_METADATA_BYTES, size));
+            }
+        }
+
+        let adapters = match adapters {
+            Some(_) if adapter_id.is_some() => return Err(ValidationError::AdaptersAdapterId),
+            Some(adapters) => {
+                if adapters.is_empty() || adapters.len() > MAX_ADAPTERS {
+                    return Err(ValidationError::AdaptersCount(MAX_ADAPTERS, adapters.len()));
+                }
+                for (i, adapter) in adapters.iter().enumerate() {
+                    if !adapter.weight.is_finite() || adapter.weight == 0.0 {
+                        return Err(ValidationError::AdapterWeight(adapter.id.clone()));
+                    }
+                    if adapters[..i].iter().any(|other| other.id == adapter.id) {
+                        return Err(ValidationError::DuplicateAdapter(adapter.id.clone()));
+                    }
+                }
+                adapters
+            }
+            None => Vec::new(),
+        };
 
-        if stop_sequences.len() > self.max_stop_sequences {
+        if stop_sequences.len() > limits.max_stop_sequences {
             return Err(ValidationError::StopSequence(
-                self.max_stop_sequences,
+                limits.max_stop_sequences,
                 stop_sequences.len(),
             ));
         }
+        if stop_token_ids.len() > limits.max_stop_sequences {
+            return Err(ValidationError::StopTokenIds(
+                limits.max_stop_sequences,
+                stop_token_ids.len(),
+            ));
+        }
+        if let Some(vocab_size) = self.vocab_size {
+            if let Some(&token_id) = stop_token_ids.iter().find(|&&id| id as usize >= vocab_size) {
+                return Err(ValidationError::StopTokenId(vocab_size, token_id));
+            }
+        }
 
         // If seed is None, assign a random one
         let seed = match seed {
@@ -311,24 +709,78 @@ impl Validation {
         }
 
         // Check if truncate is strictly positive and less than max_input_length
-        let truncate = truncate
-            .map(|value| {
+        let truncate = match truncate {
+            Some(value) => {
                 if value == 0 || value > self.max_input_length {
                     return Err(ValidationError::Truncate(self.max_input_length, value));
                 }
-                Ok(Some(value))
-            })
-            .unwrap_or(Ok(None))?;
+                Some(value)
+            }
+            // Truncate to whatever the limits leave to the prompt
+            None if truncation_side.is_some() => {
+                let available = max_new_tokens.map_or(self.max_input_length, |max_new_tokens| {
+                    self.max_total_tokens
+                        .saturating_sub(max_new_tokens as usize)
+                });
+                Some(min(self.max_input_length, available).max(1))
+            }
+            None => None,
+        };
 
         // Validate inputs
-        let (inputs, input_ids, input_length, max_new_tokens, max_total_new_tokens) = self
+        let (
+            inputs,
+            input_ids,
+            input_length,
+            mut max_new_tokens,
+            mut max_total_new_tokens,
+            healing,
+        ) = self
             .validate_input(
                 request.inputs,
                 request.add_special_tokens,
-                truncate,
+                truncate.map(|truncate| (truncate, truncation_side.unwrap_or_default())),
                 max_new_tokens,
+                token_healing,
             )
             .await?;
+        let (healing_token_id, healed_prefix) = healing.unzip();
+        // Requests without `max_new_tokens` generate the default of the model, and are not
+        // re-queued past it
+        if let Some(default) = default_max_new_tokens {
+            max_new_tokens = max_total_new_tokens.min(default);
+            max_total_new_tokens = max_new_tokens;
+        }
+        // Requests without `max_new_tokens` generate up to the ceilings
+        for ceiling in [limits.max_new_tokens, model_ceiling].into_iter().flatten() {
+            max_new_tokens = max_new_tokens.min(ceiling);
+            max_total_new_tokens = max_total_new_tokens.min(ceiling);
+        }
+        // The rounds of a re-queued request generate at least the remaining tokens
+        let min_new_tokens = min_new_tokens.unwrap_or(0);
+        if min_new_tokens > max_total_new_tokens {
+            return Err(ValidationError::MinNewTokens(
+                max_total_new_tokens,
+                min_new_tokens,
+            ));
+        }
+        // The grammar can leave the end of sequence token as the only allowed one
+        if min_new_tokens > 0 && grammar.is_some() {
+            return Err(ValidationError::MinNewTokensGrammar);
+        }
+
+        let guidance = match guidance_scale {
+            Some(scale) => Some(
+                self.validate_negative_prompt(
+                    scale,
+                    negative_prompt.unwrap_or_default(),
+                    request.add_special_tokens,
+                    max_new_tokens,
+                )
+                .await?,
+            ),
+            None => None,
+        };
 
         // TODO: we should build the FSM here and pass the compiled FSM instead of the grammar
         // NOTE: this is currently difficult because we need the tokenizer in Python to build
@@ -368,15 +820,18 @@ impl Validation {
                                 "Grammar must have a 'properties' field".to_string(),
                             ))?;
 
-                        // Do compilation in the router for performance. In the future, we
-                        // should also move regex -> automaton compilation in the router,
-                        // but this is not yet supported in pure Rust by outlines-core.
+                        // Do compilation in the router for performance. The shards still
+                        // compile the regex to an automaton of their own.
                         let grammar_regex = json_schema_to_regex(&json, None, &json)
                             .map_err(ValidationError::RegexFromSchema)?;
 
                         ValidGrammar::Regex(grammar_regex.to_string())
                     }
-                    GrammarType::Regex(regex) => ValidGrammar::Regex(regex),
+                    GrammarType::Regex(regex) => {
+                        self.check_regex(&regex).await?;
+                        ValidGrammar::Regex(regex)
+                    }
+                    GrammarType::JsonObject => ValidGrammar::JsonObject,
                 };
                 Some(valid_grammar)
             }
@@ -387,18 +842,27 @@ impl Validation {
             temperature,
             repetition_penalty,
             frequency_penalty,
+            presence_penalty,
             top_k,
             top_p,
             typical_p,
+            min_p,
+            no_repeat_ngram_size,
             do_sample,
             seed,
             watermark,
+            watermark_key: 0,
             grammar,
+            speculate,
+            healing_token_id,
+            logit_bias,
         };
         let stopping_parameters = ValidStoppingParameters {
             max_new_tokens,
             max_total_new_tokens,
+            min_new_tokens,
             stop_sequences,
+            stop_token_ids,
             ignore_eos_token: false,
         };
 
@@ -415,6 +879,50 @@ impl Validation {
             stopping_parameters,
             top_n_tokens,
             adapter_id,
+            adapters,
+            priority: priority.unwrap_or_default(),
+            session_id,
+            generated_tokens: 0,
+            healed_prefix,
+            guidance,
+            tenant: None,
+            request_id: None,
+            deadline: None,
+            prompt_group: None,
+        })
+    }
+
+    /// Tokenize the negative prompt of a request using classifier-free guidance. It is
+    /// generated as long as the request, so it must fit the same limits as its prompt.
+    async fn validate_negative_prompt(
+        &self,
+        scale: f32,
+        negative_prompt: String,
+        add_special_tokens: bool,
+        max_new_tokens: u32,
+    ) -> Result<ValidGuidance, ValidationError> {
+        let (encoding, inputs) = self
+            .tokenize(negative_prompt, add_special_tokens, None)
+            .await?;
+        let input_length = encoding.len();
+        // An empty negative prompt still needs a token to start from
+        if input_length == 0 {
+            return Err(ValidationError::EmptyNegativePrompt);
+        }
+        if input_length > self.max_input_length
+            || input_length + max_new_tokens as usize > self.max_total_tokens
+        {
+            return Err(ValidationError::NegativePromptLength(
+                self.max_input_length,
+                self.max_total_tokens,
+                input_length,
+            ));
+        }
+        Ok(ValidGuidance {
+            scale,
+            inputs,
+            input_ids: Arc::new(encoding.get_ids().to_owned()),
+            input_length: input_length as u32,
         })
     }
 
@@ -431,21 +939,66 @@ impl Validation {
 
         Ok(best_of)
     }
-}
 
-/// Round robin tokenization task
-async fn round_robin_task(
-    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
-    senders: Vec<mpsc::UnboundedSender<TokenizerRequest>>,
-) {
-    loop {
-        for sender in &senders {
-            match receiver.recv().await {
-                None => return,
-                Some(request) => sender.send(request).unwrap(),
-            };
+    /// Validate the number of choices of an OpenAI request
+    pub(crate) fn validate_n(&self, n: usize) -> Result<usize, ValidationError> {
+        if n == 0 || n > self.max_n {
+            return Err(ValidationError::N(self.max_n, n));
         }
+        Ok(n)
+    }
+}
+
+/// Next request of the shared queue, `None` once the router stops
+fn next_request(
+    receiver: &Mutex<mpsc::Receiver<QueuedTokenizerRequest>>,
+) -> Option<TokenizerRequest> {
+    // The idle workers wait for the lock, the one holding it waits for the next request
+    let queued = receiver.lock().unwrap().blocking_recv()?;
+    metrics::gauge!("tgi_tokenizer_queue_size").decrement(1.0);
+    metrics::histogram!("tgi_tokenizer_queue_duration")
+        .record(queued.queued.elapsed().as_secs_f64());
+    Some(queued.request)
+}
+
+/// Start the tokenization workers of `tokenizer`, returning their queue and the size of its
+/// vocabulary
+fn spawn_tokenizer_workers(
+    workers: usize,
+    tokenizer: Tokenizer,
+    config: Option<Config>,
+    preprocessor_config: Option<HubPreprocessorConfig>,
+) -> (mpsc::Sender<QueuedTokenizerRequest>, Option<usize>) {
+    let workers = if let Tokenizer::Python { .. } = &tokenizer {
+        1
+    } else {
+        workers
+    };
+    let vocab_size = match &tokenizer {
+        Tokenizer::Rust(tokenizer) => Some(tokenizer.get_vocab_size(true)),
+        Tokenizer::Python { .. } => None,
+    };
+    // The workers share one queue: a request goes to the first idle worker, instead of
+    // waiting behind the long prompt of a busy one
+    let (sender, receiver) = mpsc::channel(workers * TOKENIZER_QUEUE_SIZE_PER_WORKER);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..workers {
+        let tokenizer_clone = tokenizer.clone();
+        let config_clone = config.clone();
+        let preprocessor_config_clone = preprocessor_config.clone();
+        let receiver = receiver.clone();
+
+        // Spawn worker, with its own tokenizer instance
+        tokio::task::spawn_blocking(move || {
+            tokenizer_worker(
+                tokenizer_clone,
+                config_clone,
+                preprocessor_config_clone,
+                receiver,
+            )
+        });
     }
+    (sender, vocab_size)
 }
 
 /// Start tokenization workers
@@ -453,7 +1006,7 @@ fn tokenizer_worker(
     tokenizer: Tokenizer,
     config: Option<Config>,
     preprocessor_config: Option<HubPreprocessorConfig>,
-    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
+    receiver: Arc<Mutex<mpsc::Receiver<QueuedTokenizerRequest>>>,
 ) {
     match tokenizer {
         Tokenizer::Python {
@@ -465,47 +1018,74 @@ fn tokenizer_worker(
                 let tokenizer =
                     PyTokenizer::from_py(py, tokenizer_name, revision, trust_remote_code)?;
                 // Loop over requests
-                while let Some(((inputs, add_special_tokens, truncate), response_tx, parent_span)) =
-                    receiver.blocking_recv()
-                {
-                    parent_span.in_scope(|| {
-                        response_tx
-                            .send(prepare_input(
-                                inputs,
-                                truncate,
-                                add_special_tokens,
-                                &tokenizer,
-                                config.as_ref(),
-                                preprocessor_config.as_ref(),
-                            ))
-                            .unwrap_or(())
-                    })
+                while let Some(request) = next_request(&receiver) {
+                    handle_request(
+                        request,
+                        &tokenizer,
+                        config.as_ref(),
+                        preprocessor_config.as_ref(),
+                    );
                 }
                 Ok(())
             })
             .expect("Failure in python tokenizer worker");
         }
         Tokenizer::Rust(tokenizer) => {
-            while let Some(((inputs, add_special_tokens, truncate), response_tx, parent_span)) =
-                receiver.blocking_recv()
-            {
-                parent_span.in_scope(|| {
-                    response_tx
-                        .send(prepare_input(
-                            inputs,
-                            truncate,
-                            add_special_tokens,
-                            &tokenizer,
-                            config.as_ref(),
-                            preprocessor_config.as_ref(),
-                        ))
-                        .unwrap_or(())
-                })
+            while let Some(request) = next_request(&receiver) {
+                handle_request(
+                    request,
+                    &tokenizer,
+                    config.as_ref(),
+                    preprocessor_config.as_ref(),
+                );
             }
         }
     }
 }
 
+fn handle_request<T: TokenizerTrait>(
+    request: TokenizerRequest,
+    tokenizer: &T,
+    config: Option<&Config>,
+    preprocessor_config: Option<&HubPreprocessorConfig>,
+) {
+    let start = Instant::now();
+    let operation = match request {
+        TokenizerRequest::Encode(
+            (inputs, add_special_tokens, truncate, image_limits),
+            response_tx,
+            parent_span,
+        ) => parent_span.in_scope(|| {
+            response_tx
+                .send(prepare_input(
+                    inputs,
+                    truncate,
+                    add_special_tokens,
+                    image_limits,
+                    tokenizer,
+                    config,
+                    preprocessor_config,
+                ))
+                .unwrap_or(());
+            "encode"
+        }),
+        TokenizerRequest::Decode((ids, skip_special_tokens), response_tx, parent_span) => {
+            parent_span.in_scope(|| {
+                response_tx
+                    .send(
+                        tokenizer
+                            .decode_trait(ids, skip_special_tokens)
+                            .map_err(|err| ValidationError::Tokenizer(err.to_string())),
+                    )
+                    .unwrap_or(());
+                "decode"
+            })
+        }
+    };
+    metrics::histogram!("tgi_tokenization_duration", "operation" => operation)
+        .record(start.elapsed().as_secs_f64());
+}
+
 fn format_from_mimetype(mimetype: &str) -> Option<ImageFormat> {
     match mimetype {
         "image/png" => Some(ImageFormat::Png),
@@ -536,18 +1116,40 @@ fn format_to_mimetype(format: ImageFormat) -> String {
     .to_string()
 }
 
-fn fetch_image(input: &str) -> Result<(Vec<u8>, String, usize, usize), ValidationError> {
+/// Images are downscaled from at most this many times `--max-image-pixels` pixels, the larger
+/// ones are refused before they are decoded
+const MAX_DOWNSCALE: usize = 16;
+
+/// Decode an image, once its header showed that it can be downscaled to `max_pixels`
+fn decode_image(
+    data: &[u8],
+    format: ImageFormat,
+    max_pixels: Option<usize>,
+) -> Result<DynamicImage, ValidationError> {
+    if let Some(max_pixels) = max_pixels {
+        let (width, height) =
+            ImageReader::with_format(Cursor::new(data), format).into_dimensions()?;
+        let pixels = width as usize * height as usize;
+        let max_decoded_pixels = max_pixels.saturating_mul(MAX_DOWNSCALE);
+        if pixels > max_decoded_pixels {
+            return Err(ValidationError::ImagePixels(max_decoded_pixels, pixels));
+        }
+    }
+    Ok(ImageReader::with_format(Cursor::new(data), format).decode()?)
+}
+
+fn fetch_image(
+    input: &str,
+    max_pixels: Option<usize>,
+) -> Result<(Vec<u8>, String, DynamicImage), ValidationError> {
     if input.starts_with("![](http://") || input.starts_with("![](https://") {
         let url = &input["![](".len()..input.len() - 1];
         let data = reqwest::blocking::get(url)?.bytes()?;
 
         let format = image::guess_format(&data)?;
-        // TODO Remove this clone
-        let img = ImageReader::with_format(Cursor::new(data.clone()), format).decode()?;
-        let height: usize = img.height().try_into()?;
-        let width: usize = img.width().try_into()?;
+        let img = decode_image(&data, format, max_pixels)?;
         let mimetype = format_to_mimetype(format);
-        Ok((data.to_vec(), mimetype, height, width))
+        Ok((data.to_vec(), mimetype, img))
     } else if input.starts_with("![](data:") {
         // Remove ![](....)
         let content = &input["![](data:".len()..input.len() - 1];
@@ -563,23 +1165,53 @@ fn fetch_image(input: &str) -> Result<(Vec<u8>, String, usize, usize), Validatio
         }
 
         let data = STANDARD.decode(content["base64,".len()..].as_bytes())?;
-        let img = if let Some(format) = format_from_mimetype(mimetype) {
-            ImageReader::with_format(Cursor::new(&data), format).decode()?
-        } else {
-            ImageReader::new(Cursor::new(&data))
-                .with_guessed_format()
-                .map_err(|_io_error| ValidationError::InvalidImageContent(content.to_string()))?
-                .decode()?
+        let format = match format_from_mimetype(mimetype) {
+            Some(format) => format,
+            None => image::guess_format(&data)?,
         };
+        let img = decode_image(&data, format, max_pixels)?;
 
-        let height: usize = img.height().try_into()?;
-        let width: usize = img.width().try_into()?;
-        Ok((data, mimetype.to_string(), height, width))
+        Ok((data, mimetype.to_string(), img))
     } else {
         Err(ValidationError::InvalidImageContent(input.to_string()))
     }
 }
 
+/// Downscale an image with more than `max_pixels` pixels to fit in `max_pixels`, keeping its
+/// aspect ratio, so that the shards receive and the token budget counts the smaller image
+fn preprocess_image(
+    data: Vec<u8>,
+    mimetype: String,
+    img: DynamicImage,
+    max_pixels: Option<usize>,
+) -> Result<Image, ValidationError> {
+    let (width, height) = (img.width(), img.height());
+    let pixels = width as usize * height as usize;
+    match max_pixels {
+        Some(max_pixels) if pixels > max_pixels => {
+            let scale = (max_pixels as f64 / pixels as f64).sqrt();
+            let width = ((width as f64 * scale) as u32).max(1);
+            let height = ((height as f64 * scale) as u32).max(1);
+            let img = img.resize_exact(width, height, FilterType::Triangle);
+            // Re-encoded losslessly, whatever the format of the original
+            let mut data = Vec::new();
+            img.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+            Ok(Image {
+                data,
+                mimetype: format_to_mimetype(ImageFormat::Png),
+                width,
+                height,
+            })
+        }
+        _ => Ok(Image {
+            data,
+            mimetype,
+            width,
+            height,
+        }),
+    }
+}
+
 fn image_tokens(
     config: &Config,
     preprocessor_config: Option<&HubPreprocessorConfig>,
@@ -637,8 +1269,9 @@ fn image_tokens_fixup(config: &Config, text: String) -> String {
 /// Get input length and optionally truncate it
 fn prepare_input<T: TokenizerTrait>(
     inputs: String,
-    _truncate: Option<usize>,
+    truncate: Option<(usize, TruncationSide)>,
     add_special_tokens: bool,
+    image_limits: ImageLimits,
     tokenizer: &T,
     config: Option<&Config>,
     preprocessor_config: Option<&HubPreprocessorConfig>,
@@ -649,6 +1282,14 @@ fn prepare_input<T: TokenizerTrait>(
         Some(
             config @ (Idefics | Mllama | Idefics2(_) | Paligemma(_) | LlavaNext(_) | Qwen2Vl(_)),
         ) => {
+            // Counted before any image is fetched
+            let images = RE.find_iter(&inputs).count();
+            if let Some(max_images) = image_limits.max_images {
+                if images > max_images {
+                    return Err(ValidationError::Images(max_images, images));
+                }
+            }
+
             let mut input_chunks = Vec::new();
             let mut tokenizer_query = String::with_capacity(inputs.len());
             let mut start = 0;
@@ -659,8 +1300,12 @@ fn prepare_input<T: TokenizerTrait>(
                     input_chunks.push(Chunk::Text(inputs[start..chunk_start].to_string()));
                     tokenizer_query.push_str(&inputs[start..chunk_start]);
                 }
-                let (data, mimetype, height, width) = fetch_image(&inputs[chunk_start..chunk_end])?;
-                input_chunks.push(Chunk::Image(Image { data, mimetype }));
+                let (data, mimetype, img) =
+                    fetch_image(&inputs[chunk_start..chunk_end], image_limits.max_pixels)?;
+                let image = preprocess_image(data, mimetype, img, image_limits.max_pixels)?;
+                let (height, width): (usize, usize) =
+                    (image.height.try_into()?, image.width.try_into()?);
+                input_chunks.push(Chunk::Image(image));
                 tokenizer_query.push_str(&image_tokens(config, preprocessor_config, height, width));
                 start = chunk_end;
             }
@@ -681,19 +1326,132 @@ fn prepare_input<T: TokenizerTrait>(
         .encode_trait(tokenizer_query, add_special_tokens)
         .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
 
+    // The shards truncate the left of the prompt themselves, the other sides are cut from the
+    // text they receive
+    if let Some((truncate, side @ (TruncationSide::Right | TruncationSide::Middle))) = truncate {
+        if encoding.len() > truncate {
+            let [Chunk::Text(text)] = input_chunks.as_slice() else {
+                return Err(ValidationError::TruncationSide);
+            };
+            let text = truncate_text(text, &encoding, truncate, side)?;
+            let encoding = tokenizer
+                .encode_trait(text.clone(), add_special_tokens)
+                .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+            return Ok((encoding, vec![Chunk::Text(text)]));
+        }
+    }
+
     Ok((encoding, input_chunks))
 }
 
-type TokenizerRequest = (
-    (String, bool, Option<usize>),
-    oneshot::Sender<Result<(tokenizers::Encoding, Vec<Chunk>), ValidationError>>,
-    Span,
-);
+/// Cut the tokens of a text prompt that do not fit in `truncate` from `side`, at token
+/// boundaries. The special tokens the tokenizer adds around the text cover no text and are
+/// added again when the result is tokenized, so they are kept out of the cut.
+fn truncate_text(
+    text: &str,
+    encoding: &tokenizers::Encoding,
+    truncate: usize,
+    side: TruncationSide,
+) -> Result<String, ValidationError> {
+    let offsets = encoding.get_offsets();
+    // The slow tokenizers do not report offsets
+    if offsets.len() != encoding.len() {
+        return Err(ValidationError::TruncationSide);
+    }
+    let offsets: Vec<(usize, usize)> = offsets
+        .iter()
+        .copied()
+        .filter(|(start, end)| start < end)
+        .collect();
+    let kept = truncate
+        .saturating_sub(encoding.len() - offsets.len())
+        .min(offsets.len());
+    let (head, tail) = match side {
+        TruncationSide::Left => (0, kept),
+        TruncationSide::Right => (kept, 0),
+        TruncationSide::Middle => (kept - kept / 2, kept / 2),
+    };
+    let head_end = head.checked_sub(1).map_or(0, |last| offsets[last].1);
+    let tail_start = match tail {
+        0 => text.len(),
+        tail => offsets[offsets.len() - tail].0,
+    };
+    match (text.get(..head_end), text.get(tail_start..)) {
+        (Some(head), Some(tail)) => Ok(format!("{head}{tail}")),
+        _ => Err(ValidationError::TruncationSide),
+    }
+}
+
+/// Remove the last token of a text prompt for token healing. Returns the id of the removed
+/// token and the text it covered, leading whitespace included, or `None` if the prompt has
+/// nothing left before it or ends with a special token.
+fn back_up_last_token(
+    inputs: &mut [Chunk],
+    encoding: &tokenizers::Encoding,
+) -> Result<Option<(u32, String)>, ValidationError> {
+    let [Chunk::Text(text)] = inputs else {
+        return Err(ValidationError::TokenHealing);
+    };
+    let (ids, offsets) = (encoding.get_ids(), encoding.get_offsets());
+    // The slow tokenizers do not report offsets
+    if offsets.len() != ids.len() {
+        return Err(ValidationError::TokenHealing);
+    }
+    if ids.len() < 2 || encoding.get_special_tokens_mask().last() == Some(&1) {
+        return Ok(None);
+    }
+    // Offsets may exclude the whitespace a token starts with, cut after the previous token
+    let (_, kept_end) = offsets[offsets.len() - 2];
+    if kept_end == 0 || !text.is_char_boundary(kept_end) {
+        return Ok(None);
+    }
+    let prefix = text.split_off(kept_end);
+    Ok(Some((ids[ids.len() - 1], prefix)))
+}
+
+/// Whether the tokenizer cannot be the one of a model with `model_vocab_size` embeddings: the
+/// prompts would have token ids the model does not have, or the model would generate token
+/// ids the tokenizer decodes as nothing
+pub(crate) fn vocab_size_mismatch(tokenizer_vocab_size: usize, model_vocab_size: usize) -> bool {
+    tokenizer_vocab_size > model_vocab_size
+        || model_vocab_size > tokenizer_vocab_size.next_multiple_of(MAX_VOCAB_PADDING)
+}
+
+/// Request of the queue of the tokenization workers
+struct QueuedTokenizerRequest {
+    request: TokenizerRequest,
+    queued: Instant,
+}
+
+enum TokenizerRequest {
+    Encode(
+        (String, bool, Option<(usize, TruncationSide)>, ImageLimits),
+        oneshot::Sender<Result<(tokenizers::Encoding, Vec<Chunk>), ValidationError>>,
+        Span,
+    ),
+    Decode(
+        (Vec<u32>, bool),
+        oneshot::Sender<Result<String, ValidationError>>,
+        Span,
+    ),
+}
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Image {
     pub data: Vec<u8>,
     pub mimetype: String,
+    /// Size in pixels of `data`, once downscaled to `--max-image-pixels`
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Limits of the images of the requests to the vision-language models
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ImageLimits {
+    /// Maximum number of images of a request
+    pub max_images: Option<usize>,
+    /// Images with more pixels are downscaled to this number of pixels
+    pub max_pixels: Option<usize>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -714,7 +1472,7 @@ impl ChunksToString for Vec<Chunk> {
         let mut output = String::new();
         self.iter().for_each(|c| match &c {
             Chunk::Text(text) => output.push_str(text),
-            Chunk::Image(Image { data, mimetype }) => {
+            Chunk::Image(Image { data, mimetype, .. }) => {
                 let encoded = STANDARD.encode(data);
                 output.push_str(&format!("![](data:{};base64,{})", mimetype, encoded))
             }
@@ -727,6 +1485,8 @@ impl ChunksToString for Vec<Chunk> {
 pub enum ValidGrammar {
     Json(String),
     Regex(String),
+    /// Any JSON object, enforced by the shards without a grammar
+    JsonObject,
 }
 
 #[derive(Debug, Clone)]
@@ -739,6 +1499,10 @@ pub struct ValidParameters {
     pub top_p: f32,
     /// / restricting to top tokens summing to prob_cut_off <= prob_cut_off
     pub typical_p: f32,
+    /// / restricting to tokens with a probability of at least min_p times the most likely one
+    pub min_p: f32,
+    /// / size of the n-grams that cannot occur twice in the text, disabled if 0
+    pub no_repeat_ngram_size: u32,
     /// / apply sampling on the logits
     pub do_sample: bool,
     /// / random seed for sampling
@@ -747,10 +1511,31 @@ pub struct ValidParameters {
     pub repetition_penalty: f32,
     /// / frequency penalty
     pub frequency_penalty: f32,
+    /// / presence penalty
+    pub presence_penalty: f32,
     /// / token watermarking using "A Watermark for Large Language Models"
     pub watermark: bool,
+    /// / key of the watermark, the default key of the shards if 0
+    pub watermark_key: u64,
     /// / grammar (applied if not empty)
     pub grammar: Option<ValidGrammar>,
+    /// / maximum number of accepted speculative tokens (model default if None)
+    pub speculate: Option<u32>,
+    /// / last prompt token backed up by token healing
+    pub healing_token_id: Option<u32>,
+    /// / bias added to the logits of some token ids
+    pub logit_bias: HashMap<u32, f32>,
+}
+
+/// Validation parameters that can be changed while the router is running
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ValidationLimits {
+    /// Temperature of the requests that do not set one
+    pub default_temperature: f32,
+    /// Maximum `max_new_tokens` of a request
+    pub max_new_tokens: Option<u32>,
+    /// Maximum number of stop sequences of a request
+    pub max_stop_sequences: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -759,8 +1544,13 @@ pub struct ValidStoppingParameters {
     pub max_new_tokens: u32,
     /// Maximum number of generated tokens before being re-queued by the system
     pub max_total_new_tokens: u32,
+    /// Tokens generated before the end of sequence token and the stop sequences and tokens
+    /// can end the generation
+    pub min_new_tokens: u32,
     /// / Optional stopping sequences
     pub stop_sequences: Vec<String>,
+    /// Tokens ending the generation when they are generated
+    pub stop_token_ids: Vec<u32>,
     /// / Ignore end of sequence token
     /// / used for benchmarking
     pub ignore_eos_token: bool,
@@ -778,24 +1568,102 @@ pub struct ValidGenerateRequest {
     pub stopping_parameters: ValidStoppingParameters,
     pub top_n_tokens: u32,
     pub adapter_id: Option<String>,
+    /// Weighted LoRA adapters that the shards mix, exclusive with `adapter_id`
+    pub adapters: Vec<AdapterWeight>,
+    pub priority: Priority,
+    pub session_id: Option<String>,
+    /// Tokens generated by the previous rounds of the request, when it is continued after
+    /// reaching `max_new_tokens`
+    pub generated_tokens: u32,
+    /// End of the prompt backed up by token healing, that the first generated token completes
+    pub healed_prefix: Option<String>,
+    /// Tenant of the client, that the backends may share their capacity between
+    pub tenant: Option<String>,
+    /// `x-request-id` of the request, to find it in the queues of the backends
+    pub request_id: Option<String>,
+    /// Instant by which the request must leave the queue of the backend, from `deadline_ms`
+    pub deadline: Option<tokio::time::Instant>,
+    /// Negative sequence generated next to the request, if it uses classifier-free guidance
+    pub guidance: Option<ValidGuidance>,
+    /// Requests generated for the same prompt, that share the KV of its prefill
+    pub prompt_group: Option<PromptGroup>,
 }
 
-#[derive(Error, Debug)]
-pub enum ValidationError {
-    #[error("`best_of` must be > 0 and <= {0}. Given: {1}")]
-    BestOf(usize, usize),
-    #[error("`best_of` != 1 is not allowed for this endpoint")]
-    BestOfDisabled,
-    #[error("you must use sampling when `best_of` is > 1")]
-    BestOfSampling,
-    #[error("`seed` must not be set when `best_of` > 1")]
-    BestOfSeed,
-    #[error("`best_of` != 1 is not supported when streaming tokens")]
-    BestOfStream,
-    #[error("`top_n_tokens` must be >= 0 and <= {0}. Given: {1}")]
-    TopNTokens(u32, u32),
+/// Role of a request among the requests generating the sequences of one prompt. The backends
+/// sharing the KV of the prompts batch the requests of a group together: the leader allocates
+/// the blocks of the prompt, and the followers are forked from its allocation, so that the
+/// prompt is prefilled once.
+#[derive(Debug, Clone)]
+pub struct PromptGroup {
+    /// Shared by the requests of the group
+    prompt: Arc<()>,
+    leader: bool,
+}
+
+impl PromptGroup {
+    /// Roles of the `n` requests of a prompt, the leader first
+    pub fn new(n: usize) -> Vec<Self> {
+        let prompt = Arc::new(());
+        (0..n)
+            .map(|i| Self {
+                prompt: prompt.clone(),
+                leader: i == 0,
+            })
+            .collect()
+    }
+
+    /// Whether the request allocates the blocks of the prompt
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+
+    /// Whether `other` generates a sequence of the same prompt
+    pub fn same_prompt(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.prompt, &other.prompt)
+    }
+}
+
+impl PartialEq for PromptGroup {
+    /// Same role in the same group
+    fn eq(&self, other: &Self) -> bool {
+        self.same_prompt(other) && self.leader == other.leader
+    }
+}
+
+/// Negative prompt of a request using classifier-free guidance, whose logits are combined
+/// with the ones of the request at every step
+#[derive(Debug, Clone)]
+pub struct ValidGuidance {
+    pub scale: f32,
+    pub inputs: Vec<Chunk>,
+    pub input_ids: Arc<Vec<u32>>,
+    pub input_length: u32,
+}
+
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    #[error("`best_of` must be > 0 and <= {0}. Given: {1}")]
+    BestOf(usize, usize),
+    #[error("`best_of` != 1 is not allowed for this endpoint")]
+    BestOfDisabled,
+    #[error("you must use sampling when `best_of` is > 1")]
+    BestOfSampling,
+    #[error("`seed` must not be set when `best_of` > 1")]
+    BestOfSeed,
+    #[error("`best_of` != 1 is not supported when streaming tokens")]
+    BestOfStream,
+    #[error("`n` must be > 0 and <= {0}. Given: {1}")]
+    N(usize, usize),
+    #[error("`n` > 1 is not supported with the `events` stream mode")]
+    NStreamEvents,
+    #[error("`n` > 1 cannot be combined with `best_of` > 1")]
+    NBestOf,
+    #[error("`top_n_tokens` must be >= 0 and <= {0}. Given: {1}")]
+    TopNTokens(u32, u32),
     #[error("`top_n_tokens` != 0 is not allowed for this endpoint")]
     TopNTokensDisabled,
+    #[error("`speculate` must be <= {0}. Given: {1}")]
+    Speculate(u32, u32),
     #[error("`decoder_input_details` == true is not supported when streaming tokens")]
     PrefillDetailsStream,
     #[error("`temperature` must be strictly positive")]
@@ -804,6 +1672,10 @@ pub enum ValidationError {
     RepetitionPenalty,
     #[error("`frequency_penalty` must be >= -2.0 and <= 2.0")]
     FrequencyPenalty,
+    #[error("`presence_penalty` must be >= -2.0 and <= 2.0")]
+    PresencePenalty,
+    #[error("`presence_penalty` is not supported by this backend")]
+    PresencePenaltyUnsupported,
     #[error("`top_p` must be > 0.0 and < 1.0")]
     TopP,
     #[error("`top_k` must be strictly positive")]
@@ -812,12 +1684,40 @@ pub enum ValidationError {
     Truncate(usize, usize),
     #[error("`typical_p` must be > 0.0 and < 1.0")]
     TypicalP,
+    #[error("`min_p` must be >= 0.0 and <= 1.0")]
+    MinP,
+    #[error("`min_p` is not supported by this backend")]
+    MinPUnsupported,
+    #[error("`no_repeat_ngram_size` must be strictly positive")]
+    NoRepeatNgramSize,
+    #[error("`no_repeat_ngram_size` is not supported by this backend")]
+    NoRepeatNgramSizeUnsupported,
+    #[error("`guidance_scale` must be strictly positive and finite")]
+    GuidanceScale,
+    #[error("`guidance_scale` is not supported by this backend")]
+    GuidanceUnsupported,
+    #[error("`negative_prompt` requires `guidance_scale`")]
+    NegativePromptGuidance,
+    #[error("`negative_prompt` must have at least one token once tokenized")]
+    EmptyNegativePrompt,
+    #[error("`negative_prompt` must have less than {0} tokens, and less than {1} with `max_new_tokens`. Given: {2}")]
+    NegativePromptLength(usize, usize, usize),
+    #[error("`inputs` and `negative_prompt` are prefilled together and must have less than {0} tokens. Given: {1}")]
+    NegativePromptPrefill(u32, u32),
+    #[error("watermarking with the keys of the router is not supported by this backend")]
+    WatermarkKeyUnsupported,
     #[error("one of `max_new_tokens` or `truncate` must be set if a fast tokenizer is not in use")]
     UnsetMaxNewTokens,
     #[error("`max_new_tokens` must be strictly positive")]
     NegativeMaxNewTokens,
     #[error("`max_new_tokens` must be <= {0}. Given: {1}")]
     MaxNewTokens(usize, u32),
+    #[error("`min_new_tokens` must be <= `max_new_tokens` ({0}). Given: {1}")]
+    MinNewTokens(u32, u32),
+    #[error("`min_new_tokens` is not supported by this backend")]
+    MinNewTokensUnsupported,
+    #[error("`min_new_tokens` cannot be combined with a grammar")]
+    MinNewTokensGrammar,
     #[error("`inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
     MaxTotalTokens(usize, usize, u32),
     #[error("`inputs` must have less than {0} tokens. Given: {1}")]
@@ -826,6 +1726,50 @@ pub enum ValidationError {
     EmptyInput,
     #[error("`stop` supports up to {0} stop sequences. Given: {1}")]
     StopSequence(usize, usize),
+    #[error("`stop_token_ids` supports up to {0} tokens. Given: {1}")]
+    StopTokenIds(usize, usize),
+    #[error("`stop_token_ids` must be < {0}. Given: {1}")]
+    StopTokenId(usize, u32),
+    #[error("`stop_token_ids` is not supported by this backend")]
+    StopTokenIdsUnsupported,
+    #[error("`timeout_ms` must be strictly positive")]
+    TimeoutMs,
+    #[error("`deadline_ms` must be strictly positive")]
+    DeadlineMs,
+    #[error("`deadline_ms` is not supported by this backend")]
+    DeadlineMsUnsupported,
+    #[error("`token_healing` requires a fast tokenizer and a text-only input")]
+    TokenHealing,
+    #[error("`token_healing` is not supported by this backend")]
+    TokenHealingUnsupported,
+    #[error(
+        "`truncation_side` `right` and `middle` require a fast tokenizer and a text-only input"
+    )]
+    TruncationSide,
+    #[error("`logit_bias` supports up to {0} tokens. Given: {1}")]
+    LogitBiasTokens(usize, usize),
+    #[error("`logit_bias` token ids must be < {0}. Given: {1}")]
+    LogitBiasTokenId(usize, u32),
+    #[error("`logit_bias` values must be >= -100.0 and <= 100.0")]
+    LogitBias,
+    #[error("`logit_bias` is not supported by this backend")]
+    LogitBiasUnsupported,
+    #[error("`metadata` must be at most {0} bytes once serialized. Given: {1}")]
+    Metadata(usize, usize),
+    #[error("`adapters` cannot be combined with `adapter_id`")]
+    AdaptersAdapterId,
+    #[error("`adapters` must have between 1 and {0} adapters. Given: {1}")]
+    AdaptersCount(usize, usize),
+    #[error("`adapters` lists `{0}` more than once")]
+    DuplicateAdapter(String),
+    #[error("the weight of `{0}` in `adapters` must be finite and non-zero")]
+    AdapterWeight(String),
+    #[error("`{0}` is not a LoRA adapter of the server")]
+    UnknownAdapter(String),
+    #[error("the adapters of `adapters` must have a total rank <= {0}. Given: {1}")]
+    AdaptersRank(u32, u32),
+    #[error("`adapters` is not supported by this backend")]
+    AdaptersUnsupported,
     #[error("tokenizer error {0}")]
     Tokenizer(String),
     #[error("grammar is not supported")]
@@ -846,6 +1790,113 @@ pub enum ValidationError {
     FailedFetchImage(#[from] reqwest::Error),
     #[error("{0} modality is not supported")]
     UnsupportedModality(&'static str),
+    #[error("`inputs` supports up to {0} images. Given: {1}")]
+    Images(usize, usize),
+    #[error("images can be downscaled from up to {0} pixels. Given: {1}")]
+    ImagePixels(usize, usize),
+    #[error("`template` requires the server to be started with `--prompt-templates-path`")]
+    PromptTemplatesDisabled,
+    #[error("`{0}` is not a prompt template of the server")]
+    UnknownPromptTemplate(String),
+    #[error("`inputs` must be empty when `template` is set")]
+    PromptTemplateInputs,
+    #[error("invalid `variables`: {0}")]
+    PromptTemplateVariables(String),
+    #[error("`{0}` is not a finished request that can be continued")]
+    UnknownContinuation(String),
+}
+
+impl ValidationError {
+    /// Request parameter responsible for the error
+    pub(crate) fn param(&self) -> Option<&'static str> {
+        match self {
+            ValidationError::BestOf(..)
+            | ValidationError::BestOfDisabled
+            | ValidationError::BestOfSampling
+            | ValidationError::BestOfStream => Some("best_of"),
+            ValidationError::BestOfSeed => Some("seed"),
+            ValidationError::N(..) | ValidationError::NStreamEvents | ValidationError::NBestOf => {
+                Some("n")
+            }
+            ValidationError::TopNTokens(..) | ValidationError::TopNTokensDisabled => {
+                Some("top_n_tokens")
+            }
+            ValidationError::Speculate(..) => Some("speculate"),
+            ValidationError::PrefillDetailsStream => Some("decoder_input_details"),
+            ValidationError::Temperature => Some("temperature"),
+            ValidationError::RepetitionPenalty => Some("repetition_penalty"),
+            ValidationError::FrequencyPenalty => Some("frequency_penalty"),
+            ValidationError::PresencePenalty | ValidationError::PresencePenaltyUnsupported => {
+                Some("presence_penalty")
+            }
+            ValidationError::TopP => Some("top_p"),
+            ValidationError::TopK => Some("top_k"),
+            ValidationError::Truncate(..) => Some("truncate"),
+            ValidationError::TruncationSide => Some("truncation_side"),
+            ValidationError::TypicalP => Some("typical_p"),
+            ValidationError::MinP | ValidationError::MinPUnsupported => Some("min_p"),
+            ValidationError::NoRepeatNgramSize | ValidationError::NoRepeatNgramSizeUnsupported => {
+                Some("no_repeat_ngram_size")
+            }
+            ValidationError::GuidanceScale | ValidationError::GuidanceUnsupported => {
+                Some("guidance_scale")
+            }
+            ValidationError::NegativePromptGuidance
+            | ValidationError::EmptyNegativePrompt
+            | ValidationError::NegativePromptLength(..)
+            | ValidationError::NegativePromptPrefill(..) => Some("negative_prompt"),
+            ValidationError::WatermarkKeyUnsupported => Some("watermark"),
+            ValidationError::UnsetMaxNewTokens
+            | ValidationError::NegativeMaxNewTokens
+            | ValidationError::MaxNewTokens(..)
+            | ValidationError::MaxTotalTokens(..) => Some("max_new_tokens"),
+            ValidationError::MinNewTokens(..)
+            | ValidationError::MinNewTokensUnsupported
+            | ValidationError::MinNewTokensGrammar => Some("min_new_tokens"),
+            ValidationError::StopSequence(..) => Some("stop"),
+            ValidationError::StopTokenIds(..)
+            | ValidationError::StopTokenId(..)
+            | ValidationError::StopTokenIdsUnsupported => Some("stop_token_ids"),
+            ValidationError::TimeoutMs => Some("timeout_ms"),
+            ValidationError::DeadlineMs | ValidationError::DeadlineMsUnsupported => {
+                Some("deadline_ms")
+            }
+            ValidationError::TokenHealing | ValidationError::TokenHealingUnsupported => {
+                Some("token_healing")
+            }
+            ValidationError::LogitBiasTokens(..)
+            | ValidationError::LogitBiasTokenId(..)
+            | ValidationError::LogitBias
+            | ValidationError::LogitBiasUnsupported => Some("logit_bias"),
+            ValidationError::Metadata(..) => Some("metadata"),
+            ValidationError::AdaptersAdapterId
+            | ValidationError::AdaptersCount(..)
+            | ValidationError::DuplicateAdapter(_)
+            | ValidationError::AdapterWeight(_)
+            | ValidationError::UnknownAdapter(_)
+            | ValidationError::AdaptersRank(..)
+            | ValidationError::AdaptersUnsupported => Some("adapters"),
+            ValidationError::Grammar
+            | ValidationError::InvalidGrammar(_)
+            | ValidationError::RegexFromSchema(_) => Some("grammar"),
+            ValidationError::InputLength(..)
+            | ValidationError::EmptyInput
+            | ValidationError::Tokenizer(_)
+            | ValidationError::InvalidBase64(_)
+            | ValidationError::InvalidImage(_)
+            | ValidationError::InvalidImageContent(_)
+            | ValidationError::FailedFetchImage(_)
+            | ValidationError::UnsupportedModality(_)
+            | ValidationError::Images(..)
+            | ValidationError::ImagePixels(..)
+            | ValidationError::PromptTemplateInputs => Some("inputs"),
+            ValidationError::PromptTemplatesDisabled
+            | ValidationError::UnknownPromptTemplate(_) => Some("template"),
+            ValidationError::PromptTemplateVariables(_) => Some("variables"),
+            ValidationError::UnknownContinuation(_) => Some("continue_from"),
+            ValidationError::InvalidInt(_) => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -881,7 +1932,7 @@ mod tests {
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), true, None, Some(max_new_tokens))
+            .validate_input("Hello".to_string(), true, None, Some(max_new_tokens), false)
             .await
         {
             Err(ValidationError::MaxTotalTokens(6, 1, 10)) => (),
@@ -890,6 +1941,20 @@ mod tests {
         }
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tokenizer_pool() {
+        let validation = Validation::new(2, get_tokenizer(), None, None, 2, 3, 4, 5, 6, true);
+        // More requests than the queue holds, the last ones wait for a slot
+        let requests = 3 * 2 * TOKENIZER_QUEUE_SIZE_PER_WORKER;
+        let encodings = futures::future::join_all(
+            (0..requests).map(|_| validation.tokenize("Hello".to_string(), true, None)),
+        )
+        .await;
+        assert!(encodings.iter().all(|encoding| encoding
+            .as_ref()
+            .is_ok_and(|(encoding, _)| encoding.len() == 1)));
+    }
+
     #[tokio::test]
     async fn test_validation_input_length() {
         let tokenizer = get_tokenizer();
@@ -916,7 +1981,7 @@ mod tests {
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), true, None, Some(max_new_tokens))
+            .validate_input("Hello".to_string(), true, None, Some(max_new_tokens), false)
             .await
         {
             Err(ValidationError::MaxTotalTokens(6, 1, 10)) => (),
@@ -924,6 +1989,34 @@ mod tests {
         }
     }
 
+    fn encoding(tokens: &[(u32, &str, (usize, usize))]) -> tokenizers::Encoding {
+        let tokens = tokens
+            .iter()
+            .map(|&(id, value, offsets)| tokenizers::Token::new(id, value.to_string(), offsets))
+            .collect();
+        tokenizers::Encoding::from_tokens(tokens, 0)
+    }
+
+    #[test]
+    fn test_back_up_last_token() {
+        // Offsets without the leading space of a token, as with `trim_offsets`
+        let encoding = encoding(&[
+            (1, "Hello", (0, 5)),
+            (2, "Ġthe", (6, 9)),
+            (3, "Ġwor", (10, 13)),
+        ]);
+        let mut inputs = vec![Chunk::Text("Hello the wor".to_string())];
+        let healing = back_up_last_token(&mut inputs, &encoding).unwrap();
+        assert_eq!(healing, Some((3, " wor".to_string())));
+        assert_eq!(inputs, vec![Chunk::Text("Hello the".to_string())]);
+
+        // Nothing would be left of the prompt
+        let encoding = encoding(&[(1, "Hello", (0, 5))]);
+        let mut inputs = vec![Chunk::Text("Hello".to_string())];
+        assert_eq!(back_up_last_token(&mut inputs, &encoding).unwrap(), None);
+        assert_eq!(inputs, vec![Chunk::Text("Hello".to_string())]);
+    }
+
     #[tokio::test]
     async fn test_validation_best_of_sampling() {
         let tokenizer = get_tokenizer();
@@ -950,6 +2043,8 @@ mod tests {
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     best_of: Some(2),
@@ -964,6 +2059,38 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_validate_n() {
+        // Not bounded by `max_best_of`
+        let validation =
+            Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true).with_max_n(4);
+        assert_eq!(validation.validate_n(1).unwrap(), 1);
+        assert_eq!(validation.validate_n(4).unwrap(), 4);
+        assert!(matches!(
+            validation.validate_n(0),
+            Err(ValidationError::N(4, 0))
+        ));
+        assert!(matches!(
+            validation.validate_n(5),
+            Err(ValidationError::N(4, 5))
+        ));
+    }
+
+    #[test]
+    fn test_prompt_group() {
+        let groups = PromptGroup::new(3);
+        assert_eq!(groups.len(), 3);
+        assert!(groups[0].is_leader());
+        assert!(!groups[1].is_leader() && !groups[2].is_leader());
+        assert_eq!(groups[1], groups[2]);
+        assert_ne!(groups[0], groups[1]);
+        assert!(groups[0].same_prompt(&groups[2]));
+
+        let other = PromptGroup::new(2);
+        assert!(!groups[0].same_prompt(&other[0]));
+        assert_ne!(groups[1], other[1]);
+    }
+
     #[tokio::test]
     async fn test_validation_top_p() {
         let tokenizer = get_tokenizer();
@@ -990,6 +2117,8 @@ mod tests {
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     top_p: Some(1.0),
@@ -1006,6 +2135,8 @@ mod tests {
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     top_p: Some(0.99),
@@ -1022,6 +2153,8 @@ mod tests {
         let valid_request = validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     top_p: None,
@@ -1035,6 +2168,536 @@ mod tests {
         assert_eq!(valid_request.parameters.top_p, 1.0);
     }
 
+    #[tokio::test]
+    async fn test_validation_decoder_input_details() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
+                add_special_tokens: true,
+                parameters: GenerateParameters {
+                    decoder_input_details: true,
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        // The shards compute the prompt logprobs of the requests that ask for them
+        assert!(valid_request.decoder_input_details);
+    }
+
+    #[tokio::test]
+    async fn test_validation_presence_penalty() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let request = |presence_penalty: Option<f32>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                frequency_penalty: Some(0.5),
+                presence_penalty,
+                ..default_parameters()
+            },
+        };
+
+        let valid = validation.validate(request(Some(1.5))).await.unwrap();
+        assert_eq!(valid.parameters.presence_penalty, 1.5);
+        assert_eq!(valid.parameters.frequency_penalty, 0.5);
+        let valid = validation.validate(request(None)).await.unwrap();
+        assert_eq!(valid.parameters.presence_penalty, 0.0);
+
+        match validation.validate(request(Some(2.5))).await {
+            Err(ValidationError::PresencePenalty) => (),
+            _ => panic!("Unexpected presence_penalty"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_min_p() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let request = |min_p: Option<f32>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                min_p,
+                ..default_parameters()
+            },
+        };
+
+        let valid = validation.validate(request(Some(0.05))).await.unwrap();
+        assert_eq!(valid.parameters.min_p, 0.05);
+        let valid = validation.validate(request(None)).await.unwrap();
+        assert_eq!(valid.parameters.min_p, 0.0);
+
+        match validation.validate(request(Some(1.5))).await {
+            Err(ValidationError::MinP) => (),
+            _ => panic!("Unexpected min_p"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_no_repeat_ngram_size() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let request = |no_repeat_ngram_size: Option<u32>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                no_repeat_ngram_size,
+                ..default_parameters()
+            },
+        };
+
+        let valid = validation.validate(request(Some(3))).await.unwrap();
+        assert_eq!(valid.parameters.no_repeat_ngram_size, 3);
+        let valid = validation.validate(request(None)).await.unwrap();
+        assert_eq!(valid.parameters.no_repeat_ngram_size, 0);
+
+        match validation.validate(request(Some(0))).await {
+            Err(ValidationError::NoRepeatNgramSize) => (),
+            _ => panic!("Unexpected no_repeat_ngram_size"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_guidance() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let request =
+            |guidance_scale: Option<f32>, negative_prompt: Option<&str>| GenerateRequest {
+                inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
+                add_special_tokens: true,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(5),
+                    guidance_scale,
+                    negative_prompt: negative_prompt.map(String::from),
+                    ..default_parameters()
+                },
+            };
+
+        let valid = validation
+            .validate(request(Some(1.5), Some("Goodbye")))
+            .await
+            .unwrap();
+        let guidance = valid.guidance.unwrap();
+        assert_eq!(guidance.scale, 1.5);
+        assert!(guidance.input_length > 0);
+        assert_eq!(guidance.input_ids.len(), guidance.input_length as usize);
+        let valid = validation.validate(request(None, None)).await.unwrap();
+        assert!(valid.guidance.is_none());
+
+        match validation.validate(request(Some(0.0), None)).await {
+            Err(ValidationError::GuidanceScale) => (),
+            _ => panic!("Unexpected guidance_scale"),
+        }
+        match validation.validate(request(None, Some("Goodbye"))).await {
+            Err(ValidationError::NegativePromptGuidance) => (),
+            _ => panic!("Unexpected negative_prompt without guidance_scale"),
+        }
+        let negative_prompt = "Goodbye ".repeat(10);
+        match validation
+            .validate(request(Some(1.5), Some(&negative_prompt)))
+            .await
+        {
+            Err(ValidationError::NegativePromptLength(5, 106, _)) => (),
+            _ => panic!("Unexpected negative_prompt length"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_adapters() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let adapter = |id: &str, weight: f32| AdapterWeight {
+            id: id.to_string(),
+            weight,
+        };
+        let request = |adapters: Vec<AdapterWeight>, adapter_id: Option<&str>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                adapter_id: adapter_id.map(String::from),
+                adapters: Some(adapters),
+                ..default_parameters()
+            },
+        };
+
+        let adapters = vec![adapter("a", 0.7), adapter("b", -0.3)];
+        let valid = validation
+            .validate(request(adapters.clone(), None))
+            .await
+            .unwrap();
+        assert_eq!(valid.adapters, adapters);
+
+        match validation.validate(request(adapters, Some("a"))).await {
+            Err(ValidationError::AdaptersAdapterId) => (),
+            _ => panic!("Unexpected adapters with adapter_id"),
+        }
+        match validation.validate(request(Vec::new(), None)).await {
+            Err(ValidationError::AdaptersCount(MAX_ADAPTERS, 0)) => (),
+            _ => panic!("Unexpected empty adapters"),
+        }
+        let adapters = vec![adapter("a", 0.5), adapter("a", 0.5)];
+        match validation.validate(request(adapters, None)).await {
+            Err(ValidationError::DuplicateAdapter(id)) if id == "a" => (),
+            _ => panic!("Unexpected duplicate adapters"),
+        }
+        let adapters = vec![adapter("a", 0.5), adapter("b", f32::NAN)];
+        match validation.validate(request(adapters, None)).await {
+            Err(ValidationError::AdapterWeight(id)) if id == "b" => (),
+            _ => panic!("Unexpected adapter weight"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_truncation_side() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let request = |truncation_side: Option<TruncationSide>| GenerateRequest {
+            inputs: "one two three four five six seven eight".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                truncation_side,
+                ..default_parameters()
+            },
+        };
+
+        match validation.validate(request(None)).await {
+            Err(ValidationError::InputLength(5, 8)) => (),
+            _ => panic!("Unexpected input length"),
+        }
+
+        // The shards drop the start of the prompt
+        let valid = validation
+            .validate(request(Some(TruncationSide::Left)))
+            .await
+            .unwrap();
+        assert_eq!(valid.input_length, 5);
+        assert_eq!(valid.truncate, 5);
+        assert_eq!(
+            valid.inputs,
+            vec![Chunk::Text(
+                "one two three four five six seven eight".to_string()
+            )]
+        );
+
+        let valid = validation
+            .validate(request(Some(TruncationSide::Right)))
+            .await
+            .unwrap();
+        assert_eq!(valid.input_length, 5);
+        assert_eq!(
+            valid.inputs,
+            vec![Chunk::Text("one two three four five".to_string())]
+        );
+
+        let valid = validation
+            .validate(request(Some(TruncationSide::Middle)))
+            .await
+            .unwrap();
+        assert_eq!(valid.input_length, 5);
+        assert_eq!(
+            valid.inputs,
+            vec![Chunk::Text("one two three seven eight".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_stop_token_ids() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let vocab_size = validation.vocab_size.unwrap() as u32;
+        let request = |stop_token_ids: Vec<u32>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                stop_token_ids,
+                ..default_parameters()
+            },
+        };
+
+        let valid = validation.validate(request(vec![2, 7])).await.unwrap();
+        assert_eq!(valid.stopping_parameters.stop_token_ids, vec![2, 7]);
+
+        match validation.validate(request(vec![vocab_size])).await {
+            Err(ValidationError::StopTokenId(..)) => (),
+            _ => panic!("Unexpected token id"),
+        }
+        match validation.validate(request(vec![1, 2, 3, 4])).await {
+            Err(ValidationError::StopTokenIds(3, 4)) => (),
+            _ => panic!("Unexpected number of stop token ids"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_min_new_tokens() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let request = |max_new_tokens: Option<u32>, min_new_tokens: u32| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens,
+                min_new_tokens: Some(min_new_tokens),
+                ..default_parameters()
+            },
+        };
+
+        let valid = validation.validate(request(Some(5), 5)).await.unwrap();
+        assert_eq!(valid.stopping_parameters.min_new_tokens, 5);
+        match validation.validate(request(Some(5), 6)).await {
+            Err(ValidationError::MinNewTokens(5, 6)) => (),
+            _ => panic!("Unexpected min_new_tokens"),
+        }
+        // Checked against the tokens left in the context when `max_new_tokens` is not set
+        let valid = validation.validate(request(None, 50)).await.unwrap();
+        assert_eq!(valid.stopping_parameters.min_new_tokens, 50);
+        match validation.validate(request(None, 106)).await {
+            Err(ValidationError::MinNewTokens(..)) => (),
+            _ => panic!("Unexpected min_new_tokens"),
+        }
+        let mut with_grammar = request(Some(5), 5);
+        with_grammar.parameters.grammar = Some(GrammarType::Regex("[ab]+".to_string()));
+        match validation.validate(with_grammar).await {
+            Err(ValidationError::MinNewTokensGrammar) => (),
+            _ => panic!("Unexpected min_new_tokens with a grammar"),
+        }
+    }
+
+    #[test]
+    fn test_vocab_size_mismatch() {
+        assert!(!vocab_size_mismatch(32000, 32000));
+        // Padded embeddings
+        assert!(!vocab_size_mismatch(50257, 50304));
+        assert!(!vocab_size_mismatch(151665, 152064));
+        // Tokens added to the tokenizer but not to the model
+        assert!(vocab_size_mismatch(32002, 32000));
+        // Tokenizer of another model
+        assert!(vocab_size_mismatch(32000, 128256));
+    }
+
+    #[tokio::test]
+    async fn test_validation_max_new_tokens_limits() {
+        let limits = MaxNewTokensLimits::new(Some(20), Some(50), Some(30), None).unwrap();
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true)
+            .with_max_new_tokens_limits(limits);
+        let request = |max_new_tokens: Option<u32>, stream: bool| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens,
+                stream,
+                ..default_parameters()
+            },
+        };
+
+        let valid = validation.validate(request(None, false)).await.unwrap();
+        assert_eq!(valid.stopping_parameters.max_new_tokens, 20);
+        assert_eq!(valid.stopping_parameters.max_total_new_tokens, 20);
+
+        let clamped = request(Some(80), false);
+        assert!(validation
+            .max_new_tokens_warning(&clamped.parameters)
+            .is_some());
+        let valid = validation.validate(clamped).await.unwrap();
+        assert_eq!(valid.stopping_parameters.max_new_tokens, 50);
+
+        let valid = validation.validate(request(Some(80), true)).await.unwrap();
+        assert_eq!(valid.stopping_parameters.max_new_tokens, 30);
+
+        let below = request(Some(10), true);
+        assert!(validation
+            .max_new_tokens_warning(&below.parameters)
+            .is_none());
+        let valid = validation.validate(below).await.unwrap();
+        assert_eq!(valid.stopping_parameters.max_new_tokens, 10);
+    }
+
+    #[tokio::test]
+    async fn test_validation_logit_bias() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let vocab_size = validation.vocab_size.unwrap() as u32;
+        let request = |logit_bias: HashMap<u32, f32>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                logit_bias: Some(logit_bias),
+                ..default_parameters()
+            },
+        };
+
+        let valid = validation
+            .validate(request(HashMap::from([(1, -100.0), (2, 5.0)])))
+            .await
+            .unwrap();
+        assert_eq!(valid.parameters.logit_bias.get(&1), Some(&-100.0));
+
+        match validation
+            .validate(request(HashMap::from([(vocab_size, 1.0)])))
+            .await
+        {
+            Err(ValidationError::LogitBiasTokenId(..)) => (),
+            _ => panic!("Unexpected token id"),
+        }
+        match validation
+            .validate(request(HashMap::from([(1, 101.0)])))
+            .await
+        {
+            Err(ValidationError::LogitBias) => (),
+            _ => panic!("Unexpected bias"),
+        }
+        let too_many = (0..=MAX_LOGIT_BIAS_TOKENS as u32)
+            .map(|id| (id, 1.0))
+            .collect();
+        match validation.validate(request(too_many)).await {
+            Err(ValidationError::LogitBiasTokens(..)) => (),
+            _ => panic!("Unexpected number of tokens"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_metadata() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let request = |value: &str| GenerateRequest {
+            inputs: "Hello".to_string(),
+            template: None,
+            variables: None,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                metadata: Some(HashMap::from([("session".to_string(), Value::from(value))])),
+                ..default_parameters()
+            },
+        };
+
+        assert!(validation.validate(request("abc-123")).await.is_ok());
+        match validation
+            .validate(request(&"a".repeat(MAX_METADATA_BYTES)))
+            .await
+        {
+            Err(ValidationError::Metadata(MAX_METADATA_BYTES, _)) => (),
+            _ => panic!("Unexpected metadata size"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_detokenize() {
+        let validation = Validation::new(1, get_tokenizer(), None, None, 2, 3, 4, 5, 106, true);
+        let (encoding, _) = validation
+            .tokenize("Hello world".to_string(), false, None)
+            .await
+            .unwrap();
+        let text = validation
+            .detokenize(encoding.get_ids().to_vec(), true)
+            .await
+            .unwrap();
+        assert_eq!(text, "Hello world");
+    }
+
+    #[tokio::test]
+    async fn test_validation_regex_grammar() {
+        let tokenizer = get_tokenizer();
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = false;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+        );
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
+                add_special_tokens: true,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(5),
+                    grammar: Some(GrammarType::Regex(r"\+?[0-9]{10,12}".to_string())),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            valid_request.parameters.grammar,
+            Some(ValidGrammar::Regex(regex)) if regex == r"\+?[0-9]{10,12}"
+        ));
+
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
+                add_special_tokens: true,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(5),
+                    grammar: Some(GrammarType::Regex(r"(\d+)-\1".to_string())),
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::InvalidGrammar(_)) => (),
+            _ => panic!("Unexpected regex grammar"),
+        }
+
+        // Accepted by `Regex::new`, but not by the automaton of the index
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
+                add_special_tokens: true,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(5),
+                    grammar: Some(GrammarType::Regex(r"\bcat\b".to_string())),
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::InvalidGrammar(_)) => (),
+            _ => panic!("Unexpected regex grammar"),
+        }
+    }
+
     #[tokio::test]
     async fn test_validation_top_n_tokens() {
         let tokenizer = get_tokenizer();
@@ -1061,6 +2724,8 @@ mod tests {
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(5),
@@ -1077,6 +2742,8 @@ mod tests {
         validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(4),
@@ -1090,6 +2757,8 @@ mod tests {
         validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(0),
@@ -1103,6 +2772,8 @@ mod tests {
         let valid_request = validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     top_n_tokens: None,
@@ -1167,7 +2838,9 @@ mod tests {
                     Chunk::Text("test".to_string()).into(),
                     Chunk::Image(Image {
                         data: pixel_data.clone(),
-                        mimetype: "image/gif".to_string()
+                        mimetype: "image/gif".to_string(),
+                        width: 1,
+                        height: 1,
                     })
                     .into()
                 ],
@@ -1175,6 +2848,70 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_prepare_input_image_limits() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(40, 10)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let image = format!("![](data:image/png;base64,{})", STANDARD.encode(&png));
+
+        let config = Config::Paligemma(Paligemma {
+            text_config: PaliTextConfig {
+                num_image_tokens: 1,
+            },
+        });
+        let validation = Validation::new(
+            1,
+            get_tokenizer(),
+            Some(config),
+            None,
+            2,
+            3,
+            4,
+            5,
+            6,
+            true,
+            true,
+        )
+        .with_image_limits(ImageLimits {
+            max_images: Some(1),
+            max_pixels: Some(100),
+        });
+
+        let (_, chunks) = validation
+            .tokenize(format!("test{image}"), true, None)
+            .await
+            .unwrap();
+        match &chunks[1] {
+            Chunk::Image(image) => {
+                assert_eq!((image.width, image.height), (20, 5));
+                assert_eq!(image.mimetype, "image/png");
+            }
+            _ => panic!("Expected an image"),
+        }
+
+        assert!(matches!(
+            validation
+                .tokenize(format!("test{image}{image}"), true, None)
+                .await,
+            Err(ValidationError::Images(1, 2))
+        ));
+
+        // Refused from the size in its header, before it is decoded
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(100, 20)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let image = format!("![](data:image/png;base64,{})", STANDARD.encode(&png));
+        assert!(matches!(
+            validation
+                .tokenize(format!("test{image}"), true, None)
+                .await,
+            Err(ValidationError::ImagePixels(1600, 2000))
+        ));
+    }
+
     #[tokio::test]
     async fn test_idefics2_correct_n_fake_tokens() {
         let pixel_data = STANDARD.decode(PIXEL_GIF).unwrap();
@@ -1227,12 +2964,16 @@ mod tests {
                     Chunk::Text("test".to_string()).into(),
                     Chunk::Image(Image {
                         data: pixel_data.clone(),
-                        mimetype: "image/gif".to_string()
+                        mimetype: "image/gif".to_string(),
+                        width: 1,
+                        height: 1,
                     })
                     .into(),
                     Chunk::Image(Image {
                         data: pixel_data.clone(),
-                        mimetype: "image/gif".to_string()
+                        mimetype: "image/gif".to_string(),
+                        width: 1,
+                        height: 1,
                     })
                     .into()
                 ],
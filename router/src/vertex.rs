@@ -47,13 +47,13 @@ request_body = VertexRequest,
 responses(
 (status = 200, description = "Generated Text", body = VertexResponse),
 (status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+example = json ! ({"error": {"type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"type": "overloaded", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
+example = json ! ({"error": {"type": "validation", "message": "Input validation error"}})),
 (status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"type": "incomplete_generation", "message": "Incomplete generation"}})),
 )
 )]
 #[instrument(
@@ -79,10 +79,9 @@ pub(crate) async fn vertex_compatibility(
     if req.instances.is_empty() {
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Input validation error".to_string(),
-                error_type: "Input validation error".to_string(),
-            }),
+            Json(
+                ErrorResponse::new("validation", "Input validation error").with_param("instances"),
+            ),
         ));
     }
 
@@ -93,6 +92,8 @@ pub(crate) async fn vertex_compatibility(
         let generate_request = match instance {
             VertexInstance::Generate(instance) => GenerateRequest {
                 inputs: instance.inputs.clone(),
+                template: None,
+                variables: None,
                 add_special_tokens: true,
                 parameters: GenerateParameters {
                     do_sample: true,
@@ -126,10 +127,10 @@ pub(crate) async fn vertex_compatibility(
             .map_err(|_| {
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: "Incomplete generation".into(),
-                        error_type: "Incomplete generation".into(),
-                    }),
+                    Json(ErrorResponse::new(
+                        "incomplete_generation",
+                        "Incomplete generation",
+                    )),
                 )
             })
         });
@@ -0,0 +1,283 @@
+/// WebSocket transport of `/generate_stream`, for the clients behind proxies that buffer or cut
+/// the SSE streams.
+///
+/// The client sends the `GenerateRequest` as its first message, then receives one message per
+/// event of the SSE stream, with the same JSON payload. It can send `cancel` and `feedback`
+/// messages while the tokens are streamed.
+///
+/// The connection holds the concurrency slot of its client until it is closed, and its messages
+/// are bounded by the payload limit of the HTTP requests. `Idempotency-Key` is refused, as the
+/// request is only known once the connection is upgraded.
+use crate::infer::{Infer, InferError};
+use crate::lifecycle::generate_lifecycle;
+use crate::server::{generate_stream_internal, ComputeType, StreamEvent};
+use crate::{auth, rate_limit, request_id, tenant};
+use crate::{ErrorResponse, GenerateRequest, StreamMode};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Extension;
+use axum::response::Response;
+use axum::Json;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use tokio::time::{interval_at, Instant};
+use tracing::{instrument, Instrument};
+
+/// Message of the client, after its request
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Stop the generation, the server then closes the connection
+    Cancel,
+    /// Opinion of the client on the generation, logged with the id of the request
+    Feedback {
+        #[serde(default)]
+        score: Option<f64>,
+        #[serde(default)]
+        comment: Option<String>,
+    },
+}
+
+/// Largest message accepted from the clients
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct MessageLimit(pub(crate) usize);
+
+/// Upgrade the connection to a WebSocket streaming the tokens of a generation
+pub(crate) async fn generate_stream_ws(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(MessageLimit(message_limit)): Extension<MessageLimit>,
+    in_flight: Option<Extension<rate_limit::InFlight>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // The upgraded connection is served outside of the request task
+    let api_key = auth::current_key();
+    let request_id = request_id::current_scope();
+    let tenant = tenant::current();
+    let rate_limited_client = rate_limit::current_client();
+    let span = tracing::Span::current();
+    // The shutdown of the server waits for the connection, and the slot of the client is only
+    // freed once it is closed
+    let connection = infer.upgraded_connection();
+    let in_flight = in_flight.map(|Extension(in_flight)| in_flight);
+    let upgrade = upgrade
+        .max_message_size(message_limit)
+        .max_frame_size(message_limit);
+    upgrade.on_upgrade(move |socket| {
+        let session = async move {
+            let _connection = connection;
+            let _in_flight = in_flight;
+            session(socket, infer, compute_type).await
+        };
+        let future = request_id::scope(
+            request_id,
+            auth::scope(
+                api_key,
+                tenant::scope(tenant, rate_limit::scope(rate_limited_client, session)),
+            ),
+        );
+        future.instrument(span)
+    })
+}
+
+async fn session(mut socket: WebSocket, infer: Infer, compute_type: ComputeType) {
+    let request = loop {
+        let message = tokio::select! {
+            message = socket.recv() => message,
+            // The connections still waiting for their request do not hold up the drain
+            _ = infer.draining() => {
+                close(&mut socket, close_code::AWAY).await;
+                return;
+            }
+        };
+        match message {
+            Some(Ok(Message::Text(text))) => break serde_json::from_str(&text),
+            Some(Ok(Message::Binary(bytes))) => break serde_json::from_slice(&bytes),
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            // The client left before sending its request
+            _ => return,
+        }
+    };
+    let code = match request {
+        Ok(request) => stream(&mut socket, infer, compute_type, request).await,
+        Err(err) => {
+            let error = ErrorResponse::new("invalid_request", err.to_string());
+            let _ = socket.send(Message::Text(payload(&error))).await;
+            Some(close_code::INVALID)
+        }
+    };
+    if let Some(code) = code {
+        close(&mut socket, code).await;
+    }
+}
+
+async fn close(socket: &mut WebSocket, code: u16) {
+    let frame = CloseFrame {
+        code,
+        reason: Cow::Borrowed(""),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+/// Send the events of the generation of `req` until its end or its cancellation. Returns the
+/// code to close the connection with, or `None` if the client is gone.
+#[instrument(
+skip_all,
+fields(
+parameters = ? req.parameters,
+total_time,
+validation_time,
+queue_time,
+inference_time,
+time_per_token,
+seed,
+)
+)]
+async fn stream(
+    socket: &mut WebSocket,
+    infer: Infer,
+    compute_type: ComputeType,
+    mut req: GenerateRequest,
+) -> Option<u16> {
+    let span = tracing::Span::current();
+    let stream_mode = req.parameters.stream_mode;
+    if stream_mode == StreamMode::Events {
+        // The `done` event always carries the finish reason and the usage of the generation
+        req.parameters.details = true;
+    }
+    let heartbeat_interval = infer.stream_heartbeat_interval();
+    let (_, response_stream) = generate_stream_internal(infer, compute_type, Json(req), span).await;
+    // Dropping the stream stops the generation
+    let mut response_stream = Box::pin(response_stream);
+
+    // Pings keep the connections of idle streams open, as the comments of the SSE streams
+    let mut heartbeat = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+    loop {
+        tokio::select! {
+            event = response_stream.next() => {
+                let Some(event) = event else {
+                    return Some(close_code::NORMAL);
+                };
+                for message in messages(event, stream_mode) {
+                    socket.send(Message::Text(message)).await.ok()?;
+                }
+            }
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Binary(bytes))) => String::from_utf8_lossy(&bytes).into_owned(),
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => {
+                        tracing::info!("Client disconnected");
+                        return None;
+                    }
+                };
+                match serde_json::from_str(&text) {
+                    Ok(ClientMessage::Cancel) => {
+                        metrics::counter!("tgi_request_cancelled").increment(1);
+                        tracing::info!("Generation cancelled by the client");
+                        return Some(close_code::NORMAL);
+                    }
+                    Ok(ClientMessage::Feedback { score, comment }) => {
+                        metrics::counter!("tgi_request_feedback").increment(1);
+                        tracing::info!(score, comment = comment.as_deref(), "Feedback of the client");
+                    }
+                    Err(err) => {
+                        let error = ErrorResponse::new("invalid_request", err.to_string());
+                        socket.send(Message::Text(payload(&error))).await.ok()?;
+                    }
+                }
+            }
+            _ = heartbeat.tick() => {
+                socket.send(Message::Ping(Vec::new())).await.ok()?;
+            }
+        }
+    }
+}
+
+/// Messages of an event of the stream, with the payloads of the SSE events
+fn messages(event: Result<StreamEvent, InferError>, stream_mode: StreamMode) -> Vec<String> {
+    let event = match event {
+        Ok(event) => event,
+        Err(err) => return vec![payload(&ErrorResponse::from(&err))],
+    };
+    if stream_mode == StreamMode::Events {
+        return generate_lifecycle(event).iter().map(payload).collect();
+    }
+    match event {
+        StreamEvent::Queued(position) => {
+            vec![payload(&serde_json::json!({ "queue_position": position }))]
+        }
+        StreamEvent::Prefilling => Vec::new(),
+        StreamEvent::Token(token) => vec![payload(&token)],
+    }
+}
+
+fn payload<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| {
+        let err = InferError::StreamSerializationError(e.to_string());
+        serde_json::to_string(&ErrorResponse::from(&err)).unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StreamResponse, Token};
+
+    #[test]
+    fn test_client_message() {
+        assert_eq!(
+            serde_json::from_str::<ClientMessage>(r#"{"type": "cancel"}"#).unwrap(),
+            ClientMessage::Cancel
+        );
+        assert_eq!(
+            serde_json::from_str::<ClientMessage>(r#"{"type": "feedback", "score": 1}"#).unwrap(),
+            ClientMessage::Feedback {
+                score: Some(1.0),
+                comment: None
+            }
+        );
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "pause"}"#).is_err());
+    }
+
+    fn token() -> StreamResponse {
+        StreamResponse {
+            index: 1,
+            token: Token {
+                id: 0,
+                text: "Hello".to_string(),
+                logprob: -0.5,
+                special: false,
+            },
+            top_tokens: Vec::new(),
+            generated_text: None,
+            details: None,
+            metadata: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        let deltas = messages(Ok(StreamEvent::Token(token())), StreamMode::Deltas);
+        assert_eq!(deltas, vec![serde_json::to_string(&token()).unwrap()]);
+        assert!(messages(Ok(StreamEvent::Prefilling), StreamMode::Deltas).is_empty());
+        assert_eq!(
+            messages(Ok(StreamEvent::Queued(3)), StreamMode::Deltas),
+            vec![r#"{"queue_position":3}"#.to_string()]
+        );
+
+        let events = messages(Ok(StreamEvent::Token(token())), StreamMode::Events);
+        let event: serde_json::Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(event["type"], "token");
+        assert_eq!(event["text"], "Hello");
+
+        let errors = messages(
+            Err(InferError::IncompleteGenerationStream),
+            StreamMode::Events,
+        );
+        let error: serde_json::Value = serde_json::from_str(&errors[0]).unwrap();
+        assert_eq!(error["error"]["type"], "incomplete_generation_stream");
+    }
+}
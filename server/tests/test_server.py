@@ -0,0 +1,111 @@
+import asyncio
+import threading
+
+import pytest
+import torch
+
+from text_generation_server import server
+from text_generation_server.models.globals import (
+    get_adapter_to_index,
+    set_adapter_to_index,
+)
+from text_generation_server.pb import generate_pb2
+
+
+class FakeModel:
+    quantize = None
+    device = torch.device("cpu")
+
+    def __init__(self):
+        self.reserved_adapter_indices = set()
+        self.loaded_adapters = set()
+
+    def free_adapter_index(self):
+        used_indices = set(get_adapter_to_index().values())
+        used_indices.update(self.reserved_adapter_indices)
+        return next(i for i in range(1, len(used_indices) + 2) if i not in used_indices)
+
+    @property
+    def lora_adapters(self):
+        return [
+            generate_pb2.LoraAdapter(id=adapter_id, rank=8, memory_bytes=0)
+            for adapter_id in get_adapter_to_index()
+        ]
+
+
+@pytest.fixture
+def service(monkeypatch):
+    set_adapter_to_index({})
+    monkeypatch.setattr(server, "download_adapter", lambda adapter: None)
+    return server.TextGenerationService(FakeModel(), None, [])
+
+
+def test_load_adapter_off_the_event_loop(service, monkeypatch):
+    loop_thread = threading.get_ident()
+    prepared_on = []
+
+    def prepare_lora_adapter(model, adapter, index):
+        prepared_on.append(threading.get_ident())
+        # Reserved while the weights are read
+        assert index in model.reserved_adapter_indices
+        return index
+
+    def add_lora_adapter(model, index, prepared):
+        assert threading.get_ident() == loop_thread
+        model.loaded_adapters.add(prepared)
+
+    monkeypatch.setattr(server, "prepare_lora_adapter", prepare_lora_adapter)
+    monkeypatch.setattr(server, "add_lora_adapter", add_lora_adapter)
+
+    request = generate_pb2.LoadAdapterRequest(id="predibase/customer_support")
+    response = asyncio.run(service.LoadAdapter(request, None))
+
+    assert response.adapter.id == "predibase/customer_support"
+    assert prepared_on and prepared_on[0] != loop_thread
+    assert get_adapter_to_index() == {"predibase/customer_support": 1}
+    assert service.model.loaded_adapters == {1}
+    assert not service.model.reserved_adapter_indices
+    assert not service.loading_adapters
+
+
+def test_load_adapter_concurrently(service, monkeypatch):
+    release = threading.Event()
+
+    def prepare_lora_adapter(model, adapter, index):
+        release.wait(timeout=10)
+        return index
+
+    monkeypatch.setattr(server, "prepare_lora_adapter", prepare_lora_adapter)
+    monkeypatch.setattr(server, "add_lora_adapter", lambda model, index, prepared: None)
+
+    async def load():
+        first = asyncio.ensure_future(
+            service.LoadAdapter(generate_pb2.LoadAdapterRequest(id="a"), None)
+        )
+        await asyncio.sleep(0.1)
+        # The same adapter cannot be loaded twice, another one takes the next index
+        with pytest.raises(ValueError):
+            await service.LoadAdapter(generate_pb2.LoadAdapterRequest(id="a"), None)
+        second = asyncio.ensure_future(
+            service.LoadAdapter(generate_pb2.LoadAdapterRequest(id="b"), None)
+        )
+        await asyncio.sleep(0.1)
+        release.set()
+        await asyncio.gather(first, second)
+
+    asyncio.run(load())
+    assert get_adapter_to_index() == {"a": 1, "b": 2}
+
+
+def test_load_adapter_failure(service, monkeypatch):
+    def prepare_lora_adapter(model, adapter, index):
+        raise ValueError("missing adapter_config.json")
+
+    monkeypatch.setattr(server, "prepare_lora_adapter", prepare_lora_adapter)
+
+    request = generate_pb2.LoadAdapterRequest(id="a")
+    with pytest.raises(ValueError):
+        asyncio.run(service.LoadAdapter(request, None))
+    assert get_adapter_to_index() == {}
+    assert not service.model.reserved_adapter_indices
+    assert not service.loading_adapters
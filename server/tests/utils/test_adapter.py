@@ -1,5 +1,7 @@
 import pytest
+import torch
 from unittest.mock import Mock
+from text_generation_server.adapters.lora import LoraConfig, LoraWeights
 from text_generation_server.utils.adapter import (
     get_attn_weights,
     get_mlp_weights,
@@ -245,3 +247,38 @@ def test_get_mlp_weights_gemma_compatibility():
         (3, "down_proj"): ("model.layers.3.mlp.down_proj", mock_layer.mlp.down_proj),
     }
     assert result == expected
+
+
+def test_lora_weights_mix():
+    config = LoraConfig(
+        base_model_name_or_path="base",
+        r=8,
+        target_modules=None,
+        fan_in_fan_out=False,
+        lora_alpha=8,
+        use_rslora=False,
+    )
+
+    def lora_weights(rank):
+        weights_a = [torch.randn(32, rank) for _ in range(2)]
+        weights_b = [torch.randn(rank, 24) for _ in range(2)]
+        return LoraWeights(weights_a, weights_b, config), weights_a, weights_b
+
+    # Below and above the rank from which A is stored transposed
+    first, first_a, first_b = lora_weights(8)
+    second, second_a, second_b = lora_weights(16)
+    mixture = LoraWeights.mix([(first, 0.7), (second, -0.3)], world_size=1)
+
+    x = torch.randn(5, 32)
+    for layer in range(2):
+        expected = 0.7 * x @ first_a[layer] @ first_b[layer] - 0.3 * (
+            x @ second_a[layer] @ second_b[layer]
+        )
+        a = mixture.weights_a[layer]
+        if a.size(0) != 32:
+            a = a.transpose(0, 1)
+        result = x @ a @ mixture.weights_b[layer]
+        assert torch.allclose(result, expected, atol=1e-4)
+    assert mixture.adapter_config.r == mixture.lora_a_r
+    # The weights of the mixed adapters are unchanged
+    assert torch.equal(first.weights_b[0], first_b[0])
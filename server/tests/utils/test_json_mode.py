@@ -0,0 +1,74 @@
+import json
+
+from text_generation_server.utils import json_mode
+from text_generation_server.utils.json_mode import JsonObjectGuide, feed, token_class
+
+
+class FakeTokenizer:
+    eos_token_id = 0
+    special_tokens = {"</s>"}
+
+    def __init__(self, tokens):
+        self.vocabulary = {"</s>": 0}
+        for token in tokens:
+            self.vocabulary[token] = len(self.vocabulary)
+
+    def convert_token_to_string(self, token):
+        return token
+
+
+def test_json_object_parser():
+    start = ("start", 0, "")
+    document = '{"a": [true, null, -1.5e+3, "\\u00e9"], "b": {"c": {}}}'
+    assert feed(start, document)[0] == "done"
+    for invalid in ["[1]", '{"a" 1}', '{"a": 01}', '{"a": 1,}', '{"a": 1}}', '{"\n"}']:
+        state = feed(start, invalid)
+        assert state is None or state[0] != "done"
+
+
+def test_json_object_guide():
+    tokens = ["{", "}", '{"', '"', ":", " ", "a", "1", "12", "true", '"}', ",", "]"]
+    tokenizer = FakeTokenizer(tokens)
+    guide = JsonObjectGuide(tokenizer)
+
+    def allowed(state):
+        ids = guide.get_next_instruction(state).tokens.tolist()
+        return {token for token, id in tokenizer.vocabulary.items() if id in ids}
+
+    assert allowed(0) == {"{", '{"', " "}
+    text = ""
+    state = 0
+    for token in ['{"', "a", '"', ":", " ", "12", ",", " ", '"', "a", '"', ":", "true"]:
+        assert token in allowed(state)
+        state = guide.get_next_state(state, tokenizer.vocabulary[token])
+        text += token
+    assert allowed(state) == {",", "}", " "}
+    assert '"}' not in allowed(state)
+
+    state = guide.get_next_state(state, tokenizer.vocabulary["}"])
+    json.loads(text + "}")
+    # Only the end of the generation follows the object
+    assert allowed(state) == {"</s>"}
+    assert guide.get_next_state(state, tokenizer.eos_token_id) == -1
+
+
+def test_token_classes():
+    # The letters of the literals and escapes stay, the other letters are ordinary
+    assert token_class('{"héllo": 12, \\u00e9\n\t}') == '{"ggllg": 11, \\u00e1\n\n}'
+    guide = JsonObjectGuide(FakeTokenizer(["1", "2", "7", "x", "y", "é", "e"]))
+    assert guide.texts == ["1", "e", "g"]
+
+
+def test_json_object_guide_states(monkeypatch):
+    monkeypatch.setattr(json_mode, "MAX_STATES", 3)
+    tokenizer = FakeTokenizer(['{"', '"', ":", " "])
+    guide = JsonObjectGuide(tokenizer)
+    state = 0
+    states = []
+    for token in ['{"', '"', ":", " "]:
+        state = guide.get_next_state(state, tokenizer.vocabulary[token])
+        states.append(state)
+    # The oldest states are forgotten, never the initial one
+    assert list(guide.states) == [states[-2], states[-1], 0]
+    assert guide.get_next_state(states[0], tokenizer.vocabulary['"']) == -1
+    assert guide.get_next_instruction(states[0]).tokens.tolist() == [0]
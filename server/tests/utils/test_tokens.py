@@ -3,8 +3,18 @@ from text_generation_server.utils.tokens import (
     StopSequenceCriteria,
     StoppingCriteria,
     FinishReason,
+    HeterogeneousSampling,
+    Sampling,
     batch_top_tokens,
 )
+from text_generation_server.utils.logits_process import (
+    HeterogeneousMinPLogitsWarper,
+    HeterogeneousNoRepeatNGramLogitsProcessor,
+    HeterogeneousPresenceFrequencyPenaltyLogitsProcessor,
+    NoRepeatNGramLogitsProcessor,
+    PresenceFrequencyPenaltyLogitsProcessor,
+    classifier_free_guidance,
+)
 
 
 def test_stop_sequence_criteria():
@@ -37,6 +47,30 @@ def test_stopping_criteria_eos():
     assert criteria(0, "") == (True, FinishReason.FINISH_REASON_EOS_TOKEN)
 
 
+def test_stopping_criteria_stop_token_ids():
+    criteria = StoppingCriteria(
+        0, [], max_new_tokens=5, ignore_eos_token=True, stop_token_ids={7}
+    )
+    assert criteria(0, "") == (False, None)
+    assert criteria(7, "") == (True, FinishReason.FINISH_REASON_STOP_SEQUENCE)
+
+
+def test_stopping_criteria_min_new_tokens():
+    criteria = StoppingCriteria(
+        0,
+        [StopSequenceCriteria("/test;")],
+        max_new_tokens=5,
+        stop_token_ids={7},
+        min_new_tokens=2,
+    )
+    assert criteria.suppressed_token_ids() == [0, 7]
+    assert criteria(0, "/test;") == (False, None)
+    assert criteria.suppressed_token_ids() == [0, 7]
+    assert criteria(7, "") == (False, None)
+    assert criteria.suppressed_token_ids() == []
+    assert criteria(0, "") == (True, FinishReason.FINISH_REASON_EOS_TOKEN)
+
+
 def test_stopping_criteria_max():
     criteria = StoppingCriteria(0, [StopSequenceCriteria("/test;")], max_new_tokens=5)
     assert criteria(1, "") == (False, None)
@@ -86,3 +120,135 @@ def test_batch_top_tokens():
     assert topn_tok_logprobs[2] == [[-1, -2, -3, -3]]
     assert topn_tok_logprobs[3] == [[-1, -2, -3, -3]]
     assert topn_tok_logprobs[4] == [[-1, -2, -3, -3, -4]]
+
+
+def test_sampling_depends_on_seed_and_step():
+    logits = torch.linspace(0, 1, 32)
+    sampling = Sampling(42)
+    tokens = [sampling(logits).item() for _ in range(8)]
+
+    # Resuming at a step samples the same tokens
+    resumed = Sampling(42, step=3)
+    assert [resumed(logits).item() for _ in range(5)] == tokens[3:]
+    assert Sampling(42)(logits, step=5).item() == tokens[5]
+
+
+def test_heterogeneous_sampling_does_not_depend_on_batch():
+    logits = torch.linspace(0, 1, 32)
+    alone = HeterogeneousSampling([True], [7], torch.device("cpu"))
+    batched = HeterogeneousSampling([True, True], [3, 7], torch.device("cpu"))
+
+    for step in range(4):
+        token = alone(logits.unsqueeze(0), [step])[0]
+        assert batched(logits.repeat(2, 1), [9, step])[1] == token
+
+
+def test_presence_frequency_penalties():
+    # Prompt of token 4, then 2 generated twice, 3 and 0 once, and padding
+    input_ids = torch.tensor([[4, 2, 3, 2, 0, 0], [4, 2, 3, 2, 0, 0]])
+    scores = torch.zeros(2, 5)
+    processor = HeterogeneousPresenceFrequencyPenaltyLogitsProcessor(
+        [0.5, 0.5], [0.0, 1.0], torch.device("cpu")
+    )
+
+    scores = processor(input_ids, scores, [5, 5], [4, 4])
+    assert scores[0].tolist() == [-0.5, 0.0, -1.0, -0.5, 0.0]
+    # The presence penalty is added once to the frequency penalty
+    assert scores[1].tolist() == [-1.5, 0.0, -2.0, -1.5, 0.0]
+
+    processor = processor.filter([1])
+    assert processor.presence_penalty == [1.0]
+    assert processor.filter([]) is None
+
+
+def test_presence_frequency_penalties_ignore_prompt():
+    # The prompt repeats token 2, the first generated token is not penalized
+    input_ids = torch.tensor([[2, 2, 2, 1]])
+    processor = HeterogeneousPresenceFrequencyPenaltyLogitsProcessor(
+        [1.0], [1.0], torch.device("cpu")
+    )
+    scores = processor(input_ids, torch.zeros(1, 3), [4], [0])
+    assert scores[0].tolist() == [0.0, 0.0, 0.0]
+
+    # Once generated, token 2 is only counted once
+    input_ids = torch.tensor([[2, 2, 2, 1, 2]])
+    scores = processor(input_ids, torch.zeros(1, 3), [5], [1])
+    assert scores[0].tolist() == [0.0, 0.0, -2.0]
+
+    # The single request chooser counts the tokens it generated
+    processor = PresenceFrequencyPenaltyLogitsProcessor(1.0, 0.0)
+    assert processor(input_ids[:, :4], torch.zeros(1, 3)).tolist() == [[0.0, 0.0, 0.0]]
+    assert processor(input_ids, torch.zeros(1, 3)).tolist() == [[0.0, 0.0, -1.0]]
+
+
+def test_min_p():
+    # Probabilities 0.5, 0.25, 0.125, 0.125
+    scores = torch.log(torch.tensor([[0.5, 0.25, 0.125, 0.125]] * 3))
+    warper = HeterogeneousMinPLogitsWarper(
+        [0.0, 0.5, 0.3], torch.float32, torch.device("cpu")
+    )
+
+    kept = torch.isfinite(warper(None, scores))
+    assert kept[0].tolist() == [True, True, True, True]
+    # Only the tokens at least half as likely as the first one are kept
+    assert kept[1].tolist() == [True, True, False, False]
+    assert kept[2].tolist() == [True, True, False, False]
+
+    assert warper.filter([0]) is None
+
+
+def test_no_repeat_ngram():
+    # Rows padded past their lengths
+    input_ids = torch.tensor([[1, 2, 3, 1, 2], [1, 2, 3, 1, 2], [4, 5, 4, 0, 0]])
+    processor = HeterogeneousNoRepeatNGramLogitsProcessor([3, 0, 2])
+
+    banned = torch.isinf(processor(input_ids, torch.zeros(3, 8), [5, 5, 3]))
+    # `1 2 3` already occurred
+    assert banned[0].nonzero().view(-1).tolist() == [3]
+    assert not banned[1].any()
+    # The padding is not part of the text
+    assert banned[2].nonzero().view(-1).tolist() == [5]
+
+    # Token 0 is part of the text, and the next steps only index the new tokens
+    input_ids = torch.tensor(
+        [[1, 2, 3, 1, 2, 4, 0], [0, 0, 0, 0, 0, 0, 0], [4, 5, 4, 0, 4, 0, 0]]
+    )
+    banned = torch.isinf(processor(input_ids, torch.zeros(3, 8), [6, 5, 5]))
+    assert not banned[0].any()
+    assert banned[2].nonzero().view(-1).tolist() == [0, 5]
+    assert processor.indices[2].tokens == [4, 5, 4, 0, 4]
+
+    assert processor.filter([1]) is None
+
+
+def test_no_repeat_ngram_single():
+    processor = NoRepeatNGramLogitsProcessor(2)
+    banned = torch.isinf(processor(torch.tensor([[0, 1, 0]]), torch.zeros(1, 4)))
+    assert banned[0].nonzero().view(-1).tolist() == [1]
+    banned = torch.isinf(processor(torch.tensor([[0, 1, 0, 2, 1]]), torch.zeros(1, 4)))
+    assert banned[0].nonzero().view(-1).tolist() == [0]
+
+
+def test_classifier_free_guidance():
+    # Rows 0 and 1 are guided by row 2, row 3 is not guided
+    logits = torch.log(
+        torch.tensor(
+            [
+                [0.5, 0.25, 0.25],
+                [0.5, 0.25, 0.25],
+                [0.25, 0.5, 0.25],
+                [0.25, 0.5, 0.25],
+            ]
+        )
+    )
+    guided = torch.tensor([0, 1])
+    negative = torch.tensor([2, 2])
+    scales = torch.tensor([1.0, 2.0])
+
+    logits = classifier_free_guidance(logits.clone(), guided, negative, scales)
+    # A scale of 1 keeps the probabilities of the guided row
+    assert torch.allclose(logits[0].exp(), torch.tensor([0.5, 0.25, 0.25]))
+    # A larger scale moves the guided row further away from the negative one
+    assert logits[1].argmax() == 0
+    assert logits[1][1] < torch.log(torch.tensor(0.25))
+    assert torch.allclose(logits[3].exp(), torch.tensor([0.25, 0.5, 0.25]))
@@ -3,7 +3,10 @@
 import os
 import numpy as np
 import torch
-from text_generation_server.utils.watermark import WatermarkLogitsProcessor
+from text_generation_server.utils.watermark import (
+    WatermarkLogitsProcessor,
+    keyed_greenlist_mask,
+)
 
 
 GAMMA = os.getenv("WATERMARK_GAMMA", 0.5)
@@ -52,3 +55,22 @@ def test_call():
     scores = torch.tensor([[0.5, 0.3, 0.2, 0.8], [0.1, 0.2, 0.7, 0.9]])
     result = processor(input_ids, scores)
     assert result.shape == scores.shape
+
+
+def test_keyed_greenlist_mask():
+    # Same values as the tests of the router, which scores the texts
+    mask = keyed_greenlist_mask(0x0123456789ABCDEF, 42, 16, 0.5, torch.device("cpu"))
+    assert mask.nonzero().squeeze(-1).tolist() == [0, 1, 2, 3, 6, 9, 10, 11, 13, 14, 15]
+    mask = keyed_greenlist_mask(15485863, 7, 16, 0.25, torch.device("cpu"))
+    assert mask.nonzero().squeeze(-1).tolist() == [4, 10, 15]
+
+
+def test_keyed_get_greenlist_ids():
+    input_ids = [101, 2036, 3731, 102, 2003, 42]
+    processor = WatermarkLogitsProcessor(gamma=0.5, key=0x0123456789ABCDEF)
+    result = processor._get_greenlist_ids(input_ids, 16, torch.device("cpu"))
+    assert result.tolist() == [0, 1, 2, 3, 6, 9, 10, 11, 13, 14, 15]
+
+    # The greenlist of a token does not depend on the size of the vocabulary
+    result = processor._get_greenlist_ids(input_ids, 12, torch.device("cpu"))
+    assert result.tolist() == [0, 1, 2, 3, 6, 9, 10, 11]
@@ -3,7 +3,7 @@
 # License:  Apache License Version 2.0, January 2004
 
 from collections import defaultdict
-from dataclasses import dataclass
+from dataclasses import dataclass, replace
 from typing import Dict, List, Optional, Set, Tuple, Type, Union
 
 import torch
@@ -20,6 +20,7 @@ from text_generation_server.adapters.weights import (
 from text_generation_server.utils.sgmv import (
     BGMV_MAX_RANK,
     MAX_RANK_CUSTOM,
+    MIN_RANK_CUSTOM,
     get_tmp_tensors,
     orient_for_rank,
     pad_rank,
@@ -174,6 +175,51 @@ class LoraWeights(AdapterWeights):
     def get_batch_types(cls) -> List[Type[BatchAdapterWeights]]:
         return [BatchLoraWeights]
 
+    @classmethod
+    def mix(
+        cls, adapters: List[Tuple["LoraWeights", float]], world_size: int
+    ) -> "LoraWeights":
+        """Mixture of the weighted `adapters` of a layer type.
+
+        The ranks of the adapters are concatenated and the weight of each adapter is
+        folded into its B, so that the mixture adds exactly `sum(weight * x @ A @ B)`
+        with the same kernels as a single adapter.
+        """
+        weights_a, weights_b = [], []
+        for weights, weight in adapters:
+            # [num_layers, hidden_size, r] whatever the orientation used by the kernels
+            a = weights.weights_a
+            if MIN_RANK_CUSTOM <= weights.lora_a_r <= MAX_RANK_CUSTOM:
+                a = a.transpose(1, 2)
+            weights_a.append(a)
+            weights_b.append(weights.weights_b * weight)
+
+        # The rank of A is sharded with column parallel layers, the shards gather their
+        # shrunk inputs: B then has one block of rows per shard
+        local_rank = sum(a.size(2) for a in weights_a)
+        blocks = adapters[0][0].weights_b.size(1) // adapters[0][0].lora_a_r
+        padded_rank = pad_rank(
+            weights_a[0].new_empty(0, local_rank * blocks), dim=1, world_size=world_size
+        ).size(1)
+        pad = padded_rank // blocks - local_rank
+
+        num_layers, hidden_size, _ = weights_a[0].shape
+        mixed_a = torch.cat(
+            weights_a + [weights_a[0].new_zeros(num_layers, hidden_size, pad)], dim=2
+        )
+        mixed_b = []
+        for block in range(blocks):
+            for a, b in zip(weights_a, weights_b):
+                rank = a.size(2)
+                mixed_b.append(b[:, block * rank : (block + 1) * rank])
+            mixed_b.append(
+                weights_b[0].new_zeros(num_layers, pad, weights_b[0].size(2))
+            )
+        mixed_b = torch.cat(mixed_b, dim=1)
+
+        config = replace(adapters[0][0].adapter_config, r=padded_rank)
+        return cls(list(mixed_a), list(mixed_b), config)
+
     # prepare pre-loaded lora weights for use in the model.
     #
     # this method processes and organizes lora weights for a specific layer type across all layers:
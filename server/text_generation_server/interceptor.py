@@ -1,3 +1,4 @@
+import inspect
 import torch
 import grpc
 
@@ -21,20 +22,37 @@ class ExceptionInterceptor(AsyncServerInterceptor):
     ) -> Any:
         try:
             response = method(request_or_iterator, context)
+            # Server streaming methods are async generators, which fail while iterated
+            if inspect.isasyncgen(response):
+                return self._intercept_stream(response, context, method_name)
             return await response
         except Exception as err:
-            method_name = method_name.split("/")[-1]
-            logger.exception(f"Method {method_name} encountered an error.")
+            await self._abort(err, context, method_name)
 
-            # Runtime Error cannot be recovered from
-            if isinstance(err, RuntimeError):
-                self.shutdown_callback()
+    async def _intercept_stream(
+        self, response, context: grpc.ServicerContext, method_name: str
+    ):
+        try:
+            async for message in response:
+                yield message
+        except Exception as err:
+            await self._abort(err, context, method_name)
+
+    async def _abort(
+        self, err: Exception, context: grpc.ServicerContext, method_name: str
+    ):
+        method_name = method_name.split("/")[-1]
+        logger.exception(f"Method {method_name} encountered an error.")
+
+        # Runtime Error cannot be recovered from
+        if isinstance(err, RuntimeError):
+            self.shutdown_callback()
 
-            if torch.cuda.is_available():
-                torch.cuda.empty_cache()
+        if torch.cuda.is_available():
+            torch.cuda.empty_cache()
 
-            await context.abort_with_status(
-                rpc_status.to_status(
-                    status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
-                )
+        await context.abort_with_status(
+            rpc_status.to_status(
+                status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
             )
+        )
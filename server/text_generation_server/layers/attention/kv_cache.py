@@ -41,6 +41,7 @@ class KVCache:
     """
 
     kv_cache: Tuple[torch.Tensor, torch.Tensor]
+    host_kv_cache: Tuple[torch.Tensor, ...] = ()
 
     def __init__(
         self,
@@ -106,6 +107,60 @@ class KVCache:
                 ),
             )
 
+    def init_host_cache(self, num_blocks: int):
+        """Allocate the host memory that device blocks are swapped to."""
+        self.host_kv_cache = tuple(
+            torch.empty(
+                (num_blocks, *cache.shape[1:]),
+                dtype=cache.dtype,
+                device="cpu",
+                pin_memory=cache.device.type == "cuda",
+            )
+            for cache in self.kv_cache
+        )
+
+    def swap_out(self, device_blocks: torch.Tensor, host_blocks: torch.Tensor):
+        """Copy device blocks to host blocks."""
+        for cache, host_cache in zip(self.kv_cache, self.host_kv_cache):
+            host_cache[host_blocks] = cache[device_blocks.to(cache.device)].cpu()
+
+    def swap_in(self, host_blocks: torch.Tensor, device_blocks: torch.Tensor):
+        """Copy host blocks back to device blocks."""
+        for cache, host_cache in zip(self.kv_cache, self.host_kv_cache):
+            cache[device_blocks.to(cache.device)] = host_cache[host_blocks].to(
+                cache.device, non_blocking=True
+            )
+
+    def export_blocks(self, blocks: torch.Tensor) -> bytes:
+        """Raw bytes of the key blocks followed by the value blocks."""
+        return b"".join(
+            cache[blocks.to(cache.device)]
+            .contiguous()
+            .view(torch.uint8)
+            .cpu()
+            .numpy()
+            .tobytes()
+            for cache in self.kv_cache
+        )
+
+    def import_blocks(self, blocks: torch.Tensor, data: bytes):
+        """Write the blocks exported by `export_blocks` on a shard of the same shape."""
+        sizes = [
+            len(blocks) * cache[0].numel() * cache.element_size()
+            for cache in self.kv_cache
+        ]
+        if len(data) != sum(sizes):
+            raise ValueError(
+                f"Expected {sum(sizes)} bytes for {len(blocks)} blocks, got {len(data)}"
+            )
+        data = torch.frombuffer(bytearray(data), dtype=torch.uint8)
+        offset = 0
+        for cache, size in zip(self.kv_cache, sizes):
+            values = data[offset : offset + size].view(cache.dtype)
+            values = values.view(len(blocks), *cache.shape[1:])
+            cache[blocks.to(cache.device)] = values.to(cache.device)
+            offset += size
+
     def can_scale(self, kv_scales: KVScales) -> bool:
         """Check if the cache can be scaled by the given scales."""
         if kv_scales.key_scale_cpu == 1.0 and kv_scales.value_scale_cpu == 1.0:
@@ -10,7 +10,9 @@ import torch
 import enum
 import os
 
+from dataclasses import dataclass
 from loguru import logger
+from transformers import PreTrainedTokenizerBase
 from transformers.configuration_utils import PretrainedConfig
 from transformers.models.auto import modeling_auto
 from huggingface_hub import hf_hub_download, HfApi
@@ -53,6 +55,7 @@ from text_generation_server.utils.adapter import (
     AdapterInfo,
 )
 from text_generation_server.adapters.lora import LoraWeights
+from text_generation_server.adapters.weights import AdapterWeights
 
 
 from text_generation_server.utils.import_utils import SYSTEM
@@ -73,6 +76,9 @@ __all__ = [
     "CausalLM",
     "Seq2SeqLM",
     "get_model_with_lora_adapters",
+    "add_lora_adapter",
+    "load_lora_adapter",
+    "prepare_lora_adapter",
 ]
 
 FLASH_ATT_ERROR_MESSAGE = "{} requires Flash Attention enabled models."
@@ -1377,84 +1383,126 @@ def get_model_with_lora_adapters(
         target_to_layer = build_layer_weight_lookup(model.model)
 
         for index, adapter in enumerate(lora_adapters):
-            # The AdapterParameters object allows for merging multiple adapters into a single adapter.
-            # At the moment, we only support loading a single adapter into the model, but we keep the
-            # AdapterParameters object for easier extension in the future.
-            adapter_parameters = AdapterParameters(
-                adapter_info=[adapter],
-                # when merging multiple adapters we can weight them differently
-                # if this is not set, all adapters will be weighted equally
-                # see: text_generation_server.utils.merges.strategies for impl
-                weights=None,
-                merge_strategy=0,
-                density=1.0,
-                majority_sign_method=0,
-            )
-
             adapter_index = index + 1
             adapter_to_index[adapter.id] = adapter_index
+            load_lora_adapter(model, adapter, adapter_index, target_to_layer)
 
-            logger.info(
-                f"Loading adapter weights into model: {','.join([adapter.id for adapter in adapter_parameters.adapter_info])}"
-            )
-            weight_names = tuple([v[0] for v in target_to_layer.values()])
-            (
-                module_map,
-                adapter_config,
-                adapter_weight_names,
-                adapter_tokenizer,
-            ) = load_and_merge_adapters(
-                model.model_id,
-                adapter_parameters,
-                adapter_index,
-                weight_names,
-                False,
-            )
+    return model
 
-            unused_weight_names = adapter_weight_names.copy()
-
-            adapter_layers = [
-                "q_proj",
-                "k_proj",
-                "v_proj",
-                "o_proj",
-                "gate_proj",
-                "up_proj",
-                "down_proj",
-                "qkv_proj",
-            ]
 
-            for layer_name in adapter_layers:
-                nlayers = (
-                    1 if layer_name == "lm_head" else len(model.model.model.layers)
-                )
-                adapter_weights = LoraWeights.prepare_weights(
-                    config=adapter_config,
-                    module_map=module_map,
-                    layer_type=layer_name,
-                    unused_weight_names=unused_weight_names,
-                    nlayers=nlayers,
-                    dtype=model.dtype,
-                    world_size=model.world_size,
-                    process_group=model.process_group,
-                    target_to_layer=target_to_layer,
-                )
+def load_lora_adapter(
+    model: Model,
+    adapter: AdapterInfo,
+    adapter_index: int,
+    target_to_layer: Optional[Dict] = None,
+):
+    """Load the weights of the LoRA `adapter` in the layers of `model`, under
+    `adapter_index`"""
+    add_lora_adapter(
+        model,
+        adapter_index,
+        prepare_lora_adapter(model, adapter, adapter_index, target_to_layer),
+    )
 
-                if adapter_weights is None:
-                    continue
 
-                model.layer_to_adapter_weights[layer_name].add_adapter(
-                    adapter_index, adapter_weights
-                )
+@dataclass
+class PreparedLoraAdapter:
+    """Weights of a LoRA adapter, read but not used by the layers of the model yet"""
 
-            if len(unused_weight_names) > 0:
-                logger.warning(
-                    f"{','.join([a.id for a in lora_adapters])} unused adapter weights: {unused_weight_names}"
-                )
+    layer_weights: Dict[str, AdapterWeights]
+    tokenizer: Optional[PreTrainedTokenizerBase]
 
-            if adapter_tokenizer is not None:
-                model.tokenizers.add_tokenizer(adapter_index, adapter_tokenizer)
 
-            model.loaded_adapters.add(adapter_index)
+def prepare_lora_adapter(
+    model: Model,
+    adapter: AdapterInfo,
+    adapter_index: int,
+    target_to_layer: Optional[Dict] = None,
+) -> PreparedLoraAdapter:
+    """Read the weights of the LoRA `adapter` for the layers of `model`, without changing
+    the model, so that it can run outside of the steps of the batches"""
+    if target_to_layer is None:
+        target_to_layer = build_layer_weight_lookup(model.model)
 
-    return model
+    # The AdapterParameters object allows for merging multiple adapters into a single adapter.
+    # At the moment, we only support loading a single adapter into the model, but we keep the
+    # AdapterParameters object for easier extension in the future.
+    adapter_parameters = AdapterParameters(
+        adapter_info=[adapter],
+        # when merging multiple adapters we can weight them differently
+        # if this is not set, all adapters will be weighted equally
+        # see: text_generation_server.utils.merges.strategies for impl
+        weights=None,
+        merge_strategy=0,
+        density=1.0,
+        majority_sign_method=0,
+    )
+
+    logger.info(
+        f"Loading adapter weights into model: {','.join([adapter.id for adapter in adapter_parameters.adapter_info])}"
+    )
+    weight_names = tuple([v[0] for v in target_to_layer.values()])
+    (
+        module_map,
+        adapter_config,
+        adapter_weight_names,
+        adapter_tokenizer,
+    ) = load_and_merge_adapters(
+        model.model_id,
+        adapter_parameters,
+        adapter_index,
+        weight_names,
+        False,
+    )
+
+    unused_weight_names = adapter_weight_names.copy()
+    layer_weights = {}
+
+    adapter_layers = [
+        "q_proj",
+        "k_proj",
+        "v_proj",
+        "o_proj",
+        "gate_proj",
+        "up_proj",
+        "down_proj",
+        "qkv_proj",
+    ]
+
+    for layer_name in adapter_layers:
+        nlayers = 1 if layer_name == "lm_head" else len(model.model.model.layers)
+        adapter_weights = LoraWeights.prepare_weights(
+            config=adapter_config,
+            module_map=module_map,
+            layer_type=layer_name,
+            unused_weight_names=unused_weight_names,
+            nlayers=nlayers,
+            dtype=model.dtype,
+            world_size=model.world_size,
+            process_group=model.process_group,
+            target_to_layer=target_to_layer,
+        )
+
+        if adapter_weights is None:
+            continue
+
+        layer_weights[layer_name] = adapter_weights
+
+    if len(unused_weight_names) > 0:
+        logger.warning(f"{adapter.id} unused adapter weights: {unused_weight_names}")
+
+    return PreparedLoraAdapter(layer_weights=layer_weights, tokenizer=adapter_tokenizer)
+
+
+def add_lora_adapter(model: Model, adapter_index: int, prepared: PreparedLoraAdapter):
+    """Use the prepared weights of a LoRA adapter in the layers of `model`, under
+    `adapter_index`"""
+    for layer_name, adapter_weights in prepared.layer_weights.items():
+        model.layer_to_adapter_weights[layer_name].add_adapter(
+            adapter_index, adapter_weights
+        )
+
+    if prepared.tokenizer is not None:
+        model.tokenizers.add_tokenizer(adapter_index, prepared.tokenizer)
+
+    model.loaded_adapters.add(adapter_index)
@@ -682,6 +682,40 @@ class CausalLM(Model):
             speculative_logits = None
         return outputs.logits, speculative_logits, outputs.past_key_values
 
+    @tracer.start_as_current_span("embed")
+    def embed(self, input_ids: List[List[int]]) -> List[List[float]]:
+        max_length = max(len(ids) for ids in input_ids)
+        padded_input_ids = torch.full(
+            (len(input_ids), max_length),
+            self.tokenizer.pad_token_id,
+            dtype=torch.int64,
+            device=self.device,
+        )
+        attention_mask = torch.zeros_like(padded_input_ids)
+        for i, ids in enumerate(input_ids):
+            padded_input_ids[i, : len(ids)] = torch.tensor(ids, device=self.device)
+            attention_mask[i, : len(ids)] = 1
+
+        kwargs = {
+            "input_ids": padded_input_ids,
+            "attention_mask": attention_mask,
+            "use_cache": False,
+            "output_hidden_states": True,
+            "return_dict": True,
+        }
+        if self.has_position_ids:
+            kwargs["position_ids"] = (attention_mask.cumsum(-1) - 1).clamp(min=0)
+
+        outputs = self.model.forward(**kwargs)
+        if isinstance(outputs, tuple):
+            outputs, _ = outputs
+        hidden_states = outputs.hidden_states[-1]
+
+        # Mean pooling over the tokens of every input
+        mask = attention_mask.unsqueeze(-1).to(hidden_states.dtype)
+        embeddings = (hidden_states * mask).sum(dim=1) / mask.sum(dim=1)
+        return embeddings.float().tolist()
+
     @tracer.start_as_current_span("generate_token")
     def generate_token(
         self, batch: CausalLMBatch
@@ -741,6 +775,11 @@ class CausalLM(Model):
             top_token_ids,
             top_token_logprobs,
         ) in enumerate(iterator):
+            # Tokens cannot end the generation before `min_new_tokens`
+            suppressed_token_ids = stopping_criteria.suppressed_token_ids()
+            if suppressed_token_ids:
+                logits[-1, suppressed_token_ids] = -float("inf")
+
             # Select next token
             next_token_id, logprobs = next_token_chooser(
                 all_input_ids.view(1, -1), logits[-1:, :]
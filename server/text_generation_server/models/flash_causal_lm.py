@@ -39,6 +39,7 @@ from text_generation_server.utils.prefill_chunking import (
     get_max_prefill_tokens,
 )
 from text_generation_server.utils.tokens import batch_top_tokens
+from text_generation_server.utils.logits_process import classifier_free_guidance
 from text_generation_server.utils.speculate import get_speculate
 from text_generation_server.utils import (
     initialize_torch_distributed,
@@ -57,8 +58,9 @@ from text_generation_server.models.globals import (
     ATTENTION,
     BLOCK_SIZE,
     CUDA_GRAPHS,
-    REQUEST_LOGPROBS,
     TGI_WIGGLE_ROOM,
+    KV_CACHE_SWAP_SPACE,
+    PREFIX_CACHING,
     get_adapter_to_index,
 )
 from text_generation_server.layers.attention import KVCache, Seqlen
@@ -293,10 +295,6 @@ class FlashCausalLMBatch(Batch):
         for i, (r, tokenized_input) in enumerate(
             zip(pb.requests, batch_tokenized_inputs)
         ):
-            ### XXX: This consumes so much memory on long requests
-            ### Deactivating it by default seems like the best course.
-            if not REQUEST_LOGPROBS:
-                r.prefill_logprobs = False
             # request id -> idx in list mapping
             requests_idx_mapping[r.id] = i
 
@@ -949,6 +947,94 @@ class FlashCausalLMBatch(Batch):
             adapter_meta=adapter_meta,
         )
 
+    def set_draft_tokens(self, draft_tokens: Dict[int, List[int]]):
+        """Verify the tokens drafted by the router instead of the n-gram speculation.
+        Drafts shorter than the speculation only replace its first tokens."""
+        if self.speculative_ids is None:
+            return
+        speculate = self.speculative_ids.shape[1]
+        indices = []
+        positions = []
+        ids = []
+        for request_id, draft in draft_tokens.items():
+            idx = self.requests_idx_mapping.get(request_id)
+            if idx is None:
+                continue
+            draft = draft[:speculate]
+            indices.extend([idx] * len(draft))
+            positions.extend(range(len(draft)))
+            ids.extend(draft)
+        if not ids:
+            return
+        device = self.speculative_ids.device
+        self.speculative_ids[
+            torch.tensor(indices, device=device),
+            torch.tensor(positions, device=device),
+        ] = torch.tensor(ids, dtype=self.speculative_ids.dtype, device=device)
+
+    def guidance_indices(
+        self,
+    ) -> Optional[Tuple[torch.Tensor, torch.Tensor, torch.Tensor]]:
+        """Indices of the requests using classifier-free guidance, indices of their negative
+        sequences and guidance scales, None if no request of the batch is guided"""
+        guided = []
+        negative = []
+        scales = []
+        for i, request in enumerate(self.requests):
+            if not request.HasField("guidance"):
+                continue
+            negative_idx = self.requests_idx_mapping.get(request.guidance.negative_id)
+            if negative_idx is None:
+                continue
+            guided.append(i)
+            negative.append(negative_idx)
+            scales.append(request.guidance.scale)
+        if not guided:
+            return None
+        device = self.all_input_ids_tensor.device
+        return (
+            torch.tensor(guided, dtype=torch.int64, device=device),
+            torch.tensor(negative, dtype=torch.int64, device=device),
+            torch.tensor(scales, dtype=torch.float32, device=device),
+        )
+
+    def suppressed_tokens(
+        self, rows_per_request: int
+    ) -> Optional[Tuple[torch.Tensor, torch.Tensor]]:
+        """Rows of the logits and ids of the tokens that would end the generations before
+        their `min_new_tokens`, None if there are none. The speculative rows of a request
+        are all suppressed like its first one.
+
+        The tokens suppressed for a request do not change until it reaches its
+        `min_new_tokens`, so the tensors are only built again when a request of the batch
+        reaches it. `filter` and `concatenate` build batches without them."""
+        suppressing = tuple(
+            i
+            for i, stopping_criteria in enumerate(self.stopping_criterias)
+            if stopping_criteria.current_tokens < stopping_criteria.min_new_tokens
+        )
+        key = (rows_per_request, suppressing)
+        cached = getattr(self, "_suppressed_tokens", None)
+        if cached is not None and cached[0] == key:
+            return cached[1]
+
+        rows = []
+        token_ids = []
+        for i in suppressing:
+            suppressed = self.stopping_criterias[i].suppressed_token_ids()
+            for row in range(i * rows_per_request, (i + 1) * rows_per_request):
+                rows.extend([row] * len(suppressed))
+                token_ids.extend(suppressed)
+        suppressed_tokens = None
+        if rows:
+            device = self.all_input_ids_tensor.device
+            suppressed_tokens = (
+                torch.tensor(rows, dtype=torch.int64, device=device),
+                torch.tensor(token_ids, dtype=torch.int64, device=device),
+            )
+        self._suppressed_tokens = (key, suppressed_tokens)
+        return suppressed_tokens
+
     def prepare_for_prefill(self):
         # Prepare values if we need to continue prefilling
         # Speculation must be ignored while we prefill even with chunking
@@ -1274,6 +1360,7 @@ class FlashCausalLM(Model):
         )
         config.quantize = quantize
         config.speculator = speculator
+        self.speculator = speculator
 
         torch.distributed.barrier(group=self.process_group)
 
@@ -1332,8 +1419,20 @@ class FlashCausalLM(Model):
 
         self.cuda_graphs = {}
         self.kv_cache = []
+        # Positions every request advanced by since its last generated token, when the
+        # model drafts the tokens of other shards
+        self.drafted: Dict[int, int] = {}
         self.kv_cache_dtype = dtype if kv_cache_dtype is None else kv_cache_dtype
 
+        # Blocks of the preempted requests are swapped to host memory
+        swap_blocks = 0
+        if PREFIX_CACHING and KV_CACHE_SWAP_SPACE > 0:
+            block_bytes = BLOCK_SIZE * self.kv_bytes_per_token
+            swap_blocks = int(KV_CACHE_SWAP_SPACE * 1024**3) // block_bytes
+            log_master(
+                logger.info, f"Using {swap_blocks} host blocks for KV cache swapping"
+            )
+
         if ATTENTION == "flashinfer":
             from text_generation_server.layers.attention.flashinfer import (
                 create_prefill_state,
@@ -1363,12 +1462,34 @@ class FlashCausalLM(Model):
             world_size=world_size,
             sliding_window=config.sliding_window,
             support_chunking=support_chunking,
+            swap_blocks=swap_blocks,
         )
 
     @property
     def batch_type(self) -> Type[FlashCausalLMBatch]:
         return FlashCausalLMBatch
 
+    @property
+    def accepts_draft_tokens(self) -> bool:
+        # Medusa and mlp speculator heads propose their own tokens
+        return self.speculate > 0 and self.speculator is None
+
+    @property
+    def supports_guidance(self) -> bool:
+        # The negative sequences are batched as requests, one row of logits each
+        return True
+
+    @property
+    def support_embeddings(self) -> bool:
+        # The flash models only return the logits of their last layer, which cannot be pooled
+        return False
+
+    @property
+    def kv_bytes_per_token(self) -> int:
+        # Keys and values of every layer
+        dtype_size = torch.tensor([], dtype=self.kv_cache_dtype).element_size()
+        return 2 * self.num_layers * self.num_kv_heads * self.head_size * dtype_size
+
     def max_past(self) -> int:
         return getattr(self.model, "max_past", None)
 
@@ -1393,6 +1514,38 @@ class FlashCausalLM(Model):
             )
             for _ in range(num_layers)
         ]
+        if self.swap_blocks > 0:
+            for layer in self.kv_cache:
+                layer.init_host_cache(self.swap_blocks)
+
+    def swap_out(self, device_blocks: List[int], host_blocks: List[int]):
+        device_blocks = torch.tensor(device_blocks, dtype=torch.int64)
+        host_blocks = torch.tensor(host_blocks, dtype=torch.int64)
+        for layer in self.kv_cache:
+            layer.swap_out(device_blocks, host_blocks)
+
+    def swap_in(self, host_blocks: List[int], device_blocks: List[int]):
+        host_blocks = torch.tensor(host_blocks, dtype=torch.int64)
+        device_blocks = torch.tensor(device_blocks, dtype=torch.int64)
+        for layer in self.kv_cache:
+            layer.swap_in(host_blocks, device_blocks)
+
+    def export_kv(self, blocks: List[int]) -> Iterable[bytes]:
+        blocks = torch.tensor(blocks, dtype=torch.int64)
+        for layer in self.kv_cache:
+            yield layer.export_blocks(blocks)
+
+    def import_kv(self, blocks: List[int], layer: int, data: bytes):
+        if layer >= len(self.kv_cache):
+            raise ValueError(
+                f"Expected the KV of {len(self.kv_cache)} layers, got layer {layer}"
+            )
+        blocks = torch.tensor(blocks, dtype=torch.int64)
+        self.kv_cache[layer].import_blocks(blocks, data)
+
+    @property
+    def kv_layers(self) -> int:
+        return len(self.kv_cache)
 
     def cuda_graph_warmup(self, bs: int, max_s: int, max_bt: int):
         max_bs = max(self.cuda_graphs.keys()) if self.cuda_graphs else None
@@ -1538,6 +1691,7 @@ class FlashCausalLM(Model):
         batch: FlashCausalLMBatch,
         max_input_tokens: Optional[int],
         max_total_tokens: Optional[int],
+        max_batch_total_tokens: Optional[int] = None,
     ):
         # The warmup batch is the biggest batch we could ever receive
         self.kv_cache = []
@@ -1545,51 +1699,62 @@ class FlashCausalLM(Model):
 
         # Inspired by the original implementation in [vllm](https://github.com/vllm-project/vllm)
         # Calculate the number of blocks that can be allocated with the free memory
-        dtype_size = torch.tensor([], dtype=self.kv_cache_dtype).element_size()
-        cache_block_size = BLOCK_SIZE * self.num_kv_heads * self.head_size
-        total_cache_size = self.num_layers * cache_block_size * 2 * dtype_size
+        total_cache_size = BLOCK_SIZE * self.kv_bytes_per_token
 
-        try:
-            self.init_kv_cache(
-                batch.num_blocks,
-                self.num_layers,
-                self.num_kv_heads,
-                self.head_size,
-                self.kv_cache_dtype,
-                self.device,
+        if max_batch_total_tokens is not None:
+            # Memory probing failed on a previous warmup, trust the router
+            log_master(
+                logger.warning,
+                f"Skipping memory probing, allocating the KV cache for {max_batch_total_tokens} tokens",
             )
+            num_blocks = (max_batch_total_tokens + BLOCK_SIZE - 1) // BLOCK_SIZE
+        else:
+            try:
+                self.init_kv_cache(
+                    batch.num_blocks,
+                    self.num_layers,
+                    self.num_kv_heads,
+                    self.head_size,
+                    self.kv_cache_dtype,
+                    self.device,
+                )
 
-            batch_num_blocks = batch.num_blocks
+                batch_num_blocks = batch.num_blocks
+
+                num_tokens = batch.to_pb().current_tokens
+                if SYSTEM == "rocm" and os.environ.get(
+                    "PYTORCH_TUNABLEOP_ENABLED", False
+                ):
+                    torch.cuda.tunable.tuning_enable(False)
+                synchronize(self.device)
+                free_memory = get_free_memory(
+                    self.device, MEMORY_FRACTION * TGI_WIGGLE_ROOM
+                )
+                real_free_memory = get_free_memory(self.device, MEMORY_FRACTION)
+                log_master(
+                    logger.debug,
+                    f"Free memory {free_memory/1e9:.2f}GB , (real: {real_free_memory/1e9:.2f}GB",
+                )
+
+                _, _batch, _ = self.generate_token(batch)
+            except torch.cuda.OutOfMemoryError as e:
+                raise RuntimeError(
+                    f"Not enough memory to handle {num_tokens} prefill tokens. "
+                    f"You need to decrease `--max-batch-prefill-tokens`"
+                ) from e
 
-            num_tokens = batch.to_pb().current_tokens
-            if SYSTEM == "rocm" and os.environ.get("PYTORCH_TUNABLEOP_ENABLED", False):
-                torch.cuda.tunable.tuning_enable(False)
             synchronize(self.device)
             free_memory = get_free_memory(
                 self.device, MEMORY_FRACTION * TGI_WIGGLE_ROOM
             )
-            real_free_memory = get_free_memory(self.device, MEMORY_FRACTION)
-            log_master(
-                logger.debug,
-                f"Free memory {free_memory/1e9:.2f}GB , (real: {real_free_memory/1e9:.2f}GB",
+            kv_memory = free_memory
+            num_blocks = (
+                # Leave 5% for some wiggle room
+                int(kv_memory // total_cache_size)
+                # Add batch.num_blocks as we allocated it above, so it is included in the peak memory.
+                + batch_num_blocks
             )
-
-            _, _batch, _ = self.generate_token(batch)
-        except torch.cuda.OutOfMemoryError as e:
-            raise RuntimeError(
-                f"Not enough memory to handle {num_tokens} prefill tokens. "
-                f"You need to decrease `--max-batch-prefill-tokens`"
-            ) from e
-
-        synchronize(self.device)
-        free_memory = get_free_memory(self.device, MEMORY_FRACTION * TGI_WIGGLE_ROOM)
-        kv_memory = free_memory
-        num_blocks = (
-            # Leave 5% for some wiggle room
-            int(kv_memory // total_cache_size)
-            # Add batch.num_blocks as we allocated it above, so it is included in the peak memory.
-            + batch_num_blocks
-        )
+            del _batch
 
         log_master(logger.info, f"KV-cache blocks: {num_blocks}, size: {BLOCK_SIZE}")
         if max_total_tokens is None:
@@ -1605,7 +1770,7 @@ class FlashCausalLM(Model):
         if max_input_tokens is None:
             max_input_tokens = max_total_tokens - 1
 
-        del _batch, batch
+        del batch
         self.kv_cache = []
         empty_cache()
 
@@ -2022,6 +2187,21 @@ class FlashCausalLM(Model):
             batch.prefilling_mask = next_prefilling_mask
 
         speculate = get_speculate()
+        # Step of the next token of every request, previous rounds included, so that a
+        # request samples the same tokens however it is batched or interrupted
+        sampling_steps = [
+            request.parameters.sampling_step + stopping_criteria.current_tokens
+            for request, stopping_criteria in zip(
+                batch.requests, batch.stopping_criterias
+            )
+        ]
+        # Guided requests are not speculated, they have a single row of logits
+        guidance = batch.guidance_indices()
+        if guidance is not None:
+            next_token_logits = classifier_free_guidance(next_token_logits, *guidance)
+        suppressed = batch.suppressed_tokens(next_token_logits.shape[0] // len(batch))
+        if suppressed is not None:
+            next_token_logits[suppressed] = -float("inf")
         (
             next_input_ids,
             next_token_logprobs,
@@ -2034,7 +2214,13 @@ class FlashCausalLM(Model):
             speculate,
             batch.speculative_ids,
             speculative_logits,
+            sampling_steps=sampling_steps,
+            input_lengths=[len(input_ids) for input_ids in batch.all_input_ids],
         )
+        if guidance is not None:
+            # The negative sequences continue with the tokens of their guided request
+            guided, negative, _ = guidance
+            next_input_ids[negative] = next_input_ids[guided]
 
         batch_top_token_ids, batch_top_token_logprobs = batch_top_tokens(
             batch.top_n_tokens, batch.top_n_tokens_tensor, logprobs, accepted_ids
@@ -2203,6 +2389,13 @@ class FlashCausalLM(Model):
         # Results
         generations: List[Generation] = []
         stopped = True
+        # The negative sequences only guide other requests, the router expects no
+        # generation from them
+        negative_ids = {
+            request.guidance.negative_id
+            for request in batch.requests
+            if request.HasField("guidance")
+        }
 
         # Zipped iterator
         iterator = zip(
@@ -2351,7 +2544,10 @@ class FlashCausalLM(Model):
 
                 # Shard generations
                 # All generations will be appended in the rust sharded client
-                if request.id % self.world_size == self.rank:
+                if (
+                    request.id % self.world_size == self.rank
+                    and request.id not in negative_ids
+                ):
                     if stop:
                         # Decode generated tokens
                         output_text, _, _ = self.decode_token(
@@ -2451,6 +2647,70 @@ class FlashCausalLM(Model):
         decode_ns = time.time_ns() - start_decode
         return generations, batch, (forward_ns, decode_ns)
 
+    @tracer.start_as_current_span("draft")
+    def draft(
+        self,
+        batch: FlashCausalLMBatch,
+        generated: Dict[int, List[int]],
+        tokens: int,
+    ) -> Dict[int, List[int]]:
+        """Catch up with the tokens `generated` by the verifying model, then greedily
+        propose the next `tokens` tokens of every request.
+
+        The batch mirrors the one of the verifying shards, which accept at most the drafted
+        tokens and the one they sample: the requests only go back to the position of their
+        last generated token, whose KV is in the cache if it was drafted. One more step than
+        `tokens` is run to also cache the KV of the last proposed token."""
+        # The next input of every request is its last generated token instead of the last
+        # proposed one
+        offsets = []
+        input_ids = batch.input_ids.tolist()
+        for i, request in enumerate(batch.requests):
+            ids = generated.get(request.id)
+            if ids:
+                offset = len(ids) - self.drafted.get(request.id, 1)
+                input_ids[i] = ids[-1]
+            else:
+                offset = 0
+            batch.cache_lengths[i] += offset
+            offsets.append(offset)
+
+        device = batch.input_ids.device
+        offsets = torch.tensor(offsets, dtype=torch.int32, device=device)
+        batch.input_ids = torch.tensor(
+            input_ids, dtype=batch.input_ids.dtype, device=device
+        )
+        batch.position_ids += offsets.to(batch.position_ids.dtype)
+        batch.cache_lengths_tensor += offsets.to(batch.cache_lengths_tensor.dtype)
+        batch.slot_indices += offsets.to(batch.slot_indices.dtype)
+        batch.max_current_length = max(
+            cache_length + input_length
+            for cache_length, input_length in zip(
+                batch.cache_lengths, batch.input_lengths
+            )
+        )
+
+        adapter_data = AdapterBatchData.from_meta(
+            batch.adapter_meta, self.layer_to_adapter_weights, False, None
+        )
+        proposals = []
+        for _ in range(tokens + 1):
+            out, _ = self.forward(batch, adapter_data)
+            next_input_ids = out.argmax(dim=-1)
+            proposals.append(next_input_ids)
+
+            batch.input_ids = next_input_ids
+            batch.position_ids += 1
+            batch.cache_lengths_tensor += 1
+            batch.slot_indices += 1
+            for i in range(len(batch)):
+                batch.cache_lengths[i] += 1
+            batch.max_current_length += 1
+
+        self.drafted = {request.id: tokens + 1 for request in batch.requests}
+        proposals = torch.stack(proposals, dim=1)[:, :tokens].tolist()
+        return {request.id: proposals[i] for i, request in enumerate(batch.requests)}
+
     def _forward_context(
         self,
         *,
@@ -5,7 +5,6 @@ from typing import Dict, Optional
 
 from text_generation_server.utils.log import log_master
 
-REQUEST_LOGPROBS = os.getenv("REQUEST_LOGPROBS", "0").lower() in {"1", "true"}
 ATTENTION = os.environ["ATTENTION"]
 # default_prefix_caching = "1" if ATTENTION in {"flashinfer", "flashdecoding"} else "0"
 PREFIX_CACHING = os.environ["PREFIX_CACHING"].lower() in {
@@ -24,7 +23,16 @@ if PREFIX_CACHING and ATTENTION not in {"flashinfer", "flashdecoding"}:
     raise RuntimeError("Prefix caching is only supported with flashinfer")
 
 MEM_POOL = torch.cuda.graph_pool_handle() if torch.cuda.is_available() else None
+# GiB of pinned host memory that evicted KV cache blocks are swapped to
+KV_CACHE_SWAP_SPACE = float(os.getenv("KV_CACHE_SWAP_SPACE", "0"))
 TGI_WIGGLE_ROOM = float(os.getenv("TGI_WIGGLE_ROOM", "0.95"))
+DETERMINISTIC = os.getenv("DETERMINISTIC", "0").lower() in {"1", "true"}
+if DETERMINISTIC:
+    # Kernels without a deterministic implementation only warn
+    torch.use_deterministic_algorithms(True, warn_only=True)
+    torch.backends.cudnn.benchmark = False
+    torch.backends.cuda.matmul.allow_tf32 = False
+    log_master(logger.info, "Using deterministic kernels")
 assert TGI_WIGGLE_ROOM > 0
 assert TGI_WIGGLE_ROOM < 1
 
@@ -769,6 +769,11 @@ class IdeficsCausalLM(Model):
             stopping_criteria,
             all_input_ids,
         ) in enumerate(iterator):
+            # Tokens cannot end the generation before `min_new_tokens`
+            suppressed_token_ids = stopping_criteria.suppressed_token_ids()
+            if suppressed_token_ids:
+                logits[-1, suppressed_token_ids] = -float("inf")
+
             # Select next token
             next_token_id, logprobs = next_token_chooser(
                 all_input_ids.view(1, -1), logits[-1:, :]
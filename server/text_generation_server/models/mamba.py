@@ -476,7 +476,11 @@ class Mamba(Model):
         return MambaBatch
 
     def warmup(
-        self, batch, max_input_tokens: Optional[int], max_total_tokens: Optional[int]
+        self,
+        batch,
+        max_input_tokens: Optional[int],
+        max_total_tokens: Optional[int],
+        max_batch_total_tokens: Optional[int] = None,
     ) -> Union[Optional[int], Optional[int], Optional[int]]:
         # TODO: implement warmup for Mamba if needed
         if CUDA_GRAPHS:
@@ -691,6 +695,11 @@ class Mamba(Model):
             top_token_ids,
             top_token_logprobs,
         ) in enumerate(iterator):
+            # Tokens cannot end the generation before `min_new_tokens`
+            suppressed_token_ids = stopping_criteria.suppressed_token_ids()
+            if suppressed_token_ids:
+                logits[-1, suppressed_token_ids] = -float("inf")
+
             # Select next token
             next_token_id, logprobs = next_token_chooser(
                 all_input_ids.view(1, -1), logits[-1:, :]
@@ -2,8 +2,8 @@ import inspect
 import torch
 
 from abc import ABC, abstractmethod
-from typing import List, Tuple, Optional, TypeVar, Type, Dict
-from collections import defaultdict
+from typing import Iterable, List, Tuple, Optional, TypeVar, Type, Dict
+from collections import OrderedDict, defaultdict
 from transformers import PreTrainedTokenizerBase
 from loguru import logger
 
@@ -12,15 +12,20 @@ from text_generation_server.models.globals import (
     PREFIX_CACHING,
     BLOCK_SIZE,
     PREFILL_CHUNKING,
+    get_adapter_to_index,
 )
 from text_generation_server.models.types import Batch, Generation
+from text_generation_server.utils.import_utils import get_kernel_versions
 from text_generation_server.utils.log import log_master
 from text_generation_server.utils.prefill_chunking import set_support_chunking
 from text_generation_server.utils.speculate import get_speculate
-from text_generation_server.pb.generate_pb2 import InfoResponse
+from text_generation_server.pb.generate_pb2 import InfoResponse, LoraAdapter
+from text_generation_server.adapters.lora import LoraWeights
 from text_generation_server.adapters.weights import LayerAdapterWeights
 
 BASE_MODEL_ADAPTER_ID = "__base_model__"
+# Mixtures of adapters kept loaded, past which the least recently used are unloaded
+MAX_ADAPTER_MIXTURES = 16
 
 
 B = TypeVar("B", bound=Batch)
@@ -41,6 +46,7 @@ class Model(ABC):
         speculate: Optional[int] = None,
         adapter_id: str = BASE_MODEL_ADAPTER_ID,
         support_chunking: bool = False,
+        swap_blocks: int = 0,
     ):
         self.model_id = model_id
         self.model = model.eval()
@@ -64,6 +70,10 @@ class Model(ABC):
             LayerAdapterWeights
         )
         self.loaded_adapters = set()
+        # Adapter indices of the adapters being loaded, which no other adapter can take
+        self.reserved_adapter_indices = set()
+        # Adapter index of the mixtures of adapters, least recently used first
+        self.adapter_mixtures: "OrderedDict[str, int]" = OrderedDict()
         self.static_adapter_id = adapter_id
 
         if speculate is None:
@@ -89,6 +99,7 @@ class Model(ABC):
         log_master(logger.info, f"Using prefill chunking = {support_chunking}")
 
         self.support_chunking = support_chunking
+        self.swap_blocks = swap_blocks
         set_support_chunking(support_chunking)
 
         self.has_position_ids = (
@@ -103,6 +114,17 @@ class Model(ABC):
         if self.requires_padding and self.sliding_window is not None:
             raise NotImplementedError("sliding_window is not implemented with padding")
 
+        config = getattr(self, "config", None) or getattr(self.model, "config", None)
+        # The language model of the VLMs has its own configuration
+        text_config = getattr(config, "text_config", None) or config
+        context_length = getattr(config, "max_position_embeddings", None) or getattr(
+            text_config, "max_position_embeddings", None
+        )
+        vocab_size = (
+            getattr(config, "vocab_size", None)
+            or getattr(text_config, "vocab_size", None)
+            or len(self.tokenizer)
+        )
         return InfoResponse(
             requires_padding=self.requires_padding,
             dtype=str(self.dtype),
@@ -113,8 +135,162 @@ class Model(ABC):
             use_prefix_caching=PREFIX_CACHING,
             attention_impl=ATTENTION,
             block_size=BLOCK_SIZE,
+            support_embeddings=self.support_embeddings,
+            swap_blocks=self.swap_blocks,
+            accepts_draft_tokens=self.accepts_draft_tokens,
+            lora_adapters=self.lora_adapters,
+            context_length=context_length,
+            vocab_size=vocab_size,
+            quantize=getattr(self, "quantize", None),
+            kernel_versions=get_kernel_versions(),
+            kv_bytes_per_token=self.kv_bytes_per_token,
+            supports_guidance=self.supports_guidance,
         )
 
+    @property
+    def lora_adapters(self) -> List[LoraAdapter]:
+        """The loaded LoRA adapters that the requests can mix, with their rank and the
+        memory of their weights"""
+        ranks = defaultdict(int)
+        memory = defaultdict(int)
+        for layer_weights in self.layer_to_adapter_weights.values():
+            for index, weights in layer_weights.adapter_weights.items():
+                ranks[index] = max(ranks[index], weights.adapter_config.r)
+                for tensor in (weights.weights_a, weights.weights_b):
+                    memory[index] += tensor.numel() * tensor.element_size()
+        adapter_to_index = get_adapter_to_index() or {}
+        return [
+            LoraAdapter(id=adapter_id, rank=ranks[index], memory_bytes=memory[index])
+            for adapter_id, index in adapter_to_index.items()
+            if index in ranks and adapter_id not in self.adapter_mixtures
+        ]
+
+    def free_adapter_index(self) -> int:
+        """Lowest adapter index that no loaded or loading adapter or mixture uses"""
+        used_indices = set(get_adapter_to_index().values())
+        used_indices.update(self.reserved_adapter_indices)
+        return next(i for i in range(1, len(used_indices) + 2) if i not in used_indices)
+
+    def unload_adapter(self, adapter_id: str, in_use: Iterable[str]):
+        """Unload the LoRA adapter `adapter_id`, unless the requests of the cached
+        batches (`in_use`) use it. The mixtures of the adapter keep their weights."""
+        adapter_to_index = get_adapter_to_index()
+        if adapter_id not in adapter_to_index or adapter_id in self.adapter_mixtures:
+            raise ValueError(f"Unknown LoRA adapter {adapter_id}")
+        if adapter_id in in_use:
+            raise ValueError(f"LoRA adapter {adapter_id} is used by running requests")
+        index = adapter_to_index.pop(adapter_id)
+        for layer_weights in self.layer_to_adapter_weights.values():
+            layer_weights.remove_adapter(index)
+        self.loaded_adapters.discard(index)
+        log_master(logger.info, f"Unloaded the adapter {adapter_id}")
+
+    def load_adapter_mixture(self, adapters, in_use: Iterable[str]) -> str:
+        """Load the mixture of the weighted `adapters` of a request, unless it is loaded
+        already, and return its adapter id. `in_use` are the adapter ids of the requests
+        of the cached batches, whose mixtures cannot be unloaded."""
+        adapter_to_index = get_adapter_to_index()
+        adapters = sorted(adapters, key=lambda adapter: adapter.id)
+        mixture_id = "+".join(
+            f"{adapter.weight:g}*{adapter.id}" for adapter in adapters
+        )
+        if mixture_id in self.adapter_mixtures:
+            self.adapter_mixtures.move_to_end(mixture_id)
+            return mixture_id
+        for adapter in adapters:
+            if (
+                adapter.id not in adapter_to_index
+                or adapter.id in self.adapter_mixtures
+            ):
+                raise ValueError(f"Unknown LoRA adapter {adapter.id}")
+
+        # Past the limit if every mixture is in use, rather than failing the request
+        in_use = set(in_use)
+        unused = [mixture for mixture in self.adapter_mixtures if mixture not in in_use]
+        for mixture in unused:
+            if len(self.adapter_mixtures) < MAX_ADAPTER_MIXTURES:
+                break
+            self.unload_adapter_mixture(mixture)
+
+        index = self.free_adapter_index()
+        weighted = [
+            (adapter_to_index[adapter.id], adapter.weight) for adapter in adapters
+        ]
+        for layer_weights in self.layer_to_adapter_weights.values():
+            weights = [
+                (layer_weights.adapter_weights[i], weight)
+                for i, weight in weighted
+                if i in layer_weights.adapter_weights
+            ]
+            if weights:
+                mixture = LoraWeights.mix(weights, self.world_size)
+                layer_weights.add_adapter(index, mixture)
+        adapter_to_index[mixture_id] = index
+        self.loaded_adapters.add(index)
+        self.adapter_mixtures[mixture_id] = index
+        log_master(logger.info, f"Loaded the mixture of adapters {mixture_id}")
+        return mixture_id
+
+    def unload_adapter_mixture(self, mixture_id: str):
+        index = self.adapter_mixtures.pop(mixture_id)
+        for layer_weights in self.layer_to_adapter_weights.values():
+            layer_weights.remove_adapter(index)
+        self.loaded_adapters.discard(index)
+        del get_adapter_to_index()[mixture_id]
+
+    @property
+    def support_embeddings(self) -> bool:
+        return type(self).embed is not Model.embed
+
+    @property
+    def accepts_draft_tokens(self) -> bool:
+        """Whether the batches can verify speculative tokens drafted by the router"""
+        return False
+
+    @property
+    def supports_guidance(self) -> bool:
+        """Whether the batches combine the logits of the guided requests with the ones of
+        their negative sequences"""
+        return False
+
+    @property
+    def kv_bytes_per_token(self) -> int:
+        """Bytes of KV cache held by a token, over all the layers, 0 if the KV cache is
+        not paged"""
+        return 0
+
+    def swap_out(self, device_blocks: List[int], host_blocks: List[int]):
+        """Copy KV cache blocks to host memory"""
+        raise NotImplementedError
+
+    def swap_in(self, host_blocks: List[int], device_blocks: List[int]):
+        """Copy KV cache blocks back from host memory"""
+        raise NotImplementedError
+
+    def export_kv(self, blocks: List[int]) -> Iterable[bytes]:
+        """Raw KV cache blocks of every layer, one layer at a time, to copy to another shard"""
+        raise NotImplementedError
+
+    def import_kv(self, blocks: List[int], layer: int, data: bytes):
+        """Write KV cache blocks of a layer exported by another shard"""
+        raise NotImplementedError
+
+    @property
+    def kv_layers(self) -> int:
+        """Number of layers of the KV cache"""
+        raise NotImplementedError
+
+    def draft(
+        self, batch: B, generated: Dict[int, List[int]], tokens: int
+    ) -> Dict[int, List[int]]:
+        """Propose the next `tokens` tokens of every request, as the draft model of other
+        shards"""
+        raise NotImplementedError
+
+    def embed(self, input_ids: List[List[int]]) -> List[List[float]]:
+        """Mean-pooled last hidden state of every input"""
+        raise NotImplementedError
+
     @property
     @abstractmethod
     def batch_type(self) -> Type[B]:
@@ -127,7 +303,11 @@ class Model(ABC):
         raise NotImplementedError
 
     def warmup(
-        self, batch: B, max_input_tokens: Optional[int], max_total_tokens: Optional[int]
+        self,
+        batch: B,
+        max_input_tokens: Optional[int],
+        max_total_tokens: Optional[int],
+        max_batch_total_tokens: Optional[int] = None,
     ) -> Tuple[Optional[int], int, int]:
         self.generate_token(batch)
         total = sum(len(i) for i in batch.input_ids)
@@ -797,6 +797,11 @@ class Seq2SeqLM(Model):
             top_token_ids,
             top_token_logprobs,
         ) in enumerate(iterator):
+            # Tokens cannot end the generation before `min_new_tokens`
+            suppressed_token_ids = stopping_criteria.suppressed_token_ids()
+            if suppressed_token_ids:
+                logits[-1, suppressed_token_ids] = -float("inf")
+
             # Select next token
             next_token_id, logprobs = next_token_chooser(
                 all_decoder_input_ids.view(1, -1), logits[-1:, :]
@@ -6,6 +6,7 @@ import signal
 
 from grpc import aio
 from loguru import logger
+from opentelemetry import trace
 
 from grpc_reflection.v1alpha import reflection
 from pathlib import Path
@@ -13,8 +14,13 @@ from typing import List, Optional
 
 from text_generation_server.cache import Cache
 from text_generation_server.interceptor import ExceptionInterceptor
-from text_generation_server.models import Model, get_model_with_lora_adapters
-from text_generation_server.utils.adapter import AdapterInfo
+from text_generation_server.models import (
+    Model,
+    add_lora_adapter,
+    get_model_with_lora_adapters,
+    prepare_lora_adapter,
+)
+from text_generation_server.utils.adapter import AdapterInfo, download_adapter
 from text_generation_server.utils.prefill_chunking import set_max_prefill_tokens
 
 try:
@@ -37,7 +43,13 @@ except (ImportError, NotImplementedError):
 
 from text_generation_server.pb import generate_pb2_grpc, generate_pb2
 from text_generation_server.tracing import UDSOpenTelemetryAioServerInterceptor
-from text_generation_server.models.globals import set_adapter_to_index
+from text_generation_server.models.globals import (
+    get_adapter_to_index,
+    set_adapter_to_index,
+)
+
+# Size of the messages streaming KV cache blocks to another shard
+KV_CHUNK_SIZE = 1 << 20
 
 
 class SignalHandler:
@@ -67,6 +79,8 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         # Quantize is resolved during model loading
         self.quantize = model.quantize
         self.server_urls = server_urls
+        # Ids of the adapters whose weights are being read by `LoadAdapter`
+        self.loading_adapters = set()
         # For some reason, inference_mode does not work well with GLOO which we use on CPU
         if model.device.type == "cuda":
             # Force inference mode for the lifetime of TextGenerationService
@@ -99,6 +113,69 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
 
         return generate_pb2.FilterBatchResponse(batch=filtered_batch.to_pb())
 
+    async def Embed(self, request, context):
+        if any(len(i.input_ids) == 0 for i in request.inputs):
+            raise ValueError("Cannot embed an empty input")
+        if len(request.inputs) == 0:
+            return generate_pb2.EmbedResponse(embeddings=[])
+        embeddings = self.model.embed([list(i.input_ids) for i in request.inputs])
+        return generate_pb2.EmbedResponse(
+            embeddings=[
+                generate_pb2.Embedding(id=i.id, values=values)
+                for i, values in zip(request.inputs, embeddings)
+            ]
+        )
+
+    async def SwapOut(self, request, context):
+        self.model.swap_out(
+            [swap.device_block for swap in request.swaps],
+            [swap.host_block for swap in request.swaps],
+        )
+        return generate_pb2.SwapResponse()
+
+    async def SwapIn(self, request, context):
+        self.model.swap_in(
+            [swap.host_block for swap in request.swaps],
+            [swap.device_block for swap in request.swaps],
+        )
+        return generate_pb2.SwapResponse()
+
+    async def ExportKv(self, request, context):
+        for layer, data in enumerate(self.model.export_kv(list(request.blocks))):
+            for start in range(0, len(data), KV_CHUNK_SIZE):
+                yield generate_pb2.KvChunk(
+                    layer=layer, data=data[start : start + KV_CHUNK_SIZE]
+                )
+
+    async def ImportKv(self, request_iterator, context):
+        blocks = []
+        layer = None
+        data = bytearray()
+        imported = 0
+        async for request in request_iterator:
+            # Only the first message names the blocks
+            if request.blocks:
+                blocks = list(request.blocks)
+            if not request.HasField("chunk"):
+                continue
+            # The layers are exported in order, each one is written once all of its
+            # chunks arrived instead of holding the whole KV
+            if request.chunk.layer != layer:
+                if layer is not None:
+                    self.model.import_kv(blocks, layer, data)
+                    imported += 1
+                layer = request.chunk.layer
+                data = bytearray()
+            data.extend(request.chunk.data)
+        if layer is not None:
+            self.model.import_kv(blocks, layer, data)
+            imported += 1
+        if imported != self.model.kv_layers:
+            raise ValueError(
+                f"Expected the KV of {self.model.kv_layers} layers, got {imported}"
+            )
+        return generate_pb2.ImportKvResponse()
+
     async def Warmup(self, request, context):
         set_max_prefill_tokens(request.max_prefill_tokens)
 
@@ -140,8 +217,15 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         max_total_tokens = (
             request.max_total_tokens if request.HasField("max_total_tokens") else None
         )
+        max_batch_total_tokens = (
+            request.max_batch_total_tokens
+            if request.HasField("max_batch_total_tokens")
+            else None
+        )
         max_supported_total_tokens, max_input_tokens, max_total_tokens = (
-            self.model.warmup(batch, max_input_tokens, max_total_tokens)
+            self.model.warmup(
+                batch, max_input_tokens, max_total_tokens, max_batch_total_tokens
+            )
         )
 
         return generate_pb2.WarmupResponse(
@@ -152,6 +236,7 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
 
     async def Prefill(self, request, context):
         start = time.time_ns()
+        self.load_adapter_mixtures(request.batch)
         if (
             self.model.batch_type in VLM_BATCH_TYPES
         ):  # Hack, i would rather use kwargs in the `from_pb` call
@@ -182,6 +267,7 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
 
         generations, next_batch, timings = self.model.generate_token(batch)
         self.cache.set(next_batch)
+        set_span_attributes(batch, generations)
 
         return generate_pb2.PrefillResponse(
             generations=[generation.to_pb() for generation in generations],
@@ -192,6 +278,65 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
             concat_ns=concat_ns,
         )
 
+    def adapters_in_use(self):
+        """Adapter ids of the requests of the cached batches"""
+        return {
+            r.adapter_id
+            for cached_batch in self.cache.cache.values()
+            for r in getattr(cached_batch, "requests", [])
+        }
+
+    def load_adapter_mixtures(self, batch):
+        """Load the adapters mixed by the requests of a new batch, which then use them by
+        their adapter id"""
+        in_use = self.adapters_in_use()
+        for r in batch.requests:
+            if r.adapters:
+                r.adapter_id = self.model.load_adapter_mixture(r.adapters, in_use)
+                in_use.add(r.adapter_id)
+
+    async def LoadAdapter(self, request, context):
+        if request.id in get_adapter_to_index() or request.id in self.loading_adapters:
+            raise ValueError(f"LoRA adapter {request.id} is already loaded")
+        # The captured graphs do not apply the adapters
+        if getattr(self.model, "cuda_graphs", None):
+            raise ValueError(
+                "LoRA adapters cannot be loaded at runtime with CUDA graphs, "
+                "start the server with `--cuda-graphs 0`"
+            )
+        adapter = AdapterInfo(
+            id=request.id,
+            path=request.path if request.HasField("path") else None,
+            revision=request.revision if request.HasField("revision") else None,
+        )
+        index = self.model.free_adapter_index()
+        self.model.reserved_adapter_indices.add(index)
+        self.loading_adapters.add(adapter.id)
+        try:
+            # Downloaded and read outside of the event loop, which keeps serving the
+            # steps of the batches meanwhile
+            prepared = await asyncio.get_running_loop().run_in_executor(
+                None, self.prepare_adapter, adapter, index
+            )
+        finally:
+            self.model.reserved_adapter_indices.discard(index)
+            self.loading_adapters.discard(adapter.id)
+        # The steps of the batches do not yield to the event loop, so the layers get the
+        # weights between two of them
+        add_lora_adapter(self.model, index, prepared)
+        get_adapter_to_index()[adapter.id] = index
+        loaded = next(a for a in self.model.lora_adapters if a.id == adapter.id)
+        return generate_pb2.LoadAdapterResponse(adapter=loaded)
+
+    def prepare_adapter(self, adapter: AdapterInfo, index: int):
+        if adapter.path is None:
+            download_adapter(adapter)
+        return prepare_lora_adapter(self.model, adapter, index)
+
+    async def UnloadAdapter(self, request, context):
+        self.model.unload_adapter(request.id, self.adapters_in_use())
+        return generate_pb2.UnloadAdapterResponse()
+
     async def Decode(self, request, context):
         start = time.time_ns()
         if len(request.batches) == 0:
@@ -215,8 +360,14 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
             batch = batches[0]
             concat_ns = None
 
+        if request.draft_tokens and self.model.accepts_draft_tokens:
+            batch.set_draft_tokens(
+                {draft.request_id: list(draft.ids) for draft in request.draft_tokens}
+            )
+
         generations, next_batch, timings = self.model.generate_token(batch)
         self.cache.set(next_batch)
+        set_span_attributes(batch, generations)
 
         return generate_pb2.DecodeResponse(
             generations=[generation.to_pb() for generation in generations],
@@ -227,6 +378,51 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
             total_ns=time.time_ns() - start,
         )
 
+    async def Draft(self, request, context):
+        if len(request.batches) == 0:
+            raise ValueError("Must provide at least one batch")
+
+        batches = []
+        for batch_pb in request.batches:
+            batch = self.cache.pop(batch_pb.id)
+            if batch is None:
+                raise ValueError(f"Batch ID {batch_pb.id} not found in cache.")
+            batches.append(batch)
+
+        # Concatenated like the batches of the verifying shards, under the same ID
+        if len(batches) > 1:
+            batch = self.model.batch_type.concatenate(batches)
+        else:
+            batch = batches[0]
+
+        drafts = self.model.draft(
+            batch,
+            {tokens.request_id: list(tokens.ids) for tokens in request.generated},
+            request.tokens,
+        )
+        self.cache.set(batch)
+
+        return generate_pb2.DraftResponse(
+            drafts=[
+                generate_pb2.DraftTokens(request_id=request_id, ids=ids)
+                for request_id, ids in drafts.items()
+            ]
+        )
+
+
+def set_span_attributes(batch, generations):
+    """Add the batch and its generated tokens to the span of the current gRPC call"""
+    span = trace.get_current_span()
+    span.set_attribute("batch.id", batch.batch_id)
+    span.set_attribute("batch.size", len(batch))
+    span.set_attribute(
+        "batch.request_ids", [generation.request_id for generation in generations]
+    )
+    span.set_attribute(
+        "batch.generated_tokens",
+        sum(len(generation.tokens.token_ids) for generation in generations),
+    )
+
 
 def serve(
     model_id: str,
@@ -1,6 +1,6 @@
 import grpc
 
-from opentelemetry import trace
+from opentelemetry import propagate, trace
 from opentelemetry.exporter.otlp.proto.grpc.trace_exporter import OTLPSpanExporter
 from opentelemetry.instrumentation.grpc._aio_server import (
     OpenTelemetryAioServerInterceptor,
@@ -46,8 +46,12 @@ class UDSOpenTelemetryAioServerInterceptor(OpenTelemetryAioServerInterceptor):
         # We use gRPC over a UNIX socket
         attributes.update({SpanAttributes.NET_TRANSPORT: "unix"})
 
+        # Continue the trace of the router, propagated in the `traceparent` metadata
+        parent = propagate.extract(metadata)
+
         return self._tracer.start_as_current_span(
             name=handler_call_details.method,
+            context=parent,
             kind=trace.SpanKind.SERVER,
             attributes=attributes,
             set_status_on_exception=set_status_on_exception,
@@ -68,6 +68,14 @@ def parse_lora_adapters(lora_adapters: Optional[str]) -> List[AdapterInfo]:
     return adapter_list
 
 
+def download_adapter(adapter: AdapterInfo):
+    """Download the weights of an adapter of the Hub, unless they are cached already"""
+    filenames = hub.weight_hub_files(
+        adapter.id, revision=adapter.revision, extension=".safetensors"
+    )
+    hub.download_weights(filenames, adapter.id, revision=adapter.revision)
+
+
 def load_and_merge_adapters(
     model_id: str,
     adapter_parameters: AdapterParameters,
@@ -3,13 +3,37 @@ from loguru import logger
 import os
 
 
+import importlib.metadata
 import importlib.util
 
+# Packages of the kernels that the models can run, reported with the shard info
+KERNEL_PACKAGES = [
+    "torch",
+    "attention-kernels",
+    "exllamav2",
+    "flash-attn",
+    "flashinfer",
+    "marlin-kernels",
+    "moe-kernels",
+    "vllm",
+]
+
 
 def is_ipex_available():
     return importlib.util.find_spec("intel_extension_for_pytorch") is not None
 
 
+def get_kernel_versions():
+    """Versions of the installed kernel packages, by package name"""
+    versions = {}
+    for package in KERNEL_PACKAGES:
+        try:
+            versions[package] = importlib.metadata.version(package)
+        except importlib.metadata.PackageNotFoundError:
+            pass
+    return versions
+
+
 def get_cuda_free_memory(device, memory_fraction):
     total_free_memory, _ = torch.cuda.mem_get_info(device)
     total_gpu_memory = torch.cuda.get_device_properties(device).total_memory
@@ -0,0 +1,294 @@
+"""Grammar of the `json_object` response format: any JSON object, enforced by an
+incremental JSON parser instead of a compiled schema."""
+
+import bisect
+import os
+import time
+from collections import OrderedDict
+from typing import Dict, List, Optional, Tuple
+
+import torch
+from loguru import logger
+from outlines.fsm.guide import Generate
+
+# Longest run of whitespace outside of the strings, so that models cannot loop on it
+MAX_WHITESPACE = 32
+# Open brackets that the masks depend on. Tokens closing more brackets than this at
+# once are refused, the model can still close them one token at a time.
+MASK_DEPTH = 4
+# Bottom of the stack of the masks, for the brackets below the `MASK_DEPTH` last ones
+DEEPER = "*"
+# Parser states kept by a guide, the least recently used ones are forgotten past it. The
+# states of the running requests are used at every step, so they are never the oldest.
+MAX_STATES = 100_000
+
+WHITESPACE = " \t\n\r"
+DIGITS = "0123456789"
+HEX_DIGITS = "0123456789abcdefABCDEF"
+ESCAPES = '"\\/bfnrt'
+LITERALS = {"t": "rue", "f": "alse", "n": "ull"}
+# Positions between tokens of the structure, where whitespace is allowed
+SEPARATED = {"start", "value", "object_first", "key", "colon", "array_first", "after"}
+
+# Parser state: mode, auxiliary value of the mode and stack of the open brackets
+State = Tuple[str, object, str]
+
+# Characters that are only ever part of strings
+ORDINARY = "g"
+
+
+def _character_classes() -> Dict[str, str]:
+    """Characters that the parser treats alike, mapped to the first one of their class"""
+    classes = {chr(code): "\x00" for code in range(0x20)}
+    for cls in ["\n\t\r", "123456789", "cd", "ABCDF"]:
+        classes.update((ch, cls[0]) for ch in cls)
+    classes.update((ch, ch) for ch in ' {}[]":,\\/-+.0abefEtrunls')
+    return classes
+
+
+CLASS_OF = _character_classes()
+
+
+def token_class(text: str) -> str:
+    """Text accepted by the parser in the same states as `text`"""
+    return "".join(CLASS_OF.get(ch, ORDINARY) for ch in text)
+
+
+def _close(stack: str) -> State:
+    stack = stack[:-1]
+    if not stack:
+        return ("done", 0, stack)
+    return ("after", 0, stack)
+
+
+def _value(ch: str, stack: str) -> Optional[State]:
+    if ch == "{":
+        return ("object_first", 0, stack + "{")
+    if ch == "[":
+        return ("array_first", 0, stack + "[")
+    if ch == '"':
+        return ("string", 0, stack)
+    if ch == "-":
+        return ("minus", 0, stack)
+    if ch == "0":
+        return ("zero", 0, stack)
+    if ch in DIGITS:
+        return ("int", 0, stack)
+    if ch in LITERALS:
+        return ("literal", LITERALS[ch], stack)
+    return None
+
+
+def _string(ch: str, stack: str, prefix: str, end: State) -> Optional[State]:
+    """Character of a string (`prefix` is empty) or of a key (`prefix` is `key_`)"""
+    if ch == '"':
+        return end
+    if ch == "\\":
+        return (prefix + "escape", 0, stack)
+    if ch < " ":
+        return None
+    return (prefix + "string", 0, stack)
+
+
+def step(state: State, ch: str) -> Optional[State]:
+    """State after character `ch`, None if the text cannot start a JSON object"""
+    mode, aux, stack = state
+    if mode in SEPARATED and ch in WHITESPACE:
+        if aux >= MAX_WHITESPACE:
+            return None
+        return (mode, aux + 1, stack)
+
+    if mode == "start":
+        return ("object_first", 0, "{") if ch == "{" else None
+    if mode == "value":
+        return _value(ch, stack)
+    if mode == "array_first":
+        return _close(stack) if ch == "]" else _value(ch, stack)
+    if mode == "object_first" or mode == "key":
+        if ch == '"':
+            return ("key_string", 0, stack)
+        return _close(stack) if ch == "}" and mode == "object_first" else None
+    if mode == "colon":
+        return ("value", 0, stack) if ch == ":" else None
+    if mode == "after":
+        top = stack[-1]
+        if ch == ",":
+            if top == "{":
+                return ("key", 0, stack)
+            return ("value", 0, stack) if top == "[" else None
+        if (ch == "}" and top == "{") or (ch == "]" and top == "["):
+            return _close(stack)
+        return None
+
+    if mode == "string":
+        return _string(ch, stack, "", ("after", 0, stack))
+    if mode == "key_string":
+        return _string(ch, stack, "key_", ("colon", 0, stack))
+    if mode == "escape" or mode == "key_escape":
+        prefix = mode[: -len("escape")]
+        if ch in ESCAPES:
+            return (prefix + "string", 0, stack)
+        return (prefix + "unicode", 4, stack) if ch == "u" else None
+    if mode == "unicode" or mode == "key_unicode":
+        if ch not in HEX_DIGITS:
+            return None
+        if aux == 1:
+            return (mode[: -len("unicode")] + "string", 0, stack)
+        return (mode, aux - 1, stack)
+
+    if mode == "literal":
+        if ch != aux[0]:
+            return None
+        return ("after", 0, stack) if len(aux) == 1 else ("literal", aux[1:], stack)
+
+    # Numbers end at the first character that cannot continue them
+    if mode == "minus":
+        if ch == "0":
+            return ("zero", 0, stack)
+        return ("int", 0, stack) if ch in DIGITS else None
+    if mode == "frac_start":
+        return ("frac", 0, stack) if ch in DIGITS else None
+    if mode == "exp_sign":
+        return ("exp", 0, stack) if ch in DIGITS else None
+    if mode == "exp_start":
+        if ch in "+-":
+            return ("exp_sign", 0, stack)
+        return ("exp", 0, stack) if ch in DIGITS else None
+    if mode in ("zero", "int", "frac", "exp"):
+        if ch in DIGITS and mode != "zero":
+            return (mode, 0, stack)
+        if ch == "." and mode in ("zero", "int"):
+            return ("frac_start", 0, stack)
+        if ch in "eE" and mode != "exp":
+            return ("exp_start", 0, stack)
+        return step(("after", 0, stack), ch)
+
+    # Nothing follows the end of the object
+    return None
+
+
+def feed(state: Optional[State], text: str) -> Optional[State]:
+    for ch in text:
+        if state is None:
+            return None
+        state = step(state, ch)
+    return state
+
+
+def _mask_key(state: State) -> State:
+    """State that the mask of `state` is computed from, with only its last brackets"""
+    mode, aux, stack = state
+    if len(stack) > MASK_DEPTH:
+        stack = DEEPER + stack[-MASK_DEPTH:]
+    return (mode, aux, stack)
+
+
+class JsonObjectGuide:
+    """Guide with the interface of the outlines guides, whose integer states stand for
+    the states of a JSON parser. The allowed tokens of a state are the ones its parser
+    accepts, computed when the state is first reached. The guide of a tokenizer is built
+    once, with its tokens grouped by `token_class`."""
+
+    def __init__(self, tokenizer):
+        start_time = time.time()
+        self.eos_token_id = tokenizer.eos_token_id
+        ids_by_class: Dict[str, List[int]] = {}
+        self.token_classes: Dict[int, str] = {}
+        for token, token_id in tokenizer.vocabulary.items():
+            if token in tokenizer.special_tokens:
+                continue
+            text = tokenizer.convert_token_to_string(token)
+            if not text:
+                continue
+            cls = token_class(text)
+            self.token_classes[token_id] = cls
+            ids_by_class.setdefault(cls, []).append(token_id)
+        # Sorted, so that the tokens sharing a prefix are parsed from the state after it
+        self.texts = sorted(ids_by_class)
+        self.ids = [ids_by_class[text] for text in self.texts]
+
+        # Least recently used first
+        self.states: "OrderedDict[int, State]" = OrderedDict([(0, ("start", 0, ""))])
+        self.state_ids: Dict[State, int] = {self.states[0]: 0}
+        self.next_state_id = 1
+        self.masks: Dict[State, torch.Tensor] = {}
+        logger.debug(
+            f"Built JSON object guide of {len(self.texts)} token classes in "
+            f"{time.time() - start_time:.2f}s"
+        )
+
+    def _state(self, state_id: int) -> Optional[State]:
+        state = self.states.get(state_id)
+        if state is None:
+            logger.warning(f"JSON object state {state_id} was forgotten")
+            return None
+        self.states.move_to_end(state_id)
+        return state
+
+    def _state_id(self, state: State) -> int:
+        state_id = self.state_ids.get(state)
+        if state_id is not None:
+            self.states.move_to_end(state_id)
+            return state_id
+        state_id = self.next_state_id
+        self.next_state_id += 1
+        self.states[state_id] = state
+        self.state_ids[state] = state_id
+        if len(self.states) > MAX_STATES:
+            # The initial state is used by every new request
+            self.states.move_to_end(0)
+            _, forgotten = self.states.popitem(last=False)
+            del self.state_ids[forgotten]
+        return state_id
+
+    def _allowed_tokens(self, key: State) -> torch.Tensor:
+        if key[0] == "done":
+            return torch.tensor([self.eos_token_id], dtype=torch.int64)
+        allowed = []
+        # `states[n]` is the state after the first `n` characters of `prefix`
+        prefix = ""
+        states = [key]
+        i = 0
+        while i < len(self.texts):
+            text = self.texts[i]
+            common = len(os.path.commonprefix([prefix, text]))
+            del states[common + 1 :]
+            state = states[-1]
+            for ch in text[common:]:
+                state = step(state, ch)
+                if state is None:
+                    break
+                states.append(state)
+            if state is None:
+                # No token starting with the refused characters is allowed either
+                dead = text[: len(states)]
+                prefix = dead[:-1]
+                after_dead = dead[:-1] + chr(min(ord(dead[-1]) + 1, 0x10FFFF))
+                i = bisect.bisect_left(self.texts, after_dead, i + 1)
+                continue
+            allowed.extend(self.ids[i])
+            prefix = text
+            i += 1
+        return torch.tensor(allowed, dtype=torch.int64)
+
+    def get_next_instruction(self, state_id: int) -> Generate:
+        state = self._state(state_id)
+        if state is None:
+            # The object cannot be completed
+            return Generate(torch.tensor([self.eos_token_id], dtype=torch.int64))
+        key = _mask_key(state)
+        mask = self.masks.get(key)
+        if mask is None:
+            mask = self._allowed_tokens(key)
+            self.masks[key] = mask
+        return Generate(mask)
+
+    def get_next_state(self, state_id: int, token_id: int) -> int:
+        if state_id == -1 or token_id not in self.token_classes:
+            return -1
+        state = self._state(state_id)
+        if state is not None:
+            state = feed(state, self.token_classes[token_id])
+        if state is None:
+            return -1
+        return self._state_id(state)
@@ -10,6 +10,8 @@ from text_generation_server.pb.generate_pb2 import GrammarType
 
 from outlines.fsm.guide import RegexGuide
 
+from text_generation_server.utils.json_mode import JsonObjectGuide
+
 from transformers import (
     LogitsWarper,
     LogitsProcessor,
@@ -30,6 +32,7 @@ class StaticWarper:
         top_k=None,
         top_p=None,
         typical_p=None,
+        min_p=None,
     ):
         self.warpers = []
 
@@ -40,6 +43,8 @@ class StaticWarper:
             self.warpers.append(TopKLogitsWarper(top_k=top_k))
         if top_p is not None and top_p < 1.0:
             self.warpers.append(TopPLogitsWarper(top_p=top_p))
+        if min_p is not None and min_p > 0.0:
+            self.warpers.append(MinPLogitsWarper(min_p=min_p))
         if typical_p is not None and typical_p < 1.0:
             self.warpers.append(TypicalLogitsWarper(mass=typical_p))
 
@@ -82,12 +87,41 @@ def static_warper(
     top_k: Optional[int],
     top_p: Optional[float],
     typical_p: Optional[float],
+    min_p: Optional[float] = None,
 ) -> StaticWarper:
     return StaticWarper(
-        temperature=temperature, top_k=top_k, top_p=top_p, typical_p=typical_p
+        temperature=temperature,
+        top_k=top_k,
+        top_p=top_p,
+        typical_p=typical_p,
+        min_p=min_p,
     )
 
 
+class MinPLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs min-p, i.e. keeps the tokens whose probability is at least `min_p` times the
+    probability of the most likely token. The cut-off follows the confidence of the model: it is strict when one
+    token is much more likely than the others, and lenient when many tokens are plausible.
+
+    Args:
+        min_p (`float`):
+            Value of min_p between 0 and 1 inclusive. 0 keeps every token.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(self, min_p: float, filter_value: float = -math.inf):
+        self.min_p = min_p
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = scores.softmax(dim=-1)
+        threshold = self.min_p * probs.max(dim=-1, keepdim=True).values
+        # The most likely token is always above the threshold
+        return scores.masked_fill_(probs < threshold, self.filter_value)
+
+
 class HeterogeneousRepetitionPenaltyLogitsProcessor(LogitsProcessor):
     r"""
     [`LogitsProcessor`] enforcing an exponential penalty on repeated sequences.
@@ -125,65 +159,307 @@ class HeterogeneousRepetitionPenaltyLogitsProcessor(LogitsProcessor):
         return None
 
 
-class FrequencyPenaltyLogitsProcessor(LogitsProcessor):
+def token_counts(
+    input_ids: torch.Tensor,
+    vocab_size: int,
+    lengths: List[int],
+    generated: List[int],
+) -> torch.Tensor:
+    """Occurrences of every token among the `generated` last tokens of each row of
+    `input_ids`, the rows being padded past their `lengths`. The prompt is not
+    counted."""
+    device = input_ids.device
+    ends = torch.tensor(lengths, device=device).unsqueeze(1)
+    starts = (ends - torch.tensor(generated, device=device).unsqueeze(1)).clamp(min=0)
+    positions = torch.arange(input_ids.shape[1], device=device).unsqueeze(0)
+    # Ids past the logits (e.g. added tokens of the tokenizer) cannot be penalized
+    counted = (positions >= starts) & (positions < ends) & (input_ids < vocab_size)
+    counts = torch.zeros(
+        input_ids.shape[0], vocab_size, dtype=torch.float32, device=device
+    )
+    counts.scatter_add_(
+        1, torch.where(counted, input_ids, 0), counted.to(torch.float32)
+    )
+    return counts
+
+
+class PresenceFrequencyPenaltyLogitsProcessor(LogitsProcessor):
     r"""
-    Frequency penalty as defined by OpenAI
+    Presence and frequency penalties as defined by OpenAI in
+    https://platform.openai.com/docs/guides/text-generation/parameter-details
+
+    The logit of a token is lowered by `frequency_penalty` for every time the token was
+    generated so far, and by `presence_penalty` once if it was generated at all. The
+    tokens of the prompt are not penalized.
 
     Args:
-        penalty (`float`):
+        frequency_penalty (`float`):
             The parameter for frequency penalty. 0.0 means no penalty.
+        presence_penalty (`float`):
+            The parameter for presence penalty. 0.0 means no penalty.
+        generated (`int`):
+            Number of tokens already generated, at the end of the input ids.
     """
 
-    def __init__(self, penalty: float):
-        self.penalty = penalty
+    def __init__(
+        self, frequency_penalty: float, presence_penalty: float, generated: int = 0
+    ):
+        self.frequency_penalty = frequency_penalty
+        self.presence_penalty = presence_penalty
+        self.generated = generated
 
     def __call__(
         self, input_ids: torch.LongTensor, scores: torch.FloatTensor
     ) -> torch.FloatTensor:
-        score = torch.gather(scores, 1, input_ids)
-        # if score < 0 then penalty has to be multiplied to reduce the previous token probability
-        score = -torch.where(score < 0, score * self.penalty, score / self.penalty)
-        # set score to 0 where input_ids is a padding token
-        score *= input_ids.ne(0)
-
-        return scores.scatter_add_(1, input_ids, score)
+        # Called once per generated token
+        counts = token_counts(
+            input_ids,
+            scores.shape[-1],
+            [input_ids.shape[1]] * input_ids.shape[0],
+            [self.generated] * input_ids.shape[0],
+        )
+        self.generated += 1
+        penalty = (
+            counts * self.frequency_penalty
+            + (counts > 0).to(counts.dtype) * self.presence_penalty
+        )
+        return scores - penalty.to(scores.dtype)
 
 
-class HeterogeneousFrequencyPenaltyLogitsProcessor(LogitsProcessor):
+class HeterogeneousPresenceFrequencyPenaltyLogitsProcessor(LogitsProcessor):
     r"""
-    Frequency penalty as defined by OpenAI in
-    https://platform.openai.com/docs/guides/text-generation/parameter-details
+    [`PresenceFrequencyPenaltyLogitsProcessor`] for a batch, each request having its own
+    penalties.
 
     Args:
         frequency_penalty (`List[float]`):
             The parameter for frequency penalty. 0.0 means no penalty.
+        presence_penalty (`List[float]`):
+            The parameter for presence penalty. 0.0 means no penalty.
     """
 
-    def __init__(self, penalty: List[float], dtype: torch.dtype, device: torch.device):
-        self.penalty = penalty
-        self.penalty_tensor = torch.tensor(
-            penalty, dtype=dtype, device=device
+    def __init__(
+        self,
+        frequency_penalty: List[float],
+        presence_penalty: List[float],
+        device: torch.device,
+    ):
+        self.frequency_penalty = frequency_penalty
+        self.presence_penalty = presence_penalty
+        self.frequency_penalty_tensor = torch.tensor(
+            frequency_penalty, dtype=torch.float32, device=device
+        ).unsqueeze(1)
+        self.presence_penalty_tensor = torch.tensor(
+            presence_penalty, dtype=torch.float32, device=device
         ).unsqueeze(1)
 
-    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
-        batch_size, input_size = input_ids.size()
-        vocab_size = scores.size(1)
+    def __call__(
+        self,
+        input_ids: torch.Tensor,
+        scores: torch.Tensor,
+        lengths: List[int],
+        generated: List[int],
+    ) -> torch.Tensor:
+        counts = token_counts(input_ids, scores.shape[-1], lengths, generated)
+        # Both penalties are added up when a request sets both
+        penalty = (
+            counts * self.frequency_penalty_tensor
+            + (counts > 0).to(counts.dtype) * self.presence_penalty_tensor
+        )
+        scores -= penalty.to(scores.dtype)
+        return scores
+
+    def filter(self, indices):
+        self.frequency_penalty = [self.frequency_penalty[i] for i in indices]
+        self.presence_penalty = [self.presence_penalty[i] for i in indices]
+        if any(x != 0.0 for x in self.frequency_penalty + self.presence_penalty):
+            self.frequency_penalty_tensor = self.frequency_penalty_tensor[indices]
+            self.presence_penalty_tensor = self.presence_penalty_tensor[indices]
+            return self
+        return None
+
+
+class LogitBiasLogitsProcessor(LogitsProcessor):
+    r"""
+    Logit bias as defined by OpenAI: a bias is added to the logits of some tokens before
+    sampling. Token ids past the vocabulary of the model are ignored.
+
+    Args:
+        logit_bias (`Dict[int, float]`):
+            Bias of every token id. -100 bans a token, 100 makes it almost certain.
+    """
 
-        # Calculate the frequency for each token so far
-        token_freq = torch.zeros(batch_size, vocab_size, device=input_ids.device)
-        token_freq.scatter_add_(
-            1, input_ids, torch.ones_like(input_ids, dtype=torch.float)
+    def __init__(self, logit_bias: Dict[int, float], device: torch.device):
+        self.token_ids = torch.tensor(
+            list(logit_bias.keys()), dtype=torch.long, device=device
+        )
+        self.bias = torch.tensor(
+            list(logit_bias.values()), dtype=torch.float32, device=device
         )
-        token_freq /= input_size
 
-        # Apply the frequency penalty to logits
-        scores -= token_freq * self.penalty_tensor
+    def __call__(self, scores: torch.Tensor) -> torch.Tensor:
+        in_vocab = self.token_ids < scores.shape[-1]
+        token_ids = self.token_ids[in_vocab]
+        scores[..., token_ids] += self.bias[in_vocab].to(scores.dtype)
+        return scores
+
+
+class HeterogeneousLogitBiasLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitBiasLogitsProcessor`] for a batch, each request having its own biases.
+
+    Args:
+        logit_biases (`List[Dict[int, float]]`):
+            Bias of the token ids of every request, empty if the request biases no token.
+        device (`torch.device`):
+            Device of the logits.
+    """
+
+    def __init__(self, logit_biases: List[Dict[int, float]], device: torch.device):
+        self.processors = [
+            LogitBiasLogitsProcessor(logit_bias, device) if logit_bias else None
+            for logit_bias in logit_biases
+        ]
+
+    def __call__(self, scores: torch.Tensor) -> torch.Tensor:
+        for i, processor in enumerate(self.processors):
+            if processor is not None:
+                scores[i] = processor(scores[i])
         return scores
 
     def filter(self, indices):
-        self.penalty = [self.penalty[i] for i in indices]
-        if any([x != 0.0 for x in self.penalty]):
-            self.penalty_tensor = self.penalty_tensor[indices]
+        self.processors = [self.processors[i] for i in indices]
+        if any(processor is not None for processor in self.processors):
+            return self
+        return None
+
+
+class NGramIndex:
+    """N-grams of the text of a request, extended with its new tokens at every step."""
+
+    def __init__(self, ngram_size: int):
+        self.ngram_size = ngram_size
+        # Indexed tokens, and the tokens that followed each prefix of `ngram_size - 1`
+        self.reset()
+
+    def reset(self):
+        self.tokens = []
+        self.next_tokens = {}
+
+    def extend(self, tokens: List[int]):
+        for token in tokens:
+            self.tokens.append(token)
+            if len(self.tokens) >= self.ngram_size:
+                prefix = tuple(self.tokens[len(self.tokens) - self.ngram_size : -1])
+                self.next_tokens.setdefault(prefix, set()).add(token)
+
+    def banned(self, vocab_size: int) -> List[int]:
+        """Tokens that would complete an n-gram already in the text."""
+        prefix = tuple(self.tokens[len(self.tokens) - self.ngram_size + 1 :])
+        if len(prefix) < self.ngram_size - 1:
+            return []
+        return [
+            token for token in self.next_tokens.get(prefix, ()) if token < vocab_size
+        ]
+
+    def update(self, input_ids: torch.Tensor, length: int):
+        """Index the tokens of `input_ids[:length]` that are not indexed yet."""
+        if length < len(self.tokens):
+            # The text was rewritten, index it again
+            self.reset()
+        if length > len(self.tokens):
+            self.extend(input_ids[len(self.tokens) : length].tolist())
+
+
+def classifier_free_guidance(
+    logits: torch.Tensor,
+    guided: torch.Tensor,
+    negative: torch.Tensor,
+    scales: torch.Tensor,
+) -> torch.Tensor:
+    """Replace the logits of the `guided` rows by their log probabilities pushed away from
+    the ones of their `negative` rows, by `scales`. A scale of 1 keeps the probabilities of
+    the guided rows."""
+    conditional = torch.log_softmax(logits[guided].float(), dim=-1)
+    unconditional = torch.log_softmax(logits[negative].float(), dim=-1)
+    guided_logits = unconditional + scales.unsqueeze(1) * (conditional - unconditional)
+    logits[guided] = guided_logits.to(logits.dtype)
+    return logits
+
+
+class NoRepeatNGramLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] banning the tokens that would repeat an n-gram of the text so far, prompt
+    included. Token ids past the vocabulary of the model are ignored.
+
+    Args:
+        ngram_size (`int`):
+            Size of the n-grams that can only occur once.
+    """
+
+    def __init__(self, ngram_size: int, filter_value: float = -math.inf):
+        self.ngram_size = ngram_size
+        self.filter_value = filter_value
+        self.indices = []
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        if len(self.indices) != input_ids.shape[0]:
+            self.indices = [NGramIndex(self.ngram_size) for _ in input_ids]
+        for i, index in enumerate(self.indices):
+            # Only the new tokens are read from the device
+            index.update(input_ids[i], input_ids.shape[1])
+            scores[i, index.banned(scores.shape[-1])] = self.filter_value
+        return scores
+
+
+class HeterogeneousNoRepeatNGramLogitsProcessor(LogitsProcessor):
+    r"""
+    [`NoRepeatNGramLogitsProcessor`] for a batch, each request having its own n-gram size.
+    The rows of `input_ids` are padded past the lengths of their text.
+
+    Args:
+        ngram_size (`List[int]`):
+            Size of the n-grams that can only occur once. 0 disables it for this member of the batch.
+    """
+
+    def __init__(self, ngram_size: List[int], filter_value: float = -math.inf):
+        self.ngram_size = ngram_size
+        self.filter_value = filter_value
+        self.indices = [NGramIndex(size) if size else None for size in ngram_size]
+
+    def __call__(
+        self, input_ids: torch.Tensor, scores: torch.Tensor, lengths: List[int]
+    ) -> torch.Tensor:
+        lengths = [min(length, input_ids.shape[1]) for length in lengths]
+        rows = [
+            i
+            for i, index in enumerate(self.indices)
+            if index is not None and lengths[i] != len(index.tokens)
+        ]
+        for i in rows:
+            if lengths[i] < len(self.indices[i].tokens):
+                self.indices[i].reset()
+        if rows:
+            # Only the tokens not indexed yet are read from the device, in one copy
+            start = min(len(self.indices[i].tokens) for i in rows)
+            end = max(lengths[i] for i in rows)
+            new_tokens = input_ids[:, start:end].tolist()
+            for i in rows:
+                index = self.indices[i]
+                index.extend(
+                    new_tokens[i][len(index.tokens) - start : lengths[i] - start]
+                )
+        for i, index in enumerate(self.indices):
+            if index is not None:
+                scores[i, index.banned(scores.shape[-1])] = self.filter_value
+        return scores
+
+    def filter(self, indices):
+        self.ngram_size = [self.ngram_size[i] for i in indices]
+        self.indices = [self.indices[i] for i in indices]
+        if any(x != 0 for x in self.ngram_size):
             return self
         return None
 
@@ -449,6 +725,44 @@ class HeterogeneousTypicalLogitsWarper(LogitsWarper):
         return None
 
 
+class HeterogeneousMinPLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs min-p, i.e. keeps the tokens whose probability is at least `min_p` times the
+    probability of the most likely token.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        min_p (`List[float]`):
+            Value of min_p between 0 and 1 inclusive. 0 disables min_p warping for this member of the batch.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        min_p: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.min_p = min_p
+        self.min_p_tensor = torch.tensor(min_p, dtype=dtype, device=device).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = scores.softmax(dim=-1)
+        threshold = self.min_p_tensor * probs.max(dim=-1, keepdim=True).values
+        return scores.masked_fill_(probs < threshold, self.filter_value)
+
+    def filter(self, indices):
+        self.min_p = [self.min_p[i] for i in indices]
+        if any([x > 0.0 for x in self.min_p]):
+            self.min_p_tensor = self.min_p_tensor[indices]
+            return self
+        return None
+
+
 class HeterogeneousProcessorWrapper(LogitsProcessor):
     r"""
     A wrapper for logit warpers or processors without heterogeneous parameter support.
@@ -480,6 +794,11 @@ class HeterogeneousProcessorWrapper(LogitsProcessor):
         return None
 
 
+def has_grammar(grammar: str, grammar_type: GrammarType) -> bool:
+    """Whether the generation is constrained, the `json_object` type has no grammar"""
+    return grammar != "" or grammar_type == GrammarType.GRAMMAR_TYPE_JSON_OBJECT
+
+
 class GrammarLogitProcessor(LogitsProcessor):
     fsm_state: DefaultDict[int, int]
     fsm: RegexGuide
@@ -530,6 +849,8 @@ class GrammarLogitProcessor(LogitsProcessor):
         schema: str,
         tokenizer: Optional[PreTrainedTokenizerBase],
     ):
+        if grammar_type == GrammarType.GRAMMAR_TYPE_JSON_OBJECT:
+            return JsonObjectGuide(tokenizer)
         start_time = time.time()
         if grammar_type == GrammarType.GRAMMAR_TYPE_JSON:
             # JSON schema is compiled by the v3 router.
@@ -579,7 +900,7 @@ class HeterogeneousGrammarLogitProcessor(LogitsProcessor):
         self.tokenizer = GrammarLogitProcessor._cached_adapt_tokenizer(tokenizer)
         self.fsms = []
         for grammar, grammar_type in zip(grammars, grammar_types):
-            if len(grammar) == 0:
+            if not has_grammar(grammar, grammar_type):
                 self.fsms.append(None)
                 continue
             fsm = GrammarLogitProcessor._cached_compile_fsm(
@@ -624,3 +945,76 @@ class HeterogeneousGrammarLogitProcessor(LogitsProcessor):
             new_fsms.append(self.fsms[i])
         self.fsms = new_fsms
         return self
+
+
+@lru_cache(maxsize=1024)
+def _cached_healing_token_ids(tokenizer, healing_token_id: int) -> List[int]:
+    """Ids of the tokens starting with the text of `healing_token_id`, itself included"""
+    vocab = tokenizer.get_vocab()
+    prefix = tokenizer.convert_ids_to_tokens(healing_token_id)
+    return [token_id for token, token_id in vocab.items() if token.startswith(prefix)]
+
+
+class TokenHealingLogitsProcessor(LogitsProcessor):
+    r"""
+    Constrain the first generated token of a request to extend the prompt token removed by
+    token healing, so that the model picks the most likely completion of the partial word.
+
+    Args:
+        tokenizer (`PreTrainedTokenizerBase`):
+            Tokenizer of the model.
+        healing_token_id (`int`):
+            Last token of the prompt, removed by the router.
+    """
+
+    def __init__(self, tokenizer: PreTrainedTokenizerBase, healing_token_id: int):
+        self.allowed_ids = _cached_healing_token_ids(tokenizer, healing_token_id)
+
+    def __call__(self, scores: torch.Tensor) -> torch.Tensor:
+        mask = torch.full_like(scores, -math.inf)
+        mask[..., self.allowed_ids] = 0
+        return scores + mask
+
+
+class HeterogeneousTokenHealingLogitsProcessor(LogitsProcessor):
+    r"""
+    [`TokenHealingLogitsProcessor`] for a batch, applied to the token a request samples at its
+    first step only.
+
+    Args:
+        tokenizer (`PreTrainedTokenizerBase`):
+            Tokenizer of the model.
+        healing_token_ids (`List[Optional[int]]`):
+            Removed prompt token of every request, None if the request does not heal its prompt.
+        healing_steps (`List[int]`):
+            Sampling step of the first token of every request.
+    """
+
+    def __init__(
+        self,
+        tokenizer: PreTrainedTokenizerBase,
+        healing_token_ids: List[Optional[int]],
+        healing_steps: List[int],
+    ):
+        self.processors = [
+            (
+                TokenHealingLogitsProcessor(tokenizer, healing_token_id)
+                if healing_token_id is not None
+                else None
+            )
+            for healing_token_id in healing_token_ids
+        ]
+        self.healing_steps = healing_steps
+
+    def __call__(self, scores: torch.Tensor, steps: List[int]) -> torch.Tensor:
+        for i, processor in enumerate(self.processors):
+            if processor is not None and steps[i] == self.healing_steps[i]:
+                scores[i] = processor(scores[i])
+        return scores
+
+    def filter(self, indices):
+        self.processors = [self.processors[i] for i in indices]
+        self.healing_steps = [self.healing_steps[i] for i in indices]
+        if any(processor is not None for processor in self.processors):
+            return self
+        return None
@@ -1,20 +1,28 @@
 import re
-from typing import List, Optional, Tuple, Set, Union
+from typing import Dict, List, Optional, Tuple, Set, Union
 
 import torch
 from text_generation_server.pb import generate_pb2
 from text_generation_server.pb.generate_pb2 import FinishReason, GrammarType
 from text_generation_server.utils.logits_process import (
-    FrequencyPenaltyLogitsProcessor,
     GrammarLogitProcessor,
+    HeterogeneousLogitBiasLogitsProcessor,
+    HeterogeneousMinPLogitsWarper,
+    HeterogeneousNoRepeatNGramLogitsProcessor,
     HeterogeneousProcessorWrapper,
     HeterogeneousRepetitionPenaltyLogitsProcessor,
-    HeterogeneousFrequencyPenaltyLogitsProcessor,
+    HeterogeneousPresenceFrequencyPenaltyLogitsProcessor,
     HeterogeneousTemperatureLogitsWarper,
     HeterogeneousTopKLogitsWarper,
     HeterogeneousTopPLogitsWarper,
     HeterogeneousTypicalLogitsWarper,
     HeterogeneousGrammarLogitProcessor,
+    HeterogeneousTokenHealingLogitsProcessor,
+    LogitBiasLogitsProcessor,
+    NoRepeatNGramLogitsProcessor,
+    PresenceFrequencyPenaltyLogitsProcessor,
+    TokenHealingLogitsProcessor,
+    has_grammar,
     static_warper,
 )
 from text_generation_server.utils.watermark import WatermarkLogitsProcessor
@@ -28,9 +36,11 @@ class NextTokenChooser:
         temperature: float = 1.0,
         repetition_penalty: float = 1.0,
         frequency_penalty: float = 0.0,
+        presence_penalty: float = 0.0,
         top_k: Optional[int] = None,
         top_p: Optional[float] = None,
         typical_p: Optional[float] = None,
+        min_p: Optional[float] = None,
         do_sample: bool = False,
         seed: int = 0,
         device: str = "cpu",
@@ -38,23 +48,48 @@ class NextTokenChooser:
         grammar: str = "",
         grammar_type: GrammarType = GrammarType.GRAMMAR_TYPE_NONE,
         fsm_grammar_state: int = 0,
+        sampling_step: int = 0,
+        healing_token_id: Optional[int] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
+        watermark_key: Optional[int] = None,
+        no_repeat_ngram_size: int = 0,
     ):
         self.watermark_processor = (
-            WatermarkLogitsProcessor(device=device) if watermark else None
+            WatermarkLogitsProcessor(device=device, key=watermark_key)
+            if watermark
+            else None
         )
         self.repetition_processor = (
             RepetitionPenaltyLogitsProcessor(penalty=repetition_penalty)
             if repetition_penalty and repetition_penalty != 1.0
             else None
         )
-        self.frequency_processor = (
-            FrequencyPenaltyLogitsProcessor(penalty=frequency_penalty)
-            if frequency_penalty and frequency_penalty != 0.0
+        self.presence_frequency_processor = (
+            PresenceFrequencyPenaltyLogitsProcessor(
+                frequency_penalty=frequency_penalty or 0.0,
+                presence_penalty=presence_penalty or 0.0,
+                generated=sampling_step,
+            )
+            if frequency_penalty or presence_penalty
+            else None
+        )
+        self.logit_bias_processor = (
+            LogitBiasLogitsProcessor(logit_bias, device) if logit_bias else None
+        )
+        self.no_repeat_ngram_processor = (
+            NoRepeatNGramLogitsProcessor(no_repeat_ngram_size)
+            if no_repeat_ngram_size
             else None
         )
         self.grammar_processor = (
             GrammarLogitProcessor(tokenizer, device, grammar, grammar_type)
-            if grammar != ""
+            if has_grammar(grammar, grammar_type)
+            else None
+        )
+        # Only constrains the first token
+        self.healing_processor = (
+            TokenHealingLogitsProcessor(tokenizer, healing_token_id)
+            if healing_token_id is not None
             else None
         )
         self.tokenizer = tokenizer
@@ -64,17 +99,22 @@ class NextTokenChooser:
             or (top_k is not None and top_k != 0)
             or (top_p is not None and top_p < 1.0)
             or (typical_p is not None and typical_p < 1.0)
+            or (min_p is not None and min_p > 0.0)
         )
         if has_warpers:
             self.static_warper = static_warper(
-                temperature=temperature, top_k=top_k, top_p=top_p, typical_p=typical_p
+                temperature=temperature,
+                top_k=top_k,
+                top_p=top_p,
+                typical_p=typical_p,
+                min_p=min_p,
             )
         else:
             self.static_warper = None
 
         sampling = do_sample or has_warpers
 
-        self.choice = Sampling(seed, device) if sampling else Greedy()
+        self.choice = Sampling(seed, device, sampling_step) if sampling else Greedy()
         self.fsm_grammar_state = fsm_grammar_state
         self.grammar = grammar
 
@@ -83,10 +123,17 @@ class NextTokenChooser:
             scores = self.watermark_processor(input_ids, scores)
         if self.repetition_processor is not None:
             scores = self.repetition_processor(input_ids, scores)
-        if self.frequency_processor is not None:
-            scores = self.frequency_processor(input_ids, scores)
+        if self.presence_frequency_processor is not None:
+            scores = self.presence_frequency_processor(input_ids, scores)
+        if self.logit_bias_processor is not None:
+            scores = self.logit_bias_processor(scores)
+        if self.no_repeat_ngram_processor is not None:
+            scores = self.no_repeat_ngram_processor(input_ids, scores)
         if self.grammar_processor is not None:
             scores = self.grammar_processor(scores, self.fsm_grammar_state)
+        if self.healing_processor is not None:
+            scores[-1] = self.healing_processor(scores[-1])
+            self.healing_processor = None
 
         if self.static_warper is None:
             next_logprob = torch.log_softmax(scores, -1)
@@ -116,15 +163,24 @@ class NextTokenChooser:
             temperature=pb.temperature,
             repetition_penalty=pb.repetition_penalty,
             frequency_penalty=pb.frequency_penalty,
+            presence_penalty=pb.presence_penalty,
             top_k=pb.top_k,
             top_p=pb.top_p,
             typical_p=pb.typical_p,
+            min_p=pb.min_p,
             do_sample=pb.do_sample,
             seed=pb.seed,
             device=device,
             tokenizer=tokenizer,
             grammar=pb.grammar,
             grammar_type=pb.grammar_type,
+            sampling_step=pb.sampling_step,
+            healing_token_id=(
+                pb.healing_token_id if pb.HasField("healing_token_id") else None
+            ),
+            logit_bias=dict(pb.logit_bias),
+            watermark_key=pb.watermark_key or None,
+            no_repeat_ngram_size=pb.no_repeat_ngram_size,
         )
 
 
@@ -146,6 +202,8 @@ class StoppingCriteria:
         stop_sequence_criterias: List[StopSequenceCriteria],
         max_new_tokens: int = 20,
         ignore_eos_token: bool = False,
+        stop_token_ids: Optional[Set[int]] = None,
+        min_new_tokens: int = 0,
     ):
         if eos_token_ids is None:
             eos_token_ids = set()
@@ -163,6 +221,18 @@ class StoppingCriteria:
         self.current_tokens = 0
         self.current_output = ""
         self.ignore_eos_token = ignore_eos_token
+        self.stop_token_ids = stop_token_ids or set()
+        self.min_new_tokens = min_new_tokens
+
+    def suppressed_token_ids(self) -> List[int]:
+        """Tokens that would end the generation before `min_new_tokens` if they were
+        generated next"""
+        if self.current_tokens >= self.min_new_tokens:
+            return []
+        suppressed = set(self.stop_token_ids)
+        if not self.ignore_eos_token:
+            suppressed |= self.eos_token_ids
+        return sorted(suppressed)
 
     def __call__(self, last_token: int, last_output: str) -> Tuple[bool, Optional[str]]:
         self.current_tokens += 1
@@ -172,15 +242,24 @@ class StoppingCriteria:
         if isinstance(last_token, torch.Tensor):
             last_token = last_token.item()
 
-        if not self.ignore_eos_token and last_token in self.eos_token_ids:
+        # Only the length ends the generation before `min_new_tokens`
+        early = self.current_tokens <= self.min_new_tokens
+
+        if not early and not self.ignore_eos_token and last_token in self.eos_token_ids:
             return True, FinishReason.FINISH_REASON_EOS_TOKEN
 
+        # Requested by the client, so they are not ignored like the EOS token
+        if not early and last_token in self.stop_token_ids:
+            return True, FinishReason.FINISH_REASON_STOP_SEQUENCE
+
         if self.stop_sequence_criterias:
             self.current_output += last_output
             # There is no need to keep an output that is too long
             if len(self.current_output) > 300:
                 # Slice to -200 to avoid doing it all the time
                 self.current_output = self.current_output[-200:]
+            if early:
+                return False, None
             for stop_sequence_criteria in self.stop_sequence_criterias:
                 if stop_sequence_criteria(self.current_output):
                     return True, FinishReason.FINISH_REASON_STOP_SEQUENCE
@@ -203,6 +282,8 @@ class StoppingCriteria:
             stop_sequence_criterias,
             pb.max_new_tokens,
             pb.ignore_eos_token,
+            set(pb.stop_token_ids),
+            pb.min_new_tokens,
         )
 
 
@@ -248,14 +329,26 @@ class HeterogeneousNextTokenChooser:
         grammars: List[str],
         grammar_types: List[int],
         fsm_grammar_states=List[int],
+        speculate: Optional[List[Optional[int]]] = None,
+        healing_token_ids: Optional[List[Optional[int]]] = None,
+        healing_steps: Optional[List[int]] = None,
+        logit_biases: Optional[List[Dict[int, float]]] = None,
+        presence_penalty: Optional[List[float]] = None,
+        min_p: Optional[List[float]] = None,
+        watermark_keys: Optional[List[Optional[int]]] = None,
+        no_repeat_ngram_size: Optional[List[int]] = None,
     ):
         warpers = []
 
+        if watermark_keys is None:
+            watermark_keys = [None] * len(watermark)
         self.watermark_processor = (
             HeterogeneousProcessorWrapper(
                 {
-                    i: WatermarkLogitsProcessor(device=device)
-                    for i, do_watermark in enumerate(watermark)
+                    i: WatermarkLogitsProcessor(device=device, key=key)
+                    for i, (do_watermark, key) in enumerate(
+                        zip(watermark, watermark_keys)
+                    )
                     if do_watermark
                 }
             )
@@ -271,11 +364,25 @@ class HeterogeneousNextTokenChooser:
             else None
         )
 
-        self.frequency_processor = (
-            HeterogeneousFrequencyPenaltyLogitsProcessor(
-                frequency_penalty, dtype, device
+        if presence_penalty is None:
+            presence_penalty = [0.0] * len(frequency_penalty)
+        self.presence_frequency_processor = (
+            HeterogeneousPresenceFrequencyPenaltyLogitsProcessor(
+                frequency_penalty, presence_penalty, device
             )
-            if any([x != 0.0 for x in frequency_penalty])
+            if any(x != 0.0 for x in frequency_penalty + presence_penalty)
+            else None
+        )
+
+        self.logit_bias_processor = (
+            HeterogeneousLogitBiasLogitsProcessor(logit_biases, device)
+            if logit_biases is not None and any(logit_biases)
+            else None
+        )
+
+        self.no_repeat_ngram_processor = (
+            HeterogeneousNoRepeatNGramLogitsProcessor(no_repeat_ngram_size)
+            if no_repeat_ngram_size is not None and any(no_repeat_ngram_size)
             else None
         )
 
@@ -283,7 +390,16 @@ class HeterogeneousNextTokenChooser:
             HeterogeneousGrammarLogitProcessor(
                 tokenizer, device, grammars, grammar_types
             )
-            if any([grammar != "" for grammar in grammars])
+            if any(map(has_grammar, grammars, grammar_types))
+            else None
+        )
+
+        self.healing_processor = (
+            HeterogeneousTokenHealingLogitsProcessor(
+                tokenizer, healing_token_ids, healing_steps
+            )
+            if healing_token_ids is not None
+            and any(x is not None for x in healing_token_ids)
             else None
         )
 
@@ -303,6 +419,10 @@ class HeterogeneousNextTokenChooser:
             do_sample = [sample or x < 1.0 for x, sample in zip(top_p, do_sample)]
             warpers.append(HeterogeneousTopPLogitsWarper(top_p, dtype, device))
 
+        if min_p is not None and any(x > 0.0 for x in min_p):
+            do_sample = [sample or x > 0.0 for x, sample in zip(min_p, do_sample)]
+            warpers.append(HeterogeneousMinPLogitsWarper(min_p, dtype, device))
+
         if any(x < 1.0 for x in typical_p):
             do_sample = [sample or x < 1.0 for x, sample in zip(typical_p, do_sample)]
             warpers.append(HeterogeneousTypicalLogitsWarper(typical_p, dtype, device))
@@ -322,6 +442,8 @@ class HeterogeneousNextTokenChooser:
         self.fsm_grammar_states = fsm_grammar_states
         self.grammars = grammars
         self.grammar_types = grammar_types
+        # Per request cap on the number of accepted speculative tokens, None to use the model default
+        self.speculate = speculate if speculate is not None else [None] * len(seeds)
 
     def __call__(
         self,
@@ -331,6 +453,8 @@ class HeterogeneousNextTokenChooser:
         speculated_ids: Optional[torch.Tensor] = None,
         speculative_scores: Optional[torch.Tensor] = None,
         verbose=False,
+        sampling_steps: Optional[List[int]] = None,
+        input_lengths: Optional[List[int]] = None,
     ):
         if speculated_ids is not None:
             B = scores.shape[0] // (speculated_ids.shape[1] + 1)
@@ -344,18 +468,39 @@ class HeterogeneousNextTokenChooser:
         next_ids = torch.zeros((B, S), device=scores.device, dtype=torch.long)
 
         for j in range(S):
+            # The j-th speculative token of a request is sampled at step `step + j`
+            steps = (
+                [step + j for step in sampling_steps]
+                if sampling_steps is not None
+                else None
+            )
             _scores = scores[:, j]
             if self.watermark_processor is not None:
                 _scores = self.watermark_processor(input_ids, _scores)
             if self.repetition_processor is not None:
                 _scores = self.repetition_processor(input_ids, _scores)
-            if self.frequency_processor is not None:
-                _scores = self.frequency_processor(input_ids, _scores)
+            if self.presence_frequency_processor is not None:
+                # Only the generated tokens, the last `sampling_steps` of each row, are
+                # penalized
+                _scores = self.presence_frequency_processor(
+                    input_ids,
+                    _scores,
+                    input_lengths or [input_ids.shape[1]] * B,
+                    sampling_steps or [0] * B,
+                )
+            if self.logit_bias_processor is not None:
+                _scores = self.logit_bias_processor(_scores)
+            if self.no_repeat_ngram_processor is not None:
+                # The rows of `input_ids` are padded past the text of their request
+                lengths = input_lengths or [input_ids.shape[1]] * B
+                _scores = self.no_repeat_ngram_processor(input_ids, _scores, lengths)
             if self.grammar_processor is not None:
                 _scores = self.grammar_processor(_scores, self.fsm_grammar_states)
+            if self.healing_processor is not None and steps is not None:
+                _scores = self.healing_processor(_scores, steps)
             for warper in self.warpers:
                 _scores = warper(input_ids, _scores)
-            _next_ids = self.choice(_scores)
+            _next_ids = self.choice(_scores, steps)
             scores[:, j] = _scores
             next_ids[:, j] = _next_ids
         next_ids = next_ids.view(B * S)
@@ -373,10 +518,11 @@ class HeterogeneousNextTokenChooser:
                 validate_speculative = _next_ids[:-1] == _speculated_ids
                 index = i * S
                 accepted = 1
+                max_accepted = self.speculate[i]
                 # First is always valid
                 indices.append(index)
                 for valid in validate_speculative.tolist():
-                    if valid:
+                    if valid and (max_accepted is None or accepted <= max_accepted):
                         index += 1
                         accepted += 1
                         indices.append(index)
@@ -438,12 +584,25 @@ class HeterogeneousNextTokenChooser:
         if self.repetition_processor is not None:
             self.repetition_processor = self.repetition_processor.filter(indices)
 
-        if self.frequency_processor is not None:
-            self.frequency_processor = self.frequency_processor.filter(indices)
+        if self.presence_frequency_processor is not None:
+            self.presence_frequency_processor = (
+                self.presence_frequency_processor.filter(indices)
+            )
+
+        if self.logit_bias_processor is not None:
+            self.logit_bias_processor = self.logit_bias_processor.filter(indices)
+
+        if self.no_repeat_ngram_processor is not None:
+            self.no_repeat_ngram_processor = self.no_repeat_ngram_processor.filter(
+                indices
+            )
 
         if self.grammar_processor is not None:
             self.grammar_processor = self.grammar_processor.filter(indices)
 
+        if self.healing_processor is not None:
+            self.healing_processor = self.healing_processor.filter(indices)
+
         filtered_warpers = []
         for warper in self.warpers:
             filtered_warper = warper.filter(indices)
@@ -453,6 +612,7 @@ class HeterogeneousNextTokenChooser:
 
         self.seeds = [self.seeds[i] for i in indices]
         self.do_sample = [self.do_sample[i] for i in indices]
+        self.speculate = [self.speculate[i] for i in indices]
 
         new_grammars = []
         new_fsm_grammar_states = []
@@ -500,16 +660,41 @@ class HeterogeneousNextTokenChooser:
             fsm_grammar_states=(
                 fsm_grammar_states if fsm_grammar_states else [0] * len(pb)
             ),
+            speculate=[
+                pb_.speculate if pb_.HasField("speculate") else None for pb_ in pb
+            ],
+            healing_token_ids=[
+                pb_.healing_token_id if pb_.HasField("healing_token_id") else None
+                for pb_ in pb
+            ],
+            healing_steps=[pb_.sampling_step for pb_ in pb],
+            logit_biases=[dict(pb_.logit_bias) for pb_ in pb],
+            presence_penalty=[pb_.presence_penalty for pb_ in pb],
+            min_p=[pb_.min_p for pb_ in pb],
+            watermark_keys=[pb_.watermark_key or None for pb_ in pb],
+            no_repeat_ngram_size=[pb_.no_repeat_ngram_size for pb_ in pb],
         )
 
 
+def sampling_seed(seed: int, step: int) -> int:
+    """Seed of the generator sampling the token at `step`. Deriving it from the step, instead
+    of drawing from one generator per request, makes the sampled tokens independent of the
+    batches and of the number of forwards that generated the previous tokens."""
+    return (seed + step * 0x9E3779B97F4A7C15) % 2**64
+
+
 class Sampling:
-    def __init__(self, seed: int, device: str = "cpu"):
+    def __init__(self, seed: int, device: str = "cpu", step: int = 0):
         self.generator = torch.Generator(device)
-        self.generator.manual_seed(seed)
         self.seed = seed
-
-    def __call__(self, logits):
+        # Step of the next token when the caller does not give it
+        self.step = step
+
+    def __call__(self, logits, step: Optional[int] = None):
+        if step is None:
+            step = self.step
+            self.step += 1
+        self.generator.manual_seed(sampling_seed(self.seed, step))
         probs = torch.nn.functional.softmax(logits, -1)
         # Avoid GPU<->CPU sync done by torch multinomial
         # See: https://github.com/pytorch/pytorch/blob/925a3788ec5c06db62ca732a0e9425a26a00916f/aten/src/ATen/native/Distributions.cpp#L631-L637
@@ -518,7 +703,7 @@ class Sampling:
 
 
 class Greedy:
-    def __call__(self, logits):
+    def __call__(self, logits, steps=None):
         return logits.argmax(dim=-1)
 
 
@@ -540,14 +725,14 @@ class HeterogeneousSampling:
 
         self.greedy = Greedy()
 
-    def __call__(self, logits):
+    def __call__(self, logits, steps: Optional[List[int]] = None):
         out = torch.empty(logits.shape[0], dtype=torch.int64, device=logits.device)
         if self.greedy_indices:
             # Computing for all indices is faster than slicing
             torch.argmax(logits, -1, out=out)
 
         for i, sampling in self.sampling_mapping.items():
-            out[i] = sampling(logits[i])
+            out[i] = sampling(logits[i], steps[i] if steps is not None else None)
         return out
 
     def filter(self, indices):
@@ -17,11 +17,36 @@ import os
 
 import torch
 from transformers import LogitsProcessor
-from typing import List, Union
+from typing import List, Optional, Union
 
 GAMMA = float(os.getenv("WATERMARK_GAMMA", 0.5))
 DELTA = float(os.getenv("WATERMARK_DELTA", 2.0))
 
+MASK = 0xFFFFFFFF
+
+
+def _mix(x):
+    # 32 bits hash that the router computes the same way to detect the watermark
+    x = x ^ (x >> 16)
+    x = (x * 0x7FEB352D) & MASK
+    x = x ^ (x >> 15)
+    x = (x * 0x6A09E667) & MASK
+    return x ^ (x >> 16)
+
+
+def keyed_greenlist_mask(
+    key: int, prev_token: int, max_value: int, gamma: float, device: torch.device
+) -> torch.BoolTensor:
+    """Greenlist of the tokens following `prev_token` with a server-configured key.
+
+    Unlike the permutations of `torch.Generator`, it does not depend on the device or
+    on the size of the vocabulary.
+    """
+    seed = _mix((key ^ (key >> 32) ^ prev_token) & MASK)
+    token_ids = torch.arange(max_value, device=device, dtype=torch.int64)
+    hashes = _mix((seed + token_ids * 0x9E3779B1) & MASK)
+    return hashes < int(gamma * 2**32)
+
 
 class WatermarkLogitsProcessor(LogitsProcessor):
     def __init__(
@@ -30,14 +55,18 @@ class WatermarkLogitsProcessor(LogitsProcessor):
         delta: float = DELTA,
         hash_key: int = 15485863,  # just a large prime number to create a rng seed with sufficient bit width
         device: str = "cpu",
+        key: Optional[int] = None,
     ):
         # watermarking parameters
         self.gamma = gamma
         self.delta = delta
         self.rng = torch.Generator(device=device)
         self.hash_key = hash_key
+        # key set by the router, that selects the greenlists of `keyed_greenlist_mask`
+        self.key = key
 
-    def _seed_rng(self, input_ids: Union[List[int], torch.LongTensor]):
+    @staticmethod
+    def _prev_token(input_ids: Union[List[int], torch.LongTensor]) -> int:
         if isinstance(input_ids, list):
             assert (
                 len(input_ids) >= 1
@@ -50,7 +79,10 @@ class WatermarkLogitsProcessor(LogitsProcessor):
                 input_ids.shape[-1] >= 1
             ), "requires at least a 1 token prefix sequence to seed rng"
             prev_token = input_ids[-1].item()
-        self.rng.manual_seed(self.hash_key * prev_token)
+        return prev_token
+
+    def _seed_rng(self, input_ids: Union[List[int], torch.LongTensor]):
+        self.rng.manual_seed(self.hash_key * self._prev_token(input_ids))
 
     def _get_greenlist_ids(
         self,
@@ -58,6 +90,12 @@ class WatermarkLogitsProcessor(LogitsProcessor):
         max_value: int,
         device: torch.device,
     ) -> List[int]:
+        if self.key is not None:
+            greenlist_mask = keyed_greenlist_mask(
+                self.key, self._prev_token(input_ids), max_value, self.gamma, device
+            )
+            return greenlist_mask.nonzero().squeeze(-1)
+
         # seed the rng using the previous tokens/prefix
         self._seed_rng(input_ids)
 